@@ -0,0 +1,654 @@
+//! FUSE gateway exposing an object store as a local filesystem.
+//!
+//! The root directory holds the objects of the store which are not part of
+//! any namespace as regular files, and each namespace of the store as a
+//! directory of its objects.  Directories can not be nested and files can
+//! not be renamed into another directory, `mv` copies them.  Namespaces
+//! exist only as long as they contain objects, directories created with
+//! `mkdir` are kept in memory until the first file is created in them.
+//!
+//! File contents are read and written with the chunked object paths of
+//! `ObjectHandle::read_at` and `ObjectHandle::write_at`.  Changes become
+//! durable with `fsync`, on unmount, or with the periodic sync configured by
+//! `sync_interval_ms`.
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    os::{raw::c_int, unix::ffi::OsStrExt},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use betree_storage_stack::{
+    database::{Database, DatabaseConfiguration, Error as BetreeError, ErrorCode},
+    object::{ObjectHandle, ObjectInfo, ObjectStore, NAMESPACE_MARKER},
+    vdev::BLOCK_SIZE,
+    StoragePreference,
+};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyStatfs, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use log::{info, warn};
+use parking_lot::RwLock;
+
+use crate::Error;
+
+const TTL: Duration = Duration::from_secs(1);
+
+type FsResult<T> = Result<T, c_int>;
+
+fn errno(err: BetreeError) -> c_int {
+    match err.code() {
+        ErrorCode::DoesNotExist => libc::ENOENT,
+        ErrorCode::AlreadyExists => libc::EEXIST,
+        ErrorCode::OutOfSpace => libc::ENOSPC,
+        ErrorCode::InUse | ErrorCode::Pinned => libc::EBUSY,
+        ErrorCode::InvalidArgument | ErrorCode::Configuration => libc::EINVAL,
+        ErrorCode::Canceled => libc::EINTR,
+        _ => libc::EIO,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    // A namespace by its name.
+    Dir(Vec<u8>),
+    // An object by its namespace, if any, and its name.
+    File(Option<Vec<u8>>, Vec<u8>),
+}
+
+// Inode numbers are assigned on first lookup and stay valid until the node
+// is removed.
+struct Inodes {
+    nodes: HashMap<u64, Node>,
+    inos: HashMap<Node, u64>,
+    next: u64,
+}
+
+impl Inodes {
+    fn new() -> Self {
+        let mut inodes = Inodes {
+            nodes: HashMap::new(),
+            inos: HashMap::new(),
+            next: FUSE_ROOT_ID,
+        };
+        inodes.ino(Node::Root);
+        inodes
+    }
+
+    fn ino(&mut self, node: Node) -> u64 {
+        if let Some(&ino) = self.inos.get(&node) {
+            return ino;
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.nodes.insert(ino, node.clone());
+        self.inos.insert(node, ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> FsResult<Node> {
+        self.nodes.get(&ino).cloned().ok_or(libc::ENOENT)
+    }
+
+    fn remove(&mut self, node: &Node) {
+        if let Some(ino) = self.inos.remove(node) {
+            self.nodes.remove(&ino);
+        }
+    }
+}
+
+struct ObjectFs {
+    db: Arc<RwLock<Database>>,
+    store: Option<ObjectStore>,
+    inodes: Inodes,
+    // Namespaces created with mkdir which do not contain objects yet.
+    empty_dirs: HashSet<Vec<u8>>,
+    mounted: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+impl ObjectFs {
+    fn store(&self) -> &ObjectStore {
+        self.store.as_ref().expect("object store is closed")
+    }
+
+    fn dir_exists(&self, name: &[u8]) -> FsResult<bool> {
+        if self.empty_dirs.contains(name) {
+            return Ok(true);
+        }
+        let mut objects = self
+            .store()
+            .namespace(name)
+            .and_then(|namespace| namespace.list_objects())
+            .map_err(errno)?;
+        Ok(objects.next().transpose().map_err(errno)?.is_some())
+    }
+
+    // Returns the node of the object `name` in the directory `parent`.
+    fn child_node(&self, parent: u64, name: &OsStr) -> FsResult<Node> {
+        let name = name.as_bytes();
+        match self.inodes.node(parent)? {
+            Node::Root if name.first() == Some(&NAMESPACE_MARKER) => Err(libc::EINVAL),
+            Node::Root => Ok(Node::File(None, name.to_vec())),
+            Node::Dir(namespace) => Ok(Node::File(Some(namespace), name.to_vec())),
+            Node::File(..) => Err(libc::ENOTDIR),
+        }
+    }
+
+    // Opens the object of the file `node`, going through its namespace if it
+    // has one.
+    fn open_file(&self, node: &Node) -> FsResult<(ObjectHandle<'_>, ObjectInfo)> {
+        let opened = match node {
+            Node::File(None, name) => self.store().open_object_with_info(name),
+            Node::File(Some(namespace), name) => self
+                .store()
+                .namespace(namespace)
+                .and_then(|namespace| namespace.open_object_with_info(name)),
+            Node::Root | Node::Dir(_) => return Err(libc::EISDIR),
+        };
+        opened.map_err(errno)?.ok_or(libc::ENOENT)
+    }
+
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    fn object_info(&self, node: &Node) -> FsResult<ObjectInfo> {
+        let (_, info) = self.open_file(node)?;
+        Ok(info)
+    }
+
+    fn file_attr(&self, ino: u64, info: &ObjectInfo) -> FileAttr {
+        FileAttr {
+            ino,
+            size: info.size,
+            blocks: (info.size + 511) / 512,
+            atime: info.mtime,
+            mtime: info.mtime,
+            ctime: info.mtime,
+            crtime: info.mtime,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    fn attr(&self, ino: u64) -> FsResult<FileAttr> {
+        match self.inodes.node(ino)? {
+            Node::Root => Ok(self.dir_attr(ino)),
+            Node::Dir(name) if self.dir_exists(&name)? => Ok(self.dir_attr(ino)),
+            Node::Dir(_) => Err(libc::ENOENT),
+            node @ Node::File(..) => Ok(self.file_attr(ino, &self.object_info(&node)?)),
+        }
+    }
+
+    fn lookup_node(&mut self, parent: u64, name: &OsStr) -> FsResult<FileAttr> {
+        // Namespaces shadow objects of the same name in the root directory.
+        if self.inodes.node(parent)? == Node::Root && self.dir_exists(name.as_bytes())? {
+            let ino = self.inodes.ino(Node::Dir(name.as_bytes().to_vec()));
+            return Ok(self.dir_attr(ino));
+        }
+        let node = self.child_node(parent, name)?;
+        let info = self.object_info(&node)?;
+        let ino = self.inodes.ino(node);
+        Ok(self.file_attr(ino, &info))
+    }
+
+    fn entries(&mut self, ino: u64) -> FsResult<Vec<(u64, FileType, Vec<u8>)>> {
+        let mut entries = vec![
+            (ino, FileType::Directory, b".".to_vec()),
+            (FUSE_ROOT_ID, FileType::Directory, b"..".to_vec()),
+        ];
+        match self.inodes.node(ino)? {
+            Node::Root => {
+                let mut namespaces: Vec<Vec<u8>> = self
+                    .store()
+                    .list_namespaces()
+                    .map_err(errno)?
+                    .into_iter()
+                    .map(|name| name.to_vec())
+                    .collect();
+                namespaces.extend(self.empty_dirs.iter().cloned());
+                namespaces.sort();
+                namespaces.dedup();
+                for name in namespaces {
+                    let ino = self.inodes.ino(Node::Dir(name.clone()));
+                    entries.push((ino, FileType::Directory, name));
+                }
+                // Namespaced objects are not listed here.
+                let names = self
+                    .store()
+                    .list_objects_with_prefix(&[])
+                    .map_err(errno)?
+                    .map(|res| res.map(|(key, _info)| key.to_vec()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(errno)?;
+                for name in names {
+                    let ino = self.inodes.ino(Node::File(None, name.clone()));
+                    entries.push((ino, FileType::RegularFile, name));
+                }
+            }
+            Node::Dir(namespace) => {
+                let names = self
+                    .store()
+                    .namespace(&namespace)
+                    .and_then(|namespace| namespace.list_objects())
+                    .map_err(errno)?
+                    .map(|res| res.map(|(name, _info)| name.to_vec()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(errno)?;
+                for name in names {
+                    let ino = self
+                        .inodes
+                        .ino(Node::File(Some(namespace.clone()), name.clone()));
+                    entries.push((ino, FileType::RegularFile, name));
+                }
+            }
+            Node::File(..) => return Err(libc::ENOTDIR),
+        }
+        Ok(entries)
+    }
+
+    fn create_file(&mut self, parent: u64, name: &OsStr) -> FsResult<FileAttr> {
+        let node = self.child_node(parent, name)?;
+        let handle = match &node {
+            Node::File(Some(namespace), name) => self
+                .store()
+                .namespace(namespace)
+                .and_then(|namespace| namespace.open_or_create_object(name)),
+            Node::File(None, name) => self.store().open_or_create_object(name),
+            Node::Root | Node::Dir(_) => unreachable!(),
+        }
+        .map_err(errno)?;
+        handle.close().map_err(errno)?;
+        if let Node::Dir(namespace) = self.inodes.node(parent)? {
+            self.empty_dirs.remove(&namespace);
+        }
+        let info = self.object_info(&node)?;
+        let ino = self.inodes.ino(node);
+        Ok(self.file_attr(ino, &info))
+    }
+
+    fn read_file(&self, ino: u64, offset: u64, size: u32) -> FsResult<Vec<u8>> {
+        let (handle, _) = self.open_file(&self.inodes.node(ino)?)?;
+        let mut buf = vec![0; size as usize];
+        let read = handle
+            .read_at(&mut buf, offset)
+            .map_err(|(_, err)| errno(err))?;
+        buf.truncate(read as usize);
+        Ok(buf)
+    }
+
+    fn write_file(&self, ino: u64, offset: u64, data: &[u8]) -> FsResult<u32> {
+        let (handle, _) = self.open_file(&self.inodes.node(ino)?)?;
+        let written = handle
+            .write_at(data, offset)
+            .map_err(|(_, err)| errno(err))?;
+        Ok(written as u32)
+    }
+
+    fn truncate_file(&self, ino: u64, size: u64) -> FsResult<()> {
+        let (handle, _) = self.open_file(&self.inodes.node(ino)?)?;
+        handle.truncate(size).map_err(errno)
+    }
+
+    fn unlink_file(&mut self, parent: u64, name: &OsStr) -> FsResult<()> {
+        let node = self.child_node(parent, name)?;
+        let (handle, _) = self.open_file(&node)?;
+        handle.delete().map_err(errno)?;
+        self.inodes.remove(&node);
+        Ok(())
+    }
+
+    fn make_dir(&mut self, parent: u64, name: &OsStr) -> FsResult<FileAttr> {
+        if self.inodes.node(parent)? != Node::Root {
+            return Err(libc::EPERM);
+        }
+        let name = name.as_bytes();
+        // Checks whether the name is valid for a namespace.
+        self.store().namespace(name).map_err(errno)?;
+        if self.dir_exists(name)? {
+            return Err(libc::EEXIST);
+        }
+        self.empty_dirs.insert(name.to_vec());
+        let ino = self.inodes.ino(Node::Dir(name.to_vec()));
+        Ok(self.dir_attr(ino))
+    }
+
+    fn remove_dir(&mut self, parent: u64, name: &OsStr) -> FsResult<()> {
+        if self.inodes.node(parent)? != Node::Root {
+            return Err(libc::ENOENT);
+        }
+        let name = name.as_bytes();
+        if !self.empty_dirs.remove(name) {
+            return Err(if self.dir_exists(name)? {
+                libc::ENOTEMPTY
+            } else {
+                libc::ENOENT
+            });
+        }
+        self.inodes.remove(&Node::Dir(name.to_vec()));
+        Ok(())
+    }
+
+    fn rename_file(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> FsResult<()> {
+        if self.inodes.node(parent)? == Node::Root && self.dir_exists(name.as_bytes())? {
+            // Renaming a namespace would move all of its objects, `mv` falls
+            // back to copying on this error.
+            return Err(libc::EXDEV);
+        }
+        let old = self.child_node(parent, name)?;
+        let new = self.child_node(new_parent, new_name)?;
+        let overwrite = flags & libc::RENAME_NOREPLACE == 0;
+        match (&old, &new) {
+            (Node::File(None, old_name), Node::File(None, new_name)) => self
+                .store()
+                .rename(old_name, new_name, overwrite)
+                .map_err(errno)?,
+            (Node::File(Some(namespace), old_name), Node::File(Some(new_namespace), new_name))
+                if namespace == new_namespace =>
+            {
+                self.store()
+                    .namespace(namespace)
+                    .and_then(|namespace| namespace.rename(old_name, new_name, overwrite))
+                    .map_err(errno)?
+            }
+            // Objects can not move between namespaces, `mv` copies them.
+            _ => return Err(libc::EXDEV),
+        }
+        if let Node::Dir(namespace) = self.inodes.node(new_parent)? {
+            self.empty_dirs.remove(&namespace);
+        }
+        // The inode moves along with the object.
+        self.inodes.remove(&new);
+        if let Some(ino) = self.inodes.inos.remove(&old) {
+            self.inodes.nodes.insert(ino, new.clone());
+            self.inodes.inos.insert(new, ino);
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.db.write().sync().map_err(errno)
+    }
+}
+
+fn reply_entry(reply: ReplyEntry, res: FsResult<FileAttr>) {
+    match res {
+        Ok(attr) => reply.entry(&TTL, &attr, 0),
+        Err(err) => reply.error(err),
+    }
+}
+
+fn reply_attr(reply: ReplyAttr, res: FsResult<FileAttr>) {
+    match res {
+        Ok(attr) => reply.attr(&TTL, &attr),
+        Err(err) => reply.error(err),
+    }
+}
+
+fn reply_empty(reply: ReplyEmpty, res: FsResult<()>) {
+    match res {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(err),
+    }
+}
+
+impl Filesystem for ObjectFs {
+    fn destroy(&mut self) {
+        if let Some(store) = self.store.take() {
+            self.db.write().close_object_store(store);
+        }
+        if let Err(err) = self.db.write().sync() {
+            warn!("Could not sync database on unmount: {}", err);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let res = self.lookup_node(parent, name);
+        reply_entry(reply, res)
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        reply_attr(reply, self.attr(ino))
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Only the size can be changed, other attributes are fixed.
+        let res = size
+            .map_or(Ok(()), |size| self.truncate_file(ino, size))
+            .and_then(|()| self.attr(ino));
+        reply_attr(reply, res)
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let res = self.make_dir(parent, name);
+        reply_entry(reply, res)
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = self.unlink_file(parent, name);
+        reply_empty(reply, res)
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = self.remove_dir(parent, name);
+        reply_empty(reply, res)
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.rename_file(parent, name, newparent, newname, flags);
+        reply_empty(reply, res)
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_file(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write_file(ino, offset as u64, data) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(reply, self.sync())
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(reply, self.sync())
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.entries(ino) {
+            Ok(entries) => entries,
+            Err(err) => return reply.error(err),
+        };
+        for (idx, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, idx as i64 + 1, kind, OsStr::from_bytes(&name)) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let tiers = self.db.read().free_space_tier();
+        let free = tiers.iter().map(|tier| tier.free.as_u64()).sum();
+        let total = tiers.iter().map(|tier| tier.total.as_u64()).sum();
+        reply.statfs(
+            total,
+            free,
+            free,
+            0,
+            0,
+            BLOCK_SIZE as u32,
+            255,
+            BLOCK_SIZE as u32,
+        )
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.create_file(parent, name) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+}
+
+/// Mounts the object store `name` at `mountpoint` and serves requests until
+/// it is unmounted.
+pub fn mount(
+    cfg: DatabaseConfiguration,
+    name: &[u8],
+    storage_preference: StoragePreference,
+    mountpoint: &Path,
+) -> Result<(), Error> {
+    let db = Database::build_threaded(cfg)?;
+    let store = db
+        .write()
+        .open_named_object_store(name, storage_preference)?;
+    let fs = ObjectFs {
+        db,
+        store: Some(store),
+        inodes: Inodes::new(),
+        empty_dirs: HashSet::new(),
+        mounted: SystemTime::now(),
+        // Safe, these calls can not fail.
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    };
+    info!("Mounting object store at {}", mountpoint.display());
+    fuser::mount2(
+        fs,
+        mountpoint,
+        &[
+            MountOption::FSName(String::from_utf8_lossy(name).into_owned()),
+            MountOption::Subtype("bepsi".into()),
+            MountOption::DefaultPermissions,
+        ],
+    )?;
+    Ok(())
+}
@@ -1,27 +1,34 @@
 use std::{
     fmt::{self, Display},
-    io::{self, BufReader, BufWriter, Write},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
     num,
+    path::PathBuf,
     str::FromStr,
 };
 
 use betree_storage_stack::{
     cow_bytes::CowBytes,
-    database::{Database, DatabaseConfiguration, Superblock},
-    tree::{DefaultMessageAction, TreeLayer},
+    database::{
+        ingest::{BinaryDumpReader, HexDumpReader},
+        Database, DatabaseConfiguration, Superblock,
+    },
+    tree::{DefaultMessageAction, DumpDetail, TreeLayer},
     StoragePreference, storage_pool::DiskOffset,
 };
 use chrono::{DateTime, Utc};
-use figment::providers::Format;
 use log::info;
 use structopt::StructOpt;
 
+#[cfg(feature = "fuse")]
+mod fuse;
+
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 #[derive(StructOpt)]
 struct Opt {
-    /// Path to JSON configuration file of database.
+    /// Path to the JSON, TOML or YAML configuration file of the database.
     #[structopt(long, short, env = "BETREE_CONFIG")]
     database_config: String,
 
@@ -80,6 +87,10 @@ impl FromStr for OptStoragePreference {
 enum ConfigMode {
     PrintActive,
     PrintDefault,
+    /// Print the settings which differ in the given configuration file
+    Diff {
+        other: String,
+    },
 }
 
 #[derive(StructOpt)]
@@ -104,7 +115,33 @@ enum KvMode {
         name: String,
         value: String,
     },
-    TreeDump,
+    TreeDump {
+        #[structopt(short = "c", long)]
+        compact: bool,
+    },
+    /// Ingest a dump of entries sorted by key, `-` reads from stdin
+    Import {
+        path: PathBuf,
+        /// `binary` for length-prefixed records or `hex` for the output of
+        /// `ldb dump --hex` and `sst_dump --command=scan --output_hex`
+        #[structopt(long, default_value = "binary")]
+        format: DumpFormat,
+    },
+}
+
+enum DumpFormat {
+    Binary,
+    Hex,
+}
+impl FromStr for DumpFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(DumpFormat::Binary),
+            "hex" => Ok(DumpFormat::Hex),
+            _ => Err(format!("unknown dump format {}", s)),
+        }
+    }
 }
 
 #[derive(StructOpt)]
@@ -133,6 +170,11 @@ enum ObjMode {
         #[structopt(subcommand)]
         mode: ObjMetaMode,
     },
+    /// Mount the object store as a filesystem until it is unmounted, requires
+    /// the `fuse` feature
+    Mount {
+        mountpoint: PathBuf,
+    },
 }
 
 #[derive(StructOpt)]
@@ -177,11 +219,7 @@ fn bectl_main() -> Result<(), Error> {
     betree_storage_stack::env_logger::init_env_logger();
     let opt = Opt::from_args();
 
-    let cfg: DatabaseConfiguration = figment::Figment::new()
-        .merge(DatabaseConfiguration::figment_default())
-        .merge(figment::providers::Json::file(opt.database_config))
-        .merge(DatabaseConfiguration::figment_env())
-        .extract()?;
+    let cfg = DatabaseConfiguration::load(&opt.database_config)?;
 
     info!("{:#?}", cfg);
 
@@ -189,6 +227,11 @@ fn bectl_main() -> Result<(), Error> {
         Mode::Config { mode } => match mode {
             ConfigMode::PrintActive => println!("{:#?}", cfg),
             ConfigMode::PrintDefault => println!("{:#?}", DatabaseConfiguration::default()),
+            ConfigMode::Diff { other } => {
+                for change in cfg.diff(&DatabaseConfiguration::load(other)?) {
+                    println!("{}", change);
+                }
+            }
         },
 
         Mode::Db { mode } => match mode {
@@ -235,7 +278,7 @@ fn bectl_main() -> Result<(), Error> {
             ..
         } => match mode {
             KvMode::List { with_value } => {
-                let mut db = open_db(cfg)?;
+                let db = open_db(cfg)?;
                 let ds = db.open_custom_dataset::<DefaultMessageAction>(
                     dataset.as_bytes(),
                     storage_preference.0,
@@ -251,7 +294,7 @@ fn bectl_main() -> Result<(), Error> {
             }
 
             KvMode::Get { name } => {
-                let mut db = open_db(cfg)?;
+                let db = open_db(cfg)?;
                 let ds = db.open_or_create_custom_dataset::<DefaultMessageAction>(
                     dataset.as_bytes(),
                     storage_preference.0,
@@ -268,8 +311,8 @@ fn bectl_main() -> Result<(), Error> {
                 db.sync()?;
             }
 
-            KvMode::TreeDump => {
-                let mut db = open_db(cfg)?;
+            KvMode::TreeDump { compact } => {
+                let db = open_db(cfg)?;
                 let ds = db.open_or_create_custom_dataset::<DefaultMessageAction>(
                     dataset.as_bytes(),
                     storage_preference.0,
@@ -278,7 +321,38 @@ fn bectl_main() -> Result<(), Error> {
                 let stdout = io::stdout();
                 let mut stdout_lock = stdout.lock();
 
-                let _ = serde_json::to_writer_pretty(&mut stdout_lock, &ds.tree_dump()?);
+                let detail = if compact {
+                    DumpDetail::Compact
+                } else {
+                    DumpDetail::Full
+                };
+                let _ = serde_json::to_writer_pretty(&mut stdout_lock, &ds.dump(detail)?);
+            }
+
+            KvMode::Import { path, format } => {
+                let mut db = open_db(cfg)?;
+                let ds = db.open_or_create_custom_dataset::<DefaultMessageAction>(
+                    dataset.as_bytes(),
+                    storage_preference.0,
+                )?;
+                let input: Box<dyn BufRead> = if path.as_os_str() == "-" {
+                    Box::new(BufReader::new(io::stdin()))
+                } else {
+                    Box::new(BufReader::new(File::open(&path)?))
+                };
+                let report = match format {
+                    DumpFormat::Binary => {
+                        ds.ingest(BinaryDumpReader::new(input), storage_preference.0)?
+                    }
+                    DumpFormat::Hex => {
+                        ds.ingest(HexDumpReader::new(input), storage_preference.0)?
+                    }
+                };
+                db.sync()?;
+                println!(
+                    "ingested {} entries with {} bytes",
+                    report.entries, report.bytes
+                );
             }
         },
 
@@ -393,6 +467,16 @@ fn bectl_main() -> Result<(), Error> {
 
                 db.sync()?;
             }
+
+            #[cfg(feature = "fuse")]
+            ObjMode::Mount { mountpoint } => {
+                fuse::mount(cfg, namespace.as_bytes(), storage_preference.0, &mountpoint)?
+            }
+
+            #[cfg(not(feature = "fuse"))]
+            ObjMode::Mount { .. } => {
+                error_chain::bail!("bectl has been built without the `fuse` feature")
+            }
         },
     }
 
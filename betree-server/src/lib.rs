@@ -0,0 +1,461 @@
+//! A gRPC service exposing a database to remote clients, see
+//! `proto/betree.proto` for the protocol.
+//!
+//! The service owns the database, so that multiple client processes and hosts
+//! can share one storage pool.  Datasets and object stores are opened on first
+//! use and stay open until the service is dropped.  Dataset lookups, inserts
+//! and range queries use [AsyncDataset], all other operations run on the
+//! blocking threads of the Tokio runtime.
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use betree_storage_stack::{
+    database::{AsyncDataset, Database, Error, ErrorCode, Result, TokioSpawner},
+    object::{ObjectInfo, ObjectStore},
+    storage_pool::NUM_STORAGE_CLASSES,
+    StoragePreference,
+};
+use futures::{stream::BoxStream, StreamExt};
+use parking_lot::{Mutex, RwLock};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataValue, Request, Response, Status, Streaming};
+
+/// The types and service traits generated from `proto/betree.proto`.
+pub mod proto {
+    #![allow(clippy::derive_partial_eq_without_eq)]
+    tonic::include_proto!("betree.v1");
+}
+
+use proto::betree_server::Betree;
+pub use proto::betree_server::BetreeServer;
+
+// Size of the chunks in which object data is streamed.
+const READ_SIZE: usize = 128 * 1024;
+// Number of messages buffered per streaming response.
+const STREAM_BUFFER: usize = 16;
+
+/// Returns the status of a failed call, which carries the [ErrorCode] of
+/// `err` as `betree-error-code` metadata.
+pub fn status(err: Error) -> Status {
+    let code = err.code();
+    let message = err.to_string();
+    let mut result = match code {
+        ErrorCode::DoesNotExist => Status::not_found(message),
+        ErrorCode::AlreadyExists => Status::already_exists(message),
+        ErrorCode::OutOfSpace => Status::resource_exhausted(message),
+        ErrorCode::InUse | ErrorCode::Pinned | ErrorCode::Closed => {
+            Status::failed_precondition(message)
+        }
+        ErrorCode::InvalidArgument | ErrorCode::Configuration => Status::invalid_argument(message),
+        ErrorCode::Canceled => Status::cancelled(message),
+        ErrorCode::Conflict => Status::aborted(message),
+        ErrorCode::Checksum | ErrorCode::Corrupted => Status::data_loss(message),
+        ErrorCode::Io => Status::unavailable(message),
+        _ => Status::internal(message),
+    };
+    result
+        .metadata_mut()
+        .insert("betree-error-code", MetadataValue::from(code as u32));
+    result
+}
+
+fn storage_preference(pref: Option<u32>) -> std::result::Result<StoragePreference, Status> {
+    match pref {
+        None => Ok(StoragePreference::NONE),
+        Some(class) if (class as usize) < NUM_STORAGE_CLASSES => {
+            Ok(StoragePreference::new(class as u8))
+        }
+        Some(class) => Err(Status::invalid_argument(format!(
+            "storage class {} does not exist",
+            class
+        ))),
+    }
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn object_info(key: &[u8], info: &ObjectInfo) -> proto::ObjectInfo {
+    proto::ObjectInfo {
+        key: key.to_vec(),
+        size: info.size,
+        mtime_us: micros_since_epoch(info.mtime),
+        storage_preference: info.pref.preferred_class().map(u32::from),
+    }
+}
+
+async fn blocking<F, R>(f: F) -> std::result::Result<R, Status>
+where
+    F: FnOnce() -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(status)
+}
+
+struct Inner {
+    db: Arc<RwLock<Database>>,
+    spawner: Arc<TokioSpawner>,
+    datasets: Mutex<HashMap<Vec<u8>, AsyncDataset>>,
+    stores: Mutex<HashMap<Vec<u8>, ObjectStore>>,
+}
+
+/// The implementation of the `Betree` service on a database.
+#[derive(Clone)]
+pub struct BetreeService {
+    inner: Arc<Inner>,
+}
+
+impl BetreeService {
+    /// Serves `db`, whose blocking operations run on the runtime `handle`.
+    pub fn new(db: Arc<RwLock<Database>>, handle: Handle) -> Self {
+        BetreeService {
+            inner: Arc::new(Inner {
+                db,
+                spawner: Arc::new(TokioSpawner(handle)),
+                datasets: Mutex::new(HashMap::new()),
+                stores: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    async fn dataset(&self, name: Vec<u8>) -> std::result::Result<AsyncDataset, Status> {
+        if let Some(ds) = self.inner.datasets.lock().get(&name) {
+            return Ok(ds.clone());
+        }
+        let inner = Arc::clone(&self.inner);
+        blocking(move || {
+            let mut datasets = inner.datasets.lock();
+            if let Some(ds) = datasets.get(&name) {
+                return Ok(ds.clone());
+            }
+            let ds = inner.db.read().open_dataset(&name)?;
+            let ds = AsyncDataset::new(ds, Arc::clone(&inner.spawner));
+            datasets.insert(name, ds.clone());
+            Ok(ds)
+        })
+        .await
+    }
+
+    async fn object_store(&self, name: Vec<u8>) -> std::result::Result<ObjectStore, Status> {
+        if let Some(store) = self.inner.stores.lock().get(&name) {
+            return Ok(store.clone());
+        }
+        let inner = Arc::clone(&self.inner);
+        blocking(move || {
+            let mut stores = inner.stores.lock();
+            if let Some(store) = stores.get(&name) {
+                return Ok(store.clone());
+            }
+            let store = inner
+                .db
+                .write()
+                .open_named_object_store(&name, StoragePreference::NONE)?;
+            stores.insert(name, store.clone());
+            Ok(store)
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl Betree for BetreeService {
+    async fn sync(
+        &self,
+        _request: Request<proto::SyncRequest>,
+    ) -> std::result::Result<Response<proto::SyncResponse>, Status> {
+        let db = Arc::clone(&self.inner.db);
+        blocking(move || db.write().sync()).await?;
+        Ok(Response::new(proto::SyncResponse {}))
+    }
+
+    async fn list_datasets(
+        &self,
+        _request: Request<proto::ListDatasetsRequest>,
+    ) -> std::result::Result<Response<proto::ListDatasetsResponse>, Status> {
+        let db = Arc::clone(&self.inner.db);
+        let names = blocking(move || {
+            db.read()
+                .iter_datasets()?
+                .map(|name| name.map(|name| name.to_vec()))
+                .collect::<Result<Vec<_>>>()
+        })
+        .await?;
+        Ok(Response::new(proto::ListDatasetsResponse { names }))
+    }
+
+    async fn create_dataset(
+        &self,
+        request: Request<proto::CreateDatasetRequest>,
+    ) -> std::result::Result<Response<proto::CreateDatasetResponse>, Status> {
+        let name = request.into_inner().name;
+        let db = Arc::clone(&self.inner.db);
+        blocking(move || db.read().create_dataset(&name)).await?;
+        Ok(Response::new(proto::CreateDatasetResponse {}))
+    }
+
+    async fn storage_info(
+        &self,
+        _request: Request<proto::StorageInfoRequest>,
+    ) -> std::result::Result<Response<proto::StorageInfoResponse>, Status> {
+        let tiers = self
+            .inner
+            .db
+            .read()
+            .storage_report()
+            .tiers
+            .into_iter()
+            .map(|tier| proto::TierInfo {
+                name: tier.name,
+                free_blocks: tier.info.free.as_u64(),
+                total_blocks: tier.info.total.as_u64(),
+            })
+            .collect();
+        Ok(Response::new(proto::StorageInfoResponse { tiers }))
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> std::result::Result<Response<proto::GetResponse>, Status> {
+        let request = request.into_inner();
+        let ds = self.dataset(request.dataset).await?;
+        let value = ds.get(&request.key[..]).await.map_err(status)?;
+        Ok(Response::new(proto::GetResponse {
+            value: value.map(|value| value.to_vec()),
+        }))
+    }
+
+    async fn insert(
+        &self,
+        request: Request<proto::InsertRequest>,
+    ) -> std::result::Result<Response<proto::InsertResponse>, Status> {
+        let request = request.into_inner();
+        let pref = storage_preference(request.storage_preference)?;
+        let ds = self.dataset(request.dataset).await?;
+        if pref == StoragePreference::NONE {
+            ds.insert(request.key, &request.value)
+                .await
+                .map_err(status)?;
+        } else {
+            let ds = ds.dataset().clone();
+            blocking(move || ds.insert_with_pref(request.key, &request.value, pref)).await?;
+        }
+        Ok(Response::new(proto::InsertResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> std::result::Result<Response<proto::DeleteResponse>, Status> {
+        let request = request.into_inner();
+        let ds = self.dataset(request.dataset).await?;
+        ds.delete(request.key).await.map_err(status)?;
+        Ok(Response::new(proto::DeleteResponse {}))
+    }
+
+    type RangeStream = BoxStream<'static, std::result::Result<proto::Entry, Status>>;
+
+    async fn range(
+        &self,
+        request: Request<proto::RangeRequest>,
+    ) -> std::result::Result<Response<Self::RangeStream>, Status> {
+        let request = request.into_inner();
+        let ds = self.dataset(request.dataset).await?;
+        let end = request.end.map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = ds
+            .range::<_, Vec<u8>>((Bound::Included(request.start), end))
+            .map_err(status)?
+            .map(|entry| {
+                entry
+                    .map(|(key, value)| proto::Entry {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    })
+                    .map_err(status)
+            });
+        Ok(Response::new(entries.boxed()))
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<proto::CreateSnapshotRequest>,
+    ) -> std::result::Result<Response<proto::CreateSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let mut ds = self.dataset(request.dataset).await?.dataset().clone();
+        let db = Arc::clone(&self.inner.db);
+        blocking(move || db.write().create_snapshot(&mut ds, &request.name)).await?;
+        Ok(Response::new(proto::CreateSnapshotResponse {}))
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<proto::ListSnapshotsRequest>,
+    ) -> std::result::Result<Response<proto::ListSnapshotsResponse>, Status> {
+        let ds = self
+            .dataset(request.into_inner().dataset)
+            .await?
+            .dataset()
+            .clone();
+        let db = Arc::clone(&self.inner.db);
+        let snapshots = blocking(move || db.read().list_snapshots(&ds))
+            .await?
+            .into_iter()
+            .map(|snapshot| proto::SnapshotInfo {
+                name: snapshot.name.to_vec(),
+                generation: snapshot.generation.as_u64(),
+                created_us: snapshot.created.map(micros_since_epoch),
+                referenced_blocks: snapshot.referenced.as_u64(),
+                unique_blocks: snapshot.unique.as_u64(),
+            })
+            .collect();
+        Ok(Response::new(proto::ListSnapshotsResponse { snapshots }))
+    }
+
+    type ListObjectsStream = ReceiverStream<std::result::Result<proto::ObjectInfo, Status>>;
+
+    async fn list_objects(
+        &self,
+        request: Request<proto::ListObjectsRequest>,
+    ) -> std::result::Result<Response<Self::ListObjectsStream>, Status> {
+        let request = request.into_inner();
+        let store = self.object_store(request.store).await?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let objects = match store.list_objects_with_prefix(&request.prefix) {
+                Ok(objects) => objects,
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(status(err)));
+                    return;
+                }
+            };
+            for object in objects {
+                let object = object
+                    .map(|(key, info)| object_info(&key, &info))
+                    .map_err(status);
+                // The client has gone away.
+                if tx.blocking_send(object).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn stat_object(
+        &self,
+        request: Request<proto::ObjectRequest>,
+    ) -> std::result::Result<Response<proto::ObjectInfo>, Status> {
+        let request = request.into_inner();
+        let store = self.object_store(request.store).await?;
+        let info = blocking(move || {
+            let (_, info) = store
+                .open_object_with_info(&request.key)?
+                .ok_or(Error::DoesNotExist)?;
+            Ok(object_info(&request.key, &info))
+        })
+        .await?;
+        Ok(Response::new(info))
+    }
+
+    type ReadObjectStream = ReceiverStream<std::result::Result<proto::ObjectData, Status>>;
+
+    async fn read_object(
+        &self,
+        request: Request<proto::ReadObjectRequest>,
+    ) -> std::result::Result<Response<Self::ReadObjectStream>, Status> {
+        let request = request.into_inner();
+        let store = self.object_store(request.store).await?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let (handle, info) = match store.open_object_with_info(&request.key) {
+                Ok(Some(object)) => object,
+                Ok(None) => {
+                    let _ = tx.blocking_send(Err(status(Error::DoesNotExist)));
+                    return;
+                }
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(status(err)));
+                    return;
+                }
+            };
+            let end = request
+                .length
+                .map_or(info.size, |length| request.offset.saturating_add(length))
+                .min(info.size);
+            let mut offset = request.offset;
+            while offset < end {
+                let mut data = vec![0; READ_SIZE.min((end - offset) as usize)];
+                let chunk = match handle.read_at(&mut data, offset) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        data.truncate(read as usize);
+                        let chunk = proto::ObjectData { offset, data };
+                        offset += read;
+                        Ok(chunk)
+                    }
+                    Err((_, err)) => Err(status(err)),
+                };
+                let failed = chunk.is_err();
+                if tx.blocking_send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn write_object(
+        &self,
+        request: Request<Streaming<proto::WriteObjectRequest>>,
+    ) -> std::result::Result<Response<proto::WriteObjectResponse>, Status> {
+        let mut messages = request.into_inner();
+        let mut object = None;
+        let mut written = 0;
+        while let Some(message) = messages.message().await? {
+            if object.is_none() {
+                let store = self.object_store(message.store.clone()).await?;
+                object = Some((store, message.key.clone()));
+            }
+            let (store, key) = object.clone().unwrap();
+            let pref = storage_preference(message.storage_preference)?;
+            written += blocking(move || {
+                let handle = store.open_or_create_object(&key)?;
+                let result = if pref == StoragePreference::NONE {
+                    handle.write_at(&message.data, message.offset)
+                } else {
+                    handle.write_at_with_pref(&message.data, message.offset, pref)
+                };
+                result.map_err(|(_, err)| err)
+            })
+            .await?;
+        }
+        Ok(Response::new(proto::WriteObjectResponse { written }))
+    }
+
+    async fn delete_object(
+        &self,
+        request: Request<proto::ObjectRequest>,
+    ) -> std::result::Result<Response<proto::DeleteObjectResponse>, Status> {
+        let request = request.into_inner();
+        let store = self.object_store(request.store).await?;
+        blocking(move || {
+            store
+                .open_object(&request.key)?
+                .ok_or(Error::DoesNotExist)?
+                .delete()
+        })
+        .await?;
+        Ok(Response::new(proto::DeleteObjectResponse {}))
+    }
+}
@@ -0,0 +1,42 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use betree_server::{BetreeServer, BetreeService};
+use betree_storage_stack::{database::Database, DatabaseConfiguration};
+use log::info;
+use structopt::StructOpt;
+use tokio::{runtime::Handle, task::block_in_place};
+use tonic::transport::Server;
+
+#[derive(StructOpt)]
+struct Opt {
+    /// Path to the JSON, TOML or YAML configuration file of the database.
+    #[structopt(long, short, env = "BETREE_CONFIG")]
+    database_config: String,
+
+    /// Address to serve the gRPC interface on.
+    #[structopt(long, short, default_value = "127.0.0.1:50051")]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    betree_storage_stack::env_logger::init_env_logger();
+    let opt = Opt::from_args();
+
+    let cfg = DatabaseConfiguration::load(&opt.database_config)?;
+
+    let db = block_in_place(|| Database::build_threaded(cfg))?;
+    let service = BetreeService::new(Arc::clone(&db), Handle::current());
+
+    info!("Serving on {}", opt.listen);
+    Server::builder()
+        .add_service(BetreeServer::new(service))
+        .serve_with_shutdown(opt.listen, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    info!("Shutting down");
+    block_in_place(|| db.write().sync())?;
+    Ok(())
+}
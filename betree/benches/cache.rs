@@ -1,5 +1,5 @@
 use betree_storage_stack::{
-    cache::{Cache, ClockCache},
+    cache::{Cache, PolicyCache},
     storage_pool::DiskOffset,
     vdev::Block,
 };
@@ -7,7 +7,7 @@ use criterion::{black_box, criterion_group, criterion_main, Bencher, Criterion};
 
 fn get_and_pin(b: &mut Bencher) {
     let five = DiskOffset::new(0, 0, Block(5));
-    let mut c = ClockCache::new(5);
+    let mut c = PolicyCache::new(5);
     c.insert(five, five, 1);
     b.iter(|| {
         black_box(c.get(&five, true));
@@ -11,6 +11,81 @@
 #include <stdlib.h>
 
 
+/**
+ * None of the other error codes applies
+ */
+#define BETREE_ERR_OTHER 1
+
+/**
+ * Reading from or writing to a storage device failed
+ */
+#define BETREE_ERR_IO 2
+
+/**
+ * Data read from a storage device does not match its checksum
+ */
+#define BETREE_ERR_CHECKSUM 3
+
+/**
+ * Stored data could not be decoded or decrypted
+ */
+#define BETREE_ERR_CORRUPTED 4
+
+/**
+ * A storage class does not have enough free space
+ */
+#define BETREE_ERR_OUT_OF_SPACE 5
+
+/**
+ * The key, data set, snapshot, or object does not exist
+ */
+#define BETREE_ERR_DOES_NOT_EXIST 6
+
+/**
+ * A data set or snapshot of the same name already exists
+ */
+#define BETREE_ERR_ALREADY_EXISTS 7
+
+/**
+ * The data set, snapshot, or vdev is still in use
+ */
+#define BETREE_ERR_IN_USE 8
+
+/**
+ * The data set has been closed
+ */
+#define BETREE_ERR_CLOSED 9
+
+/**
+ * An argument is not valid, e.g. a key is too large
+ */
+#define BETREE_ERR_INVALID_ARGUMENT 10
+
+/**
+ * The configuration is not valid or does not match the stored data
+ */
+#define BETREE_ERR_CONFIGURATION 11
+
+/**
+ * The key is pinned to a storage class
+ */
+#define BETREE_ERR_PINNED 12
+
+/**
+ * An asynchronous operation has been canceled
+ */
+#define BETREE_ERR_CANCELED 13
+
+/**
+ * An internal error
+ */
+#define BETREE_ERR_INTERNAL 14
+
+/**
+ * A transaction conflicts with a concurrent modification
+ */
+#define BETREE_ERR_CONFLICT 15
+
 /**
  * Internal block size (4KiB)
  */
@@ -68,6 +143,11 @@ typedef struct err_t err_t;
  */
 typedef struct name_iter_t name_iter_t;
 
+/**
+ * The object iterator type
+ */
+typedef struct obj_iter_t obj_iter_t;
+
 /**
  * The object store wrapper type
  */
@@ -83,6 +163,11 @@ typedef struct obj_t obj_t;
  */
 typedef struct range_iter_t range_iter_t;
 
+/**
+ * The snapshot information iterator type
+ */
+typedef struct snapshot_iter_t snapshot_iter_t;
+
 /**
  * The snapshot type
  */
@@ -144,6 +229,64 @@ typedef struct byte_slice_t {
   const struct byte_slice_rc_t *arc;
 } byte_slice_t;
 
+/**
+ * Information about a snapshot
+ */
+typedef struct snapshot_info_t {
+  /**
+   * The name of the snapshot
+   */
+  struct byte_slice_t name;
+  /**
+   * The generation of the data set captured by the snapshot
+   */
+  uint64_t generation;
+  /**
+   * The time of creation in microseconds since the Unix epoch, 0 if unknown
+   */
+  uint64_t created_us;
+  /**
+   * Blocks referenced by the snapshot which are no longer part of the data set
+   */
+  uint64_t referenced_blocks;
+  /**
+   * Blocks which would be freed by deleting the snapshot
+   */
+  uint64_t unique_blocks;
+} snapshot_info_t;
+
+/**
+ * Information about an object
+ */
+typedef struct obj_info_t {
+  /**
+   * The size of the object in bytes
+   */
+  uint64_t size;
+  /**
+   * The last modification time in microseconds since the Unix epoch
+   */
+  uint64_t mtime_us;
+  /**
+   * The most recently used storage preference
+   */
+  struct storage_pref_t pref;
+} obj_info_t;
+
+/**
+ * The occupancy of a storage class
+ */
+typedef struct storage_info_t {
+  /**
+   * Remaining free storage in blocks
+   */
+  uint64_t free_blocks;
+  /**
+   * Total storage in blocks
+   */
+  uint64_t total_blocks;
+} storage_info_t;
+
 #define STORAGE_PREF_FASTEST (storage_pref_t){ ._0 = StoragePreference_FASTEST }
 
 #define STORAGE_PREF_NONE (storage_pref_t){ ._0 = StoragePreference_NONE }
@@ -175,6 +318,21 @@ void betree_close_db(struct db_t *db);
  */
 int betree_close_ds(struct db_t *db, struct ds_t *ds, struct err_t **err);
 
+/**
+ * Close an object store.
+ *
+ * Note that the `obj_store_t` may not be used afterwards, all objects of it
+ * have to be closed before.
+ */
+void betree_close_object_store(struct db_t *db, struct obj_store_t *os);
+
+/**
+ * Close a snapshot of the given data set.
+ *
+ * Note that the `ss_t` may not be used afterwards.
+ */
+void betree_close_snapshot(const struct db_t *db, struct ds_t *ds, struct ss_t *ss);
+
 /**
  * Parse configuration from file specified in environment (BETREE_CONFIG).
  *
@@ -289,6 +447,34 @@ int betree_dataset_insert(const struct ds_t *ds,
                           struct storage_pref_t storage_pref,
                           struct err_t **err);
 
+/**
+ * Migrate the value for the given `key` to the given storage preference.
+ *
+ * On success, return 0.  If the key does not exist, return -1.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_dataset_migrate(const struct ds_t *ds,
+                           const char *key,
+                           unsigned int len,
+                           struct storage_pref_t storage_pref,
+                           struct err_t **err);
+
+/**
+ * Migrate all key-value pairs in the given key range to the given storage
+ * preference.
+ * `low_key` is inclusive, `high_key` is exclusive.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_dataset_migrate_range(const struct ds_t *ds,
+                                 const char *low_key,
+                                 unsigned int low_key_len,
+                                 const char *high_key,
+                                 unsigned int high_key_len,
+                                 struct storage_pref_t storage_pref,
+                                 struct err_t **err);
+
 /**
  * Return the data set's name.
  */
@@ -375,11 +561,21 @@ void betree_free_err(struct err_t *err);
  */
 void betree_free_name_iter(struct name_iter_t *name_iter);
 
+/**
+ * Free an object iterator.
+ */
+void betree_free_obj_iter(struct obj_iter_t *obj_iter);
+
 /**
  * Free a range iterator.
  */
 void betree_free_range_iter(struct range_iter_t *range_iter);
 
+/**
+ * Free a snapshot information iterator.
+ */
+void betree_free_snapshot_iter(struct snapshot_iter_t *snapshot_iter);
+
 /**
  * Enable the global env_logger, configured via environment variables.
  */
@@ -405,6 +601,17 @@ struct name_iter_t *betree_iter_snapshots(const struct db_t *db,
                                           const struct ds_t *ds,
                                           struct err_t **err);
 
+/**
+ * List all snapshots of a data set along with their space usage.
+ *
+ * On success, return a `snapshot_iter_t` which has to be freed with
+ * `betree_free_snapshot_iter`. On error, return null.  If `err` is not null,
+ * store an error in `err`.
+ */
+struct snapshot_iter_t *betree_list_snapshots(const struct db_t *db,
+                                              const struct ds_t *ds,
+                                              struct err_t **err);
+
 /**
  * Save the next item in the iterator in `name`.
  *
@@ -418,6 +625,19 @@ int betree_name_iter_next(struct name_iter_t *name_iter,
                           struct byte_slice_t *name,
                           struct err_t **err);
 
+/**
+ * Save the key and information of the next object in the iterator.
+ *
+ * On success, return 0.  If there are no more objects, return -1.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ *
+ * Note that on success `key` has to be freed with `betree_free_byte_slice`.
+ */
+int betree_obj_iter_next(struct obj_iter_t *obj_iter,
+                         struct byte_slice_t *key,
+                         struct obj_info_t *info,
+                         struct err_t **err);
+
 /**
  * Closes an object. The handle may not be used afterwards.
  */
@@ -437,6 +657,39 @@ struct obj_t *betree_object_create(struct obj_store_t *os,
  */
 int betree_object_delete(struct obj_t *obj, struct err_t **err);
 
+/**
+ * Save the size, modification time and storage preference of `obj` in
+ * `info`.
+ *
+ * On success, return 0.  If the object does not exist anymore, return -1.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_object_info(const struct obj_t *obj, struct obj_info_t *info, struct err_t **err);
+
+/**
+ * Migrate all data of `obj` to the given storage preference, which is also
+ * used for future writes through this handle.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_object_migrate(struct obj_t *obj,
+                          struct storage_pref_t storage_pref,
+                          struct err_t **err);
+
+/**
+ * Migrate `len` bytes of `obj`, starting at `offset` bytes into the objects
+ * data, to the given storage preference.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_object_migrate_range(const struct obj_t *obj,
+                                unsigned long offset,
+                                unsigned long len,
+                                struct storage_pref_t storage_pref,
+                                struct err_t **err);
+
 /**
  * Open an existing object.
  */
@@ -467,6 +720,41 @@ int betree_object_read_at(struct obj_t *obj,
                           unsigned long *n_read,
                           struct err_t **err);
 
+/**
+ * Rename the object `old_key` to `new_key`.  An existing object `new_key`
+ * is only replaced if `overwrite` is not 0.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_object_rename(const struct obj_store_t *os,
+                         const char *old_key,
+                         unsigned int old_key_len,
+                         const char *new_key,
+                         unsigned int new_key_len,
+                         int overwrite,
+                         struct err_t **err);
+
+/**
+ * Iterate over all objects whose key starts with the given prefix.
+ *
+ * On success, return an `obj_iter_t` which has to be freed with
+ * `betree_free_obj_iter`. On error, return null.  If `err` is not null,
+ * store an error in `err`.
+ */
+struct obj_iter_t *betree_object_store_list(const struct obj_store_t *os,
+                                            const char *prefix,
+                                            unsigned int prefix_len,
+                                            struct err_t **err);
+
+/**
+ * Truncate or zero-extend `obj` to `len` bytes.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ */
+int betree_object_truncate(const struct obj_t *obj, unsigned long len, struct err_t **err);
+
 /**
  * Try to write `buf_len` bytes from `buf` into `obj`, starting at `offset` bytes into the objects
  * data.
@@ -509,6 +797,21 @@ struct ds_t *betree_open_ds(struct db_t *db,
  */
 struct db_t *betree_open_or_create_db(const struct cfg_t *cfg, struct err_t **err);
 
+/**
+ * Open the snapshot of the given data set with the given name.
+ *
+ * On success, return a `ss_t` which has to be freed with
+ * `betree_close_snapshot`.  On error, return null.  If `err` is not null,
+ * store an error in `err`.
+ *
+ * Note that a snapshot can only be opened once at a time.
+ */
+struct ss_t *betree_open_snapshot(const struct db_t *db,
+                                  struct ds_t *ds,
+                                  const char *name,
+                                  unsigned int len,
+                                  struct err_t **err);
+
 /**
  * Parse the configuration string for a storage pool.
  *
@@ -524,6 +827,19 @@ struct cfg_t *betree_parse_configuration(const char *const *cfg_strings,
  */
 void betree_print_error(struct err_t *err);
 
+/**
+ * Return the stable code classifying the given error, e.g. 5 if a storage
+ * class is out of space.  See `ErrorCode` for all codes.
+ */
+unsigned int betree_error_code(const struct err_t *err);
+
+/**
+ * Save the description of the given error in `msg`.
+ *
+ * Note that `msg` has to be freed with `betree_free_byte_slice`.
+ */
+void betree_error_message(const struct err_t *err, struct byte_slice_t *msg);
+
 /**
  * Save the next key-value pair in the iterator.
  *
@@ -538,6 +854,20 @@ int betree_range_iter_next(struct range_iter_t *range_iter,
                            struct byte_slice_t *value,
                            struct err_t **err);
 
+/**
+ * Roll the given data set back to the snapshot with the given name.
+ *
+ * On success, return 0.
+ * On error, return -1.  If `err` is not null, store an error in `err`.
+ *
+ * Note that the snapshot and all later snapshots are kept.
+ */
+int betree_rollback_snapshot(const struct db_t *db,
+                             struct ds_t *ds,
+                             const char *name,
+                             unsigned int len,
+                             struct err_t **err);
+
 /**
  * Retrieve the `value` for the given `key`.
  *
@@ -552,6 +882,16 @@ int betree_snapshot_get(const struct ss_t *ss,
                         struct byte_slice_t *value,
                         struct err_t **err);
 
+/**
+ * Save the information about the next snapshot in the iterator.
+ *
+ * On success, return 0.  If there are no more snapshots, return -1.
+ *
+ * Note that on success the `name` of `info` has to be freed with
+ * `betree_free_byte_slice`.
+ */
+int betree_snapshot_iter_next(struct snapshot_iter_t *snapshot_iter, struct snapshot_info_t *info);
+
 /**
  * Iterate over all key-value pairs in the given key range.
  * `low_key` is inclusive, `high_key` is exclusive.
@@ -567,6 +907,18 @@ struct range_iter_t *betree_snapshot_range(const struct ss_t *ss,
                                            unsigned int high_key_len,
                                            struct err_t **err);
 
+/**
+ * Return the number of storage classes of the database.
+ */
+unsigned int betree_storage_class_count(const struct db_t *db);
+
+/**
+ * Save the occupancy of the given storage class in `info`.
+ *
+ * On success, return 0.  If the storage class does not exist, return -1.
+ */
+int betree_storage_info(const struct db_t *db, uint8_t class, struct storage_info_t *info);
+
 /**
  * Sync a database.
  *
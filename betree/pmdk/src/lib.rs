@@ -99,22 +99,46 @@ impl PMem {
 
     /// Write a range of bytes to the specified offset.
     ///
-    /// By default, we always perform a persisting write here, equivalent to a
-    /// direct & sync in traditional interfaces.
+    /// On actual persistent memory the data is written with non-temporal
+    /// stores, which bypass the cache, but without a final fence. The write is
+    /// only guaranteed to be persistent after a call to [PMem::persist].
     ///
     /// # Safety
     /// It is possible to issue multiple write requests to the same area at the
     /// same time. What happens then is undefined and might lead to
     /// inconsistencies.
     pub unsafe fn write(&self, offset: usize, data: &[u8]) {
+        let flags = if self.actually_pmem {
+            PMEM_F_MEM_NONTEMPORAL | PMEM_F_MEM_NODRAIN
+        } else {
+            // Mappings of regular files are persisted with msync, flushing
+            // single cache lines is of no use there.
+            PMEM_F_MEM_NOFLUSH
+        };
         let _ = pmem_memcpy(
             self.ptr.as_ptr().add(offset),
             data.as_ptr() as *mut c_void,
             data.len(),
-            PMEM_F_MEM_NONTEMPORAL,
+            flags,
         );
     }
 
+    /// Makes all previous writes persistent.
+    ///
+    /// On actual persistent memory this waits for all flushes and
+    /// non-temporal stores to complete, otherwise the whole mapping is synced
+    /// to the underlying file.
+    pub fn persist(&self) -> Result<(), std::io::Error> {
+        if self.actually_pmem {
+            unsafe { pmem_drain() };
+            Ok(())
+        } else if unsafe { pmem_msync(self.ptr.as_ptr(), self.len) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
     /// Returns whether or not the underlying storage is either fsdax or devdax.
     pub fn is_pmem(&self) -> bool {
         self.actually_pmem
@@ -194,6 +218,7 @@ mod tests {
                 pmem.write(0, &buf);
                 pmem.write(buf.len(), &offseted);
             }
+            pmem.persist().unwrap();
         }
         {
             let pmem = PMem::open(file.path()).unwrap();
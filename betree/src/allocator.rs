@@ -1,9 +1,14 @@
 //! This module provides `SegmentAllocator` and `SegmentId` for bitmap
 //! allocation of 1GiB segments.
+//!
+//! Allocations larger than the free space of a single segment are satisfied
+//! by contiguous [Extent]s spanning several consecutive segments, the part in
+//! each segment is recorded in the bitmap of this segment.
 
 use crate::{cow_bytes::CowBytes, storage_pool::DiskOffset, vdev::Block, Error};
 use bitvec::prelude::*;
 use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
 use std::io::Write;
 
 /// 256KiB, so that `vdev::BLOCK_SIZE * SEGMENT_SIZE == 1GiB`
@@ -13,17 +18,44 @@ pub const SEGMENT_SIZE_BYTES: usize = SEGMENT_SIZE / 8;
 const SEGMENT_SIZE_LOG_2: usize = 18;
 const SEGMENT_SIZE_MASK: usize = SEGMENT_SIZE - 1;
 
-/// Simple first-fit bitmap allocator
+/// The strategy by which a [SegmentAllocator] places allocations in the free
+/// ranges of a segment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AllocatorType {
+    /// Use the first free range which is large enough, the fastest strategy.
+    #[default]
+    FirstFit,
+    /// Use the smallest free range which is large enough, which keeps large
+    /// free ranges intact.
+    BestFit,
+    /// Use the largest free range, which leaves usable remainders.
+    WorstFit,
+    /// Place allocations at offsets aligned to their size rounded up to the
+    /// next power of two, like a buddy allocator. Only the requested size is
+    /// occupied, so that the remainder stays usable.
+    Buddy,
+}
+
+/// Bitmap allocator of a single segment
 pub struct SegmentAllocator {
     data: BitArr!(for SEGMENT_SIZE, in u8, Lsb0),
+    allocator_type: AllocatorType,
 }
 
 impl SegmentAllocator {
-    /// Constructs a new `SegmentAllocator` given the segment allocation bitmap.
+    /// Constructs a new first-fit `SegmentAllocator` given the segment
+    /// allocation bitmap.
     /// The `bitmap` must have a length of `SEGMENT_SIZE`.
     pub fn new(bitmap: [u8; SEGMENT_SIZE_BYTES]) -> Self {
+        Self::with_type(bitmap, AllocatorType::FirstFit)
+    }
+
+    /// Constructs a new `SegmentAllocator` given the segment allocation bitmap
+    /// and the strategy to place allocations with.
+    pub fn with_type(bitmap: [u8; SEGMENT_SIZE_BYTES], allocator_type: AllocatorType) -> Self {
         SegmentAllocator {
             data: BitArray::new(bitmap),
+            allocator_type,
         }
     }
 
@@ -33,6 +65,61 @@ impl SegmentAllocator {
         if size == 0 {
             return Some(0);
         }
+        let offset = match self.allocator_type {
+            AllocatorType::FirstFit => self.first_fit(size)?,
+            AllocatorType::BestFit => {
+                self.free_ranges()
+                    .filter(|&(_, len)| len >= size)
+                    .min_by_key(|&(_, len)| len)?
+                    .0
+            }
+            AllocatorType::WorstFit => {
+                self.free_ranges()
+                    .filter(|&(_, len)| len >= size)
+                    .max_by_key(|&(_, len)| len)?
+                    .0
+            }
+            AllocatorType::Buddy => {
+                let align = size.checked_next_power_of_two()?;
+                self.free_ranges().find_map(|(offset, len)| {
+                    let aligned = (offset + align - 1) / align * align;
+                    (aligned + size <= offset + len).then(|| aligned)
+                })?
+            }
+        };
+        self.mark(offset, size, Action::Allocate);
+        Some(offset)
+    }
+
+    /// Allocates a block of the given `size` at the first free position at or
+    /// after `near`, to place it close to related blocks.
+    /// Returns `None` if there is no such position in the segment.
+    pub fn allocate_near(&mut self, size: u32, near: u32) -> Option<u32> {
+        if size == 0 {
+            return Some(0);
+        }
+        let offset = self.free_ranges().find_map(|(offset, len)| {
+            let start = offset.max(near);
+            (start + size <= offset + len).then(|| start)
+        })?;
+        self.mark(offset, size, Action::Allocate);
+        Some(offset)
+    }
+
+    /// Iterates over the free ranges of the segment as offset and length.
+    pub fn free_ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let mut idx = 0;
+        std::iter::from_fn(move || {
+            let start = idx + self.data[idx..].first_zero()?;
+            let len = self.data[start..]
+                .first_one()
+                .unwrap_or(SEGMENT_SIZE - start);
+            idx = start + len;
+            Some((start as u32, len as u32))
+        })
+    }
+
+    fn first_fit(&self, size: u32) -> Option<u32> {
         let offset = {
             let mut idx = 0;
             loop {
@@ -55,8 +142,7 @@ impl SegmentAllocator {
                 }
             }
         };
-        self.mark(offset, size, Action::Allocate);
-        return Some(offset);
+        Some(offset)
     }
 
     /// Allocates a block of the given `size` at `offset`.
@@ -78,6 +164,38 @@ impl SegmentAllocator {
         true
     }
 
+    /// Overwrites the allocation state of the blocks whose bit is set in the
+    /// raw bitmap `mask` with the corresponding bit of `value`.
+    pub fn apply_update(&mut self, mask: &[u8], value: &[u8]) {
+        let data = self.data.as_raw_mut_slice();
+        assert!(mask.len() == data.len() && value.len() == data.len());
+        for ((data, mask), value) in data.iter_mut().zip(mask).zip(value) {
+            *data = (*data & !mask) | (value & mask);
+        }
+    }
+
+    /// Returns the number of free blocks at the start of the segment.
+    pub fn free_prefix(&self) -> u32 {
+        self.data.first_one().unwrap_or(SEGMENT_SIZE) as u32
+    }
+
+    /// Returns the number of free blocks at the end of the segment.
+    pub fn free_suffix(&self) -> u32 {
+        self.data
+            .last_one()
+            .map_or(SEGMENT_SIZE, |idx| SEGMENT_SIZE - idx - 1) as u32
+    }
+
+    /// Returns the number of allocated blocks.
+    pub fn allocated(&self) -> u32 {
+        self.data.count_ones() as u32
+    }
+
+    /// Returns the length of the largest free range of the segment.
+    pub fn largest_free_range(&self) -> u32 {
+        self.free_ranges().map(|(_, len)| len).max().unwrap_or(0)
+    }
+
     /// Deallocates the allocated block.
     pub fn deallocate(&mut self, offset: u32, size: u32) {
         log::debug!(
@@ -184,6 +302,45 @@ impl SegmentId {
     }
 }
 
+/// A contiguous range of blocks on a disk, which may span several segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// The first block of the extent.
+    pub offset: DiskOffset,
+    /// The number of blocks of the extent.
+    pub size: Block<u32>,
+}
+
+impl Extent {
+    /// Returns the extent of `size` blocks starting at `offset`.
+    pub fn new(offset: DiskOffset, size: Block<u32>) -> Self {
+        Extent { offset, size }
+    }
+
+    /// Splits the extent into the parts lying in a single segment, given as
+    /// the segment, the block offset into the segment and the number of
+    /// blocks.
+    pub fn segments(&self) -> impl Iterator<Item = (SegmentId, u32, u32)> {
+        let mut offset = self.offset.as_u64();
+        let end = offset + self.size.as_u64();
+        std::iter::from_fn(move || {
+            if offset >= end {
+                return None;
+            }
+            let segment_id = SegmentId(offset & !(SEGMENT_SIZE_MASK as u64));
+            let segment_offset = (offset & SEGMENT_SIZE_MASK as u64) as u32;
+            let len = (end - offset).min(u64::from(SEGMENT_SIZE as u32 - segment_offset)) as u32;
+            offset += u64::from(len);
+            Some((segment_id, segment_offset, len))
+        })
+    }
+
+    /// Returns whether the extent spans more than one segment.
+    pub fn is_multi_segment(&self) -> bool {
+        self.segments().nth(1).is_some()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -201,4 +358,80 @@ mod tests {
         );
         assert_eq!(SegmentId::get_block_offset(offset), 1);
     }
+
+    fn allocator_with_holes(allocator_type: AllocatorType) -> SegmentAllocator {
+        let mut allocator = SegmentAllocator::with_type([0; SEGMENT_SIZE_BYTES], allocator_type);
+        // Free ranges of 10 blocks at 0, 4 blocks at 20 and 8 blocks at 30
+        // before the remainder of the segment at 40.
+        assert!(allocator.allocate_at(10, 10));
+        assert!(allocator.allocate_at(6, 24));
+        assert!(allocator.allocate_at(2, 38));
+        allocator
+    }
+
+    #[test]
+    fn allocator_types() {
+        let mut first_fit = allocator_with_holes(AllocatorType::FirstFit);
+        assert_eq!(first_fit.allocate(4), Some(0));
+        let mut best_fit = allocator_with_holes(AllocatorType::BestFit);
+        assert_eq!(best_fit.allocate(4), Some(20));
+        assert_eq!(best_fit.allocate(5), Some(30));
+        let mut worst_fit = allocator_with_holes(AllocatorType::WorstFit);
+        assert_eq!(worst_fit.allocate(4), Some(40));
+        let mut buddy = allocator_with_holes(AllocatorType::Buddy);
+        assert_eq!(buddy.allocate(3), Some(0));
+        assert_eq!(buddy.allocate(8), Some(40));
+        assert_eq!(buddy.allocate(4), Some(4));
+        assert_eq!(buddy.allocate(4), Some(20));
+        assert_eq!(buddy.allocate(4), Some(32));
+    }
+
+    #[test]
+    fn allocate_near() {
+        let mut allocator = allocator_with_holes(AllocatorType::BestFit);
+        assert_eq!(allocator.allocate_near(4, 16), Some(20));
+        assert_eq!(allocator.allocate_near(4, 30), Some(30));
+        assert_eq!(allocator.allocate_near(8, 30), Some(40));
+        assert_eq!(allocator.allocate_near(2, 5), Some(5));
+        assert_eq!(allocator.allocate_near(1, SEGMENT_SIZE as u32), None);
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut allocator = allocator_with_holes(AllocatorType::FirstFit);
+        assert_eq!(allocator.allocated(), 18);
+        assert_eq!(allocator.largest_free_range(), SEGMENT_SIZE as u32 - 40);
+        assert!(allocator.allocate_at(SEGMENT_SIZE as u32 - 40, 40));
+        assert_eq!(allocator.largest_free_range(), 10);
+    }
+
+    #[test]
+    fn free_prefix_and_suffix() {
+        let mut allocator = SegmentAllocator::new([0; SEGMENT_SIZE_BYTES]);
+        assert_eq!(allocator.free_prefix(), SEGMENT_SIZE as u32);
+        assert_eq!(allocator.free_suffix(), SEGMENT_SIZE as u32);
+        assert!(allocator.allocate_at(10, 100));
+        assert_eq!(allocator.free_prefix(), 100);
+        assert_eq!(allocator.free_suffix(), SEGMENT_SIZE as u32 - 110);
+    }
+
+    #[test]
+    fn extent_segments() {
+        let segment = SegmentId::get(DiskOffset::new(0, 3, Block(0)));
+        let offset = segment.disk_offset(SEGMENT_SIZE as u32 - 4);
+        let extent = Extent::new(offset, Block(2 * SEGMENT_SIZE as u32 + 6));
+        assert!(extent.is_multi_segment());
+        let parts: Vec<_> = extent.segments().collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], (segment, SEGMENT_SIZE as u32 - 4, 4));
+        assert_eq!(parts[1].1, 0);
+        assert_eq!(parts[1].2, SEGMENT_SIZE as u32);
+        assert_eq!(parts[2].1, 0);
+        assert_eq!(parts[2].2, 2);
+        assert_eq!(parts[2].0.disk_id(), 3);
+
+        let small = Extent::new(segment.disk_offset(8), Block(16));
+        assert!(!small.is_multi_segment());
+        assert_eq!(small.segments().collect::<Vec<_>>(), vec![(segment, 8, 16)]);
+    }
 }
@@ -8,8 +8,12 @@
 //! a growable buffer.
 //!
 //! [MutBuf] does not support growing with [io::Write] because the semantics of growing an inner split buffer are unclear.
+//!
+//! Large buffers can be backed by huge pages, see [BufferAllocation].
 
 use crate::vdev::{Block, BLOCK_SIZE};
+use parking_lot::{const_mutex, Mutex};
+use serde::{Deserialize, Serialize};
 use std::{
     alloc::{self, Layout},
     cell::UnsafeCell,
@@ -18,12 +22,133 @@ use std::{
     ops::{Deref, Range},
     ptr::NonNull,
     slice,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 const MIN_GROWTH_SIZE: Block<u32> = Block(1);
 const GROWTH_FACTOR: f32 = 1.0;
 
+/// The size of a huge page.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+static HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// How the memory of buffers is allocated, e.g. of nodes held in the cache
+/// and of the data read from and written to vdevs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BufferAllocation {
+    /// Allocate all buffers with the global allocator.
+    #[default]
+    Default,
+    /// Back buffers of at least [HUGE_PAGE_SIZE] bytes with huge pages to
+    /// reduce the TLB pressure of large caches.  Their size is rounded up to
+    /// whole huge pages.  If no huge pages are available, e.g. because none
+    /// have been reserved, the global allocator is used instead.
+    HugePages,
+}
+
+// The allocation of the open databases and their number.
+static OPEN_ALLOCATION: Mutex<(BufferAllocation, usize)> =
+    const_mutex((BufferAllocation::Default, 0));
+
+// Sets how buffers allocated from now on are backed.
+fn set_allocation(allocation: BufferAllocation) {
+    HUGE_PAGES.store(allocation == BufferAllocation::HugePages, Ordering::Relaxed);
+}
+
+/// Keeps the buffer allocation of a database set until it is dropped, see
+/// [acquire_allocation].
+#[derive(Debug)]
+pub(crate) struct AllocationGuard(());
+
+impl Drop for AllocationGuard {
+    fn drop(&mut self) {
+        OPEN_ALLOCATION.lock().1 -= 1;
+    }
+}
+
+/// Sets how buffers are backed for a database which is opened.  The
+/// allocation applies to all buffers of the process, so databases which are
+/// open at the same time have to agree on it.  Returns the allocation of the
+/// open databases if it differs from `allocation`.
+pub(crate) fn acquire_allocation(
+    allocation: BufferAllocation,
+) -> Result<AllocationGuard, BufferAllocation> {
+    let mut open = OPEN_ALLOCATION.lock();
+    if open.1 > 0 && open.0 != allocation {
+        return Err(open.0);
+    }
+    *open = (allocation, open.1 + 1);
+    set_allocation(allocation);
+    Ok(AllocationGuard(()))
+}
+
+// Rounds `size` up to whole huge pages.
+fn huge_page_len(size: usize) -> usize {
+    (size + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE
+}
+
+// Maps `size` zeroed bytes on huge pages, if any are available.
+#[cfg(target_os = "linux")]
+fn map_huge_pages(size: usize) -> Option<NonNull<u8>> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            huge_page_len(size),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        log::debug!(
+            "No huge pages available for {} bytes: {}",
+            size,
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    NonNull::new(ptr as *mut u8)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_huge_pages(_size: usize) -> Option<NonNull<u8>> {
+    None
+}
+
+// Allocates `size` zeroed bytes, on huge pages if enabled and `size` spans at
+// least one of them.  Returns whether huge pages are used.
+fn allocate_zeroed(size: usize) -> (NonNull<u8>, bool) {
+    if size >= HUGE_PAGE_SIZE && HUGE_PAGES.load(Ordering::Relaxed) {
+        if let Some(ptr) = map_huge_pages(size) {
+            return (ptr, true);
+        }
+    }
+    let ptr = unsafe {
+        let layout = Layout::from_size_align_unchecked(size, BLOCK_SIZE);
+        NonNull::new(alloc::alloc_zeroed(layout)).expect("Allocation failed.")
+    };
+    (ptr, false)
+}
+
+// Frees `size` bytes allocated by `allocate_zeroed`.
+unsafe fn deallocate(ptr: NonNull<u8>, size: usize, huge: bool) {
+    #[cfg(target_os = "linux")]
+    if huge {
+        libc::munmap(ptr.as_ptr() as *mut libc::c_void, huge_page_len(size));
+        return;
+    }
+    debug_assert!(!huge);
+    alloc::dealloc(
+        ptr.as_ptr(),
+        Layout::from_size_align_unchecked(size, BLOCK_SIZE),
+    )
+}
+
 fn is_aligned(buf: &[u8]) -> bool {
     buf.as_ptr() as usize % BLOCK_SIZE == 0 && buf.len() % BLOCK_SIZE == 0
 }
@@ -57,6 +182,9 @@ fn split_range_at(
 struct AlignedStorage {
     ptr: NonNull<u8>,
     capacity: Block<u32>,
+    // Whether `ptr` has been mapped on huge pages instead of being allocated
+    // with the global allocator.
+    huge: bool,
 }
 
 // impl Default for AlignedStorage {
@@ -70,13 +198,11 @@ struct AlignedStorage {
 
 impl AlignedStorage {
     fn zeroed(capacity: Block<u32>) -> Self {
+        let (ptr, huge) = allocate_zeroed(capacity.to_bytes() as usize);
         Self {
-            ptr: unsafe {
-                let new_layout =
-                    Layout::from_size_align_unchecked(capacity.to_bytes() as usize, BLOCK_SIZE);
-                NonNull::new(alloc::alloc_zeroed(new_layout)).expect("Allocation failed.")
-            },
+            ptr,
             capacity,
+            huge,
         }
     }
 
@@ -98,6 +224,23 @@ impl AlignedStorage {
             );
         }
 
+        let huge_pages = wanted_capacity.to_bytes() as usize >= HUGE_PAGE_SIZE
+            && HUGE_PAGES.load(Ordering::Relaxed);
+        if self.huge || huge_pages {
+            // Memory mapped on huge pages cannot be reallocated.
+            let (new_ptr, huge) = allocate_zeroed(wanted_capacity.to_bytes() as usize);
+            unsafe {
+                self.ptr
+                    .as_ptr()
+                    .copy_to_nonoverlapping(new_ptr.as_ptr(), self.capacity.to_bytes() as usize);
+                deallocate(self.ptr, self.capacity.to_bytes() as usize, self.huge);
+            }
+            self.ptr = new_ptr;
+            self.huge = huge;
+            self.capacity = wanted_capacity;
+            return;
+        }
+
         unsafe {
             let curr_layout =
                 Layout::from_size_align_unchecked(self.capacity.to_bytes() as usize, BLOCK_SIZE);
@@ -129,11 +272,7 @@ impl AlignedStorage {
 
 impl Drop for AlignedStorage {
     fn drop(&mut self) {
-        unsafe {
-            let layout =
-                Layout::from_size_align_unchecked(self.capacity.to_bytes() as usize, BLOCK_SIZE);
-            alloc::dealloc(self.ptr.as_ptr(), layout)
-        }
+        unsafe { deallocate(self.ptr, self.capacity.to_bytes() as usize, self.huge) }
     }
 }
 
@@ -148,6 +287,7 @@ impl From<Box<[u8]>> for AlignedStorage {
                 ptr: unsafe {
                     NonNull::new((*Box::into_raw(b)).as_mut_ptr()).expect("Assume valid pointer.")
                 },
+                huge: false,
             }
         } else {
             assert!(
@@ -233,6 +373,10 @@ pub struct Buf {
     range: Range<Block<u32>>,
 }
 
+// A Buf only grants read access to its range, which is not mutated while the
+// Buf exists since conversion into a MutBuf or BufWrite requires uniqueness.
+unsafe impl Sync for Buf {}
+
 impl fmt::Debug for Buf {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Buf").field("range", &self.range).finish()
@@ -402,14 +546,17 @@ impl Buf {
         }
     }
 
-    /// If this [Buf] is unique, return its backing buffer without reallocation or copying.
-    /// Panics if this [Buf] was not unique.
+    /// If this [Buf] is unique, return its backing buffer without reallocation or copying,
+    /// unless it is backed by huge pages.  Panics if this [Buf] was not unique.
     pub fn into_boxed_slice(self) -> Box<[u8]> {
-        let storage = ManuallyDrop::new(
-            Arc::try_unwrap(self.buf.buf)
-                .expect("AlignedBuf was not unique")
-                .into_inner(),
-        );
+        let storage = Arc::try_unwrap(self.buf.buf)
+            .expect("AlignedBuf was not unique")
+            .into_inner();
+        if storage.huge {
+            let len = storage.capacity.to_bytes() as usize;
+            return unsafe { slice::from_raw_parts(storage.ptr.as_ptr(), len) }.into();
+        }
+        let storage = ManuallyDrop::new(storage);
 
         unsafe {
             Box::from_raw(slice::from_raw_parts_mut(
@@ -549,4 +696,20 @@ mod tests {
         assert!(right.size() == Block(0));
         assert!(left[0] == 2);
     }
+
+    #[test]
+    fn huge_pages() {
+        // Falls back to the global allocator if no huge pages are reserved.
+        set_allocation(BufferAllocation::HugePages);
+        let mut buf = BufWrite::with_capacity(Block(1));
+        let data = (0..HUGE_PAGE_SIZE + BLOCK_SIZE)
+            .map(|idx| idx as u8)
+            .collect::<Vec<_>>();
+        io::Write::write_all(&mut buf, &data).unwrap();
+        let buf = buf.into_buf();
+        assert_eq!(&buf[..data.len()], &data[..]);
+        let b = buf.into_boxed_slice();
+        assert_eq!(&b[..data.len()], &data[..]);
+        set_allocation(BufferAllocation::Default);
+    }
 }
@@ -12,14 +12,15 @@ use std::{
     ptr::{null_mut, read, write},
     slice::{from_raw_parts, from_raw_parts_mut},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use libc::{c_void, memcpy};
 
 use crate::{
     cow_bytes::{CowBytes, SlicedCowBytes},
-    database::{AccessMode, Database, Dataset, Error, Snapshot},
-    object::{ObjectHandle, ObjectStore},
+    database::{AccessMode, Database, Dataset, Error, ErrorCode, Snapshot, SnapshotInfo},
+    object::{ObjectHandle, ObjectInfo, ObjectStore},
     storage_pool::{LeafVdev, StoragePoolConfiguration, TierConfiguration, Vdev},
     tree::DefaultMessageAction,
     DatabaseConfiguration, StoragePreference,
@@ -43,15 +44,51 @@ pub struct name_iter_t(Box<dyn Iterator<Item = Result<SlicedCowBytes, Error>>>);
 /// The range iterator type
 pub struct range_iter_t(Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes), Error>>>);
 
+/// The snapshot information iterator type
+pub struct snapshot_iter_t(std::vec::IntoIter<SnapshotInfo>);
+
 /// The object store wrapper type
 pub struct obj_store_t(ObjectStore);
 /// The handle of an object in the corresponding object store
 pub struct obj_t<'os>(ObjectHandle<'os>);
+/// The object iterator type
+pub struct obj_iter_t(Box<dyn Iterator<Item = Result<(CowBytes, ObjectInfo), Error>>>);
 
 pub const STORAGE_PREF_NONE: storage_pref_t = storage_pref_t(StoragePreference::NONE);
 pub const STORAGE_PREF_FASTEST: storage_pref_t = storage_pref_t(StoragePreference::FASTEST);
 pub const STORAGE_PREF_SLOWEST: storage_pref_t = storage_pref_t(StoragePreference::SLOWEST);
 
+/// None of the other error codes applies
+pub const BETREE_ERR_OTHER: c_uint = ErrorCode::Other as c_uint;
+/// Reading from or writing to a storage device failed
+pub const BETREE_ERR_IO: c_uint = ErrorCode::Io as c_uint;
+/// Data read from a storage device does not match its checksum
+pub const BETREE_ERR_CHECKSUM: c_uint = ErrorCode::Checksum as c_uint;
+/// Stored data could not be decoded or decrypted
+pub const BETREE_ERR_CORRUPTED: c_uint = ErrorCode::Corrupted as c_uint;
+/// A storage class does not have enough free space
+pub const BETREE_ERR_OUT_OF_SPACE: c_uint = ErrorCode::OutOfSpace as c_uint;
+/// The key, data set, snapshot, or object does not exist
+pub const BETREE_ERR_DOES_NOT_EXIST: c_uint = ErrorCode::DoesNotExist as c_uint;
+/// A data set or snapshot of the same name already exists
+pub const BETREE_ERR_ALREADY_EXISTS: c_uint = ErrorCode::AlreadyExists as c_uint;
+/// The data set, snapshot, or vdev is still in use
+pub const BETREE_ERR_IN_USE: c_uint = ErrorCode::InUse as c_uint;
+/// The data set has been closed
+pub const BETREE_ERR_CLOSED: c_uint = ErrorCode::Closed as c_uint;
+/// An argument is not valid, e.g. a key is too large
+pub const BETREE_ERR_INVALID_ARGUMENT: c_uint = ErrorCode::InvalidArgument as c_uint;
+/// The configuration is not valid or does not match the stored data
+pub const BETREE_ERR_CONFIGURATION: c_uint = ErrorCode::Configuration as c_uint;
+/// The key is pinned to a storage class
+pub const BETREE_ERR_PINNED: c_uint = ErrorCode::Pinned as c_uint;
+/// An asynchronous operation has been canceled
+pub const BETREE_ERR_CANCELED: c_uint = ErrorCode::Canceled as c_uint;
+/// An internal error
+pub const BETREE_ERR_INTERNAL: c_uint = ErrorCode::Internal as c_uint;
+/// A transaction conflicts with a concurrent modification
+pub const BETREE_ERR_CONFLICT: c_uint = ErrorCode::Conflict as c_uint;
+
 /// A reference counted byte slice
 #[repr(C)]
 pub struct byte_slice_t {
@@ -84,6 +121,57 @@ impl Drop for byte_slice_t {
     }
 }
 
+/// Information about a snapshot
+#[repr(C)]
+pub struct snapshot_info_t {
+    /// The name of the snapshot
+    name: byte_slice_t,
+    /// The generation of the data set captured by the snapshot
+    generation: u64,
+    /// The time of creation in microseconds since the Unix epoch, 0 if unknown
+    created_us: u64,
+    /// Blocks referenced by the snapshot which are no longer part of the data set
+    referenced_blocks: u64,
+    /// Blocks which would be freed by deleting the snapshot
+    unique_blocks: u64,
+}
+
+/// Information about an object
+#[repr(C)]
+pub struct obj_info_t {
+    /// The size of the object in bytes
+    size: u64,
+    /// The last modification time in microseconds since the Unix epoch
+    mtime_us: u64,
+    /// The most recently used storage preference
+    pref: storage_pref_t,
+}
+
+impl From<ObjectInfo> for obj_info_t {
+    fn from(x: ObjectInfo) -> Self {
+        obj_info_t {
+            size: x.size,
+            mtime_us: micros_since_epoch(x.mtime),
+            pref: storage_pref_t(x.pref),
+        }
+    }
+}
+
+/// The occupancy of a storage class
+#[repr(C)]
+pub struct storage_info_t {
+    /// Remaining free storage in blocks
+    free_blocks: u64,
+    /// Total storage in blocks
+    total_blocks: u64,
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
 /// A byte slice reference counter
 // Intentionally not #[repr(C)], or cbindgen will expose Vec internals
 struct byte_slice_rc_t(Vec<u8>);
@@ -184,6 +272,16 @@ impl<'os> HandleResult for ObjectHandle<'os> {
     }
 }
 
+impl HandleResult for Box<dyn Iterator<Item = Result<(CowBytes, ObjectInfo), Error>>> {
+    type Result = *mut obj_iter_t;
+    fn success(self) -> *mut obj_iter_t {
+        b(obj_iter_t(self))
+    }
+    fn fail() -> *mut obj_iter_t {
+        null_mut()
+    }
+}
+
 trait HandleResultExt {
     type Result;
     fn handle_result(self, err: *mut *mut err_t) -> Self::Result;
@@ -264,7 +362,10 @@ pub unsafe extern "C" fn betree_configuration_from_env(err: *mut *mut err_t) ->
         Some(val) => val,
         None => {
             handle_err(
-                Error::Generic("Environment variable BETREE_CONFIG is not defined.".into()),
+                Error::InvalidConfiguration {
+                    field: "BETREE_CONFIG".into(),
+                    reason: "the environment variable is not defined".into(),
+                },
                 err,
             );
             return null_mut();
@@ -313,6 +414,18 @@ pub unsafe extern "C" fn betree_free_range_iter(range_iter: *mut range_iter_t) {
     let _ = Box::from_raw(range_iter);
 }
 
+/// Free a snapshot information iterator.
+#[no_mangle]
+pub unsafe extern "C" fn betree_free_snapshot_iter(snapshot_iter: *mut snapshot_iter_t) {
+    let _ = Box::from_raw(snapshot_iter);
+}
+
+/// Free an object iterator.
+#[no_mangle]
+pub unsafe extern "C" fn betree_free_obj_iter(obj_iter: *mut obj_iter_t) {
+    let _ = Box::from_raw(obj_iter);
+}
+
 fn set_leaf_vdev_direct(leaf: &mut LeafVdev) {
     if let LeafVdev::FileWithOpts { direct, .. } = leaf {
         *direct = Some(true)
@@ -327,7 +440,7 @@ pub unsafe extern "C" fn betree_configuration_set_direct(cfg: *mut cfg_t, direct
             for vdev in tier.top_level_vdevs.iter_mut() {
                 match vdev {
                     crate::storage_pool::Vdev::Leaf(ref mut l) => set_leaf_vdev_direct(l),
-                    crate::storage_pool::Vdev::Mirror { ref mut mirror } => {
+                    crate::storage_pool::Vdev::Mirror { ref mut mirror, .. } => {
                         for l in mirror.iter_mut() {
                             set_leaf_vdev_direct(l)
                         }
@@ -337,6 +450,11 @@ pub unsafe extern "C" fn betree_configuration_set_direct(cfg: *mut cfg_t, direct
                             set_leaf_vdev_direct(l)
                         }
                     }
+                    crate::storage_pool::Vdev::Parity2 { ref mut parity2 } => {
+                        for l in parity2.iter_mut() {
+                            set_leaf_vdev_direct(l)
+                        }
+                    }
                 }
             }
         }
@@ -431,6 +549,38 @@ pub unsafe extern "C" fn betree_sync_db(db: *mut db_t, err: *mut *mut err_t) ->
     db.sync().handle_result(err)
 }
 
+/// Return the number of storage classes of the database.
+#[no_mangle]
+pub unsafe extern "C" fn betree_storage_class_count(db: *const db_t) -> c_uint {
+    let db = &(*db).0;
+    db.free_space_tier().len() as c_uint
+}
+
+/// Save the occupancy of the given storage class in `info`.
+///
+/// On success, return 0.  If the storage class does not exist, return -1.
+#[no_mangle]
+pub unsafe extern "C" fn betree_storage_info(
+    db: *const db_t,
+    class: u8,
+    info: *mut storage_info_t,
+) -> c_int {
+    let db = &(*db).0;
+    match db.free_space_tier().get(class as usize) {
+        None => -1,
+        Some(tier) => {
+            write(
+                info,
+                storage_info_t {
+                    free_blocks: tier.free.as_u64(),
+                    total_blocks: tier.total.as_u64(),
+                },
+            );
+            0
+        }
+    }
+}
+
 /// Closes a database.
 ///
 /// Note that the `db_t` may not be used afterwards.
@@ -626,6 +776,110 @@ pub unsafe extern "C" fn betree_iter_snapshots(
         .handle_result(err)
 }
 
+/// Open the snapshot of the given data set with the given name.
+///
+/// On success, return a `ss_t` which has to be freed with
+/// `betree_close_snapshot`.  On error, return null.  If `err` is not null,
+/// store an error in `err`.
+///
+/// Note that a snapshot can only be opened once at a time.
+#[no_mangle]
+pub unsafe extern "C" fn betree_open_snapshot(
+    db: *const db_t,
+    ds: *mut ds_t,
+    name: *const c_char,
+    len: c_uint,
+    err: *mut *mut err_t,
+) -> *mut ss_t {
+    let db = &(*db).0;
+    let ds = &mut (*ds).0;
+    let name = from_raw_parts(name as *const u8, len as usize);
+    db.open_snapshot(ds, name).handle_result(err)
+}
+
+/// Close a snapshot of the given data set.
+///
+/// Note that the `ss_t` may not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn betree_close_snapshot(db: *const db_t, ds: *mut ds_t, ss: *mut ss_t) {
+    let db = &(*db).0;
+    let ds = &mut (*ds).0;
+    let ss = Box::from_raw(ss).0;
+    db.close_snapshot(ds, ss)
+}
+
+/// List all snapshots of a data set along with their space usage.
+///
+/// On success, return a `snapshot_iter_t` which has to be freed with
+/// `betree_free_snapshot_iter`. On error, return null.  If `err` is not null,
+/// store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_list_snapshots(
+    db: *const db_t,
+    ds: *const ds_t,
+    err: *mut *mut err_t,
+) -> *mut snapshot_iter_t {
+    let db = &(*db).0;
+    let ds = &(*ds).0;
+    match db.list_snapshots(ds) {
+        Ok(snapshots) => b(snapshot_iter_t(snapshots.into_iter())),
+        Err(e) => {
+            handle_err(e, err);
+            null_mut()
+        }
+    }
+}
+
+/// Save the information about the next snapshot in the iterator.
+///
+/// On success, return 0.  If there are no more snapshots, return -1.
+///
+/// Note that on success the `name` of `info` has to be freed with
+/// `betree_free_byte_slice`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_snapshot_iter_next(
+    snapshot_iter: *mut snapshot_iter_t,
+    info: *mut snapshot_info_t,
+) -> c_int {
+    let snapshot_iter = &mut (*snapshot_iter).0;
+    match snapshot_iter.next() {
+        None => -1,
+        Some(snapshot) => {
+            write(
+                info,
+                snapshot_info_t {
+                    name: snapshot.name.into(),
+                    generation: snapshot.generation.as_u64(),
+                    created_us: snapshot.created.map_or(0, micros_since_epoch),
+                    referenced_blocks: snapshot.referenced.as_u64(),
+                    unique_blocks: snapshot.unique.as_u64(),
+                },
+            );
+            0
+        }
+    }
+}
+
+/// Roll the given data set back to the snapshot with the given name.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+///
+/// Note that the snapshot and all later snapshots are kept.
+#[no_mangle]
+pub unsafe extern "C" fn betree_rollback_snapshot(
+    db: *const db_t,
+    ds: *mut ds_t,
+    name: *const c_char,
+    len: c_uint,
+    err: *mut *mut err_t,
+) -> c_int {
+    let db = &(*db).0;
+    let ds = &mut (*ds).0;
+    let name = from_raw_parts(name as *const u8, len as usize);
+    db.rollback_snapshot(ds, name).handle_result(err)
+}
+
 /// Retrieve the `value` for the given `key`.
 ///
 /// On success, return 0.  If the key does not exist, return -1.
@@ -738,6 +992,46 @@ pub unsafe extern "C" fn betree_dataset_range_delete(
     ds.range_delete(low_key..high_key).handle_result(err)
 }
 
+/// Migrate the value for the given `key` to the given storage preference.
+///
+/// On success, return 0.  If the key does not exist, return -1.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_dataset_migrate(
+    ds: *const ds_t,
+    key: *const c_char,
+    len: c_uint,
+    storage_pref: storage_pref_t,
+    err: *mut *mut err_t,
+) -> c_int {
+    let ds = &(*ds).0;
+    let key = from_raw_parts(key as *const u8, len as usize);
+    ds.migrate(key, storage_pref.0).handle_result(err)
+}
+
+/// Migrate all key-value pairs in the given key range to the given storage
+/// preference.
+/// `low_key` is inclusive, `high_key` is exclusive.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_dataset_migrate_range(
+    ds: *const ds_t,
+    low_key: *const c_char,
+    low_key_len: c_uint,
+    high_key: *const c_char,
+    high_key_len: c_uint,
+    storage_pref: storage_pref_t,
+    err: *mut *mut err_t,
+) -> c_int {
+    let ds = &(*ds).0;
+    let low_key = from_raw_parts(low_key as *const u8, low_key_len as usize);
+    let high_key = from_raw_parts(high_key as *const u8, high_key_len as usize);
+    ds.migrate_range(low_key..high_key, storage_pref.0)
+        .handle_result(err)
+}
+
 /// Return the data set's name.
 #[no_mangle]
 pub unsafe extern "C" fn betree_dataset_name(ds: *const ds_t, len: *mut c_uint) -> *const c_char {
@@ -828,6 +1122,22 @@ pub unsafe extern "C" fn betree_print_error(err: *mut err_t) {
     }
 }
 
+/// Return the stable code classifying the given error, e.g. 5 if a storage
+/// class is out of space.  See `ErrorCode` for all codes.
+#[no_mangle]
+pub unsafe extern "C" fn betree_error_code(err: *const err_t) -> c_uint {
+    (*err).0.code() as c_uint
+}
+
+/// Save the description of the given error in `msg`.
+///
+/// Note that `msg` has to be freed with `betree_free_byte_slice`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_error_message(err: *const err_t, msg: *mut byte_slice_t) {
+    let err = &(*err).0;
+    write(msg, CowBytes::from(err.to_string().into_bytes()).into());
+}
+
 /// Create an object store interface.
 #[no_mangle]
 pub unsafe extern "C" fn betree_create_object_store(
@@ -844,6 +1154,86 @@ pub unsafe extern "C" fn betree_create_object_store(
         .handle_result(err)
 }
 
+/// Close an object store.
+///
+/// Note that the `obj_store_t` may not be used afterwards, all objects of it
+/// have to be closed before.
+#[no_mangle]
+pub unsafe extern "C" fn betree_close_object_store(db: *mut db_t, os: *mut obj_store_t) {
+    let db = &mut (*db).0;
+    let os = Box::from_raw(os).0;
+    db.close_object_store(os)
+}
+
+/// Iterate over all objects whose key starts with the given prefix.
+///
+/// On success, return an `obj_iter_t` which has to be freed with
+/// `betree_free_obj_iter`. On error, return null.  If `err` is not null,
+/// store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_object_store_list(
+    os: *const obj_store_t,
+    prefix: *const c_char,
+    prefix_len: c_uint,
+    err: *mut *mut err_t,
+) -> *mut obj_iter_t {
+    let os = &(*os).0;
+    let prefix = from_raw_parts(prefix as *const u8, prefix_len as usize);
+    os.list_objects_with_prefix(prefix)
+        .map(|it| Box::new(it) as Box<dyn Iterator<Item = Result<(CowBytes, ObjectInfo), Error>>>)
+        .handle_result(err)
+}
+
+/// Save the key and information of the next object in the iterator.
+///
+/// On success, return 0.  If there are no more objects, return -1.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+///
+/// Note that on success `key` has to be freed with `betree_free_byte_slice`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_obj_iter_next(
+    obj_iter: *mut obj_iter_t,
+    key: *mut byte_slice_t,
+    info: *mut obj_info_t,
+    err: *mut *mut err_t,
+) -> c_int {
+    let obj_iter = &mut (*obj_iter).0;
+    match obj_iter.next() {
+        None => -1,
+        Some(Err(e)) => {
+            handle_err(e, err);
+            -1
+        }
+        Some(Ok((next_key, next_info))) => {
+            write(key, next_key.into());
+            write(info, next_info.into());
+            0
+        }
+    }
+}
+
+/// Rename the object `old_key` to `new_key`.  An existing object `new_key`
+/// is only replaced if `overwrite` is not 0.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_object_rename(
+    os: *const obj_store_t,
+    old_key: *const c_char,
+    old_key_len: c_uint,
+    new_key: *const c_char,
+    new_key_len: c_uint,
+    overwrite: c_int,
+    err: *mut *mut err_t,
+) -> c_int {
+    let os = &(*os).0;
+    let old_key = from_raw_parts(old_key as *const u8, old_key_len as usize);
+    let new_key = from_raw_parts(new_key as *const u8, new_key_len as usize);
+    os.rename(old_key, new_key, overwrite != 0)
+        .handle_result(err)
+}
+
 /// Open an existing object.
 #[no_mangle]
 pub unsafe extern "C" fn betree_object_open<'os>(
@@ -961,23 +1351,66 @@ pub unsafe extern "C" fn betree_object_write_at(
         .handle_result(err)
 }
 
-/*
-/// Return the objects size in bytes.
+/// Save the size, modification time and storage preference of `obj` in
+/// `info`.
+///
+/// On success, return 0.  If the object does not exist anymore, return -1.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
 #[no_mangle]
-pub unsafe extern "C" fn betree_object_status(obj: *const obj_t, err: *mut *mut err_t) -> c_ulong {
+pub unsafe extern "C" fn betree_object_info(
+    obj: *const obj_t,
+    info: *mut obj_info_t,
+    err: *mut *mut err_t,
+) -> c_int {
     let obj = &(*obj).0;
-    let info = obj.info();
-    obj.
-    obj.size()
+    obj.info()
+        .map(|res| res.map(|obj_info| write(info, obj_info.into())))
+        .handle_result(err)
 }
 
-/// Returns the last modification timestamp in microseconds since the Unix epoch.
+/// Truncate or zero-extend `obj` to `len` bytes.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
 #[no_mangle]
-pub unsafe extern "C" fn betree_object_mtime_us(obj: *const obj_t) -> c_ulong {
+pub unsafe extern "C" fn betree_object_truncate(
+    obj: *const obj_t,
+    len: c_ulong,
+    err: *mut *mut err_t,
+) -> c_int {
     let obj = &(*obj).0;
-    obj.modification_time()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_micros() as u64)
-        .unwrap_or(0)
+    obj.truncate(len).handle_result(err)
+}
+
+/// Migrate all data of `obj` to the given storage preference, which is also
+/// used for future writes through this handle.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_object_migrate(
+    obj: *mut obj_t,
+    storage_pref: storage_pref_t,
+    err: *mut *mut err_t,
+) -> c_int {
+    let obj = &mut (*obj).0;
+    obj.migrate(storage_pref.0).handle_result(err)
+}
+
+/// Migrate `len` bytes of `obj`, starting at `offset` bytes into the objects
+/// data, to the given storage preference.
+///
+/// On success, return 0.
+/// On error, return -1.  If `err` is not null, store an error in `err`.
+#[no_mangle]
+pub unsafe extern "C" fn betree_object_migrate_range(
+    obj: *const obj_t,
+    offset: c_ulong,
+    len: c_ulong,
+    storage_pref: storage_pref_t,
+    err: *mut *mut err_t,
+) -> c_int {
+    let obj = &(*obj).0;
+    obj.migrate_range(len, offset, storage_pref.0)
+        .handle_result(err)
 }
-*/
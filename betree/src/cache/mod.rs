@@ -1,4 +1,5 @@
-//! This module provides a cache interface and a CLOCK cache implementation.
+//! This module provides a cache interface and a cache implementation with
+//! pluggable replacement policies.
 
 use stable_deref_trait::StableDeref;
 use std::{
@@ -99,8 +100,7 @@ pub trait Cache: Send + Sync {
     /// if the cache should not grow beyond the capacity bound.
     fn insert(&mut self, key: Self::Key, value: Self::Value, size: usize);
 
-    /// Returns an iterator that iterates over the cache entry keys in order
-    /// from old to new.
+    /// Returns an iterator that iterates over the cache entry keys.
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Self::Key> + 'a>;
 
     /// Returns the total size of all cache entries.
@@ -109,6 +109,10 @@ pub trait Cache: Send + Sync {
     /// Returns the capacity.
     fn capacity(&self) -> usize;
 
+    /// Sets the capacity.  A cache exceeding the new capacity is shrunk by
+    /// subsequent evictions.
+    fn set_capacity(&mut self, capacity: usize);
+
     /// The value returned by `stats`.
     type Stats: Stats;
 
@@ -151,5 +155,11 @@ pub trait Stats: Display + Debug + serde::Serialize {
 }
 
 mod clock;
-mod clock_cache;
-pub use self::clock_cache::ClockCache;
+mod policy;
+mod policy_cache;
+pub use self::{
+    policy::{
+        CachePolicy, CachePolicyType, ClockPolicy, ClockProPolicy, LruPolicy, WTinyLfuPolicy,
+    },
+    policy_cache::PolicyCache,
+};
@@ -0,0 +1,76 @@
+//! CLOCK is a more efficient implementation of Second Chance which is a 1-bit
+//! approximation of LRU.
+//! The benefit compared to LRU is the much lower overhead for cache hits
+//! as CLOCK does not have to move the cache entry to the MRU position like LRU
+//! does.
+
+use super::{super::clock::Clock, CachePolicy};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A clock policy. (1-bit approximation of LRU)
+pub struct ClockPolicy<K> {
+    clock: Clock<K>,
+    referenced: HashMap<K, AtomicBool>,
+}
+
+impl<K> Default for ClockPolicy<K> {
+    fn default() -> Self {
+        ClockPolicy {
+            clock: Default::default(),
+            referenced: Default::default(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash + Send + Sync> CachePolicy<K> for ClockPolicy<K> {
+    fn insert(&mut self, key: K) {
+        self.referenced.insert(key.clone(), AtomicBool::new(false));
+        self.clock.push_back(key);
+    }
+
+    fn access(&self, key: &K) {
+        if let Some(referenced) = self.referenced.get(key) {
+            referenced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.referenced.remove(key).is_some() {
+            self.clock.retain(|entry| entry != key);
+        }
+    }
+
+    fn change_key(&mut self, key: &K, new_key: K) {
+        if let Some(referenced) = self.referenced.remove(key) {
+            self.referenced.insert(new_key.clone(), referenced);
+            if let Some(entry) = self.clock.iter_mut().find(|entry| *entry == key) {
+                *entry = new_key;
+            }
+        }
+    }
+
+    fn evict(&mut self, try_evict: &mut dyn FnMut(&K) -> bool) -> Option<K> {
+        let len = self.referenced.len();
+        if len == 0 {
+            return None;
+        }
+        for _ in 0..2 * len {
+            let key = self.clock.peek_front()?;
+            // An entry will be evicted if its referenced bit is false and the
+            // cache signals a successful eviction.
+            let was_referenced = self.referenced[key].swap(false, Ordering::Relaxed);
+            if !was_referenced && try_evict(key) {
+                let key = self.clock.pop_front().unwrap();
+                self.referenced.remove(&key);
+                return Some(key);
+            }
+            self.clock.next();
+        }
+        warn!("Clock eviction failed");
+        None
+    }
+}
@@ -0,0 +1,206 @@
+//! CLOCK-Pro replacement, see Jiang et al., "CLOCK-Pro: An Effective
+//! Improvement of the CLOCK Replacement".
+//!
+//! Entries are either hot or cold, only cold entries are evicted. A new entry
+//! starts cold in a test period, which lasts until it would be evicted again.
+//! An entry which is reused during its test period has a small reuse distance
+//! and becomes hot, while hot entries which have not been used since the hot
+//! hand passed them are demoted to cold. Evicted entries are remembered for
+//! the rest of their test period, and a reinsertion of such an entry grows
+//! the share of cold entries as the cold entries are evicted too early.
+//!
+//! This implementation keeps hot and cold entries in two separate clocks
+//! instead of one clock with three hands.
+
+use super::{super::clock::Clock, CachePolicy};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+struct Meta {
+    referenced: AtomicBool,
+    hot: bool,
+    test: bool,
+}
+
+/// Scan resistant replacement by reuse distance, see the module
+/// documentation.
+pub struct ClockProPolicy<K> {
+    hot: Clock<K>,
+    cold: Clock<K>,
+    meta: HashMap<K, Meta>,
+    // Evicted entries in their test period, with the sequence number of their
+    // eviction.
+    non_resident: HashMap<K, u64>,
+    non_resident_order: VecDeque<(u64, K)>,
+    evictions: u64,
+    cold_len: usize,
+    // Number of resident cold entries to aim for.
+    cold_target: usize,
+}
+
+impl<K> Default for ClockProPolicy<K> {
+    fn default() -> Self {
+        ClockProPolicy {
+            hot: Default::default(),
+            cold: Default::default(),
+            meta: Default::default(),
+            non_resident: Default::default(),
+            non_resident_order: Default::default(),
+            evictions: 0,
+            cold_len: 0,
+            cold_target: 1,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> ClockProPolicy<K> {
+    fn max_cold_target(&self) -> usize {
+        self.meta.len().saturating_sub(1).max(1)
+    }
+
+    // Remembers an evicted entry until as many entries are remembered as
+    // are resident.
+    fn remember(&mut self, key: K) {
+        self.evictions += 1;
+        self.non_resident.insert(key.clone(), self.evictions);
+        self.non_resident_order.push_back((self.evictions, key));
+        while self.non_resident.len() > self.meta.len().max(1) {
+            self.forget_oldest();
+        }
+    }
+
+    // The test period of the oldest evicted entry ends without reuse, so cold
+    // entries are kept too long.
+    fn forget_oldest(&mut self) {
+        while let Some((seq, key)) = self.non_resident_order.pop_front() {
+            if self.non_resident.get(&key) == Some(&seq) {
+                self.non_resident.remove(&key);
+                self.cold_target = self.cold_target.saturating_sub(1).max(1);
+                return;
+            }
+        }
+    }
+
+    // Runs the hot hand by one entry, demoting it if it has not been used
+    // since the hand passed it last.
+    fn run_hot_hand(&mut self) {
+        let key = match self.hot.peek_front() {
+            Some(key) => key,
+            None => return,
+        };
+        let meta = self.meta.get_mut(key).unwrap();
+        if *meta.referenced.get_mut() {
+            *meta.referenced.get_mut() = false;
+            self.hot.next();
+            return;
+        }
+        meta.hot = false;
+        meta.test = false;
+        let key = self.hot.pop_front().unwrap();
+        self.cold.push_back(key);
+        self.cold_len += 1;
+    }
+}
+
+impl<K: Clone + Eq + Hash + Send + Sync> CachePolicy<K> for ClockProPolicy<K> {
+    fn insert(&mut self, key: K) {
+        let hot = self.non_resident.remove(&key).is_some();
+        if hot {
+            self.cold_target = (self.cold_target + 1).min(self.max_cold_target());
+        }
+        self.meta.insert(
+            key.clone(),
+            Meta {
+                referenced: AtomicBool::new(false),
+                hot,
+                test: !hot,
+            },
+        );
+        if hot {
+            self.hot.push_back(key);
+        } else {
+            self.cold.push_back(key);
+            self.cold_len += 1;
+        }
+    }
+
+    fn access(&self, key: &K) {
+        if let Some(meta) = self.meta.get(key) {
+            meta.referenced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(meta) = self.meta.remove(key) {
+            if meta.hot {
+                self.hot.retain(|entry| entry != key);
+            } else {
+                self.cold.retain(|entry| entry != key);
+                self.cold_len -= 1;
+            }
+        }
+    }
+
+    fn change_key(&mut self, key: &K, new_key: K) {
+        if let Some(meta) = self.meta.remove(key) {
+            let clock = if meta.hot { &self.hot } else { &self.cold };
+            if let Some(entry) = clock.iter_mut().find(|entry| *entry == key) {
+                *entry = new_key.clone();
+            }
+            self.meta.insert(new_key, meta);
+        }
+    }
+
+    fn evict(&mut self, try_evict: &mut dyn FnMut(&K) -> bool) -> Option<K> {
+        // Every entry is passed at most twice by each hand, once to clear its
+        // referenced bit and once to demote or evict it.
+        let mut budget = 4 * self.meta.len() + 1;
+        while budget > 0 {
+            budget -= 1;
+            if self.meta.is_empty() {
+                return None;
+            }
+            if self.cold_len < self.cold_target.min(self.meta.len()) || self.cold_len == 0 {
+                self.run_hot_hand();
+                continue;
+            }
+
+            let key = self.cold.peek_front().unwrap();
+            let meta = self.meta.get_mut(key).unwrap();
+            if *meta.referenced.get_mut() {
+                *meta.referenced.get_mut() = false;
+                if meta.test {
+                    // Reused within its test period.
+                    meta.hot = true;
+                    meta.test = false;
+                    let key = self.cold.pop_front().unwrap();
+                    self.hot.push_back(key);
+                    self.cold_len -= 1;
+                    self.cold_target = (self.cold_target + 1).min(self.max_cold_target());
+                } else {
+                    meta.test = true;
+                    self.cold.next();
+                }
+                continue;
+            }
+
+            if try_evict(key) {
+                let key = self.cold.pop_front().unwrap();
+                let meta = self.meta.remove(&key).unwrap();
+                self.cold_len -= 1;
+                if meta.test {
+                    self.remember(key.clone());
+                }
+                return Some(key);
+            }
+            self.cold.next();
+            // Give the hot hand a chance to provide other cold entries.
+            self.run_hot_hand();
+        }
+        warn!("Clock-Pro eviction failed");
+        None
+    }
+}
@@ -0,0 +1,151 @@
+//! Least recently used replacement.
+
+use super::CachePolicy;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// Keys ordered by their last use, stamped with a logical time.
+pub(super) struct LruList<K> {
+    stamps: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+    now: u64,
+}
+
+impl<K> Default for LruList<K> {
+    fn default() -> Self {
+        LruList {
+            stamps: Default::default(),
+            order: Default::default(),
+            now: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> LruList<K> {
+    pub(super) fn len(&self) -> usize {
+        self.stamps.len()
+    }
+
+    pub(super) fn contains(&self, key: &K) -> bool {
+        self.stamps.contains_key(key)
+    }
+
+    /// Moves `key` to the most recently used position, inserting it if it is
+    /// not present.
+    pub(super) fn touch(&mut self, key: &K) {
+        self.now += 1;
+        if let Some(stamp) = self.stamps.get_mut(key) {
+            let key = self.order.remove(stamp).unwrap();
+            *stamp = self.now;
+            self.order.insert(self.now, key);
+        } else {
+            self.stamps.insert(key.clone(), self.now);
+            self.order.insert(self.now, key.clone());
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &K) -> bool {
+        match self.stamps.remove(key) {
+            Some(stamp) => {
+                self.order.remove(&stamp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn change_key(&mut self, key: &K, new_key: K) -> bool {
+        match self.stamps.remove(key) {
+            Some(stamp) => {
+                self.stamps.insert(new_key.clone(), stamp);
+                self.order.insert(stamp, new_key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and returns the least recently used key.
+    pub(super) fn pop_lru(&mut self) -> Option<K> {
+        let key = self.lru()?.clone();
+        self.remove(&key);
+        Some(key)
+    }
+
+    pub(super) fn lru(&self) -> Option<&K> {
+        self.order.values().next()
+    }
+
+    /// Iterates from the least to the most recently used key.
+    pub(super) fn iter(&self) -> impl Iterator<Item = &K> {
+        self.order.values()
+    }
+}
+
+/// Evicts the least recently used entry.
+///
+/// Every cache hit moves the entry to the most recently used position under a
+/// lock, which makes hits more costly than with [ClockPolicy](super::ClockPolicy).
+pub struct LruPolicy<K> {
+    list: Mutex<LruList<K>>,
+}
+
+impl<K> Default for LruPolicy<K> {
+    fn default() -> Self {
+        LruPolicy {
+            list: Default::default(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash + Send + Sync> CachePolicy<K> for LruPolicy<K> {
+    fn insert(&mut self, key: K) {
+        self.list.get_mut().touch(&key);
+    }
+
+    fn access(&self, key: &K) {
+        let mut list = self.list.lock();
+        if list.contains(key) {
+            list.touch(key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.list.get_mut().remove(key);
+    }
+
+    fn change_key(&mut self, key: &K, new_key: K) {
+        self.list.get_mut().change_key(key, new_key);
+    }
+
+    fn evict(&mut self, try_evict: &mut dyn FnMut(&K) -> bool) -> Option<K> {
+        let list = self.list.get_mut();
+        let key = list.iter().find(|key| try_evict(key))?.clone();
+        list.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruList;
+
+    #[test]
+    fn touch_reorders() {
+        let mut list = LruList::default();
+        for key in 0..4 {
+            list.touch(&key);
+        }
+        list.touch(&1);
+        assert!(list.change_key(&2, 5));
+        assert!(list.iter().eq(&[0, 5, 3, 1]));
+        assert_eq!(list.pop_lru(), Some(0));
+        assert!(list.remove(&3));
+        assert!(!list.remove(&3));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.lru(), Some(&5));
+    }
+}
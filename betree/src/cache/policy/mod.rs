@@ -0,0 +1,169 @@
+//! Replacement policies deciding which entries a
+//! [PolicyCache](super::PolicyCache) evicts.
+//!
+//! Policies only track the keys of the cache entries, pinning and sizes are
+//! handled by the cache itself.
+
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+
+mod clock;
+mod clock_pro;
+mod lru;
+mod tiny_lfu;
+
+pub use self::{
+    clock::ClockPolicy, clock_pro::ClockProPolicy, lru::LruPolicy, tiny_lfu::WTinyLfuPolicy,
+};
+
+/// The replacement order of the entries of a cache.
+pub trait CachePolicy<K>: Send + Sync {
+    /// Starts tracking a newly inserted entry.
+    fn insert(&mut self, key: K);
+
+    /// Records a hit on a tracked entry. Called concurrently while the cache
+    /// is shared.
+    fn access(&self, key: &K);
+
+    /// Stops tracking an entry which has been removed from the cache.
+    fn remove(&mut self, key: &K);
+
+    /// Changes the key of a tracked entry without changing its position.
+    fn change_key(&mut self, key: &K, new_key: K);
+
+    /// Offers tracked entries in replacement order to `try_evict` until it
+    /// returns `true`, i.e. the entry has been evicted.
+    /// Returns the key of the evicted entry, which is not tracked anymore.
+    fn evict(&mut self, try_evict: &mut dyn FnMut(&K) -> bool) -> Option<K>;
+}
+
+/// The replacement policy of the node cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CachePolicyType {
+    /// Second chance approximation of LRU with cheap cache hits.
+    #[default]
+    Clock,
+    /// Evict the least recently used entry.
+    Lru,
+    /// Distinguish hot and cold entries by their reuse distance, so that
+    /// entries used once by a scan do not displace frequently used ones.
+    ClockPro,
+    /// Admit entries to the main cache only if they are used more frequently
+    /// than the entries they would displace, which makes the cache resistant
+    /// to scans.
+    WTinyLfu,
+}
+
+impl CachePolicyType {
+    /// Constructs an empty policy of this type.
+    pub fn build<K>(self) -> Box<dyn CachePolicy<K>>
+    where
+        K: Clone + Eq + Hash + Send + Sync + 'static,
+    {
+        match self {
+            CachePolicyType::Clock => Box::new(ClockPolicy::default()),
+            CachePolicyType::Lru => Box::new(LruPolicy::default()),
+            CachePolicyType::ClockPro => Box::new(ClockProPolicy::default()),
+            CachePolicyType::WTinyLfu => Box::new(WTinyLfuPolicy::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CachePolicyType;
+    use std::collections::HashSet;
+
+    const POLICIES: [CachePolicyType; 4] = [
+        CachePolicyType::Clock,
+        CachePolicyType::Lru,
+        CachePolicyType::ClockPro,
+        CachePolicyType::WTinyLfu,
+    ];
+
+    #[test]
+    fn evicts_each_entry_once() {
+        for policy_type in POLICIES {
+            let mut policy = policy_type.build();
+            for key in 0..100u32 {
+                policy.insert(key);
+                policy.access(&(key / 2));
+            }
+            policy.remove(&7);
+            policy.change_key(&8, 1000);
+
+            let mut evicted = HashSet::new();
+            while let Some(key) = policy.evict(&mut |_| true) {
+                assert!(
+                    evicted.insert(key),
+                    "{:?} evicted {} twice",
+                    policy_type,
+                    key
+                );
+            }
+            let expected: HashSet<_> = (0..100)
+                .filter(|&key| key != 7 && key != 8)
+                .chain(Some(1000))
+                .collect();
+            assert_eq!(evicted, expected, "{:?}", policy_type);
+        }
+    }
+
+    #[test]
+    fn skips_refused_entries() {
+        for policy_type in POLICIES {
+            let mut policy = policy_type.build();
+            for key in 0..10u32 {
+                policy.insert(key);
+            }
+            assert_eq!(policy.evict(&mut |_| false), None, "{:?}", policy_type);
+            assert_eq!(
+                policy.evict(&mut |&key| key == 3),
+                Some(3),
+                "{:?}",
+                policy_type
+            );
+            assert_eq!(
+                policy.evict(&mut |&key| key == 3),
+                None,
+                "{:?}",
+                policy_type
+            );
+        }
+    }
+
+    #[test]
+    fn scan_resistance() {
+        // Repeatedly use a small working set while scanning many entries used
+        // only once, with room for 100 entries.
+        let hits = |policy_type: CachePolicyType| {
+            let mut policy = policy_type.build();
+            let mut cached = HashSet::new();
+            let mut hits = 0;
+            let mut scan = 1000u32;
+            for round in 0..200 {
+                for key in (0..50).chain(scan..scan + 100) {
+                    if round % 2 == 1 && key >= 1000 {
+                        continue;
+                    }
+                    if cached.contains(&key) {
+                        policy.access(&key);
+                        hits += 1;
+                        continue;
+                    }
+                    if cached.len() == 100 {
+                        let victim = policy.evict(&mut |_| true).unwrap();
+                        cached.remove(&victim);
+                    }
+                    policy.insert(key);
+                    cached.insert(key);
+                }
+                scan += 100;
+            }
+            hits
+        };
+        let lru = hits(CachePolicyType::Lru);
+        assert!(hits(CachePolicyType::ClockPro) > lru);
+        assert!(hits(CachePolicyType::WTinyLfu) > lru);
+    }
+}
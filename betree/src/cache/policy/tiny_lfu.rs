@@ -0,0 +1,221 @@
+//! W-TinyLFU replacement, see Einziger et al., "TinyLFU: A Highly Efficient
+//! Cache Admission Policy".
+//!
+//! New entries enter a small LRU window. Entries leaving the window are
+//! admitted to the main cache only if they have been used more frequently than
+//! the entry they would displace, as estimated by a count-min sketch over the
+//! recent history. The main cache is a segmented LRU whose protected segment
+//! holds the entries which have been hit while in the probation segment.
+
+use super::{lru::LruList, CachePolicy};
+use parking_lot::Mutex;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+// Share of the window on all tracked entries in percent.
+const WINDOW_PERCENT: usize = 1;
+// Share of the protected segment on the main cache in percent.
+const PROTECTED_PERCENT: usize = 80;
+const SKETCH_DEPTH: usize = 4;
+const MAX_FREQUENCY: u8 = 15;
+const MIN_SKETCH_WIDTH: usize = 64;
+
+/// Approximate access frequencies of keys, halved periodically so that the
+/// estimate reflects the recent history.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    width: usize,
+    additions: usize,
+}
+
+impl FrequencySketch {
+    fn new(width: usize) -> Self {
+        let width = width.next_power_of_two().max(MIN_SKETCH_WIDTH);
+        FrequencySketch {
+            counters: vec![0; SKETCH_DEPTH * width],
+            width,
+            additions: 0,
+        }
+    }
+
+    fn indices<K: Hash>(&self, key: &K) -> [usize; SKETCH_DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut indices = [0; SKETCH_DEPTH];
+        for (row, index) in indices.iter_mut().enumerate() {
+            let row_hash = (hash ^ (row as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+                .wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            *index = row * self.width + (row_hash >> 32) as usize % self.width;
+        }
+        indices
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.indices(key)
+            .iter()
+            .map(|&index| self.counters[index])
+            .min()
+            .unwrap()
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for index in self.indices(key) {
+            let counter = &mut self.counters[index];
+            *counter = (*counter + 1).min(MAX_FREQUENCY);
+        }
+        self.additions += 1;
+        if self.additions >= 10 * self.width {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+}
+
+struct State<K> {
+    window: LruList<K>,
+    probation: LruList<K>,
+    protected: LruList<K>,
+    sketch: FrequencySketch,
+}
+
+impl<K: Clone + Eq + Hash> State<K> {
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn hit(&mut self, key: &K) {
+        self.sketch.increment(key);
+        if self.window.contains(key) {
+            self.window.touch(key);
+        } else if self.protected.contains(key) {
+            self.protected.touch(key);
+        } else if self.probation.remove(key) {
+            self.protected.touch(key);
+            let max_protected = (self.len() - self.window.len()) * PROTECTED_PERCENT / 100;
+            while self.protected.len() > max_protected.max(1) {
+                let demoted = self.protected.pop_lru().unwrap();
+                self.probation.touch(&demoted);
+            }
+        }
+    }
+
+    // Moves the entries exceeding the window to the probation segment, returns
+    // the one moved last which competes with the probation victim.
+    fn drain_window(&mut self) -> Option<K> {
+        let max_window = (self.len() * WINDOW_PERCENT / 100).max(1);
+        let mut candidate = None;
+        while self.window.len() > max_window {
+            let key = self.window.pop_lru().unwrap();
+            self.probation.touch(&key);
+            candidate = Some(key);
+        }
+        candidate
+    }
+
+    fn remove(&mut self, key: &K) {
+        let _ = self.window.remove(key) || self.probation.remove(key) || self.protected.remove(key);
+    }
+}
+
+/// Scan resistant replacement by frequency based admission, see the module
+/// documentation.
+pub struct WTinyLfuPolicy<K> {
+    state: Mutex<State<K>>,
+}
+
+impl<K> Default for WTinyLfuPolicy<K> {
+    fn default() -> Self {
+        WTinyLfuPolicy {
+            state: Mutex::new(State {
+                window: Default::default(),
+                probation: Default::default(),
+                protected: Default::default(),
+                sketch: FrequencySketch::new(MIN_SKETCH_WIDTH),
+            }),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash + Send + Sync> CachePolicy<K> for WTinyLfuPolicy<K> {
+    fn insert(&mut self, key: K) {
+        let state = self.state.get_mut();
+        // Grow the sketch with the cache, forgetting the history.
+        if state.len() >= state.sketch.width {
+            state.sketch = FrequencySketch::new(2 * state.sketch.width);
+        }
+        state.sketch.increment(&key);
+        state.window.touch(&key);
+    }
+
+    fn access(&self, key: &K) {
+        self.state.lock().hit(key);
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.state.get_mut().remove(key);
+    }
+
+    fn change_key(&mut self, key: &K, new_key: K) {
+        let state = self.state.get_mut();
+        let _ = state.window.change_key(key, new_key.clone())
+            || state.probation.change_key(key, new_key.clone())
+            || state.protected.change_key(key, new_key);
+    }
+
+    fn evict(&mut self, try_evict: &mut dyn FnMut(&K) -> bool) -> Option<K> {
+        let state = self.state.get_mut();
+        let candidate = state.drain_window();
+        // The candidate is rejected if it is not used more frequently than
+        // the entry it would displace.
+        let rejected = candidate.filter(|candidate| match state.probation.lru() {
+            Some(victim) => state.sketch.frequency(candidate) <= state.sketch.frequency(victim),
+            None => false,
+        });
+        let key = rejected
+            .iter()
+            .chain(
+                state
+                    .probation
+                    .iter()
+                    .filter(|&key| Some(key) != rejected.as_ref()),
+            )
+            .chain(state.protected.iter())
+            .chain(state.window.iter())
+            .find(|key| try_evict(key))?
+            .clone();
+        state.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrequencySketch, MAX_FREQUENCY};
+
+    #[test]
+    fn sketch_ages() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..20 {
+            sketch.increment(&1u32);
+        }
+        assert_eq!(sketch.frequency(&1u32), MAX_FREQUENCY);
+        assert!(sketch.frequency(&2u32) < MAX_FREQUENCY);
+
+        let mut key = 1000u32;
+        loop {
+            let additions = sketch.additions;
+            sketch.increment(&key);
+            key += 1;
+            if sketch.additions < additions {
+                break;
+            }
+        }
+        assert_eq!(sketch.additions, 5 * 64);
+        assert!(sketch.frequency(&1u32) <= MAX_FREQUENCY / 2);
+    }
+}
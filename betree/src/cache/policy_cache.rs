@@ -1,12 +1,10 @@
-//! This module provides a CLOCK cache implementation.
-//!
-//! CLOCK is a more efficient implementation of Second Chance which is a 1-bit
-//! approximation of LRU.
-//! The benefit compared to LRU is the much lower overhead for cache hits
-//! as CLOCK does not have to move the cache entry to the MRU position like LRU
-//! does.
-
-use super::{clock::Clock, AddSize, Cache, ChangeKeyError, RemoveError, Stats};
+//! This module provides a cache implementation with a pluggable replacement
+//! policy.
+
+use super::{
+    policy::{CachePolicy, CachePolicyType},
+    AddSize, Cache, ChangeKeyError, RemoveError, Stats,
+};
 use crate::size::SizeMut;
 use stable_deref_trait::StableDeref;
 use std::{
@@ -15,15 +13,15 @@ use std::{
     hash::Hash,
     ops::Deref,
     sync::{
-        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
 };
 
-/// A clock cache. (1-bit approximation of LRU)
-pub struct ClockCache<K, V> {
+/// A cache whose entries are evicted in the order of a [CachePolicy].
+pub struct PolicyCache<K, V> {
     map: HashMap<K, Arc<CacheEntry<V>>>,
-    clock: Clock<K>,
+    policy: Box<dyn CachePolicy<K>>,
     capacity: usize,
     // Let's leak it
     size: &'static AtomicUsize,
@@ -36,7 +34,6 @@ pub struct ClockCache<K, V> {
 
 struct CacheEntry<V> {
     value: V,
-    referenced: AtomicBool,
 }
 
 /// Pinned cache entry
@@ -77,7 +74,7 @@ impl fmt::Display for CacheStats {
 STATISTICS:
 ===
               Size: {s}/{c} ({s_p:.2}% filled)
-        Clock size: {c_s:>6}
+           Entries: {c_s:>6}
 Average entry size: {avg_e:.2}
 
   Hits: {h:>8} ({h_p:>6.2}%)
@@ -146,12 +143,18 @@ impl<V> AddSize for PinnedEntry<V> {
     }
 }
 
-impl<K: Hash + Eq, V: SizeMut> ClockCache<K, V> {
-    /// Returns a new cache instance with the given `capacity`.
+impl<K: Clone + Eq + Hash + Send + Sync + 'static, V: SizeMut> PolicyCache<K, V> {
+    /// Returns a new CLOCK cache instance with the given `capacity`.
     pub fn new(capacity: usize) -> Self {
-        ClockCache {
+        Self::with_policy(capacity, CachePolicyType::default())
+    }
+
+    /// Returns a new cache instance with the given `capacity` which evicts
+    /// entries according to `policy`.
+    pub fn with_policy(capacity: usize, policy: CachePolicyType) -> Self {
+        PolicyCache {
             map: Default::default(),
-            clock: Default::default(),
+            policy: policy.build(),
             size: Box::leak(Default::default()),
             hits: Default::default(),
             misses: Default::default(),
@@ -164,7 +167,7 @@ impl<K: Hash + Eq, V: SizeMut> ClockCache<K, V> {
 }
 
 impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 'static> Cache
-    for ClockCache<K, V>
+    for PolicyCache<K, V>
 {
     type Key = K;
     type Value = V;
@@ -182,7 +185,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
     fn get(&self, key: &K, count_miss: bool) -> Option<Self::ValueRef> {
         if let Some(entry) = self.map.get(key).cloned() {
             self.hits.fetch_add(1, Ordering::Relaxed);
-            entry.referenced.store(true, Ordering::Relaxed);
+            self.policy.access(key);
             Some(PinnedEntry {
                 size: self.size,
                 entry,
@@ -204,7 +207,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
             let entry = self.map.get_mut(key).ok_or(RemoveError::NotPresent)?;
             Arc::get_mut(entry).ok_or(RemoveError::Pinned)?;
         }
-        self.clock.retain(|entry| entry != key);
+        self.policy.remove(key);
         let entry = self.map.remove(key).unwrap();
         let mut value = Arc::try_unwrap(entry).ok().unwrap().value;
         let size = f(&mut value);
@@ -216,10 +219,10 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
 
     fn force_remove(&mut self, key: &Self::Key, size: usize) -> bool {
         self.verify();
-        self.clock.retain(|entry| entry != key);
         if self.map.remove(key).is_none() {
             return false;
         }
+        self.policy.remove(key);
         self.removals += 1;
         self.size.fetch_sub(size, Ordering::Relaxed);
         self.verify();
@@ -239,9 +242,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         };
         let entry = self.map.remove(key).unwrap();
         self.map.insert(new_key.clone(), entry);
-        if let Some(entry) = self.clock.iter_mut().find(|entry| *entry == key) {
-            *entry = new_key;
-        }
+        self.policy.change_key(key, new_key);
         self.verify();
         Ok(())
     }
@@ -253,9 +254,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
             Some(entry) => entry,
         };
         self.map.insert(new_key.clone(), entry);
-        if let Some(entry) = self.clock.iter_mut().find(|entry| *entry == key) {
-            *entry = new_key;
-        }
+        self.policy.change_key(key, new_key);
         self.verify();
         true
     }
@@ -266,63 +265,41 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
     {
         self.verify();
 
-        let len = self.clock.len();
-        let mut cnt = 0;
-        let ret = loop {
-            let eviction_successful = {
-                let key = match self.clock.peek_front().cloned() {
-                    None => {
-                        warn!("Clock size mismatch");
-                        break None;
-                    }
-                    Some(key) => key,
-                };
-
-                let second_ref: &Self = unsafe { &*(self as *mut _) };
-                let entry = self.map.get_mut(&key).unwrap();
-
-                // An entry will be evicted if the following three conditions are satisfied:
-                // - The cache entry is not pinned
-                // - The referenced bit of the cache entry is false
-                // - The eviction callback signals a successful eviction.
-
-                if let Some(entry) = Arc::get_mut(entry) {
-                    // reset reference bit
-                    let was_referenced = *entry.referenced.get_mut();
-                    *entry.referenced.get_mut() = false;
-                    if was_referenced {
-                        None
-                    } else {
-                        f(&key, &mut entry.value, &|k| second_ref.contains_key(k))
-                    }
-                } else {
-                    None
-                }
+        let map: *mut HashMap<K, Arc<CacheEntry<V>>> = &mut self.map;
+        let mut evicted_size = 0;
+        let key = self.policy.evict(&mut |key| {
+            // An entry will be evicted if the following three conditions are satisfied:
+            // - The cache entry is not pinned
+            // - The replacement policy offers the cache entry
+            // - The eviction callback signals a successful eviction.
+            let entry = match unsafe { &mut *map }.get_mut(key).and_then(Arc::get_mut) {
+                None => return false,
+                Some(entry) => entry,
             };
-            if let Some(size) = eviction_successful {
-                let key = self.clock.pop_front().unwrap();
-                let mut entry = self.map.remove(&key).unwrap();
-
-                #[cfg(debug_assertions)]
-                {
-                    if let Some(entry) = Arc::get_mut(&mut entry) {
-                        assert_eq!(entry.value.size(), size);
-                    }
+            match f(key, &mut entry.value, &|k| unsafe { &*map }.contains_key(k)) {
+                None => false,
+                Some(size) => {
+                    evicted_size = size;
+                    true
                 }
-
-                self.evictions += 1;
-                self.size.fetch_sub(size, Ordering::Relaxed);
-                let value = Arc::try_unwrap(entry).ok().unwrap().value;
-                break Some((key, value));
             }
+        });
+
+        let ret = key.map(|key| {
+            let mut entry = self.map.remove(&key).unwrap();
 
-            self.clock.next();
-            cnt += 1;
-            if cnt == 2 * len {
-                warn!("Clock eviction failed");
-                break None;
+            #[cfg(debug_assertions)]
+            {
+                if let Some(entry) = Arc::get_mut(&mut entry) {
+                    assert_eq!(entry.value.size(), evicted_size);
+                }
             }
-        };
+
+            self.evictions += 1;
+            self.size.fetch_sub(evicted_size, Ordering::Relaxed);
+            let value = Arc::try_unwrap(entry).ok().unwrap().value;
+            (key, value)
+        });
 
         self.verify();
         ret
@@ -331,15 +308,9 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
     fn insert(&mut self, key: K, mut value: V, size: usize) {
         debug_assert_eq!(value.size(), size);
 
-        let old_value = self.map.insert(
-            key.clone(),
-            Arc::new(CacheEntry {
-                value,
-                referenced: AtomicBool::new(false),
-            }),
-        );
+        let old_value = self.map.insert(key.clone(), Arc::new(CacheEntry { value }));
         assert!(old_value.is_none());
-        self.clock.push_back(key);
+        self.policy.insert(key);
         self.insertions += 1;
         self.size.fetch_add(size, Ordering::Relaxed);
     }
@@ -358,7 +329,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
     }
 
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a K> + 'a> {
-        Box::new(self.clock.iter())
+        Box::new(self.map.keys())
     }
 
     fn size(&self) -> usize {
@@ -369,6 +340,10 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         self.capacity
     }
 
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
     // This is wildly unsafe, because it was hacked on top of a cache design which assumed interior
     // mutability, but it's only a debugging feature to locate faulty size adjustments, and if you
     // only run it without optimisations, the nasal demons might leave you alone.
@@ -0,0 +1,211 @@
+/// `Crc32c` contains a CRC-32C (Castagnoli) digest, which is computed with the
+/// `crc32` instruction of SSE 4.2 if the CPU supports it.
+///
+/// Large buffers are checksummed in chunks in parallel, whose digests are
+/// combined into the digest of the whole buffer, so that the result does not
+/// depend on the chunking.
+use super::{Builder, Checksum, ChecksumError, State};
+use crate::size::StaticSize;
+use serde::{Deserialize, Serialize};
+use std::thread;
+
+// The reversed polynomial of CRC-32C.
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = make_table();
+
+// Buffers of at least this length are checksummed in parallel.
+const PARALLEL_MIN_LEN: usize = 1024 * 1024;
+// The minimum length of a chunk checksummed in parallel.
+const CHUNK_MIN_LEN: usize = 256 * 1024;
+// The maximum number of threads checksumming a single buffer.
+const MAX_THREADS: usize = 8;
+
+/// A checksum created by `Crc32c`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crc32c(u32);
+
+impl StaticSize for Crc32c {
+    fn static_size() -> usize {
+        4
+    }
+}
+
+impl Checksum for Crc32c {
+    type Builder = Crc32cBuilder;
+    type Legacy = Self;
+
+    fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
+        &self,
+        data: I,
+    ) -> Result<(), ChecksumError> {
+        let mut state = Crc32cBuilder.build();
+        for x in data {
+            state.ingest(x.as_ref());
+        }
+        let other = state.finish();
+        if *self == other {
+            Ok(())
+        } else {
+            Err(ChecksumError)
+        }
+    }
+
+    fn builder() -> Self::Builder {
+        Crc32cBuilder
+    }
+}
+
+/// The corresponding `Builder` for `Crc32c`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Crc32cBuilder;
+
+impl Builder<Crc32c> for Crc32cBuilder {
+    type State = Crc32cState;
+
+    fn build(&self) -> Self::State {
+        Crc32cState(0)
+    }
+}
+
+/// The internal state of `Crc32c`, which is the digest of the data ingested
+/// so far.
+pub struct Crc32cState(u32);
+
+impl State for Crc32cState {
+    type Checksum = Crc32c;
+
+    fn ingest(&mut self, data: &[u8]) {
+        self.0 = if data.len() < PARALLEL_MIN_LEN {
+            update(self.0, data)
+        } else {
+            update_parallel(self.0, data)
+        };
+    }
+
+    fn finish(self) -> Self::Checksum {
+        Crc32c(self.0)
+    }
+}
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < table.len() {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+// Returns the digest of the data of digest `crc` followed by `data`.
+fn update(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            // SAFETY: The CPU supports SSE 4.2.
+            return unsafe { update_sse42(crc, data) };
+        }
+    }
+    update_table(crc, data)
+}
+
+fn update_table(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn update_sse42(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut words = data.chunks_exact(8);
+    let mut crc = (&mut words).fold(!crc as u64, |crc, word| {
+        _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()))
+    }) as u32;
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+fn update_parallel(crc: u32, data: &[u8]) -> u32 {
+    let chunk_len = CHUNK_MIN_LEN.max((data.len() + MAX_THREADS - 1) / MAX_THREADS);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = data
+            .chunks(chunk_len)
+            .map(|chunk| (chunk.len(), scope.spawn(move || update(0, chunk))))
+            .collect();
+        chunks.into_iter().fold(crc, |crc, (len, chunk)| {
+            combine(crc, chunk.join().expect("Checksum thread panicked"), len)
+        })
+    })
+}
+
+// Returns the digest of the data of digest `first` followed by `len` bytes of
+// digest `second`.
+fn combine(first: u32, second: u32, len: usize) -> u32 {
+    // Appending `len` bytes multiplies the first digest by x^(8 * len).
+    let mut shift = 1 << 31;
+    let mut square = 1 << 23;
+    let mut len = len as u64;
+    while len != 0 {
+        if len & 1 == 1 {
+            shift = multiply(shift, square);
+        }
+        square = multiply(square, square);
+        len >>= 1;
+    }
+    multiply(shift, first) ^ second
+}
+
+// Multiplies two polynomials modulo the polynomial of CRC-32C, in the
+// reflected bit order in which x^0 is the highest bit.
+fn multiply(a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    let mut mask = 1 << 31;
+    while mask != 0 {
+        if a & mask != 0 {
+            product ^= b;
+        }
+        b = if b & 1 == 1 { (b >> 1) ^ POLY } else { b >> 1 };
+        mask >>= 1;
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update, update_parallel, update_table, PARALLEL_MIN_LEN};
+
+    #[test]
+    fn check_value() {
+        assert_eq!(update(0, b"123456789"), 0xe306_9283);
+        assert_eq!(update_table(0, b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let data: Vec<u8> = (0..3 * PARALLEL_MIN_LEN + 13)
+            .map(|idx| (idx * 7 + idx / 251) as u8)
+            .collect();
+        let sequential = update_table(update_table(0, &data[..5]), &data[5..]);
+        assert_eq!(update(update(0, &data[..5]), &data[5..]), sequential);
+        assert_eq!(
+            update_parallel(update(0, &data[..5]), &data[5..]),
+            sequential
+        );
+    }
+}
@@ -17,6 +17,7 @@ impl StaticSize for FxHash {
 
 impl Checksum for FxHash {
     type Builder = FxHashBuilder;
+    type Legacy = Self;
 
     fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
         &self,
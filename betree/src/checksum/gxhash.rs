@@ -17,6 +17,7 @@ impl StaticSize for GxHash {
 
 impl Checksum for GxHash {
     type Builder = GxHashBuilder;
+    type Legacy = Self;
 
     fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
         &self,
@@ -5,12 +5,16 @@ use crate::size::Size;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{error::Error, fmt, iter::once};
 
+mod crc32c;
 mod fxhash;
 mod gxhash;
+mod tagged;
 mod xxhash;
 
 pub use self::gxhash::{GxHash, GxHashBuilder};
+pub use crc32c::{Crc32c, Crc32cBuilder};
 pub use fxhash::{FxHash, FxHashBuilder};
+pub use tagged::{ChecksumConfiguration, TaggedChecksum};
 pub use xxhash::{XxHash, XxHashBuilder};
 
 /// A checksum to verify data integrity.
@@ -20,6 +24,10 @@ pub trait Checksum:
     /// Builds a new `Checksum`.
     type Builder: Builder<Self>;
 
+    /// The checksum of objects written in format version 1, which is
+    /// converted into this one when they are read.
+    type Legacy: DeserializeOwned + Into<Self>;
+
     /// Verifies the contents of the given buffer which consists of multiple
     /// `u8` slices.
     fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
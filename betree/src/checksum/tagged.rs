@@ -0,0 +1,116 @@
+/// `TaggedChecksum` is computed by the algorithm selected by a
+/// `ChecksumConfiguration` and records that algorithm, so that objects stay
+/// verifiable after the configuration has been changed.
+use super::{
+    crc32c::Crc32cState, gxhash::GxHashState, Builder, Checksum, ChecksumError, Crc32c,
+    Crc32cBuilder, GxHash, GxHashBuilder, State,
+};
+use crate::size::StaticSize;
+use serde::{Deserialize, Serialize};
+
+/// The checksum algorithm of new objects. Objects record the algorithm their
+/// checksum has been computed with, so the configuration may be changed at
+/// any time.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumConfiguration {
+    /// [GxHash], which uses the AES instructions of the CPU.
+    #[default]
+    GxHash,
+    /// [Crc32c], which uses the `crc32` instruction of SSE 4.2 and checksums
+    /// large objects in parallel chunks.
+    Crc32c,
+}
+
+/// A checksum created by the algorithm of a `ChecksumConfiguration`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaggedChecksum {
+    GxHash(GxHash),
+    Crc32c(Crc32c),
+}
+
+impl StaticSize for TaggedChecksum {
+    fn static_size() -> usize {
+        // The variant index followed by the larger of both checksums.
+        4 + GxHash::static_size()
+    }
+}
+
+impl From<GxHash> for TaggedChecksum {
+    fn from(checksum: GxHash) -> Self {
+        TaggedChecksum::GxHash(checksum)
+    }
+}
+
+impl Checksum for TaggedChecksum {
+    type Builder = ChecksumConfiguration;
+    // Format version 1 has only known `GxHash`.
+    type Legacy = GxHash;
+
+    fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
+        &self,
+        data: I,
+    ) -> Result<(), ChecksumError> {
+        match self {
+            TaggedChecksum::GxHash(checksum) => checksum.verify_buffer(data),
+            TaggedChecksum::Crc32c(checksum) => checksum.verify_buffer(data),
+        }
+    }
+
+    fn builder() -> Self::Builder {
+        ChecksumConfiguration::default()
+    }
+}
+
+impl Builder<TaggedChecksum> for ChecksumConfiguration {
+    type State = TaggedChecksumState;
+
+    fn build(&self) -> Self::State {
+        match self {
+            ChecksumConfiguration::GxHash => TaggedChecksumState::GxHash(GxHashBuilder.build()),
+            ChecksumConfiguration::Crc32c => TaggedChecksumState::Crc32c(Crc32cBuilder.build()),
+        }
+    }
+}
+
+/// The internal state of `TaggedChecksum`.
+pub enum TaggedChecksumState {
+    GxHash(GxHashState),
+    Crc32c(Crc32cState),
+}
+
+impl State for TaggedChecksumState {
+    type Checksum = TaggedChecksum;
+
+    fn ingest(&mut self, data: &[u8]) {
+        match self {
+            TaggedChecksumState::GxHash(state) => state.ingest(data),
+            TaggedChecksumState::Crc32c(state) => state.ingest(data),
+        }
+    }
+
+    fn finish(self) -> Self::Checksum {
+        match self {
+            TaggedChecksumState::GxHash(state) => TaggedChecksum::GxHash(state.finish()),
+            TaggedChecksumState::Crc32c(state) => TaggedChecksum::Crc32c(state.finish()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_with_recorded_algorithm() {
+        let data = [42; 4096];
+        for config in [ChecksumConfiguration::GxHash, ChecksumConfiguration::Crc32c] {
+            let mut state = config.build();
+            state.ingest(&data);
+            let checksum = state.finish();
+            assert!(checksum.verify(&data).is_ok());
+            assert!(checksum.verify(&data[1..]).is_err());
+            let size = bincode::serialized_size(&checksum).unwrap() as usize;
+            assert!(size <= TaggedChecksum::static_size());
+        }
+    }
+}
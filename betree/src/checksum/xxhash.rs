@@ -18,6 +18,7 @@ impl StaticSize for XxHash {
 
 impl Checksum for XxHash {
     type Builder = XxHashBuilder;
+    type Legacy = Self;
 
     fn verify_buffer<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(
         &self,
@@ -0,0 +1,54 @@
+//! The time source of the database.
+//!
+//! Everything whose outcome depends on the time, e.g. snapshot creation
+//! times, modification times of objects, migration rounds and measured write
+//! latencies, reads the clock through this module.  Within a
+//! [Simulation](crate::sim::Simulation) it yields the virtual time of the
+//! simulation on its thread instead of the time of the system.
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Returns the current monotonic time.
+pub(crate) fn now() -> Instant {
+    #[cfg(feature = "sim")]
+    if let Some(clock) = crate::sim::current_clock() {
+        return clock.instant();
+    }
+    Instant::now()
+}
+
+/// Returns the time elapsed since `start`, which has been returned by [now].
+pub(crate) fn elapsed(start: Instant) -> Duration {
+    now().saturating_duration_since(start)
+}
+
+/// Returns the current wall-clock time.
+pub(crate) fn system_now() -> SystemTime {
+    #[cfg(feature = "sim")]
+    if let Some(clock) = crate::sim::current_clock() {
+        return clock.system_time();
+    }
+    SystemTime::now()
+}
+
+/// Blocks for `duration`, which only advances the virtual time within a
+/// simulation.
+pub(crate) fn sleep(duration: Duration) {
+    #[cfg(feature = "sim")]
+    if let Some(clock) = crate::sim::current_clock() {
+        clock.elapse(duration);
+        return;
+    }
+    thread::sleep(duration)
+}
+
+/// Returns whether the current thread runs a simulation.
+pub(crate) fn simulated() -> bool {
+    #[cfg(feature = "sim")]
+    if crate::sim::current_clock().is_some() {
+        return true;
+    }
+    false
+}
@@ -1,11 +1,24 @@
 #![allow(missing_docs, unused_doc_comments)]
-error_chain! {
-    types {
-        Error, ErrorKind, ResultExt, Result;
-    }
-    foreign_links {
-        Io(::std::io::Error);
-    }
+use crate::database::ErrorCode;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
 
-    skip_msg_variant
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Compressing or decompressing data failed.")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("The dictionary {0} the data has been compressed with is not available.")]
+    MissingDictionary(u32),
+}
+
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        // Failures are caused by data which cannot be decoded.
+        ErrorCode::Corrupted
+    }
 }
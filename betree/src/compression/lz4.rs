@@ -1,48 +1,58 @@
-use super::{ CompressionConfiguration, CompressionState, DecompressionState, DecompressionTag, DEFAULT_BUFFER_SIZE, Result };
-use crate::size::StaticSize;
-use crate::buffer::{Buf, BufWrite};
-
+use super::{
+    CompressionBuilder, CompressionState, DecompressionState, DecompressionTag, Result,
+    DEFAULT_BUFFER_SIZE,
+};
+use crate::{
+    buffer::{Buf, BufWrite},
+    size::StaticSize,
+};
+use lz4_sys::{LZ4_compressBound, LZ4_compress_HC, LZ4_compress_default, LZ4_decompress_safe};
 use serde::{Deserialize, Serialize};
-use std::io::{self, Read};
+use std::{
+    io::{self, Write},
+    mem,
+    os::raw::{c_char, c_int},
+};
 
-// use lz4_sys::{ Lz4
+// The highest level supported by the high compression mode.
+const MAX_LEVEL: u8 = 12;
+// Original and compressed size are stored in front of the compressed block.
+const DATA_OFF: usize = 2 * mem::size_of::<u32>();
 
 /// LZ4 compression. (<https://github.com/lz4/lz4>)
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub struct Lz4 {
     /// The compression level which describes the trade-off between
     /// compression ratio and compression speed.
-    /// Lower level provides faster compression at a worse ratio.
-    /// Maximum level is 16, higher values will count as 16.
+    /// Level 0 uses the fast default mode, higher levels the high compression
+    /// mode. Maximum level is 12, higher values will count as 12.
     pub level: u8,
 }
 
-pub struct Lz4Compression {
-    config: Lz4,
-    encoder: Encoder<BufWrite>,
+struct Lz4Compression {
+    level: u8,
+    buf: BufWrite,
 }
 
-pub struct Lz4Decompression;
+struct Lz4Decompression;
 
 impl StaticSize for Lz4 {
-    fn size() -> usize {
+    fn static_size() -> usize {
         1
     }
 }
 
-impl CompressionConfiguration for Lz4 {
+impl CompressionBuilder for Lz4 {
     fn new_compression(&self) -> Result<Box<dyn CompressionState>> {
-        let encoder = EncoderBuilder::new()
-            .level(u32::from(self.level))
-            .checksum(ContentChecksum::NoChecksum)
-            .block_size(BlockSize::Max4MB)
-            .block_mode(BlockMode::Linked)
-            .build(BufWrite::with_capacity(DEFAULT_BUFFER_SIZE))?;
-
-        Ok(Box::new(Lz4Compression { config: self.clone(), encoder }))
+        Ok(Box::new(Lz4Compression {
+            level: self.level.min(MAX_LEVEL),
+            buf: BufWrite::with_capacity(DEFAULT_BUFFER_SIZE),
+        }))
     }
 
-    fn decompression_tag(&self) -> DecompressionTag { DecompressionTag::Lz4 }
+    fn decompression_tag(&self) -> DecompressionTag {
+        DecompressionTag::Lz4
+    }
 }
 
 impl Lz4 {
@@ -53,30 +63,108 @@ impl Lz4 {
 
 impl io::Write for Lz4Compression {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.encoder.write(buf)
+        self.buf.write(buf)
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.encoder.write_all(buf)
+        self.buf.write_all(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.encoder.flush()
+        Ok(())
     }
 }
 
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
 impl CompressionState for Lz4Compression {
-    fn finish(&mut self) -> Buf {
-        let (v, result) = self.encoder.finish();
-        result.unwrap();
-        v.into_buf()
+    fn finish(&mut self, data: Buf) -> Result<Buf> {
+        let len = c_int::try_from(data.len())
+            .map_err(|_| invalid_data("Object exceeds the maximum size of LZ4"))?;
+        let bound = unsafe { LZ4_compressBound(len) };
+        let mut output = vec![0u8; DATA_OFF + bound as usize];
+        let src = data.as_ref().as_ptr() as *const c_char;
+        let dst = output[DATA_OFF..].as_mut_ptr() as *mut c_char;
+        // SAFETY: `dst` has room for the bound of compressing `len` bytes.
+        let compressed = unsafe {
+            match self.level {
+                0 => LZ4_compress_default(src, dst, len, bound),
+                level => LZ4_compress_HC(src, dst, len, bound, level as c_int),
+            }
+        };
+        if compressed <= 0 {
+            return Err(invalid_data("LZ4 compression failed").into());
+        }
+        output[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        output[4..DATA_OFF].copy_from_slice(&(compressed as u32).to_le_bytes());
+        output.truncate(DATA_OFF + compressed as usize);
+        Ok(Buf::from_zero_padded(output))
     }
 }
 
 impl DecompressionState for Lz4Decompression {
-    fn decompress(&mut self, data: &[u8]) -> Result<Box<[u8]>> {
-        let mut output = Vec::with_capacity(DEFAULT_BUFFER_SIZE.to_bytes() as usize);
-        Decoder::new(&data[..])?.read_to_end(&mut output)?;
-        Ok(output.into_boxed_slice())
+    fn decompress(&mut self, data: Buf) -> Result<Buf> {
+        let header = data
+            .get(..DATA_OFF)
+            .ok_or_else(|| invalid_data("LZ4 header is missing"))?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let compressed = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let input = data
+            .get(DATA_OFF..DATA_OFF + compressed)
+            .ok_or_else(|| invalid_data("LZ4 block is truncated"))?;
+        let mut output = vec![0u8; len];
+        // SAFETY: Both buffers are valid for the given sizes and
+        // `LZ4_decompress_safe` never writes beyond the capacity of `output`.
+        let decompressed = unsafe {
+            LZ4_decompress_safe(
+                input.as_ptr() as *const c_char,
+                output.as_mut_ptr() as *mut c_char,
+                compressed as c_int,
+                len as c_int,
+            )
+        };
+        if decompressed < 0 || decompressed as usize != len {
+            return Err(invalid_data("LZ4 block is corrupted").into());
+        }
+        Ok(Buf::from_zero_padded(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn round_trip(level: u8, data: Vec<u8>) {
+        let buf = Buf::from_zero_padded(data);
+        let lz4 = Lz4 { level };
+        let c_buf = lz4.new_compression().unwrap().finish(buf.clone()).unwrap();
+        let mut decomp = lz4.decompression_tag().new_decompression().unwrap();
+        let d_buf = decomp.decompress(c_buf).unwrap();
+        assert_eq!(buf.as_ref(), d_buf.as_ref());
+    }
+
+    #[test]
+    fn encode_then_decode() {
+        let mut buf = vec![0u8; 4 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut buf);
+        round_trip(0, buf.clone());
+        round_trip(9, buf);
+    }
+
+    #[test]
+    fn compresses_redundant_data() {
+        let buf = Buf::from_zero_padded(vec![42u8; 1024 * 1024]);
+        for level in [0, 12, 255] {
+            let c_buf = Lz4 { level }
+                .new_compression()
+                .unwrap()
+                .finish(buf.clone())
+                .unwrap();
+            assert!(c_buf.len() < buf.len() / 16);
+            round_trip(level, vec![42u8; 1024 * 1024]);
+        }
     }
 }
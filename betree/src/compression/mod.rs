@@ -1,6 +1,7 @@
 //! This module provides the `Compression` trait for compressing and
 //! decompressing data.
-//! `None` and `Lz4` are provided as implementation.
+//! `None`, `Lz4` and `Zstd` are provided as implementation, `Zstd` may be
+//! combined with a dictionary as `ZstdDictionary`.
 
 use crate::{
     buffer::{Buf, BufWrite},
@@ -8,17 +9,19 @@ use crate::{
     vdev::Block,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, io::Write, mem};
+use std::{fmt::Debug, io::Write, mem, sync::Arc};
 
 mod errors;
 pub use errors::*;
 
 const DEFAULT_BUFFER_SIZE: Block<u32> = Block(1);
 
+/// The compression codec of new objects. Objects record the codec they have
+/// been compressed with, so the configuration may be changed at any time.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum CompressionConfiguration {
     None,
-    // Lz4,
+    Lz4(Lz4),
     Zstd(Zstd),
 }
 
@@ -26,6 +29,7 @@ impl CompressionConfiguration {
     pub fn to_builder(&self) -> Box<dyn CompressionBuilder> {
         match self {
             CompressionConfiguration::None => Box::new(None),
+            CompressionConfiguration::Lz4(lz4) => Box::new(*lz4),
             CompressionConfiguration::Zstd(zstd) => Box::new(*zstd),
         }
     }
@@ -35,21 +39,45 @@ impl CompressionConfiguration {
 /// method. This differs from a CompressionConfiguration, in that it is not configurable, as
 /// all methods will decompress just fine without knowing at which compression level it was
 /// originally written, so there's no advantage in storing the compression level with each object.
+/// Dictionaries are too large to be stored with each object, only their id is.
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum DecompressionTag {
     None,
     Lz4,
     Zstd,
+    ZstdDictionary(u32),
 }
 
 impl DecompressionTag {
     pub fn new_decompression(&self) -> Result<Box<dyn DecompressionState>> {
+        self.new_decompression_with(Option::None)
+    }
+
+    /// Like `new_decompression`, objects compressed with a dictionary are
+    /// decompressed with `dictionary`, which has to be the one identified by
+    /// [DecompressionTag::dictionary].
+    pub fn new_decompression_with(
+        &self,
+        dictionary: Option<&[u8]>,
+    ) -> Result<Box<dyn DecompressionState>> {
         use DecompressionTag as Tag;
         match self {
             Tag::None => Ok(None::new_decompression()?),
-            Tag::Lz4 => todo!(), //Ok(Lz4::new_decompression()?),
+            Tag::Lz4 => Ok(Lz4::new_decompression()?),
             Tag::Zstd => Ok(Zstd::new_decompression()?),
+            Tag::ZstdDictionary(id) => {
+                let dictionary = dictionary.ok_or(Error::MissingDictionary(*id))?;
+                Ok(ZstdDictionary::new_decompression(dictionary)?)
+            }
+        }
+    }
+
+    /// Returns the id of the dictionary needed for decompression, if any.
+    pub fn dictionary(&self) -> Option<u32> {
+        match self {
+            DecompressionTag::ZstdDictionary(id) => Some(*id),
+            _ => Option::None,
         }
     }
 }
@@ -66,6 +94,16 @@ pub trait CompressionBuilder: Debug + Size + Send + Sync + 'static {
     /// Returns an object for compressing data into a `Box<[u8]>`.
     fn new_compression(&self) -> Result<Box<dyn CompressionState>>;
     fn decompression_tag(&self) -> DecompressionTag;
+
+    /// Returns a builder which additionally uses the trained `dictionary`
+    /// identified by `id`, if this codec supports dictionaries.
+    fn with_dictionary(
+        &self,
+        _id: u32,
+        _dictionary: Arc<[u8]>,
+    ) -> Option<Box<dyn CompressionBuilder>> {
+        Option::None
+    }
 }
 
 /// Trait for the object that compresses data.
@@ -82,8 +120,8 @@ pub trait DecompressionState {
 mod none;
 pub use self::none::None;
 
-//mod lz4;
-//pub use self::lz4::Lz4;
+mod lz4;
+pub use self::lz4::Lz4;
 
 mod zstd;
-pub use self::zstd::Zstd;
+pub use self::zstd::{Zstd, ZstdDictionary};
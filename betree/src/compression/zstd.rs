@@ -5,13 +5,14 @@ use super::{
 use crate::{
     buffer::{Buf, BufWrite},
     database,
-    size::StaticSize,
+    size::{Size, StaticSize},
     vdev::Block,
 };
 use serde::{Deserialize, Serialize};
 use std::{
     io::{self, Cursor, Write},
     mem,
+    sync::Arc,
 };
 use zstd::{
     block::{Compressor, Decompressor},
@@ -22,8 +23,6 @@ use zstd::{
 };
 use zstd_safe::{FrameFormat, InBuffer, OutBuffer, WriteBuf};
 
-// TODO: investigate pre-created dictionary payoff
-
 /// Zstd compression. (<https://github.com/facebook/zstd>)
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub struct Zstd {
@@ -32,6 +31,20 @@ pub struct Zstd {
     pub level: u8,
 }
 
+/// Zstd compression with a dictionary trained on the contents of a dataset,
+/// see `Dataset::train_dictionary`.  Objects compressed with it can only be
+/// decompressed with the same dictionary, so dictionaries are kept as long as
+/// their dataset exists.
+#[derive(Debug, Clone)]
+pub struct ZstdDictionary {
+    /// The compression level, see [Zstd::level].
+    pub level: u8,
+    /// The id of the dictionary, unique within its dataset.
+    pub id: u32,
+    /// The trained dictionary.
+    pub dictionary: Arc<[u8]>,
+}
+
 struct ZstdCompression {
     writer: Encoder<'static>,
 }
@@ -51,31 +64,78 @@ impl CompressionBuilder for Zstd {
     fn new_compression(&self) -> Result<Box<dyn CompressionState>> {
         // "The library supports regular compression levels from 1 up to ZSTD_maxCLevel(),
         // which is currently 22."
-        let mut encoder = Encoder::new(self.level as i32)?;
-
-        // Compression format is stored externally, don't need to duplicate it
-        encoder.set_parameter(CParameter::Format(FrameFormat::Magicless))?;
-        // // Integrity is handled at a different layer
-        encoder.set_parameter(CParameter::ChecksumFlag(false))?;
-
-        Ok(Box::new(ZstdCompression { writer: encoder }))
+        new_compression(Encoder::new(self.level as i32)?)
     }
 
     fn decompression_tag(&self) -> DecompressionTag {
         DecompressionTag::Zstd
     }
+
+    fn with_dictionary(
+        &self,
+        id: u32,
+        dictionary: Arc<[u8]>,
+    ) -> Option<Box<dyn CompressionBuilder>> {
+        Some(Box::new(ZstdDictionary {
+            level: self.level,
+            id,
+            dictionary,
+        }))
+    }
 }
 
 impl Zstd {
     pub fn new_decompression() -> Result<Box<dyn DecompressionState>> {
-        let mut decoder = Decoder::new()?;
-        decoder.set_parameter(DParameter::Format(FrameFormat::Magicless))?;
-        // decoder.set_parameter(DParameter::ForceIgnoreChecksum(true))?;
+        new_decompression(Decoder::new()?)
+    }
 
-        Ok(Box::new(ZstdDecompression { writer: decoder }))
+    /// Trains a dictionary of at most `max_size` bytes on `samples`.
+    pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
     }
 }
 
+impl Size for ZstdDictionary {
+    fn size(&self) -> usize {
+        1 + mem::size_of::<u32>() + self.dictionary.len()
+    }
+}
+
+impl CompressionBuilder for ZstdDictionary {
+    fn new_compression(&self) -> Result<Box<dyn CompressionState>> {
+        new_compression(Encoder::with_dictionary(
+            self.level as i32,
+            &self.dictionary,
+        )?)
+    }
+
+    fn decompression_tag(&self) -> DecompressionTag {
+        DecompressionTag::ZstdDictionary(self.id)
+    }
+}
+
+impl ZstdDictionary {
+    pub fn new_decompression(dictionary: &[u8]) -> Result<Box<dyn DecompressionState>> {
+        new_decompression(Decoder::with_dictionary(dictionary)?)
+    }
+}
+
+fn new_compression(mut encoder: Encoder<'static>) -> Result<Box<dyn CompressionState>> {
+    // Compression format is stored externally, don't need to duplicate it
+    encoder.set_parameter(CParameter::Format(FrameFormat::Magicless))?;
+    // // Integrity is handled at a different layer
+    encoder.set_parameter(CParameter::ChecksumFlag(false))?;
+
+    Ok(Box::new(ZstdCompression { writer: encoder }))
+}
+
+fn new_decompression(mut decoder: Decoder<'static>) -> Result<Box<dyn DecompressionState>> {
+    decoder.set_parameter(DParameter::Format(FrameFormat::Magicless))?;
+    // decoder.set_parameter(DParameter::ForceIgnoreChecksum(true))?;
+
+    Ok(Box::new(ZstdDecompression { writer: decoder }))
+}
+
 impl io::Write for ZstdCompression {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         unimplemented!()
@@ -174,6 +234,37 @@ mod tests {
         assert_eq!(buf.as_ref().len(), d_buf.as_ref().len());
     }
 
+    #[test]
+    fn encode_then_decode_with_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..1000u32)
+            .map(|i| {
+                format!(
+                    "{{\"id\": {i}, \"kind\": \"record\", \"value\": {}}}",
+                    i * 7
+                )
+            })
+            .map(String::into_bytes)
+            .collect();
+        let dictionary: Arc<[u8]> = Zstd::train_dictionary(&samples, 4096).unwrap().into();
+        let zstd = Zstd { level: 1 }
+            .with_dictionary(1, dictionary.clone())
+            .unwrap();
+        assert_eq!(
+            zstd.decompression_tag(),
+            DecompressionTag::ZstdDictionary(1)
+        );
+
+        let buf = Buf::from_zero_padded(samples.concat());
+        let c_buf = zstd.new_compression().unwrap().finish(buf.clone()).unwrap();
+        assert!(zstd.decompression_tag().new_decompression().is_err());
+        let mut decomp = zstd
+            .decompression_tag()
+            .new_decompression_with(Some(&dictionary))
+            .unwrap();
+        let d_buf = decomp.decompress(c_buf).unwrap();
+        assert_eq!(buf.as_ref(), d_buf.as_ref());
+    }
+
     #[test]
     fn sanity() {
         let buf = [42u8, 42];
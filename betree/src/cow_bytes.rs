@@ -1,22 +1,58 @@
 //! This module provides `CowBytes` which is a Copy-on-Write smart pointer
 //! similar to `std::borrow::Cow`.
 
-use crate::size::Size;
+use crate::{buffer::Buf, size::Size};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use stable_deref_trait::StableDeref;
 use std::{
     borrow::Borrow,
-    cmp,
-    ops::{Deref, DerefMut},
+    cmp, fmt,
+    hash::{Hash, Hasher},
+    ops::{Deref, DerefMut, Range},
     sync::Arc,
 };
 
 /// Copy-on-Write smart pointer which supports cheap cloning as it is
 /// reference-counted.
-#[derive(Hash, Debug, Clone, Eq, Ord, Default)]
+#[derive(Clone)]
 pub struct CowBytes {
-    // TODO Replace by own implementation
-    pub(super) inner: Arc<Vec<u8>>,
+    inner: Inner,
+}
+
+#[derive(Clone)]
+enum Inner {
+    Vec(Arc<Vec<u8>>),
+    // A byte range of a buffer read from a vdev, which stays on the
+    // allocation of the buffer, e.g. on huge pages, until it is modified.
+    Buf(Buf, Range<usize>),
+}
+
+impl Default for CowBytes {
+    fn default() -> Self {
+        CowBytes {
+            inner: Inner::Vec(Arc::default()),
+        }
+    }
+}
+
+impl fmt::Debug for CowBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CowBytes").field("inner", &&**self).finish()
+    }
+}
+
+impl Eq for CowBytes {}
+
+impl Hash for CowBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl Ord for CowBytes {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
 }
 
 impl<T: AsRef<[u8]>> PartialEq<T> for CowBytes {
@@ -80,29 +116,27 @@ impl<'de> Deserialize<'de> for CowBytes {
 
 impl Size for CowBytes {
     fn size(&self) -> usize {
-        8 + self.inner.len()
+        8 + self.len()
     }
 }
 
 impl<'a> From<&'a [u8]> for CowBytes {
     fn from(x: &'a [u8]) -> Self {
-        CowBytes {
-            inner: Arc::new(x.to_vec()),
-        }
+        CowBytes::from(x.to_vec())
     }
 }
 
 impl From<Box<[u8]>> for CowBytes {
     fn from(x: Box<[u8]>) -> Self {
-        CowBytes {
-            inner: Arc::new(x.into_vec()),
-        }
+        CowBytes::from(x.into_vec())
     }
 }
 
 impl From<Vec<u8>> for CowBytes {
     fn from(x: Vec<u8>) -> Self {
-        CowBytes { inner: Arc::new(x) }
+        CowBytes {
+            inner: Inner::Vec(Arc::new(x)),
+        }
     }
 }
 
@@ -123,13 +157,16 @@ unsafe impl StableDeref for CowBytes {}
 impl Deref for CowBytes {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
-        &self.inner
+        match &self.inner {
+            Inner::Vec(vec) => vec,
+            Inner::Buf(buf, range) => &buf[range.clone()],
+        }
     }
 }
 
 impl DerefMut for CowBytes {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut Arc::make_mut(&mut self.inner)[..]
+        &mut self.make_mut()[..]
     }
 }
 
@@ -148,30 +185,52 @@ impl CowBytes {
     pub fn new() -> Self {
         CowBytes::default()
     }
+    /// Returns the bytes `range` of `buf` without copying them.  They are
+    /// copied once they are modified.
+    pub fn from_buf(buf: Buf, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= buf.len());
+        CowBytes {
+            inner: Inner::Buf(buf, range),
+        }
+    }
+
     /// Returns the length of the byte buffer.
     #[inline]
     pub fn len(&self) -> usize {
-        self.inner.len()
+        match &self.inner {
+            Inner::Vec(vec) => vec.len(),
+            Inner::Buf(_, range) => range.len(),
+        }
     }
 
     /// Returns whether this buffer is empty.
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+        self.len() == 0
     }
 
     /// Create a new, empty `CowBytes` with the given capacity.
     #[inline]
     pub fn with_capacity(cap: usize) -> Self {
-        CowBytes {
-            inner: Arc::new(Vec::with_capacity(cap)),
+        CowBytes::from(Vec::with_capacity(cap))
+    }
+
+    // Returns the bytes for modification, which are copied if they are
+    // shared or still on a buffer.
+    fn make_mut(&mut self) -> &mut Vec<u8> {
+        if let Inner::Buf(buf, range) = &self.inner {
+            self.inner = Inner::Vec(Arc::new(buf[range.clone()].to_vec()));
+        }
+        match &mut self.inner {
+            Inner::Vec(vec) => Arc::make_mut(vec),
+            Inner::Buf(..) => unreachable!(),
         }
     }
 
     /// Pushes a byte slice onto the end of the byte buffer.
     #[inline]
     pub fn push_slice(&mut self, v: &[u8]) {
-        Arc::make_mut(&mut self.inner).extend_from_slice(v)
+        self.make_mut().extend_from_slice(v)
     }
 
     /// Fills the buffer with zeros up to `size`.
@@ -187,16 +246,16 @@ impl CowBytes {
     /// Returns the size (number of bytes) that this object would have
     /// if serialized using `bincode`.
     pub fn size(&self) -> usize {
-        8 + self.inner.len()
+        8 + self.len()
     }
 
     /// Returns the underlying data as `Vec<u8>`.
     /// If this object is the only reference to the data,
     /// this functions avoids copying the underlying data.
     pub fn into_vec(self) -> Vec<u8> {
-        match Arc::try_unwrap(self.inner) {
-            Ok(v) => v,
-            Err(this) => Vec::clone(&this),
+        match self.inner {
+            Inner::Vec(vec) => Arc::try_unwrap(vec).unwrap_or_else(|this| Vec::clone(&this)),
+            Inner::Buf(buf, range) => buf[range].to_vec(),
         }
     }
 
@@ -214,11 +273,17 @@ impl CowBytes {
 
 impl<'a> Extend<&'a u8> for CowBytes {
     fn extend<T: IntoIterator<Item = &'a u8>>(&mut self, iter: T) {
-        Arc::make_mut(&mut self.inner).extend(iter)
+        self.make_mut().extend(iter)
     }
 }
 
 /// Reference-counted pointer which points to a subslice of the referenced data.
+///
+/// Values read from a tree point into the buffer of the node or message they
+/// are stored in instead of being copied out of it.  The buffer stays alive
+/// as long as a value referencing it is held, even if the node has been
+/// evicted from the cache in the meantime, so long-lived small values should
+/// be copied to release large node buffers.
 #[derive(Debug, Default, Clone)]
 pub struct SlicedCowBytes {
     pub(super) data: CowBytes,
@@ -304,8 +369,8 @@ impl Deref for SlicedCowBytes {
 
 #[cfg(test)]
 mod tests {
-    use super::{Arc, CowBytes};
-    use crate::arbitrary::GenExt;
+    use super::CowBytes;
+    use crate::{arbitrary::GenExt, buffer::Buf};
     use quickcheck::{Arbitrary, Gen};
     use rand::{Rng, RngCore};
 
@@ -315,13 +380,23 @@ mod tests {
             let len = rng.gen_range(0..128);
             let mut bytes = vec![0; len];
             rng.fill_bytes(&mut bytes);
-            CowBytes {
-                inner: Arc::new(bytes),
-            }
+            CowBytes::from(bytes)
         }
 
         fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-            Box::new(self.inner.shrink().map(|inner| CowBytes { inner }))
+            Box::new(self.to_vec().shrink().map(CowBytes::from))
         }
     }
+
+    #[test]
+    fn buf_is_copied_on_write() {
+        let buf = Buf::from_zero_padded(vec![1, 2, 3, 4]);
+        let mut bytes = CowBytes::from_buf(buf, 1..3);
+        assert_eq!(bytes, [2, 3]);
+        let shared = bytes.clone();
+        bytes.push_slice(&[5]);
+        assert_eq!(bytes, [2, 3, 5]);
+        assert_eq!(shared, [2, 3]);
+        assert_eq!(shared.into_vec(), vec![2, 3]);
+    }
 }
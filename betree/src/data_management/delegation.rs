@@ -1,7 +1,14 @@
-use crate::{database::DatasetId, tree::PivotKey};
+use crate::{
+    database::{DatasetId, EventHandlers},
+    tree::PivotKey,
+};
 
 use super::{Dml, Error};
-use std::ops::{Deref, DerefMut};
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+};
 
 impl<T> Dml for T
 where
@@ -69,6 +76,10 @@ where
         (**self).verify_cache()
     }
 
+    fn events(&self) -> &EventHandlers {
+        (**self).events()
+    }
+
     fn root_ref_from_ptr(r: Self::ObjectPointer) -> Self::ObjectRef {
         <T::Target as Dml>::root_ref_from_ptr(r)
     }
@@ -91,6 +102,13 @@ where
         (**self).finish_prefetch(p)
     }
 
+    fn finish_prefetch_async<'a>(
+        &'a self,
+        p: Self::Prefetch,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        (**self).finish_prefetch_async(p)
+    }
+
     fn cache_stats(&self) -> Self::CacheStats {
         (**self).cache_stats()
     }
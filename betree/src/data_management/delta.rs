@@ -0,0 +1,47 @@
+//! Delta encoding of leaves which changed only slightly.
+//!
+//! A modified leaf is written as a delta, which holds only the entries
+//! changed since the leaf has been written in full the last time, the base of
+//! the delta.  Deltas are cumulative, so reading a leaf takes at most two
+//! reads.  The leaf is written in full again when the changes grow too large,
+//! after a number of deltas or when the base has to be moved, e.g. off an
+//! evacuated disk.
+//!
+//! Which nodes are deltas of which base is recorded by the handler, as the
+//! object pointers do not tell them apart.  The handler counts the references
+//! to each base by deltas and modified leaves.  As deltas may be retained by
+//! snapshots, a base is only deallocated once the last delta referring to it
+//! is.
+
+use serde::{Deserialize, Serialize};
+
+/// When leaves are written as deltas, see the module documentation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct DeltaConfiguration {
+    /// The largest share of the size of a leaf its changed entries may make
+    /// up for it to be written as a delta.
+    pub max_changed_share: f32,
+    /// The number of deltas written for the same base, after which the leaf
+    /// is written in full again.
+    pub max_deltas: u32,
+}
+
+impl Default for DeltaConfiguration {
+    fn default() -> Self {
+        DeltaConfiguration {
+            max_changed_share: 0.25,
+            max_deltas: 16,
+        }
+    }
+}
+
+/// The node a delta refers to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaBase<P> {
+    /// The leaf as written in full.
+    pub base: P,
+    /// The number of deltas written for this base so far, including the one
+    /// referring to it.
+    pub deltas: u32,
+}
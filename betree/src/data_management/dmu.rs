@@ -1,67 +1,169 @@
 use super::{
     cache_value::{CacheValueRef, TaggedCacheValue},
+    delta::{DeltaBase, DeltaConfiguration},
     errors::*,
     impls::{ModifiedObjectId, ObjRef, ObjectKey},
+    l2_cache::{L2Cache, L2CacheStats},
     object_ptr::ObjectPointer,
-    CopyOnWriteEvent, Dml, HasStoragePreference, Object, ObjectReference,
+    CopyOnWriteEvent, Displacement, Dml, HasStoragePreference, Object, ObjectReference,
 };
 use crate::{
-    allocator::{Action, SegmentAllocator, SegmentId, SEGMENT_SIZE},
+    allocator::{Action, Extent, SegmentAllocator, SegmentId, SEGMENT_SIZE},
     buffer::Buf,
     cache::{Cache, ChangeKeyError, RemoveError},
     checksum::{Builder, Checksum, State},
-    compression::CompressionBuilder,
+    clock,
+    compression::{self, CompressionBuilder},
     data_management::CopyOnWriteReason,
-    database::{DatasetId, Generation, Handler},
+    database::{DatasetId, Event, EventHandlers, Generation, Handler},
+    encryption::{self, Encryption, EncryptionTag},
     migration::DmlMsg,
     size::{Size, SizeMut, StaticSize},
-    storage_pool::{DiskOffset, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    storage_pool::{
+        io_priority, with_io_priority, DiskOffset, GlobalDiskId, StoragePoolLayer,
+        TierConfiguration, NUM_STORAGE_CLASSES,
+    },
     tree::{Node, PivotKey},
-    vdev::{Block, File, BLOCK_SIZE},
+    vdev::{Block, Error as VdevError, File, BLOCK_SIZE},
     StoragePreference,
 };
 use byteorder::{LittleEndian, WriteBytesExt};
 use crossbeam_channel::Sender;
-use futures::{executor::block_on, future::ok, prelude::*};
+use futures::{
+    executor::block_on,
+    future::{self, ok},
+    prelude::*,
+};
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::{
     arch::x86_64::{__rdtscp, _rdtsc},
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::OpenOptions,
     io::{BufWriter, Write},
+    iter,
     mem::replace,
-    ops::DerefMut,
+    ops::{DerefMut, Range},
     path::PathBuf,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    thread::yield_now,
+    thread::{self, yield_now},
 };
 
+// The number of segments of each disk which are allocated from concurrently.
+const ALLOCATION_SHARDS: usize = 4;
+
+static NEXT_ALLOCATION_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Threads are distributed round-robin over the allocation shards.
+    static ALLOCATION_SHARD: usize =
+        NEXT_ALLOCATION_SHARD.fetch_add(1, Ordering::Relaxed) % ALLOCATION_SHARDS;
+}
+
+// Allocation state of a top-level vdev.
+#[derive(Default)]
+struct DiskAllocation {
+    // The segments the shards continue allocating in.  Each thread allocates
+    // with the cursor of its shard, so that concurrent write-backs use
+    // different segments instead of contending on the same allocator.
+    cursors: [Mutex<Option<SegmentId>>; ALLOCATION_SHARDS],
+    // Serializes allocations which may span several segments and holds the
+    // smallest extent which has not been found on the disk.
+    extents: Mutex<Option<MissingExtent>>,
+}
+
+// An extent size for which the whole disk has been searched in vain.  The
+// allocators only gain free blocks on the generation bump and when segments
+// are no longer defragmented, until then no larger extent can be found
+// either.
+struct MissingExtent {
+    generation: Generation,
+    disk_size: Block<u64>,
+    size: Block<u32>,
+}
+
+impl DiskAllocation {
+    // The segment a shard starts allocating in, shards are spread evenly
+    // over the disk.
+    fn first_segment(class: u8, disk_id: u16, disk_size: Block<u64>, shard: usize) -> SegmentId {
+        let segments = (disk_size.as_u64() + SEGMENT_SIZE as u64 - 1) / SEGMENT_SIZE as u64;
+        let index = segments * shard as u64 / ALLOCATION_SHARDS as u64;
+        SegmentId::get(DiskOffset::new(
+            class,
+            disk_id,
+            Block(index * SEGMENT_SIZE as u64),
+        ))
+    }
+}
+
+// An object which has been packed, compressed and checksummed for its write
+// back, but not yet been written.
+struct PreparedWrite<C> {
+    data: Buf,
+    obj_ptr: ObjectPointer<C>,
+    mid: ModifiedObjectId,
+    pivot_key: PivotKey,
+    object_size: usize,
+    // The base if the object is written as a delta.
+    delta: Option<DeltaBase<ObjectPointer<C>>>,
+}
+
 /// The Data Management Unit.
 pub struct Dmu<E: 'static, SPL: StoragePoolLayer>
 where
     SPL::Checksum: StaticSize,
 {
     default_compression: Box<dyn CompressionBuilder>,
+    // Compression of datasets which differs from the default.
+    dataset_compression: RwLock<HashMap<DatasetId, Box<dyn CompressionBuilder>>>,
+    // The trained compression dictionaries of each dataset by their id, the
+    // newest one is used for nodes written from now on.
+    dictionaries: RwLock<HashMap<DatasetId, BTreeMap<u32, Arc<[u8]>>>>,
     // NOTE: Why was this included in the first place? Delayed Compression? Streaming Compression?
     // default_compression_state: C::CompressionState,
     default_storage_class: u8,
     default_checksum_builder: <SPL::Checksum as Checksum>::Builder,
+    encryption: Option<Encryption>,
+    delta_encoding: Option<DeltaConfiguration>,
     alloc_strategy: [[Option<u8>; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES],
     pool: SPL,
     cache: RwLock<E>,
+    // Clean nodes evicted from `cache` which are staged on a fast storage
+    // class.
+    l2_cache: Option<L2Cache<SPL::Checksum>>,
+    // The segment the second-level cache last allocated in.
+    l2_cache_cursor: Mutex<Option<SegmentId>>,
+    // The number of threads writing back the dirty nodes of a subtree.
+    write_back_threads: usize,
     written_back: Mutex<HashMap<ModifiedObjectId, ObjectPointer<SPL::Checksum>>>,
     modified_info: Mutex<HashMap<ModifiedObjectId, DatasetId>>,
+    // The bases modified leaves may be written as deltas of, each holding a
+    // reference to its base.
+    modified_bases: Mutex<HashMap<ModifiedObjectId, DeltaBase<ObjectPointer<SPL::Checksum>>>>,
     storage_hints: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+    // Nodes which have been written to a fallback storage class, so that the
+    // migration policies can move them back once there is space again.
+    displaced_nodes: Arc<Mutex<HashMap<PivotKey, Displacement>>>,
     handler: Handler<ObjRef<ObjectPointer<SPL::Checksum>>>,
     // NOTE: The semantic structure of this looks as this
     // Storage Pool Layers:
     //      Layer Disks:
-    //          Tuple of SegmentIDs and their according Allocators
-    allocation_data: Box<[Box<[Mutex<Option<SegmentId>>]>]>,
+    //          Cursors of the allocation shards
+    // Disks added to a running pool are only considered for allocations once
+    // they have been pushed here.
+    allocation_data: Box<[RwLock<Vec<DiskAllocation>>]>,
+    // Disks which are being evacuated and are skipped on allocation.
+    evacuating_disks: RwLock<HashSet<GlobalDiskId>>,
+    // Segments which are being defragmented and are skipped on allocation.
+    defragmenting_segments: RwLock<HashSet<SegmentId>>,
+    // The end of the last object written back for each dataset, objects of
+    // the same dataset are placed after it on sequential storage tiers.
+    last_allocations: Mutex<HashMap<DatasetId, DiskOffset>>,
+    // The lowest level of the nodes of each dataset which are never evicted.
+    pinned_levels: RwLock<HashMap<DatasetId, u32>>,
     next_modified_node_id: AtomicU64,
     next_disk_id: AtomicU64,
     report_tx: Option<Sender<DmlMsg>>,
@@ -78,19 +180,24 @@ where
     pub fn new(
         default_compression: Box<dyn CompressionBuilder>,
         default_checksum_builder: <SPL::Checksum as Checksum>::Builder,
+        encryption: Option<Encryption>,
+        delta_encoding: Option<DeltaConfiguration>,
         default_storage_class: u8,
         pool: SPL,
         alloc_strategy: [[Option<u8>; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES],
         cache: E,
+        l2_cache: Option<L2Cache<SPL::Checksum>>,
+        write_back_threads: usize,
         handler: Handler<ObjRef<ObjectPointer<SPL::Checksum>>>,
         #[cfg(feature = "allocation_log")] allocation_log_file_path: PathBuf,
     ) -> Self {
         let allocation_data = (0..pool.storage_class_count())
             .map(|class| {
-                (0..pool.disk_count(class))
-                    .map(|_| Mutex::new(None))
-                    .collect::<Vec<_>>()
-                    .into_boxed_slice()
+                RwLock::new(
+                    (0..pool.disk_count(class))
+                        .map(|_| DiskAllocation::default())
+                        .collect::<Vec<_>>(),
+                )
             })
             .collect::<Vec<_>>()
             .into_boxed_slice();
@@ -108,16 +215,29 @@ where
         Dmu {
             // default_compression_state: default_compression.new_compression().expect("Can't create compression state"),
             default_compression,
+            dataset_compression: RwLock::new(HashMap::new()),
+            dictionaries: RwLock::new(HashMap::new()),
             default_storage_class,
             default_checksum_builder,
+            encryption,
+            delta_encoding,
             alloc_strategy,
             pool,
             cache: RwLock::new(cache),
+            l2_cache,
+            l2_cache_cursor: Mutex::new(None),
+            write_back_threads: write_back_threads.max(1),
             written_back: Mutex::new(HashMap::new()),
             modified_info: Mutex::new(HashMap::new()),
+            modified_bases: Mutex::new(HashMap::new()),
             storage_hints: Arc::new(Mutex::new(HashMap::new())),
+            displaced_nodes: Arc::new(Mutex::new(HashMap::new())),
             handler,
             allocation_data,
+            evacuating_disks: RwLock::new(HashSet::new()),
+            defragmenting_segments: RwLock::new(HashSet::new()),
+            last_allocations: Mutex::new(HashMap::new()),
+            pinned_levels: RwLock::new(HashMap::new()),
             next_modified_node_id: AtomicU64::new(1),
             next_disk_id: AtomicU64::new(0),
             report_tx: None,
@@ -141,6 +261,75 @@ where
         &self.pool
     }
 
+    /// Returns the usage of the second-level cache, if there is one.
+    pub fn l2_cache_stats(&self) -> Option<L2CacheStats> {
+        self.l2_cache.as_ref().map(L2Cache::stats)
+    }
+
+    /// Compresses nodes of the dataset `id` written from now on with
+    /// `compression` instead of the default compression.
+    pub(crate) fn set_dataset_compression(
+        &self,
+        id: DatasetId,
+        compression: Box<dyn CompressionBuilder>,
+    ) {
+        self.dataset_compression.write().insert(id, compression);
+    }
+
+    /// Registers the trained compression dictionary `dictionary_id` of the
+    /// dataset `id`.  Nodes of the dataset written from now on are
+    /// compressed with the newest dictionary if its compression supports
+    /// dictionaries.
+    pub(crate) fn add_dictionary(&self, id: DatasetId, dictionary_id: u32, dictionary: Arc<[u8]>) {
+        self.dictionaries
+            .write()
+            .entry(id)
+            .or_default()
+            .insert(dictionary_id, dictionary);
+    }
+
+    /// Returns the id of the newest compression dictionary of the dataset
+    /// `id`, if any.
+    pub(crate) fn newest_dictionary(&self, id: DatasetId) -> Option<u32> {
+        self.dictionaries
+            .read()
+            .get(&id)
+            .and_then(|dictionaries| dictionaries.keys().next_back().copied())
+    }
+
+    // Decompresses the object `op` points to, looking up the dictionary of
+    // its dataset it has been compressed with.
+    fn decompress(&self, op: &<Self as Dml>::ObjectPointer, data: Buf) -> Result<Buf, Error> {
+        let tag = op.decompression_tag();
+        let dictionary = tag.dictionary().and_then(|id| {
+            self.dictionaries
+                .read()
+                .get(&op.info())
+                .and_then(|dictionaries| dictionaries.get(&id).cloned())
+        });
+        Ok(tag
+            .new_decompression_with(dictionary.as_deref())?
+            .decompress(data)?)
+    }
+
+    /// Keeps the cached nodes of the dataset `id` at `min_level` and above,
+    /// where leaves are at level 0, from being evicted.  Nodes of the dataset
+    /// are evicted as usual if `min_level` is `None`.
+    pub(crate) fn set_pinned_levels(&self, id: DatasetId, min_level: Option<u32>) {
+        let mut pinned_levels = self.pinned_levels.write();
+        match min_level {
+            Some(min_level) => pinned_levels.insert(id, min_level),
+            None => pinned_levels.remove(&id),
+        };
+    }
+
+    /// Returns the tag of newly written objects.
+    pub(crate) fn encryption_tag(&self) -> EncryptionTag {
+        self.encryption
+            .as_ref()
+            .map_or(EncryptionTag::None, Encryption::tag)
+    }
+
     /// Writes the global header for the allocation logging.
     pub fn write_global_header(&self) -> Result<(), Error> {
         #[cfg(feature = "allocation_log")]
@@ -212,11 +401,73 @@ where
         let obj = CacheValueRef::write(entry);
 
         if let ObjRef::Unmodified(ptr, ..) = replace(or, ObjRef::Modified(mid, pk)) {
-            self.copy_on_write(ptr, CopyOnWriteReason::Steal, or.index().clone());
+            let base = if obj.level() == 0 {
+                self.retain_base(&ptr)
+            } else {
+                None
+            };
+            // A leaf written in full is kept as the base of its next version.
+            let is_base = base
+                .as_ref()
+                .map_or(false, |base| base.base.offset() == ptr.offset());
+            if let Some(base) = base {
+                self.modified_bases.lock().insert(mid, base);
+            }
+            if !is_base {
+                self.copy_on_write(ptr, CopyOnWriteReason::Steal, or.index().clone());
+            }
         }
         Ok(Some(obj))
     }
 
+    // Returns the base the leaf stored at `ptr` may be written as a delta of
+    // once modified, holding a reference to it.  This is the base of `ptr` if
+    // it is a delta, and `ptr` itself otherwise.
+    fn retain_base(
+        &self,
+        ptr: &ObjectPointer<SPL::Checksum>,
+    ) -> Option<DeltaBase<ObjectPointer<SPL::Checksum>>> {
+        if self.delta_encoding.is_none() || !self.handler.supports_deltas(ptr.info()) {
+            return None;
+        }
+        let base = self
+            .handler
+            .delta_base(ptr.offset(), ptr.info())
+            .unwrap_or_else(|| DeltaBase {
+                base: ptr.clone(),
+                deltas: 0,
+            });
+        self.handler.retain_delta_base(base.base.offset());
+        Some(base)
+    }
+
+    // Drops the reference to `base` and deallocates it if it has been the
+    // last one.
+    fn release_base(&self, base: ObjectPointer<SPL::Checksum>) {
+        if self.handler.release_delta_base(base.offset()) {
+            let pivot_key = PivotKey::Root(base.info());
+            self.copy_on_write(base, CopyOnWriteReason::Steal, pivot_key);
+        }
+    }
+
+    // Releases the base a removed modified leaf would have been written as a
+    // delta of.
+    fn release_modified_base(&self, or: &<Self as Dml>::ObjectRef) {
+        if let ObjRef::Modified(mid, _) | ObjRef::InWriteback(mid, _) = or {
+            if let Some(base) = self.modified_bases.lock().remove(mid) {
+                self.release_base(base.base);
+            }
+        }
+    }
+
+    /// Releases the base of the node at `offset` if it is a delta, which has
+    /// to be called once the node has been deallocated.
+    pub(crate) fn release_delta(&self, offset: DiskOffset) {
+        if let Some(delta) = self.handler.remove_delta(offset) {
+            self.release_base(delta.base);
+        }
+    }
+
     /// Will be called when `or` is not in cache but was modified.
     /// Resolves two cases:
     ///     - Previous write back (`Modified(_)`) Will change `or` to
@@ -246,6 +497,13 @@ where
         steal: CopyOnWriteReason,
         pivot_key: PivotKey,
     ) {
+        if let Some(staged) = self
+            .l2_cache
+            .as_ref()
+            .and_then(|l2_cache| l2_cache.invalidate(obj_ptr.offset()))
+        {
+            self.handler.free_cache_allocation(staged.offset());
+        }
         let actual_size = self.pool.actual_size(
             obj_ptr.offset().storage_class(),
             obj_ptr.offset().disk_id(),
@@ -260,44 +518,139 @@ where
             let _ = file.write_u64::<LittleEndian>(0);
             let _ = file.write_u64::<LittleEndian>(0);
         }
-        if let (CopyOnWriteEvent::Removed, Some(tx), CopyOnWriteReason::Remove) = (
-            self.handler.copy_on_write(
-                obj_ptr.offset(),
-                actual_size,
-                obj_ptr.generation(),
-                obj_ptr.info(),
-            ),
-            &self.report_tx,
-            steal,
-        ) {
+        self.handler.update_logical_usage(
+            obj_ptr.info(),
+            obj_ptr.logical_size(),
+            Action::Deallocate,
+        );
+        if steal == CopyOnWriteReason::Remove {
+            self.displaced_nodes.lock().remove(&pivot_key);
+        }
+        let event = self.handler.copy_on_write(
+            obj_ptr.offset(),
+            actual_size,
+            obj_ptr.generation(),
+            obj_ptr.info(),
+        );
+        if let CopyOnWriteEvent::Removed = event {
+            self.release_delta(obj_ptr.offset());
+        }
+        if let (CopyOnWriteEvent::Removed, Some(tx), CopyOnWriteReason::Remove) =
+            (event, &self.report_tx, steal)
+        {
             let _ = tx
                 .send(DmlMsg::remove(obj_ptr.offset(), obj_ptr.size(), pivot_key))
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
         }
     }
 
+    fn decrypt(&self, op: &<Self as Dml>::ObjectPointer, data: Buf) -> Result<Buf, Error> {
+        Ok(encryption::decrypt(
+            self.encryption.as_ref(),
+            op.encryption_tag(),
+            data,
+            op.offset(),
+        )?)
+    }
+
     /// Fetches synchronously an object from disk and inserts it into the
     /// cache.
     fn fetch(&self, op: &<Self as Dml>::ObjectPointer, pivot_key: PivotKey) -> Result<(), Error> {
         // FIXME: reuse decompression_state
         debug!("Fetching {op:?}");
-        let mut decompression_state = op.decompression_tag().new_decompression()?;
         let offset = op.offset();
         let generation = op.generation();
 
-        let compressed_data = self
-            .pool
-            .read(op.size(), op.offset(), op.checksum().clone())?;
-
-        let object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> = {
-            let data = decompression_state.decompress(compressed_data)?;
-            Object::unpack_at(op.offset(), op.info(), data.into_boxed_slice())?
+        let object = match self.fetch_staged(op)? {
+            Some(object) => object,
+            None => self.read_object(op)?,
         };
         let key = ObjectKey::Unmodified { offset, generation };
         self.insert_object_into_cache(key, TaggedCacheValue::new(RwLock::new(object), pivot_key));
         Ok(())
     }
 
+    // Reads the object `op` points to from disk, a delta is applied to its
+    // base.
+    fn read_object(
+        &self,
+        op: &<Self as Dml>::ObjectPointer,
+    ) -> Result<Node<ObjRef<ObjectPointer<SPL::Checksum>>>, Error> {
+        let data = op
+            .read_from(&self.pool)
+            .map_err(|error| self.read_failed(op, error))?;
+        self.report_repairs(op.offset());
+        let compressed_data = self.decrypt(op, data)?;
+        let data = self.decompress(op, compressed_data)?;
+        match self.handler.delta_base(op.offset(), op.info()) {
+            Some(delta) => {
+                let mut object = self.read_object(&delta.base)?;
+                object.apply_delta(&data)?;
+                Ok(object)
+            }
+            None => Ok(Object::unpack_at(op.offset(), op.info(), data)?),
+        }
+    }
+
+    // Notifies the event handlers about the failed read of `op` and adds the
+    // location to the error.
+    fn read_failed(&self, op: &<Self as Dml>::ObjectPointer, error: VdevError) -> Error {
+        self.handler.events.emit(|| Event::ReadFailed {
+            offset: op.offset(),
+            size: op.size(),
+            error: error.clone(),
+        });
+        Error::ReadFailed {
+            dataset: op.info(),
+            offset: op.offset(),
+            size: op.size(),
+            source: error,
+        }
+    }
+
+    // Notifies the event handlers about the faulted replicas of the vdev of
+    // `offset` which have been repaired by reads.
+    fn report_repairs(&self, offset: DiskOffset) {
+        let repairs = self
+            .pool
+            .take_repairs(offset.storage_class(), offset.disk_id());
+        for (disk, offset) in repairs {
+            self.handler
+                .events
+                .emit(|| Event::MirrorRepaired { disk, offset });
+        }
+    }
+
+    /// Reads the object `op` points to from the second-level cache, if it
+    /// has been staged there.
+    fn fetch_staged(
+        &self,
+        op: &<Self as Dml>::ObjectPointer,
+    ) -> Result<Option<Node<ObjRef<ObjectPointer<SPL::Checksum>>>>, Error> {
+        let l2_cache = match &self.l2_cache {
+            Some(l2_cache) => l2_cache,
+            None => return Ok(None),
+        };
+        let staged = match l2_cache.get(op.offset(), op.generation()) {
+            Some(staged) => staged,
+            None => return Ok(None),
+        };
+        let data = match staged.read_from(&self.pool) {
+            Ok(data) => data,
+            Err(e) => {
+                // The original is still intact.
+                warn!("Reading staged {staged:?} failed: {e:?}");
+                if let Some(staged) = l2_cache.invalidate(op.offset()) {
+                    self.handler.free_cache_allocation(staged.offset());
+                }
+                return Ok(None);
+            }
+        };
+        let compressed_data = self.decrypt(&staged, data)?;
+        let data = self.decompress(&staged, compressed_data)?;
+        Ok(Some(Object::unpack_at(op.offset(), op.info(), data)?))
+    }
+
     /// Fetches asynchronously an object from disk and inserts it into the
     /// cache.
     fn try_fetch_async(
@@ -316,14 +669,62 @@ where
         Error,
     > {
         let ptr = op.clone();
+        let (dataset, offset, size) = (op.info(), op.offset(), op.size());
+        let events = Arc::clone(&self.handler.events);
 
-        Ok(self
-            .pool
-            .read_async(op.size(), op.offset(), op.checksum().clone())?
-            .map_err(Error::from)
+        // Unpadded objects are copied from the mapping right away.
+        let read = if op.is_unpadded() {
+            future::Either::Left(future::ready(op.read_from(&self.pool)))
+        } else {
+            future::Either::Right(
+                self.pool
+                    .read_async(op.size(), op.offset(), op.checksum().clone())?
+                    .into_future(),
+            )
+        };
+        Ok(read
+            .map_err(move |error| {
+                events.emit(|| Event::ReadFailed {
+                    offset,
+                    size,
+                    error: error.clone(),
+                });
+                Error::ReadFailed {
+                    dataset,
+                    offset,
+                    size,
+                    source: error,
+                }
+            })
             .and_then(move |data| ok((ptr, data, pivot_key))))
     }
 
+    // Inserts an object read by a prefetch into the cache.
+    fn insert_prefetched(
+        &self,
+        ptr: ObjectPointer<SPL::Checksum>,
+        data: Buf,
+        pk: PivotKey,
+    ) -> Result<(), Error> {
+        self.report_repairs(ptr.offset());
+        let object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> = {
+            let compressed_data = self.decrypt(&ptr, data)?;
+            let data = self.decompress(&ptr, compressed_data)?;
+            Object::unpack_at(ptr.offset(), ptr.info(), data)?
+        };
+        let key = ObjectKey::Unmodified {
+            offset: ptr.offset(),
+            generation: ptr.generation(),
+        };
+        self.insert_object_into_cache(key, TaggedCacheValue::new(RwLock::new(object), pk.clone()));
+        if let Some(report_tx) = &self.report_tx {
+            let _ = report_tx
+                .send(DmlMsg::fetch(ptr.offset(), ptr.size(), pk))
+                .map_err(|_| warn!("Channel Receiver has been dropped."));
+        }
+        Ok(())
+    }
+
     fn insert_object_into_cache(&self, key: ObjectKey<Generation>, mut object: E::Value) {
         let size = object.value_mut().get_mut().size();
         let mut cache = self.cache.write();
@@ -351,8 +752,13 @@ where
         // If ok -> done
         // If this fails, call copy_on_write as object has been modified again
 
+        let pinned_levels = self.pinned_levels.read();
         let evict_result = cache.evict(|&key, entry, cache_contains_key| {
+            let min_level = pinned_levels.get(&entry.tag().d_id()).copied();
             let object = entry.value_mut().get_mut();
+            if min_level.map_or(false, |min_level| object.level() >= min_level) {
+                return None;
+            }
             let can_be_evicted = match key {
                 ObjectKey::InWriteback(_) => false,
                 ObjectKey::Unmodified { .. } => true,
@@ -381,6 +787,7 @@ where
                 None
             }
         });
+        drop(pinned_levels);
         let (key, mut object) = match evict_result {
             None => return Ok(()),
             Some((key, object)) => (key, object),
@@ -388,7 +795,28 @@ where
 
         let mid = match key {
             ObjectKey::InWriteback(_) => unreachable!(),
-            ObjectKey::Unmodified { .. } => return Ok(()),
+            ObjectKey::Unmodified { offset, generation } => {
+                // Deltas are not staged, as a leaf read in full has lost track
+                // of the entries changed since its base.
+                let is_delta = self
+                    .handler
+                    .delta_base(offset, object.tag().d_id())
+                    .is_some();
+                if let Some(l2_cache) = self
+                    .l2_cache
+                    .as_ref()
+                    .filter(|l2_cache| !is_delta && l2_cache.should_stage(offset))
+                {
+                    drop(cache);
+                    let info = object.tag().d_id();
+                    let object = object.value_mut().get_mut();
+                    // Losing the copy is harmless, the original is intact.
+                    if let Err(e) = self.stage(l2_cache, offset, generation, info, object) {
+                        warn!("Staging node at {offset:?} in the second-level cache failed: {e:?}");
+                    }
+                }
+                return Ok(());
+            }
             ObjectKey::Modified(mid) => mid,
         };
 
@@ -406,11 +834,24 @@ where
 
     fn handle_write_back(
         &self,
-        mut object: <Self as Dml>::CacheValueRefMut,
+        object: <Self as Dml>::CacheValueRefMut,
         mid: ModifiedObjectId,
         evict: bool,
         pivot_key: PivotKey,
     ) -> Result<<Self as Dml>::ObjectPointer, Error> {
+        let write = self.prepare_write(object, mid, pivot_key)?;
+        self.submit_write(write, evict)
+    }
+
+    // Packs, compresses, encrypts and checksums `object` and allocates space
+    // for it, the write itself is left to `submit_write`.  Until then, the
+    // object stays in the write back state.
+    fn prepare_write(
+        &self,
+        mut object: <Self as Dml>::CacheValueRefMut,
+        mid: ModifiedObjectId,
+        pivot_key: PivotKey,
+    ) -> Result<PreparedWrite<SPL::Checksum>, Error> {
         let object_size = {
             #[cfg(debug_assertions)]
             {
@@ -428,7 +869,6 @@ where
         }
 
         debug!("Estimated object size is {object_size} bytes");
-        debug!("Using compression {:?}", &self.default_compression);
         let generation = self.handler.current_generation();
         // Use storage hints if available
         if let Some(pref) = self.storage_hints.lock().remove(&pivot_key) {
@@ -439,49 +879,161 @@ where
             .preferred_class()
             .unwrap_or(self.default_storage_class);
 
-        let compression = &self.default_compression;
-        let compressed_data = {
+        // Objects on persistent memory are read back cheaply, so the cost of
+        // a compression round-trip outweighs the saved space there.
+        let dataset_compression = self.dataset_compression.read();
+        let dictionary_compression;
+        let compression: &dyn CompressionBuilder = if self.pool.is_byte_addressable(storage_class) {
+            &compression::None
+        } else {
+            let compression = dataset_compression
+                .get(&pivot_key.d_id())
+                .map_or(&*self.default_compression, |compression| &**compression);
+            // Nodes are compressed with the newest dictionary of their dataset.
+            dictionary_compression = self
+                .dictionaries
+                .read()
+                .get(&pivot_key.d_id())
+                .and_then(|dictionaries| dictionaries.iter().next_back())
+                .and_then(|(id, dictionary)| compression.with_dictionary(*id, dictionary.clone()));
+            dictionary_compression.as_deref().unwrap_or(compression)
+        };
+        debug!("Using compression {:?}", compression);
+        // The leaf is written as a delta if its base is still usable and few
+        // of its entries changed, and in full otherwise.
+        let base = self.modified_bases.lock().remove(&mid);
+        let (mut delta, mut replaced_base) = (None, None);
+        let (compressed_data, logical_size) = {
             // FIXME: cache this
             let mut state = compression.new_compression()?;
             let mut buf = crate::buffer::BufWrite::with_capacity(Block(128));
             {
-                object.pack(&mut buf)?;
+                match (base, &self.delta_encoding) {
+                    (Some(base), Some(config))
+                        if self.is_usable_base(&base, config, storage_class)
+                            && object.pack_delta(config.max_changed_share, &mut buf)? =>
+                    {
+                        delta = Some(base);
+                    }
+                    (base, _) => {
+                        object.pack(&mut buf)?;
+                        object.reset_changes(self.delta_encoding.is_some());
+                        replaced_base = base;
+                    }
+                }
                 drop(object);
             }
-            state.finish(buf.into_buf())?
+            let logical_size = buf.len() as u32;
+            (state.finish(buf.into_buf())?, logical_size)
         };
+        if let Some(base) = replaced_base {
+            self.release_base(base.base);
+        }
+        let decompression_tag = compression.decompression_tag();
+        drop(dataset_compression);
 
         assert!(compressed_data.len() <= u32::max_value() as usize);
         let size = compressed_data.len();
         debug!("Compressed object size is {size} bytes");
+        let encryption_tag = self.encryption_tag();
+        let size = encryption_tag.encrypted_len(size);
         let size = Block(((size + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32);
-        assert!(size.to_bytes() as usize >= compressed_data.len());
-        let offset = self.allocate(storage_class, size)?;
+        let info = self.modified_info.lock().remove(&mid).unwrap();
+        let hint = self.last_allocations.lock().get(&info).copied();
+        let offset = self.allocate(storage_class, size, info, hint)?;
+        self.record_displacement(&pivot_key, storage_class, offset.storage_class(), size);
+        self.last_allocations.lock().insert(
+            info,
+            DiskOffset::new(
+                offset.storage_class(),
+                offset.disk_id(),
+                offset.block_offset() + size.as_u64(),
+            ),
+        );
+        // The offset is authenticated as well, so encryption has to wait for
+        // the allocation.
+        let compressed_data = match &self.encryption {
+            Some(encryption) => encryption.encrypt(compressed_data, offset)?,
+            None => compressed_data,
+        };
         assert_eq!(size.to_bytes() as usize, compressed_data.len());
         /*if size.to_bytes() as usize != compressed_data.len() {
             let mut v = compressed_data.into_vec();
             v.resize(size.to_bytes() as usize, 0);
             compressed_data = v.into_boxed_slice();
         }*/
-
-        let info = self.modified_info.lock().remove(&mid).unwrap();
-
+        self.handler
+            .update_logical_usage(info, logical_size, Action::Allocate);
+
+        // Uncompressed objects on byte-addressable storage classes are
+        // written and read at their serialized length, the padding of their
+        // last block is skipped.
+        let unpadded = self.encryption.is_none()
+            && decompression_tag == compression::DecompressionTag::None
+            && self.pool.is_byte_addressable(offset.storage_class());
         let checksum = {
             let mut state = self.default_checksum_builder.build();
-            state.ingest(compressed_data.as_ref());
+            if unpadded {
+                state.ingest(&compressed_data[..logical_size as usize]);
+            } else {
+                state.ingest(compressed_data.as_ref());
+            }
             state.finish()
         };
 
-        self.pool.begin_write(compressed_data, offset)?;
+        Ok(PreparedWrite {
+            data: compressed_data,
+            obj_ptr: ObjectPointer {
+                offset,
+                size,
+                logical_size,
+                unpadded,
+                checksum,
+                decompression_tag,
+                encryption_tag,
+                generation,
+                info,
+            },
+            mid,
+            pivot_key,
+            object_size,
+            delta,
+        })
+    }
 
-        let obj_ptr = ObjectPointer {
-            offset,
-            size,
-            checksum,
-            decompression_tag: compression.decompression_tag(),
-            generation,
-            info,
-        };
+    // Writes an object prepared by `prepare_write` and moves it from the
+    // write back state to the unmodified state, or removes it from the cache
+    // if `evict` is set.
+    fn submit_write(
+        &self,
+        write: PreparedWrite<SPL::Checksum>,
+        evict: bool,
+    ) -> Result<<Self as Dml>::ObjectPointer, Error> {
+        let PreparedWrite {
+            data,
+            obj_ptr,
+            mid,
+            pivot_key,
+            object_size,
+            delta,
+        } = write;
+        let size = obj_ptr.size();
+        if obj_ptr.is_unpadded() {
+            self.pool
+                .write_bytes(&data[..obj_ptr.logical_size() as usize], obj_ptr.offset())?;
+        } else {
+            self.pool.begin_write(data, obj_ptr.offset())?;
+        }
+
+        if let Some(base) = delta {
+            self.handler.record_delta(
+                obj_ptr.offset(),
+                DeltaBase {
+                    base: base.base,
+                    deltas: base.deltas + 1,
+                },
+            );
+        }
 
         let was_present;
         {
@@ -525,11 +1077,68 @@ where
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
         }
 
-        trace!("handle_write_back: Leaving");
+        trace!("submit_write: Leaving");
         Ok(obj_ptr)
     }
 
-    fn allocate(&self, storage_preference: u8, size: Block<u32>) -> Result<DiskOffset, Error> {
+    // Returns whether a leaf to be written to `storage_class` may still be
+    // written as a delta of `base`.  Otherwise, it is written in full, e.g.
+    // so that the base is moved along when it is relocated.
+    fn is_usable_base(
+        &self,
+        base: &DeltaBase<ObjectPointer<SPL::Checksum>>,
+        config: &DeltaConfiguration,
+        storage_class: u8,
+    ) -> bool {
+        let ptr = &base.base;
+        base.deltas < config.max_deltas
+            && ptr.offset().storage_class() == storage_class
+            && ptr.encryption_tag() == self.encryption_tag()
+            && !self
+                .evacuating_disks
+                .read()
+                .contains(&ptr.offset().class_disk_id())
+            && !self
+                .defragmenting_segments
+                .read()
+                .contains(&SegmentId::get(ptr.offset()))
+            && self
+                .handler
+                .legacy_generation
+                .read()
+                .map_or(true, |legacy| ptr.generation() > legacy)
+    }
+
+    // Remembers nodes which did not end up in the first class of the
+    // allocation strategy of their storage class, e.g. because it was full.
+    fn record_displacement(&self, pivot_key: &PivotKey, class: u8, actual: u8, size: Block<u32>) {
+        let preferred = self.alloc_strategy[class as usize][0].unwrap_or(class);
+        let mut displaced_nodes = self.displaced_nodes.lock();
+        if actual == preferred {
+            displaced_nodes.remove(pivot_key);
+        } else {
+            debug!("Node {pivot_key:?} displaced from storage class {preferred} to {actual}");
+            displaced_nodes.insert(
+                pivot_key.clone(),
+                Displacement {
+                    preferred: StoragePreference::from_u8(preferred),
+                    actual: StoragePreference::from_u8(actual),
+                    size,
+                },
+            );
+        }
+    }
+
+    // Allocates `size` blocks for an object of the dataset `info`.  On
+    // storage classes with sequential access, the object is placed at or
+    // after `hint` if possible.
+    fn allocate(
+        &self,
+        storage_preference: u8,
+        size: Block<u32>,
+        info: DatasetId,
+        hint: Option<DiskOffset>,
+    ) -> Result<DiskOffset, Error> {
         assert!(storage_preference < NUM_STORAGE_CLASSES as u8);
         if size >= Block(2048) {
             warn!("Very large allocation requested: {:?}", size);
@@ -554,88 +1163,130 @@ where
         #[cfg(feature = "allocation_log")]
         let mut total_cycles_local: u64 = 0;
         'class: for &class in strategy.iter().flatten() {
-            let disks_in_class = self.pool.disk_count(class);
+            let allocation_data = self.allocation_data[class as usize].read_recursive();
+            let disks_in_class = allocation_data.len() as u16;
             if disks_in_class == 0 {
                 continue;
             }
 
-            if self
-                .handler
-                .free_space_tier(class)
-                .expect("Has to exist")
-                .free
-                .as_u64()
-                < size.as_u64()
-            {
+            // Space reserved for other datasets and the headroom of the
+            // internal trees are not available.
+            let free = self.handler.available_blocks(class, info);
+            if free.as_u64() < size.as_u64() {
                 warn!(
                     "Storage tier {class} does not have enough space remaining. {} blocks of {}",
-                    self.handler.free_space_tier(class).unwrap().free.as_u64(),
+                    free.as_u64(),
                     size.as_u64()
                 );
+                self.handler.events.emit(|| Event::TierFull {
+                    class,
+                    free,
+                    requested: size,
+                });
                 continue;
             }
 
             let start_disk_id = (self.next_disk_id.fetch_add(1, Ordering::Relaxed)
                 % u64::from(disks_in_class)) as u16;
-            let disk_id = (start_disk_id..disks_in_class)
-                .chain(0..start_disk_id)
-                .max_by_key(|&disk_id| {
-                    self.pool.effective_free_size(
-                        class,
-                        disk_id,
-                        self.handler
-                            .free_space_disk(DiskOffset::construct_disk_id(class, disk_id))
-                            .expect("We can be sure that this disk id exists.")
-                            .free,
-                    )
-                })
-                .unwrap();
+            let evacuating_disks = self.evacuating_disks.read();
+            let sealed_disks = self.handler.sealed_disks.read();
+            let excluded = |disk: GlobalDiskId| {
+                evacuating_disks.contains(&disk) || sealed_disks.contains(&disk)
+            };
+            let hint = hint.filter(|hint| {
+                hint.storage_class() == class
+                    && hint.disk_id() < disks_in_class
+                    && !excluded(hint.class_disk_id())
+                    && self.pool.preferred_access_type(class).is_sequential()
+            });
+            let disk_id = hint.map(|hint| hint.disk_id()).or_else(|| {
+                (start_disk_id..disks_in_class)
+                    .chain(0..start_disk_id)
+                    .filter(|&disk_id| !excluded(DiskOffset::construct_disk_id(class, disk_id)))
+                    .max_by_key(|&disk_id| {
+                        self.pool.effective_free_size(
+                            class,
+                            disk_id,
+                            self.handler
+                                .free_space_disk(DiskOffset::construct_disk_id(class, disk_id))
+                                .expect("We can be sure that this disk id exists.")
+                                .free,
+                        )
+                    })
+            });
+            drop(sealed_disks);
+            drop(evacuating_disks);
+            let disk_id = match disk_id {
+                Some(disk_id) => disk_id,
+                None => continue,
+            };
             let size = self.pool.actual_size(class, disk_id, size);
             let disk_size = self.pool.size_in_blocks(class, disk_id);
 
             let disk_offset = {
-                let mut last_seg_id = self.allocation_data[class as usize][disk_id as usize].lock();
-                let segment_id = if last_seg_id.is_some() {
-                    last_seg_id.as_mut().unwrap()
-                } else {
-                    let segment_id = SegmentId::get(DiskOffset::new(class, disk_id, Block(0)));
-                    *last_seg_id = Some(segment_id);
-                    last_seg_id.as_mut().unwrap()
-                };
+                let disk_allocation = &allocation_data[disk_id as usize];
+                let shard = ALLOCATION_SHARD.with(|shard| *shard);
+                let mut last_seg_id = disk_allocation.cursors[shard].lock();
+                let segment_id = last_seg_id.get_or_insert_with(|| {
+                    DiskAllocation::first_segment(class, disk_id, disk_size, shard)
+                });
+                // The search starts at the hint and is continued from there
+                // on later allocations.
+                let mut near = hint
+                    .filter(|hint| hint.block_offset() < disk_size)
+                    .map(|hint| {
+                        *segment_id = SegmentId::get(hint);
+                        SegmentId::get_block_offset(hint)
+                    });
 
                 let first_seen_segment_id = *segment_id;
-                loop {
-                    // Has to be split because else the temporary value is dropped while borrowing
-                    let bitmap = self.handler.get_allocation_bitmap(*segment_id, self)?;
-                    let mut allocator = bitmap.access();
-
-                    #[cfg(not(feature = "allocation_log"))]
-                    {
-                        let allocation = allocator.allocate(size.as_u32());
-                        if let Some(segment_offset) = allocation {
-                            let disk_offset = segment_id.disk_offset(segment_offset);
-                            break disk_offset;
-                        }
+                let disk_offset = loop {
+                    // Requests exceeding a segment can only be satisfied by
+                    // an extent spanning several segments.
+                    if size.as_u32() as usize > SEGMENT_SIZE {
+                        break None;
                     }
-                    #[cfg(feature = "allocation_log")]
-                    {
-                        let start_cycles_allocation = get_cycles();
-                        let allocation = allocator.allocate(size.as_u32());
-                        let end_cycles_allocation = get_cycles();
-                        total_cycles_local += end_cycles_allocation - start_cycles_allocation;
-
-                        if let Some(segment_offset) = allocation {
-                            let disk_offset = segment_id.disk_offset(segment_offset);
-                            let total_cycles_global = end_cycles_allocation - start_cycles_global;
-
-                            let mut file = self.allocation_log_file.lock();
-                            file.write_u8(Action::Allocate.as_bool() as u8)?;
-                            file.write_u64::<LittleEndian>(disk_offset.as_u64())?;
-                            file.write_u32::<LittleEndian>(size.as_u32())?;
-                            file.write_u64::<LittleEndian>(total_cycles_local)?;
-                            file.write_u64::<LittleEndian>(total_cycles_global)?;
-
-                            break disk_offset;
+                    // The hint only applies to the segment it points into.
+                    let near = near.take();
+                    // Segments which are being defragmented are skipped.
+                    if !self.defragmenting_segments.read().contains(segment_id) {
+                        // Has to be split because else the temporary value is dropped while borrowing
+                        let bitmap = self.handler.get_allocation_bitmap(*segment_id, self)?;
+                        let mut allocator = bitmap.access();
+
+                        #[cfg(not(feature = "allocation_log"))]
+                        {
+                            let allocation = near
+                                .and_then(|near| allocator.allocate_near(size.as_u32(), near))
+                                .or_else(|| allocator.allocate(size.as_u32()));
+                            if let Some(segment_offset) = allocation {
+                                let disk_offset = segment_id.disk_offset(segment_offset);
+                                break Some(disk_offset);
+                            }
+                        }
+                        #[cfg(feature = "allocation_log")]
+                        {
+                            let start_cycles_allocation = get_cycles();
+                            let allocation = near
+                                .and_then(|near| allocator.allocate_near(size.as_u32(), near))
+                                .or_else(|| allocator.allocate(size.as_u32()));
+                            let end_cycles_allocation = get_cycles();
+                            total_cycles_local += end_cycles_allocation - start_cycles_allocation;
+
+                            if let Some(segment_offset) = allocation {
+                                let disk_offset = segment_id.disk_offset(segment_offset);
+                                let total_cycles_global =
+                                    end_cycles_allocation - start_cycles_global;
+
+                                let mut file = self.allocation_log_file.lock();
+                                file.write_u8(Action::Allocate.as_bool() as u8)?;
+                                file.write_u64::<LittleEndian>(disk_offset.as_u64())?;
+                                file.write_u32::<LittleEndian>(size.as_u32())?;
+                                file.write_u64::<LittleEndian>(total_cycles_local)?;
+                                file.write_u64::<LittleEndian>(total_cycles_global)?;
+
+                                break Some(disk_offset);
+                            }
                         }
                     }
 
@@ -647,15 +1298,42 @@ where
                         disk_size,
                     );
                     if next_segment_id == first_seen_segment_id {
-                        // Can't allocate in this class, try next
-                        warn!("Allocation failed not enough space");
-                        debug!(
-                            "Free space is {:?} blocks",
-                            self.handler.free_space_tier(class)
-                        );
-                        continue 'class;
+                        break None;
                     }
                     *segment_id = next_segment_id;
+                };
+                match disk_offset {
+                    Some(disk_offset) => disk_offset,
+                    None => match self.allocate_extent(
+                        disk_allocation,
+                        class,
+                        disk_id,
+                        size,
+                        disk_size,
+                    )? {
+                        Some(disk_offset) => {
+                            #[cfg(feature = "allocation_log")]
+                            {
+                                let total_cycles_global = get_cycles() - start_cycles_global;
+                                let mut file = self.allocation_log_file.lock();
+                                file.write_u8(Action::Allocate.as_bool() as u8)?;
+                                file.write_u64::<LittleEndian>(disk_offset.as_u64())?;
+                                file.write_u32::<LittleEndian>(size.as_u32())?;
+                                file.write_u64::<LittleEndian>(total_cycles_local)?;
+                                file.write_u64::<LittleEndian>(total_cycles_global)?;
+                            }
+                            disk_offset
+                        }
+                        None => {
+                            // Can't allocate in this class, try next
+                            warn!("Allocation failed not enough space");
+                            debug!(
+                                "Free space is {:?} blocks",
+                                self.handler.free_space_tier(class)
+                            );
+                            continue 'class;
+                        }
+                    },
                 }
             };
 
@@ -664,8 +1342,13 @@ where
                 "Remaining space is {:?} blocks",
                 self.handler.free_space_tier(class)
             );
-            self.handler
-                .update_allocation_bitmap(disk_offset, size, Action::Allocate, self)?;
+            self.handler.update_allocation_bitmap(
+                disk_offset,
+                size,
+                Action::Allocate,
+                info,
+                self,
+            )?;
 
             return Ok(disk_offset);
         }
@@ -674,26 +1357,269 @@ where
             "No available layer can provide enough free storage {:?}",
             size
         );
-        Err(Error::OutOfSpaceError)
+        Err(Error::OutOfSpaceError {
+            dataset: info,
+            class: storage_preference,
+            size,
+        })
+    }
+
+    // Searches the disk for a free range of `size` blocks crossing segment
+    // boundaries and allocates it in the segment allocators.
+    fn allocate_extent(
+        &self,
+        disk_allocation: &DiskAllocation,
+        class: u8,
+        disk_id: u16,
+        size: Block<u32>,
+        disk_size: Block<u64>,
+    ) -> Result<Option<DiskOffset>, Error> {
+        let mut missing = disk_allocation.extents.lock();
+        let generation = self.handler.current_generation();
+        if let Some(missing) = &*missing {
+            if missing.generation == generation
+                && missing.disk_size == disk_size
+                && missing.size <= size
+            {
+                return Ok(None);
+            }
+        }
+        let first = SegmentId::get(DiskOffset::new(class, disk_id, Block(0)));
+        let mut segment_id = first;
+        // Start and length of the free range reaching the end of the
+        // previous segment.
+        let mut run: Option<(DiskOffset, u64)> = None;
+        loop {
+            let segment_start = segment_id.as_disk_offset().block_offset().as_u64();
+            // The last segment of a disk may be cut short.
+            let usable = (disk_size.as_u64() - segment_start).min(SEGMENT_SIZE as u64) as u32;
+            let (prefix, suffix) = if self.defragmenting_segments.read().contains(&segment_id) {
+                (0, 0)
+            } else {
+                let bitmap = self.handler.get_allocation_bitmap(segment_id, self)?;
+                let allocator = bitmap.access();
+                (allocator.free_prefix().min(usable), allocator.free_suffix())
+            };
+            if let Some((start, len)) = run {
+                if len + u64::from(prefix) >= size.as_u64() {
+                    // Other shards may have allocated within the range in
+                    // the meantime, the search continues after it then.
+                    if self.claim_extent(Extent::new(start, size))? {
+                        return Ok(Some(start));
+                    }
+                    run = None;
+                } else if prefix as usize == SEGMENT_SIZE {
+                    run = Some((start, len + SEGMENT_SIZE as u64));
+                } else {
+                    run = None;
+                }
+            }
+            if run.is_none() && usable as usize == SEGMENT_SIZE && suffix > 0 {
+                let start = segment_id.disk_offset(SEGMENT_SIZE as u32 - suffix);
+                run = Some((start, u64::from(suffix)));
+            }
+            let next_segment_id = segment_id.next(disk_size);
+            if next_segment_id == first {
+                *missing = Some(MissingExtent {
+                    generation,
+                    disk_size,
+                    size,
+                });
+                return Ok(None);
+            }
+            segment_id = next_segment_id;
+        }
+    }
+
+    // Marks all parts of `extent` as allocated in their segment allocators.
+    // Nothing is marked if any part is in use already.
+    fn claim_extent(&self, extent: Extent) -> Result<bool, Error> {
+        let mut claimed = Vec::new();
+        for (segment_id, segment_offset, len) in extent.segments() {
+            let bitmap = self.handler.get_allocation_bitmap(segment_id, self)?;
+            if !bitmap.access().allocate_at(len, segment_offset) {
+                drop(bitmap);
+                for (segment_id, segment_offset, len) in claimed {
+                    self.handler
+                        .get_allocation_bitmap(segment_id, self)?
+                        .access()
+                        .deallocate(segment_offset, len);
+                }
+                return Ok(false);
+            }
+            claimed.push((segment_id, segment_offset, len));
+        }
+        Ok(true)
+    }
+
+    // Writes a copy of the unmodified `object` of the dataset `info` stored
+    // at `offset` to the second-level cache.  The copy is not compressed to
+    // keep reading it cheap.
+    fn stage(
+        &self,
+        l2_cache: &L2Cache<SPL::Checksum>,
+        offset: DiskOffset,
+        generation: Generation,
+        info: DatasetId,
+        object: &Node<ObjRef<ObjectPointer<SPL::Checksum>>>,
+    ) -> Result<(), Error> {
+        let data = {
+            let mut state = compression::None.new_compression()?;
+            let mut buf = crate::buffer::BufWrite::with_capacity(Block(128));
+            object.pack(&mut buf)?;
+            state.finish(buf.into_buf())?
+        };
+        let logical_size = data.len() as u32;
+        let encryption_tag = self.encryption_tag();
+        let size = encryption_tag.encrypted_len(data.len());
+        let size = Block(((size + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32);
+        let staged_offset = match self.allocate_unrecorded(l2_cache.storage_class(), size)? {
+            Some(staged_offset) => staged_offset,
+            None => {
+                debug!("No space left to stage node at {offset:?}");
+                return Ok(());
+            }
+        };
+        let checksum = match self.write_staged(data, staged_offset) {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                self.handler.free_cache_allocation(staged_offset);
+                return Err(e);
+            }
+        };
+
+        let staged = ObjectPointer {
+            offset: staged_offset,
+            size,
+            logical_size,
+            unpadded: false,
+            checksum,
+            decompression_tag: compression::None.decompression_tag(),
+            encryption_tag,
+            generation,
+            info,
+        };
+        for displaced in l2_cache.insert(offset, generation, staged) {
+            self.handler.free_cache_allocation(displaced.offset());
+        }
+        Ok(())
+    }
+
+    // Encrypts and writes the `data` of a staged node to `offset`, returns
+    // the checksum of the written data.
+    fn write_staged(&self, data: Buf, offset: DiskOffset) -> Result<SPL::Checksum, Error> {
+        let data = match &self.encryption {
+            Some(encryption) => encryption.encrypt(data, offset)?,
+            None => data,
+        };
+        let checksum = {
+            let mut state = self.default_checksum_builder.build();
+            state.ingest(data.as_ref());
+            state.finish()
+        };
+        self.pool.begin_write(data, offset)?;
+        Ok(checksum)
+    }
+
+    // Allocates `size` blocks on the storage class `class` for the
+    // second-level cache.  The allocation is only marked in the segment
+    // allocators and never recorded in the allocation trees.
+    fn allocate_unrecorded(
+        &self,
+        class: u8,
+        size: Block<u32>,
+    ) -> Result<Option<DiskOffset>, Error> {
+        if size.as_u32() as usize > SEGMENT_SIZE {
+            return Ok(None);
+        }
+        let disks_in_class = self.allocation_data[class as usize].read_recursive().len() as u16;
+        let mut cursor = self.l2_cache_cursor.lock();
+        // Continue with the disk allocated from last.
+        let start_disk_id = cursor
+            .map(|segment_id| segment_id.as_disk_offset().disk_id())
+            .filter(|&disk_id| disk_id < disks_in_class)
+            .unwrap_or(0);
+        for disk_id in (start_disk_id..disks_in_class).chain(0..start_disk_id) {
+            let disk = DiskOffset::construct_disk_id(class, disk_id);
+            if self.evacuating_disks.read().contains(&disk)
+                || self.handler.sealed_disks.read().contains(&disk)
+            {
+                continue;
+            }
+            let size = self.pool.actual_size(class, disk_id, size);
+            let disk_size = self.pool.size_in_blocks(class, disk_id);
+            let first = cursor
+                .filter(|segment_id| segment_id.as_disk_offset().disk_id() == disk_id)
+                .unwrap_or_else(|| SegmentId::get(DiskOffset::new(class, disk_id, Block(0))));
+            let mut segment_id = first;
+            loop {
+                if !self.defragmenting_segments.read().contains(&segment_id) {
+                    let bitmap = self.handler.get_allocation_bitmap(segment_id, self)?;
+                    let allocation = bitmap.access().allocate(size.as_u32());
+                    if let Some(segment_offset) = allocation {
+                        let offset = segment_id.disk_offset(segment_offset);
+                        self.handler.cache_allocations.write().insert(offset, size);
+                        *cursor = Some(segment_id);
+                        return Ok(Some(offset));
+                    }
+                }
+                segment_id = segment_id.next(disk_size);
+                if segment_id == first {
+                    break;
+                }
+            }
+        }
+        Ok(None)
     }
 
-    /// Tries to allocate `size` blocks at `disk_offset`.  Might fail if
-    /// already in use.
-    pub fn allocate_raw_at(&self, disk_offset: DiskOffset, size: Block<u32>) -> Result<(), Error> {
+    /// Tries to allocate `size` blocks at `disk_offset` on behalf of the
+    /// dataset `info`.  Might fail if already in use.
+    pub fn allocate_raw_at(
+        &self,
+        disk_offset: DiskOffset,
+        size: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let allocation_data =
+            self.allocation_data[disk_offset.storage_class() as usize].read_recursive();
+        self.allocate_raw_at_with(
+            &allocation_data[disk_offset.disk_id() as usize],
+            disk_offset,
+            size,
+            info,
+        )
+    }
+
+    fn allocate_raw_at_with(
+        &self,
+        disk_allocation: &DiskAllocation,
+        disk_offset: DiskOffset,
+        size: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
         let disk_id = disk_offset.disk_id();
         let num_disks = self.pool.num_disks(disk_offset.storage_class(), disk_id);
-        let size = size * num_disks as u32;
-        let segment_id = SegmentId::get(disk_offset);
-        let mut x =
-            self.allocation_data[disk_offset.storage_class() as usize][disk_id as usize].lock();
-        let allocator = self.handler.get_allocation_bitmap(segment_id, self)?;
-        if allocator
-            .access()
-            .allocate_at(size.as_u32(), SegmentId::get_block_offset(disk_offset))
-        {
-            *x = Some(segment_id);
-            self.handler
-                .update_allocation_bitmap(disk_offset, size, Action::Allocate, self)?;
+        self.claim_raw_at(disk_allocation, disk_offset, size * num_disks as u32, info)
+    }
+
+    // Allocates exactly the `size` blocks at `disk_offset`, without
+    // accounting for the number of leaves of the vdev.
+    fn claim_raw_at(
+        &self,
+        disk_allocation: &DiskAllocation,
+        disk_offset: DiskOffset,
+        size: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let _extents = disk_allocation.extents.lock();
+        if self.claim_extent(Extent::new(disk_offset, size))? {
+            self.handler.update_allocation_bitmap(
+                disk_offset,
+                size,
+                Action::Allocate,
+                info,
+                self,
+            )?;
             Ok(())
         } else {
             Err(Error::RawAllocationError {
@@ -703,11 +1629,419 @@ where
         }
     }
 
+    /// Allocates the first and the last `reserved` blocks of each leaf of
+    /// the vdev `disk_id` of the storage class `class` on behalf of the
+    /// dataset `info`.
+    pub fn reserve_raw_ends(
+        &self,
+        class: u8,
+        disk_id: u16,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let allocation_data = self.allocation_data[class as usize].read_recursive();
+        self.reserve_raw_ends_with(
+            &allocation_data[disk_id as usize],
+            class,
+            disk_id,
+            reserved,
+            info,
+        )
+    }
+
+    fn reserve_raw_ends_with(
+        &self,
+        disk_allocation: &DiskAllocation,
+        class: u8,
+        disk_id: u16,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        self.allocate_raw_at_with(
+            disk_allocation,
+            DiskOffset::new(class, disk_id, Block(0)),
+            reserved,
+            info,
+        )?;
+        let size = self.pool.size_in_blocks(class, disk_id);
+        self.allocate_raw_at_with(
+            disk_allocation,
+            self.raw_tail(class, disk_id, size, reserved),
+            reserved,
+            info,
+        )
+    }
+
+    /// Allocates only the last `reserved` blocks of each leaf of the vdev
+    /// `disk_id` of the storage class `class` on behalf of the dataset
+    /// `info`, for vdevs whose first blocks have been allocated already.
+    /// Fails if any of them is in use.
+    pub fn reserve_raw_tail(
+        &self,
+        class: u8,
+        disk_id: u16,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let size = self.pool.size_in_blocks(class, disk_id);
+        self.allocate_raw_at(
+            self.raw_tail(class, disk_id, size, reserved),
+            reserved,
+            info,
+        )
+    }
+
+    /// Returns the extents of the first and the last `reserved` blocks of
+    /// each leaf of the vdev `disk_id` of the storage class `class`, as
+    /// allocated by [Dmu::reserve_raw_ends].
+    pub fn raw_ends(&self, class: u8, disk_id: u16, reserved: Block<u32>) -> [Extent; 2] {
+        let len = reserved * self.pool.num_disks(class, disk_id) as u32;
+        let size = self.pool.size_in_blocks(class, disk_id);
+        [
+            Extent::new(DiskOffset::new(class, disk_id, Block(0)), len),
+            Extent::new(self.raw_tail(class, disk_id, size, reserved), len),
+        ]
+    }
+
+    /// Marks the blocks of `extent` as allocated on behalf of the dataset
+    /// `info`, regardless of whether some of them are in use already.  This
+    /// repairs allocation bitmaps which miss blocks which are referenced.
+    pub fn force_allocate_raw(&self, extent: Extent, info: DatasetId) -> Result<(), Error> {
+        let class = extent.offset.storage_class();
+        let allocation_data = self.allocation_data[class as usize].read_recursive();
+        let _extents = allocation_data[extent.offset.disk_id() as usize]
+            .extents
+            .lock();
+        for (segment_id, segment_offset, len) in extent.segments() {
+            let bitmap = self.handler.get_allocation_bitmap(segment_id, self)?;
+            let mut allocator = bitmap.access();
+            for offset in segment_offset..segment_offset + len {
+                allocator.allocate_at(1, offset);
+            }
+        }
+        self.handler.update_allocation_bitmap(
+            extent.offset,
+            extent.size,
+            Action::Allocate,
+            info,
+            self,
+        )?;
+        Ok(())
+    }
+
+    /// Moves the allocation of the last `reserved` blocks of each leaf of
+    /// the vdev `disk_id` of the storage class `class`, which has been made
+    /// while the vdev had `old_size` blocks, to the current end of the grown
+    /// vdev.
+    pub fn move_raw_tail(
+        &self,
+        class: u8,
+        disk_id: u16,
+        old_size: Block<u64>,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let allocation_data = self.allocation_data[class as usize].read_recursive();
+        self.move_raw_tail_with(
+            &allocation_data[disk_id as usize],
+            class,
+            disk_id,
+            old_size,
+            reserved,
+            info,
+        )
+    }
+
+    fn move_raw_tail_with(
+        &self,
+        disk_allocation: &DiskAllocation,
+        class: u8,
+        disk_id: u16,
+        old_size: Block<u64>,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<(), Error> {
+        let len = reserved.as_u64() * self.pool.num_disks(class, disk_id) as u64;
+        let old = self.raw_tail(class, disk_id, old_size, reserved);
+        let new = self.raw_tail(
+            class,
+            disk_id,
+            self.pool.size_in_blocks(class, disk_id),
+            reserved,
+        );
+        // Blocks freed in this generation can only be allocated again in the
+        // next one, so blocks shared by both locations stay allocated.
+        let shift = new.block_offset().as_u64() - old.block_offset().as_u64();
+        let moved = Block(shift.min(len) as u32);
+        if moved == Block(0) {
+            return Ok(());
+        }
+        self.handler
+            .update_allocation_bitmap(old, moved, Action::Deallocate, info, self)?;
+        let start = new.block_offset().as_u64() + len - moved.as_u64();
+        self.claim_raw_at(
+            disk_allocation,
+            DiskOffset::new(class, disk_id, Block(start)),
+            moved,
+            info,
+        )
+    }
+
+    // Returns the offset of the last `reserved` blocks of each leaf of the
+    // vdev `disk_id` of the storage class `class` if it has `size` blocks.
+    fn raw_tail(
+        &self,
+        class: u8,
+        disk_id: u16,
+        size: Block<u64>,
+        reserved: Block<u32>,
+    ) -> DiskOffset {
+        let reserved = reserved.as_u64() * self.pool.num_disks(class, disk_id) as u64;
+        DiskOffset::new(class, disk_id, Block(size.as_u64() - reserved))
+    }
+
+    /// Adds the top-level vdevs of `config` to the storage class `class` of
+    /// the running pool and returns their disk ids.  The first and the last
+    /// `reserved` blocks of each new vdev are allocated on behalf of the
+    /// dataset `info` before the vdev is used for any other allocation.
+    pub fn add_vdevs(
+        &self,
+        class: u8,
+        config: &TierConfiguration,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<Range<u16>, Error> {
+        let mut allocation_data = self.allocation_data[class as usize].write();
+        let first = self.pool.add_vdevs(class, config)?;
+        let disk_ids = first..first + config.top_level_vdevs.len() as u16;
+        for disk_id in disk_ids.clone() {
+            let free = self.pool.effective_free_size(
+                class,
+                disk_id,
+                self.pool.size_in_blocks(class, disk_id),
+            );
+            self.handler.add_disk(class, disk_id, free);
+            allocation_data.push(DiskAllocation::default());
+            self.reserve_raw_ends_with(
+                &allocation_data[disk_id as usize],
+                class,
+                disk_id,
+                reserved,
+                info,
+            )?;
+        }
+        Ok(disk_ids)
+    }
+
+    /// Probes the sizes of all vdevs again and makes the space gained by
+    /// grown vdevs available for allocations.  The allocations of the last
+    /// `reserved` blocks of grown vdevs, see [Dmu::add_vdevs], are moved to
+    /// their new end.  Returns the number of usable blocks gained.
+    pub fn expand(&self, reserved: Block<u32>, info: DatasetId) -> Result<Block<u64>, Error> {
+        let mut gained = Block(0);
+        for class in 0..self.pool.storage_class_count() {
+            // Nothing may be allocated at the new end before it is reserved.
+            let allocation_data = self.allocation_data[class as usize].write();
+            for disk_id in 0..self.pool.disk_count(class) {
+                let old_size = self.pool.size_in_blocks(class, disk_id);
+                let size = self.pool.refresh_size(class, disk_id)?;
+                if size != old_size {
+                    self.move_raw_tail_with(
+                        &allocation_data[disk_id as usize],
+                        class,
+                        disk_id,
+                        old_size,
+                        reserved,
+                        info,
+                    )?;
+                }
+                let total = self.pool.effective_free_size(class, disk_id, size);
+                gained += self.handler.grow_disk(class, disk_id, total);
+            }
+        }
+        Ok(gained)
+    }
+
+    /// Excludes the disk `disk_id` from all further allocations while
+    /// `evacuating` is set.  Explicit allocations via
+    /// [Dmu::allocate_raw_at] are not affected.
+    pub fn set_evacuating(&self, disk_id: GlobalDiskId, evacuating: bool) {
+        let mut evacuating_disks = self.evacuating_disks.write();
+        if evacuating {
+            evacuating_disks.insert(disk_id);
+        } else {
+            evacuating_disks.remove(&disk_id);
+        }
+    }
+
+    /// Excludes `segments` from all further allocations, replacing the
+    /// segments excluded before.  Explicit allocations via
+    /// [Dmu::allocate_raw_at] are not affected.
+    pub fn set_defragmenting(&self, segments: HashSet<SegmentId>) {
+        *self.defragmenting_segments.write() = segments;
+        for allocation_data in self.allocation_data.iter() {
+            for disk_allocation in allocation_data.read_recursive().iter() {
+                *disk_allocation.extents.lock() = None;
+            }
+        }
+    }
+
+    /// Removes the last top-level vdev of the storage class `class` from the
+    /// running pool and returns its disk id.  Apart from the first and the
+    /// last `reserved` blocks, which have been allocated on behalf of the
+    /// dataset `info`, the vdev has to be empty.
+    pub fn remove_last_vdev(
+        &self,
+        class: u8,
+        reserved: Block<u32>,
+        info: DatasetId,
+    ) -> Result<u16, Error> {
+        let mut allocation_data = self.allocation_data[class as usize].write();
+        let disk_id = match allocation_data.len().checked_sub(1) {
+            Some(disk_id) => disk_id as u16,
+            None => return Err(Error::NoVdevs(class)),
+        };
+        let offset = DiskOffset::new(class, disk_id, Block(0));
+        let tail = self.raw_tail(
+            class,
+            disk_id,
+            self.pool.size_in_blocks(class, disk_id),
+            reserved,
+        );
+        let reserved = reserved * self.pool.num_disks(class, disk_id) as u32;
+        for offset in [offset, tail] {
+            self.handler.update_allocation_bitmap(
+                offset,
+                reserved,
+                Action::Deallocate,
+                info,
+                self,
+            )?;
+        }
+        self.handler
+            .remove_disk(class, disk_id, self.pool.size_in_blocks(class, disk_id));
+        allocation_data.pop();
+        self.pool.remove_last_vdev(class)?;
+        self.evacuating_disks
+            .write()
+            .remove(&offset.class_disk_id());
+        Ok(disk_id)
+    }
+
     /// Receives an appendable list of [ModifiedObjectId] which is filled with
     /// all modified children of this node.  The reference [ModifiedObjectId] is
     /// updated from [ObjectKey::Modified] to [ObjectKey::InWriteback] in the
     /// cache, if no children are found which need to be written first.
     /// Returns a [CacheValueRef] to this new key if succesful.
+    // Writes back an object prepared by `prepare_write_back`, which is
+    // returned to the modified state if that fails.
+    fn write_back_prepared(
+        &self,
+        object: <Self as Dml>::CacheValueRefMut,
+        mid: ModifiedObjectId,
+        pivot_key: PivotKey,
+    ) -> Result<(), Error> {
+        self.handle_write_back(object, mid, false, pivot_key)
+            .map(|_| ())
+            .map_err(|err| {
+                self.abort_write_back(mid);
+                err
+            })
+    }
+
+    // Returns an object whose write back has failed to the modified state.
+    fn abort_write_back(&self, mid: ModifiedObjectId) {
+        let mut cache = self.cache.write();
+        let _ = cache.change_key::<(), _>(
+            &ObjectKey::InWriteback(mid),
+            // Has to have been in the modified state before
+            |_, _, _| Ok(ObjectKey::Modified(mid)),
+        );
+    }
+
+    // Writes back the modified objects `mids` and their modified descendants
+    // with up to `write_back_threads` threads.  Each round prepares all
+    // pending objects, packs, compresses and checksums those without
+    // modified children and defers the others along with their modified
+    // children to the next round.  The packed objects are written by another
+    // thread, so that packing the next objects overlaps with the writes of
+    // the previous ones.
+    fn write_back_concurrently(
+        &self,
+        mut mids: Vec<(ModifiedObjectId, PivotKey)>,
+    ) -> Result<(), Error> {
+        while !mids.is_empty() {
+            // Children are listed again by every deferred parent, but each
+            // object may only be prepared once per round.
+            let mut listed = HashSet::new();
+            mids.retain(|(mid, _)| listed.insert(*mid));
+            let threads = self.write_back_threads.min(mids.len());
+            let pending = Mutex::new(mids.into_iter());
+            let deferred = Mutex::new(Vec::new());
+            // Each thread is at most one packed object ahead of the writes.
+            let (prepared_tx, prepared_rx) = crossbeam_channel::bounded(threads);
+            let worker = |prepared_tx: Sender<PreparedWrite<SPL::Checksum>>| -> Result<(), Error> {
+                loop {
+                    let next = pending.lock().next();
+                    let (mid, mid_pk) = match next {
+                        Some(entry) => entry,
+                        None => return Ok(()),
+                    };
+                    let mut dep_mids = Vec::new();
+                    match self.prepare_write_back(mid, &mut dep_mids) {
+                        Ok(None) => {}
+                        Ok(Some(object)) => {
+                            let write = self.prepare_write(object, mid, mid_pk).map_err(|err| {
+                                self.abort_write_back(mid);
+                                err
+                            })?;
+                            prepared_tx.send(write).expect("Write back thread panicked");
+                        }
+                        Err(()) => {
+                            let mut deferred = deferred.lock();
+                            deferred.push((mid, mid_pk));
+                            deferred.append(&mut dep_mids);
+                        }
+                    }
+                }
+            };
+            let submitter = || -> Result<(), Error> {
+                // Keeps receiving after a failed write, so that no worker
+                // blocks on a full channel.
+                prepared_rx
+                    .iter()
+                    .map(|write| {
+                        let mid = write.mid;
+                        self.submit_write(write, false).map(|_| ()).map_err(|err| {
+                            self.abort_write_back(mid);
+                            err
+                        })
+                    })
+                    .fold(Ok(()), Result::and)
+            };
+            // The spawned threads issue their IO with the priority of the
+            // calling thread.
+            let priority = io_priority();
+            thread::scope(|scope| {
+                let submitter = scope.spawn(|| with_io_priority(priority, submitter));
+                let workers: Vec<_> = (1..threads)
+                    .map(|_| {
+                        let prepared_tx = prepared_tx.clone();
+                        scope.spawn(move || with_io_priority(priority, || worker(prepared_tx)))
+                    })
+                    .collect();
+                let result = worker(prepared_tx);
+                workers
+                    .into_iter()
+                    .chain(iter::once(submitter))
+                    .map(|handle| handle.join().expect("Write back thread panicked"))
+                    .fold(result, Result::and)
+            })?;
+            mids = deferred.into_inner();
+        }
+        Ok(())
+    }
+
     fn prepare_write_back(
         &self,
         mid: ModifiedObjectId,
@@ -910,6 +2244,7 @@ where
             // TODO
             Err(RemoveError::Pinned) => unimplemented!(),
         };
+        self.release_modified_base(&or);
         if let ObjRef::Unmodified(ref ptr, ..) = or {
             self.copy_on_write(ptr.clone(), CopyOnWriteReason::Remove, or.index().clone());
         }
@@ -928,6 +2263,7 @@ where
                 Err(RemoveError::Pinned) => unimplemented!(),
             };
         };
+        self.release_modified_base(&or);
         if let ObjRef::Unmodified(ref ptr, ..) = or {
             self.copy_on_write(ptr.clone(), CopyOnWriteReason::Remove, or.index().clone());
         }
@@ -951,6 +2287,10 @@ where
         self.cache.write().verify();
     }
 
+    fn events(&self) -> &EventHandlers {
+        &self.handler.events
+    }
+
     /// Trigger a write back of an entire subtree.  This is intended for use
     /// with a dataset root, though will function on any subtree specified if
     /// needed.  A write back on a subtree will always write the lowest modified
@@ -982,27 +2322,22 @@ where
                 Err(()) => {
                     trace!("write_back: Was Err");
                     drop(or);
-                    while let Some((mid, mid_pk)) = mids.last().cloned() {
-                        trace!("write_back: Trying to prepare write back");
-                        match self.prepare_write_back(mid, &mut mids) {
-                            Ok(None) => {}
-                            Ok(Some(object)) => {
-                                trace!("write_back: Was Ok Some");
-                                self.handle_write_back(object, mid, false, mid_pk).map_err(
-                                    |err| {
-                                        let mut cache = self.cache.write();
-                                        let _ = cache.change_key::<(), _>(
-                                            &ObjectKey::InWriteback(mid),
-                                            // Has to have been in the modified state before
-                                            |_, _, _| Ok(ObjectKey::Modified(mid)),
-                                        );
-                                        err
-                                    },
-                                )?;
-                            }
-                            Err(()) => continue,
-                        };
-                        mids.pop();
+                    // Within a simulation, all work happens on its thread.
+                    if !clock::simulated() {
+                        self.write_back_concurrently(mids)?;
+                    } else {
+                        while let Some((mid, mid_pk)) = mids.last().cloned() {
+                            trace!("write_back: Trying to prepare write back");
+                            match self.prepare_write_back(mid, &mut mids) {
+                                Ok(None) => {}
+                                Ok(Some(object)) => {
+                                    trace!("write_back: Was Ok Some");
+                                    self.write_back_prepared(object, mid, mid_pk)?;
+                                }
+                                Err(()) => continue,
+                            };
+                            mids.pop();
+                        }
                     }
                 }
             }
@@ -1025,6 +2360,12 @@ where
         }
         Ok(match *or {
             ObjRef::Modified(..) | ObjRef::InWriteback(..) => None,
+            // Deltas are read along with their base on fetch.
+            ObjRef::Unmodified(ref p, _)
+                if self.handler.delta_base(p.offset(), p.info()).is_some() =>
+            {
+                None
+            }
             ObjRef::Unmodified(ref p, ref pk) => {
                 Some(Box::pin(self.try_fetch_async(p, pk.clone())?.into_future()))
             }
@@ -1033,25 +2374,18 @@ where
     }
 
     fn finish_prefetch(&self, p: Self::Prefetch) -> Result<(), Error> {
-        let (ptr, compressed_data, pk) = block_on(p)?;
-        let object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> = {
-            let data = ptr
-                .decompression_tag()
-                .new_decompression()?
-                .decompress(compressed_data)?;
-            Object::unpack_at(ptr.offset(), ptr.info(), data.into_boxed_slice())?
-        };
-        let key = ObjectKey::Unmodified {
-            offset: ptr.offset(),
-            generation: ptr.generation(),
-        };
-        self.insert_object_into_cache(key, TaggedCacheValue::new(RwLock::new(object), pk.clone()));
-        if let Some(report_tx) = &self.report_tx {
-            let _ = report_tx
-                .send(DmlMsg::fetch(ptr.offset(), ptr.size(), pk))
-                .map_err(|_| warn!("Channel Receiver has been dropped."));
-        }
-        Ok(())
+        let (ptr, data, pk) = block_on(p)?;
+        self.insert_prefetched(ptr, data, pk)
+    }
+
+    fn finish_prefetch_async<'a>(
+        &'a self,
+        p: Self::Prefetch,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        Box::pin(async move {
+            let (ptr, data, pk) = p.await?;
+            self.insert_prefetched(ptr, data, pk)
+        })
     }
 
     // Cache depending methods
@@ -1103,6 +2437,10 @@ where
         Arc::clone(&self.storage_hints)
     }
 
+    fn displaced_nodes(&self) -> Arc<Mutex<HashMap<PivotKey, Displacement>>> {
+        Arc::clone(&self.displaced_nodes)
+    }
+
     fn default_storage_class(&self) -> StoragePreference {
         StoragePreference::from_u8(self.default_storage_class)
     }
@@ -1,5 +1,9 @@
 #![allow(missing_docs, unused_doc_comments)]
-use crate::{storage_pool::DiskOffset, vdev::Block};
+use crate::{
+    database::{DatasetId, ErrorCode},
+    storage_pool::DiskOffset,
+    vdev::Block,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,11 +13,24 @@ pub enum Error {
         #[from]
         source: crate::vdev::Error,
     },
+    #[error("Reading {size:?} at {offset:?} of dataset {dataset} failed.")]
+    ReadFailed {
+        dataset: DatasetId,
+        offset: DiskOffset,
+        size: Block<u32>,
+        #[source]
+        source: crate::vdev::Error,
+    },
     #[error("The chosen compression type encountered an error.")]
     CompressionError {
         #[from]
         source: crate::compression::Error,
     },
+    #[error("The encryption encountered an error.")]
+    EncryptionError {
+        #[from]
+        source: crate::encryption::Error,
+    },
     #[error("Decompressing serialized data failed.")]
     DecompressionError,
     #[error("Deserialization failed.")]
@@ -21,24 +38,78 @@ pub enum Error {
     #[error("Serialization failed.")]
     SerializationError,
     #[error("The allocation handler encountered an error.")]
-    HandlerError(String),
+    HandlerError {
+        // Boxed to avoid a recursive type, the database error may contain
+        // errors of this layer.
+        #[source]
+        source: Box<crate::database::Error>,
+    },
     #[error("Input/Output procedure encountered an error.")]
     IoError {
         #[from]
         source: std::io::Error,
     },
-    #[error("Could not find fitting space to allocate data.")]
-    OutOfSpaceError,
+    #[error(
+        "Could not find {size:?} of free space in storage class {class} for dataset {dataset}."
+    )]
+    OutOfSpaceError {
+        dataset: DatasetId,
+        class: u8,
+        size: Block<u32>,
+    },
+    #[error("Storage class {0} has no vdevs.")]
+    NoVdevs(u8),
     #[error("A callback function to the cache has errored.")]
     CallbackError,
     #[error("A raw allocation has failed.")]
     RawAllocationError { at: DiskOffset, size: Block<u32> },
 }
 
-// To avoid recursive error types here, define a simple translation from
-// database to Error.
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::VdevError { source } | Error::ReadFailed { source, .. } => source.code(),
+            Error::CompressionError { source } => source.code(),
+            Error::EncryptionError { source } => source.code(),
+            Error::HandlerError { source } => source.code(),
+            Error::DecompressionError | Error::DeserializationError => ErrorCode::Corrupted,
+            Error::IoError { .. } => ErrorCode::Io,
+            Error::OutOfSpaceError { .. } | Error::RawAllocationError { .. } => {
+                ErrorCode::OutOfSpace
+            }
+            Error::NoVdevs(_) => ErrorCode::InvalidArgument,
+            Error::SerializationError | Error::CallbackError => ErrorCode::Internal,
+        }
+    }
+
+    /// Returns the dataset this error occurred in, if known.
+    pub fn dataset(&self) -> Option<DatasetId> {
+        match self {
+            Error::ReadFailed { dataset, .. } | Error::OutOfSpaceError { dataset, .. } => {
+                Some(*dataset)
+            }
+            Error::HandlerError { source } => source.dataset(),
+            _ => None,
+        }
+    }
+
+    /// Returns the disk offset this error occurred at, if known.
+    pub fn disk_offset(&self) -> Option<DiskOffset> {
+        match self {
+            Error::ReadFailed { offset, .. } => Some(*offset),
+            Error::RawAllocationError { at, .. } => Some(*at),
+            Error::HandlerError { source } => source.disk_offset(),
+            _ => None,
+        }
+    }
+}
+
+// To avoid recursive error types here, box errors of the database.
 impl From<crate::database::Error> for Error {
     fn from(value: crate::database::Error) -> Self {
-        Error::HandlerError(format!("{value:?}"))
+        Error::HandlerError {
+            source: Box::new(value),
+        }
     }
 }
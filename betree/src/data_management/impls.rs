@@ -1,5 +1,9 @@
-use super::{object_ptr::ObjectPointer, HasStoragePreference};
+use super::{
+    object_ptr::{LegacyObjectPointer, ObjectPointer},
+    HasStoragePreference,
+};
 use crate::{
+    checksum::Checksum,
     database::Generation,
     size::{StaticSize},
     storage_pool::DiskOffset,
@@ -40,7 +44,7 @@ pub enum ObjRef<P> {
 
 impl<D> super::ObjectReference for ObjRef<ObjectPointer<D>>
 where
-    D: std::fmt::Debug + 'static,
+    D: Checksum,
     ObjectPointer<D>: Serialize + DeserializeOwned + StaticSize + Clone,
 {
     type ObjectPointer = ObjectPointer<D>;
@@ -72,6 +76,11 @@ where
             ObjRef::Unmodified(_, pk) | ObjRef::Modified(_, pk) | ObjRef::InWriteback(_, pk) => pk,
         }
     }
+
+    fn deserialize_legacy<'de, E: Deserializer<'de>>(deserializer: E) -> Result<Self, E::Error> {
+        LegacyObjectPointer::<D::Legacy>::deserialize(deserializer)
+            .map(|ptr| ObjRef::Incomplete(ptr.into()))
+    }
 }
 
 impl<D> ObjRef<ObjectPointer<D>> {
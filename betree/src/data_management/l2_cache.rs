@@ -0,0 +1,194 @@
+//! Second-level cache of clean nodes on a fast storage class.
+//!
+//! Unmodified nodes of slower storage classes which are evicted from the
+//! in-memory cache are written to blocks of the fast class, which are only
+//! marked in the in-memory allocators and never recorded in the allocation
+//! trees.  Fetches look up the small in-memory index first and read the staged
+//! copy instead of the original.  The index is lost when the database is
+//! closed, which frees the blocks implicitly.
+
+use super::object_ptr::ObjectPointer;
+use crate::{
+    database::Generation,
+    storage_pool::DiskOffset,
+    vdev::{Block, BLOCK_SIZE},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Where and how large the second-level node cache is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct L2CacheConfiguration {
+    /// The storage class the evicted nodes are staged on, usually the fastest
+    /// one.  Only nodes of slower classes are staged.
+    pub storage_class: u8,
+    /// The space taken from the storage class in bytes.
+    pub size: usize,
+}
+
+/// Usage of the second-level node cache.
+#[derive(Debug, Clone, Copy)]
+pub struct L2CacheStats {
+    /// The number of staged nodes.
+    pub entries: usize,
+    /// The blocks occupied by staged nodes.
+    pub used: Block<u64>,
+    /// The maximum number of blocks occupied by staged nodes.
+    pub capacity: Block<u64>,
+    /// Fetches which have been served by a staged node.
+    pub hits: u64,
+    /// Fetches of nodes of slower classes which have not been staged.
+    pub misses: u64,
+}
+
+struct Staged<C> {
+    generation: Generation,
+    ptr: ObjectPointer<C>,
+}
+
+struct State<C> {
+    // Staged nodes by the offset of their original.
+    index: HashMap<DiskOffset, Staged<C>>,
+    // Original and staged offsets in staging order, entries which have been
+    // invalidated in the meantime are skipped on eviction.
+    order: VecDeque<(DiskOffset, DiskOffset)>,
+    used: Block<u64>,
+}
+
+impl<C> State<C> {
+    fn remove(&mut self, offset: DiskOffset) -> Option<ObjectPointer<C>> {
+        let staged = self.index.remove(&offset)?;
+        self.used = self.used - staged.ptr.size.as_u64();
+        Some(staged.ptr)
+    }
+}
+
+/// Index of the nodes staged on the fast storage class, see the module
+/// documentation.
+pub struct L2Cache<C> {
+    storage_class: u8,
+    capacity: Block<u64>,
+    state: Mutex<State<C>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<C: Clone> L2Cache<C> {
+    /// Returns an empty cache as described by `config`.
+    pub fn new(config: &L2CacheConfiguration) -> Self {
+        L2Cache {
+            storage_class: config.storage_class,
+            capacity: Block((config.size / BLOCK_SIZE) as u64),
+            state: Mutex::new(State {
+                index: HashMap::new(),
+                order: VecDeque::new(),
+                used: Block(0),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The storage class the nodes are staged on.
+    pub fn storage_class(&self) -> u8 {
+        self.storage_class
+    }
+
+    // Whether nodes stored at `offset` are read faster from the cache.
+    fn covers(&self, offset: DiskOffset) -> bool {
+        offset.storage_class() > self.storage_class
+    }
+
+    /// Whether the evicted node stored at `offset` should be staged.
+    pub fn should_stage(&self, offset: DiskOffset) -> bool {
+        self.covers(offset) && !self.state.lock().index.contains_key(&offset)
+    }
+
+    /// Returns the staged copy of the node stored at `offset` with the given
+    /// `generation`.
+    pub fn get(&self, offset: DiskOffset, generation: Generation) -> Option<ObjectPointer<C>> {
+        if !self.covers(offset) {
+            return None;
+        }
+        let staged = self
+            .state
+            .lock()
+            .index
+            .get(&offset)
+            .filter(|staged| staged.generation == generation)
+            .map(|staged| staged.ptr.clone());
+        let counter = if staged.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        staged
+    }
+
+    /// Records `staged` as the copy of the node stored at `offset`.  Returns
+    /// the staged copies which have been displaced to stay within the
+    /// capacity, their blocks have to be freed by the caller.
+    pub fn insert(
+        &self,
+        offset: DiskOffset,
+        generation: Generation,
+        staged: ObjectPointer<C>,
+    ) -> Vec<ObjectPointer<C>> {
+        let mut state = self.state.lock();
+        let mut displaced: Vec<_> = state.remove(offset).into_iter().collect();
+        state.used += staged.size.as_u64();
+        state.order.push_back((offset, staged.offset));
+        state.index.insert(
+            offset,
+            Staged {
+                generation,
+                ptr: staged,
+            },
+        );
+        while state.used > self.capacity {
+            let (offset, staged_offset) = match state.order.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if state.index.get(&offset).map(|staged| staged.ptr.offset) == Some(staged_offset) {
+                displaced.extend(state.remove(offset));
+            }
+        }
+        // Drop the entries of invalidated nodes once they dominate.
+        if state.order.len() > 2 * state.index.len() {
+            let State { index, order, .. } = &mut *state;
+            order.retain(|(offset, staged_offset)| {
+                index.get(offset).map(|staged| staged.ptr.offset) == Some(*staged_offset)
+            });
+        }
+        displaced
+    }
+
+    /// Forgets the staged copy of the node stored at `offset`, e.g. because
+    /// the node has been modified.  Returns the staged copy, whose blocks
+    /// have to be freed by the caller.
+    pub fn invalidate(&self, offset: DiskOffset) -> Option<ObjectPointer<C>> {
+        if !self.covers(offset) {
+            return None;
+        }
+        self.state.lock().remove(offset)
+    }
+
+    /// Returns the current usage of the cache.
+    pub fn stats(&self) -> L2CacheStats {
+        let state = self.state.lock();
+        L2CacheStats {
+            entries: state.index.len(),
+            used: state.used,
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -13,8 +13,9 @@
 //! data blobs as in the [crate::object] module.
 
 use crate::{
+    buffer::Buf,
     cache::AddSize,
-    database::DatasetId,
+    database::{DatasetId, EventHandlers},
     migration::DmlMsg,
     size::{Size, StaticSize},
     storage_pool::{DiskOffset, GlobalDiskId, StoragePoolLayer},
@@ -23,14 +24,16 @@ use crate::{
     StoragePreference,
 };
 use parking_lot::Mutex;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserializer, Serialize};
 use stable_deref_trait::StableDeref;
 use std::{
     collections::HashMap,
     fmt::Debug,
+    future::Future,
     hash::Hash,
     io::{self, Write},
     ops::DerefMut,
+    pin::Pin,
     sync::Arc,
 };
 
@@ -61,7 +64,9 @@ impl<
 /// While this trait only has one known implementor [impls::ObjRef], it is
 /// useful to hide away ugly types such as the ObjectPointer within the [Dml]
 /// trait.
-pub trait ObjectReference: Serialize + DeserializeOwned + StaticSize + Debug + 'static {
+pub trait ObjectReference:
+    Serialize + DeserializeOwned + StaticSize + Debug + Clone + 'static
+{
     /// The ObjectPointer for this ObjectRef.
     type ObjectPointer;
     /// Return a reference to an `Self::ObjectPointer`
@@ -71,6 +76,8 @@ pub trait ObjectReference: Serialize + DeserializeOwned + StaticSize + Debug + '
     fn set_index(&mut self, pk: PivotKey);
     /// Retrieve the index of this node.
     fn index(&self) -> &PivotKey;
+    /// Deserialize a reference in the layout of format version 1.
+    fn deserialize_legacy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
 }
 
 /// Implementing types have an allocation preference, which can be invalidated
@@ -113,11 +120,7 @@ pub trait Object<R>: Size + Sized + HasStoragePreference {
     /// Packs the object into the given `writer`.
     fn pack<W: Write>(&self, writer: W) -> Result<(), io::Error>;
     /// Unpacks the object from the given `data`.
-    fn unpack_at(
-        disk_offset: DiskOffset,
-        d_id: DatasetId,
-        data: Box<[u8]>,
-    ) -> Result<Self, io::Error>;
+    fn unpack_at(disk_offset: DiskOffset, d_id: DatasetId, data: Buf) -> Result<Self, io::Error>;
 
     /// Returns debug information about an object.
     fn debug_info(&self) -> String;
@@ -217,6 +220,13 @@ pub trait Dml: Sized {
     /// Finishes the prefetching.
     fn finish_prefetch(&self, p: Self::Prefetch) -> Result<(), Error>;
 
+    /// Finishes the prefetching like [Dml::finish_prefetch], but waits for
+    /// the read without blocking the calling thread.
+    fn finish_prefetch_async<'a>(
+        &'a self,
+        p: Self::Prefetch,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
     /// Which format the cache statistics are represented in. For example a simple struct.
     type CacheStats: serde::Serialize;
     /// Cache-dependent statistics.
@@ -227,6 +237,8 @@ pub trait Dml: Sized {
     fn verify_cache(&self);
     /// Evicts excessive cache entries.
     fn evict(&self) -> Result<(), Error>;
+    /// Returns the handlers to notify about events.
+    fn events(&self) -> &EventHandlers;
 }
 
 /// Legible result of a copy-on-write call. This describes wether the given
@@ -259,11 +271,26 @@ pub trait DmlWithHandler {
     fn handler(&self) -> &Self::Handler;
 }
 
+/// A node which has been written to another storage class than intended,
+/// because the intended one was full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Displacement {
+    /// The storage class the node should have been written to.
+    pub preferred: StoragePreference,
+    /// The storage class the node has been written to instead.
+    pub actual: StoragePreference,
+    /// The size of the node on disk.
+    pub size: Block<u32>,
+}
+
 /// Denotes if an implementor of the [Dml] can also handle storage hints emitted
 /// by the migration policies.
 pub trait DmlWithStorageHints {
     /// Returns a handle to the storage hint data structure.
     fn storage_hints(&self) -> Arc<Mutex<HashMap<PivotKey, StoragePreference>>>;
+    /// Returns a handle to the nodes which are currently displaced from their
+    /// preferred storage class.
+    fn displaced_nodes(&self) -> Arc<Mutex<HashMap<PivotKey, Displacement>>>;
     /// Returns the default storage class used when [StoragePreference] is `None`.
     fn default_storage_class(&self) -> StoragePreference;
 }
@@ -280,11 +307,19 @@ pub trait DmlWithReport {
 
 mod cache_value;
 mod delegation;
+mod delta;
 mod dmu;
 pub(crate) mod errors;
 pub(crate) mod impls;
+mod l2_cache;
 mod object_ptr;
 
-pub(crate) use self::cache_value::TaggedCacheValue;
+pub(crate) use self::{cache_value::TaggedCacheValue, object_ptr::LegacyObjectPointer};
 
-pub use self::{dmu::Dmu, errors::Error, object_ptr::ObjectPointer};
+pub use self::{
+    delta::{DeltaBase, DeltaConfiguration},
+    dmu::Dmu,
+    errors::Error,
+    l2_cache::{L2Cache, L2CacheConfiguration, L2CacheStats},
+    object_ptr::ObjectPointer,
+};
@@ -1,10 +1,14 @@
 use super::HasStoragePreference;
 use crate::{
+    allocator::Extent,
+    buffer::Buf,
+    checksum::Checksum,
     compression::DecompressionTag,
     database::{DatasetId, Generation},
+    encryption::EncryptionTag,
     size::StaticSize,
-    storage_pool::DiskOffset,
-    vdev::Block,
+    storage_pool::{DiskOffset, StoragePoolLayer},
+    vdev::{Block, Result as VdevResult},
     StoragePreference,
 };
 use serde::{Deserialize, Serialize};
@@ -13,13 +17,71 @@ use serde::{Deserialize, Serialize};
 /// A pointer to an on-disk serialized object.
 pub struct ObjectPointer<D> {
     pub(super) decompression_tag: DecompressionTag,
+    pub(super) encryption_tag: EncryptionTag,
     pub(super) checksum: D,
     pub(super) offset: DiskOffset,
     pub(super) size: Block<u32>,
+    // The size of the serialized object before compression.
+    pub(super) logical_size: u32,
+    // Whether only the first `logical_size` bytes of the object have been
+    // written and are covered by the checksum, see `read_from`.
+    pub(super) unpadded: bool,
     pub(super) info: DatasetId,
     pub(super) generation: Generation,
 }
 
+/// The layout of object pointers of format version 1, which have no
+/// encryption tag and an offset in the layout of
+/// [DiskOffset::from_legacy].
+#[derive(Debug, Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub(crate) struct LegacyObjectPointer<D> {
+    decompression_tag: DecompressionTag,
+    checksum: D,
+    offset: u64,
+    size: Block<u32>,
+    info: DatasetId,
+    generation: Generation,
+}
+
+impl<L: Into<D>, D> From<LegacyObjectPointer<L>> for ObjectPointer<D> {
+    fn from(legacy: LegacyObjectPointer<L>) -> Self {
+        ObjectPointer {
+            decompression_tag: legacy.decompression_tag,
+            encryption_tag: EncryptionTag::None,
+            checksum: legacy.checksum.into(),
+            offset: DiskOffset::from_legacy(legacy.offset),
+            size: legacy.size,
+            // The size before compression has not been recorded.
+            logical_size: legacy.size.to_bytes(),
+            unpadded: false,
+            info: legacy.info,
+            generation: legacy.generation,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<D> LegacyObjectPointer<D> {
+    /// Points to an uncompressed object at the legacy `offset`.
+    pub(crate) fn new(
+        checksum: D,
+        offset: u64,
+        size: Block<u32>,
+        info: DatasetId,
+        generation: Generation,
+    ) -> Self {
+        LegacyObjectPointer {
+            decompression_tag: DecompressionTag::None,
+            checksum,
+            offset,
+            size,
+            info,
+            generation,
+        }
+    }
+}
+
 impl<D> HasStoragePreference for ObjectPointer<D> {
     fn current_preference(&self) -> Option<StoragePreference> {
         Some(self.correct_preference())
@@ -46,11 +108,14 @@ impl<D> HasStoragePreference for ObjectPointer<D> {
 impl<D: StaticSize> StaticSize for ObjectPointer<D> {
     fn static_size() -> usize {
         <DecompressionTag as StaticSize>::static_size()
+            + EncryptionTag::static_size()
             + D::static_size()
             + DatasetId::static_size()
             + Generation::static_size()
             + <DiskOffset as StaticSize>::static_size()
             + Block::<u32>::static_size()
+            + 4
+            + 1
     }
 }
 
@@ -59,6 +124,10 @@ impl<D> ObjectPointer<D> {
     pub fn decompression_tag(&self) -> DecompressionTag {
         self.decompression_tag
     }
+    /// Get the encryption tag.
+    pub fn encryption_tag(&self) -> EncryptionTag {
+        self.encryption_tag
+    }
     /// Get a reference to the checksum of the target.
     pub fn checksum(&self) -> &D {
         &self.checksum
@@ -71,6 +140,22 @@ impl<D> ObjectPointer<D> {
     pub fn size(&self) -> Block<u32> {
         self.size
     }
+    /// Get the size in bytes of the serialized object before compression,
+    /// which is accounted as the logical size of its dataset.
+    pub fn logical_size(&self) -> u32 {
+        self.logical_size
+    }
+    /// Whether only the serialized object itself has been written, without
+    /// padding it to whole blocks.  This is the case for uncompressed objects
+    /// on byte-addressable storage classes.
+    pub fn is_unpadded(&self) -> bool {
+        self.unpadded
+    }
+    /// Get the blocks occupied by the serialized object, which may span
+    /// several segments.
+    pub fn extent(&self) -> Extent {
+        Extent::new(self.offset, self.size)
+    }
     /// Get the generation this object reference is belonging to. Relevant for
     /// dataset snapshots.
     pub fn generation(&self) -> Generation {
@@ -81,3 +166,19 @@ impl<D> ObjectPointer<D> {
         self.info
     }
 }
+
+impl<D: Checksum> ObjectPointer<D> {
+    /// Reads the serialized object from `pool` and verifies it with its
+    /// checksum.  Unpadded objects are read at their exact length, the
+    /// returned buffer is padded with zeroes in either case.
+    pub fn read_from<SPL>(&self, pool: &SPL) -> VdevResult<Buf>
+    where
+        SPL: StoragePoolLayer<Checksum = D>,
+    {
+        if self.unpadded {
+            pool.read_bytes(self.logical_size, self.offset, self.checksum.clone())
+        } else {
+            pool.read(self.size, self.offset, self.checksum.clone())
+        }
+    }
+}
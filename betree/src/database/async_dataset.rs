@@ -0,0 +1,172 @@
+//! Asynchronous access to datasets.
+//!
+//! Lookups descend the tree in the calling task and await the reads of nodes
+//! which are not cached on the futures of the data management layer, so they
+//! never block the executor.  Insertions and range scans may still write back
+//! or read nodes synchronously, e.g. when the cache has to evict dirty nodes,
+//! and are handed to a [BlockingSpawner] instead.  The spawner is the only
+//! part which depends on the executor: it is implemented for the thread pool
+//! of the `futures` crate and, with the `async_tokio` feature, for a Tokio
+//! runtime by [TokioSpawner].
+use super::{errors::*, Dataset};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    tree::{self, DefaultMessageAction, MessageAction, TreeLayer},
+};
+use futures::{
+    channel::oneshot,
+    executor::ThreadPool,
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use std::{borrow::Borrow, ops::RangeBounds, sync::Arc};
+
+// Number of entries a range scan reads per blocking task.
+const RANGE_BATCH: usize = 256;
+
+/// Runs the blocking parts of the asynchronous dataset operations.
+pub trait BlockingSpawner: Send + Sync + 'static {
+    /// Runs `task` on a thread on which it may block without stalling other
+    /// futures.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+/// Runs the tasks on the pool threads, which should therefore not be shared
+/// with futures that are not allowed to wait.
+impl BlockingSpawner for ThreadPool {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        self.spawn_ok(async move { task() })
+    }
+}
+
+/// Runs the tasks on the blocking threads of a Tokio runtime.
+#[cfg(feature = "async_tokio")]
+pub struct TokioSpawner(pub tokio::runtime::Handle);
+
+#[cfg(feature = "async_tokio")]
+impl BlockingSpawner for TokioSpawner {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        // The result is passed on by the task itself.
+        drop(self.0.spawn_blocking(task));
+    }
+}
+
+async fn run_blocking<F, R>(spawner: &dyn BlockingSpawner, f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    spawner.spawn_blocking(Box::new(move || {
+        let _ = sender.send(f());
+    }));
+    receiver.await.map_err(|_| Error::Canceled)?
+}
+
+/// A [Dataset] with asynchronous operations, see the module documentation.
+pub struct AsyncDataset<Message = DefaultMessageAction> {
+    dataset: Dataset<Message>,
+    spawner: Arc<dyn BlockingSpawner>,
+}
+
+impl<Message> Clone for AsyncDataset<Message> {
+    fn clone(&self) -> Self {
+        Self {
+            dataset: self.dataset.clone(),
+            spawner: Arc::clone(&self.spawner),
+        }
+    }
+}
+
+impl<Message> AsyncDataset<Message> {
+    /// Wraps `dataset`, running its blocking operations with `spawner`.
+    pub fn new(dataset: Dataset<Message>, spawner: Arc<dyn BlockingSpawner>) -> Self {
+        AsyncDataset { dataset, spawner }
+    }
+
+    /// Returns the synchronous handle of the dataset.
+    pub fn dataset(&self) -> &Dataset<Message> {
+        &self.dataset
+    }
+}
+
+impl<Message: MessageAction + 'static> AsyncDataset<Message> {
+    /// Returns the value for the given key if existing.
+    pub async fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
+        let tree = self.dataset.call_tree(|tree| tree.clone());
+        Ok(tree.get_async(key.borrow()).await?)
+    }
+
+    /// Inserts a message for the given key.
+    pub async fn insert_msg<K>(&self, key: K, msg: SlicedCowBytes) -> Result<()>
+    where
+        K: Borrow<[u8]> + Into<CowBytes> + Send + 'static,
+    {
+        let dataset = self.dataset.clone();
+        run_blocking(&*self.spawner, move || dataset.insert_msg(key, msg)).await
+    }
+
+    /// Iterates over all key-value pairs in the given key range.  The entries
+    /// are read in batches by blocking tasks.
+    pub fn range<R, K>(
+        &self,
+        range: R,
+    ) -> Result<BoxStream<'static, Result<(CowBytes, SlicedCowBytes)>>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        let iter = self.dataset.call_tree(|tree| tree.range(range))?;
+        let spawner = Arc::clone(&self.spawner);
+        let batches = stream::unfold(Some(iter), move |iter| {
+            let spawner = Arc::clone(&spawner);
+            async move {
+                let mut iter = iter?;
+                let batch = run_blocking(&*spawner, move || {
+                    let batch: Vec<Result<_>> = iter
+                        .by_ref()
+                        .take(RANGE_BATCH)
+                        .map(|entry| Ok(entry?))
+                        .collect();
+                    let iter = if batch.len() < RANGE_BATCH {
+                        None
+                    } else {
+                        Some(iter)
+                    };
+                    Ok((batch, iter))
+                })
+                .await;
+                Some(match batch {
+                    Ok((batch, iter)) => (batch, iter),
+                    Err(e) => (vec![Err(e)], None),
+                })
+            }
+        });
+        Ok(batches.flat_map(stream::iter).boxed())
+    }
+}
+
+impl AsyncDataset<DefaultMessageAction> {
+    /// Inserts the given key-value pair.
+    ///
+    /// Note that any existing value will be overwritten.
+    pub async fn insert<K>(&self, key: K, data: &[u8]) -> Result<()>
+    where
+        K: Borrow<[u8]> + Into<CowBytes> + Send + 'static,
+    {
+        if data.len() > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.insert_msg(key, DefaultMessageAction::insert_msg(data))
+            .await
+    }
+
+    /// Deletes the key-value pair if existing.
+    pub async fn delete<K>(&self, key: K) -> Result<()>
+    where
+        K: Borrow<[u8]> + Into<CowBytes> + Send + 'static,
+    {
+        self.insert_msg(key, DefaultMessageAction::delete_msg())
+            .await
+    }
+}
@@ -0,0 +1,389 @@
+//! Backups of a whole database into a portable archive, see
+//! [Database::backup] and [Database::restore].
+//!
+//! The archive holds the logical contents of all datasets, their snapshots
+//! and pinned key ranges, and the object stores built on them, but none of
+//! the physical layout.  It can therefore be restored onto a storage pool of
+//! a different tier layout.
+//!
+//! An archive starts with an [ArchiveHeader] followed by a stream of
+//! [Record]s, both encoded with bincode.  The entries of a dataset are
+//! written as the changes from one snapshot to the next in the order of
+//! their generation, followed by the changes to the current contents, so
+//! that restored snapshots share unchanged entries again.
+use super::{
+    errors::*,
+    fetch_ds_data, fetch_ss_data,
+    pinning::PinnedRanges,
+    root_tree_msg::{snapshot, DATASET_NAME_TO_ID},
+    AccessMode, Database, DatabaseConfiguration, Dataset, DatasetId, Generation, MessageTree,
+    ObjectPointer, RootDmu,
+};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    data_management::Dml,
+    object::MetaMessageAction,
+    storage_pool::StoragePoolLayer,
+    tree::{DefaultMessageAction, MessageAction, Tree, TreeLayer},
+    StoragePreference,
+};
+use bincode::{deserialize_from, serialize_into};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    io::{BufReader, BufWriter, Read, Write},
+    sync::Arc,
+};
+
+const ARCHIVE_MAGIC: [u8; 8] = *b"BETREBAK";
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    magic: [u8; 8],
+    version: u32,
+    // The names of the storage classes of the backed up database, by which
+    // pinned ranges are assigned to the storage classes of the restored one.
+    tiers: Vec<Option<String>>,
+}
+
+// How the entries of a dataset are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum DatasetKind {
+    Plain,
+    // The metadata of an object store, see `MetaMessageAction`.
+    ObjectMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    // Starts a dataset, the following entry and snapshot records belong to
+    // it.
+    Dataset {
+        name: CowBytes,
+        kind: DatasetKind,
+        pins: PinnedRanges,
+    },
+    Put {
+        key: CowBytes,
+        value: SlicedCowBytes,
+    },
+    Delete {
+        key: CowBytes,
+    },
+    // The entries so far make up the snapshot `name`.
+    Snapshot {
+        name: CowBytes,
+    },
+    ObjectStore {
+        name: CowBytes,
+        data: CowBytes,
+        meta: CowBytes,
+    },
+    End,
+}
+
+pub(super) type Entries = Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>>;
+
+// Returns all entries of the tree `ptr` of the dataset `id`.
+pub(super) fn entries<M>(
+    db: &Database,
+    id: DatasetId,
+    ptr: ObjectPointer,
+    msg_action: M,
+) -> Result<Entries>
+where
+    M: MessageAction + 'static,
+{
+    let tree: MessageTree<RootDmu, M> = Tree::open(
+        id,
+        ptr,
+        msg_action,
+        Arc::clone(db.root_tree.dmu()),
+        StoragePreference::NONE,
+    );
+    Ok(Box::new(
+        tree.range::<_, &[u8]>(..)?
+            .map(|result| result.map_err(Error::from)),
+    ))
+}
+
+// Calls `f` for each change turning the entries `old` into the entries
+// `new`, with the new value of the key or `None` if it has been removed.
+pub(super) fn for_each_change<I, J, F>(mut old: I, mut new: J, mut f: F) -> Result<()>
+where
+    I: Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>,
+    J: Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>,
+    F: FnMut(CowBytes, Option<SlicedCowBytes>) -> Result<()>,
+{
+    let mut old_entry = old.next().transpose()?;
+    let mut new_entry = new.next().transpose()?;
+    loop {
+        let ordering = match (&old_entry, &new_entry) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((old_key, _)), Some((new_key, _))) => old_key[..].cmp(&new_key[..]),
+        };
+        if ordering != Ordering::Greater {
+            let (key, old_value) = old_entry.take().unwrap();
+            old_entry = old.next().transpose()?;
+            if ordering == Ordering::Less {
+                f(key, None)?;
+                continue;
+            }
+            let (_, value) = new_entry.take().unwrap();
+            new_entry = new.next().transpose()?;
+            if old_value[..] != value[..] {
+                f(key, Some(value))?;
+            }
+        } else {
+            let (key, value) = new_entry.take().unwrap();
+            new_entry = new.next().transpose()?;
+            f(key, Some(value))?;
+        }
+    }
+}
+
+// Writes the records turning the entries `old` into the entries `new`.
+fn write_changes<W: Write>(writer: &mut W, old: Entries, new: Entries) -> Result<()> {
+    for_each_change(old, new, |key, value| {
+        let record = match value {
+            Some(value) => Record::Put { key, value },
+            None => Record::Delete { key },
+        };
+        serialize_into(&mut *writer, &record)?;
+        Ok(())
+    })
+}
+
+// A dataset which is being restored.
+enum Target {
+    Plain(Dataset),
+    ObjectMeta(Dataset<MetaMessageAction>),
+}
+
+impl Target {
+    fn put(&self, key: CowBytes, value: SlicedCowBytes) -> Result<()> {
+        match self {
+            Target::Plain(ds) => ds.insert(key, &value),
+            Target::ObjectMeta(ds) => {
+                let msg = MetaMessageAction::replace_msg(&key, &value)?;
+                ds.insert_msg(key, msg)
+            }
+        }
+    }
+
+    fn delete(&self, key: CowBytes) -> Result<()> {
+        match self {
+            Target::Plain(ds) => ds.delete(key),
+            Target::ObjectMeta(ds) => {
+                let msg = MetaMessageAction::delete_msg(&key);
+                ds.insert_msg(key, msg)
+            }
+        }
+    }
+
+    fn snapshot(&mut self, db: &mut Database, name: &[u8]) -> Result<()> {
+        match self {
+            Target::Plain(ds) => db.create_snapshot(ds, name),
+            Target::ObjectMeta(ds) => db.create_snapshot(ds, name),
+        }
+    }
+
+    fn close(self, db: &Database) -> Result<()> {
+        match self {
+            Target::Plain(ds) => db.close_dataset(ds),
+            Target::ObjectMeta(ds) => db.close_dataset(ds),
+        }
+    }
+}
+
+impl Database {
+    /// Writes all datasets including their snapshots and pinned key ranges,
+    /// and all object stores into a self-contained archive, from which
+    /// [Database::restore] reconstructs them on another storage pool.
+    ///
+    /// The database is synced first and the archive reflects this state.
+    /// Datasets must not be modified concurrently.  Checkpoints, the space
+    /// accounting and the placement of nodes on the storage classes are not
+    /// part of the archive.
+    pub fn backup<W: Write>(&mut self, writer: W) -> Result<()> {
+        self.sync()?;
+        let mut writer = BufWriter::new(writer);
+        let spl = self.root_tree.dmu().spl();
+        let header = ArchiveHeader {
+            magic: ARCHIVE_MAGIC,
+            version: ARCHIVE_VERSION,
+            tiers: (0..spl.storage_class_count())
+                .map(|class| spl.tier_name(class))
+                .collect(),
+        };
+        serialize_into(&mut writer, &header)?;
+
+        let object_stores = self.object_stores()?;
+        let meta_datasets: HashSet<_> = object_stores.iter().map(|&(_, _, meta)| meta).collect();
+        let low = &[DATASET_NAME_TO_ID] as &[_];
+        let high = &[DATASET_NAME_TO_ID + 1] as &[_];
+        let mut names = HashMap::new();
+        for result in self.root_tree.range(low..high)? {
+            let (key, value) = result?;
+            let id = DatasetId::unpack(&value);
+            let name = CowBytes::from(&key[1..]);
+            names.insert(id, name.clone());
+            let kind = if meta_datasets.contains(&id) {
+                DatasetKind::ObjectMeta
+            } else {
+                DatasetKind::Plain
+            };
+            serialize_into(
+                &mut writer,
+                &Record::Dataset {
+                    name,
+                    kind,
+                    pins: PinnedRanges::fetch(&self.root_tree, id)?,
+                },
+            )?;
+            self.backup_dataset(&mut writer, id, kind)?;
+        }
+
+        for (name, data, meta) in object_stores {
+            let (data, meta) = match (names.get(&data), names.get(&meta)) {
+                (Some(data), Some(meta)) => (data.clone(), meta.clone()),
+                _ => return Err(Error::DoesNotExist),
+            };
+            serialize_into(&mut writer, &Record::ObjectStore { name, data, meta })?;
+        }
+        serialize_into(&mut writer, &Record::End)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Writes the snapshots and the contents of the dataset `id`.
+    fn backup_dataset<W: Write>(
+        &self,
+        writer: &mut W,
+        id: DatasetId,
+        kind: DatasetKind,
+    ) -> Result<()> {
+        let open = |ptr| match kind {
+            DatasetKind::Plain => entries(self, id, ptr, DefaultMessageAction),
+            DatasetKind::ObjectMeta => entries(self, id, ptr, MetaMessageAction),
+        };
+        let low = &snapshot::min_key(id) as &[_];
+        let high = &snapshot::max_key(id) as &[_];
+        let mut snapshots = self
+            .root_tree
+            .range(low..high)?
+            .map(|result| {
+                let (key, value) = result?;
+                Ok((
+                    Generation::unpack(&value),
+                    CowBytes::from(snapshot::name_from_key(&key)),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        snapshots.sort_by_key(|&(generation, _)| generation);
+
+        let mut previous: Entries = Box::new(std::iter::empty());
+        for (generation, name) in snapshots {
+            let ptr = fetch_ss_data(&self.root_tree, id, generation)?.ptr;
+            write_changes(writer, previous, open(ptr.clone())?)?;
+            serialize_into(&mut *writer, &Record::Snapshot { name })?;
+            previous = open(ptr)?;
+        }
+        let ptr = fetch_ds_data(&self.root_tree, id)?.ptr;
+        write_changes(writer, previous, open(ptr)?)
+    }
+
+    /// Creates a new database with `config` and restores the contents of an
+    /// archive written by [Database::backup] into it.  The storage pool of
+    /// `config` is always created anew and may have another tier layout
+    /// than the backed up one.
+    ///
+    /// Key ranges pinned to a storage class are pinned to the class of the
+    /// same name, or of the same index if there is no such class.  Pins to
+    /// classes which do not exist are dropped.  Restored snapshots are
+    /// dated to the time of the restore.
+    pub fn restore<R: Read>(reader: R, mut config: DatabaseConfiguration) -> Result<Database> {
+        config.access_mode = AccessMode::AlwaysCreateNew;
+        let mut db = Database::build(config)?;
+        let mut reader = BufReader::new(reader);
+        let header: ArchiveHeader = deserialize_from(&mut reader)?;
+        if header.magic != ARCHIVE_MAGIC || header.version != ARCHIVE_VERSION {
+            return Err(Error::InvalidArchive);
+        }
+
+        let mut target: Option<Target> = None;
+        loop {
+            match deserialize_from(&mut reader)? {
+                Record::Dataset {
+                    name,
+                    kind,
+                    mut pins,
+                } => {
+                    if let Some(target) = target.take() {
+                        target.close(&db)?;
+                    }
+                    let spl = db.root_tree.dmu().spl();
+                    pins.map_preferences(|pref| {
+                        let class = pref.as_u8();
+                        match header.tiers.get(class as usize) {
+                            Some(Some(name)) if spl.tier(name).is_some() => spl.tier(name),
+                            _ => (class < spl.storage_class_count()).then_some(pref),
+                        }
+                    });
+                    target = Some(db.restore_dataset(&name, kind, &pins)?);
+                }
+                Record::Put { key, value } => target
+                    .as_ref()
+                    .ok_or(Error::InvalidArchive)?
+                    .put(key, value)?,
+                Record::Delete { key } => {
+                    target.as_ref().ok_or(Error::InvalidArchive)?.delete(key)?
+                }
+                Record::Snapshot { name } => {
+                    let mut current = target.take().ok_or(Error::InvalidArchive)?;
+                    current.snapshot(&mut db, &name)?;
+                    target = Some(current);
+                }
+                Record::ObjectStore { name, data, meta } => {
+                    let data = db.lookup_dataset_id(&data)?;
+                    let meta = db.lookup_dataset_id(&meta)?;
+                    db.register_object_store(&name, data, meta)?;
+                }
+                Record::End => break,
+            }
+        }
+        if let Some(target) = target.take() {
+            target.close(&db)?;
+        }
+        db.sync()?;
+        Ok(db)
+    }
+
+    // Creates the dataset `name` with the pinned ranges `pins` and opens it.
+    fn restore_dataset(
+        &self,
+        name: &[u8],
+        kind: DatasetKind,
+        pins: &PinnedRanges,
+    ) -> Result<Target> {
+        match kind {
+            DatasetKind::Plain => {
+                self.create_custom_dataset::<DefaultMessageAction>(name, StoragePreference::NONE)?
+            }
+            DatasetKind::ObjectMeta => {
+                self.create_custom_dataset::<MetaMessageAction>(name, StoragePreference::NONE)?
+            }
+        }
+        pins.store(&self.root_tree, self.lookup_dataset_id(name)?)?;
+        Ok(match kind {
+            DatasetKind::Plain => Target::Plain(self.open_dataset(name)?),
+            DatasetKind::ObjectMeta => {
+                Target::ObjectMeta(self.open_custom_dataset(name, StoragePreference::NONE)?)
+            }
+        })
+    }
+}
@@ -0,0 +1,208 @@
+use super::{
+    errors::*,
+    root_tree_msg::{checkpoint, deadlist, snapshot},
+    Database, DatasetId, DeadListData, Generation, ObjectPointer, StorageInfo,
+};
+use crate::{
+    allocator::Action,
+    cow_bytes::CowBytes,
+    storage_pool::NUM_STORAGE_CLASSES,
+    tree::{DefaultMessageAction, TreeLayer},
+    DatabaseConfiguration, StoragePreference,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Information about a named checkpoint.
+#[derive(Debug, Clone)]
+pub struct CheckpointInfo {
+    /// The name under which the checkpoint has been recorded.
+    pub name: CowBytes,
+    /// The superblock generation the checkpoint refers to.
+    pub generation: Generation,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct CheckpointData<P> {
+    pub(super) root_ptr: P,
+    pub(super) tiers: [StorageInfo; NUM_STORAGE_CLASSES],
+}
+
+impl CheckpointData<ObjectPointer> {
+    pub(super) fn pack(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub(super) fn unpack(b: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(b)?)
+    }
+}
+
+pub(super) fn fetch_checkpoint_data<T>(
+    root_tree: &T,
+    name: &[u8],
+) -> Result<CheckpointData<ObjectPointer>>
+where
+    T: TreeLayer<DefaultMessageAction>,
+{
+    let data = root_tree
+        .get(checkpoint::key(name))?
+        .ok_or(Error::DoesNotExist)?;
+    CheckpointData::unpack(&data)
+}
+
+impl Database {
+    /// Synchronizes the database and records the resulting superblock
+    /// generation under the given name.
+    ///
+    /// All blocks belonging to this generation are retained until the
+    /// checkpoint is deleted with [Database::delete_checkpoint], so that the
+    /// state can be recovered with [Database::open_at_checkpoint].
+    /// Note that the creation fails if a checkpoint with the same name exists
+    /// already.
+    pub fn checkpoint(&mut self, name: &[u8]) -> Result<Generation> {
+        match fetch_checkpoint_data(&self.root_tree, name) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::DoesNotExist) => {}
+            Err(e) => return Err(e),
+        }
+
+        let (root_ptr, tiers) = self.sync_internal(true)?;
+        let data = CheckpointData { root_ptr, tiers }.pack()?;
+        self.root_tree.insert(
+            checkpoint::key(name),
+            DefaultMessageAction::insert_msg(&data),
+            StoragePreference::NONE,
+        )?;
+        // Make the checkpoint itself durable.
+        self.sync()?;
+        Ok(root_ptr.generation())
+    }
+
+    /// Lists all named checkpoints in the database.
+    pub fn list_checkpoints(&self) -> Result<Vec<CheckpointInfo>> {
+        let low = &checkpoint::min_key() as &[_];
+        let high = &checkpoint::max_key() as &[_];
+        self.root_tree
+            .range(low..high)?
+            .map(|result| {
+                let (key, value) = result?;
+                Ok(CheckpointInfo {
+                    name: CowBytes::from(checkpoint::name_from_key(&key)),
+                    generation: CheckpointData::unpack(&value)?.root_ptr.generation(),
+                })
+            })
+            .collect()
+    }
+
+    /// Opens the database given by the configuration in the state recorded by
+    /// the named checkpoint.
+    ///
+    /// All changes made after the checkpoint, including checkpoints taken
+    /// later on, are discarded.  The rollback is synced to disk before this
+    /// function returns.
+    pub fn open_at_checkpoint(builder: DatabaseConfiguration, name: &[u8]) -> Result<Self> {
+        let mut db = Self::build_internal(
+            DatabaseConfiguration {
+                access_mode: super::AccessMode::OpenIfExists,
+                ..builder
+            },
+            None,
+            None,
+            Some(name),
+        )?;
+        db.sync()?;
+        Ok(db)
+    }
+
+    /// Deletes the checkpoint recorded under the given name and deallocates
+    /// the blocks which have only been retained for it.  The deallocation
+    /// becomes durable with the next sync.
+    ///
+    /// Note that the deletion fails if no checkpoint with the given name
+    /// exists.
+    pub fn delete_checkpoint(&mut self, name: &[u8]) -> Result<()> {
+        let generation = fetch_checkpoint_data(&self.root_tree, name)?
+            .root_ptr
+            .generation();
+        self.root_tree.insert(
+            checkpoint::key(name),
+            DefaultMessageAction::delete_msg(),
+            StoragePreference::NONE,
+        )?;
+        let handler = self.root_tree.dmu().handler();
+        handler.checkpoint_generations.write().remove(&generation);
+        // Dead list entries are queued until the next sync.
+        self.flush_delayed_messages()?;
+
+        let checkpoints: Vec<_> = handler
+            .checkpoint_generations
+            .read()
+            .iter()
+            .copied()
+            .collect();
+        let mut snapshots: HashMap<DatasetId, Vec<Generation>> = HashMap::new();
+        let low = &snapshot::data_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &snapshot::data_key_max_all() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, _) = entry?;
+            snapshots
+                .entry(snapshot::ds_id_from_data_key(&key))
+                .or_default()
+                .push(snapshot::generation_from_data_key(&key));
+        }
+
+        let low = &deadlist::min_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &deadlist::max_key_all() as &[_];
+        let mut released = Vec::new();
+        for entry in self.root_tree.range(low..high)? {
+            let (key, value) = entry?;
+            let data = DeadListData::unpack(&value)?;
+            let id = deadlist::ds_id_from_key(&key);
+            let death = deadlist::generation_from_key(&key);
+            let retains = |g: &Generation| data.birth <= *g && *g < death;
+            if retains(&generation)
+                && !checkpoints.iter().any(retains)
+                && !handler.read_view_generations(id).iter().any(retains)
+                && !snapshots
+                    .get(&id)
+                    .map_or(false, |generations| generations.iter().any(retains))
+            {
+                released.push((key, id, data.size));
+            }
+        }
+        for (key, id, size) in released {
+            let offset = deadlist::offset_from_key(&key);
+            handler.update_allocation_bitmap(
+                offset,
+                size,
+                Action::Deallocate,
+                id,
+                self.root_tree.dmu(),
+            )?;
+            self.root_tree.dmu().release_delta(offset);
+            self.root_tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Restore the generations which have to be retained for checkpoints.
+    pub(super) fn load_checkpoint_generations(&self) -> Result<()> {
+        let generations = self
+            .list_checkpoints()?
+            .into_iter()
+            .map(|info| info.generation)
+            .collect();
+        *self
+            .root_tree
+            .dmu()
+            .handler()
+            .checkpoint_generations
+            .write() = generations;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Compaction of the child buffers of cold key ranges.
+//!
+//! Messages stay in the buffers of internal nodes until enough of them have
+//! accumulated for a child.  Key ranges which are written rarely keep their
+//! messages buffered for long, so that every lookup in them has to apply
+//! these messages, and the first writes after a quiet period flush many
+//! buffers at once.  While the database is idle, such buffers are flushed
+//! ahead of time.
+use super::{errors::*, Database, DatasetId};
+use crate::storage_pool::{with_io_priority, IoPriority};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, thread, time::Duration};
+
+/// Selection of the child buffers to flush, see [Database::compact].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct CompactionConfiguration {
+    /// When set and the database is built with
+    /// [Database::build_threaded], compact every `interval_ms` milliseconds
+    /// if no dataset has been written to since the previous interval.
+    pub interval_ms: Option<u64>,
+    /// Only buffers holding at least this many bytes of messages are
+    /// flushed.
+    pub min_buffer_size: usize,
+    /// Only buffers into which no message has been inserted for this many
+    /// milliseconds are flushed.
+    pub cold_after_ms: u64,
+    /// The maximum number of buffers flushed per dataset at once.
+    pub max_flushes: usize,
+}
+
+impl Default for CompactionConfiguration {
+    fn default() -> Self {
+        CompactionConfiguration {
+            interval_ms: None,
+            min_buffer_size: 64 * 1024,
+            cold_after_ms: 60_000,
+            max_flushes: 64,
+        }
+    }
+}
+
+impl Database {
+    /// Flushes the child buffers of the open datasets which hold many
+    /// messages for key ranges that have not been written to for a while, as
+    /// configured by [CompactionConfiguration].  Returns the number of
+    /// flushed buffers.
+    ///
+    /// Datasets which are not open are left as they are, their buffers have
+    /// not been written to since they were opened the last time.  The
+    /// flushed nodes are written on the next sync.
+    pub fn compact(&self) -> Result<usize> {
+        let ids: Vec<_> = self.open_datasets.read().keys().copied().collect();
+        let mut flushed = 0;
+        for id in ids {
+            flushed += self.compact_dataset(id)?;
+        }
+        Ok(flushed)
+    }
+
+    // Flushes the cold child buffers of the dataset `id`, if it is open.
+    fn compact_dataset(&self, id: DatasetId) -> Result<usize> {
+        let config = &self.builder.compaction;
+        let cold_after = Duration::from_millis(config.cold_after_ms);
+        self.open_datasets.read().get(&id).map_or(Ok(0), |tree| {
+            tree.erased_compact_buffers(config.min_buffer_size, cold_after, config.max_flushes)
+        })
+    }
+
+    // The number of messages inserted into the open datasets since they have
+    // been opened.
+    fn inserted_messages(&self) -> u64 {
+        self.open_datasets
+            .read()
+            .values()
+            .map(|tree| tree.erased_stats().inserts)
+            .sum()
+    }
+}
+
+/// The periodic compaction of a database, which only compacts if the
+/// database has been idle since the previous round.
+#[derive(Default)]
+pub(super) struct IdleCompaction {
+    inserted_messages: Option<u64>,
+}
+
+impl IdleCompaction {
+    /// Compacts `db` if no message has been inserted since the previous
+    /// call.  The first call only takes note of the inserted messages.
+    pub(super) fn round(&mut self, db: &RwLock<Database>) {
+        let inserted_messages = db.read().inserted_messages();
+        if self.inserted_messages.replace(inserted_messages) == Some(inserted_messages) {
+            compact(db);
+        }
+    }
+}
+
+pub fn compaction_timer(interval_ms: u64, db: Arc<RwLock<Database>>) {
+    let interval = Duration::from_millis(interval_ms);
+    let mut compaction = IdleCompaction::default();
+
+    loop {
+        thread::sleep(interval);
+        compaction.round(&db);
+    }
+}
+
+/// Performs one compaction of `db`.  Flushing requires the lock of `db`,
+/// which blocks writers like a sync does, so it is not throttled in favour of
+/// other users of `db`.  The lock is taken for one dataset at a time to let
+/// waiting writers in between.
+fn compact(db: &RwLock<Database>) {
+    log::debug!("compacting db");
+    let ids: Vec<_> = db.read().open_datasets.read().keys().copied().collect();
+    let mut flushed = 0;
+    for id in ids {
+        match with_io_priority(IoPriority::Commit, || db.read().compact_dataset(id)) {
+            Ok(count) => flushed += count,
+            Err(err) => {
+                log::error!("couldn't compact db: {}", err);
+                return;
+            }
+        }
+    }
+    log::debug!("compaction flushed {} buffers", flushed);
+}
@@ -0,0 +1,364 @@
+//! Validation and comparison of [DatabaseConfiguration]s, and changes of
+//! settings while a database is running.
+use super::{errors::*, Database, DatabaseConfiguration};
+use crate::{
+    cache::Cache,
+    data_management::Dml,
+    migration::{DatabaseMsg, MigrationPolicies},
+    storage_pool::{check_tier_name, QosConfiguration, StoragePoolLayer, NUM_STORAGE_CLASSES},
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, mem,
+};
+
+/// A setting which differs between two configurations, see
+/// [DatabaseConfiguration::diff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationChange {
+    /// The path of the setting, e.g. `storage.tiers[1].name`.
+    pub field: String,
+    /// The previous value, `None` if the setting has been added.
+    pub old: Option<Value>,
+    /// The new value, `None` if the setting has been removed.
+    pub new: Option<Value>,
+}
+
+impl fmt::Display for ConfigurationChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "{}: {} -> {}", self.field, old, new),
+            (None, Some(new)) => write!(f, "{}: added {}", self.field, new),
+            (Some(old), None) => write!(f, "{}: removed {}", self.field, old),
+            (None, None) => write!(f, "{}: unchanged", self.field),
+        }
+    }
+}
+
+fn invalid(field: impl Into<String>, reason: impl Into<String>) -> Error {
+    Error::InvalidConfiguration {
+        field: field.into(),
+        reason: reason.into(),
+    }
+}
+
+fn check_class(field: impl Into<String>, class: u8) -> Result<()> {
+    if class as usize >= NUM_STORAGE_CLASSES {
+        return Err(invalid(
+            field,
+            format!(
+                "storage class {} does not exist, there are {} classes",
+                class, NUM_STORAGE_CLASSES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn check_interval(field: &str, interval_ms: Option<u64>) -> Result<()> {
+    if interval_ms == Some(0) {
+        return Err(invalid(field, "the interval must not be zero"));
+    }
+    Ok(())
+}
+
+impl DatabaseConfiguration {
+    /// Checks the configuration for values which cannot be used to build a
+    /// [Database](super::Database), e.g. storage classes which do not exist.
+    ///
+    /// The error is an [Error::InvalidConfiguration] naming the first offending
+    /// field.  This is also checked by [Database::build](super::Database::build).
+    pub fn validate(&self) -> Result<()> {
+        let tiers = &self.storage.tiers;
+        if tiers.len() > NUM_STORAGE_CLASSES {
+            return Err(invalid(
+                "storage.tiers",
+                format!(
+                    "{} tiers are configured, at most {} are supported",
+                    tiers.len(),
+                    NUM_STORAGE_CLASSES
+                ),
+            ));
+        }
+        for (class, tier) in tiers.iter().enumerate() {
+            if let Some(name) = &tier.name {
+                let field = format!("storage.tiers[{}].name", class);
+                check_tier_name(name).map_err(|reason| invalid(&field, reason))?;
+                if tiers[..class]
+                    .iter()
+                    .any(|other| other.name.as_ref() == Some(name))
+                {
+                    return Err(invalid(field, format!("duplicate tier name {}", name)));
+                }
+            }
+        }
+        if self.storage.queue_depth_factor == 0 {
+            return Err(invalid(
+                "storage.queue_depth_factor",
+                "the queue depth must not be zero",
+            ));
+        }
+        if self.storage.thread_pool_size == Some(0) {
+            return Err(invalid(
+                "storage.thread_pool_size",
+                "the thread pool needs at least one thread",
+            ));
+        }
+
+        if self.alloc_strategy.len() > NUM_STORAGE_CLASSES {
+            return Err(invalid(
+                "alloc_strategy",
+                format!("at most {} classes have a strategy", NUM_STORAGE_CLASSES),
+            ));
+        }
+        for (class, strategy) in self.alloc_strategy.iter().enumerate() {
+            if strategy.len() > NUM_STORAGE_CLASSES {
+                return Err(invalid(
+                    format!("alloc_strategy[{}]", class),
+                    "each class can only be tried once",
+                ));
+            }
+            for (idx, &target) in strategy.iter().enumerate() {
+                check_class(format!("alloc_strategy[{}][{}]", class, idx), target)?;
+            }
+        }
+        if self.allocator_types.len() > NUM_STORAGE_CLASSES {
+            return Err(invalid(
+                "allocator_types",
+                format!("at most {} classes have an allocator", NUM_STORAGE_CLASSES),
+            ));
+        }
+        check_class("default_storage_class", self.default_storage_class)?;
+        let placements = std::iter::once(("value_placement".to_string(), &self.value_placement))
+            .chain(
+                self.dataset_value_placement
+                    .iter()
+                    .map(|(name, placement)| {
+                        (format!("dataset_value_placement.{}", name), placement)
+                    }),
+            );
+        for (field, placement) in placements {
+            for (idx, rule) in placement.rules.iter().enumerate() {
+                check_class(
+                    format!("{}.rules[{}].storage_class", field, idx),
+                    rule.storage_class,
+                )?;
+            }
+        }
+
+        if self.cache_size == 0 {
+            return Err(invalid("cache_size", "the cache must not be empty"));
+        }
+        if let Some(l2_cache) = &self.l2_cache {
+            check_class("l2_cache.storage_class", l2_cache.storage_class)?;
+            if l2_cache.size == 0 {
+                return Err(invalid("l2_cache.size", "the cache must not be empty"));
+            }
+        }
+        if let Some(delta_encoding) = &self.delta_encoding {
+            let share = delta_encoding.max_changed_share;
+            if !(share > 0.0 && share <= 1.0) {
+                return Err(invalid(
+                    "delta_encoding.max_changed_share",
+                    "the share has to be above 0 and at most 1",
+                ));
+            }
+            if delta_encoding.max_deltas == 0 {
+                return Err(invalid(
+                    "delta_encoding.max_deltas",
+                    "at least one delta has to be allowed",
+                ));
+            }
+        }
+        check_interval("sync_interval_ms", self.sync_interval_ms)?;
+        check_interval(
+            "defragmentation.interval_ms",
+            self.defragmentation.interval_ms,
+        )?;
+        check_interval("compaction.interval_ms", self.compaction.interval_ms)?;
+        if self.compaction.max_flushes == 0 {
+            return Err(invalid(
+                "compaction.max_flushes",
+                "at least one flush has to be allowed",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.defragmentation.max_occupancy) {
+            return Err(invalid(
+                "defragmentation.max_occupancy",
+                "the share has to be between 0 and 1",
+            ));
+        }
+        if !(0.0..1.0).contains(&self.headroom) {
+            return Err(invalid(
+                "headroom",
+                "the share has to be at least 0 and below 1",
+            ));
+        }
+        if let Some(class) = self.root_tree_storage_preference.preferred_class() {
+            if tiers
+                .get(class as usize)
+                .map_or(true, |tier| tier.top_level_vdevs.is_empty())
+            {
+                return Err(invalid(
+                    "root_tree_storage_preference",
+                    "the storage class is not configured",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the settings which differ from `other`, e.g. to log what
+    /// changed between two runs of a database.
+    ///
+    /// Settings are compared by their serialized values.  Nested settings and
+    /// the elements of lists are compared individually, so that a changed
+    /// tier is reported as `storage.tiers[1].name` and not as a change of all
+    /// tiers.  `old` values are those of `self`.
+    pub fn diff(&self, other: &Self) -> Vec<ConfigurationChange> {
+        let old = serde_json::to_value(self).expect("configurations are serializable");
+        let new = serde_json::to_value(other).expect("configurations are serializable");
+        let mut changes = Vec::new();
+        diff_values(String::new(), Some(&old), Some(&new), &mut changes);
+        changes
+    }
+}
+
+fn diff_values(
+    field: String,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    changes: &mut Vec<ConfigurationChange>,
+) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let child = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", field, key)
+                };
+                diff_values(child, old.get(key), new.get(key), changes);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for idx in 0..old.len().max(new.len()) {
+                diff_values(
+                    format!("{}[{}]", field, idx),
+                    old.get(idx),
+                    new.get(idx),
+                    changes,
+                );
+            }
+        }
+        (old, new) if old != new => changes.push(ConfigurationChange {
+            field,
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// Settings which can be changed while a database is running, see
+/// [Database::update_config].  Settings which are `None` are kept.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfiguration {
+    /// The new [DatabaseConfiguration::cache_size].
+    pub cache_size: Option<usize>,
+    /// The new [DatabaseConfiguration::sync_interval_ms], `Some(None)` pauses
+    /// the periodic sync.
+    pub sync_interval_ms: Option<Option<u64>>,
+    /// The new configuration of the running migration policy, which has to be
+    /// the same kind of policy.
+    pub migration_policy: Option<MigrationPolicies>,
+    /// New limits of the request rate and bandwidth by storage class, see
+    /// [TierConfiguration::qos](crate::storage_pool::TierConfiguration::qos).
+    pub qos: BTreeMap<u8, QosConfiguration>,
+}
+
+impl Database {
+    /// Applies the given settings without reopening the database, which would
+    /// drop the cache and interrupt requests.
+    ///
+    /// The resulting configuration is validated before any setting is applied,
+    /// see [DatabaseConfiguration::validate].  A smaller cache is shrunk right
+    /// away by evicting nodes, modified ones are written back first.  The sync
+    /// interval can only be changed if the periodic sync has been started by
+    /// [Database::build_threaded], and the migration policy only while one is
+    /// running.  The policy adopts the new configuration with its next round.
+    ///
+    /// Applied settings are part of the configuration written by
+    /// [Database::write_config_json].
+    pub fn update_config(&mut self, update: RuntimeConfiguration) -> Result<()> {
+        let mut config = self.builder.clone();
+        if let Some(cache_size) = update.cache_size {
+            config.cache_size = cache_size;
+        }
+        if let Some(interval_ms) = update.sync_interval_ms {
+            if self.sync_timer.is_none() {
+                return Err(invalid(
+                    "sync_interval_ms",
+                    "the database has not been built with a periodic sync",
+                ));
+            }
+            config.sync_interval_ms = interval_ms;
+        }
+        if let Some(policy) = &update.migration_policy {
+            if self.db_tx.is_none() {
+                return Err(invalid(
+                    "migration_policy",
+                    "no migration policy is running",
+                ));
+            }
+            if let Some(current) = &config.migration_policy {
+                if mem::discriminant(current) != mem::discriminant(policy) {
+                    return Err(invalid(
+                        "migration_policy",
+                        "the kind of policy cannot be changed at runtime",
+                    ));
+                }
+            }
+            config.migration_policy = Some(policy.clone());
+        }
+        for (&class, qos) in update.qos.iter() {
+            let field = format!("storage.tiers[{}].qos", class);
+            let tier = config
+                .storage
+                .tiers
+                .get_mut(class as usize)
+                .ok_or_else(|| invalid(field, "the storage class is not configured"))?;
+            tier.qos = *qos;
+        }
+        config.validate()?;
+
+        let dmu = self.root_tree.dmu();
+        if let Some(cache_size) = update.cache_size {
+            dmu.cache().write().set_capacity(cache_size);
+            loop {
+                let size = dmu.cache().read().size();
+                if size <= cache_size {
+                    break;
+                }
+                Dml::evict(dmu.as_ref())?;
+                // The remaining nodes are in use and evicted once released.
+                if dmu.cache().read().size() >= size {
+                    break;
+                }
+            }
+        }
+        if let (Some(interval_ms), Some(timer)) = (update.sync_interval_ms, &self.sync_timer) {
+            timer.set_interval(interval_ms);
+        }
+        for (&class, qos) in update.qos.iter() {
+            dmu.pool().set_qos(class, qos);
+        }
+        if let (Some(policy), Some(tx)) = (update.migration_policy, &self.db_tx) {
+            let _ = tx.send(DatabaseMsg::Reconfigure(policy));
+        }
+        self.builder = config;
+        Ok(())
+    }
+}
@@ -1,21 +1,46 @@
-use super::root_tree_msg::dataset;
+use super::merkle::{self, RootHash};
+use super::pinning::PinnedRanges;
+use super::root_tree_msg::{dataset, dataset_dictionary};
+use super::transaction::KeyVersions;
+use super::value_placement::ValuePlacement;
 use super::{
-    errors::*, fetch_ds_data, Database, DatasetData, DatasetId, DatasetTree, Generation,
-    MessageTree, StorageInfo, RootDmu,
+    errors::*, fetch_ds_data, sync_ds_tree, Database, DatasetData, DatasetId, DatasetTree, Flusher,
+    Generation, MessageTree, ObjectPointer, OpenDatasets, RootDmu, RootTree, StorageInfo,
+    ValueChecksum,
 };
 use crate::{
+    checksum::{Builder, Checksum as _, State},
+    clock,
+    compression::Zstd,
     cow_bytes::{CowBytes, SlicedCowBytes},
-    data_management::Dml,
-    migration::DatabaseMsg,
-    tree::{self, DefaultMessageAction, MessageAction, PivotKey, Tree, TreeLayer},
+    data_management::{Dml, DmlWithStorageHints, Error as DmlError},
+    migration::{
+        DatabaseMsg, MigrationFailure, MigrationReport, MigrationReports, MigrationSource,
+        MigrationSubject, PlannedMigration,
+    },
+    tree::{
+        self, DefaultMessageAction, DumpDetail, FlushSize, MessageAction, OperationStatistics,
+        PivotKey, Tree, TreeDump, TreeLayer,
+    },
     StoragePreference,
 };
 
 #[cfg(feature = "internal-api")]
 use crate::tree::NodeInfo;
 
-use parking_lot::RwLock;
-use std::{borrow::Borrow, collections::HashSet, ops::RangeBounds, sync::Arc};
+use crossbeam_channel::Sender;
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+use rand::Rng;
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Weak},
+};
+
+// The number of entries a compression dictionary is trained on, see
+// [Dataset::train_dictionary].
+const DICTIONARY_SAMPLES: usize = 16 * 1024;
 
 /// The internal data set type.  This is the non-user facing variant which is
 /// then wrapped in the [Dataset] type.
@@ -25,9 +50,89 @@ pub struct DatasetInner<Message = DefaultMessageAction> {
     name: Box<[u8]>,
     pub(super) open_snapshots: HashSet<Generation>,
     storage_preference: StoragePreference,
+    // The number of levels below the root kept in cache, see
+    // [Dataset::pin_in_cache].
+    pinned_levels: Option<u32>,
+    // The storage classes key ranges are pinned to, see [Dataset::pin_range].
+    pinned_ranges: PinnedRanges,
+    // Routes values without a storage preference by their size.
+    value_placement: ValuePlacement,
+    // The versions of keys written while transactions are open, see
+    // [Dataset::begin_transaction].
+    pub(super) versions: Mutex<KeyVersions>,
+    // Used to deregister the dataset when the last handle is dropped.
+    root_tree: RootTree<RootDmu>,
+    open_datasets: Weak<OpenDatasets>,
+    // Rebalances the nodes filled by insertions, if configured.
+    flusher: Option<Arc<Flusher>>,
+    // Receives the reports of [Dataset::migrate_range].
+    migration_reports: Arc<MigrationReports>,
+    // Notified when the dataset is closed.
+    db_tx: Option<Sender<DatabaseMsg>>,
+    closed: bool,
+}
+
+/// Computes the checksum of `value` like [Dataset::get_with_checksum], e.g.
+/// to keep it alongside the value and pass it to [Dataset::get_verified]
+/// when reading the value again.
+pub fn value_checksum(value: &[u8]) -> ValueChecksum {
+    let mut state = ValueChecksum::builder().build();
+    state.ingest(value);
+    state.finish()
+}
+
+impl<Message> DatasetInner<Message> {
+    // Syncs the dataset and deregisters it from the database, when it is
+    // closed or its last handle is dropped.  The dataset is deregistered even
+    // if syncing fails.
+    fn teardown(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        if let Some(tx) = &self.db_tx {
+            let _ = tx
+                .send(DatabaseMsg::DatasetClose(self.id))
+                .map_err(|_| warn!("Channel Receiver has been dropped."));
+        }
+        if let Some(flusher) = &self.flusher {
+            flusher.wait_idle(self.id);
+        }
+        let open_datasets = match self.open_datasets.upgrade() {
+            Some(open_datasets) => open_datasets,
+            None => return Ok(()),
+        };
+        // Keep the lock until the root tree has been updated, so that the
+        // dataset cannot be reopened with an outdated root pointer.
+        let mut open_datasets = open_datasets.write();
+        let result = open_datasets.get(&self.id).map_or(Ok(()), |ds_tree| {
+            sync_ds_tree(&self.root_tree, self.id, ds_tree.as_ref())
+        });
+        open_datasets.remove(&self.id);
+        self.root_tree
+            .dmu()
+            .handler()
+            .last_snapshot_generation
+            .write()
+            .remove(&self.id);
+        self.root_tree.dmu().set_pinned_levels(self.id, None);
+        result
+    }
+}
+
+impl<Message> Drop for DatasetInner<Message> {
+    fn drop(&mut self) {
+        if let Err(e) = self.teardown() {
+            error!("Syncing dataset {} on drop failed: {:?}", self.id, e);
+        }
+    }
 }
 
 /// The data set type.
+///
+/// Handles can be cloned freely.  Once the last handle of a data set is
+/// dropped, the data set is synced and closed, alternatively
+/// [Database::close_dataset] can be used to observe errors on closing.
 pub struct Dataset<Message = DefaultMessageAction> {
     inner: Arc<RwLock<DatasetInner<Message>>>,
 }
@@ -49,24 +154,24 @@ impl<Message> From<DatasetInner<Message>> for Dataset<Message> {
 }
 
 impl Database {
-    fn lookup_dataset_id(&self, name: &[u8]) -> Result<DatasetId> {
+    pub(super) fn lookup_dataset_id(&self, name: &[u8]) -> Result<DatasetId> {
         let key = dataset::name_to_id(name);
         let data = self.root_tree.get(key)?.ok_or(Error::DoesNotExist)?;
         Ok(DatasetId::unpack(&data))
     }
 
     /// A convenience instantiation of [Database::open_custom_dataset] with the default message set.
-    pub fn open_dataset(&mut self, name: &[u8]) -> Result<Dataset> {
+    pub fn open_dataset(&self, name: &[u8]) -> Result<Dataset> {
         self.open_custom_dataset::<DefaultMessageAction>(name, StoragePreference::NONE)
     }
 
     /// A convenience instantiation of [Database::create_custom_dataset] with the default message set.
-    pub fn create_dataset(&mut self, name: &[u8]) -> Result<()> {
+    pub fn create_dataset(&self, name: &[u8]) -> Result<()> {
         self.create_custom_dataset::<DefaultMessageAction>(name, StoragePreference::NONE)
     }
 
     /// A convenience instantiation of [Database::open_or_create_custom_dataset] with the default message set.
-    pub fn open_or_create_dataset(&mut self, name: &[u8]) -> Result<Dataset> {
+    pub fn open_or_create_dataset(&self, name: &[u8]) -> Result<Dataset> {
         self.open_or_create_custom_dataset::<DefaultMessageAction>(name, StoragePreference::NONE)
     }
 
@@ -74,40 +179,59 @@ impl Database {
     ///
     /// Fails if the data set does not exist.
     pub fn open_custom_dataset<M: MessageAction + Default + 'static>(
-        &mut self,
+        &self,
         name: &[u8],
+        storage_preference: StoragePreference,
+    ) -> Result<Dataset<M>> {
+        self.open_custom_dataset_with(name, M::default(), storage_preference)
+    }
+
+    /// Opens a data set identified by the given name, using the given message
+    /// action instance.
+    ///
+    /// Fails if the data set does not exist.
+    pub fn open_custom_dataset_with<M: MessageAction + 'static>(
+        &self,
+        name: &[u8],
+        msg_action: M,
         _storage_preference: StoragePreference,
     ) -> Result<Dataset<M>> {
         let id = self.lookup_dataset_id(name)?;
-        self.open_dataset_with_id_and_name(id, name)
+        self.open_dataset_with_id_and_name(id, name, msg_action)
     }
 
     /// Internal function to open a dataset based on it's internal id, saves knowing the actual name.
     /// THE NAME IS NOT KNOWN IN THIS CASE AND THE NAME BOX EMPTY.
     pub(crate) fn open_dataset_with_id<M: MessageAction + Default + 'static>(
-        &mut self,
+        &self,
         id: DatasetId,
     ) -> Result<Dataset<M>> {
-        self.open_dataset_with_id_and_name(id, &[])
+        self.open_dataset_with_id_and_name(id, &[], M::default())
     }
 
-    fn open_dataset_with_id_and_name<M: MessageAction + Default + 'static>(
-        &mut self,
+    fn open_dataset_with_id_and_name<M: MessageAction + 'static>(
+        &self,
         id: DatasetId,
         name: &[u8],
+        msg_action: M,
     ) -> Result<Dataset<M>> {
         let ds_data = fetch_ds_data(&self.root_tree, id)?;
-        if self.open_datasets.contains_key(&id) {
+        let pinned_ranges = PinnedRanges::fetch(&self.root_tree, id)?;
+        // Hold the lock until the dataset is registered to avoid opening the
+        // same dataset twice from concurrent callers.
+        let mut open_datasets = self.open_datasets.write();
+        if open_datasets.contains_key(&id) {
             return Err(Error::InUse);
         }
-        let storage_preference = StoragePreference::NONE;
-        let ds_tree = Tree::open(
+        let storage_preference = ds_data.storage_preference;
+        let mut ds_tree = Tree::open(
             id,
             ds_data.ptr,
-            M::default(),
+            msg_action,
             Arc::clone(self.root_tree.dmu()),
             storage_preference,
         );
+        ds_tree.set_flush_size(self.dataset_flush_size(name));
 
         if let Some(ss_id) = ds_data.previous_snapshot {
             self.root_tree
@@ -118,7 +242,8 @@ impl Database {
                 .insert(id, ss_id);
         }
         let erased_tree = Box::new(ds_tree.clone());
-        self.open_datasets.insert(id, erased_tree);
+        open_datasets.insert(id, erased_tree);
+        drop(open_datasets);
 
         let ds: Dataset<M> = DatasetInner {
             tree: ds_tree,
@@ -126,6 +251,16 @@ impl Database {
             name: Box::from(name),
             open_snapshots: Default::default(),
             storage_preference,
+            pinned_levels: None,
+            pinned_ranges,
+            value_placement: self.dataset_value_placement(name),
+            versions: Default::default(),
+            root_tree: self.root_tree.clone(),
+            open_datasets: Arc::downgrade(&self.open_datasets),
+            flusher: self.flusher.clone(),
+            migration_reports: Arc::clone(&self.migration_reports),
+            db_tx: self.db_tx.clone(),
+            closed: false,
         }
         .into();
 
@@ -140,18 +275,23 @@ impl Database {
 
     /// Creates a new data set identified by the given name.
     ///
-    /// Fails if a data set with the same name exists already.
+    /// Entries inserted without a storage preference are stored according to
+    /// `storage_preference`, which is kept persistently and can be changed
+    /// with [Dataset::set_default_storage_preference].  Fails if a data set
+    /// with the same name exists already.
     pub fn create_custom_dataset<M: MessageAction + Clone>(
-        &mut self,
+        &self,
         name: &[u8],
         storage_preference: StoragePreference,
     ) -> Result<()> {
+        let _creation = self.dataset_creation.lock();
         match self.lookup_dataset_id(name) {
             Ok(_) => return Err(Error::AlreadyExists),
             Err(Error::DoesNotExist) => {}
             Err(e) => return Err(e),
         };
         let ds_id = self.allocate_ds_id()?;
+        self.apply_dataset_compression(ds_id, name);
         let tree = DatasetTree::empty_tree(
             ds_id,
             DefaultMessageAction,
@@ -164,6 +304,7 @@ impl Database {
         let data = DatasetData {
             ptr,
             previous_snapshot: None,
+            storage_preference,
         }
         .pack()?;
         self.root_tree.insert(
@@ -183,20 +324,68 @@ impl Database {
 
     /// Opens a dataset, creating a new one if none exists by the given name.
     pub fn open_or_create_custom_dataset<M: MessageAction + Default + Clone + 'static>(
-        &mut self,
+        &self,
         name: &[u8],
         storage_preference: StoragePreference,
+    ) -> Result<Dataset<M>> {
+        self.open_or_create_custom_dataset_with(name, M::default(), storage_preference)
+    }
+
+    /// Opens a dataset using the given message action instance, creating a
+    /// new one if none exists by the given name.
+    pub fn open_or_create_custom_dataset_with<M: MessageAction + Clone + 'static>(
+        &self,
+        name: &[u8],
+        msg_action: M,
+        storage_preference: StoragePreference,
     ) -> Result<Dataset<M>> {
         match self.lookup_dataset_id(name) {
-            Ok(_) => self.open_custom_dataset(name, storage_preference),
-            Err(Error::DoesNotExist) => self
-                .create_custom_dataset::<M>(name, storage_preference)
-                .and_then(|()| self.open_custom_dataset(name, storage_preference)),
+            Ok(_) => self.open_custom_dataset_with(name, msg_action, storage_preference),
+            Err(Error::DoesNotExist) => {
+                match self.create_custom_dataset::<M>(name, storage_preference) {
+                    // Another caller might have created the dataset in the meantime.
+                    Ok(()) | Err(Error::AlreadyExists) => {
+                        self.open_custom_dataset_with(name, msg_action, storage_preference)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
             Err(e) => Err(e),
         }
     }
 
-    fn allocate_ds_id(&mut self) -> Result<DatasetId> {
+    // Compresses the dataset with the compression configured for its name,
+    // if any.
+    pub(super) fn apply_dataset_compression(&self, id: DatasetId, name: &[u8]) {
+        let compression = std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.builder.dataset_compression.get(name));
+        if let Some(compression) = compression {
+            self.root_tree
+                .dmu()
+                .set_dataset_compression(id, compression.to_builder());
+        }
+    }
+
+    // Returns the value placement configured for the dataset `name`.
+    fn dataset_value_placement(&self, name: &[u8]) -> ValuePlacement {
+        std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.builder.dataset_value_placement.get(name))
+            .unwrap_or(&self.builder.value_placement)
+            .clone()
+    }
+
+    // Returns the flush size configured for the dataset `name`.
+    fn dataset_flush_size(&self, name: &[u8]) -> FlushSize {
+        std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.builder.dataset_flush_size.get(name))
+            .copied()
+            .unwrap_or(self.builder.flush_size)
+    }
+
+    fn allocate_ds_id(&self) -> Result<DatasetId> {
         let key = &dataset::id_counter() as &[_];
         let last_ds_id = self
             .root_tree
@@ -226,14 +415,9 @@ impl Database {
 
     /// Closes the given data set.
     pub fn close_dataset<Message: MessageAction + 'static>(
-        &mut self,
+        &self,
         ds: Dataset<Message>,
     ) -> Result<()> {
-        if let Some(tx) = &self.db_tx {
-            let _ = tx
-                .send(DatabaseMsg::DatasetClose(ds.id()))
-                .map_err(|_| warn!("Channel Receiver has been dropped."));
-        }
         // Check if the dataset is still opened from other positions in the stack.
         if Arc::strong_count(&ds.inner) > 1 {
             if let Some(ds) = ds.inner.try_read() {
@@ -242,19 +426,9 @@ impl Database {
             return Ok(());
         }
         // Deactivate the dataset for further modifications
-        let ds = ds.inner.write();
+        let mut inner = ds.inner.write();
         log::trace!("close_dataset: Enter");
-        self.sync_ds(ds.id, &ds.tree)?;
-        log::trace!("synced dataset");
-        self.open_datasets.remove(&ds.id);
-        self.root_tree
-            .dmu()
-            .handler()
-            .last_snapshot_generation
-            .write()
-            .remove(&ds.id);
-        drop(ds);
-        Ok(())
+        inner.teardown()
     }
 }
 
@@ -276,12 +450,49 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
         msg: SlicedCowBytes,
         storage_preference: StoragePreference,
     ) -> Result<()> {
-        Ok(self
-            .tree
-            .insert(key, msg, storage_preference.or(self.storage_preference))?)
+        if self.tree.dmu().handler().follower {
+            return Err(Error::ReadOnlyFollower);
+        }
+        // Pinned keys are always written to their storage class.
+        let storage_preference = self
+            .pinned_ranges
+            .preference(key.borrow())
+            .unwrap_or(storage_preference)
+            .or(self.storage_preference);
+        let key: CowBytes = key.into();
+        let flusher = match &self.flusher {
+            None => {
+                self.tree.insert(key.clone(), msg, storage_preference)?;
+                None
+            }
+            Some(flusher) => self
+                .tree
+                .insert_deferred(key.clone(), msg, storage_preference)?
+                .then_some(flusher),
+        };
+        // Recorded after the insertion, so that transactions which have read
+        // the previous value cannot miss the modification.
+        self.versions.lock().record_write(&key);
+        if let Some(flusher) = flusher {
+            let tree = self.tree.clone();
+            let (id, task_key) = (self.id, key.clone());
+            let task = Box::new(move || {
+                if let Err(e) = tree.rebalance_path(&task_key) {
+                    error!("Rebalancing dataset {} failed: {:?}", id, e);
+                }
+            });
+            if flusher.submit(self.id, task).is_err() {
+                // The flusher is busy, so do it ourselves.
+                self.tree.rebalance_path(&key)?;
+            }
+        }
+        Ok(())
     }
 
     /// Returns the value for the given key if existing.
+    ///
+    /// The value shares the buffer of the cached node it is read from, see
+    /// [SlicedCowBytes].
     pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
         Ok(self.tree.get(key)?)
     }
@@ -325,6 +536,121 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
         Ok(Box::new(self.tree.range(range)?.map(|r| Ok(r?))))
     }
 
+    /// Returns the operations performed on this dataset since it has been
+    /// opened, see [OperationStatistics].
+    pub fn stats(&self) -> OperationStatistics {
+        self.tree.stats().as_stats()
+    }
+
+    /// Returns the Merkle hash of the entries of this dataset, see
+    /// [RootHash].  It does not depend on the shape of the tree, so that the
+    /// contents of two replicas, e.g. a dataset and its copy restored from a
+    /// backup, can be compared by their hashes alone.
+    ///
+    /// The hash is computed by reading all entries.
+    pub fn root_hash(&self) -> Result<RootHash> {
+        merkle::root_hash(self.range::<_, &[u8]>(..)?)
+    }
+
+    /// Returns the value for the given key if existing together with its
+    /// checksum, see [value_checksum].
+    pub fn get_with_checksum<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<(SlicedCowBytes, ValueChecksum)>> {
+        Ok(self.get(key)?.map(|value| {
+            let checksum = value_checksum(&value);
+            (value, checksum)
+        }))
+    }
+
+    /// Returns the value for the given key if existing, fails with
+    /// [Error::ChecksumMismatch] if it does not match `checksum`.
+    pub fn get_verified<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+        checksum: &ValueChecksum,
+    ) -> Result<Option<SlicedCowBytes>> {
+        match self.get(key)? {
+            Some(value) if value_checksum(&value) != *checksum => Err(Error::ChecksumMismatch),
+            value => Ok(value),
+        }
+    }
+
+    /// Reads all nodes which may hold keys of the given range from disk,
+    /// bypassing the cache, and verifies their checksums.  Returns the number
+    /// of verified nodes, nodes which have been modified since the last sync
+    /// are not on disk yet and skipped.
+    ///
+    /// Fails on the first node which cannot be read, the error carries its
+    /// disk offset.  The dataset must not be modified concurrently.
+    pub fn verify_range<R, K>(&self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        // Excluded bounds are verified as well, the nodes are the same.
+        let min_key: &[u8] = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => key.borrow(),
+            Bound::Unbounded => &[],
+        };
+        let max_key: Option<&[u8]> = match range.end_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key.borrow()),
+            Bound::Unbounded => None,
+        };
+        let pointers = self.tree.pointers_in_range(min_key, max_key)?;
+        let spl = self.tree.dmu().spl();
+        let handler = self.tree.dmu().handler();
+        for ptr in pointers.iter() {
+            // Deltas are only readable along with their base.
+            let base = handler
+                .delta_base(ptr.offset(), ptr.info())
+                .map(|delta| delta.base);
+            for ptr in std::iter::once(ptr).chain(base.as_ref()) {
+                ptr.read_from(spl).map_err(|source| DmlError::ReadFailed {
+                    dataset: ptr.info(),
+                    offset: ptr.offset(),
+                    size: ptr.size(),
+                    source,
+                })?;
+            }
+        }
+        Ok(pointers.len())
+    }
+
+    /// Trains a compression dictionary of at most `max_size` bytes on a
+    /// sample of the entries of this data set, which is used for its nodes
+    /// written from now on.  Returns the id of the new dictionary.
+    pub fn train_dictionary(&self, max_size: usize) -> Result<u32> {
+        if max_size > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        // Reservoir sampling picks the entries uniformly in a single pass.
+        let mut rng = rand::thread_rng();
+        let mut samples = Vec::new();
+        for (idx, entry) in self.range::<_, &[u8]>(..)?.enumerate() {
+            let (key, value) = entry?;
+            let sample = [&key[..], &value[..]].concat();
+            if idx < DICTIONARY_SAMPLES {
+                samples.push(sample);
+            } else if let Some(slot) = samples.get_mut(rng.gen_range(0..=idx)) {
+                *slot = sample;
+            }
+        }
+        let dictionary = Zstd::train_dictionary(&samples, max_size)
+            .map_err(|e| Error::DictionaryTrainingFailed(e.to_string()))?;
+
+        let dmu = self.tree.dmu();
+        let dictionary_id = dmu.newest_dictionary(self.id).map_or(0, |id| id + 1);
+        self.root_tree.insert(
+            CowBytes::from(&dataset_dictionary::key(self.id, dictionary_id)[..]),
+            DefaultMessageAction::insert_msg(&dictionary),
+            StoragePreference::NONE,
+        )?;
+        dmu.add_dictionary(self.id, dictionary_id, dictionary.into());
+        Ok(dictionary_id)
+    }
+
     /// Returns the name of the data set.
     pub fn name(&self) -> &[u8] {
         &self.name
@@ -335,6 +661,73 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
     pub fn tree_dump(&self) -> Result<NodeInfo> {
         Ok(self.tree.tree_dump()?)
     }
+
+    /// Returns the shape of the tree of this data set, see [TreeDump].
+    pub fn dump(&self, detail: DumpDetail) -> Result<TreeDump> {
+        Ok(self.tree.dump(detail)?)
+    }
+
+    /// Keeps the nodes of the top `levels` levels of the tree in the cache
+    /// once they have been fetched, so that they are never evicted.  A single
+    /// level pins the root node only.
+    ///
+    /// The levels are counted from the root as of this call and
+    /// [Dataset::prewarm], should the tree grow in between, one more level is
+    /// kept.  Pinned nodes count towards the cache size, the cache grows
+    /// beyond its capacity if they do not fit.
+    pub fn pin_in_cache(&mut self, levels: u32) -> Result<()> {
+        self.pinned_levels = Some(levels);
+        self.update_pinned_levels()
+    }
+
+    /// Allows all nodes of the tree to be evicted from the cache again.
+    pub fn unpin_from_cache(&mut self) {
+        self.pinned_levels = None;
+        self.tree.dmu().set_pinned_levels(self.id, None);
+    }
+
+    /// Fetches the nodes pinned by [Dataset::pin_in_cache] into the cache,
+    /// or only the root node if no levels are pinned.  Returns the number of
+    /// fetched nodes.
+    pub fn prewarm(&mut self) -> Result<usize> {
+        self.update_pinned_levels()?;
+        let depth = self.tree.depth()?;
+        let min_level = depth.saturating_sub(self.pinned_levels.unwrap_or(1).max(1));
+        Ok(self.tree.prewarm(min_level)?)
+    }
+
+    fn update_pinned_levels(&self) -> Result<()> {
+        if let Some(levels) = self.pinned_levels {
+            let depth = self.tree.depth()?;
+            self.tree
+                .dmu()
+                .set_pinned_levels(self.id, Some(depth.saturating_sub(levels)));
+        }
+        Ok(())
+    }
+
+    /// Sets the storage preference of entries inserted without one, which is
+    /// stored persistently with the next sync.  Entries written before keep
+    /// their storage class.
+    pub fn set_default_storage_preference(&mut self, pref: StoragePreference) -> Result<()> {
+        use crate::storage_pool::StoragePoolLayer;
+        if pref != StoragePreference::NONE && self.tree.dmu().spl().disk_count(pref.as_u8()) == 0 {
+            return Err(Error::EmptyStorageClass(pref.as_u8()));
+        }
+        self.root_tree.insert(
+            &dataset::data_key(self.id) as &[_],
+            DatasetData::<ObjectPointer>::update_storage_preference(pref),
+            StoragePreference::NONE,
+        )?;
+        self.storage_preference = pref;
+        self.tree.set_storage_preference(pref);
+        Ok(())
+    }
+
+    /// Returns the storage preference of entries inserted without one.
+    pub fn default_storage_preference(&self) -> StoragePreference {
+        self.storage_preference
+    }
 }
 
 // Member access on internal type
@@ -343,6 +736,11 @@ impl<Message> Dataset<Message> {
         self.inner.read().id
     }
 
+    /// Returns the generation the writes to this dataset currently belong to.
+    pub(crate) fn current_generation(&self) -> Generation {
+        self.inner.read().tree.dmu().handler().current_generation()
+    }
+
     pub(super) fn call_open_snapshots<F, R>(&self, call: F) -> R
     where
         F: FnOnce(&HashSet<Generation>) -> R,
@@ -357,12 +755,39 @@ impl<Message> Dataset<Message> {
         call(&mut self.inner.write().open_snapshots)
     }
 
+    pub(super) fn call_versions<F, R>(&self, call: F) -> R
+    where
+        F: FnOnce(&mut KeyVersions) -> R,
+    {
+        call(&mut self.inner.read().versions.lock())
+    }
+
+    /// Blocks all further operations on this dataset until the guard is dropped.
+    pub(super) fn lock_exclusive(&self) -> RwLockWriteGuard<DatasetInner<Message>> {
+        self.inner.write()
+    }
+
+    pub(super) fn same_dataset(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
     pub(crate) fn call_tree<F, R>(&self, call: F) -> R
     where
         F: FnOnce(&MessageTree<RootDmu, Message>) -> R,
     {
         call(&self.inner.read().tree)
     }
+
+    /// Runs `call` while no sync can start, so that all writes it makes
+    /// become durable together, like the writes of a transaction.
+    pub(crate) fn call_uninterrupted_by_sync<F, R>(&self, call: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let dmu = Arc::clone(self.inner.read().tree.dmu());
+        let _commit = dmu.handler().commit_lock.read();
+        call()
+    }
 }
 
 // Mirroring of the [DatasetInner] API
@@ -390,6 +815,9 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
     }
 
     /// Returns the value for the given key if existing.
+    ///
+    /// The value shares the buffer of the cached node it is read from, see
+    /// [SlicedCowBytes].
     pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
         self.inner.read().get(key)
     }
@@ -406,6 +834,64 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
         self.inner.read().range(range)
     }
 
+    /// Returns the operations performed on this dataset since it has been
+    /// opened, see [OperationStatistics].
+    pub fn stats(&self) -> OperationStatistics {
+        self.inner.read().stats()
+    }
+
+    /// Returns the Merkle hash of the entries of this dataset, see
+    /// [RootHash].  Replicas with equal contents have equal hashes.
+    pub fn root_hash(&self) -> Result<RootHash> {
+        self.inner.read().root_hash()
+    }
+
+    /// Returns the value for the given key if existing together with its
+    /// checksum, see [value_checksum].
+    pub fn get_with_checksum<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<(SlicedCowBytes, ValueChecksum)>> {
+        self.inner.read().get_with_checksum(key)
+    }
+
+    /// Returns the value for the given key if existing, fails with
+    /// [Error::ChecksumMismatch] if it does not match `checksum`.
+    pub fn get_verified<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+        checksum: &ValueChecksum,
+    ) -> Result<Option<SlicedCowBytes>> {
+        self.inner.read().get_verified(key, checksum)
+    }
+
+    /// Reads all nodes which may hold keys of the given range from disk,
+    /// bypassing the cache, and verifies their checksums, so that corruption
+    /// is detected before the data is needed.  Returns the number of
+    /// verified nodes, nodes modified since the last sync are skipped.
+    pub fn verify_range<R, K>(&self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        self.inner.read().verify_range(range)
+    }
+
+    /// Trains a zstd dictionary of at most `max_size` bytes on a sample of
+    /// the entries of this data set.  Nodes of the data set written from now
+    /// on are compressed with it if the data set is compressed with
+    /// [Zstd](crate::compression::Zstd), which pays off for many small,
+    /// similar values like JSON documents or log records.  Returns the id of
+    /// the new dictionary.
+    ///
+    /// The dictionary is stored persistently, as are the ones trained before,
+    /// since nodes compressed with them may still be in use.  Training fails
+    /// with [Error::DictionaryTrainingFailed] if the data set holds too few
+    /// entries.  The data set is locked while the dictionary is trained.
+    pub fn train_dictionary(&self, max_size: usize) -> Result<u32> {
+        self.inner.write().train_dictionary(max_size)
+    }
+
     /// Returns the name of the data set.
     pub fn name(&self) -> Box<[u8]> {
         self.inner.read().name.clone()
@@ -416,6 +902,47 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
     pub fn tree_dump(&self) -> Result<NodeInfo> {
         self.inner.read().tree_dump()
     }
+
+    /// Returns the shape of the tree of this data set in a stable, versioned
+    /// format, e.g. for visualization tools.  [DumpDetail::Compact] omits
+    /// all keys.
+    ///
+    /// All nodes of the tree are read, which is expensive for large trees.
+    pub fn dump(&self, detail: DumpDetail) -> Result<TreeDump> {
+        self.inner.read().dump(detail)
+    }
+
+    /// Keeps the nodes of the top `levels` levels of the tree in the cache
+    /// once they have been fetched, so that they are never evicted.
+    pub fn pin_in_cache(&self, levels: u32) -> Result<()> {
+        self.inner.write().pin_in_cache(levels)
+    }
+
+    /// Allows all nodes of the tree to be evicted from the cache again.
+    pub fn unpin_from_cache(&self) {
+        self.inner.write().unpin_from_cache()
+    }
+
+    /// Fetches the nodes pinned by [Dataset::pin_in_cache] into the cache,
+    /// or only the root node if no levels are pinned.  Returns the number of
+    /// fetched nodes.
+    pub fn prewarm(&self) -> Result<usize> {
+        self.inner.write().prewarm()
+    }
+
+    /// Sets the storage preference of entries inserted without one, which is
+    /// stored persistently and restored when the data set is opened again.
+    ///
+    /// Fails with [Error::EmptyStorageClass] if `pref` names a storage class
+    /// without vdevs.
+    pub fn set_default_storage_preference(&self, pref: StoragePreference) -> Result<()> {
+        self.inner.write().set_default_storage_preference(pref)
+    }
+
+    /// Returns the storage preference of entries inserted without one.
+    pub fn default_storage_preference(&self) -> StoragePreference {
+        self.inner.read().default_storage_preference()
+    }
 }
 
 impl DatasetInner<DefaultMessageAction> {
@@ -431,6 +958,8 @@ impl DatasetInner<DefaultMessageAction> {
         if data.len() > tree::MAX_MESSAGE_SIZE {
             return Err(Error::MessageTooLarge);
         }
+        self.check_quota()?;
+        let storage_preference = storage_preference.or(self.value_placement.preference(data.len()));
         self.insert_msg_with_pref(
             key,
             DefaultMessageAction::insert_msg(data),
@@ -445,6 +974,16 @@ impl DatasetInner<DefaultMessageAction> {
         self.insert_with_pref(key, data, StoragePreference::NONE)
     }
 
+    // Fails if the quota group of the dataset has reached its limit.
+    fn check_quota(&self) -> Result<()> {
+        match self.tree.dmu().handler().exceeded_quota(self.id) {
+            Some(group) => Err(Error::QuotaExceeded(
+                String::from_utf8_lossy(&group).into_owned(),
+            )),
+            None => Ok(()),
+        }
+    }
+
     /// Upserts the value for the given key at the given offset.
     ///
     /// Note that the value will be zeropadded as needed.
@@ -458,9 +997,14 @@ impl DatasetInner<DefaultMessageAction> {
         if offset as usize + data.len() > tree::MAX_MESSAGE_SIZE {
             return Err(Error::MessageTooLarge);
         }
+        self.check_quota()?;
         // TODO: In case of overfilling the underlying storage we should notify in _any_ case that the writing is not successfull, for this
         // we need to know wether the space to write out has been expanded. For this we need further information which we ideally do not want
         // to read out from the disk here.
+        // The value has at least the size of the upserted range.
+        let storage_preference = storage_preference.or(self
+            .value_placement
+            .preference(offset as usize + data.len()));
         self.insert_msg_with_pref(
             key,
             DefaultMessageAction::upsert_msg(offset, data),
@@ -491,15 +1035,75 @@ impl DatasetInner<DefaultMessageAction> {
         key: K,
         pref: StoragePreference,
     ) -> Result<Option<()>> {
+        Ok(self.migrate_entry(key, pref)?.map(|_| ()))
+    }
+
+    // Moves the entry `key` to `pref` and returns its previous storage
+    // preference if it exists.
+    fn migrate_entry<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        pref: StoragePreference,
+    ) -> Result<Option<StoragePreference>> {
         use crate::storage_pool::StoragePoolLayer;
         if self.tree.dmu().spl().disk_count(pref.as_u8()) == 0 {
             return Err(Error::MigrationNotPossible);
         }
-        Ok(self.tree.apply_with_info(key, pref)?.map(|_| ()))
+        if self.tree.dmu().handler().is_class_sealed(pref.as_u8()) {
+            return Err(Error::StorageClassSealed(pref.as_u8()));
+        }
+        match self.pinned_ranges.preference(key.borrow()) {
+            Some(pinned) if pinned != pref => Err(Error::KeyPinned(pinned.as_u8())),
+            _ => Ok(self
+                .tree
+                .apply_with_info(key, pref)?
+                .map(|info| *info.storage_preference())),
+        }
+    }
+
+    /// Pins all keys in the given range to the storage class `pref` and moves
+    /// the existing entries there.
+    ///
+    /// The rule is stored persistently and applies to entries inserted later
+    /// on as well.  Explicit migrations of pinned keys to another storage
+    /// class fail with [Error::KeyPinned], [Dataset::migrate_range] skips
+    /// them.  Automatic migration policies may still promote nodes holding
+    /// pinned entries to a faster storage class, but never demote them below
+    /// `pref`.  Where rules overlap the latest one applies.
+    pub fn pin_range<R, K>(&mut self, range: R, pref: StoragePreference) -> Result<()>
+    where
+        K: Borrow<[u8]> + Into<CowBytes>,
+        R: RangeBounds<K>,
+    {
+        use crate::storage_pool::StoragePoolLayer;
+        if pref == StoragePreference::NONE || self.tree.dmu().spl().disk_count(pref.as_u8()) == 0 {
+            return Err(Error::MigrationNotPossible);
+        }
+        if self.tree.dmu().handler().is_class_sealed(pref.as_u8()) {
+            return Err(Error::StorageClassSealed(pref.as_u8()));
+        }
+        self.pinned_ranges.pin(&range, pref);
+        self.pinned_ranges.store(&self.root_tree, self.id)?;
+        for (k, _v) in self.tree.range(range)?.flatten() {
+            self.tree.apply_with_info(k, pref)?;
+        }
+        Ok(())
+    }
+
+    /// Removes all pinning rules which lie completely within the given range.
+    /// The entries stay where they are until they are migrated.
+    pub fn unpin_range<R, K>(&mut self, range: R) -> Result<()>
+    where
+        K: Borrow<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.pinned_ranges.unpin(&range);
+        self.pinned_ranges.store(&self.root_tree, self.id)
     }
 
     /// Deletes the key-value pair if existing.
     pub fn delete<K: Borrow<[u8]> + Into<CowBytes>>(&self, key: K) -> Result<()> {
+        self.tree.stats().record_delete();
         self.insert_msg_with_pref(
             key,
             DefaultMessageAction::delete_msg(),
@@ -515,6 +1119,17 @@ impl DatasetInner<DefaultMessageAction> {
         }
     }
 
+    // The number of storage classes up to the slowest one with disks, which
+    // bounds the classes data may be migrated down to.
+    pub(crate) fn configured_class_count(&self) -> u8 {
+        use crate::storage_pool::StoragePoolLayer;
+        let spl = self.tree.dmu().spl();
+        (0..spl.storage_class_count())
+            .rev()
+            .find(|&class| spl.disk_count(class) > 0)
+            .map_or(0, |class| class + 1)
+    }
+
     /// Removes all key-value pairs in the given key range.
     pub fn range_delete<R, K>(&self, range: R) -> Result<()>
     where
@@ -536,16 +1151,60 @@ impl DatasetInner<DefaultMessageAction> {
 
     /// Migrate a complete range of keys to another storage preference.
     /// If an entry is already located on this layer no operation is performed and success is returned.
+    /// Keys pinned to another storage class are skipped.
+    ///
+    /// The outcome is reported to the subscribers of
+    /// [Database::subscribe_migrations].
     pub fn migrate_range<R, K>(&self, range: R, pref: StoragePreference) -> Result<()>
     where
         K: Borrow<[u8]> + Into<CowBytes>,
         R: RangeBounds<K>,
     {
-        for (k, _v) in self.tree.range(range)?.flatten() {
-            // abort on errors, they will likely be that one layer is full
-            self.migrate(k, pref)?;
+        let start = clock::now();
+        let reported = self.migration_reports.is_subscribed();
+        let default_class = self.tree.dmu().default_storage_class();
+        let mut report = MigrationReport {
+            source: MigrationSource::Explicit,
+            migrations: Vec::new(),
+            failures: Vec::new(),
+            duration: Default::default(),
+        };
+        let mut res = Ok(());
+        for (k, v) in self.tree.range(range)?.flatten() {
+            let subject = || MigrationSubject::Key {
+                dataset: self.id,
+                key: k.clone(),
+            };
+            match self.migrate_entry(k.clone(), pref) {
+                Ok(Some(from)) if reported => report.migrations.push(PlannedMigration {
+                    subject: subject(),
+                    from: from.or(self.storage_preference).or(default_class).as_u8(),
+                    to: pref.as_u8(),
+                    size: (k.len() + v.len()) as u64,
+                }),
+                Ok(_) => {}
+                Err(e) => {
+                    if reported {
+                        report.failures.push(MigrationFailure {
+                            subject: subject(),
+                            to: pref.as_u8(),
+                            error: e.to_string(),
+                        });
+                    }
+                    // abort on errors, they will likely be that one layer is
+                    // full
+                    if !matches!(e, Error::KeyPinned(_)) {
+                        res = Err(e);
+                        break;
+                    }
+                }
+            }
         }
-        Ok(())
+        if reported {
+            report.duration = clock::elapsed(start);
+            self.migration_reports.publish(report);
+        }
+        res
     }
 }
 
@@ -646,6 +1305,10 @@ impl Dataset<DefaultMessageAction> {
         self.inner.read().free_space_tier(pref)
     }
 
+    pub(crate) fn configured_class_count(&self) -> u8 {
+        self.inner.read().configured_class_count()
+    }
+
     /// Removes all key-value pairs in the given key range.
     pub fn range_delete<R, K>(&self, range: R) -> Result<()>
     where
@@ -657,6 +1320,10 @@ impl Dataset<DefaultMessageAction> {
 
     /// Migrate a complete range of keys to another storage preference.
     /// If an entry is already located on this layer no operation is performed and success is returned.
+    /// Keys pinned to another storage class are skipped.
+    ///
+    /// The outcome is reported to the subscribers of
+    /// [Database::subscribe_migrations].
     pub fn migrate_range<R, K>(&self, range: R, pref: StoragePreference) -> Result<()>
     where
         K: Borrow<[u8]> + Into<CowBytes>,
@@ -664,4 +1331,31 @@ impl Dataset<DefaultMessageAction> {
     {
         self.inner.read().migrate_range(range, pref)
     }
+
+    /// Pins all keys in the given range to the storage class `pref` and moves
+    /// the existing entries there, so that they stay on it.
+    ///
+    /// The rule is stored persistently and applies to entries inserted later
+    /// on as well.  Explicit migrations of pinned keys to another storage
+    /// class fail with [Error::KeyPinned], [Dataset::migrate_range] skips
+    /// them.  Automatic migration policies may still promote nodes holding
+    /// pinned entries to a faster storage class, but never demote them below
+    /// `pref`.  Where rules overlap the latest one applies.
+    pub fn pin_range<R, K>(&self, range: R, pref: StoragePreference) -> Result<()>
+    where
+        K: Borrow<[u8]> + Into<CowBytes>,
+        R: RangeBounds<K>,
+    {
+        self.inner.write().pin_range(range, pref)
+    }
+
+    /// Removes all pinning rules which lie completely within the given range.
+    /// The entries stay where they are until they are migrated.
+    pub fn unpin_range<R, K>(&self, range: R) -> Result<()>
+    where
+        K: Borrow<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.inner.write().unpin_range(range)
+    }
 }
@@ -0,0 +1,464 @@
+//! Defragmentation of the storage pool.
+//!
+//! Long-running databases scatter their free space over many segments, until
+//! large allocations fail even though enough space is free in total.  Segments
+//! in which only few blocks are allocated while their free space is
+//! fragmented are emptied by rewriting the nodes stored in them elsewhere.
+//! The distribution of the free space is reported by
+//! [Database::fragmentation_report], the density and age of the allocations
+//! by [Database::allocation_heatmap].
+use super::{
+    allocation_tree_of,
+    errors::*,
+    root_tree_msg::{deadlist, segment},
+    AllocationHeatmap, Database, DatasetId, DeadListData, DiskFragmentation, DiskHeatmap,
+    FragmentationInfo, FragmentationReport, Generation, ObjectPointer, RootDmu, RootTree,
+    SegmentFragmentation, SegmentHeat,
+};
+use crate::{
+    allocator::{Extent, SegmentAllocator, SegmentId, SEGMENT_SIZE, SEGMENT_SIZE_BYTES},
+    storage_pool::{with_io_priority, IoPriority, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    tree::{DefaultMessageAction, TreeLayer},
+    vdev::Block,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// Selection of the segments to empty, see [Database::defragment].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct DefragmentationConfiguration {
+    /// When set and the database is built with
+    /// [Database::build_threaded], defragment every `interval_ms`
+    /// milliseconds.
+    pub interval_ms: Option<u64>,
+    /// Only segments with at most this share of allocated blocks are
+    /// emptied.
+    pub max_occupancy: f32,
+    /// The maximum number of segments emptied at once.
+    pub max_segments: usize,
+}
+
+impl Default for DefragmentationConfiguration {
+    fn default() -> Self {
+        DefragmentationConfiguration {
+            interval_ms: None,
+            max_occupancy: 0.25,
+            max_segments: 16,
+        }
+    }
+}
+
+impl DefragmentationConfiguration {
+    // Whether a segment with the given allocation state is worth emptying.
+    fn is_fragmented(&self, allocator: &SegmentAllocator) -> bool {
+        let allocated = allocator.allocated();
+        let free = SEGMENT_SIZE as u32 - allocated;
+        allocated > 0
+            && allocated as f32 <= self.max_occupancy * SEGMENT_SIZE as f32
+            && allocator.largest_free_range() < free
+    }
+}
+
+// Calls `f` with the allocation state of each segment of the storage class
+// `class` which has been allocated from, in ascending order.  Allocations
+// which have not been synced yet are not included.
+fn for_each_segment<F>(dmu: &Arc<RootDmu>, class: u8, mut f: F) -> Result<()>
+where
+    F: FnMut(SegmentId, &SegmentAllocator),
+{
+    let tree = allocation_tree_of(dmu, class);
+    for entry in tree.range(&segment::min_key()[..]..&segment::max_key()[..])? {
+        let (key, data) = entry?;
+        let mut bitmap = [0u8; SEGMENT_SIZE_BYTES];
+        bitmap[..data.len()].copy_from_slice(&data);
+        f(segment::read_key(&key), &SegmentAllocator::new(bitmap));
+    }
+    Ok(())
+}
+
+// Accumulates the free space distribution of a top-level vdev from its
+// segments in ascending order.
+struct DiskScan {
+    size: Block<u64>,
+    // Index of the next segment to account for.
+    next_segment: u64,
+    // Length of the free extent reaching the end of the previous segment.
+    run: u64,
+    report: DiskFragmentation,
+}
+
+impl DiskScan {
+    fn new(size: Block<u64>) -> Self {
+        DiskScan {
+            size,
+            next_segment: 0,
+            run: 0,
+            report: DiskFragmentation {
+                info: FragmentationInfo::default(),
+                segments: Vec::new(),
+            },
+        }
+    }
+
+    fn segment_count(&self) -> u64 {
+        (self.size.as_u64() + SEGMENT_SIZE as u64 - 1) / SEGMENT_SIZE as u64
+    }
+
+    // The last segment of a disk may be cut short.
+    fn usable(&self, index: u64) -> u32 {
+        (self.size.as_u64() - index * SEGMENT_SIZE as u64).min(SEGMENT_SIZE as u64) as u32
+    }
+
+    fn end_run(&mut self) {
+        let run = std::mem::take(&mut self.run);
+        self.report.info.add_free_extent(run);
+    }
+
+    // Segments which have never been allocated from are entirely free.
+    fn skip_to(&mut self, index: u64) {
+        while self.next_segment < index {
+            self.run += u64::from(self.usable(self.next_segment));
+            self.next_segment += 1;
+        }
+    }
+
+    fn add_segment(&mut self, index: u64, allocator: &SegmentAllocator) {
+        if index < self.next_segment || index >= self.segment_count() {
+            return;
+        }
+        self.skip_to(index);
+        let usable = self.usable(index);
+        if allocator.free_prefix() == 0 {
+            self.end_run();
+        }
+        let mut info = FragmentationInfo::default();
+        for (offset, len) in allocator.free_ranges() {
+            if offset >= usable {
+                break;
+            }
+            let len = len.min(usable - offset);
+            info.add_free_extent(u64::from(len));
+            self.run += u64::from(len);
+            if offset + len < usable {
+                self.end_run();
+            }
+        }
+        self.report.segments.push(SegmentFragmentation {
+            offset: Block(index * SEGMENT_SIZE as u64),
+            info,
+        });
+        self.next_segment = index + 1;
+    }
+
+    fn finish(mut self) -> DiskFragmentation {
+        self.skip_to(self.segment_count());
+        self.end_run();
+        self.report
+    }
+}
+
+impl Database {
+    /// Empties the segments in which few blocks are allocated while their
+    /// free space is fragmented, as configured by
+    /// [DefragmentationConfiguration].  Returns the number of segments which
+    /// are entirely free afterwards.
+    ///
+    /// The nodes stored in these segments are rewritten to other segments
+    /// and the database is synced.  Blocks retained by snapshots or
+    /// checkpoints cannot be moved, segments holding blocks which are only
+    /// retained by them are not selected, and retained nodes are left where
+    /// they are.
+    pub fn defragment(&mut self) -> Result<usize> {
+        let segments =
+            fragmented_segments(&self.root_tree, &self.builder.defragmentation, |_| false)?;
+        Ok(self.empty_segments(&segments)?.len())
+    }
+
+    // Rewrites the nodes stored in `segments` elsewhere and returns the
+    // segments which are entirely free afterwards.
+    fn empty_segments(&mut self, segments: &HashSet<SegmentId>) -> Result<HashSet<SegmentId>> {
+        if segments.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let dmu = Arc::clone(self.root_tree.dmu());
+        dmu.set_defragmenting(segments.clone());
+        let result = self.relocate_nodes(&|ptr: &ObjectPointer| {
+            // Rewriting a retained node would only add a copy of it.
+            !dmu.handler().is_retained(ptr.generation(), ptr.info())
+                && ptr
+                    .extent()
+                    .segments()
+                    .any(|(id, _, _)| segments.contains(&id))
+        });
+        dmu.set_defragmenting(HashSet::new());
+        result?;
+
+        let mut freed = HashSet::new();
+        for &id in segments.iter() {
+            let tree = allocation_tree_of(&dmu, id.as_disk_offset().storage_class());
+            let bitmap = tree.get(&segment::id_to_key(id)[..])?;
+            if bitmap.map_or(true, |bitmap| bitmap.iter().all(|&byte| byte == 0)) {
+                freed.insert(id);
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Reports the distribution of the free space of all top-level vdevs as
+    /// of the last sync, e.g. to decide whether to [Database::defragment] or
+    /// to [Database::expand] the storage pool before large allocations fail.
+    ///
+    /// The allocation bitmaps of all segments are read, which can be costly
+    /// on large storage pools.
+    pub fn fragmentation_report(&self) -> Result<FragmentationReport> {
+        let dmu = self.root_tree.dmu();
+        let mut tiers = Vec::new();
+        for class in 0..dmu.spl().storage_class_count() {
+            let mut disks: Vec<_> = (0..dmu.spl().disk_count(class))
+                .map(|disk_id| DiskScan::new(dmu.spl().size_in_blocks(class, disk_id)))
+                .collect();
+            for_each_segment(dmu, class, |id, allocator| {
+                let index = id.as_disk_offset().block_offset().as_u64() / SEGMENT_SIZE as u64;
+                if let Some(disk) = disks.get_mut(id.disk_id() as usize) {
+                    disk.add_segment(index, allocator);
+                }
+            })?;
+            tiers.push(disks.into_iter().map(DiskScan::finish).collect());
+        }
+        Ok(FragmentationReport { tiers })
+    }
+
+    /// Reports how densely each segment is allocated and how old the nodes
+    /// stored in it are, e.g. to visualize where data lives across disks and
+    /// tiers and to decide whether to [Database::defragment] or to evacuate
+    /// a disk with [Database::evacuate_disk].
+    ///
+    /// The allocation bitmaps are the ones of the last sync.  All internal
+    /// nodes of all data sets are read, which can be costly on large storage
+    /// pools.  Nodes only reachable from snapshots or checkpoints count as
+    /// allocated but not as referenced.
+    pub fn allocation_heatmap(&self) -> Result<AllocationHeatmap> {
+        let dmu = self.root_tree.dmu();
+
+        // Referenced blocks and the range of generations of each segment.
+        let mut referenced: HashMap<SegmentId, (u32, Generation, Generation)> = HashMap::new();
+        let mut visit = |ptr: &ObjectPointer| {
+            let generation = ptr.generation();
+            for (id, _, len) in ptr.extent().segments() {
+                let entry = referenced.entry(id).or_insert((0, generation, generation));
+                entry.0 += len;
+                entry.1 = entry.1.min(generation);
+                entry.2 = entry.2.max(generation);
+            }
+        };
+        self.root_tree.for_each_pointer(&mut visit)?;
+        for class in 0..NUM_STORAGE_CLASSES as u8 {
+            allocation_tree_of(dmu, class).for_each_pointer(&mut visit)?;
+        }
+        let ids = self
+            .iter_datasets()?
+            .map(|id| id.map(|id| DatasetId::unpack(&id)))
+            .collect::<Result<Vec<_>>>()?;
+        for id in ids {
+            // Datasets which are not open are opened for the time being.
+            let ds = match self.open_dataset_with_id::<DefaultMessageAction>(id) {
+                Ok(ds) => Some(ds),
+                Err(Error::InUse) => None,
+                Err(e) => return Err(e),
+            };
+            let result = self
+                .open_datasets
+                .read()
+                .get(&id)
+                .map_or(Ok(()), |tree| tree.erased_for_each_pointer(&mut visit));
+            if let Some(ds) = ds {
+                self.close_dataset(ds)?;
+            }
+            result?;
+        }
+
+        let mut tiers = Vec::new();
+        for class in 0..dmu.spl().storage_class_count() {
+            let mut disks: Vec<_> = (0..dmu.spl().disk_count(class))
+                .map(|_| DiskHeatmap {
+                    segments: Vec::new(),
+                })
+                .collect();
+            for_each_segment(dmu, class, |id, allocator| {
+                if let Some(disk) = disks.get_mut(id.disk_id() as usize) {
+                    let (blocks, oldest, newest) = match referenced.get(&id) {
+                        Some(&(blocks, oldest, newest)) => (blocks, Some(oldest), Some(newest)),
+                        None => (0, None, None),
+                    };
+                    disk.segments.push(SegmentHeat {
+                        offset: id.as_disk_offset().block_offset(),
+                        allocated: Block(allocator.allocated()),
+                        referenced: Block(blocks),
+                        oldest,
+                        newest,
+                    });
+                }
+            })?;
+            tiers.push(disks);
+        }
+        Ok(AllocationHeatmap {
+            generation: dmu.handler().current_generation(),
+            tiers,
+        })
+    }
+}
+
+// Scans the allocation trees for the most sparsely used fragmented segments,
+// leaving out those for which `skip` returns true.  Segments holding blocks on
+// the dead lists are left out as well, these blocks are retained by snapshots
+// or checkpoints and keep the segments in use.
+fn fragmented_segments<F>(
+    root_tree: &RootTree<RootDmu>,
+    config: &DefragmentationConfiguration,
+    skip: F,
+) -> Result<HashSet<SegmentId>>
+where
+    F: Fn(SegmentId) -> bool,
+{
+    let mut pinned = HashSet::new();
+    let low = &deadlist::min_key(DatasetId::default(), Generation(0)) as &[_];
+    let high = &deadlist::max_key_all() as &[_];
+    for entry in root_tree.range(low..high)? {
+        let (key, value) = entry?;
+        let data = DeadListData::unpack(&value)?;
+        let extent = Extent::new(deadlist::offset_from_key(&key), data.size);
+        pinned.extend(extent.segments().map(|(id, _, _)| id));
+    }
+
+    let dmu = root_tree.dmu();
+    let mut candidates = Vec::new();
+    for class in 0..dmu.spl().storage_class_count() {
+        for_each_segment(dmu, class, |id, allocator| {
+            if config.is_fragmented(allocator) && !pinned.contains(&id) && !skip(id) {
+                candidates.push((allocator.allocated(), id));
+            }
+        })?;
+    }
+    candidates.sort_unstable();
+    Ok(candidates
+        .into_iter()
+        .take(config.max_segments)
+        .map(|(_, id)| id)
+        .collect())
+}
+
+// The maximum number of rounds for which a segment which could not be emptied
+// is skipped.
+const MAX_BACKOFF_ROUNDS: u64 = 64;
+
+/// The periodic defragmentation of a database, which backs off from the
+/// segments it could not empty, e.g. because they hold nodes retained by
+/// snapshots or checkpoints.
+#[derive(Default)]
+pub(super) struct PeriodicDefragmentation {
+    round: u64,
+    // The number of failed attempts to empty a segment and the round until
+    // which it is skipped.
+    backoff: HashMap<SegmentId, (u32, u64)>,
+}
+
+impl PeriodicDefragmentation {
+    /// Performs one defragmentation of `db`.  The segments are selected
+    /// without holding the lock of `db`, with the priority of background
+    /// work.  Emptying them requires exclusive access, which blocks all users
+    /// of `db` like a sync does, so it is not throttled in favour of them.
+    pub(super) fn round(&mut self, db: &RwLock<Database>) {
+        log::debug!("defragmenting db");
+        self.round += 1;
+        let round = self.round;
+        self.backoff
+            .retain(|_, &mut (_, until)| until + MAX_BACKOFF_ROUNDS > round);
+
+        let (root_tree, config) = {
+            let db = db.read();
+            (db.root_tree.clone(), db.builder.defragmentation.clone())
+        };
+        let backoff = &self.backoff;
+        let segments = with_io_priority(IoPriority::Background, || {
+            fragmented_segments(&root_tree, &config, |id| {
+                backoff.get(&id).map_or(false, |&(_, until)| round < until)
+            })
+        });
+        let result = segments.and_then(|segments| {
+            with_io_priority(IoPriority::Commit, || db.write().empty_segments(&segments))
+                .map(|freed| (segments, freed))
+        });
+        let (segments, freed) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("couldn't defragment db: {}", err);
+                return;
+            }
+        };
+        for id in segments {
+            if freed.contains(&id) {
+                self.backoff.remove(&id);
+            } else {
+                let (failures, until) = self.backoff.entry(id).or_insert((0, 0));
+                *failures += 1;
+                *until = round + (1 << (*failures).min(6) as u64).min(MAX_BACKOFF_ROUNDS);
+            }
+        }
+        log::debug!("defragmentation freed {} segments", freed.len());
+    }
+}
+
+pub fn defragmentation_timer(interval_ms: u64, db: Arc<RwLock<Database>>) {
+    let interval = Duration::from_millis(interval_ms);
+    let mut defragmentation = PeriodicDefragmentation::default();
+
+    loop {
+        thread::sleep(interval);
+        defragmentation.round(&db);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiskScan;
+    use crate::{
+        allocator::{SegmentAllocator, SEGMENT_SIZE, SEGMENT_SIZE_BYTES},
+        vdev::Block,
+    };
+
+    #[test]
+    fn free_extents_span_segments() {
+        const S: u64 = SEGMENT_SIZE as u64;
+        let mut scan = DiskScan::new(Block(2 * S + S / 2));
+        let mut first = SegmentAllocator::new([0; SEGMENT_SIZE_BYTES]);
+        assert!(first.allocate_at(10, 10));
+        scan.add_segment(0, &first);
+        // The second segment has never been allocated from.
+        let mut last = SegmentAllocator::new([0; SEGMENT_SIZE_BYTES]);
+        assert!(last.allocate_at(1, 5));
+        scan.add_segment(2, &last);
+        let report = scan.finish();
+
+        assert_eq!(report.info.free_extents(), 3);
+        assert_eq!(report.info.histogram[3], 1);
+        assert_eq!(report.info.largest_free_extent, Block(2 * S - 15));
+        assert_eq!(report.info.free, Block(2 * S + S / 2 - 11));
+        assert!(report.info.fragmentation() > 0.0);
+
+        assert_eq!(report.segments.len(), 2);
+        assert_eq!(report.segments[1].offset, Block(2 * S));
+        assert_eq!(report.segments[1].info.free, Block(S / 2 - 1));
+        assert_eq!(
+            report.segments[1].info.largest_free_extent,
+            Block(S / 2 - 6)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Messages which are queued by the [Handler](super::Handler) and inserted
+//! into the root or allocation trees on the next sync.
+//!
+//! Messages targeting the same key are coalesced on insertion into the queue.
+//! Allocation bitmap updates are gathered per segment and turned into a single
+//! upsert message when the queue is drained, which keeps large deletions from
+//! producing one message per freed block range.
+
+use crate::{
+    allocator::{SegmentAllocator, SEGMENT_SIZE},
+    cow_bytes::SlicedCowBytes,
+    tree::{DefaultMessageAction, MessageAction},
+};
+use bitvec::prelude::*;
+use std::collections::{btree_map::Entry, BTreeMap};
+
+type SegmentBits = BitArr!(for SEGMENT_SIZE, in u8, Lsb0);
+
+/// A queue of messages, coalesced per key.
+#[derive(Default)]
+pub(crate) struct DelayedMessages {
+    msgs: BTreeMap<Box<[u8]>, SlicedCowBytes>,
+    // Bitmap updates are always newer than the message of the same key in
+    // `msgs`, as queueing a message folds the pending bitmap update first.
+    segments: BTreeMap<Box<[u8]>, SegmentUpdate>,
+}
+
+impl DelayedMessages {
+    /// Queues `msg` for `key`, merging it with the messages already queued
+    /// for this key.
+    pub fn push(&mut self, key: Box<[u8]>, msg: SlicedCowBytes) {
+        let msg = match self.segments.remove(&key) {
+            Some(update) => DefaultMessageAction.merge(&key, msg, update.into_msg()),
+            None => msg,
+        };
+        match self.msgs.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let lower = entry.get().clone();
+                let merged = DefaultMessageAction.merge(entry.key(), msg, lower);
+                entry.insert(merged);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(msg);
+            }
+        }
+    }
+
+    /// Queues setting `amount_bits` bits starting at `offset_bits` of the
+    /// segment bitmap stored under `key` to `value`.
+    pub fn push_bits(&mut self, key: Box<[u8]>, offset_bits: u32, amount_bits: u32, value: bool) {
+        self.segments
+            .entry(key)
+            .or_insert_with(SegmentUpdate::new)
+            .set(offset_bits, amount_bits, value);
+    }
+
+    /// Merges the pending bitmap updates of all segments into `updates`,
+    /// where they are gathered until the allocators are updated.
+    pub fn merge_segment_updates_into(&self, updates: &mut BTreeMap<Box<[u8]>, SegmentUpdate>) {
+        for (key, update) in self.segments.iter() {
+            updates
+                .entry(key.clone())
+                .or_insert_with(SegmentUpdate::new)
+                .merge(update);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty() && self.segments.is_empty()
+    }
+
+    /// Consumes the queue, returning a single message per key.
+    pub fn into_messages(self) -> impl Iterator<Item = (Box<[u8]>, SlicedCowBytes)> {
+        let DelayedMessages { msgs, mut segments } = self;
+        let mut merged = msgs
+            .into_iter()
+            .map(|(key, msg)| match segments.remove(&key) {
+                Some(update) => {
+                    let msg = DefaultMessageAction.merge(&key, update.into_msg(), msg);
+                    (key, msg)
+                }
+                None => (key, msg),
+            })
+            .collect::<Vec<_>>();
+        merged.extend(
+            segments
+                .into_iter()
+                .map(|(key, update)| (key, update.into_msg())),
+        );
+        merged.into_iter()
+    }
+}
+
+// The pending bits of a segment bitmap, `mask` marks the bits which are
+// overwritten with the corresponding bit of `value`.
+pub(crate) struct SegmentUpdate {
+    mask: Box<SegmentBits>,
+    value: Box<SegmentBits>,
+}
+
+impl SegmentUpdate {
+    fn new() -> Self {
+        SegmentUpdate {
+            mask: Box::new(SegmentBits::ZERO),
+            value: Box::new(SegmentBits::ZERO),
+        }
+    }
+
+    fn set(&mut self, offset_bits: u32, amount_bits: u32, value: bool) {
+        let range = offset_bits as usize..(offset_bits + amount_bits) as usize;
+        debug_assert!(range.end <= SEGMENT_SIZE);
+        self.mask[range.clone()].fill(true);
+        self.value[range].fill(value);
+    }
+
+    // Overwrites the bits which are set by the `newer` update.
+    fn merge(&mut self, newer: &SegmentUpdate) {
+        let masks = self.mask.as_raw_mut_slice().iter_mut();
+        let values = self.value.as_raw_mut_slice().iter_mut();
+        let newer_bits = newer
+            .mask
+            .as_raw_slice()
+            .iter()
+            .zip(newer.value.as_raw_slice());
+        for ((mask, value), (newer_mask, newer_value)) in masks.zip(values).zip(newer_bits) {
+            *value = (*value & !newer_mask) | (newer_value & newer_mask);
+            *mask |= newer_mask;
+        }
+    }
+
+    /// Applies the update to the bitmap of `allocator`.
+    pub fn apply(&self, allocator: &mut SegmentAllocator) {
+        allocator.apply_update(self.mask.as_raw_slice(), self.value.as_raw_slice());
+    }
+
+    /// Applies the update to the raw allocation bitmap `bitmap`.
+    pub fn apply_to_bitmap(&self, bitmap: &mut [u8]) {
+        let updates = self
+            .mask
+            .as_raw_slice()
+            .iter()
+            .zip(self.value.as_raw_slice());
+        for (data, (mask, value)) in bitmap.iter_mut().zip(updates) {
+            *data = (*data & !mask) | (value & mask);
+        }
+    }
+
+    fn into_msg(self) -> SlicedCowBytes {
+        let mut ranges: Vec<(u32, u32, bool)> = Vec::new();
+        for idx in self.mask.iter_ones() {
+            let value = self.value[idx];
+            let idx = idx as u32;
+            match ranges.last_mut() {
+                Some((offset, amount, v)) if *offset + *amount == idx && *v == value => {
+                    *amount += 1
+                }
+                _ => ranges.push((idx, 1, value)),
+            }
+        }
+        DefaultMessageAction::upsert_bit_ranges_msg(&ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(msgs: impl IntoIterator<Item = SlicedCowBytes>) -> Option<SlicedCowBytes> {
+        let mut data = None;
+        for msg in msgs {
+            DefaultMessageAction.apply(&[], &msg, &mut data);
+        }
+        data
+    }
+
+    #[test]
+    fn coalesced_bits_match_sequential_upserts() {
+        let updates = [
+            (0, 16, true),
+            (4, 4, false),
+            (100, 3, true),
+            (8, 200, false),
+        ];
+        let mut queue = DelayedMessages::default();
+        for &(offset, amount, value) in updates.iter() {
+            queue.push_bits(Box::new([0]), offset, amount, value);
+        }
+        let coalesced = queue
+            .into_messages()
+            .map(|(_, msg)| msg)
+            .collect::<Vec<_>>();
+        assert_eq!(coalesced.len(), 1);
+
+        let sequential = updates.iter().map(|&(offset, amount, value)| {
+            DefaultMessageAction::upsert_bits_msg(offset, amount, value)
+        });
+        assert_eq!(apply(coalesced), apply(sequential));
+    }
+
+    #[test]
+    fn merged_updates_apply_to_allocators() {
+        let mut updates = BTreeMap::new();
+        let mut queue = DelayedMessages::default();
+        queue.push_bits(Box::new([0]), 0, 16, true);
+        queue.push_bits(Box::new([0]), 4, 4, false);
+        queue.merge_segment_updates_into(&mut updates);
+        let mut queue = DelayedMessages::default();
+        queue.push_bits(Box::new([0]), 6, 4, true);
+        queue.push_bits(Box::new([0]), 12, 2, false);
+        queue.merge_segment_updates_into(&mut updates);
+        assert_eq!(updates.len(), 1);
+
+        let mut allocator = SegmentAllocator::new([0; crate::allocator::SEGMENT_SIZE_BYTES]);
+        assert!(allocator.allocate_at(4, 100));
+        updates[&[0u8][..]].apply(&mut allocator);
+        // Blocks 0..4, 6..12 and 14..16 and the untouched blocks at 100 are
+        // in use.
+        assert_eq!(allocator.allocate(2), Some(4));
+        assert_eq!(allocator.allocate(2), Some(12));
+        assert_eq!(allocator.allocate(1), Some(16));
+        assert!(!allocator.allocate_at(1, 103));
+    }
+
+    #[test]
+    fn messages_keep_their_order() {
+        let mut queue = DelayedMessages::default();
+        queue.push(Box::new([1]), DefaultMessageAction::insert_msg(&[0; 4]));
+        queue.push_bits(Box::new([1]), 0, 8, true);
+        queue.push(Box::new([1]), DefaultMessageAction::upsert_msg(1, &[2]));
+        queue.push_bits(Box::new([1]), 16, 4, true);
+        queue.push(Box::new([2]), DefaultMessageAction::delete_msg());
+
+        let msgs = queue.into_messages().collect::<Vec<_>>();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(
+            apply(Some(msgs[0].1.clone())).as_deref(),
+            Some(&[0xff, 2, 0x0f, 0][..])
+        );
+        assert_eq!(apply(Some(msgs[1].1.clone())), None);
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(missing_docs, unused_doc_comments)]
 
-use crate::vdev::Block;
+use super::{DatasetId, Generation};
+use crate::{storage_pool::DiskOffset, vdev::Block};
 use thiserror::Error;
 
 pub type Result<R> = std::result::Result<R, Error>;
@@ -32,6 +33,11 @@ pub enum Error {
         #[from]
         source: crate::storage_pool::configuration::Error,
     },
+    #[error("Encryption configuration was not valid.")]
+    EncryptionError {
+        #[from]
+        source: crate::encryption::Error,
+    },
     #[error("IO error occurred.")]
     IoError {
         #[from]
@@ -46,8 +52,22 @@ pub enum Error {
     Closed,
     #[error("Superblock corrupted.")]
     InvalidSuperblock,
+    #[error("The storage pool has been written in the format version {0}, which is newer than the supported one.")]
+    UnsupportedFormat(u32),
+    #[error("The backup archive is damaged or of an unsupported version.")]
+    InvalidArchive,
+    #[error("The replication batch is damaged or of an unsupported version.")]
+    InvalidReplicationBatch,
+    #[error("The replication batch does not continue generation {0:?} applied by the follower.")]
+    ReplicationGap(Option<Generation>),
+    #[error("The database is a replication follower, its datasets are read-only.")]
+    ReadOnlyFollower,
+    #[error("The value does not match the given checksum.")]
+    ChecksumMismatch,
     #[error("Key does not exist.")]
     DoesNotExist,
+    #[error("The state of the dataset in generation {0:?} is no longer available.")]
+    GenerationUnavailable(Generation),
     #[error("Dataset name already occupied. Try to `.open()` the dataset instead.")]
     AlreadyExists,
     // TODO: This should anyway not happen, as there are no problems occuring
@@ -63,10 +83,157 @@ pub enum Error {
     },
     #[error("Migration is not possible as {1:?} blocks are not available in tier {0}.")]
     MigrationWouldExceedStorage(u8, Block<u64>),
+    #[error("Reserving {1:?} blocks is not possible as they are not available in tier {0}.")]
+    ReservationWouldExceedStorage(u8, Block<u64>),
+    #[error("The quota group {0} has reached its limit.")]
+    QuotaExceeded(String),
     #[error("Migration is not possible as the given tier does not exist.")]
     MigrationNotPossible,
+    #[error("Migration is not possible as storage class {0} is sealed.")]
+    StorageClassSealed(u8),
+    #[error("Storage class {0} has no vdevs.")]
+    EmptyStorageClass(u8),
+    #[error("The key is pinned to storage class {0}.")]
+    KeyPinned(u8),
+    #[error("Storage class {0} lacks {1} vdevs in the configuration which are in use.")]
+    MissingVdevs(u8, u16),
+    #[error("Vdev {1} of storage class {0} still holds blocks which cannot be relocated.")]
+    VdevInUse(u8, u16),
     #[error("Null bytes are disallowed in keys.")]
     KeyContainsNullByte,
-    #[error("{0}")]
-    Generic(String),
+    #[error("Ingested keys are not in strictly ascending order.")]
+    UnsortedKeys,
+    #[error("Malformed key-value dump: {0}")]
+    InvalidDump(String),
+    #[error("A typed key could not be encoded or decoded.")]
+    KeyEncodingError {
+        #[from]
+        source: super::key_encoding::Error,
+    },
+    #[error("Training a compression dictionary failed: {0}")]
+    DictionaryTrainingFailed(String),
+    #[error("Invalid configuration of `{field}`: {reason}")]
+    InvalidConfiguration { field: String, reason: String },
+    #[error("The blocking task of an asynchronous operation was dropped before it finished.")]
+    Canceled,
+    #[error("A key read by the transaction has been modified since it began.")]
+    TransactionConflict,
+    #[error("Storage class {0} does not exist.")]
+    NoSuchStorageClass(u8),
+    #[error("Vdev {1} of storage class {0} does not exist.")]
+    NoSuchVdev(u8, u16),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    #[error("Stored data is corrupted: {0}")]
+    Corrupted(&'static str),
+}
+
+/// Classification of errors which is consistent across all layers, see
+/// [Error::code].  The numeric values are stable and are also returned by the
+/// C interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ErrorCode {
+    /// None of the other codes applies.
+    Other = 1,
+    /// Reading from or writing to a storage device failed.
+    Io = 2,
+    /// Data read from a storage device does not match its checksum.
+    Checksum = 3,
+    /// Stored data could not be decoded or decrypted.
+    Corrupted = 4,
+    /// A storage class does not have enough free space.
+    OutOfSpace = 5,
+    /// The key, dataset, snapshot, or object does not exist.
+    DoesNotExist = 6,
+    /// A dataset or snapshot of the same name already exists.
+    AlreadyExists = 7,
+    /// The dataset or vdev is still in use.
+    InUse = 8,
+    /// The dataset has been closed.
+    Closed = 9,
+    /// An argument is not valid, e.g. a key or message is too large.
+    InvalidArgument = 10,
+    /// The configuration is not valid or does not match the stored data.
+    Configuration = 11,
+    /// The key is pinned to a storage class.
+    Pinned = 12,
+    /// An asynchronous operation has been canceled.
+    Canceled = 13,
+    /// An internal error, e.g. serializing internal data failed.
+    Internal = 14,
+    /// A transaction conflicts with a concurrent modification.
+    Conflict = 15,
+}
+
+impl Error {
+    /// Returns the classification of this error, which is taken from the
+    /// error of the lowest layer for errors passed on by other layers.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::VdevError { source } => source.code(),
+            Error::StoragePoolError { source } => source.code(),
+            Error::TreeError { source } => source.code(),
+            Error::EncryptionError { source } => source.code(),
+            Error::DmlError { source } => source.code(),
+            Error::ConfigurationError { .. }
+            | Error::MissingVdevs(..)
+            | Error::InvalidConfiguration { .. } => ErrorCode::Configuration,
+            Error::IoError { .. } => ErrorCode::Io,
+            Error::BinarySerializationError { .. } | Error::SerializeFailed { .. } => {
+                ErrorCode::Internal
+            }
+            Error::Closed => ErrorCode::Closed,
+            Error::InvalidSuperblock => ErrorCode::Corrupted,
+            Error::UnsupportedFormat(_) => ErrorCode::Configuration,
+            Error::InvalidArchive | Error::InvalidReplicationBatch | Error::Corrupted(_) => {
+                ErrorCode::Corrupted
+            }
+            Error::ReplicationGap(_) => ErrorCode::Conflict,
+            Error::ReadOnlyFollower => ErrorCode::InvalidArgument,
+            Error::ChecksumMismatch => ErrorCode::Checksum,
+            Error::DoesNotExist | Error::GenerationUnavailable(_) | Error::NoSuchVdev(..) => {
+                ErrorCode::DoesNotExist
+            }
+            Error::AlreadyExists => ErrorCode::AlreadyExists,
+            Error::InUse | Error::VdevInUse(..) => ErrorCode::InUse,
+            Error::MessageTooLarge
+            | Error::MigrationNotPossible
+            | Error::StorageClassSealed(_)
+            | Error::EmptyStorageClass(_)
+            | Error::KeyContainsNullByte
+            | Error::UnsortedKeys
+            | Error::InvalidDump(_)
+            | Error::DictionaryTrainingFailed(_)
+            | Error::KeyEncodingError { .. }
+            | Error::NoSuchStorageClass(_)
+            | Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::MigrationWouldExceedStorage(..)
+            | Error::ReservationWouldExceedStorage(..)
+            | Error::QuotaExceeded(_) => ErrorCode::OutOfSpace,
+            Error::KeyPinned(_) => ErrorCode::Pinned,
+            Error::Canceled => ErrorCode::Canceled,
+            Error::TransactionConflict => ErrorCode::Conflict,
+        }
+    }
+
+    fn dml_error(&self) -> Option<&crate::data_management::Error> {
+        match self {
+            Error::DmlError { source }
+            | Error::TreeError {
+                source: crate::tree::Error::DmuError { source },
+            } => Some(source),
+            _ => None,
+        }
+    }
+
+    /// Returns the dataset this error occurred in, if known.
+    pub fn dataset(&self) -> Option<DatasetId> {
+        self.dml_error().and_then(|e| e.dataset())
+    }
+
+    /// Returns the disk offset this error occurred at, if known.
+    pub fn disk_offset(&self) -> Option<DiskOffset> {
+        self.dml_error().and_then(|e| e.disk_offset())
+    }
 }
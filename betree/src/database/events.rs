@@ -0,0 +1,156 @@
+//! Notifications about internal events of the storage stack, see
+//! [Database::register_event_handler](super::Database::register_event_handler).
+use super::{DatasetId, Generation};
+use crate::{
+    migration::MigrationReport,
+    storage_pool::DiskOffset,
+    vdev::{Block, Error as VdevError},
+};
+use parking_lot::RwLock;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// An event within the storage stack.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A node of a tree has been split in two.  Nodes of internal trees, e.g.
+    /// the root tree, are reported with the id of the internal tree.
+    NodeSplit {
+        /// The dataset the tree belongs to.
+        dataset: DatasetId,
+        /// The height of the node in the tree, leaves are at level 0.
+        level: u32,
+    },
+    /// Two sibling nodes of a tree have been merged into one.
+    NodeMerge {
+        /// The dataset the tree belongs to.
+        dataset: DatasetId,
+        /// The height of the merged nodes in the tree, leaves are at level 0.
+        level: u32,
+    },
+    /// An allocation did not fit into a storage class, it is then placed in
+    /// the next class of the allocation strategy if there is any.  Emitted
+    /// for each such allocation as long as the class stays full.
+    TierFull {
+        /// The storage class.
+        class: u8,
+        /// The remaining free space of the class.
+        free: Block<u64>,
+        /// The size of the allocation.
+        requested: Block<u32>,
+    },
+    /// Reading a node from disk failed.  Checksum mismatches are reported
+    /// with [VdevError::Checksum].  Mismatches which a mirror has repaired
+    /// from another replica do not fail the read, they are reported by
+    /// [Event::MirrorRepaired] instead.
+    ReadFailed {
+        /// The location of the node.
+        offset: DiskOffset,
+        /// The size of the node on disk.
+        size: Block<u32>,
+        /// The cause of the failure.
+        error: VdevError,
+    },
+    /// A faulted replica of a mirror has been rewritten with the data of
+    /// another replica while reading a node, which has then been read
+    /// successfully.
+    MirrorRepaired {
+        /// The id of the rewritten leaf vdev.
+        disk: String,
+        /// The location of the repaired data.
+        offset: DiskOffset,
+    },
+    /// Data has been migrated between storage classes, see
+    /// [Database::subscribe_migrations](super::Database::subscribe_migrations).
+    Migration(MigrationReport),
+    /// A sync has been completed, all changes made before it are durable.
+    SyncCompleted {
+        /// The generation which has been written.
+        generation: Generation,
+        /// How long the sync took.
+        duration: Duration,
+    },
+}
+
+/// Identifies a registered event handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventHandlerId(u64);
+
+type EventHandler = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// The handlers called on each [Event].
+#[derive(Default)]
+pub struct EventHandlers {
+    next_id: AtomicU64,
+    handlers: RwLock<Vec<(EventHandlerId, EventHandler)>>,
+}
+
+impl EventHandlers {
+    pub(crate) fn register(&self, handler: EventHandler) -> EventHandlerId {
+        let id = EventHandlerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.handlers.write().push((id, handler));
+        id
+    }
+
+    /// Removes the handler `id`, returns whether it had been registered.
+    pub(crate) fn unregister(&self, id: EventHandlerId) -> bool {
+        let mut handlers = self.handlers.write();
+        let before = handlers.len();
+        handlers.retain(|(other, _)| *other != id);
+        handlers.len() != before
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.handlers.read().is_empty()
+    }
+
+    /// Calls all handlers with the event built by `event`, which is skipped if
+    /// there are none.
+    pub(crate) fn emit<F: FnOnce() -> Event>(&self, event: F) {
+        let handlers = self.handlers.read_recursive();
+        if handlers.is_empty() {
+            return;
+        }
+        let event = event();
+        for (_, handler) in handlers.iter() {
+            handler(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventHandlers};
+    use crate::vdev::Block;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn unregistered_handlers_are_not_called() {
+        let events = EventHandlers::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let id = events.register(Box::new(move |event| {
+            assert!(matches!(event, Event::TierFull { class: 1, .. }));
+            counted.fetch_add(1, Ordering::Relaxed);
+        }));
+        let full = || Event::TierFull {
+            class: 1,
+            free: Block(0),
+            requested: Block(1),
+        };
+        events.emit(full);
+        assert!(events.unregister(id));
+        assert!(!events.unregister(id));
+        events.emit(full);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(events.is_empty());
+
+        // Without handlers the event is not even built.
+        events.emit(|| unreachable!());
+    }
+}
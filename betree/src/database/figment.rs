@@ -1,7 +1,9 @@
+use super::errors::*;
 use figment::{
-    providers::{Env, Serialized},
-    Provider,
+    providers::{Env, Format, Json, Serialized, Toml, Yaml},
+    Figment, Provider,
 };
+use std::path::Path;
 
 impl super::DatabaseConfiguration {
     /// A figment provider for the default DatabaseConfiguration.
@@ -14,4 +16,51 @@ impl super::DatabaseConfiguration {
     pub fn figment_env() -> impl Provider {
         Env::prefixed("BETREE__").ignore(&["CONFIG"]).split("__")
     }
+
+    /// A figment provider for the configuration file at `path`, whose format is
+    /// chosen by its extension: `.toml` for TOML, `.yaml` or `.yml` for YAML and
+    /// JSON otherwise.
+    pub fn figment_file<P: AsRef<Path>>(path: P) -> Figment {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Figment::from(Toml::file(path)),
+            Some("yaml") | Some("yml") => Figment::from(Yaml::file(path)),
+            _ => Figment::from(Json::file(path)),
+        }
+    }
+
+    /// Loads the configuration from the defaults, the file at `path` and the
+    /// environment, see [Self::figment_file] and [Self::figment_env], and
+    /// validates it.
+    ///
+    /// Errors are reported as [Error::InvalidConfiguration] naming the
+    /// offending field and where its value comes from.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        // Figment treats missing files as empty, which would silently fall
+        // back to the defaults.
+        std::fs::metadata(path)?;
+        let config: Self = Figment::new()
+            .merge(Self::figment_default())
+            .merge(Self::figment_file(path))
+            .merge(Self::figment_env())
+            .extract()
+            .map_err(invalid_configuration)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn invalid_configuration(error: figment::Error) -> Error {
+    let mut reason = error.kind.to_string();
+    if let Some(metadata) = &error.metadata {
+        match &metadata.source {
+            Some(source) => reason = format!("{} in {}", reason, source),
+            None => reason = format!("{} from {}", reason, metadata.name),
+        }
+    }
+    Error::InvalidConfiguration {
+        field: error.path.join("."),
+        reason,
+    }
 }
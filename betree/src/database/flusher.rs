@@ -0,0 +1,113 @@
+//! Background rebalancing of overfull nodes.
+//!
+//! With [DatabaseConfiguration::flush_threads](super::DatabaseConfiguration)
+//! set, insertions into datasets leave nodes which have grown too large to the
+//! threads of the [Flusher] instead of flushing, splitting and merging nodes on
+//! the inserting thread.  Each task descends to the overfull node like an
+//! insertion, holding the locks of only the current node and its parent, so
+//! tasks for disjoint subtrees run in parallel.
+//!
+//! The queue is bounded: if it is full, the inserting thread rebalances the
+//! node itself, which throttles insertions to the pace of the flusher.
+use super::DatasetId;
+use crate::storage_pool::{with_io_priority, IoPriority};
+use crossbeam_channel::{Sender, TrySendError};
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+// Number of queued tasks per thread before insertions rebalance themselves.
+const QUEUE_LEN_PER_THREAD: usize = 64;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+// Queued and running tasks per dataset.
+#[derive(Default)]
+struct Pending {
+    tasks: Mutex<HashMap<DatasetId, usize>>,
+    done: Condvar,
+}
+
+impl Pending {
+    fn finish(&self, ds_id: DatasetId) {
+        let mut tasks = self.tasks.lock();
+        let count = tasks.get_mut(&ds_id).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            tasks.remove(&ds_id);
+            self.done.notify_all();
+        }
+    }
+}
+
+/// Pool of threads rebalancing overfull nodes, see the module documentation.
+pub(crate) struct Flusher {
+    sender: Option<Sender<(DatasetId, Task)>>,
+    pending: Arc<Pending>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Starts `threads` flusher threads.
+    pub(crate) fn new(threads: usize) -> Self {
+        let (sender, receiver) =
+            crossbeam_channel::bounded::<(DatasetId, Task)>(QUEUE_LEN_PER_THREAD * threads);
+        let pending = Arc::new(Pending::default());
+        let threads = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let pending = Arc::clone(&pending);
+                thread::spawn(move || {
+                    with_io_priority(IoPriority::Background, || {
+                        for (ds_id, task) in receiver {
+                            task();
+                            pending.finish(ds_id);
+                        }
+                    })
+                })
+            })
+            .collect();
+        Flusher {
+            sender: Some(sender),
+            pending,
+            threads,
+        }
+    }
+
+    /// Queues `task` for the dataset `ds_id`.  Returns the task if the queue
+    /// is full, it then has to be run by the caller.
+    pub(crate) fn submit(&self, ds_id: DatasetId, task: Task) -> Result<(), Task> {
+        *self.pending.tasks.lock().entry(ds_id).or_insert(0) += 1;
+        match self.sender.as_ref().unwrap().try_send((ds_id, task)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full((_, task))) | Err(TrySendError::Disconnected((_, task))) => {
+                self.pending.finish(ds_id);
+                Err(task)
+            }
+        }
+    }
+
+    /// Waits until all tasks of the dataset `ds_id` are finished.  The dataset
+    /// must not be modified concurrently, e.g. because it is being closed.
+    pub(crate) fn wait_idle(&self, ds_id: DatasetId) {
+        let mut tasks = self.pending.tasks.lock();
+        while tasks.contains_key(&ds_id) {
+            self.pending.done.wait(&mut tasks);
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Finish all queued tasks.
+        drop(self.sender.take());
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Flusher thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Consistency check of the space accounting.
+//!
+//! The allocation bitmaps and dead lists are only ever updated incrementally,
+//! so that a single missed update leaks blocks for good or, worse, lets
+//! blocks which are still in use be allocated again.  [Database::fsck]
+//! compares the allocation bitmaps to the nodes reachable from all datasets,
+//! snapshots, read views and checkpoints and optionally repairs them.
+//!
+//! [Database::audit_dead_lists] is a cheaper check of the dead lists alone,
+//! which only looks at the generations of the snapshots, read views and
+//! checkpoints.
+use super::{
+    allocation_tree_of,
+    checkpoint::CheckpointData,
+    errors::*,
+    root_tree_msg::{
+        allocation_tree, checkpoint as checkpoint_key, dataset as dataset_key, deadlist,
+        sealed_disk, segment, snapshot as snapshot_key,
+    },
+    Database, DatasetData, DatasetId, DeadListData, Generation, ObjectPointer, RootDmu, RootTree,
+    ROOT_DATASET_ID, SUPERBLOCK_BLOCKS,
+};
+use crate::{
+    allocator::{Action, Extent, SegmentId, SEGMENT_SIZE},
+    storage_pool::{DiskOffset, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    tree::{DefaultMessageAction, TreeLayer},
+    vdev::Block,
+    StoragePreference,
+};
+use bitvec::prelude::*;
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+type SegmentBits = BitArr!(for SEGMENT_SIZE, in u8, Lsb0);
+
+/// Options of [Database::fsck].
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Whether to repair the allocation bitmaps and dead lists.  Blocks
+    /// which are referenced more than once cannot be repaired.
+    pub repair: bool,
+}
+
+/// Inconsistencies of the space accounting found by [Database::fsck].
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// The number of distinct nodes reachable from all datasets, snapshots,
+    /// checkpoints and internal trees.
+    pub nodes: u64,
+    /// Blocks which are allocated but not referenced by any node.
+    pub leaked: Vec<Extent>,
+    /// Blocks which are referenced but not allocated, they may be allocated
+    /// again and overwritten.
+    pub unallocated: Vec<Extent>,
+    /// Blocks which are referenced by more than one distinct node.
+    pub doubly_referenced: Vec<Extent>,
+    /// The number of dead list entries of blocks which are not referenced by
+    /// any snapshot or checkpoint anymore.
+    pub stale_dead_list_entries: u64,
+    /// Whether the leaked and unallocated blocks and the stale dead list
+    /// entries have been repaired.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// Returns whether no inconsistency has been found.
+    pub fn is_consistent(&self) -> bool {
+        self.leaked.is_empty()
+            && self.unallocated.is_empty()
+            && self.doubly_referenced.is_empty()
+            && self.stale_dead_list_entries == 0
+    }
+}
+
+/// The dead lists as found by [Database::audit_dead_lists].
+#[derive(Debug, Clone, Default)]
+pub struct DeadListReport {
+    /// The number of dead list entries.
+    pub entries: u64,
+    /// The blocks of all dead list entries, including the orphaned ones.
+    pub size: Block<u64>,
+    /// The number of entries of blocks which are retained by neither a
+    /// snapshot nor a checkpoint anymore.
+    pub orphaned: u64,
+    /// The blocks of the orphaned entries.
+    pub orphaned_size: Block<u64>,
+    /// Whether the orphaned entries have been removed and their blocks
+    /// deallocated.
+    pub reclaimed: bool,
+}
+
+// The blocks referenced by the visited nodes.
+#[derive(Default)]
+struct References {
+    // The size and generation of each distinct node by its offset.
+    nodes: HashMap<DiskOffset, (Block<u32>, Generation)>,
+    // The root nodes of the trees which have been visited already.
+    trees: HashSet<DiskOffset>,
+    // The bases of the deltas by the offset of the delta, which are visited
+    // along with them.
+    delta_bases: HashMap<DiskOffset, ObjectPointer>,
+    segments: BTreeMap<SegmentId, Box<SegmentBits>>,
+    doubly_referenced: Vec<Extent>,
+}
+
+impl References {
+    // Marks the blocks of `extent` as referenced.
+    fn mark(&mut self, extent: Extent) {
+        for (id, offset, len) in extent.segments() {
+            let bits = self
+                .segments
+                .entry(id)
+                .or_insert_with(|| Box::new(SegmentBits::ZERO));
+            let range = &mut bits[offset as usize..(offset + len) as usize];
+            if range.any() {
+                self.doubly_referenced
+                    .push(Extent::new(id.disk_offset(offset), Block(len)));
+            }
+            range.fill(true);
+        }
+    }
+
+    // Nodes shared by several trees, e.g. by a dataset and its snapshots,
+    // are only accounted for once.
+    fn visit(&mut self, ptr: &ObjectPointer) {
+        match self.nodes.entry(ptr.offset()) {
+            Entry::Occupied(entry) if *entry.get() == (ptr.size(), ptr.generation()) => {}
+            Entry::Occupied(_) => self.mark(ptr.extent()),
+            Entry::Vacant(entry) => {
+                entry.insert((ptr.size(), ptr.generation()));
+                self.mark(ptr.extent());
+            }
+        }
+        if let Some(base) = self.delta_bases.get(&ptr.offset()).cloned() {
+            self.visit(&base);
+        }
+    }
+
+    // Visits the nodes of the tree with the root node `ptr`, unless it has
+    // been visited already.
+    fn visit_tree(&mut self, dmu: &Arc<RootDmu>, id: DatasetId, ptr: ObjectPointer) -> Result<()> {
+        if !self.trees.insert(ptr.offset()) {
+            return Ok(());
+        }
+        let tree = RootTree::open(
+            id,
+            ptr,
+            DefaultMessageAction,
+            Arc::clone(dmu),
+            StoragePreference::NONE,
+        );
+        tree.for_each_pointer(&mut |ptr: &ObjectPointer| self.visit(ptr))?;
+        Ok(())
+    }
+
+    // Visits the nodes of the root tree `tree` and of all trees listed in it
+    // except for checkpoints.
+    fn visit_root_tree(&mut self, dmu: &Arc<RootDmu>, tree: &RootTree<RootDmu>) -> Result<()> {
+        tree.for_each_pointer(&mut |ptr: &ObjectPointer| self.visit(ptr))?;
+        for class in 0..NUM_STORAGE_CLASSES as u8 {
+            if let Some(data) = tree.get(&allocation_tree::key(class)[..])? {
+                self.visit_tree(dmu, ROOT_DATASET_ID, bincode::deserialize(&data)?)?;
+            }
+        }
+        let low = &dataset_key::data_key(DatasetId::default()) as &[_];
+        let high = &dataset_key::data_key_max() as &[_];
+        for entry in tree.range(low..high)? {
+            let (key, data) = entry?;
+            let ptr = DatasetData::<ObjectPointer>::unpack(&data)?.ptr;
+            self.visit_tree(dmu, DatasetId::unpack(&key[1..]), ptr)?;
+        }
+        let low = &snapshot_key::data_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &snapshot_key::data_key_max_all() as &[_];
+        for entry in tree.range(low..high)? {
+            let (key, data) = entry?;
+            let ptr = DatasetData::<ObjectPointer>::unpack(&data)?.ptr;
+            self.visit_tree(dmu, snapshot_key::ds_id_from_data_key(&key), ptr)?;
+        }
+        Ok(())
+    }
+}
+
+// Sets the bits of the blocks of `extent` in the bitmaps `segments`.
+fn fill(segments: &mut BTreeMap<SegmentId, Box<SegmentBits>>, extent: Extent) {
+    for (id, offset, len) in extent.segments() {
+        let bits = segments
+            .entry(id)
+            .or_insert_with(|| Box::new(SegmentBits::ZERO));
+        bits[offset as usize..(offset + len) as usize].fill(true);
+    }
+}
+
+// Returns the runs of blocks of the segment `id` which are set in `bits` but
+// not in `other`.
+fn difference(id: SegmentId, bits: &SegmentBits, other: &SegmentBits) -> Vec<Extent> {
+    let mut diff = *bits;
+    for (data, other) in diff.as_raw_mut_slice().iter_mut().zip(other.as_raw_slice()) {
+        *data &= !other;
+    }
+    let mut runs = Vec::new();
+    let mut idx = 0;
+    while let Some(start) = diff[idx..].first_one().map(|first| idx + first) {
+        let len = diff[start..].first_zero().unwrap_or(SEGMENT_SIZE - start);
+        runs.push(Extent::new(id.disk_offset(start as u32), Block(len as u32)));
+        idx = start + len;
+    }
+    runs
+}
+
+impl Database {
+    /// Checks the space accounting of the whole storage pool.
+    ///
+    /// The database is synced, then all nodes reachable from the datasets,
+    /// snapshots, read views and checkpoints and the internal trees are
+    /// visited and compared to the allocation bitmaps.  Blocks which are
+    /// allocated but not referenced are reported as leaked, referenced blocks
+    /// which are not allocated and blocks referenced by several nodes are
+    /// reported as well, as are dead list entries of blocks which are not
+    /// referenced by any snapshot, read view or checkpoint anymore.
+    ///
+    /// With [FsckOptions::repair] set, leaked blocks are freed, unallocated
+    /// blocks are allocated and stale dead list entries are removed, which
+    /// is synced before this function returns.  Datasets must not be
+    /// modified concurrently, and all internal nodes of all trees are read,
+    /// which can be costly on large storage pools.
+    pub fn fsck(&mut self, options: FsckOptions) -> Result<FsckReport> {
+        self.sync()?;
+        let dmu = Arc::clone(self.root_tree.dmu());
+        let handler = dmu.handler();
+
+        let mut references = References {
+            delta_bases: handler
+                .delta_bases
+                .read()
+                .iter()
+                .map(|(&offset, delta)| (offset, delta.base.clone()))
+                .collect(),
+            ..References::default()
+        };
+        for class in 0..dmu.spl().storage_class_count() {
+            for disk_id in 0..dmu.spl().disk_count(class) {
+                for extent in dmu.raw_ends(class, disk_id, SUPERBLOCK_BLOCKS) {
+                    references.mark(extent);
+                }
+            }
+        }
+        references.visit_root_tree(&dmu, &self.root_tree)?;
+        for (id, root_ptr) in handler.read_view_roots() {
+            references.visit_tree(&dmu, id, root_ptr)?;
+        }
+        let low = &checkpoint_key::min_key() as &[_];
+        let high = &checkpoint_key::max_key() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (_, data) = entry?;
+            let root_ptr = CheckpointData::unpack(&data)?.root_ptr;
+            if references.trees.insert(root_ptr.offset()) {
+                let tree = RootTree::open(
+                    ROOT_DATASET_ID,
+                    root_ptr,
+                    DefaultMessageAction,
+                    Arc::clone(&dmu),
+                    dmu.handler().root_tree_storage_preference,
+                );
+                references.visit_root_tree(&dmu, &tree)?;
+            }
+        }
+
+        // The allocation bitmaps as of the last sync, including the updates
+        // which are only written by the next one.
+        let mut allocated: BTreeMap<SegmentId, Box<SegmentBits>> = BTreeMap::new();
+        for class in 0..dmu.spl().storage_class_count() {
+            let tree = allocation_tree_of(&dmu, class);
+            for entry in tree.range(&segment::min_key()[..]..&segment::max_key()[..])? {
+                let (key, data) = entry?;
+                let mut bits = Box::new(SegmentBits::ZERO);
+                bits.as_raw_mut_slice()[..data.len()].copy_from_slice(&data);
+                allocated.insert(segment::read_key(&key), bits);
+            }
+            let mut updates = BTreeMap::new();
+            handler.delayed_allocation_messages[class as usize]
+                .lock()
+                .merge_segment_updates_into(&mut updates);
+            for (key, update) in updates {
+                let bits = allocated
+                    .entry(segment::read_key(&key))
+                    .or_insert_with(|| Box::new(SegmentBits::ZERO));
+                update.apply_to_bitmap(bits.as_raw_mut_slice());
+            }
+        }
+        // Neither the root nodes written by the last sync nor the nodes of the
+        // second-level cache are recorded in the allocation bitmaps.  The
+        // latter are not referenced by any tree either.
+        let root_allocations = handler.old_root_allocations.read().clone();
+        let cache_allocations: Vec<_> = handler
+            .cache_allocations
+            .read()
+            .iter()
+            .map(|(&offset, &size)| (offset, size))
+            .collect();
+        for &(offset, size) in root_allocations.iter().chain(cache_allocations.iter()) {
+            fill(&mut allocated, Extent::new(offset, size));
+        }
+        for &(offset, size) in cache_allocations.iter() {
+            fill(&mut references.segments, Extent::new(offset, size));
+        }
+        // Blocks freed on sealed disks stay allocated until the disks are
+        // unsealed.
+        let low = &sealed_disk::min_key_all() as &[_];
+        let high = &sealed_disk::max_key_all() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, data) = entry?;
+            if sealed_disk::is_deferred_key(&key) {
+                let (size, _) = sealed_disk::read_deferred_value(&data);
+                let offset = sealed_disk::offset_from_key(&key);
+                fill(&mut references.segments, Extent::new(offset, size));
+            }
+        }
+
+        let mut report = FsckReport {
+            nodes: references.nodes.len() as u64,
+            ..FsckReport::default()
+        };
+        let none = SegmentBits::ZERO;
+        for (&id, bits) in allocated.iter() {
+            let referenced = references.segments.get(&id).map_or(&none, |bits| &**bits);
+            report.leaked.extend(difference(id, bits, referenced));
+        }
+        for (&id, bits) in references.segments.iter() {
+            let allocated = allocated.get(&id).map_or(&none, |bits| &**bits);
+            report.unallocated.extend(difference(id, bits, allocated));
+        }
+        report.doubly_referenced = references.doubly_referenced;
+
+        let mut stale = Vec::new();
+        let low = &deadlist::min_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &deadlist::max_key_all() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, _) = entry?;
+            if !references
+                .nodes
+                .contains_key(&deadlist::offset_from_key(&key))
+            {
+                stale.push(key);
+            }
+        }
+        report.stale_dead_list_entries = stale.len() as u64;
+
+        let repairable =
+            !(report.leaked.is_empty() && report.unallocated.is_empty() && stale.is_empty());
+        if options.repair && repairable {
+            for extent in report.leaked.iter() {
+                handler.update_allocation_bitmap(
+                    extent.offset,
+                    extent.size,
+                    Action::Deallocate,
+                    ROOT_DATASET_ID,
+                    &*dmu,
+                )?;
+            }
+            for extent in report.unallocated.iter() {
+                dmu.force_allocate_raw(*extent, ROOT_DATASET_ID)?;
+            }
+            for key in stale {
+                self.root_tree.insert(
+                    key,
+                    DefaultMessageAction::delete_msg(),
+                    StoragePreference::NONE,
+                )?;
+            }
+            self.sync()?;
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+
+    /// Checks the dead lists for entries of blocks which are not part of any
+    /// snapshot, read view or checkpoint anymore, e.g. because the read view
+    /// they have been kept for has been dropped without being closed.  With
+    /// `reclaim` set, these entries are removed and their blocks deallocated,
+    /// which is synced before this function returns.
+    ///
+    /// A block is retained if it has been written in or before the
+    /// generation of a snapshot or open read view of its dataset or a
+    /// checkpoint and freed after it.  Unlike [Database::fsck], no trees are
+    /// read, only the dead lists and the generations of all snapshots, read
+    /// views and checkpoints.
+    pub fn audit_dead_lists(&mut self, reclaim: bool) -> Result<DeadListReport> {
+        // Dead list entries are only written to the root tree on sync.
+        self.sync()?;
+
+        let mut snapshots: HashMap<DatasetId, Vec<Generation>> = HashMap::new();
+        let low = &snapshot_key::data_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &snapshot_key::data_key_max_all() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, _) = entry?;
+            snapshots
+                .entry(snapshot_key::ds_id_from_data_key(&key))
+                .or_default()
+                .push(snapshot_key::generation_from_data_key(&key));
+        }
+        let checkpoints: Vec<_> = self
+            .list_checkpoints()?
+            .into_iter()
+            .map(|info| info.generation)
+            .collect();
+
+        let handler = self.root_tree.dmu().handler();
+        let mut report = DeadListReport::default();
+        let mut orphaned = Vec::new();
+        let low = &deadlist::min_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &deadlist::max_key_all() as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, value) = entry?;
+            let data = DeadListData::unpack(&value)?;
+            let id = deadlist::ds_id_from_key(&key);
+            let death = deadlist::generation_from_key(&key);
+            let retains =
+                |generation: &Generation| data.birth <= *generation && *generation < death;
+            report.entries += 1;
+            report.size += Block(data.size.as_u64());
+            if !checkpoints.iter().any(retains)
+                && !handler.read_view_generations(id).iter().any(retains)
+                && !snapshots
+                    .get(&id)
+                    .map_or(false, |generations| generations.iter().any(retains))
+            {
+                report.orphaned += 1;
+                report.orphaned_size += Block(data.size.as_u64());
+                orphaned.push((key, id, data.size));
+            }
+        }
+
+        if reclaim && !orphaned.is_empty() {
+            let dmu = self.root_tree.dmu();
+            for (key, id, size) in orphaned {
+                let offset = deadlist::offset_from_key(&key);
+                dmu.handler().update_allocation_bitmap(
+                    offset,
+                    size,
+                    Action::Deallocate,
+                    id,
+                    dmu,
+                )?;
+                dmu.release_delta(offset);
+                self.root_tree.insert(
+                    key,
+                    DefaultMessageAction::delete_msg(),
+                    StoragePreference::NONE,
+                )?;
+            }
+            self.sync()?;
+            report.reclaimed = true;
+        }
+        Ok(report)
+    }
+}
@@ -1,29 +1,35 @@
 use super::{
+    delayed_messages::{DelayedMessages, SegmentUpdate},
     errors::*,
-    root_tree_msg::{deadlist, segment, space_accounting},
-    AtomicStorageInfo, DatasetId, DeadListData, Generation, StorageInfo, TreeInner,
+    quota::{QuotaGroup, QuotaGroups},
+    reservation::Reservations,
+    root_tree_msg::{dataset_usage, deadlist, delta_base, sealed_disk, segment, space_accounting},
+    AtomicStorageInfo, DatasetId, DeadListData, EventHandlers, Generation, SpaceUsage, StorageInfo,
+    TreeInner, ROOT_DATASET_ID,
 };
 use crate::{
-    allocator::{Action, SegmentAllocator, SegmentId, SEGMENT_SIZE_BYTES},
+    allocator::{Action, AllocatorType, Extent, SegmentAllocator, SegmentId, SEGMENT_SIZE_BYTES},
     atomic_option::AtomicOption,
-    cow_bytes::SlicedCowBytes,
-    data_management::{CopyOnWriteEvent, Dml, HasStoragePreference, ObjectReference},
-    storage_pool::{DiskOffset, GlobalDiskId},
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    data_management::{CopyOnWriteEvent, DeltaBase, Dml, HasStoragePreference, ObjectReference},
+    storage_pool::{DiskOffset, GlobalDiskId, StoragePreference},
     tree::{DefaultMessageAction, Node, Tree, TreeLayer},
     vdev::Block,
 };
 use owning_ref::OwningRef;
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use seqlock::SeqLock;
+use serde::Serialize;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
 };
 
-/// Returns a message for updating the allocation bitmap.
+/// Returns a message for updating the allocation bitmap of the segment of
+/// `offset`. The range must not exceed this segment, see [Extent::segments].
 pub fn update_allocation_bitmap_msg(
     offset: DiskOffset,
     size: Block<u32>,
@@ -37,72 +43,211 @@ pub fn update_storage_info(info: &StorageInfo) -> Option<SlicedCowBytes> {
     bincode::serialize(info)
         .ok()
         .as_ref()
-        .map(|bytes| DefaultMessageAction::upsert_msg(0, bytes))
+        .map(|bytes| DefaultMessageAction::insert_msg(&bytes[..]))
 }
 
 /// The database handler, holding management data for interactions
 /// between the database and data management layers.
 pub struct Handler<OR: ObjectReference> {
-    // The version of the root tree which is initially present at the start of the process.
-    pub(crate) root_tree_inner: AtomicOption<Arc<TreeInner<OR, DefaultMessageAction>>>,
-    // An updated version of the root tree from this session, created after first sync.
-    pub(crate) root_tree_snapshot: RwLock<Option<TreeInner<OR, DefaultMessageAction>>>,
+    // The allocation tree of each storage class, holding the allocation
+    // bitmaps and disk space accounting of this class.
+    pub(crate) allocation_trees: Vec<AtomicOption<Arc<TreeInner<OR, DefaultMessageAction>>>>,
+    // The versions of the allocation trees written by the last sync.
+    pub(crate) allocation_tree_snapshots: Vec<RwLock<Option<TreeInner<OR, DefaultMessageAction>>>>,
     pub(crate) current_generation: SeqLock<Generation>,
     // Free Space counted as blocks
-    pub(crate) free_space: HashMap<GlobalDiskId, AtomicStorageInfo>,
+    pub(crate) free_space: RwLock<HashMap<GlobalDiskId, AtomicStorageInfo>>,
     pub(crate) free_space_tier: Vec<AtomicStorageInfo>,
-    pub(crate) delayed_messages: Mutex<Vec<(Box<[u8]>, SlicedCowBytes)>>,
+    pub(crate) delayed_messages: Mutex<DelayedMessages>,
+    // Messages for the allocation tree of each storage class.
+    pub(crate) delayed_allocation_messages: Vec<Mutex<DelayedMessages>>,
     pub(crate) last_snapshot_generation: RwLock<HashMap<DatasetId, Generation>>,
-    // Cache for allocators which have been in use since the last sync. This is
-    // done to avoid cyclical updates on evictions.
-    // NOTE: This map needs to be updated/emptied on sync's as the internal
-    // representation is not updated on deallocation to avoid overwriting
-    // potentially valid fallback data.
-    pub(crate) allocators: RwLock<HashMap<SegmentId, RwLock<SegmentAllocator>>>,
+    // The generations of all checkpoints, blocks born up to one of these
+    // generations and removed after it are retained on the dead lists, see
+    // `Database::delete_checkpoint`.
+    pub(crate) checkpoint_generations: RwLock<BTreeSet<Generation>>,
+    // The generations pinned by read views of each dataset, see
+    // `Database::open_read_view`.
+    pub(crate) read_views: Mutex<HashMap<DatasetId, ReadViewPins<OR::ObjectPointer>>>,
+    // Held shared while a transaction applies its writes and exclusively
+    // while syncing, so that the writes of a transaction become durable
+    // together, see `Transaction::commit`.
+    pub(crate) commit_lock: RwLock<()>,
+    // Blocks reserved for the future writes of datasets, see
+    // `Database::reserve`.
+    pub(crate) reservations: Mutex<Reservations>,
+    // The share of each storage class which only the root and allocation
+    // trees may allocate, so that syncs can complete on a full pool.
+    pub(crate) headroom: f32,
+    // The storage preference of the root and allocation trees.
+    pub(crate) root_tree_storage_preference: StoragePreference,
+    // Whether datasets may only be modified by replication, see
+    // `Database::apply_replication`.
+    pub(crate) follower: bool,
+    // Nodes born up to this generation may still be in an older on-disk
+    // format, see `Database::upgrade_format`.
+    pub(crate) legacy_generation: RwLock<Option<Generation>>,
+    // Space usage of each dataset, persisted in the root tree.
+    pub(crate) dataset_usage: RwLock<HashMap<DatasetId, SpaceUsage>>,
+    // The datasets whose usage has changed since it has last been queued for
+    // the root tree, see `queue_dataset_usage`.
+    pub(crate) changed_dataset_usage: Mutex<HashSet<DatasetId>>,
+    // The quota groups limiting the usage of datasets, see
+    // `Database::create_quota_group`.
+    pub(crate) quota_groups: RwLock<QuotaGroups>,
+    // Disks on which no blocks are allocated and whose deallocations are
+    // deferred until they are unsealed, see `Database::seal_disk`.
+    pub(crate) sealed_disks: RwLock<HashSet<GlobalDiskId>>,
+    // The bases of the leaves written as deltas by the offset of the delta,
+    // see `DatabaseConfiguration::delta_encoding`.
+    pub(crate) delta_bases: RwLock<HashMap<DiskOffset, DeltaBase<OR::ObjectPointer>>>,
+    // The number of deltas and modified leaves referring to each base by its
+    // offset.  A base is deallocated once the last of them is gone.
+    pub(crate) delta_base_refs: Mutex<HashMap<DiskOffset, u32>>,
+    // Cache for allocators which have been in use recently. This is done to
+    // avoid cyclical updates on evictions and to save reading the bitmaps
+    // from the allocation trees.
+    // NOTE: The internal representation is not updated on deallocation to
+    // avoid overwriting potentially valid fallback data.  Deallocations are
+    // applied on the generation bump after they have been written, see
+    // `written_segment_updates`.
+    pub(crate) allocators: RwLock<HashMap<SegmentId, CachedAllocator>>,
+    // The bitmap updates which have been written to the allocation trees in
+    // the current generation.
+    pub(crate) written_segment_updates: Mutex<BTreeMap<Box<[u8]>, SegmentUpdate>>,
+    // The strategy of the segment allocators of each storage class.
+    pub(crate) allocator_types: Vec<AllocatorType>,
     pub(crate) allocations: AtomicU64,
-    pub(crate) old_root_allocation: SeqLock<Option<(DiskOffset, Block<u32>)>>,
+    // The root nodes written by the last sync, their allocations are only
+    // recorded in the following generation.
+    pub(crate) old_root_allocations: RwLock<Vec<(DiskOffset, Block<u32>)>>,
+    // The blocks holding nodes of the second-level cache, which are never
+    // recorded in the allocation trees.
+    pub(crate) cache_allocations: RwLock<HashMap<DiskOffset, Block<u32>>>,
+    // Blocks of the second-level cache which have been freed in the current
+    // generation.  Like other deallocations, they are applied on the
+    // generation bump, when pending writes to them have completed.
+    pub(crate) freed_cache_allocations: Mutex<Vec<(DiskOffset, Block<u32>)>>,
+    // The handlers notified about events in all layers.
+    pub(crate) events: Arc<EventHandlers>,
 }
 
 impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
-    fn current_root_tree<'a, X>(&'a self, dmu: &'a X) -> impl TreeLayer<DefaultMessageAction> + 'a
+    fn current_allocation_tree<'a, X>(
+        &'a self,
+        class: u8,
+        dmu: &'a X,
+    ) -> impl TreeLayer<DefaultMessageAction> + 'a
     where
         X: Dml<Object = Node<OR>, ObjectRef = OR, ObjectPointer = OR::ObjectPointer>,
     {
         Tree::from_inner(
-            self.root_tree_inner.get().unwrap().as_ref(),
+            self.allocation_trees[class as usize]
+                .get()
+                .unwrap()
+                .as_ref(),
             dmu,
             false,
-            super::ROOT_TREE_STORAGE_PREFERENCE,
+            self.root_tree_storage_preference,
         )
     }
 
-    fn last_root_tree<'a, X>(
+    fn last_allocation_tree<'a, X>(
         &'a self,
+        class: u8,
         dmu: &'a X,
     ) -> Option<impl TreeLayer<DefaultMessageAction> + 'a>
     where
         X: Dml<Object = Node<OR>, ObjectRef = OR, ObjectPointer = OR::ObjectPointer>,
     {
-        OwningRef::new(self.root_tree_snapshot.read())
+        OwningRef::new(self.allocation_tree_snapshots[class as usize].read())
             .try_map(|lock| lock.as_ref().ok_or(()))
             .ok()
-            .map(|inner| Tree::from_inner(inner, dmu, false, super::ROOT_TREE_STORAGE_PREFERENCE))
+            .map(|inner| Tree::from_inner(inner, dmu, false, self.root_tree_storage_preference))
     }
 
+    // Brings the cached allocators up to date with the written generation,
+    // as if they were read from the allocation trees again.  Allocators
+    // which have not been used during the generation are dropped.
     pub(super) fn bump_generation(&self) {
-        self.allocators.write().clear();
+        let updates = std::mem::take(&mut *self.written_segment_updates.lock());
+        let freed_cache_allocations = std::mem::take(&mut *self.freed_cache_allocations.lock());
+        let mut allocators = self.allocators.write();
+        allocators.retain(|_, cached| cached.used.swap(false, Ordering::Relaxed));
+        for (&id, cached) in allocators.iter() {
+            let mut allocator = cached.allocator.write();
+            if let Some(update) = updates.get(&segment::id_to_key(id)[..]) {
+                update.apply(&mut allocator);
+            }
+            for &(offset, size) in freed_cache_allocations.iter() {
+                for (segment_id, segment_offset, len) in Extent::new(offset, size).segments() {
+                    if segment_id == id {
+                        allocator.deallocate(segment_offset, len);
+                    }
+                }
+            }
+            self.mark_unrecorded_allocations(id, &mut allocator);
+        }
+        drop(allocators);
         self.current_generation.lock_write().0 += 1;
     }
+
+    // Marks the blocks of the segment `id` which are in use but not recorded
+    // in the allocation trees.
+    fn mark_unrecorded_allocations(&self, id: SegmentId, allocator: &mut SegmentAllocator) {
+        let old_root_allocations = self.old_root_allocations.read();
+        let cache_allocations = self.cache_allocations.read();
+        let unrecorded = old_root_allocations.iter().copied().chain(
+            cache_allocations
+                .iter()
+                .map(|(&offset, &size)| (offset, size)),
+        );
+        for (offset, size) in unrecorded {
+            for (segment_id, segment_offset, len) in Extent::new(offset, size).segments() {
+                if segment_id == id {
+                    allocator.allocate_at(len, segment_offset);
+                }
+            }
+        }
+    }
+}
+
+// The read views of a dataset.
+pub(crate) struct ReadViewPins<P> {
+    // The root node and the number of open views of each generation.
+    generations: BTreeMap<Generation, (P, usize)>,
+    // The generation in which blocks of the dataset have been deallocated
+    // the last time.  Older generations cannot be pinned afterwards, as their
+    // blocks may have been among them.
+    last_removal: Option<Generation>,
+}
+
+impl<P> Default for ReadViewPins<P> {
+    fn default() -> Self {
+        ReadViewPins {
+            generations: BTreeMap::new(),
+            last_removal: None,
+        }
+    }
+}
+
+// A segment allocator in the cache of the handler.
+pub(crate) struct CachedAllocator {
+    allocator: RwLock<SegmentAllocator>,
+    // Whether the allocator has been accessed in the current generation.
+    used: AtomicBool,
 }
 
 pub struct SegmentAllocatorGuard<'a> {
-    inner: RwLockReadGuard<'a, HashMap<SegmentId, RwLock<SegmentAllocator>>>,
+    inner: RwLockReadGuard<'a, HashMap<SegmentId, CachedAllocator>>,
     id: SegmentId,
 }
 
 impl<'a> SegmentAllocatorGuard<'a> {
     pub fn access(&self) -> RwLockWriteGuard<SegmentAllocator> {
-        self.inner.get(&self.id).unwrap().write()
+        let cached = self.inner.get(&self.id).unwrap();
+        cached.used.store(true, Ordering::Relaxed);
+        cached.allocator.write()
     }
 }
 
@@ -111,25 +256,175 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
         self.current_generation.read()
     }
 
+    /// Pins `generation` of the dataset with the root node `root` for a
+    /// read view, so that blocks written up to it are retained.  Fails if
+    /// blocks of the dataset have been deallocated after this generation.
+    pub(crate) fn pin_read_view(
+        &self,
+        dataset_id: DatasetId,
+        generation: Generation,
+        root: OR::ObjectPointer,
+    ) -> bool {
+        let mut read_views = self.read_views.lock();
+        let pins = read_views.entry(dataset_id).or_default();
+        if pins.last_removal > Some(generation) {
+            return false;
+        }
+        pins.generations.entry(generation).or_insert((root, 0)).1 += 1;
+        true
+    }
+
+    /// Releases a pin of [Handler::pin_read_view].
+    pub(crate) fn unpin_read_view(&self, dataset_id: DatasetId, generation: Generation) {
+        let mut read_views = self.read_views.lock();
+        if let Some(pins) = read_views.get_mut(&dataset_id) {
+            if let Some((_, count)) = pins.generations.get_mut(&generation) {
+                *count -= 1;
+                if *count == 0 {
+                    pins.generations.remove(&generation);
+                }
+            }
+        }
+    }
+
+    /// Returns whether the nodes of the dataset `dataset_id` may be written
+    /// as deltas.  The nodes of the root and allocation trees never are.
+    pub(crate) fn supports_deltas(&self, dataset_id: DatasetId) -> bool {
+        dataset_id != ROOT_DATASET_ID
+    }
+
+    /// Returns the base of the node of the dataset `dataset_id` at `offset`,
+    /// if it has been written as a delta.
+    pub(crate) fn delta_base(
+        &self,
+        offset: DiskOffset,
+        dataset_id: DatasetId,
+    ) -> Option<DeltaBase<OR::ObjectPointer>>
+    where
+        OR::ObjectPointer: Clone,
+    {
+        if !self.supports_deltas(dataset_id) {
+            return None;
+        }
+        self.delta_bases.read().get(&offset).cloned()
+    }
+
+    /// Records that the node written at `offset` is a delta of `base`, which
+    /// takes over a reference to the base.  This becomes durable with the
+    /// next sync.
+    pub(crate) fn record_delta(&self, offset: DiskOffset, base: DeltaBase<OR::ObjectPointer>)
+    where
+        OR::ObjectPointer: Serialize,
+    {
+        let msg = DefaultMessageAction::insert_msg(&bincode::serialize(&base).unwrap());
+        self.delta_bases.write().insert(offset, base);
+        self.delayed_messages
+            .lock()
+            .push(delta_base::key(offset)[..].into(), msg);
+    }
+
+    /// Forgets the base of the node at `offset` once the node has been
+    /// deallocated and returns it, the reference of the node to the base has
+    /// to be released by the caller.
+    pub(crate) fn remove_delta(&self, offset: DiskOffset) -> Option<DeltaBase<OR::ObjectPointer>> {
+        if !self.delta_bases.read().contains_key(&offset) {
+            return None;
+        }
+        let base = self.delta_bases.write().remove(&offset)?;
+        self.delayed_messages.lock().push(
+            delta_base::key(offset)[..].into(),
+            DefaultMessageAction::delete_msg(),
+        );
+        Some(base)
+    }
+
+    /// Adds a reference to the base at `offset`, which keeps it from being
+    /// deallocated.
+    pub(crate) fn retain_delta_base(&self, offset: DiskOffset) {
+        *self.delta_base_refs.lock().entry(offset).or_insert(0) += 1;
+    }
+
+    /// Drops a reference to the base at `offset` and returns whether it has
+    /// been the last one, in which case the base has to be deallocated.
+    pub(crate) fn release_delta_base(&self, offset: DiskOffset) -> bool {
+        let mut refs = self.delta_base_refs.lock();
+        let count = refs
+            .get_mut(&offset)
+            .expect("Released a delta base without references");
+        *count -= 1;
+        if *count == 0 {
+            refs.remove(&offset);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the generations of the dataset pinned by read views.
+    pub(crate) fn read_view_generations(&self, dataset_id: DatasetId) -> Vec<Generation> {
+        self.read_views
+            .lock()
+            .get(&dataset_id)
+            .map(|pins| pins.generations.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the root nodes of all pinned read views.
+    pub(crate) fn read_view_roots(&self) -> Vec<(DatasetId, OR::ObjectPointer)>
+    where
+        OR::ObjectPointer: Clone,
+    {
+        let read_views = self.read_views.lock();
+        let mut roots = Vec::new();
+        for (&id, pins) in read_views.iter() {
+            roots.extend(
+                pins.generations
+                    .values()
+                    .map(|(root, _)| (id, root.clone())),
+            );
+        }
+        roots
+    }
+
     pub fn update_allocation_bitmap<X>(
         &self,
         offset: DiskOffset,
         size: Block<u32>,
         action: Action,
+        dataset_id: DatasetId,
         dmu: &X,
     ) -> Result<()>
     where
         X: Dml<Object = Node<OR>, ObjectRef = OR, ObjectPointer = OR::ObjectPointer>,
     {
+        if matches!(action, Action::Deallocate) && self.defer_deallocation(offset, size, dataset_id)
+        {
+            return Ok(());
+        }
         self.allocations.fetch_add(1, Ordering::Release);
-        let id = SegmentId::get(offset);
-        let key = segment::id_to_key(id);
+        match action {
+            Action::Allocate => {
+                self.reservations
+                    .lock()
+                    .consume(offset.storage_class(), dataset_id, size.as_u64())
+            }
+            Action::Deallocate => {
+                self.read_views
+                    .lock()
+                    .entry(dataset_id)
+                    .or_default()
+                    .last_removal = Some(self.current_generation.read());
+            }
+        }
+        self.update_dataset_usage(dataset_id, |usage| {
+            usage.update_physical(offset.storage_class(), Block(size.as_u64()), action)
+        });
         let disk_key = offset.class_disk_id();
-        let msg = update_allocation_bitmap_msg(offset, size, action);
         // NOTE: We perform double the amount of atomics here than necessary, but we do this for now to avoid reiteration
         match action {
             Action::Deallocate => {
                 self.free_space
+                    .read()
                     .get(&disk_key)
                     .expect("Could not find disk id in storage class")
                     .free
@@ -140,6 +435,7 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             }
             Action::Allocate => {
                 self.free_space
+                    .read()
                     .get(&disk_key)
                     .expect("Could not find disk id in storage class")
                     .free
@@ -150,15 +446,68 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             }
         };
 
-        let mut delayed_msgs = self.delayed_messages.lock();
-        delayed_msgs.push((key.into(), msg));
-        delayed_msgs.push((
+        let mut delayed_msgs =
+            self.delayed_allocation_messages[offset.storage_class() as usize].lock();
+        for (id, segment_offset, len) in Extent::new(offset, size).segments() {
+            delayed_msgs.push_bits(
+                segment::id_to_key(id).into(),
+                segment_offset,
+                len,
+                action.as_bool(),
+            );
+        }
+        delayed_msgs.push(
             space_accounting::key(disk_key).into(),
-            update_storage_info(&self.free_space.get(&disk_key).unwrap().into()).unwrap(),
-        ));
+            update_storage_info(&self.free_space.read().get(&disk_key).unwrap().into()).unwrap(),
+        );
         Ok(())
     }
 
+    // Records the deallocation of blocks on a sealed disk, which takes place
+    // once the disk is unsealed.  Returns whether the disk is sealed.
+    fn defer_deallocation(
+        &self,
+        offset: DiskOffset,
+        size: Block<u32>,
+        dataset_id: DatasetId,
+    ) -> bool {
+        // Keep the lock until the record is queued, so that unsealing the disk
+        // cannot miss it.
+        let sealed_disks = self.sealed_disks.read();
+        if !sealed_disks.contains(&offset.class_disk_id()) {
+            return false;
+        }
+        log::debug!("Deferred the deallocation of {size:?} at {offset:?} on a sealed disk");
+        self.delayed_messages.lock().push(
+            sealed_disk::deferred_key(offset).into(),
+            DefaultMessageAction::insert_msg(&sealed_disk::deferred_value(size, dataset_id)),
+        );
+        true
+    }
+
+    /// Returns whether all disks of `class` are sealed.
+    pub(crate) fn is_class_sealed(&self, class: u8) -> bool {
+        let sealed_disks = self.sealed_disks.read();
+        let free_space = self.free_space.read();
+        let mut disks = free_space
+            .keys()
+            .filter(|disk| disk.storage_class() == class)
+            .peekable();
+        disks.peek().is_some() && disks.all(|disk| sealed_disks.contains(disk))
+    }
+
+    // The free blocks of the sealed disks of `class`.
+    fn sealed_free_blocks(&self, class: u8) -> u64 {
+        let free_space = self.free_space.read();
+        self.sealed_disks
+            .read()
+            .iter()
+            .filter(|disk| disk.storage_class() == class)
+            .filter_map(|disk| free_space.get(disk))
+            .map(|info| info.free.load(Ordering::Relaxed))
+            .sum()
+    }
+
     pub fn get_allocation_bitmap<X>(&self, id: SegmentId, dmu: &X) -> Result<SegmentAllocatorGuard>
     where
         X: Dml<Object = Node<OR>, ObjectRef = OR, ObjectPointer = OR::ObjectPointer>,
@@ -174,8 +523,9 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
         let now = std::time::Instant::now();
         let mut bitmap = [0u8; SEGMENT_SIZE_BYTES];
 
+        let class = id.as_disk_offset().storage_class();
         let key = segment::id_to_key(id);
-        let segment = self.current_root_tree(dmu).get(&key[..])?;
+        let segment = self.current_allocation_tree(class, dmu).get(&key[..])?;
         log::info!(
             "fetched {:?} bitmap elements",
             segment.as_ref().map(|s| s.len())
@@ -186,7 +536,7 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             bitmap[..segment.len()].copy_from_slice(&segment[..]);
         }
 
-        if let Some(tree) = self.last_root_tree(dmu) {
+        if let Some(tree) = self.last_allocation_tree(class, dmu) {
             if let Some(old_segment) = tree.get(&key[..])? {
                 for (w, old) in bitmap.iter_mut().zip(old_segment.iter()) {
                     *w |= *old;
@@ -194,24 +544,104 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             }
         }
 
-        let mut allocator = SegmentAllocator::new(bitmap);
+        let mut allocator =
+            SegmentAllocator::with_type(bitmap, self.allocator_types[class as usize]);
 
-        if let Some((offset, size)) = self.old_root_allocation.read() {
-            if SegmentId::get(offset) == id {
-                allocator.allocate_at(size.as_u32(), SegmentId::get_block_offset(offset));
-            }
-        }
+        self.mark_unrecorded_allocations(id, &mut allocator);
 
         log::info!("requested allocation bitmap, took {:?}", now.elapsed());
 
-        self.allocators.write().insert(id, RwLock::new(allocator));
+        // Another thread might have read the bitmap in the meantime, its
+        // allocator may have been used already.
+        self.allocators
+            .write()
+            .entry(id)
+            .or_insert_with(|| CachedAllocator {
+                allocator: RwLock::new(allocator),
+                used: AtomicBool::new(true),
+            });
 
         let foo = self.allocators.read();
         Ok(SegmentAllocatorGuard { inner: foo, id })
     }
 
+    /// Frees the blocks of a node of the second-level cache staged at
+    /// `offset` on the next generation bump.
+    pub(crate) fn free_cache_allocation(&self, offset: DiskOffset) {
+        // Holding the allocators keeps the segment from being cached anew in
+        // between, which would already see the blocks as free.
+        let allocators = self.allocators.read();
+        if let Some(size) = self.cache_allocations.write().remove(&offset) {
+            if allocators.contains_key(&SegmentId::get(offset)) {
+                self.freed_cache_allocations.lock().push((offset, size));
+            }
+        }
+    }
+
+    /// Updates the logical size of the data referenced by a dataset by the
+    /// uncompressed size in bytes of a node.
+    pub fn update_logical_usage(&self, dataset_id: DatasetId, size: u32, action: Action) {
+        self.update_dataset_usage(dataset_id, |usage| {
+            usage.update_logical(size as u64, action)
+        });
+    }
+
+    // Changes are only kept in memory, they are queued for the root tree once
+    // per sync by `queue_dataset_usage`.
+    fn update_dataset_usage<F>(&self, dataset_id: DatasetId, f: F)
+    where
+        F: FnOnce(&mut SpaceUsage),
+    {
+        f(self.dataset_usage.write().entry(dataset_id).or_default());
+        self.changed_dataset_usage.lock().insert(dataset_id);
+    }
+
+    /// Queues the usage of the datasets which has changed since the last call
+    /// as messages for the root tree.
+    pub(crate) fn queue_dataset_usage(&self) {
+        let changed = std::mem::take(&mut *self.changed_dataset_usage.lock());
+        if changed.is_empty() {
+            return;
+        }
+        // Changes made after the set has been taken are queued with the next
+        // call, so that the latest usage is always persisted eventually.
+        let dataset_usage = self.dataset_usage.read();
+        let mut delayed_messages = self.delayed_messages.lock();
+        for dataset_id in changed {
+            let usage = dataset_usage.get(&dataset_id).copied().unwrap_or_default();
+            let msg = DefaultMessageAction::insert_msg(&bincode::serialize(&usage).unwrap());
+            delayed_messages.push(dataset_usage::key(dataset_id).into(), msg);
+        }
+    }
+
+    pub fn dataset_usage(&self, dataset_id: DatasetId) -> SpaceUsage {
+        self.dataset_usage
+            .read()
+            .get(&dataset_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of bytes allocated by the datasets of the group.
+    pub(crate) fn quota_group_usage(&self, group: &QuotaGroup) -> u64 {
+        let usage = self.dataset_usage.read();
+        group
+            .datasets()
+            .filter_map(|id| usage.get(&id))
+            .map(|usage| usage.physical_blocks.to_bytes())
+            .sum()
+    }
+
+    /// Returns the name of the quota group of the given dataset if the group
+    /// has reached its limit.
+    pub(crate) fn exceeded_quota(&self, dataset_id: DatasetId) -> Option<CowBytes> {
+        let groups = self.quota_groups.read();
+        let (name, group) = groups.group_of(dataset_id)?;
+        (self.quota_group_usage(group) >= group.limit()).then(|| name.clone())
+    }
+
     pub fn free_space_disk(&self, disk_id: GlobalDiskId) -> Option<StorageInfo> {
-        self.free_space.get(&disk_id).map(|elem| elem.into())
+        self.free_space.read().get(&disk_id).map(|elem| elem.into())
     }
 
     pub fn free_space_tier(&self, class: u8) -> Option<StorageInfo> {
@@ -220,6 +650,160 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             .map(|elem| elem.into())
     }
 
+    /// Returns the free blocks of `class` which allocations for the given
+    /// dataset may use.  Blocks reserved for other datasets are excluded, and
+    /// only the root and allocation trees may use the headroom of the class.
+    pub(crate) fn available_blocks(&self, class: u8, dataset_id: DatasetId) -> Block<u64> {
+        let tier = self.free_space_tier(class).expect("Has to exist");
+        let reservations = self.reservations.lock();
+        let claimed = self.sealed_free_blocks(class)
+            + if dataset_id == ROOT_DATASET_ID {
+                reservations.reserved(class)
+            } else {
+                reservations.reserved(class) - reservations.reserved_for(class, dataset_id)
+                    + self.headroom_blocks(tier)
+            };
+        Block(tier.free.as_u64().saturating_sub(claimed))
+    }
+
+    fn headroom_blocks(&self, tier: StorageInfo) -> u64 {
+        (tier.total.as_u64() as f64 * f64::from(self.headroom)).ceil() as u64
+    }
+
+    /// Reserves `blocks` of `class` for the given dataset if they are neither
+    /// reserved yet nor part of the headroom, see `Database::reserve`.
+    pub(crate) fn reserve(
+        &self,
+        class: u8,
+        dataset_id: DatasetId,
+        blocks: Block<u64>,
+    ) -> Option<u64> {
+        let tier = self.free_space_tier(class)?;
+        let mut reservations = self.reservations.lock();
+        let claimed = reservations.reserved(class)
+            + self.headroom_blocks(tier)
+            + self.sealed_free_blocks(class);
+        if tier.free.as_u64().saturating_sub(claimed) < blocks.as_u64() {
+            return None;
+        }
+        Some(reservations.insert(class, dataset_id, blocks.as_u64()))
+    }
+
+    /// Accounts for a disk which has been added to the running storage pool
+    /// with `free` usable blocks.
+    pub(crate) fn add_disk(&self, class: u8, disk_id: u16, free: Block<u64>) {
+        self.free_space.write().insert(
+            DiskOffset::construct_disk_id(class, disk_id),
+            AtomicStorageInfo {
+                free: AtomicU64::new(free.as_u64()),
+                total: AtomicU64::new(free.as_u64()),
+            },
+        );
+        let tier = &self.free_space_tier[class as usize];
+        tier.free.fetch_add(free.as_u64(), Ordering::Relaxed);
+        tier.total.fetch_add(free.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Raises the usable blocks of a disk to `total` if it has grown and
+    /// returns the number of blocks gained.
+    pub(crate) fn grow_disk(&self, class: u8, disk_id: u16, total: Block<u64>) -> Block<u64> {
+        let disk_key = DiskOffset::construct_disk_id(class, disk_id);
+        let free_space = self.free_space.read();
+        let info = free_space
+            .get(&disk_key)
+            .expect("Could not find disk id in storage class");
+        let gained = total
+            .as_u64()
+            .saturating_sub(info.total.fetch_max(total.as_u64(), Ordering::Relaxed));
+        if gained == 0 {
+            return Block(0);
+        }
+        info.free.fetch_add(gained, Ordering::Relaxed);
+        let tier = &self.free_space_tier[class as usize];
+        tier.free.fetch_add(gained, Ordering::Relaxed);
+        tier.total.fetch_add(gained, Ordering::Relaxed);
+        self.delayed_allocation_messages[class as usize]
+            .lock()
+            .push(
+                space_accounting::key(disk_key).into(),
+                update_storage_info(&info.into()).unwrap(),
+            );
+        Block(gained)
+    }
+
+    /// Forgets about a disk of `disk_size` blocks which is removed from the
+    /// running storage pool, including its allocation bitmaps and space
+    /// accounting in the allocation tree.
+    pub(crate) fn remove_disk(&self, class: u8, disk_id: u16, disk_size: Block<u64>) {
+        let disk_key = DiskOffset::construct_disk_id(class, disk_id);
+        if let Some(info) = self.free_space.write().remove(&disk_key) {
+            let tier = &self.free_space_tier[class as usize];
+            tier.free
+                .fetch_sub(info.free.load(Ordering::Relaxed), Ordering::Relaxed);
+            tier.total
+                .fetch_sub(info.total.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        let first = SegmentId::get(DiskOffset::new(class, disk_id, Block(0)));
+        let mut segment_ids = vec![first];
+        loop {
+            let next = segment_ids.last().unwrap().next(disk_size);
+            if next == first {
+                break;
+            }
+            segment_ids.push(next);
+        }
+        self.allocators
+            .write()
+            .retain(|id, _| id.as_disk_offset().class_disk_id() != disk_key);
+        let mut delayed_msgs = self.delayed_allocation_messages[class as usize].lock();
+        for id in segment_ids {
+            delayed_msgs.push(
+                segment::id_to_key(id).into(),
+                DefaultMessageAction::delete_msg(),
+            );
+        }
+        delayed_msgs.push(
+            space_accounting::key(disk_key).into(),
+            DefaultMessageAction::delete_msg(),
+        );
+    }
+
+    // The newest generation of the dataset `dataset_id` whose blocks are
+    // retained by a snapshot, checkpoint or read view.  All checkpoints
+    // precede the current generation, so the newest one contains a block if
+    // any does.
+    fn retained_generation(
+        &self,
+        dataset_id: DatasetId,
+        read_views: &HashMap<DatasetId, ReadViewPins<OR::ObjectPointer>>,
+    ) -> Option<Generation> {
+        self.last_snapshot_generation
+            .read()
+            .get(&dataset_id)
+            .cloned()
+            .max(
+                self.checkpoint_generations
+                    .read()
+                    .iter()
+                    .next_back()
+                    .copied(),
+            )
+            .max(
+                read_views
+                    .get(&dataset_id)
+                    .and_then(|pins| pins.generations.keys().next_back().copied()),
+            )
+    }
+
+    /// Returns whether a block of the dataset `dataset_id` written in
+    /// `generation` would be retained if it was removed now, so that
+    /// rewriting it elsewhere would not free its blocks.
+    pub(crate) fn is_retained(&self, generation: Generation, dataset_id: DatasetId) -> bool {
+        let read_views = self.read_views.lock();
+        self.retained_generation(dataset_id, &read_views) >= Some(generation)
+    }
+
     /// Marks blocks from removed objects to be removed if they are no longer needed.
     /// Checks for the existence of snapshots which included this data, if snapshots are found continue to hold this key as "dead" key.
     // copy on write is a bit of an unlucky name
@@ -230,24 +814,25 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
         generation: Generation,
         dataset_id: DatasetId,
     ) -> CopyOnWriteEvent {
-        if self
-            .last_snapshot_generation
-            .read()
-            .get(&dataset_id)
-            .cloned()
-            < Some(generation)
-        {
+        // Read views are only pinned if no block has been removed after their
+        // generation, the decision and its record must not be interleaved.
+        let mut read_views = self.read_views.lock();
+        if self.retained_generation(dataset_id, &read_views) < Some(generation) {
+            if self.defer_deallocation(offset, size, dataset_id) {
+                return CopyOnWriteEvent::Removed;
+            }
+            read_views.entry(dataset_id).or_default().last_removal =
+                Some(self.current_generation.read());
+            drop(read_views);
             // Deallocate
-            let id = SegmentId::get(offset);
-            let key = &segment::id_to_key(id) as &[_];
             log::debug!(
                 "Marked a block range {{ offset: {:?}, size: {:?} }} for deallocation",
                 offset,
                 size
             );
-            let msg = update_allocation_bitmap_msg(offset, size, Action::Deallocate);
             // NOTE: Update free size on both positions
             self.free_space
+                .read()
                 .get(&offset.class_disk_id())
                 .expect("Could not fetch disk id from storage class")
                 .free
@@ -255,13 +840,35 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             self.free_space_tier[offset.storage_class() as usize]
                 .free
                 .fetch_add(size.as_u64(), Ordering::Relaxed);
-            let mut delayed_msgs = self.delayed_messages.lock();
-            delayed_msgs.push((key.into(), msg));
-            delayed_msgs.push((
+            self.update_dataset_usage(dataset_id, |usage| {
+                usage.update_physical(
+                    offset.storage_class(),
+                    Block(size.as_u64()),
+                    Action::Deallocate,
+                )
+            });
+            let mut delayed_msgs =
+                self.delayed_allocation_messages[offset.storage_class() as usize].lock();
+            for (id, segment_offset, len) in Extent::new(offset, size).segments() {
+                delayed_msgs.push_bits(
+                    segment::id_to_key(id).into(),
+                    segment_offset,
+                    len,
+                    Action::Deallocate.as_bool(),
+                );
+            }
+            delayed_msgs.push(
                 Box::new(space_accounting::key(offset.class_disk_id())),
-                update_storage_info(&self.free_space.get(&offset.class_disk_id()).unwrap().into())
-                    .unwrap(),
-            ));
+                update_storage_info(
+                    &self
+                        .free_space
+                        .read()
+                        .get(&offset.class_disk_id())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap(),
+            );
             CopyOnWriteEvent::Removed
         } else {
             // Add to dead list
@@ -275,7 +882,7 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             .unwrap();
 
             let msg = DefaultMessageAction::insert_msg(&data);
-            self.delayed_messages.lock().push((key.into(), msg));
+            self.delayed_messages.lock().push(key.into(), msg);
             CopyOnWriteEvent::Preserved
         }
     }
@@ -0,0 +1,267 @@
+//! Ingestion of sorted key-value pairs into a dataset, e.g. to migrate the
+//! contents of an LSM-based store onto a storage pool, see [Dataset::ingest].
+//!
+//! Any iterator of entries can be ingested, including the range of another
+//! dataset.  Two dump formats can be read as well:
+//!
+//! - [BinaryDumpReader] reads a sequence of records, each consisting of the
+//!   length of the key, the key, the length of the value and the value, with
+//!   both lengths as big endian `u32`.  [write_binary_entry] writes a record.
+//! - [HexDumpReader] reads the output of the RocksDB tools
+//!   `ldb dump --hex` and `sst_dump --command=scan --output_hex`, so that a
+//!   whole RocksDB database or single SST files can be imported without
+//!   linking against RocksDB.
+use super::{errors::*, Dataset};
+use crate::{cow_bytes::CowBytes, tree::DefaultMessageAction, StoragePreference};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::{
+    io::{BufRead, ErrorKind, Read, Write},
+    ops::Deref,
+};
+
+// Larger lengths can only stem from a corrupted dump and would otherwise
+// lead to huge allocations.
+const MAX_DUMP_LENGTH: usize = crate::tree::MAX_MESSAGE_SIZE;
+
+/// The result of [Dataset::ingest].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IngestReport {
+    /// The number of ingested entries.
+    pub entries: u64,
+    /// The total size of the ingested keys and values in bytes.
+    pub bytes: u64,
+}
+
+impl Dataset<DefaultMessageAction> {
+    /// Inserts the given entries, whose keys have to be in strictly ascending
+    /// order, with the given storage preference.
+    ///
+    /// As consecutive entries end up in the same leaves, the tree is filled
+    /// from left to right and each node is flushed and written once instead
+    /// of being revisited for random keys.  Existing values of ingested keys
+    /// are overwritten.
+    ///
+    /// Ingestion stops at the first error of `entries` or with
+    /// [Error::UnsortedKeys] at the first key which is not larger than its
+    /// predecessor.  The entries before it remain inserted.  Like all other
+    /// insertions, the ingested entries are durable after the next
+    /// [Database::sync](super::Database::sync).
+    pub fn ingest<I, K, V>(&self, entries: I, pref: StoragePreference) -> Result<IngestReport>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+        K: Into<CowBytes>,
+        V: Deref<Target = [u8]>,
+    {
+        let mut report = IngestReport::default();
+        let mut previous: Option<CowBytes> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            let key = key.into();
+            if matches!(&previous, Some(p) if key[..] <= p[..]) {
+                return Err(Error::UnsortedKeys);
+            }
+            self.insert_with_pref(key.clone(), &value, pref)?;
+            report.entries += 1;
+            report.bytes += (key.len() + value.len()) as u64;
+            previous = Some(key);
+        }
+        Ok(report)
+    }
+}
+
+/// Writes an entry in the format read by [BinaryDumpReader].
+pub fn write_binary_entry<W: Write>(mut writer: W, key: &[u8], value: &[u8]) -> Result<()> {
+    if key.len() > MAX_DUMP_LENGTH || value.len() > MAX_DUMP_LENGTH {
+        return Err(Error::MessageTooLarge);
+    }
+    writer.write_u32::<BigEndian>(key.len() as u32)?;
+    writer.write_all(key)?;
+    writer.write_u32::<BigEndian>(value.len() as u32)?;
+    writer.write_all(value)?;
+    Ok(())
+}
+
+/// Reads the entries of a binary dump, see the [module documentation](self).
+pub struct BinaryDumpReader<R> {
+    reader: R,
+    record: u64,
+}
+
+impl<R: Read> BinaryDumpReader<R> {
+    /// Reads the entries from `reader`, which should be buffered.
+    pub fn new(reader: R) -> Self {
+        BinaryDumpReader { reader, record: 0 }
+    }
+
+    // Returns `None` if the reader is exhausted before the first byte.
+    fn read_length(&mut self, at_start: bool) -> Result<Option<usize>> {
+        let mut buf = [0; 4];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) if at_start && filled == 0 => return Ok(None),
+                Ok(0) => return Err(self.truncated()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = u32::from_be_bytes(buf) as usize;
+        if len > MAX_DUMP_LENGTH {
+            return Err(Error::InvalidDump(format!(
+                "record {} has a length of {} bytes",
+                self.record, len
+            )));
+        }
+        Ok(Some(len))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<CowBytes> {
+        let mut data = vec![0; len];
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => Ok(data.into()),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(self.truncated()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn truncated(&self) -> Error {
+        Error::InvalidDump(format!("record {} is truncated", self.record))
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(CowBytes, CowBytes)>> {
+        let key_len = match self.read_length(true)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let key = self.read_bytes(key_len)?;
+        let value_len = self.read_length(false)?.unwrap();
+        let value = self.read_bytes(value_len)?;
+        self.record += 1;
+        Ok(Some((key, value)))
+    }
+}
+
+impl<R: Read> Iterator for BinaryDumpReader<R> {
+    type Item = Result<(CowBytes, CowBytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// Reads the entries of the hexadecimal output of the RocksDB tools, see the
+/// [module documentation](self).
+///
+/// Lines of `ldb dump --hex` have the form `0x<key> ==> 0x<value>`.  Lines of
+/// `sst_dump --command=scan --output_hex` have the form
+/// `'<key>' seq:<seq>, type:<type> => <value>` and list all versions of a key
+/// from the newest to the oldest.  Only the newest version of each key is
+/// returned and keys whose newest version is a deletion are skipped.  Merge
+/// operands cannot be resolved without the merge operator of the database and
+/// are rejected, so such databases have to be compacted or exported with
+/// `ldb` first.  Range deletions are not part of the scan output and are
+/// therefore not applied.
+///
+/// Other lines, like the file names and statistics printed by the tools, are
+/// ignored.
+pub struct HexDumpReader<R> {
+    reader: R,
+    line: String,
+    line_number: u64,
+    // The key of the last line of `sst_dump`, whose older versions follow it.
+    last_key: Option<CowBytes>,
+}
+
+// Value types of RocksDB's internal keys.
+const TYPE_DELETION: u8 = 0x0;
+const TYPE_VALUE: u8 = 0x1;
+const TYPE_MERGE: u8 = 0x2;
+const TYPE_SINGLE_DELETION: u8 = 0x7;
+const TYPE_DELETION_WITH_TIMESTAMP: u8 = 0x14;
+
+impl<R: BufRead> HexDumpReader<R> {
+    /// Reads the entries from `reader`.
+    pub fn new(reader: R) -> Self {
+        HexDumpReader {
+            reader,
+            line: String::new(),
+            line_number: 0,
+            last_key: None,
+        }
+    }
+
+    fn invalid(&self, what: &str) -> Error {
+        Error::InvalidDump(format!("{} in line {}", what, self.line_number))
+    }
+
+    fn decode(&self, hex: &str) -> Result<CowBytes> {
+        decode_hex(hex)
+            .map(CowBytes::from)
+            .ok_or_else(|| self.invalid("invalid hexadecimal string"))
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(CowBytes, CowBytes)>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let line = self.line.trim();
+
+            // Empty values leave the separator at the end of the line.
+            if let Some((key, value)) = line.split_once(" ==>") {
+                let value = value.trim_start();
+                let (key, value) = match (key.strip_prefix("0x"), value.strip_prefix("0x")) {
+                    (Some(key), Some(value)) => (key, value),
+                    _ => return Err(self.invalid("missing 0x prefix")),
+                };
+                return Ok(Some((self.decode(key)?, self.decode(value)?)));
+            }
+
+            if let Some((internal_key, value)) = line.split_once(" =>") {
+                let value = value.trim_start();
+                let (key, value_type) = self
+                    .parse_internal_key(internal_key)
+                    .ok_or_else(|| self.invalid("invalid internal key"))?;
+                let key = self.decode(key)?;
+                if self.last_key.as_ref() == Some(&key) {
+                    continue;
+                }
+                self.last_key = Some(key.clone());
+                match value_type {
+                    TYPE_VALUE => return Ok(Some((key, self.decode(value)?))),
+                    TYPE_DELETION | TYPE_SINGLE_DELETION | TYPE_DELETION_WITH_TIMESTAMP => continue,
+                    TYPE_MERGE => return Err(self.invalid("unresolved merge operand")),
+                    _ => return Err(self.invalid("unsupported value type")),
+                }
+            }
+        }
+    }
+
+    // Splits `'<key>' seq:<seq>, type:<type>` into the key and the type.
+    fn parse_internal_key<'a>(&self, internal_key: &'a str) -> Option<(&'a str, u8)> {
+        let (key, rest) = internal_key.strip_prefix('\'')?.split_once('\'')?;
+        let value_type = rest.split_once("type:")?.1.trim().parse().ok()?;
+        Some((key, value_type))
+    }
+}
+
+impl<R: BufRead> Iterator for HexDumpReader<R> {
+    type Item = Result<(CowBytes, CowBytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
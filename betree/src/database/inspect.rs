@@ -0,0 +1,328 @@
+//! Offline inspection of the on-disk structures of a storage pool, e.g. for
+//! debugging and consistency checking tools.
+//!
+//! An [Inspector] reads the newest usable superblock of a pool and decodes
+//! nodes directly from disk.  Neither a cache nor an allocation handler is
+//! involved and nothing is written to the pool, so the inspected state is the
+//! one of the last completed sync.  The pool should not be in use by a
+//! [Database](super::Database) at the same time, as nodes may then be
+//! overwritten while they are read.
+use super::{
+    errors::*,
+    root_tree_msg::{
+        allocation_tree, dataset, dataset_dictionary, delta_base, segment, snapshot,
+        DATASET_NAME_TO_ID,
+    },
+    snapshot::unpack_snapshot_created,
+    DatabaseConfiguration, DatasetData, DatasetId, Generation, Object, ObjectPointer, RootSpu,
+    Superblock,
+};
+use crate::{
+    allocator::{SegmentAllocator, SegmentId, SEGMENT_SIZE_BYTES},
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    data_management::{DeltaBase, Error as DmlError, Object as _, ObjectReference},
+    encryption::{self, Encryption},
+    storage_pool::DiskOffset,
+    tree::{DefaultMessageAction, MessageAction, Node, NodeContents},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::SystemTime,
+};
+
+/// A node of a tree as stored on disk.
+#[derive(Debug, Clone)]
+pub struct NodeRecord {
+    /// The height of the node in its tree, leaves are at level 0.
+    pub level: u32,
+    /// The pointers to the children of an internal node from left to right,
+    /// empty for leaves.
+    pub children: Vec<ObjectPointer>,
+    /// The number of entries of a leaf, or the number of messages buffered
+    /// in an internal node.
+    pub entries: usize,
+}
+
+/// A data set as recorded in the root tree.
+#[derive(Debug, Clone)]
+pub struct DatasetRecord {
+    /// The name of the data set.
+    pub name: CowBytes,
+    /// The internal id of the data set.
+    pub id: DatasetId,
+    /// The pointer to the root node of the tree of the data set.
+    pub root: ObjectPointer,
+    /// The generation of the latest snapshot of the data set, if any.
+    pub previous_snapshot: Option<Generation>,
+}
+
+/// A snapshot as recorded in the root tree.
+#[derive(Debug, Clone)]
+pub struct SnapshotRecord {
+    /// The name of the snapshot.
+    pub name: CowBytes,
+    /// The generation of the data set captured by the snapshot.
+    pub generation: Generation,
+    /// The time of creation, not known for snapshots created by earlier
+    /// versions.
+    pub created: Option<SystemTime>,
+    /// The pointer to the root node of the tree of the snapshot.
+    pub root: ObjectPointer,
+}
+
+/// Reads the structures of a storage pool without opening a
+/// [Database](super::Database), see the [module documentation](self).
+pub struct Inspector {
+    pool: RootSpu,
+    encryption: Option<Encryption>,
+    superblock: Superblock<ObjectPointer>,
+    // The bases of the leaves written as deltas by the offset of the delta.
+    delta_bases: HashMap<DiskOffset, ObjectPointer>,
+    // The compression dictionaries by dataset and dictionary id.
+    dictionaries: HashMap<(DatasetId, u32), SlicedCowBytes>,
+}
+
+impl Inspector {
+    /// Opens the storage pool of `cfg` and reads its newest superblock.  Only
+    /// the storage pool and the encryption settings of `cfg` are used.
+    pub fn open(cfg: &DatabaseConfiguration) -> Result<Self> {
+        let pool = cfg.new_spu()?;
+        let encryption = cfg.encryption.as_ref().map(Encryption::new).transpose()?;
+        let superblock = Superblock::fetch_superblocks(&pool)?.ok_or(Error::InvalidSuperblock)?;
+        let mut inspector = Inspector {
+            pool,
+            encryption,
+            superblock,
+            delta_bases: HashMap::new(),
+            dictionaries: HashMap::new(),
+        };
+        // The root tree itself never holds deltas or nodes compressed with a
+        // dictionary.
+        let low = &delta_base::min_key() as &[_];
+        let high = &delta_base::max_key() as &[_];
+        inspector.delta_bases = inspector
+            .root_entries()?
+            .range::<[u8], _>(low..high)
+            .map(|(key, value)| {
+                let delta: DeltaBase<ObjectPointer> = bincode::deserialize(value)?;
+                Ok((delta_base::read_key(key), delta.base))
+            })
+            .collect::<Result<_>>()?;
+        let low = &dataset_dictionary::min_key() as &[_];
+        let high = &dataset_dictionary::max_key() as &[_];
+        inspector.dictionaries = inspector
+            .root_entries()?
+            .range::<[u8], _>(low..high)
+            .map(|(key, value)| (dataset_dictionary::read_key(key), value.clone()))
+            .collect();
+        Ok(inspector)
+    }
+
+    /// Returns the superblock the inspection starts from.
+    pub fn superblock(&self) -> &Superblock<ObjectPointer> {
+        &self.superblock
+    }
+
+    /// Returns the pointer to the root node of the root tree, which holds the
+    /// metadata of all data sets, snapshots and allocations.
+    pub fn root_pointer(&self) -> &ObjectPointer {
+        self.superblock.root_pointer()
+    }
+
+    fn read(&self, ptr: &ObjectPointer) -> Result<NodeContents<super::ObjectRef>> {
+        Ok(self.read_object(ptr)?.into_contents())
+    }
+
+    // Reads the node `ptr` points to, a delta is applied to its base.
+    fn read_object(&self, ptr: &ObjectPointer) -> Result<Node<super::ObjectRef>> {
+        let data = ptr
+            .read_from(&self.pool)
+            .map_err(|source| DmlError::ReadFailed {
+                dataset: ptr.info(),
+                offset: ptr.offset(),
+                size: ptr.size(),
+                source,
+            })?;
+        let compressed_data = encryption::decrypt(
+            self.encryption.as_ref(),
+            ptr.encryption_tag(),
+            data,
+            ptr.offset(),
+        )?;
+        let tag = ptr.decompression_tag();
+        let dictionary = tag
+            .dictionary()
+            .and_then(|id| self.dictionaries.get(&(ptr.info(), id)));
+        let data = tag
+            .new_decompression_with(dictionary.map(|dictionary| &dictionary[..]))
+            .and_then(|mut state| state.decompress(compressed_data))
+            .map_err(DmlError::from)?;
+        match self.delta_bases.get(&ptr.offset()) {
+            Some(base) => {
+                let mut node = self.read_object(base)?;
+                node.apply_delta(&data)?;
+                Ok(node)
+            }
+            None => Ok(Object::unpack_at(ptr.offset(), ptr.info(), data)?),
+        }
+    }
+
+    /// Reads and decodes the node `ptr` points to, verifying its checksum.
+    pub fn read_node(&self, ptr: &ObjectPointer) -> Result<NodeRecord> {
+        Ok(match self.read(ptr)? {
+            NodeContents::Internal { level, children } => NodeRecord {
+                level,
+                entries: children.iter().map(|(_, messages)| messages.len()).sum(),
+                children: children
+                    .iter()
+                    .map(|(child, _)| unmodified(child))
+                    .collect::<Result<_>>()?,
+            },
+            NodeContents::Leaf(entries) => NodeRecord {
+                level: 0,
+                children: Vec::new(),
+                entries: entries.len(),
+            },
+        })
+    }
+
+    /// Visits all nodes of the tree below `root` depth-first, parents before
+    /// their children.  Nodes which cannot be read are passed as errors and
+    /// their children are skipped, so that a single damaged node does not
+    /// hide the rest of the tree.
+    pub fn walk<F>(&self, root: &ObjectPointer, mut visit: F)
+    where
+        F: FnMut(&ObjectPointer, Result<&NodeRecord>),
+    {
+        let mut pending = vec![root.clone()];
+        while let Some(ptr) = pending.pop() {
+            match self.read_node(&ptr) {
+                Ok(node) => {
+                    visit(&ptr, Ok(&node));
+                    pending.extend(node.children.into_iter().rev());
+                }
+                Err(e) => visit(&ptr, Err(e)),
+            }
+        }
+    }
+
+    /// Returns all entries of the tree below `root` with the messages
+    /// buffered in internal nodes applied by `msg_action`, which has to be
+    /// the message action the tree has been written with.
+    ///
+    /// The entries are held in memory, which is only viable for the
+    /// internal trees and small data sets.
+    pub fn entries<M: MessageAction>(
+        &self,
+        root: &ObjectPointer,
+        msg_action: &M,
+    ) -> Result<BTreeMap<CowBytes, SlicedCowBytes>> {
+        match self.read(root)? {
+            NodeContents::Leaf(entries) => Ok(entries.into_iter().collect()),
+            NodeContents::Internal { children, .. } => {
+                let mut entries = BTreeMap::new();
+                for (child, messages) in children {
+                    let mut below = self.entries(unmodified(&child)?, msg_action)?;
+                    // Buffered messages are newer than anything further down.
+                    for (key, msg) in messages {
+                        let mut data = below.remove(&key);
+                        msg_action.apply_to_leaf(&key, msg, &mut data);
+                        if let Some(data) = data {
+                            below.insert(key, data);
+                        }
+                    }
+                    entries.append(&mut below);
+                }
+                Ok(entries)
+            }
+        }
+    }
+
+    fn root_entries(&self) -> Result<BTreeMap<CowBytes, SlicedCowBytes>> {
+        self.entries(self.root_pointer(), &DefaultMessageAction)
+    }
+
+    /// Lists all data sets including the internal data sets of object
+    /// stores, ordered by their name.
+    pub fn datasets(&self) -> Result<Vec<DatasetRecord>> {
+        let entries = self.root_entries()?;
+        let low = &[DATASET_NAME_TO_ID] as &[_];
+        let high = &[DATASET_NAME_TO_ID + 1] as &[_];
+        entries
+            .range::<[u8], _>(low..high)
+            .map(|(key, value)| {
+                let id = DatasetId::unpack(value);
+                let data = entries
+                    .get(&dataset::data_key(id)[..])
+                    .ok_or(Error::DoesNotExist)?;
+                let data = DatasetData::<ObjectPointer>::unpack(data)?;
+                Ok(DatasetRecord {
+                    name: CowBytes::from(&key[1..]),
+                    id,
+                    root: data.ptr,
+                    previous_snapshot: data.previous_snapshot,
+                })
+            })
+            .collect()
+    }
+
+    /// Lists all snapshots of the data set `dataset` ordered by their
+    /// generation.
+    pub fn snapshots(&self, dataset: DatasetId) -> Result<Vec<SnapshotRecord>> {
+        let entries = self.root_entries()?;
+        let low = &snapshot::min_key(dataset) as &[_];
+        let high = &snapshot::max_key(dataset) as &[_];
+        let mut snapshots = entries
+            .range::<[u8], _>(low..high)
+            .map(|(key, value)| {
+                let generation = Generation::unpack(value);
+                let data = entries
+                    .get(&snapshot::data_key(dataset, generation)[..])
+                    .ok_or(Error::DoesNotExist)?;
+                Ok(SnapshotRecord {
+                    name: CowBytes::from(snapshot::name_from_key(key)),
+                    generation,
+                    created: unpack_snapshot_created(value),
+                    root: DatasetData::<ObjectPointer>::unpack(data)?.ptr,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        snapshots.sort_by_key(|snapshot| snapshot.generation);
+        Ok(snapshots)
+    }
+
+    /// Returns the pointer to the root node of the allocation tree of the
+    /// storage class `class`, which holds the segment bitmaps of the class.
+    pub fn allocation_tree(&self, class: u8) -> Result<ObjectPointer> {
+        let ptr = self
+            .root_entries()?
+            .remove(&allocation_tree::key(class)[..])
+            .ok_or(Error::InvalidSuperblock)?;
+        Ok(bincode::deserialize(&ptr)?)
+    }
+
+    /// Returns the allocation bitmaps of all segments of the storage class
+    /// `class` which have ever been allocated from, ordered by their id.
+    pub fn segments(&self, class: u8) -> Result<Vec<(SegmentId, SegmentAllocator)>> {
+        let root = self.allocation_tree(class)?;
+        let entries = self.entries(&root, &DefaultMessageAction)?;
+        let low = &segment::min_key() as &[_];
+        let high = &segment::max_key() as &[_];
+        Ok(entries
+            .range::<[u8], _>(low..high)
+            .filter(|(key, _)| key.len() == segment::id_to_key(SegmentId(0)).len())
+            .map(|(key, bitmap)| {
+                let mut full = [0; SEGMENT_SIZE_BYTES];
+                let len = bitmap.len().min(SEGMENT_SIZE_BYTES);
+                full[..len].copy_from_slice(&bitmap[..len]);
+                (segment::read_key(key), SegmentAllocator::new(full))
+            })
+            .collect())
+    }
+}
+
+fn unmodified(child: &super::ObjectRef) -> Result<&ObjectPointer> {
+    child
+        .get_unmodified()
+        .ok_or(Error::Corrupted("node references an unwritten child"))
+}
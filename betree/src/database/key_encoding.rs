@@ -0,0 +1,847 @@
+//! An order-preserving binary encoding of [serde] types for the keys of a
+//! [TypedDataset](super::TypedDataset).
+//!
+//! The bytewise order of two encoded keys is the order of the original values,
+//! so that ranges of typed keys can be mapped onto ranges of the dataset:
+//!
+//! - Unsigned integers are stored in big endian.  Signed integers are stored
+//!   in big endian with the sign bit flipped, so that negative numbers sort
+//!   before positive ones.
+//! - Floats are ordered by their IEEE 754 total order, i.e. `-0.0` sorts
+//!   before `0.0` and NaNs sort at either end.
+//! - Strings and byte sequences are terminated by `0x00 0x00`, contained null
+//!   bytes are escaped as `0x00 0xff`.  A string therefore sorts before all
+//!   strings it is a prefix of, and is never confused with the fields
+//!   following it.
+//! - Tuples, structs and tuple structs are the concatenation of their fields,
+//!   sorting by the first field, then by the second and so on.
+//! - Sequences and maps mark each element with `0x01` and their end with
+//!   `0x00`.  Options are `0x00` for `None` or `0x01` followed by the value.
+//! - Enums store the index of their variant as big endian `u32` followed by
+//!   the content of the variant, sorting by the order of declaration.
+//!
+//! The encoding is not self-describing, a key can only be decoded into the
+//! type it has been encoded from.
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+use std::fmt;
+use thiserror::Error;
+
+const STRING_END: [u8; 2] = [0x00, 0x00];
+const ESCAPED_NULL: [u8; 2] = [0x00, 0xff];
+const ELEMENT: u8 = 0x01;
+const END: u8 = 0x00;
+const SIGN_BIT_64: u64 = 1 << 63;
+const SIGN_BIT_128: u128 = 1 << 127;
+
+/// An error while encoding or decoding a key.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// An error reported by the [Serialize] or [Deserialize](de::Deserialize)
+    /// implementation of a type.
+    #[error("{0}")]
+    Custom(String),
+    /// The key is shorter than the encoding of the type.
+    #[error("The key ended unexpectedly.")]
+    UnexpectedEnd,
+    /// The key is longer than the encoding of the type.
+    #[error("The key contains {0} trailing bytes.")]
+    TrailingBytes(usize),
+    /// The key contains bytes which are not valid for the type.
+    #[error("The key contains an invalid {0}.")]
+    Invalid(&'static str),
+    /// The type can only be decoded from a self-describing format.
+    #[error("The key encoding is not self-describing, the type has to be known.")]
+    NotSelfDescribing,
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+/// The result of encoding or decoding a key.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Encodes `value` in the order-preserving encoding.
+pub fn to_key<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut serializer = KeySerializer { output: Vec::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+/// Decodes a value of type `T` from `key`, which has to be consumed entirely.
+pub fn from_key<T: DeserializeOwned>(key: &[u8]) -> Result<T> {
+    let mut deserializer = KeyDeserializer { input: key };
+    let value = T::deserialize(&mut deserializer)?;
+    match deserializer.input.len() {
+        0 => Ok(value),
+        trailing => Err(Error::TrailingBytes(trailing)),
+    }
+}
+
+struct KeySerializer {
+    output: Vec<u8>,
+}
+
+impl KeySerializer {
+    fn write_escaped(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == 0 {
+                self.output.extend_from_slice(&ESCAPED_NULL);
+            } else {
+                self.output.push(byte);
+            }
+        }
+        self.output.extend_from_slice(&STRING_END);
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_u8(v as u8 ^ 0x80)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_u16(v as u16 ^ 0x8000)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_u32(v as u32 ^ 0x8000_0000)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.serialize_u64(v as u64 ^ SIGN_BIT_64)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.serialize_u128(v as u128 ^ SIGN_BIT_128)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        let bits = v.to_bits();
+        // Negative numbers are inverted so that larger magnitudes sort first.
+        if bits & 0x8000_0000 != 0 {
+            self.serialize_u32(!bits)
+        } else {
+            self.serialize_u32(bits ^ 0x8000_0000)
+        }
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        let bits = v.to_bits();
+        if bits & SIGN_BIT_64 != 0 {
+            self.serialize_u64(!bits)
+        } else {
+            self.serialize_u64(bits ^ SIGN_BIT_64)
+        }
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.output.push(END);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        self.output.push(ELEMENT);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl<'a> ser::SerializeSeq for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.output.push(ELEMENT);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.output.push(END);
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTuple for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.output.push(ELEMENT);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.output.push(END);
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStruct for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct KeyDeserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> KeyDeserializer<'de> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.input.len() < N {
+            return Err(Error::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.input.split_at(N);
+        self.input = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn take_u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn take_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn take_u128(&mut self) -> Result<u128> {
+        Ok(u128::from_be_bytes(self.take()?))
+    }
+
+    fn take_escaped(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.take_u8()? {
+                0 => match self.take_u8()? {
+                    0x00 => return Ok(bytes),
+                    0xff => bytes.push(0),
+                    _ => return Err(Error::Invalid("escape sequence")),
+                },
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    // Reads the marker in front of an element of a sequence, an option or a
+    // map, returning whether an element follows.
+    fn take_marker(&mut self) -> Result<bool> {
+        match self.take_u8()? {
+            END => Ok(false),
+            ELEMENT => Ok(true),
+            _ => Err(Error::Invalid("marker")),
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for &'a mut KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error::NotSelfDescribing)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error::NotSelfDescribing)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.take_u8()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(Error::Invalid("bool")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8((self.take_u8()? ^ 0x80) as i8)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16((self.take_u16()? ^ 0x8000) as i16)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32((self.take_u32()? ^ 0x8000_0000) as i32)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64((self.take_u64()? ^ SIGN_BIT_64) as i64)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i128((self.take_u128()? ^ SIGN_BIT_128) as i128)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.take_u8()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(self.take_u16()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(self.take_u64()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u128(self.take_u128()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = self.take_u32()?;
+        let bits = if bits & 0x8000_0000 != 0 {
+            bits ^ 0x8000_0000
+        } else {
+            !bits
+        };
+        visitor.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = self.take_u64()?;
+        let bits = if bits & SIGN_BIT_64 != 0 {
+            bits ^ SIGN_BIT_64
+        } else {
+            !bits
+        };
+        visitor.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let c = char::from_u32(self.take_u32()?).ok_or(Error::Invalid("char"))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let string =
+            String::from_utf8(self.take_escaped()?).map_err(|_| Error::Invalid("string"))?;
+        visitor.visit_string(string)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.take_escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.take_marker()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Marked { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Marked { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fixed {
+            de: self,
+            len: fields.len(),
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_u32(visitor)
+    }
+}
+
+// The elements of tuples and structs, whose number is known.
+struct Fixed<'a, 'de> {
+    de: &'a mut KeyDeserializer<'de>,
+    len: usize,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Fixed<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+// The elements of sequences and maps, each preceded by a marker.
+struct Marked<'a, 'de> {
+    de: &'a mut KeyDeserializer<'de>,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.de.take_marker()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if !self.de.take_marker()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for &'a mut KeyDeserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index = self.take_u32()?;
+        let variant = seed.deserialize(index.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for &'a mut KeyDeserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Fixed { de: self, len })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_seq(Fixed {
+            de: self,
+            len: fields.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Debug;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, PartialOrd, Clone)]
+    enum Kind {
+        File,
+        Link(String),
+        Directory { entries: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, PartialOrd, Clone)]
+    struct Path {
+        parent: u64,
+        name: String,
+        kind: Kind,
+    }
+
+    // Checks that the values, which have to be sorted, roundtrip and that
+    // their encodings are sorted as well.
+    fn assert_ordered<T>(values: &[T])
+    where
+        T: Serialize + DeserializeOwned + Debug + PartialOrd,
+    {
+        let keys: Vec<_> = values.iter().map(|v| to_key(v).unwrap()).collect();
+        for (value, key) in values.iter().zip(keys.iter()) {
+            assert_eq!(&from_key::<T>(key).unwrap(), value);
+        }
+        for pair in values.windows(2).zip(keys.windows(2)) {
+            assert!(pair.0[0] < pair.0[1], "{:?} is not sorted", pair.0);
+            assert!(pair.1[0] < pair.1[1], "{:?} is not sorted", pair.0);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_ordered(&[i8::MIN, -1, 0, i8::MAX]);
+        assert_ordered(&[0u32, 1, 256, u32::MAX]);
+        assert_ordered(&[i128::MIN, 0, i128::MAX]);
+    }
+
+    #[test]
+    fn floats() {
+        assert_ordered(&[
+            f64::NEG_INFINITY,
+            -1.5,
+            -1e-300,
+            0.0,
+            1e-300,
+            2.5,
+            f64::INFINITY,
+        ]);
+        assert_ordered(&[-3.0f32, -0.5, 0.5, 3.0]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_ordered(&[
+            String::new(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "\u{1}".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn compound() {
+        assert_ordered(&[
+            ("a".to_string(), 2u16),
+            ("a".to_string(), 10),
+            ("ab".to_string(), 0),
+        ]);
+        assert_ordered(&[None, Some(0u8), Some(1)]);
+        assert_ordered(&[vec![], vec![0u8], vec![0, 0], vec![1]]);
+        assert_ordered(&[
+            Path {
+                parent: 1,
+                name: "z".into(),
+                kind: Kind::File,
+            },
+            Path {
+                parent: 2,
+                name: "a".into(),
+                kind: Kind::Link("x".into()),
+            },
+            Path {
+                parent: 2,
+                name: "a".into(),
+                kind: Kind::Directory { entries: 3 },
+            },
+        ]);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert_eq!(from_key::<u32>(&[0, 1]), Err(Error::UnexpectedEnd));
+        assert_eq!(from_key::<u8>(&[0, 1]), Err(Error::TrailingBytes(1)));
+        assert_eq!(
+            from_key::<String>(&[b'a', 0, 1]),
+            Err(Error::Invalid("escape sequence"))
+        );
+        assert_eq!(
+            from_key::<serde_json::Value>(&[0]),
+            Err(Error::NotSelfDescribing)
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Conversion of storage pools of format version 1.
+//!
+//! Their superblock is converted when it is read, see
+//! [Superblock::unpack](super::Superblock::unpack), and their nodes when they
+//! are read, see `Node::unpack_at`.  Nodes are rewritten in the current
+//! format once modified or by [Database::upgrade_format](super::Database::upgrade_format).
+//! The entries of the root tree which depend on the layout of disk offsets
+//! and object pointers, or which moved to the allocation trees since, are
+//! converted here when the pool is opened.
+use super::{
+    errors::*,
+    root_tree_msg::{
+        dataset, deadlist, segment, space_accounting, DATASET_DATA, DEADLIST, SNAPSHOT_DATA,
+    },
+    Checksum, DatasetData, ObjectPointer, RootDmu, RootTree, ROOT_DATASET_ID, SUPERBLOCK_BLOCKS,
+};
+use crate::{
+    allocator::SegmentId,
+    data_management::{self, LegacyObjectPointer},
+    storage_pool::{DiskOffset, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    tree::{DefaultMessageAction, TreeLayer},
+    StoragePreference,
+};
+use std::sync::Arc;
+
+// Object pointers as written by format version 1.
+type LegacyPointer = LegacyObjectPointer<<Checksum as crate::checksum::Checksum>::Legacy>;
+
+/// Moves the allocation bitmaps and the space accounting of the vdevs from
+/// the root tree to the allocation trees of their storage classes, which are
+/// created empty, and translates the object pointers and disk offsets stored
+/// in the root tree.
+pub(super) fn convert_root_tree(
+    tree: &RootTree<RootDmu>,
+    storage_preference: StoragePreference,
+) -> Result<()> {
+    let dmu = tree.dmu();
+    let allocation_trees: Vec<_> = (0..NUM_STORAGE_CLASSES)
+        .map(|class| {
+            let allocation_tree = RootTree::empty_tree(
+                ROOT_DATASET_ID,
+                DefaultMessageAction,
+                Arc::clone(dmu),
+                storage_preference,
+            );
+            dmu.handler().allocation_trees[class].set(Arc::clone(allocation_tree.inner()));
+            allocation_tree
+        })
+        .collect();
+
+    let mut moved = Vec::new();
+    let mut segments = Vec::new();
+    // The dataset id counter shares the prefix of the segments.
+    for entry in tree.range(&segment::min_key()[..]..&segment::max_key()[..])? {
+        let (key, bitmap) = entry?;
+        if key[..] == dataset::id_counter() {
+            continue;
+        }
+        let offset = DiskOffset::from_legacy(segment::read_key(&key).0);
+        allocation_trees[offset.storage_class() as usize].insert(
+            &segment::id_to_key(SegmentId::get(offset))[..],
+            DefaultMessageAction::insert_msg(&bitmap),
+            StoragePreference::NONE,
+        )?;
+        segments.push(SegmentId::get(offset));
+        moved.push(key);
+    }
+    for entry in tree.range(&space_accounting::min_key()[..]..&space_accounting::max_key()[..])? {
+        let (key, info) = entry?;
+        let disk_id = space_accounting::read_key(&key);
+        allocation_trees[disk_id.storage_class() as usize].insert(
+            &key[..],
+            DefaultMessageAction::insert_msg(&info),
+            StoragePreference::NONE,
+        )?;
+        moved.push(key);
+    }
+    for key in moved {
+        tree.insert(
+            key,
+            DefaultMessageAction::delete_msg(),
+            StoragePreference::NONE,
+        )?;
+    }
+    // There is no written allocation tree which keeps blocks freed before the
+    // next sync from being reused, the allocators are read while the bitmaps
+    // are still the written ones instead.
+    for id in segments {
+        dmu.handler().get_allocation_bitmap(id, &**dmu)?;
+    }
+
+    // Datasets and snapshots are located by object pointers.
+    let mut converted = Vec::new();
+    for prefix in [DATASET_DATA, SNAPSHOT_DATA] {
+        for entry in tree.range(&[prefix][..]..&[prefix + 1][..])? {
+            let (key, data) = entry?;
+            let legacy = DatasetData::<LegacyPointer>::unpack(&data)?;
+            let data = DatasetData::<ObjectPointer> {
+                previous_snapshot: legacy.previous_snapshot,
+                ptr: legacy.ptr.into(),
+                storage_preference: legacy.storage_preference,
+            };
+            converted.push((key, data.pack()?));
+        }
+    }
+    for (key, data) in converted {
+        tree.insert(
+            key,
+            DefaultMessageAction::insert_msg(&data),
+            StoragePreference::NONE,
+        )?;
+    }
+
+    // Dead list entries are keyed by the disk offset of the dead blocks.  A
+    // translated key may be the one of another legacy entry, so all of them
+    // are removed before any is inserted again.
+    let mut renamed = Vec::new();
+    for entry in tree.range(&[DEADLIST][..]..&deadlist::max_key_all()[..])? {
+        let (key, data) = entry?;
+        let offset = DiskOffset::from_legacy(deadlist::offset_from_key(&key).as_u64());
+        let new_key = deadlist::key(
+            deadlist::ds_id_from_key(&key),
+            deadlist::generation_from_key(&key),
+            offset,
+        );
+        // Offsets on the first vdev of the first storage class are the same.
+        if key[..] != new_key[..] {
+            renamed.push((key, new_key, data));
+        }
+    }
+    for (key, _, _) in &renamed {
+        tree.insert(
+            &key[..],
+            DefaultMessageAction::delete_msg(),
+            StoragePreference::NONE,
+        )?;
+    }
+    for (_, new_key, data) in renamed {
+        tree.insert(
+            &new_key[..],
+            DefaultMessageAction::insert_msg(&data),
+            StoragePreference::NONE,
+        )?;
+    }
+    Ok(())
+}
+
+/// Allocates the superblock blocks at the end of each vdev, which format
+/// version 1 did not use.  Fails if they are in use, the vdev has to be
+/// grown then.
+pub(super) fn reserve_superblock_tails(dmu: &RootDmu) -> Result<()> {
+    for class in 0..dmu.pool().storage_class_count() {
+        for disk_id in 0..dmu.pool().disk_count(class) {
+            match dmu.reserve_raw_tail(class, disk_id, SUPERBLOCK_BLOCKS, ROOT_DATASET_ID) {
+                Ok(()) => {}
+                Err(data_management::Error::RawAllocationError { .. }) => {
+                    return Err(Error::InvalidConfiguration {
+                        field: format!("storage.tiers[{class}].top_level_vdevs[{disk_id}]"),
+                        reason: format!(
+                            "the last {} blocks are in use, grow the vdev to make room for the \
+                             superblocks of the current format",
+                            SUPERBLOCK_BLOCKS.as_u32()
+                        ),
+                    })
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        root_tree_msg::{dataset, space_accounting},
+        superblock::LegacySuperblock,
+        AccessMode, Database, DatabaseConfiguration, DatasetId, FsckOptions, Generation,
+        StorageInfo, ROOT_DATASET_ID,
+    };
+    use crate::{
+        checksum::{Builder, Checksum as _, GxHash, State},
+        compression::CompressionConfiguration,
+        data_management::LegacyObjectPointer,
+        storage_pool::{GlobalDiskId, LeafVdev, StoragePoolConfiguration, TierConfiguration, Vdev},
+        tree::{legacy as nodes, DefaultMessageAction},
+        vdev::{Block, BLOCK_SIZE},
+    };
+    use std::{fs::File, os::unix::fs::FileExt, path::PathBuf};
+
+    const VDEV_BLOCKS: u64 = 16 * 1024;
+
+    const ENTRIES: [(&[u8], &[u8]); 5] = [
+        (b"a", b"1"),
+        (b"b", b"2"),
+        (b"c", b"3"),
+        (b"x", b"4"),
+        (b"y", b"5"),
+    ];
+
+    // Writes the node `data` to `block` of the vdev of storage class `class`,
+    // which is backed by `file`.
+    fn write_node(
+        file: &File,
+        class: u64,
+        block: u64,
+        data: &[u8],
+        info: DatasetId,
+    ) -> LegacyObjectPointer<GxHash> {
+        let blocks = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let mut data = data.to_vec();
+        data.resize(blocks * BLOCK_SIZE, 0);
+        file.write_all_at(&data, block * BLOCK_SIZE as u64).unwrap();
+        let mut state = GxHash::builder().build();
+        state.ingest(&data);
+        LegacyObjectPointer::new(
+            state.finish(),
+            (class << 62) | block,
+            Block(blocks as u32),
+            info,
+            Generation(1),
+        )
+    }
+
+    // Writes a pool of format version 1 with a vdev in each of the first two
+    // storage classes.  The dataset `data` holds `ENTRIES`, partly in the
+    // buffers of its root node.
+    fn write_legacy_pool(paths: &[PathBuf; 2]) {
+        let files = paths.clone().map(|path| {
+            let file = File::create(path).unwrap();
+            file.set_len(VDEV_BLOCKS * BLOCK_SIZE as u64).unwrap();
+            file
+        });
+
+        let ds_id = DatasetId(1);
+        let left = write_node(&files[1], 1, 2, &nodes::pack_leaf(&ENTRIES[..2]), ds_id);
+        let right = write_node(&files[1], 1, 3, &nodes::pack_leaf(&ENTRIES[3..4]), ds_id);
+        let ds_root = nodes::pack_internal(
+            1,
+            &[b"m"],
+            vec![
+                (
+                    vec![(ENTRIES[2].0, DefaultMessageAction::insert_msg(ENTRIES[2].1))],
+                    left,
+                ),
+                (
+                    vec![(ENTRIES[4].0, DefaultMessageAction::insert_msg(ENTRIES[4].1))],
+                    right,
+                ),
+            ],
+        );
+        let ds_root = write_node(&files[0], 0, 4, &ds_root, ds_id);
+
+        let mut ds_data = vec![0; 8];
+        bincode::serialize_into(&mut ds_data, &ds_root).unwrap();
+        // Both vdevs have their superblock blocks and two nodes allocated,
+        // the root node is not recorded in the allocation bitmaps.
+        let info = StorageInfo {
+            free: Block(VDEV_BLOCKS - 4),
+            total: Block(VDEV_BLOCKS),
+        };
+        let info_data = bincode::serialize(&info).unwrap();
+        let mut segment_keys = [[0; 9]; 2];
+        segment_keys[1][1] = 0x40;
+        let root_entries: [(&[u8], &[u8]); 7] = [
+            (&dataset::id_counter(), &ds_id.pack()),
+            (&segment_keys[0], &[0b1_0011]),
+            (&segment_keys[1], &[0b1111]),
+            (&dataset::name_to_id(b"data"), &ds_id.pack()),
+            (&dataset::data_key(ds_id), &ds_data),
+            (&space_accounting::key(GlobalDiskId(0)), &info_data),
+            (&space_accounting::key(GlobalDiskId(1 << 10)), &info_data),
+        ];
+        let root = nodes::pack_leaf(&root_entries);
+        let root = write_node(&files[0], 0, 5, &root, ROOT_DATASET_ID);
+
+        let empty = StorageInfo {
+            free: Block(0),
+            total: Block(0),
+        };
+        let superblock = LegacySuperblock::new(root, [info, info, empty, empty])
+            .pack()
+            .unwrap();
+        for file in files.iter() {
+            file.write_all_at(superblock.as_ref(), BLOCK_SIZE as u64)
+                .unwrap();
+        }
+    }
+
+    fn check_entries(db: &Database) {
+        let ds = db.open_dataset(b"data").unwrap();
+        for (key, value) in ENTRIES {
+            assert_eq!(&ds.get(key).unwrap().unwrap()[..], value);
+        }
+        db.close_dataset(ds).unwrap();
+    }
+
+    #[test]
+    fn legacy_pools_are_opened_and_upgraded() {
+        let dir = std::env::temp_dir();
+        let paths =
+            [0, 1].map(|class| dir.join(format!("betree-legacy-{}-{class}", std::process::id())));
+        write_legacy_pool(&paths);
+        let config = DatabaseConfiguration {
+            storage: StoragePoolConfiguration {
+                tiers: paths
+                    .iter()
+                    .map(|path| TierConfiguration {
+                        top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                            path: path.clone(),
+                            direct: Some(false),
+                        })],
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+            compression: CompressionConfiguration::None,
+            access_mode: AccessMode::OpenIfExists,
+            ..Default::default()
+        };
+
+        let mut db = Database::build(config.clone()).unwrap();
+        assert!(db.has_legacy_format());
+        check_entries(&db);
+        assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+        assert!(db.upgrade_format().unwrap());
+        db.sync().unwrap();
+        drop(db);
+
+        let mut db = Database::build(config).unwrap();
+        assert!(!db.has_legacy_format());
+        check_entries(&db);
+        assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+        drop(db);
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
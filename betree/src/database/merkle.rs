@@ -0,0 +1,143 @@
+//! Merkle hashes over the contents of datasets and snapshots, see
+//! [Dataset::root_hash](super::Dataset::root_hash).
+//!
+//! The checksums of the nodes cannot be compared between replicas, as the
+//! shape of a tree depends on the order of its modifications and a restored
+//! backup is built anew, see [Database::restore](super::Database::restore).
+//! The hash is therefore derived from the entries in key order: every entry
+//! is hashed with the algorithm of the node checksums and runs of `FAN_OUT`
+//! hashes are combined into a hash of the next level until a single one
+//! remains.  Replicas with equal contents have equal hashes, however they
+//! have been written.
+//!
+//! The hashes are not cryptographic, they detect diverging replicas but not
+//! deliberate tampering.
+use super::{errors::*, ValueChecksum};
+use crate::{
+    checksum::{Builder, Checksum as _, State},
+    cow_bytes::{CowBytes, SlicedCowBytes},
+};
+use serde::{Deserialize, Serialize};
+
+// The number of hashes combined into one of the next level.
+const FAN_OUT: usize = 64;
+
+/// The Merkle hash of the entries of a dataset or snapshot, see
+/// [Dataset::root_hash](super::Dataset::root_hash).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootHash {
+    /// The hash of the root of the Merkle tree
+    pub hash: ValueChecksum,
+    /// The number of entries covered by the hash
+    pub entries: u64,
+}
+
+#[derive(Default)]
+struct MerkleBuilder {
+    // The hashes which have not been combined yet by level, starting with
+    // the hashes of the entries.
+    levels: Vec<Vec<ValueChecksum>>,
+    entries: u64,
+}
+
+impl MerkleBuilder {
+    fn push(&mut self, key: &[u8], value: &[u8]) {
+        let mut state = ValueChecksum::builder().build();
+        // The length separates the key from the value.
+        state.ingest(&(key.len() as u32).to_le_bytes());
+        state.ingest(key);
+        state.ingest(value);
+        self.entries += 1;
+        self.push_hash(0, state.finish());
+    }
+
+    fn push_hash(&mut self, level: usize, hash: ValueChecksum) {
+        if self.levels.len() == level {
+            self.levels.push(Vec::with_capacity(FAN_OUT));
+        }
+        self.levels[level].push(hash);
+        if self.levels[level].len() == FAN_OUT {
+            let hash = combine(level, &self.levels[level]);
+            self.levels[level].clear();
+            self.push_hash(level + 1, hash);
+        }
+    }
+
+    fn finish(mut self) -> RootHash {
+        // The incomplete runs of the lower levels hold the last entries, so
+        // they are appended to the ones of the upper levels.
+        let mut carry = None;
+        for (level, hashes) in self.levels.iter_mut().enumerate() {
+            hashes.extend(carry);
+            carry = (!hashes.is_empty()).then(|| combine(level, hashes));
+        }
+        RootHash {
+            hash: carry.unwrap_or_else(|| combine(0, &[])),
+            entries: self.entries,
+        }
+    }
+}
+
+// Hashes the `hashes` of `level` into one of the next level.
+fn combine(level: usize, hashes: &[ValueChecksum]) -> ValueChecksum {
+    let mut state = ValueChecksum::builder().build();
+    state.ingest(&(level as u32).to_le_bytes());
+    state.ingest(&bincode::serialize(hashes).expect("Checksums are serializable"));
+    state.finish()
+}
+
+/// Computes the Merkle hash of `entries`, which have to be sorted by key.
+pub(super) fn root_hash<I>(entries: I) -> Result<RootHash>
+where
+    I: Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>,
+{
+    let mut builder = MerkleBuilder::default();
+    for entry in entries {
+        let (key, value) = entry?;
+        builder.push(&key, &value);
+    }
+    Ok(builder.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MerkleBuilder;
+
+    fn hash(entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> super::RootHash {
+        let mut builder = MerkleBuilder::default();
+        for (key, value) in entries {
+            builder.push(&key, &value);
+        }
+        builder.finish()
+    }
+
+    fn entries(count: u32) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        (0..count).map(|idx| (idx.to_be_bytes().to_vec(), vec![idx as u8; 16]))
+    }
+
+    #[test]
+    fn hash_depends_on_every_entry() {
+        for count in [0, 1, 63, 64, 65, 4096, 5000] {
+            let base = hash(entries(count));
+            assert_eq!(base, hash(entries(count)));
+            assert_eq!(base.entries, count as u64);
+            assert_ne!(base, hash(entries(count + 1)));
+            if count > 0 {
+                let changed = entries(count).map(|(key, mut value)| {
+                    if key == (count - 1).to_be_bytes() {
+                        value[0] ^= 1;
+                    }
+                    (key, value)
+                });
+                assert_ne!(base.hash, hash(changed).hash);
+            }
+        }
+    }
+
+    #[test]
+    fn key_and_value_are_separated() {
+        let a = hash([(b"ab".to_vec(), b"c".to_vec())].into_iter());
+        let b = hash([(b"a".to_vec(), b"bc".to_vec())].into_iter());
+        assert_ne!(a.hash, b.hash);
+    }
+}
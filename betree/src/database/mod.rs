@@ -1,35 +1,46 @@
 //! This module provides the Database Layer.
 use crate::{
+    allocator::AllocatorType,
     atomic_option::AtomicOption,
-    cache::ClockCache,
-    checksum::GxHash,
+    buffer::{self, BufferAllocation},
+    cache::{CachePolicyType, PolicyCache},
+    checksum::{ChecksumConfiguration, GxHash, TaggedChecksum},
+    clock,
     compression::CompressionConfiguration,
     cow_bytes::SlicedCowBytes,
     data_management::{
-        self, Dml, DmlWithHandler, DmlWithReport, DmlWithStorageHints, Dmu, TaggedCacheValue,
+        self, DeltaBase, DeltaConfiguration, Displacement, Dml, DmlWithHandler, DmlWithReport,
+        DmlWithStorageHints, Dmu, L2Cache, L2CacheConfiguration, L2CacheStats, TaggedCacheValue,
     },
+    encryption::{Encryption, EncryptionConfiguration},
     metrics::{metrics_init, MetricsConfiguration},
-    migration::{DatabaseMsg, DmlMsg, GlobalObjectId, MigrationPolicies},
+    migration::{
+        DatabaseMsg, DmlMsg, GlobalObjectId, MigrationContext, MigrationPlan, MigrationPolicies,
+        MigrationPolicy, MigrationReport, MigrationReports,
+    },
     size::StaticSize,
     storage_pool::{
-        DiskOffset, StoragePoolConfiguration, StoragePoolLayer, StoragePoolUnit,
+        io_priority, with_io_priority, DiskOffset, GlobalDiskId, IoPriority,
+        StoragePoolConfiguration, StoragePoolLayer, StoragePoolUnit, TierConfiguration, Vdev,
         NUM_STORAGE_CLASSES,
     },
     tree::{
-        DefaultMessageAction, ErasedTreeSync, Inner as TreeInner, Node, PivotKey, Tree, TreeLayer,
+        DefaultMessageAction, ErasedTreeSync, FlushSize, Inner as TreeInner, Node, PivotKey, Tree,
+        TreeLayer,
     },
     vdev::Block,
     StoragePreference,
 };
 use bincode::{deserialize, serialize_into};
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
 use itertools::Itertools;
 use parking_lot::{Mutex, RwLock};
 use seqlock::SeqLock;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use speedy::{Readable, Writable};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     iter::FromIterator,
     path::{Path, PathBuf},
     sync::{
@@ -39,37 +50,91 @@ use std::{
     thread,
 };
 
+mod async_dataset;
+mod backup;
+mod checkpoint;
+mod compaction;
+mod config;
 mod dataset;
+mod defragmentation;
+mod delayed_messages;
 pub(crate) mod errors;
+mod events;
+mod flusher;
+mod fsck;
 mod handler;
+pub mod ingest;
+pub mod inspect;
+pub mod key_encoding;
+mod legacy;
+mod merkle;
+mod pinning;
+mod quota;
+mod read_view;
+mod replication;
+mod reservation;
 pub(crate) mod root_tree_msg;
+mod sealing;
 mod snapshot;
 mod storage_info;
 mod superblock;
 mod sync_timer;
-
-use root_tree_msg::{dataset as dataset_key, snapshot as snapshot_key, space_accounting};
+mod transaction;
+mod typed_dataset;
+mod value_placement;
+
+use delayed_messages::DelayedMessages;
+use flusher::Flusher;
+use quota::QuotaGroups;
+use root_tree_msg::{
+    allocation_tree, dataset as dataset_key, dataset_dictionary, dataset_usage, delta_base,
+    snapshot as snapshot_key, space_accounting,
+};
 use storage_info::AtomicStorageInfo;
-pub use storage_info::StorageInfo;
+pub use storage_info::{
+    AllocationHeatmap, DiskFragmentation, DiskHeatmap, FragmentationInfo, FragmentationReport,
+    SegmentFragmentation, SegmentHeat, SpaceUsage, StorageInfo, StorageReport, TierReport,
+};
+use sync_timer::SyncTimer;
 
 #[cfg(feature = "figment_config")]
 mod figment;
 
 pub use self::{
-    dataset::Dataset,
+    async_dataset::{AsyncDataset, BlockingSpawner},
+    checkpoint::CheckpointInfo,
+    compaction::CompactionConfiguration,
+    config::{ConfigurationChange, RuntimeConfiguration},
+    dataset::{value_checksum, Dataset},
+    defragmentation::DefragmentationConfiguration,
     errors::*,
+    events::{Event, EventHandlerId, EventHandlers},
+    fsck::{DeadListReport, FsckOptions, FsckReport},
     handler::{update_allocation_bitmap_msg, Handler},
-    snapshot::Snapshot,
-    superblock::Superblock,
+    merkle::RootHash,
+    quota::QuotaGroupInfo,
+    read_view::ReadView,
+    replication::{ReplicationCursor, ReplicationLeader, ReplicationTransport},
+    reservation::Reservation,
+    snapshot::{Snapshot, SnapshotInfo},
+    superblock::{Superblock, FORMAT_VERSION, SUPERBLOCK_BLOCKS},
+    transaction::{Transaction, WriteBatch},
+    typed_dataset::TypedDataset,
+    value_placement::{ValuePlacement, ValueSizeRule},
 };
+#[cfg(feature = "async_tokio")]
+pub use async_dataset::TokioSpawner;
 const ROOT_DATASET_ID: DatasetId = DatasetId(0);
-const ROOT_TREE_STORAGE_PREFERENCE: StoragePreference = StoragePreference::FASTEST;
 const DEFAULT_CACHE_SIZE: usize = 256 * 1024 * 1024;
 const DEFAULT_SYNC_INTERVAL_MS: u64 = 1000;
 
-// This is the hash used overall in the entire database. For reconfiguration
-// recompilation is necessary and this type changed.
-type Checksum = GxHash;
+// The checksum of nodes, which are checksummed with the algorithm of
+// `DatabaseConfiguration::checksum`.
+type Checksum = TaggedChecksum;
+
+/// The checksum of values, see [Dataset::get_with_checksum].  It is computed
+/// by [GxHash] regardless of [DatabaseConfiguration::checksum].
+pub type ValueChecksum = GxHash;
 
 type ObjectPointer = data_management::ObjectPointer<Checksum>;
 pub(crate) type ObjectRef = data_management::impls::ObjRef<ObjectPointer>;
@@ -78,7 +143,7 @@ type DbHandler = Handler<ObjectRef>;
 
 pub(crate) type RootSpu = StoragePoolUnit<Checksum>;
 pub(crate) type RootDmu = Dmu<
-    ClockCache<
+    PolicyCache<
         data_management::impls::ObjectKey<Generation>,
         TaggedCacheValue<RwLock<Object>, PivotKey>,
     >,
@@ -128,14 +193,64 @@ pub struct DatabaseConfiguration {
     ///     but maps 2 and 3 to 1 as well. Again, without fallback
     /// - `[[0, 1, 2, 3], [1, 2, 3], [2, 3], [3]]` will try to allocate as requested,
     ///     but allows falling back to higher classes if a class is full
-    pub alloc_strategy: [Vec<u8>; NUM_STORAGE_CLASSES],
+    ///
+    /// Classes without an entry allocate each request in the request class.
+    pub alloc_strategy: Vec<Vec<u8>>,
+    /// When set, requests which cannot be allocated with `alloc_strategy`
+    /// spill over to the slower classes, the next slower one first.  Nodes
+    /// written to another class than the first one of their strategy are
+    /// listed by [Database::displaced_nodes] and moved back by the migration
+    /// policy once there is space again.
+    pub spill_to_slower_tiers: bool,
+    /// The strategy to place allocations within a segment of each storage
+    /// class, e.g. `[FirstFit, BestFit]` to preserve contiguity on a slow
+    /// second tier.
+    ///
+    /// Classes without an entry use [AllocatorType::FirstFit].
+    pub allocator_types: Vec<AllocatorType>,
     /// Default storage class, used when attempting to allocate a tree object without
     /// a storage preference
     pub default_storage_class: u8,
+    /// Storage preference of the root tree and the allocation trees, which
+    /// hold the metadata of the database, e.g. to keep them on a specific
+    /// NVMe tier.  The storage class has to be configured.
+    pub root_tree_storage_preference: StoragePreference,
     /// Which compression type to use, and the type-specific compression parameters
     pub compression: CompressionConfiguration,
+    /// Compression of the datasets with the given names, overriding `compression`
+    pub dataset_compression: HashMap<String, CompressionConfiguration>,
+    /// Which algorithm to checksum new nodes with
+    pub checksum: ChecksumConfiguration,
     /// Size of cache in TODO
     pub cache_size: usize,
+    /// Which entries to evict from the cache when it is full
+    pub cache_policy: CachePolicyType,
+    /// When set, clean nodes evicted from the cache are staged on a fast
+    /// storage class and read from there instead of their slower tier
+    pub l2_cache: Option<L2CacheConfiguration>,
+    /// How the buffers of cached nodes and of vdev IO are allocated.  This
+    /// applies to all databases of this process, building a database fails
+    /// while another one with a different allocation is open
+    pub buffer_allocation: BufferAllocation,
+    /// The number of threads which pack, compress and checksum independent
+    /// dirty nodes concurrently on a sync, while another thread issues their
+    /// writes
+    pub write_back_threads: usize,
+    /// The number of threads which rebalance overfull nodes in the
+    /// background, with 0 insertions rebalance the nodes they fill themselves
+    pub flush_threads: usize,
+    /// The size from which on messages buffered in the nodes of datasets are
+    /// flushed to their children
+    pub flush_size: FlushSize,
+    /// Flush sizes of the datasets with the given names, overriding
+    /// `flush_size`
+    pub dataset_flush_size: HashMap<String, FlushSize>,
+    /// Rules which place values inserted without a storage preference by
+    /// their size, e.g. small values on the fastest storage class
+    pub value_placement: ValuePlacement,
+    /// Value placements of the datasets with the given names, overriding
+    /// `value_placement`
+    pub dataset_value_placement: HashMap<String, ValuePlacement>,
     /// Whether to check for and open an existing database, or overwrite it
     pub access_mode: AccessMode,
 
@@ -150,6 +265,34 @@ pub struct DatabaseConfiguration {
 
     /// Where to log the allocations
     pub allocation_log_file_path: PathBuf,
+
+    /// When set, all open datasets are synced and a final superblock is
+    /// written when the [Database] is dropped
+    pub sync_on_drop: bool,
+
+    /// When set, all nodes are encrypted at rest with the active key
+    pub encryption: Option<EncryptionConfiguration>,
+
+    /// When set, leaves of datasets of which only a few entries changed are
+    /// written as deltas of the last version written in full
+    pub delta_encoding: Option<DeltaConfiguration>,
+
+    /// Which segments to empty on defragmentation and whether to do so
+    /// periodically
+    pub defragmentation: DefragmentationConfiguration,
+
+    /// Which child buffers of cold key ranges to flush ahead of time and
+    /// whether to do so periodically while the database is idle
+    pub compaction: CompactionConfiguration,
+
+    /// The share of each storage class which is kept free of user data, so
+    /// that syncs and the processing of dead lists can complete once datasets
+    /// have filled the storage pool
+    pub headroom: f32,
+
+    /// When set, the database is a follower of a replicated one, whose
+    /// datasets are only modified by [Database::apply_replication]
+    pub replication_follower: bool,
 }
 
 impl Default for DatabaseConfiguration {
@@ -157,15 +300,38 @@ impl Default for DatabaseConfiguration {
         Self {
             storage: StoragePoolConfiguration::default(),
             // identity mapping
-            alloc_strategy: [vec![0], vec![1], vec![2], vec![3]],
+            alloc_strategy: (0..NUM_STORAGE_CLASSES as u8)
+                .map(|class| vec![class])
+                .collect(),
+            spill_to_slower_tiers: false,
+            allocator_types: Vec::new(),
             default_storage_class: 0,
+            root_tree_storage_preference: StoragePreference::FASTEST,
             compression: CompressionConfiguration::None,
+            dataset_compression: HashMap::new(),
+            checksum: ChecksumConfiguration::default(),
             cache_size: DEFAULT_CACHE_SIZE,
+            cache_policy: CachePolicyType::default(),
+            l2_cache: None,
+            buffer_allocation: BufferAllocation::default(),
+            write_back_threads: 1,
+            flush_threads: 0,
+            flush_size: FlushSize::default(),
+            dataset_flush_size: HashMap::new(),
+            value_placement: ValuePlacement::default(),
+            dataset_value_placement: HashMap::new(),
             access_mode: AccessMode::OpenIfExists,
             sync_interval_ms: Some(DEFAULT_SYNC_INTERVAL_MS),
             metrics: None,
             migration_policy: None,
             allocation_log_file_path: PathBuf::from("allocation_log.bin"),
+            sync_on_drop: false,
+            encryption: None,
+            delta_encoding: None,
+            defragmentation: DefragmentationConfiguration::default(),
+            compaction: CompactionConfiguration::default(),
+            headroom: 0.0,
+            replication_follower: false,
         }
     }
 }
@@ -190,63 +356,121 @@ impl DatabaseConfiguration {
 
     pub fn new_handler(&self, spu: &RootSpu) -> DbHandler {
         Handler {
-            root_tree_inner: AtomicOption::new(),
-            root_tree_snapshot: RwLock::new(None),
+            allocation_trees: (0..NUM_STORAGE_CLASSES)
+                .map(|_| AtomicOption::new())
+                .collect(),
+            allocation_tree_snapshots: (0..NUM_STORAGE_CLASSES)
+                .map(|_| RwLock::new(None))
+                .collect(),
             current_generation: SeqLock::new(Generation(1)),
-            delayed_messages: Mutex::new(Vec::new()),
+            delayed_messages: Mutex::new(DelayedMessages::default()),
+            delayed_allocation_messages: (0..NUM_STORAGE_CLASSES)
+                .map(|_| Mutex::new(DelayedMessages::default()))
+                .collect(),
             last_snapshot_generation: RwLock::new(HashMap::new()),
-            free_space: HashMap::from_iter((0..spu.storage_class_count()).flat_map(|class| {
-                (0..spu.disk_count(class)).map(move |disk_id| {
-                    let free = spu
-                        .effective_free_size(class, disk_id, spu.size_in_blocks(class, disk_id))
-                        .as_u64();
-                    (
-                        DiskOffset::construct_disk_id(class, disk_id),
-                        AtomicStorageInfo {
-                            free: AtomicU64::new(free),
-                            total: AtomicU64::new(free),
-                        },
-                    )
-                })
-            })),
+            checkpoint_generations: RwLock::new(BTreeSet::new()),
+            read_views: Mutex::new(HashMap::new()),
+            commit_lock: RwLock::new(()),
+            reservations: Mutex::new(Default::default()),
+            headroom: self.headroom,
+            root_tree_storage_preference: self.root_tree_storage_preference,
+            follower: self.replication_follower,
+            legacy_generation: RwLock::new(None),
+            dataset_usage: RwLock::new(HashMap::new()),
+            changed_dataset_usage: Mutex::new(HashSet::new()),
+            quota_groups: RwLock::new(Default::default()),
+            sealed_disks: RwLock::new(HashSet::new()),
+            delta_bases: RwLock::new(HashMap::new()),
+            delta_base_refs: Mutex::new(HashMap::new()),
+            free_space: RwLock::new(HashMap::from_iter((0..spu.storage_class_count()).flat_map(
+                |class| {
+                    (0..spu.disk_count(class)).map(move |disk_id| {
+                        let free = spu
+                            .effective_free_size(class, disk_id, spu.size_in_blocks(class, disk_id))
+                            .as_u64();
+                        (
+                            DiskOffset::construct_disk_id(class, disk_id),
+                            AtomicStorageInfo {
+                                free: AtomicU64::new(free),
+                                total: AtomicU64::new(free),
+                            },
+                        )
+                    })
+                },
+            ))),
             free_space_tier: (0..NUM_STORAGE_CLASSES)
                 .map(|_| AtomicStorageInfo::default())
                 .collect_vec(),
             allocations: AtomicU64::new(0),
-            old_root_allocation: SeqLock::new(None),
+            old_root_allocations: RwLock::new(Vec::new()),
+            cache_allocations: RwLock::new(HashMap::new()),
+            freed_cache_allocations: Mutex::new(Vec::new()),
+            events: Default::default(),
             allocators: RwLock::new(HashMap::new()),
+            written_segment_updates: Mutex::new(BTreeMap::new()),
+            allocator_types: (0..NUM_STORAGE_CLASSES)
+                .map(|class| self.allocator_types.get(class).copied().unwrap_or_default())
+                .collect(),
         }
     }
 
-    pub fn new_dmu(&self, spu: RootSpu, handler: DbHandler) -> RootDmu {
+    pub fn new_dmu(&self, spu: RootSpu, handler: DbHandler) -> Result<RootDmu> {
         let mut strategy: [[Option<u8>; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES] =
             [[None; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES];
 
-        for (dst, src) in strategy.iter_mut().zip(self.alloc_strategy.iter()) {
-            assert!(
-                src.len() <= NUM_STORAGE_CLASSES,
-                "Invalid allocation strategy, can't try more than once per class"
-            );
-
-            for (dst, src) in dst.iter_mut().zip(src) {
-                *dst = Some(*src);
+        assert!(
+            self.alloc_strategy.len() <= NUM_STORAGE_CLASSES,
+            "Invalid allocation strategy, too many classes"
+        );
+        for (class, dst) in strategy.iter_mut().enumerate() {
+            match self.alloc_strategy.get(class) {
+                Some(src) => {
+                    assert!(
+                        src.len() <= NUM_STORAGE_CLASSES,
+                        "Invalid allocation strategy, can't try more than once per class"
+                    );
+                    for (dst, src) in dst.iter_mut().zip(src) {
+                        *dst = Some(*src);
+                    }
+                }
+                None => dst[0] = Some(class as u8),
+            }
+            if self.spill_to_slower_tiers {
+                for slower in class as u8 + 1..NUM_STORAGE_CLASSES as u8 {
+                    if dst.contains(&Some(slower)) {
+                        continue;
+                    }
+                    if let Some(free) = dst.iter_mut().find(|slot| slot.is_none()) {
+                        *free = Some(slower);
+                    }
+                }
             }
         }
 
-        Dmu::new(
+        let encryption = self.encryption.as_ref().map(Encryption::new).transpose()?;
+
+        Ok(Dmu::new(
             self.compression.to_builder(),
-            <Checksum as crate::checksum::Checksum>::builder(),
+            self.checksum,
+            encryption,
+            self.delta_encoding.clone(),
             self.default_storage_class,
             spu,
             strategy,
-            ClockCache::new(self.cache_size),
+            PolicyCache::with_policy(self.cache_size, self.cache_policy),
+            self.l2_cache.as_ref().map(L2Cache::new),
+            self.write_back_threads,
             handler,
             #[cfg(feature = "allocation_log")]
             self.allocation_log_file_path.clone(),
-        )
+        ))
     }
 
-    fn select_root_tree(&self, dmu: Arc<RootDmu>) -> Result<(RootTree<RootDmu>, ObjectPointer)> {
+    fn select_root_tree(
+        &self,
+        dmu: Arc<RootDmu>,
+        checkpoint: Option<&[u8]>,
+    ) -> Result<(RootTree<RootDmu>, ObjectPointer)> {
         if let Some(cfg) = &self.metrics {
             metrics_init::<Self>(cfg, dmu.clone())?;
         }
@@ -264,18 +488,57 @@ impl DatabaseConfiguration {
             None
         };
 
-        if let Some(sb) = root_ptr {
+        if let Some(mut sb) = root_ptr {
             let root_ptr = sb.root_ptr;
+            let legacy = sb.version == 1;
+            if legacy {
+                // Superblocks of format version 1 do not record the vdevs,
+                // the pool is taken as configured.
+                for (class, disks) in sb.disks.iter_mut().enumerate() {
+                    *disks = dmu.pool().disk_count(class as u8);
+                }
+            }
+            // Vdevs added to the running pool have to be part of the
+            // configuration from then on.
+            for (class, &disks) in sb.disks.iter().enumerate() {
+                let configured = dmu.pool().disk_count(class as u8);
+                if configured < disks {
+                    return Err(Error::MissingVdevs(class as u8, disks - configured));
+                }
+            }
+            // Tiers not named by the configuration keep their previous name.
+            for (class, name) in sb.names.iter().enumerate() {
+                if let (Some(name), None) = (name, dmu.pool().tier_name(class as u8)) {
+                    dmu.pool().set_tier_name(class as u8, name.clone())?;
+                }
+            }
+            // When rolling back to a checkpoint the latest root tree is only
+            // consulted to find the checkpointed root tree.  The latest root
+            // pointer is still returned to keep generations monotonic.
+            let (tree_ptr, tiers, rollback) = match checkpoint {
+                Some(name) => {
+                    let latest = RootTree::open(
+                        ROOT_DATASET_ID,
+                        root_ptr,
+                        DefaultMessageAction,
+                        Arc::clone(&dmu),
+                        self.root_tree_storage_preference,
+                    );
+                    let data = checkpoint::fetch_checkpoint_data(&latest, name)?;
+                    (data.root_ptr, data.tiers, Some((name, data)))
+                }
+                None => (root_ptr, sb.tiers, None),
+            };
             let tree = RootTree::open(
                 ROOT_DATASET_ID,
-                root_ptr,
+                tree_ptr,
                 DefaultMessageAction,
                 dmu,
-                ROOT_TREE_STORAGE_PREFERENCE,
+                self.root_tree_storage_preference,
             );
 
             // Update space accounting from last execution
-            for (class, info) in sb.tiers.iter().enumerate() {
+            for (class, info) in tiers.iter().enumerate() {
                 let storage_info = tree
                     .dmu()
                     .handler()
@@ -290,41 +553,166 @@ impl DatabaseConfiguration {
                     .store(info.total.as_u64(), Ordering::Release);
             }
 
-            *tree.dmu().handler().old_root_allocation.lock_write() =
-                Some((root_ptr.offset(), root_ptr.size()));
-            tree.dmu()
-                .handler()
-                .root_tree_inner
-                .set(Arc::clone(tree.inner()));
+            let mut old_root_allocations = vec![(root_ptr.offset(), root_ptr.size())];
+            if legacy {
+                // The converted bitmaps are read right away, the root node
+                // has to be known to be in use by then.
+                *tree.dmu().handler().old_root_allocations.write() = old_root_allocations.clone();
+                legacy::convert_root_tree(&tree, self.root_tree_storage_preference)?;
+            }
+            for class in (0..NUM_STORAGE_CLASSES as u8).filter(|_| !legacy) {
+                let data = tree
+                    .get(&allocation_tree::key(class)[..])?
+                    .ok_or(Error::InvalidSuperblock)?;
+                let ptr: ObjectPointer = bincode::deserialize(&data)?;
+                old_root_allocations.push((ptr.offset(), ptr.size()));
+                let handler = tree.dmu().handler();
+                let allocation_tree = RootTree::open(
+                    ROOT_DATASET_ID,
+                    ptr,
+                    DefaultMessageAction,
+                    Arc::clone(tree.dmu()),
+                    self.root_tree_storage_preference,
+                );
+                handler.allocation_trees[class as usize].set(Arc::clone(allocation_tree.inner()));
+                *handler.allocation_tree_snapshots[class as usize].write() = Some(
+                    TreeInner::new_ro(RootDmu::root_ref_from_ptr(ptr), DefaultMessageAction),
+                );
+            }
+            *tree.dmu().handler().old_root_allocations.write() = old_root_allocations;
 
             // Iterate over disk space usage
-            for (disk_id, space) in tree
-                .range(&space_accounting::min_key()[..]..&space_accounting::max_key()[..])?
-                .filter_map(|res| res.ok())
+            for class in 0..NUM_STORAGE_CLASSES as u8 {
+                for (disk_id, space) in allocation_tree_of(tree.dmu(), class)
+                    .range(&space_accounting::min_key()[..]..&space_accounting::max_key()[..])?
+                    .filter_map(|res| res.ok())
+                {
+                    let free_space = tree.dmu().handler().free_space.read();
+                    let space_info = free_space
+                        .get(&space_accounting::read_key(&disk_id))
+                        .unwrap();
+                    let stored_info: StorageInfo = bincode::deserialize(&space)?;
+                    space_info
+                        .free
+                        .store(stored_info.free.as_u64(), Ordering::Relaxed);
+                    space_info
+                        .total
+                        .store(stored_info.total.as_u64(), Ordering::Relaxed);
+                }
+            }
+
+            // Vdevs which have been added to the configuration since the last
+            // sync are not accounted for yet.
+            for (class, &disks) in sb.disks.iter().enumerate() {
+                let class = class as u8;
+                let dmu = tree.dmu();
+                for disk_id in disks..dmu.pool().disk_count(class) {
+                    let free = dmu.pool().effective_free_size(
+                        class,
+                        disk_id,
+                        dmu.pool().size_in_blocks(class, disk_id),
+                    );
+                    let tier = &dmu.handler().free_space_tier[class as usize];
+                    tier.free.fetch_add(free.as_u64(), Ordering::Relaxed);
+                    tier.total.fetch_add(free.as_u64(), Ordering::Relaxed);
+                    dmu.reserve_raw_ends(class, disk_id, SUPERBLOCK_BLOCKS, ROOT_DATASET_ID)?;
+                }
+            }
+
+            // The superblock blocks at the end of vdevs which have grown
+            // while the database was closed are moved to their new end.
+            for (class, sizes) in sb.sizes.iter().enumerate() {
+                let class = class as u8;
+                let dmu = tree.dmu();
+                for (disk_id, &old_size) in sizes.iter().enumerate() {
+                    let disk_id = disk_id as u16;
+                    let size = dmu.pool().size_in_blocks(class, disk_id);
+                    if size < old_size {
+                        return Err(Error::InvalidConfiguration {
+                            field: format!("storage.tiers[{class}].top_level_vdevs[{disk_id}]"),
+                            reason: "the vdev has shrunk".into(),
+                        });
+                    }
+                    if size > old_size {
+                        dmu.move_raw_tail(
+                            class,
+                            disk_id,
+                            old_size,
+                            SUPERBLOCK_BLOCKS,
+                            ROOT_DATASET_ID,
+                        )?;
+                    }
+                }
+            }
+
+            if legacy {
+                legacy::reserve_superblock_tails(tree.dmu())?;
+            }
+
+            // Nodes written by an older version are rewritten lazily.
+            *tree.dmu().handler().legacy_generation.write() = if sb.version < FORMAT_VERSION {
+                Some(root_ptr.generation())
+            } else {
+                sb.legacy_generation
+            };
+
+            // Restore the space usage of all datasets
             {
-                let space_info = tree
-                    .dmu()
-                    .handler()
-                    .free_space
-                    .get(&space_accounting::read_key(&disk_id))
-                    .unwrap();
-                let stored_info: StorageInfo = bincode::deserialize(&space)?;
-                space_info
-                    .free
-                    .store(stored_info.free.as_u64(), Ordering::Relaxed);
-                space_info
-                    .total
-                    .store(stored_info.total.as_u64(), Ordering::Relaxed);
+                let mut usage = tree.dmu().handler().dataset_usage.write();
+                for (ds_id, data) in tree
+                    .range(&dataset_usage::min_key()[..]..&dataset_usage::max_key()[..])?
+                    .filter_map(|res| res.ok())
+                {
+                    usage.insert(
+                        dataset_usage::read_key(&ds_id),
+                        bincode::deserialize(&data)?,
+                    );
+                }
+            }
+            *tree.dmu().handler().quota_groups.write() = QuotaGroups::fetch(&tree)?;
+            *tree.dmu().handler().sealed_disks.write() = sealing::fetch_sealed_disks(&tree)?;
+
+            // Restore the bases of the leaves written as deltas
+            {
+                let handler = tree.dmu().handler();
+                let mut delta_bases = handler.delta_bases.write();
+                for entry in tree.range(&delta_base::min_key()[..]..&delta_base::max_key()[..])? {
+                    let (key, data) = entry?;
+                    let base: DeltaBase<ObjectPointer> = bincode::deserialize(&data)?;
+                    handler.retain_delta_base(base.base.offset());
+                    delta_bases.insert(delta_base::read_key(&key), base);
+                }
+            }
+
+            // Restore the compression dictionaries of all datasets
+            for entry in
+                tree.range(&dataset_dictionary::min_key()[..]..&dataset_dictionary::max_key()[..])?
+            {
+                let (key, data) = entry?;
+                let (ds_id, dictionary_id) = dataset_dictionary::read_key(&key);
+                tree.dmu()
+                    .add_dictionary(ds_id, dictionary_id, data[..].into());
+            }
+
+            if let Some((name, data)) = rollback {
+                // The checkpoint is not contained in its own root tree.
+                tree.insert(
+                    root_tree_msg::checkpoint::key(name),
+                    DefaultMessageAction::insert_msg(&data.pack()?),
+                    StoragePreference::NONE,
+                )?;
             }
 
             Ok((tree, root_ptr))
+        } else if checkpoint.is_some() {
+            Err(Error::DoesNotExist)
         } else {
             Superblock::<ObjectPointer>::clear_superblock(dmu.pool())?;
             let tree = RootTree::empty_tree(
                 ROOT_DATASET_ID,
                 DefaultMessageAction,
                 dmu,
-                ROOT_TREE_STORAGE_PREFERENCE,
+                self.root_tree_storage_preference,
             );
 
             for (tier_id, tier) in tree.dmu().handler().free_space_tier.iter().enumerate() {
@@ -343,15 +731,21 @@ impl DatabaseConfiguration {
                 tier.total.store(free, Ordering::Relaxed);
             }
 
-            tree.dmu()
-                .handler()
-                .root_tree_inner
-                .set(Arc::clone(tree.inner()));
+            for class in 0..NUM_STORAGE_CLASSES {
+                let allocation_tree = RootTree::empty_tree(
+                    ROOT_DATASET_ID,
+                    DefaultMessageAction,
+                    Arc::clone(tree.dmu()),
+                    self.root_tree_storage_preference,
+                );
+                tree.dmu().handler().allocation_trees[class]
+                    .set(Arc::clone(allocation_tree.inner()));
+            }
             {
                 let dmu = tree.dmu();
                 for class in 0..dmu.pool().storage_class_count() {
                     for disk_id in 0..dmu.pool().disk_count(class) {
-                        dmu.allocate_raw_at(DiskOffset::new(class, disk_id, Block(0)), Block(2))?;
+                        dmu.reserve_raw_ends(class, disk_id, SUPERBLOCK_BLOCKS, ROOT_DATASET_ID)?;
                     }
                 }
             }
@@ -374,13 +768,26 @@ impl DatabaseConfiguration {
 }
 
 type ErasedTree = dyn ErasedTreeSync<Pointer = ObjectPointer, ObjectRef = ObjectRef> + Send + Sync;
+type OpenDatasets = RwLock<HashMap<DatasetId, Box<ErasedTree>>>;
 
 /// The database type.
 pub struct Database {
     pub(crate) root_tree: RootTree<RootDmu>,
     builder: DatabaseConfiguration,
-    open_datasets: HashMap<DatasetId, Box<ErasedTree>>,
+    open_datasets: Arc<OpenDatasets>,
+    // Serializes the allocation of dataset ids and the registration of
+    // dataset names in the root tree.
+    dataset_creation: Mutex<()>,
     pub(crate) db_tx: Option<Sender<DatabaseMsg>>,
+    // The latest plan of a migration policy in a dry run.
+    pub(crate) migration_plan: Arc<Mutex<Option<MigrationPlan>>>,
+    // The subscribers to reports about performed migrations.
+    pub(crate) migration_reports: Arc<MigrationReports>,
+    flusher: Option<Arc<Flusher>>,
+    // The periodic sync started by [Database::build_threaded].
+    sync_timer: Option<Arc<SyncTimer>>,
+    // Keeps other databases from changing the buffer allocation.
+    _buffer_allocation: buffer::AllocationGuard,
 }
 
 impl Database {
@@ -421,7 +828,7 @@ impl Database {
     /// Opens or creates a database given by the storage pool configuration and
     /// sets the given cache size.
     pub fn build(builder: DatabaseConfiguration) -> Result<Self> {
-        Self::build_internal(builder, None, None)
+        Self::build_internal(builder, None, None, None)
     }
 
     // Construct an instance of [Database] either using external threads or not.
@@ -430,10 +837,19 @@ impl Database {
         builder: DatabaseConfiguration,
         dml_tx: Option<Sender<DmlMsg>>,
         db_tx: Option<Sender<DatabaseMsg>>,
+        checkpoint: Option<&[u8]>,
     ) -> Result<Self> {
+        builder.validate()?;
+        let buffer_allocation =
+            buffer::acquire_allocation(builder.buffer_allocation).map_err(|open| {
+                Error::InvalidConfiguration {
+                    field: "buffer_allocation".into(),
+                    reason: format!("open databases of this process use {open:?}"),
+                }
+            })?;
         let spl = builder.new_spu()?;
         let handler = builder.new_handler(&spl);
-        let mut dmu = builder.new_dmu(spl, handler);
+        let mut dmu = builder.new_dmu(spl, handler)?;
         if let Some(tx) = &dml_tx {
             dmu.set_report(tx.clone());
         }
@@ -441,70 +857,232 @@ impl Database {
         #[cfg(feature = "allocation_log")]
         dmu.write_global_header()?;
 
-        let (tree, root_ptr) = builder.select_root_tree(Arc::new(dmu))?;
+        let (tree, root_ptr) = builder.select_root_tree(Arc::new(dmu), checkpoint)?;
 
         *tree.dmu().handler().current_generation.lock_write() = root_ptr.generation().next();
-        *tree.dmu().handler().root_tree_snapshot.write() = Some(TreeInner::new_ro(
-            RootDmu::root_ref_from_ptr(root_ptr),
-            DefaultMessageAction,
-        ));
 
-        Ok(Database {
+        let flusher = match builder.flush_threads {
+            0 => None,
+            threads => Some(Arc::new(Flusher::new(threads))),
+        };
+        let events = Arc::clone(&tree.dmu().handler().events);
+        let db = Database {
             root_tree: tree,
             builder,
             open_datasets: Default::default(),
+            dataset_creation: Mutex::new(()),
             db_tx,
-        })
+            migration_plan: Default::default(),
+            migration_reports: Arc::new(MigrationReports::new(events)),
+            flusher,
+            sync_timer: None,
+            _buffer_allocation: buffer_allocation,
+        };
+        db.load_checkpoint_generations()?;
+        for name in db.builder.dataset_compression.keys() {
+            match db.lookup_dataset_id(name.as_bytes()) {
+                Ok(id) => db.apply_dataset_compression(id, name.as_bytes()),
+                Err(Error::DoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(db)
     }
 
     /// Opens or create a database given by the storage pool configuration, sets the given cache size and spawns threads to periodically perform
-    /// sync (if configured with [SyncMode::Periodic]) and auto migration (if configured with [MigrationPolicies]).
+    /// sync (if configured with [SyncMode::Periodic]), auto migration (if configured with [MigrationPolicies]), defragmentation (if configured
+    /// with an interval in [DefragmentationConfiguration]) and compaction (if configured with an
+    /// interval in [CompactionConfiguration]).
     pub fn build_threaded(builder: DatabaseConfiguration) -> Result<Arc<RwLock<Self>>> {
-        let db = match builder.migration_policy() {
+        match builder.migration_policy() {
             Some(pol) => {
-                let (dml_tx, dml_rx) = crossbeam_channel::unbounded();
-                let (db_tx, db_rx) = crossbeam_channel::unbounded();
+                Self::build_threaded_with_migration_policy(builder, move |ctx| pol.construct(ctx))
+            }
+            None => {
                 let db = Arc::new(RwLock::new(Self::build_internal(
-                    builder,
-                    Some(dml_tx),
-                    Some(db_tx.clone()),
+                    builder, None, None, None,
                 )?));
+                Ok(Self::with_compaction(Self::with_defragmentation(
+                    Self::with_sync(db),
+                )))
+            }
+        }
+    }
 
-                // Discovery Initializiation
-                for os_id in db.read().iter_object_stores()? {
-                    // NOTE: If any of the result resolutions here fail the
-                    // state of the datastore is anyway corrupt and we can
-                    // escalate.
-                    let id = os_id?;
-                    let os = db.write().open_object_store_with_id(id)?;
-                    for (key, info) in os.iter_objects()? {
-                        db_tx
-                            .send(DatabaseMsg::ObjectDiscover(
-                                GlobalObjectId::build(id, info.object_id),
-                                info,
-                                key,
-                            ))
-                            .expect("UNREACHABLE");
-                    }
-                    db.write().close_object_store(os);
+    /// Like [Database::build_threaded], but runs the migration policy
+    /// returned by `construct` instead of the one configured in
+    /// [DatabaseConfiguration::migration_policy].
+    ///
+    /// This allows to use policies implemented outside of this crate, see
+    /// [MigrationPolicy].
+    pub fn build_threaded_with_migration_policy<F>(
+        builder: DatabaseConfiguration,
+        construct: F,
+    ) -> Result<Arc<RwLock<Self>>>
+    where
+        F: FnOnce(MigrationContext) -> Box<dyn MigrationPolicy> + Send + 'static,
+    {
+        let (db, ctx) = Self::build_with_migration_context(builder)?;
+        thread::spawn(move || {
+            let mut policy = construct(ctx);
+            // Migrations must not slow down requests of the user.
+            with_io_priority(IoPriority::Background, || loop {
+                if let Err(e) = policy.thread_loop() {
+                    error!("Automatic Migration Policy encountered {:?}", e);
+                    error!("Continuing and reinitializing policy to avoid errors, but functionality may be limited.");
                 }
+            })
+        });
+        Ok(Self::with_compaction(Self::with_defragmentation(
+            Self::with_sync(db),
+        )))
+    }
+
+    /// Like [Database::build_threaded], but runs the periodic sync,
+    /// defragmentation, compaction and migration policy as tasks of `sim`, see
+    /// [Simulation::build](crate::sim::Simulation::build).
+    #[cfg(feature = "sim")]
+    pub(crate) fn build_simulated(
+        mut builder: DatabaseConfiguration,
+        sim: &crate::sim::Simulation,
+    ) -> Result<Arc<RwLock<Self>>> {
+        // All work has to happen on the thread of the simulation.
+        builder.write_back_threads = 1;
+        builder.flush_threads = 0;
+        let (db, policy) = match builder.migration_policy() {
+            Some(pol) => {
+                let (db, ctx) = Self::build_with_migration_context(builder)?;
+                (db, Some(pol.construct(ctx)))
+            }
+            None => (
+                Arc::new(RwLock::new(Self::build_internal(
+                    builder, None, None, None,
+                )?)),
+                None,
+            ),
+        };
 
-                let other = db.clone();
-                thread::spawn(move || {
-                    let hints = other.read().root_tree.dmu().storage_hints();
-                    let mut policy = pol.construct(dml_rx, db_rx, other, hints);
-                    loop {
-                        if let Err(e) = policy.thread_loop() {
+        if let SyncMode::Periodic { interval_ms } = db.read().builder.sync_mode() {
+            let db = Arc::downgrade(&db);
+            sim.every(std::time::Duration::from_millis(interval_ms), move || {
+                if let Some(db) = db.upgrade() {
+                    sync_timer::sync(&db);
+                }
+            });
+        }
+        if let Some(interval_ms) = db.read().builder.defragmentation.interval_ms {
+            let db = Arc::downgrade(&db);
+            let mut defragmentation = defragmentation::PeriodicDefragmentation::default();
+            sim.every(std::time::Duration::from_millis(interval_ms), move || {
+                if let Some(db) = db.upgrade() {
+                    defragmentation.round(&db);
+                }
+            });
+        }
+        if let Some(interval_ms) = db.read().builder.compaction.interval_ms {
+            let db = Arc::downgrade(&db);
+            let mut compaction = compaction::IdleCompaction::default();
+            sim.every(std::time::Duration::from_millis(interval_ms), move || {
+                if let Some(db) = db.upgrade() {
+                    compaction.round(&db);
+                }
+            });
+        }
+        if let Some(mut policy) = policy {
+            let config = policy.config();
+            sim.every_after(
+                config.grace_period + config.update_period,
+                config.update_period,
+                move || {
+                    with_io_priority(IoPriority::Background, || {
+                        if let Err(e) = policy.round() {
                             error!("Automatic Migration Policy encountered {:?}", e);
-                            error!("Continuing and reinitializing policy to avoid errors, but functionality may be limited.");
                         }
-                    }
-                });
-                db
+                    })
+                },
+            );
+        }
+        Ok(db)
+    }
+
+    // Builds the database along with the context for a migration policy,
+    // which already knows about all existing objects.
+    fn build_with_migration_context(
+        builder: DatabaseConfiguration,
+    ) -> Result<(Arc<RwLock<Self>>, MigrationContext)> {
+        let (dml_tx, dml_rx) = crossbeam_channel::unbounded();
+        let (db_tx, db_rx) = crossbeam_channel::unbounded();
+        let db = Arc::new(RwLock::new(Self::build_internal(
+            builder,
+            Some(dml_tx),
+            Some(db_tx.clone()),
+            None,
+        )?));
+
+        // Discovery Initializiation
+        for os_id in db.read().iter_object_stores()? {
+            // NOTE: If any of the result resolutions here fail the
+            // state of the datastore is anyway corrupt and we can
+            // escalate.
+            let id = os_id?;
+            let os = db.write().open_object_store_with_id(id)?;
+            for (key, info) in os.iter_objects()? {
+                db_tx
+                    .send(DatabaseMsg::ObjectDiscover(
+                        GlobalObjectId::build(id, info.object_id),
+                        info,
+                        key,
+                    ))
+                    .expect("UNREACHABLE");
             }
-            None => Arc::new(RwLock::new(Self::build_internal(builder, None, None)?)),
-        };
-        Ok(Self::with_sync(db))
+            db.write().close_object_store(os);
+        }
+
+        let ctx = MigrationContext::new(dml_rx, db_rx, db.clone());
+        Ok((db, ctx))
+    }
+
+    /// Returns the migrations planned in the latest round of the migration
+    /// policy if it is configured with [crate::migration::MigrationConfig::dry_run].
+    ///
+    /// Returns `None` before the first round and if no dry run is configured.
+    pub fn migration_plan(&self) -> Option<MigrationPlan> {
+        self.migration_plan.lock().clone()
+    }
+
+    /// Returns a receiver for reports about all migrations performed from now
+    /// on, be it by [Dataset::migrate_range] or the migration policy.
+    ///
+    /// The reports queue up until they are received, drop the receiver to
+    /// unsubscribe.
+    pub fn subscribe_migrations(&self) -> Receiver<MigrationReport> {
+        self.migration_reports.subscribe()
+    }
+
+    /// Registers `handler` to be called on each [Event] from now on, e.g. on
+    /// node splits, full storage classes, failed reads, migrations, and
+    /// completed syncs.
+    ///
+    /// Handlers are called synchronously by the thread causing the event,
+    /// which may hold internal locks.  They have to return quickly and must
+    /// neither access the database nor (un)register handlers.  Forward the
+    /// events to another thread for anything else, e.g. with a channel.
+    pub fn register_event_handler<F>(&self, handler: F) -> EventHandlerId
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.root_tree
+            .dmu()
+            .handler()
+            .events
+            .register(Box::new(handler))
+    }
+
+    /// Removes a handler added with [Self::register_event_handler].  Returns
+    /// whether it had still been registered.
+    pub fn unregister_event_handler(&self, id: EventHandlerId) -> bool {
+        self.root_tree.dmu().handler().events.unregister(id)
     }
 
     /// If this [Database] was created with a [SyncMode::Periodic], this function
@@ -515,31 +1093,67 @@ impl Database {
     /// periodic syncing.
     fn with_sync(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
         if let SyncMode::Periodic { interval_ms } = this.read().builder.sync_mode() {
+            let timer = Arc::new(SyncTimer::new(Some(interval_ms)));
+            this.write().sync_timer = Some(Arc::clone(&timer));
+            thread::spawn({
+                let db = this.clone();
+                move || timer.run(db)
+            });
+        }
+        this
+    }
+
+    /// If this [Database] was created with a defragmentation interval, this
+    /// function starts a thread to periodically call `self.defragment()`.
+    fn with_defragmentation(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
+        if let Some(interval_ms) = this.read().builder.defragmentation.interval_ms {
+            thread::spawn({
+                let db = this.clone();
+                move || defragmentation::defragmentation_timer(interval_ms, db)
+            });
+        }
+        this
+    }
+
+    /// If this [Database] was created with a compaction interval, this
+    /// function starts a thread to periodically call `self.compact()` while
+    /// the database is idle.
+    fn with_compaction(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
+        if let Some(interval_ms) = this.read().builder.compaction.interval_ms {
             thread::spawn({
                 let db = this.clone();
-                move || sync_timer::sync_timer(interval_ms, db)
+                move || compaction::compaction_timer(interval_ms, db)
             });
         }
         this
     }
 
     fn sync_ds(&self, ds_id: DatasetId, ds_tree: &ErasedTree) -> Result<()> {
-        trace!("sync_ds: Enter");
-        let ptr = ds_tree.erased_sync()?;
-        trace!("sync_ds: erased_sync");
-        let msg = DatasetData::update_ptr(ptr)?;
-        let key = &dataset_key::data_key(ds_id) as &[_];
-        self.root_tree.insert(key, msg, StoragePreference::NONE)?;
-        Ok(())
+        sync_ds_tree(&self.root_tree, ds_id, ds_tree)
     }
 
     fn flush_delayed_messages(&self) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        handler.queue_dataset_usage();
         loop {
-            let v = std::mem::take(&mut *self.root_tree.dmu().handler().delayed_messages.lock());
-            if v.is_empty() {
+            let mut flushed = false;
+            for (class, delayed_msgs) in handler.delayed_allocation_messages.iter().enumerate() {
+                let v = std::mem::take(&mut *delayed_msgs.lock());
+                if v.is_empty() {
+                    continue;
+                }
+                flushed = true;
+                v.merge_segment_updates_into(&mut handler.written_segment_updates.lock());
+                let tree = allocation_tree_of(self.root_tree.dmu(), class as u8);
+                for (key, msg) in v.into_messages() {
+                    tree.insert(key, msg, StoragePreference::NONE)?;
+                }
+            }
+            let v = std::mem::take(&mut *handler.delayed_messages.lock());
+            if v.is_empty() && !flushed {
                 break;
             }
-            for (key, msg) in v {
+            for (key, msg) in v.into_messages() {
                 self.root_tree.insert(key, msg, StoragePreference::NONE)?;
             }
         }
@@ -548,8 +1162,56 @@ impl Database {
 
     /// Synchronizes the database.
     pub fn sync(&mut self) -> Result<()> {
-        let mut ds_locks = Vec::with_capacity(self.open_datasets.len());
-        for (&ds_id, ds_tree) in &self.open_datasets {
+        self.sync_internal(false).map(|_| ())
+    }
+
+    /// Synchronizes the database while no modifications to the given datasets
+    /// can take place, so that none made concurrently from other threads
+    /// slip in before the sync completes.
+    ///
+    /// Modifications made before this call may already have been written by
+    /// an earlier sync, so they do not become durable together.  Writes to
+    /// multiple datasets which have to be consistent in the case of a crash,
+    /// e.g. an index and its data, are applied with a [WriteBatch].
+    pub fn sync_datasets<M>(&mut self, datasets: &[&Dataset<M>]) -> Result<()> {
+        let mut guards = Vec::with_capacity(datasets.len());
+        for (idx, ds) in datasets.iter().enumerate() {
+            // Handles might be clones of each other, lock each dataset once.
+            if datasets[..idx].iter().any(|other| other.same_dataset(ds)) {
+                continue;
+            }
+            guards.push(ds.lock_exclusive());
+        }
+        self.sync()
+    }
+
+    // Synchronizes the database and returns the written root pointer and tier
+    // information.  If `checkpoint` is set, all blocks of the written
+    // generation are retained before any dataset is unlocked again.
+    //
+    // The IO is issued with the commit priority, unless the calling thread
+    // issues background IO.
+    fn sync_internal(
+        &mut self,
+        checkpoint: bool,
+    ) -> Result<(ObjectPointer, [StorageInfo; NUM_STORAGE_CLASSES])> {
+        with_io_priority(io_priority().max(IoPriority::Commit), || {
+            self.sync_generation(checkpoint)
+        })
+    }
+
+    fn sync_generation(
+        &mut self,
+        checkpoint: bool,
+    ) -> Result<(ObjectPointer, [StorageInfo; NUM_STORAGE_CLASSES])> {
+        let start = clock::now();
+        // Transactions are not split across generations.
+        let _commits = self.root_tree.dmu().handler().commit_lock.write();
+        let open_datasets = self.open_datasets.read();
+        let mut ds_locks = Vec::with_capacity(open_datasets.len());
+        // Datasets are synced in a fixed order to keep the resulting layout
+        // reproducible.
+        for (&ds_id, ds_tree) in open_datasets.iter().sorted_by_key(|&(&ds_id, _)| ds_id) {
             loop {
                 if let Some(lock) = ds_tree.erased_try_lock_root() {
                     ds_locks.push(lock);
@@ -559,26 +1221,35 @@ impl Database {
                 self.sync_ds(ds_id, ds_tree.as_ref())?;
             }
         }
-        let root_ptr = loop {
+        let handler = self.root_tree.dmu().handler();
+        let (root_ptr, allocation_ptrs) = loop {
             self.flush_delayed_messages()?;
-            let allocations_before = self
-                .root_tree
-                .dmu()
-                .handler()
-                .allocations
-                .load(Ordering::Acquire);
+            info!("Sync: syncing allocation trees");
+            // Each tree is settled once nothing but its root node has been
+            // written, the allocation of which is recorded in the next
+            // generation.
+            let mut settled = true;
+            let mut allocation_ptrs = Vec::with_capacity(NUM_STORAGE_CLASSES);
+            for class in 0..NUM_STORAGE_CLASSES as u8 {
+                let allocations_before = handler.allocations.load(Ordering::Acquire);
+                let ptr = allocation_tree_of(self.root_tree.dmu(), class).sync()?;
+                settled &= handler.allocations.load(Ordering::Acquire) - allocations_before <= 1;
+                self.root_tree.insert(
+                    &allocation_tree::key(class)[..],
+                    DefaultMessageAction::insert_msg(&bincode::serialize(&ptr)?),
+                    StoragePreference::NONE,
+                )?;
+                allocation_ptrs.push(ptr);
+            }
+
+            let allocations_before = handler.allocations.load(Ordering::Acquire);
             info!("Sync: syncing root tree");
 
             let root_ptr = self.root_tree.sync()?;
-            let allocations_after = self
-                .root_tree
-                .dmu()
-                .handler()
-                .allocations
-                .load(Ordering::Acquire);
+            let allocations_after = handler.allocations.load(Ordering::Acquire);
             let allocations = allocations_after - allocations_before;
-            if allocations <= 1 {
-                break root_ptr;
+            if settled && allocations <= 1 {
+                break (root_ptr, allocation_ptrs);
             } else {
                 info!("Sync: resyncing -- seen {} allocations", allocations);
             }
@@ -597,18 +1268,39 @@ impl Database {
                 .free_space_tier(idx as u8)
                 .expect("Class hat to exist");
         }
-        Superblock::<ObjectPointer>::write_superblock(pool, &root_ptr, &info)?;
+        Superblock::<ObjectPointer>::write_superblock(
+            pool,
+            &root_ptr,
+            &info,
+            *handler.legacy_generation.read(),
+        )?;
         pool.flush()?;
-        let handler = self.root_tree.dmu().handler();
-        *handler.old_root_allocation.lock_write() = Some((root_ptr.offset(), root_ptr.size()));
+        if checkpoint {
+            handler
+                .checkpoint_generations
+                .write()
+                .insert(root_ptr.generation());
+        }
+        *handler.old_root_allocations.write() = std::iter::once(&root_ptr)
+            .chain(allocation_ptrs.iter())
+            .map(|ptr| (ptr.offset(), ptr.size()))
+            .collect();
         handler.bump_generation();
-        handler
-            .root_tree_snapshot
-            .write()
-            .as_mut()
-            .unwrap()
-            .update_root_node(RootDmu::root_ref_from_ptr(root_ptr));
-        Ok(())
+        for (snapshot, ptr) in handler
+            .allocation_tree_snapshots
+            .iter()
+            .zip(allocation_ptrs)
+        {
+            *snapshot.write() = Some(TreeInner::new_ro(
+                RootDmu::root_ref_from_ptr(ptr),
+                DefaultMessageAction,
+            ));
+        }
+        handler.events.emit(|| Event::SyncCompleted {
+            generation: root_ptr.generation(),
+            duration: clock::elapsed(start),
+        });
+        Ok((root_ptr, info))
     }
 
     /// Drops the entire cache. This is useful when considering performance
@@ -618,6 +1310,19 @@ impl Database {
         Ok(())
     }
 
+    /// Returns the nodes which have been written to another storage class than
+    /// intended because it was full, see
+    /// [DatabaseConfiguration::spill_to_slower_tiers].
+    pub fn displaced_nodes(&self) -> Vec<(PivotKey, Displacement)> {
+        self.root_tree
+            .dmu()
+            .displaced_nodes()
+            .lock()
+            .iter()
+            .map(|(key, displacement)| (key.clone(), *displacement))
+            .collect()
+    }
+
     /// Storage tier information for all available tiers. These are in order as in `storage_prefernce.as_u8()`
     pub fn free_space_tier(&self) -> Vec<StorageInfo> {
         (0..self.root_tree.dmu().spl().storage_class_count())
@@ -631,6 +1336,276 @@ impl Database {
             .collect()
     }
 
+    /// Adds the top-level vdevs of `tier` to the storage class `class` without
+    /// reopening the database.  If the storage class has been empty before,
+    /// it adopts the preferred access type, the limits and the name of `tier`.
+    ///
+    /// The new vdevs are usable immediately and are recorded in the
+    /// superblock on the next sync, from then on they have to be part of the
+    /// configuration the database is opened with.  The configuration returned
+    /// by [Database::write_config_json] already contains them.
+    pub fn add_vdevs(&mut self, class: u8, tier: TierConfiguration) -> Result<()> {
+        if class as usize >= NUM_STORAGE_CLASSES {
+            return Err(Error::NoSuchStorageClass(class));
+        }
+        self.root_tree
+            .dmu()
+            .add_vdevs(class, &tier, SUPERBLOCK_BLOCKS, ROOT_DATASET_ID)?;
+
+        let tiers = &mut self.builder.storage.tiers;
+        if tiers.len() <= class as usize {
+            tiers.resize_with(class as usize + 1, Default::default);
+        }
+        let configured = &mut tiers[class as usize];
+        if configured.top_level_vdevs.is_empty() {
+            configured.preferred_access_type = tier.preferred_access_type;
+            configured.qos = tier.qos;
+            if tier.name.is_some() {
+                configured.name = tier.name;
+            }
+        }
+        configured.top_level_vdevs.extend(tier.top_level_vdevs);
+        Ok(())
+    }
+
+    /// Adds a single top-level vdev to the storage class `class`, see
+    /// [Database::add_vdevs].
+    pub fn add_vdev(&mut self, class: u8, vdev: Vdev) -> Result<()> {
+        self.add_vdevs(
+            class,
+            TierConfiguration {
+                top_level_vdevs: vec![vdev],
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Returns the [StoragePreference] of the tier named `name`, see
+    /// [TierConfiguration::name].
+    pub fn tier(&self, name: &str) -> Option<StoragePreference> {
+        self.root_tree.dmu().spl().tier(name)
+    }
+
+    /// Names the storage class `class`.  The name is stored in the superblock
+    /// on the next sync and kept on reopening unless the configuration names
+    /// the tier differently.
+    pub fn set_tier_name(&mut self, class: u8, name: &str) -> Result<()> {
+        if class as usize >= NUM_STORAGE_CLASSES {
+            return Err(Error::NoSuchStorageClass(class));
+        }
+        self.root_tree
+            .dmu()
+            .spl()
+            .set_tier_name(class, name.to_string())?;
+
+        let tiers = &mut self.builder.storage.tiers;
+        if tiers.len() <= class as usize {
+            tiers.resize_with(class as usize + 1, Default::default);
+        }
+        tiers[class as usize].name = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Makes space available which has been added to the underlying files or
+    /// block devices of the storage pool, e.g. by extending a logical volume,
+    /// without reopening the database.  Returns the number of usable blocks
+    /// gained.
+    ///
+    /// Fails if any device has shrunk.
+    pub fn expand(&self) -> Result<Block<u64>> {
+        Ok(self
+            .root_tree
+            .dmu()
+            .expand(SUPERBLOCK_BLOCKS, ROOT_DATASET_ID)?)
+    }
+
+    /// Moves all data off the top-level vdev `disk` and removes it from the
+    /// running pool.
+    ///
+    /// All trees are walked and each node stored on the vdev is rewritten to
+    /// the remaining vdevs, which need to have enough free space for it.
+    /// Blocks retained by snapshots or checkpoints cannot be moved, in this
+    /// case [Error::VdevInUse] is returned and the vdev is used for
+    /// allocations again.
+    ///
+    /// Disk ids are part of every block pointer, so only the last top-level
+    /// vdev of a storage class can be dropped from the configuration.  Any
+    /// other vdev is emptied and excluded from allocations until the database
+    /// is reopened, but stays configured.  Returns whether the vdev has been
+    /// removed.
+    pub fn evacuate_disk(&mut self, disk: GlobalDiskId) -> Result<bool> {
+        let (class, disk_id) = (disk.storage_class(), disk.disk_id());
+        let dmu = Arc::clone(self.root_tree.dmu());
+        if class as usize >= NUM_STORAGE_CLASSES || disk_id >= dmu.spl().disk_count(class) {
+            return Err(Error::NoSuchVdev(class, disk_id));
+        }
+
+        dmu.set_evacuating(disk, true);
+        if let Err(e) = self.relocate_disk(disk) {
+            dmu.set_evacuating(disk, false);
+            return Err(e);
+        }
+        if disk_id + 1 < dmu.spl().disk_count(class) {
+            return Ok(false);
+        }
+        // Blocks freed on a sealed vdev are only released once it is unsealed.
+        self.unseal_disk(disk)?;
+
+        dmu.remove_last_vdev(class, SUPERBLOCK_BLOCKS, ROOT_DATASET_ID)?;
+        if let Some(tier) = self.builder.storage.tiers.get_mut(class as usize) {
+            tier.top_level_vdevs.pop();
+        }
+        self.sync()?;
+        Ok(true)
+    }
+
+    /// Rewrites all nodes which are not encrypted with the active key of the
+    /// [EncryptionConfiguration], or encrypted at all if none is configured.
+    ///
+    /// Nodes retained by snapshots or checkpoints are not rewritten, the keys
+    /// they are encrypted with have to stay configured.
+    pub fn rotate_encryption_key(&mut self) -> Result<()> {
+        let tag = self.root_tree.dmu().encryption_tag();
+        self.relocate_nodes(&|ptr: &ObjectPointer| ptr.encryption_tag() != tag)
+    }
+
+    /// Returns whether nodes written by an older version of the on-disk
+    /// format may still be in use, see [FORMAT_VERSION].
+    pub fn has_legacy_format(&self) -> bool {
+        self.root_tree
+            .dmu()
+            .handler()
+            .legacy_generation
+            .read()
+            .is_some()
+    }
+
+    /// Rewrites all nodes which may still be in an older on-disk format in
+    /// the current one, see [FORMAT_VERSION].  Otherwise, they are only
+    /// rewritten once modified.  Returns whether no such nodes remain.
+    ///
+    /// Nodes retained by snapshots or checkpoints are not rewritten.  As
+    /// snapshots and checkpoints share unmodified nodes with older
+    /// generations, the upgrade is only completed if none of them exist.
+    pub fn upgrade_format(&mut self) -> Result<bool> {
+        let legacy = match *self.root_tree.dmu().handler().legacy_generation.read() {
+            Some(generation) => generation,
+            None => return Ok(true),
+        };
+        self.relocate_nodes(&|ptr: &ObjectPointer| ptr.generation() <= legacy)?;
+
+        let low = &snapshot_key::data_key(DatasetId::default(), Generation(0)) as &[_];
+        let high = &snapshot_key::data_key_max_all() as &[_];
+        if self.root_tree.range(low..high)?.next().is_some() || !self.list_checkpoints()?.is_empty()
+        {
+            return Ok(false);
+        }
+        *self.root_tree.dmu().handler().legacy_generation.write() = None;
+        self.sync()?;
+        Ok(true)
+    }
+
+    // Rewrites all nodes stored on `disk` until only its superblock blocks
+    // remain allocated.
+    fn relocate_disk(&mut self, disk: GlobalDiskId) -> Result<()> {
+        self.relocate_nodes(&|ptr: &ObjectPointer| ptr.offset().class_disk_id() == disk)?;
+
+        let dmu = self.root_tree.dmu();
+        let (class, disk_id) = (disk.storage_class(), disk.disk_id());
+        let info = dmu
+            .handler()
+            .free_space_disk(disk)
+            .expect("Disk has to exist");
+        // Superblock blocks are reserved at the start and at the end.
+        let reserved = 2 * SUPERBLOCK_BLOCKS.as_u64() * dmu.spl().num_disks(class, disk_id) as u64;
+        if info.total.as_u64() - info.free.as_u64() > reserved {
+            return Err(Error::VdevInUse(class, disk_id));
+        }
+        Ok(())
+    }
+
+    // Rewrites all nodes matching `relocate` until none are left.
+    fn relocate_nodes(&mut self, relocate: &dyn Fn(&ObjectPointer) -> bool) -> Result<()> {
+        let dmu = Arc::clone(self.root_tree.dmu());
+        // Deltas are rewritten along with their base, which the rewrite does
+        // not refer to anymore if it matches as well.
+        let relocate = &|ptr: &ObjectPointer| {
+            relocate(ptr)
+                || dmu
+                    .handler()
+                    .delta_base(ptr.offset(), ptr.info())
+                    .map_or(false, |delta| relocate(&delta.base))
+        };
+        loop {
+            let mut relocated = self.root_tree.relocate_nodes(relocate)?;
+            for class in 0..NUM_STORAGE_CLASSES as u8 {
+                relocated += allocation_tree_of(&dmu, class).relocate_nodes(relocate)?;
+            }
+            let ids = self
+                .iter_datasets()?
+                .map(|id| id.map(|id| DatasetId::unpack(&id)))
+                .collect::<Result<Vec<_>>>()?;
+            for id in ids {
+                // Datasets which are not open are opened for the time being.
+                let ds = match self.open_dataset_with_id::<DefaultMessageAction>(id) {
+                    Ok(ds) => Some(ds),
+                    Err(Error::InUse) => None,
+                    Err(e) => return Err(e),
+                };
+                let result = self
+                    .open_datasets
+                    .read()
+                    .get(&id)
+                    .map_or(Ok(0), |tree| tree.erased_relocate_nodes(relocate));
+                if let Some(ds) = ds {
+                    self.close_dataset(ds)?;
+                }
+                relocated += result?;
+            }
+            // Syncing writes the relocated nodes and frees the blocks of the
+            // root nodes written by the previous sync.
+            self.sync()?;
+            if relocated == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Occupancy of all tiers and their top-level vdevs.
+    pub fn storage_report(&self) -> StorageReport {
+        let dmu = self.root_tree.dmu();
+        let handler = dmu.handler();
+        let tiers = (0..dmu.spl().storage_class_count())
+            .map(|class| TierReport {
+                name: dmu.spl().tier_name(class),
+                info: handler.free_space_tier(class).unwrap(),
+                // Vdevs which are still being added are skipped.
+                disks: (0..dmu.spl().disk_count(class))
+                    .map_while(|disk_id| {
+                        handler.free_space_disk(DiskOffset::construct_disk_id(class, disk_id))
+                    })
+                    .collect(),
+                faulted: dmu.spl().faulted_vdevs(class),
+            })
+            .collect();
+        StorageReport {
+            tiers,
+            allocations: handler.allocations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Usage of the second-level cache, if it is enabled.
+    pub fn l2_cache_stats(&self) -> Option<L2CacheStats> {
+        self.root_tree.dmu().l2_cache_stats()
+    }
+
+    /// Space usage of the given dataset. Only data which has been written
+    /// back from the cache is accounted for.
+    pub fn space_usage<M>(&self, ds: &Dataset<M>) -> SpaceUsage {
+        self.root_tree.dmu().handler().dataset_usage(ds.id())
+    }
+
     #[allow(missing_docs)]
     #[cfg(feature = "internal-api")]
     pub fn root_tree(&self) -> &RootTree<RootDmu> {
@@ -638,6 +1613,44 @@ impl Database {
     }
 }
 
+impl Drop for Database {
+    fn drop(&mut self) {
+        if self.builder.sync_on_drop {
+            if let Err(e) = self.sync() {
+                error!("Syncing the database on drop failed: {:?}", e);
+            }
+        }
+    }
+}
+
+// Returns a handle to the allocation tree of the given storage class.
+fn allocation_tree_of(dmu: &Arc<RootDmu>, class: u8) -> RootTree<RootDmu> {
+    Tree::from_inner(
+        Arc::clone(
+            dmu.handler().allocation_trees[class as usize]
+                .get()
+                .unwrap(),
+        ),
+        Arc::clone(dmu),
+        true,
+        dmu.handler().root_tree_storage_preference,
+    )
+}
+
+fn sync_ds_tree(
+    root_tree: &RootTree<RootDmu>,
+    ds_id: DatasetId,
+    ds_tree: &ErasedTree,
+) -> Result<()> {
+    trace!("sync_ds: Enter");
+    let ptr = ds_tree.erased_sync()?;
+    trace!("sync_ds: erased_sync");
+    let msg = DatasetData::update_ptr(ptr)?;
+    let key = &dataset_key::data_key(ds_id) as &[_];
+    root_tree.insert(key, msg, StoragePreference::NONE)?;
+    Ok(())
+}
+
 fn fetch_ds_data<T>(root_tree: &T, id: DatasetId) -> Result<DatasetData<ObjectPointer>>
 where
     T: TreeLayer<DefaultMessageAction>,
@@ -721,9 +1734,20 @@ impl StaticSize for DatasetId {
 struct DatasetData<P> {
     previous_snapshot: Option<Generation>,
     ptr: P,
+    // Stored behind the space of the largest pointer, so that updates of the
+    // pointer leave it intact.
+    storage_preference: StoragePreference,
 }
 
 impl<P> DatasetData<P> {
+    fn storage_preference_offset() -> usize {
+        8 + ObjectPointer::static_size()
+    }
+
+    fn update_storage_preference(pref: StoragePreference) -> SlicedCowBytes {
+        DefaultMessageAction::upsert_msg(Self::storage_preference_offset() as u32, &[pref.as_u8()])
+    }
+
     fn update_previous_snapshot(x: Option<Generation>) -> SlicedCowBytes {
         let mut b = [0; 8];
         let x = if let Some(generation) = x {
@@ -756,6 +1780,9 @@ impl<P: Serialize> DatasetData<P> {
         };
         LittleEndian::write_u64(&mut v, x);
         serialize_into(&mut v, &self.ptr)?;
+        assert!(v.len() <= Self::storage_preference_offset());
+        v.resize(Self::storage_preference_offset(), 0);
+        v.push(self.storage_preference.as_u8());
         Ok(v)
     }
 }
@@ -763,18 +1790,40 @@ impl<P: DeserializeOwned> DatasetData<P> {
     fn unpack(b: &[u8]) -> Result<Self> {
         let x = LittleEndian::read_u64(
             b.get(..8)
-                .ok_or(Error::Generic("invalid data".to_string()))?,
+                .ok_or(Error::Corrupted("truncated dataset entry"))?,
         );
         let ptr = deserialize(&b[8..])?;
+        // Datasets created before the preference was stored have none.
+        let storage_preference = match b.get(Self::storage_preference_offset()) {
+            None => StoragePreference::NONE,
+            Some(&pref) if pref == StoragePreference::NONE.as_u8() => StoragePreference::NONE,
+            Some(&pref) if (pref as usize) < NUM_STORAGE_CLASSES => StoragePreference::new(pref),
+            Some(_) => return Err(Error::Corrupted("invalid storage preference of dataset")),
+        };
         Ok(DatasetData {
             previous_snapshot: if x > 0 { Some(Generation(x)) } else { None },
             ptr,
+            storage_preference,
         })
     }
 }
 
 /// Internal identifier of a generation
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Readable,
+    Writable,
+)]
 pub struct Generation(u64);
 
 impl StaticSize for Generation {
@@ -784,6 +1833,15 @@ impl StaticSize for Generation {
 }
 
 impl Generation {
+    /// Returns the number of this generation.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) const fn from_u64(generation: u64) -> Self {
+        Generation(generation)
+    }
+
     fn pack(self) -> [u8; 8] {
         let mut b = [0; 8];
         BigEndian::write_u64(&mut b, self.0);
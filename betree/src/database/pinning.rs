@@ -0,0 +1,190 @@
+//! Rules pinning key ranges of a dataset to a storage class, see
+//! [super::Dataset::pin_range].
+use super::{errors::*, root_tree_msg::dataset_pins, DatasetId};
+use crate::{
+    cow_bytes::CowBytes,
+    tree::{DefaultMessageAction, TreeLayer},
+    StoragePreference,
+};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Borrow,
+    ops::{Bound, RangeBounds},
+};
+
+/// The keys from `start` up to, but excluding, `end` pinned to `pref`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PinnedRange {
+    start: Vec<u8>,
+    // `None` if the range is unbounded.
+    end: Option<Vec<u8>>,
+    pref: StoragePreference,
+}
+
+impl PinnedRange {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && self.end.as_ref().map_or(true, |end| key < end.as_slice())
+    }
+
+    fn is_within(&self, start: &[u8], end: Option<&[u8]>) -> bool {
+        start <= self.start.as_slice()
+            && match (end, &self.end) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(end), Some(own_end)) => own_end.as_slice() <= end,
+            }
+    }
+}
+
+// Converts the bounds to a half-open range. The successor of a key is the key
+// followed by a null byte, so inclusive bounds are moved behind it.
+fn half_open<R, K>(range: &R) -> (Vec<u8>, Option<Vec<u8>>)
+where
+    R: RangeBounds<K>,
+    K: Borrow<[u8]>,
+{
+    let successor = |key: &K| {
+        let mut key = key.borrow().to_vec();
+        key.push(0);
+        key
+    };
+    let start = match range.start_bound() {
+        Bound::Included(key) => key.borrow().to_vec(),
+        Bound::Excluded(key) => successor(key),
+        Bound::Unbounded => Vec::new(),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(key) => Some(successor(key)),
+        Bound::Excluded(key) => Some(key.borrow().to_vec()),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+/// All pinning rules of a dataset.  Later rules take precedence over earlier
+/// ones where they overlap.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct PinnedRanges(Vec<PinnedRange>);
+
+impl PinnedRanges {
+    /// Loads the rules of the dataset `id` from the root tree.
+    pub(super) fn fetch<T>(root_tree: &T, id: DatasetId) -> Result<Self>
+    where
+        T: TreeLayer<DefaultMessageAction>,
+    {
+        match root_tree.get(dataset_pins::key(id))? {
+            Some(data) => Ok(deserialize(&data)?),
+            None => Ok(PinnedRanges::default()),
+        }
+    }
+
+    /// Stores the rules of the dataset `id` in the root tree.
+    pub(super) fn store<T>(&self, root_tree: &T, id: DatasetId) -> Result<()>
+    where
+        T: TreeLayer<DefaultMessageAction>,
+    {
+        let key = CowBytes::from(&dataset_pins::key(id)[..]);
+        let msg = if self.0.is_empty() {
+            DefaultMessageAction::delete_msg()
+        } else {
+            DefaultMessageAction::insert_msg(&serialize(self)?)
+        };
+        root_tree.insert(key, msg, StoragePreference::NONE)?;
+        Ok(())
+    }
+
+    /// Pins all keys in `range` to `pref`.
+    pub(super) fn pin<R, K>(&mut self, range: &R, pref: StoragePreference)
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        let (start, end) = half_open(range);
+        // Rules covered completely by the new one have no effect anymore.
+        self.0
+            .retain(|rule| !rule.is_within(&start, end.as_deref()));
+        self.0.push(PinnedRange { start, end, pref });
+    }
+
+    /// Removes all rules which lie within `range`.
+    pub(super) fn unpin<R, K>(&mut self, range: &R)
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        let (start, end) = half_open(range);
+        self.0
+            .retain(|rule| !rule.is_within(&start, end.as_deref()));
+    }
+
+    /// Replaces the storage preference of all rules by the result of `f`,
+    /// rules for which it returns `None` are removed.
+    pub(super) fn map_preferences<F>(&mut self, mut f: F)
+    where
+        F: FnMut(StoragePreference) -> Option<StoragePreference>,
+    {
+        self.0.retain_mut(|rule| match f(rule.pref) {
+            Some(pref) => {
+                rule.pref = pref;
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Returns the storage preference `key` is pinned to, if any.
+    pub(super) fn preference(&self, key: &[u8]) -> Option<StoragePreference> {
+        self.0
+            .iter()
+            .rev()
+            .find(|rule| rule.contains(key))
+            .map(|rule| rule.pref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PinnedRanges;
+    use crate::StoragePreference;
+
+    #[test]
+    fn bounds_are_respected() {
+        let mut pins = PinnedRanges::default();
+        pins.pin(&(&b"b"[..]..=&b"d"[..]), StoragePreference::FASTEST);
+        assert_eq!(pins.preference(b"a"), None);
+        assert_eq!(pins.preference(b"b"), Some(StoragePreference::FASTEST));
+        assert_eq!(pins.preference(b"d"), Some(StoragePreference::FASTEST));
+        assert_eq!(pins.preference(b"d\0"), None);
+
+        pins.pin(&(&b"x"[..]..), StoragePreference::SLOWEST);
+        assert_eq!(pins.preference(b"w"), None);
+        assert_eq!(pins.preference(b"zzz"), Some(StoragePreference::SLOWEST));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let mut pins = PinnedRanges::default();
+        pins.pin(&(&b"a"[..]..&b"z"[..]), StoragePreference::FASTEST);
+        pins.pin(&(&b"m"[..]..&b"n"[..]), StoragePreference::SLOW);
+        assert_eq!(pins.preference(b"c"), Some(StoragePreference::FASTEST));
+        assert_eq!(pins.preference(b"m"), Some(StoragePreference::SLOW));
+
+        // Covering all rules replaces them.
+        pins.pin::<_, &[u8]>(&(..), StoragePreference::FAST);
+        assert_eq!(pins.0.len(), 1);
+        assert_eq!(pins.preference(b"m"), Some(StoragePreference::FAST));
+    }
+
+    #[test]
+    fn unpin_removes_contained_rules() {
+        let mut pins = PinnedRanges::default();
+        pins.pin(&(&b"a"[..]..&b"c"[..]), StoragePreference::FASTEST);
+        pins.pin(&(&b"b"[..]..&b"z"[..]), StoragePreference::FASTEST);
+        pins.unpin(&(&b"a"[..]..&b"d"[..]));
+        assert_eq!(pins.preference(b"a"), None);
+        assert_eq!(pins.preference(b"c"), Some(StoragePreference::FASTEST));
+        pins.unpin::<_, &[u8]>(&(..));
+        assert_eq!(pins, PinnedRanges::default());
+    }
+}
@@ -0,0 +1,248 @@
+//! Quota groups limiting the space used by a set of datasets, see
+//! [Database::create_quota_group].
+//!
+//! Groups are stored in the root tree and loaded into the
+//! [Handler](super::Handler) when the database is opened.  The usage of a
+//! group is the sum of the allocated blocks of its datasets, which are
+//! tracked by the handler anyway, see [Database::space_usage].
+use super::{errors::*, root_tree_msg::quota_group, Database, Dataset, DatasetId};
+use crate::{
+    cow_bytes::CowBytes,
+    object::ObjectStore,
+    tree::{DefaultMessageAction, TreeLayer},
+    StoragePreference,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The limit and usage of a quota group, see [Database::quota_groups].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaGroupInfo {
+    /// The name of the group.
+    pub name: CowBytes,
+    /// The number of bytes the datasets of the group may allocate together.
+    pub limit: u64,
+    /// The number of bytes allocated by the datasets of the group, including
+    /// the blocks which are only retained for snapshots.
+    pub used: u64,
+    /// The datasets assigned to the group.
+    pub datasets: Vec<DatasetId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuotaGroup {
+    limit: u64,
+    datasets: BTreeSet<DatasetId>,
+}
+
+impl QuotaGroup {
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub(crate) fn datasets(&self) -> impl Iterator<Item = DatasetId> + '_ {
+        self.datasets.iter().copied()
+    }
+}
+
+/// The quota groups of a database.
+#[derive(Default)]
+pub(crate) struct QuotaGroups {
+    groups: BTreeMap<CowBytes, QuotaGroup>,
+    // The group each assigned dataset belongs to.
+    members: HashMap<DatasetId, CowBytes>,
+}
+
+impl QuotaGroups {
+    /// Loads all groups from the root tree.
+    pub(super) fn fetch<T>(root_tree: &T) -> Result<Self>
+    where
+        T: TreeLayer<DefaultMessageAction>,
+    {
+        let mut groups = QuotaGroups::default();
+        let low = &quota_group::min_key() as &[_];
+        let high = &quota_group::max_key() as &[_];
+        for entry in root_tree.range(low..high)? {
+            let (key, value) = entry?;
+            let name = CowBytes::from(quota_group::name_from_key(&key));
+            let group: QuotaGroup = bincode::deserialize(&value)?;
+            for id in group.datasets() {
+                groups.members.insert(id, name.clone());
+            }
+            groups.groups.insert(name, group);
+        }
+        Ok(groups)
+    }
+
+    // Stores the group `name` in the root tree, or removes it if it does not
+    // exist anymore.
+    fn store<T>(&self, root_tree: &T, name: &[u8]) -> Result<()>
+    where
+        T: TreeLayer<DefaultMessageAction>,
+    {
+        let msg = match self.groups.get(name) {
+            Some(group) => DefaultMessageAction::insert_msg(&bincode::serialize(group)?),
+            None => DefaultMessageAction::delete_msg(),
+        };
+        root_tree.insert(quota_group::key(name), msg, StoragePreference::NONE)?;
+        Ok(())
+    }
+
+    /// Returns the group the given dataset is assigned to, if any.
+    pub(crate) fn group_of(&self, id: DatasetId) -> Option<(&CowBytes, &QuotaGroup)> {
+        let name = self.members.get(&id)?;
+        Some((name, &self.groups[name]))
+    }
+}
+
+impl Database {
+    /// Creates a quota group of the given name, which limits the space
+    /// allocated by its datasets together to `limit` bytes.
+    ///
+    /// Once the datasets of a group have reached the limit, insertions and
+    /// upserts into them fail with [Error::QuotaExceeded] while deletions
+    /// remain possible.  As space is only allocated when data is written back
+    /// from the cache, the usage may exceed the limit by the data written in
+    /// the meantime.
+    pub fn create_quota_group(&self, name: &[u8], limit: u64) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        let mut groups = handler.quota_groups.write();
+        if groups.groups.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        groups.groups.insert(
+            CowBytes::from(name),
+            QuotaGroup {
+                limit,
+                datasets: BTreeSet::new(),
+            },
+        );
+        groups.store(&self.root_tree, name)
+    }
+
+    /// Changes the limit of the given quota group to `limit` bytes.
+    pub fn set_quota_limit(&self, name: &[u8], limit: u64) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        let mut groups = handler.quota_groups.write();
+        groups
+            .groups
+            .get_mut(name)
+            .ok_or(Error::DoesNotExist)?
+            .limit = limit;
+        groups.store(&self.root_tree, name)
+    }
+
+    /// Deletes the given quota group, its datasets are no longer limited.
+    pub fn delete_quota_group(&self, name: &[u8]) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        let mut groups = handler.quota_groups.write();
+        let group = groups.groups.remove(name).ok_or(Error::DoesNotExist)?;
+        for id in group.datasets() {
+            groups.members.remove(&id);
+        }
+        groups.store(&self.root_tree, name)
+    }
+
+    /// Assigns the given dataset to the quota group `name`, or removes it from
+    /// its group if `name` is `None`.  A dataset belongs to at most one
+    /// group, assigning it to another group moves it there.
+    pub fn assign_quota_group<M>(&self, ds: &Dataset<M>, name: Option<&[u8]>) -> Result<()> {
+        self.assign_quota_group_ids(&[ds.id()], name)
+    }
+
+    /// Assigns both datasets of the given object store to the quota group
+    /// `name` like [Database::assign_quota_group], so that the objects of the
+    /// store are limited together with their metadata.
+    pub fn assign_object_store_quota_group(
+        &self,
+        store: &ObjectStore,
+        name: Option<&[u8]>,
+    ) -> Result<()> {
+        self.assign_quota_group_ids(&store.dataset_ids(), name)
+    }
+
+    fn assign_quota_group_ids(&self, ids: &[DatasetId], name: Option<&[u8]>) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        let mut groups = handler.quota_groups.write();
+        if let Some(name) = name {
+            if !groups.groups.contains_key(name) {
+                return Err(Error::DoesNotExist);
+            }
+        }
+        for &id in ids {
+            if let Some(previous) = groups.members.remove(&id) {
+                if let Some(group) = groups.groups.get_mut(&previous) {
+                    group.datasets.remove(&id);
+                }
+                groups.store(&self.root_tree, &previous)?;
+            }
+            if let Some(name) = name {
+                groups.members.insert(id, CowBytes::from(name));
+                groups.groups.get_mut(name).unwrap().datasets.insert(id);
+            }
+        }
+        match name {
+            Some(name) => groups.store(&self.root_tree, name),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the limit and usage of the given quota group.
+    pub fn quota_group(&self, name: &[u8]) -> Result<QuotaGroupInfo> {
+        let handler = self.root_tree.dmu().handler();
+        let groups = handler.quota_groups.read();
+        let (name, group) = groups
+            .groups
+            .get_key_value(name)
+            .ok_or(Error::DoesNotExist)?;
+        Ok(QuotaGroupInfo {
+            name: name.clone(),
+            limit: group.limit,
+            used: handler.quota_group_usage(group),
+            datasets: group.datasets().collect(),
+        })
+    }
+
+    /// Lists the limits and usage of all quota groups, ordered by name.
+    pub fn quota_groups(&self) -> Vec<QuotaGroupInfo> {
+        let handler = self.root_tree.dmu().handler();
+        let groups = handler.quota_groups.read();
+        groups
+            .groups
+            .iter()
+            .map(|(name, group)| QuotaGroupInfo {
+                name: name.clone(),
+                limit: group.limit,
+                used: handler.quota_group_usage(group),
+                datasets: group.datasets().collect(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuotaGroups;
+    use crate::{cow_bytes::CowBytes, database::DatasetId};
+
+    #[test]
+    fn members_point_to_their_group() {
+        let mut groups = QuotaGroups::default();
+        let id = DatasetId::default().next();
+        assert!(groups.group_of(id).is_none());
+
+        let name = CowBytes::from(&b"tenant"[..]);
+        groups.groups.insert(
+            name.clone(),
+            super::QuotaGroup {
+                limit: 4096,
+                datasets: [id].into_iter().collect(),
+            },
+        );
+        groups.members.insert(id, name);
+        let (name, group) = groups.group_of(id).unwrap();
+        assert_eq!(&name[..], b"tenant");
+        assert_eq!(group.limit(), 4096);
+        assert_eq!(group.datasets().collect::<Vec<_>>(), vec![id]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Read-only views of datasets pinned to a synced generation.
+//!
+//! A [ReadView] reads a dataset in the state written by a sync while the
+//! dataset is modified further, e.g. for long-running scans which should see
+//! a consistent state without blocking writers.  Unlike a
+//! [Snapshot](super::Snapshot), a view is not recorded in the root tree and
+//! does not outlive the database handle.
+//!
+//! Blocks of the pinned state which are freed by writers are kept in the dead
+//! list of the dataset like blocks of snapshots, see
+//! [Handler::copy_on_write](super::Handler::copy_on_write).  They are
+//! reclaimed by [Database::close_read_view], or by
+//! [Database::audit_dead_lists] if a view has only been dropped.
+use super::{
+    errors::*, fetch_ds_data, root_tree_msg::deadlist, root_tree_msg::snapshot, Database, Dataset,
+    DatasetId, DeadListData, Generation, MessageTree, RootDmu,
+};
+use crate::{
+    allocator::Action,
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    tree::{DefaultMessageAction, MessageAction, Tree, TreeLayer},
+    StoragePreference,
+};
+use std::{borrow::Borrow, ops::RangeBounds, sync::Arc};
+
+/// A read-only view of a dataset in the state of a synced generation, see
+/// [Database::open_read_view].
+pub struct ReadView<Message = DefaultMessageAction> {
+    tree: MessageTree<RootDmu, Message>,
+    dmu: Arc<RootDmu>,
+    id: DatasetId,
+    generation: Generation,
+}
+
+impl Database {
+    /// Returns the generation written by the most recent sync, e.g. to open a
+    /// [ReadView] of it.
+    pub fn synced_generation(&self) -> Generation {
+        let current = self.root_tree.dmu().handler().current_generation();
+        Generation(current.as_u64() - 1)
+    }
+
+    /// Opens a read-only view of the given dataset in the state written by
+    /// the sync of `generation`.
+    ///
+    /// The view stays readable while the dataset is modified, the blocks it
+    /// refers to are retained until it is closed.  The state is only
+    /// available as long as no block of it has been freed, i.e. it has to be
+    /// opened before the dataset is modified after the sync.  Otherwise
+    /// [Error::GenerationUnavailable] is returned.
+    pub fn open_read_view<M>(&self, ds: &Dataset<M>, generation: Generation) -> Result<ReadView<M>>
+    where
+        M: MessageAction + Clone + 'static,
+    {
+        self.open_read_view_of(
+            ds.id(),
+            ds.call_tree(|tree| tree.msg_action().clone()),
+            generation,
+        )
+    }
+
+    /// Synchronizes the database and opens a read-only view of the given
+    /// dataset in the written state.  Unlike [Database::open_read_view] after
+    /// a sync, this cannot fail due to concurrent modifications of the dataset,
+    /// which are blocked until the view is opened.
+    pub fn sync_read_view<M>(&mut self, ds: &Dataset<M>) -> Result<ReadView<M>>
+    where
+        M: MessageAction + Clone + 'static,
+    {
+        let (id, msg_action) = (ds.id(), ds.call_tree(|tree| tree.msg_action().clone()));
+        let _guard = ds.lock_exclusive();
+        self.sync()?;
+        self.open_read_view_of(id, msg_action, self.synced_generation())
+    }
+
+    fn open_read_view_of<M>(
+        &self,
+        id: DatasetId,
+        msg_action: M,
+        generation: Generation,
+    ) -> Result<ReadView<M>>
+    where
+        M: MessageAction + 'static,
+    {
+        let handler = self.root_tree.dmu().handler();
+        let ptr = fetch_ds_data(&self.root_tree, id)?.ptr;
+        if ptr.generation() > generation
+            || generation >= handler.current_generation()
+            || !handler.pin_read_view(id, generation, ptr)
+        {
+            return Err(Error::GenerationUnavailable(generation));
+        }
+        Ok(ReadView {
+            tree: Tree::open(
+                id,
+                ptr,
+                msg_action,
+                Arc::clone(self.root_tree.dmu()),
+                StoragePreference::NONE,
+            ),
+            dmu: Arc::clone(self.root_tree.dmu()),
+            id,
+            generation,
+        })
+    }
+
+    /// Closes the given view and deallocates the blocks which have only been
+    /// retained for it.  The deallocation becomes durable with the next sync.
+    pub fn close_read_view<M>(&self, view: ReadView<M>) -> Result<()> {
+        let (id, generation) = (view.id, view.generation);
+        drop(view);
+        self.release_read_view(id, generation)
+    }
+
+    // Deallocates the blocks of the dataset `id` which have only been
+    // retained for `generation`, whose pin has been released already.
+    pub(super) fn release_read_view(&self, id: DatasetId, generation: Generation) -> Result<()> {
+        // Dead list entries are queued until the next sync.
+        self.flush_delayed_messages()?;
+
+        let handler = self.root_tree.dmu().handler();
+        let mut retained = handler.read_view_generations(id);
+        retained.extend(
+            self.list_checkpoints()?
+                .into_iter()
+                .map(|info| info.generation),
+        );
+        let low = &snapshot::data_key(id, Generation(0)) as &[_];
+        let high = &snapshot::data_key_max(id) as &[_];
+        for entry in self.root_tree.range(low..high)? {
+            let (key, _) = entry?;
+            retained.push(snapshot::generation_from_data_key(&key));
+        }
+
+        let low = &deadlist::min_key(id, generation.next()) as &[_];
+        let high = &deadlist::max_key_ds(id) as &[_];
+        let mut released = Vec::new();
+        for entry in self.root_tree.range(low..high)? {
+            let (key, value) = entry?;
+            let data = DeadListData::unpack(&value)?;
+            let death = deadlist::generation_from_key(&key);
+            let retains = |g: &Generation| data.birth <= *g && *g < death;
+            if retains(&generation) && !retained.iter().any(retains) {
+                released.push((key, data.size));
+            }
+        }
+        for (key, size) in released {
+            let offset = deadlist::offset_from_key(&key);
+            handler.update_allocation_bitmap(
+                offset,
+                size,
+                Action::Deallocate,
+                id,
+                self.root_tree.dmu(),
+            )?;
+            self.root_tree.dmu().release_delta(offset);
+            self.root_tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<Message> ReadView<Message> {
+    /// Returns the generation of the state of the view.
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+}
+
+impl<Message: MessageAction + 'static> ReadView<Message> {
+    /// Returns the value for the given key if existing.
+    pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
+        Ok(self.tree.get(key)?)
+    }
+
+    /// Iterates over all key-value pairs in the given key range.
+    pub fn range<R, K>(
+        &self,
+        range: R,
+    ) -> Result<Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        Ok(Box::new(self.tree.range(range)?.map(|r| Ok(r?))))
+    }
+}
+
+impl<Message> Drop for ReadView<Message> {
+    fn drop(&mut self) {
+        self.dmu.handler().unpin_read_view(self.id, self.generation);
+    }
+}
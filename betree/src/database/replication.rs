@@ -0,0 +1,467 @@
+//! Asynchronous replication of a database to a follower, see
+//! [Database::ship_replication] and [Database::apply_replication].
+//!
+//! The leader ships the committed state of its datasets generation by
+//! generation.  A batch holds the changes from the generation the follower
+//! has applied last, its [ReplicationCursor], to the latest synced one.
+//! Datasets whose root node is unchanged are skipped, the others are compared
+//! entry by entry like the snapshots of a backup, see
+//! [Database::backup](super::Database::backup).  The state a batch is based
+//! on is retained by read view pins on the leader until the next batch has
+//! been shipped, so that shipping can be retried after a failed transport.
+//!
+//! The follower stores its cursor in its root tree, so that replication
+//! resumes after either side has been reopened.  A leader which does not hold
+//! the state of the follower's cursor anymore, e.g. after a restart, ships a
+//! full batch instead, which replaces the contents of the datasets.
+//!
+//! Snapshots, checkpoints and pinned key ranges are not replicated.
+use super::{
+    backup::{entries, for_each_change, DatasetKind, Entries},
+    errors::*,
+    fetch_ds_data,
+    root_tree_msg::{replication, DATASET_NAME_TO_ID},
+    Database, Dataset, DatasetId, Generation, ObjectPointer,
+};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    object::MetaMessageAction,
+    tree::{DefaultMessageAction, TreeLayer},
+    StoragePreference,
+};
+use bincode::{deserialize_from, serialize_into};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const BATCH_MAGIC: [u8; 8] = *b"BETREREP";
+const BATCH_VERSION: u32 = 1;
+// How often pinning the synced state is retried if datasets are modified in
+// the meantime.
+const PIN_ATTEMPTS: usize = 8;
+
+/// The position of a follower in the replicated generations of its leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationCursor {
+    /// The generation of the leader whose state the follower has applied.
+    pub generation: Generation,
+}
+
+/// Carries replication batches from a leader to its follower, e.g. over the
+/// network.  See [Database::ship_replication].
+pub trait ReplicationTransport {
+    /// Returns the cursor of the follower, see [Database::replication_cursor].
+    fn cursor(&mut self) -> Result<Option<ReplicationCursor>>;
+
+    /// Delivers `batch` to the follower, which applies it with
+    /// [Database::apply_replication].  The batch is only regarded as shipped
+    /// once this function returns successfully.
+    fn ship(&mut self, batch: Vec<u8>) -> Result<()>;
+}
+
+/// The replication state of a leader for a single follower, which is passed
+/// to [Database::ship_replication].
+///
+/// It retains the state last shipped to the follower, which should be
+/// released with [Database::close_replication] once the follower is not
+/// served anymore.
+#[derive(Default)]
+pub struct ReplicationLeader {
+    base: Option<ReplicationState>,
+}
+
+impl ReplicationLeader {
+    /// Creates a leader which has not shipped any batch yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cursor of the last shipped batch.
+    pub fn cursor(&self) -> Option<ReplicationCursor> {
+        self.base.as_ref().map(ReplicationState::cursor)
+    }
+}
+
+// The datasets of the leader in the state of a synced generation, which is
+// retained by read view pins.
+struct ReplicationState {
+    generation: Generation,
+    datasets: BTreeMap<DatasetId, (CowBytes, ObjectPointer)>,
+}
+
+impl ReplicationState {
+    fn cursor(&self) -> ReplicationCursor {
+        ReplicationCursor {
+            generation: self.generation,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchHeader {
+    magic: [u8; 8],
+    version: u32,
+    // The generation the changes are based on, `None` for a full batch.
+    base: Option<Generation>,
+    generation: Generation,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    // Starts a dataset, the following entry records belong to it.  With
+    // `reset` set its previous contents are removed first.
+    Dataset {
+        name: CowBytes,
+        kind: DatasetKind,
+        reset: bool,
+    },
+    Put {
+        key: CowBytes,
+        value: SlicedCowBytes,
+    },
+    Delete {
+        key: CowBytes,
+    },
+    ObjectStore {
+        name: CowBytes,
+        data: CowBytes,
+        meta: CowBytes,
+    },
+    End,
+}
+
+// A dataset of the follower to which a batch is applied.
+struct Target {
+    id: DatasetId,
+    kind: DatasetKind,
+    // The dataset if it has been opened for the batch, datasets opened by
+    // the user are modified through their open tree.
+    opened: Option<Opened>,
+}
+
+enum Opened {
+    Plain(Dataset),
+    ObjectMeta(Dataset<MetaMessageAction>),
+}
+
+impl Target {
+    fn insert(&self, db: &Database, key: CowBytes, msg: SlicedCowBytes) -> Result<()> {
+        let open_datasets = db.open_datasets.read();
+        let tree = open_datasets.get(&self.id).ok_or(Error::Closed)?;
+        Ok(tree.erased_insert(key, msg)?)
+    }
+
+    fn put(&self, db: &Database, key: CowBytes, value: SlicedCowBytes) -> Result<()> {
+        let msg = match self.kind {
+            DatasetKind::Plain => DefaultMessageAction::insert_msg(&value),
+            DatasetKind::ObjectMeta => MetaMessageAction::replace_msg(&key, &value)?,
+        };
+        self.insert(db, key, msg)
+    }
+
+    fn delete(&self, db: &Database, key: CowBytes) -> Result<()> {
+        let msg = match self.kind {
+            DatasetKind::Plain => DefaultMessageAction::delete_msg(),
+            DatasetKind::ObjectMeta => MetaMessageAction::delete_msg(&key),
+        };
+        self.insert(db, key, msg)
+    }
+
+    // Removes all entries of the dataset.
+    fn clear(&self, db: &Database) -> Result<()> {
+        let keys: Vec<CowBytes> = {
+            let open_datasets = db.open_datasets.read();
+            let tree = open_datasets.get(&self.id).ok_or(Error::Closed)?;
+            tree.erased_range()?
+                .map(|entry| Ok(entry?.0))
+                .collect::<Result<_>>()?
+        };
+        for key in keys {
+            self.delete(db, key)?;
+        }
+        Ok(())
+    }
+
+    fn close(self, db: &Database) -> Result<()> {
+        match self.opened {
+            Some(Opened::Plain(ds)) => db.close_dataset(ds),
+            Some(Opened::ObjectMeta(ds)) => db.close_dataset(ds),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Database {
+    /// Ships the changes since the state last applied by the follower behind
+    /// `transport` to it and returns the new cursor of the follower.
+    ///
+    /// The database is synced first and the latest synced generation is
+    /// shipped.  If `leader` does not hold the state of the follower's
+    /// cursor, all entries are shipped.  The shipped state is retained in
+    /// `leader` until the next batch has been shipped, the previous one is
+    /// released then.  Datasets may be modified concurrently.
+    pub fn ship_replication<T: ReplicationTransport>(
+        &mut self,
+        leader: &mut ReplicationLeader,
+        transport: &mut T,
+    ) -> Result<ReplicationCursor> {
+        let follower = transport.cursor()?;
+        let state = self.pin_replication_state()?;
+        let base = leader
+            .base
+            .as_ref()
+            .filter(|base| Some(base.cursor()) == follower);
+        let shipped = self
+            .replication_batch(base, &state)
+            .and_then(|batch| transport.ship(batch));
+        if let Err(e) = shipped {
+            self.release_replication_state(state)?;
+            return Err(e);
+        }
+        let cursor = state.cursor();
+        if let Some(previous) = leader.base.replace(state) {
+            self.release_replication_state(previous)?;
+        }
+        Ok(cursor)
+    }
+
+    /// Releases the state retained by `leader`.  The deallocation of its
+    /// blocks becomes durable with the next sync.
+    pub fn close_replication(&self, mut leader: ReplicationLeader) -> Result<()> {
+        match leader.base.take() {
+            Some(state) => self.release_replication_state(state),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies a batch shipped by [Database::ship_replication] and returns
+    /// the new cursor of this database.
+    ///
+    /// Fails with [Error::ReplicationGap] if the batch is based on another
+    /// generation than the one applied last.  Datasets are created as
+    /// needed, datasets opened by the user are modified in place.  The batch
+    /// and the cursor are synced before this function returns.
+    pub fn apply_replication(&mut self, batch: &[u8]) -> Result<ReplicationCursor> {
+        let mut reader = batch;
+        let header: BatchHeader = deserialize_from(&mut reader)?;
+        if header.magic != BATCH_MAGIC || header.version != BATCH_VERSION {
+            return Err(Error::InvalidReplicationBatch);
+        }
+        let applied = self.replication_cursor()?.map(|cursor| cursor.generation);
+        if header.base.is_some() && header.base != applied {
+            return Err(Error::ReplicationGap(applied));
+        }
+
+        let mut target: Option<Target> = None;
+        loop {
+            match deserialize_from(&mut reader)? {
+                Record::Dataset { name, kind, reset } => {
+                    if let Some(target) = target.take() {
+                        target.close(self)?;
+                    }
+                    let next = self.replication_target(&name, kind)?;
+                    if reset {
+                        next.clear(self)?;
+                    }
+                    target = Some(next);
+                }
+                Record::Put { key, value } => target
+                    .as_ref()
+                    .ok_or(Error::InvalidReplicationBatch)?
+                    .put(self, key, value)?,
+                Record::Delete { key } => target
+                    .as_ref()
+                    .ok_or(Error::InvalidReplicationBatch)?
+                    .delete(self, key)?,
+                Record::ObjectStore { name, data, meta } => {
+                    let data = self.lookup_dataset_id(&data)?;
+                    let meta = self.lookup_dataset_id(&meta)?;
+                    self.register_object_store(&name, data, meta)?;
+                }
+                Record::End => break,
+            }
+        }
+        if let Some(target) = target.take() {
+            target.close(self)?;
+        }
+
+        let cursor = ReplicationCursor {
+            generation: header.generation,
+        };
+        self.root_tree.insert(
+            &replication::cursor_key()[..],
+            DefaultMessageAction::insert_msg(&bincode::serialize(&cursor)?),
+            StoragePreference::NONE,
+        )?;
+        self.sync()?;
+        Ok(cursor)
+    }
+
+    /// Returns the cursor of this database as a follower, i.e. the
+    /// generation of the leader it has applied last, if any.
+    pub fn replication_cursor(&self) -> Result<Option<ReplicationCursor>> {
+        match self.root_tree.get(&replication::cursor_key()[..])? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Syncs the database and pins the synced state of all datasets.
+    fn pin_replication_state(&mut self) -> Result<ReplicationState> {
+        for _ in 0..PIN_ATTEMPTS {
+            self.sync()?;
+            let generation = self.synced_generation();
+            let handler = self.root_tree.dmu().handler();
+            let mut state = ReplicationState {
+                generation,
+                datasets: BTreeMap::new(),
+            };
+            let mut pinned = true;
+            for (name, id) in self.dataset_names()? {
+                let ptr = fetch_ds_data(&self.root_tree, id)?.ptr;
+                // The dataset has been synced again or modified in the
+                // meantime.
+                if ptr.generation() > generation
+                    || !handler.pin_read_view(id, generation, ptr.clone())
+                {
+                    pinned = false;
+                    break;
+                }
+                state.datasets.insert(id, (name, ptr));
+            }
+            if pinned {
+                return Ok(state);
+            }
+            self.release_replication_state(state)?;
+        }
+        Err(Error::GenerationUnavailable(self.synced_generation()))
+    }
+
+    fn release_replication_state(&self, state: ReplicationState) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        for &id in state.datasets.keys() {
+            handler.unpin_read_view(id, state.generation);
+            self.release_read_view(id, state.generation)?;
+        }
+        Ok(())
+    }
+
+    fn dataset_names(&self) -> Result<Vec<(CowBytes, DatasetId)>> {
+        let low = &[DATASET_NAME_TO_ID] as &[_];
+        let high = &[DATASET_NAME_TO_ID + 1] as &[_];
+        self.root_tree
+            .range(low..high)?
+            .map(|result| {
+                let (key, value) = result?;
+                Ok((CowBytes::from(&key[1..]), DatasetId::unpack(&value)))
+            })
+            .collect()
+    }
+
+    // Writes the records turning the state `base`, or empty datasets, into
+    // `state`.
+    fn replication_batch(
+        &self,
+        base: Option<&ReplicationState>,
+        state: &ReplicationState,
+    ) -> Result<Vec<u8>> {
+        let mut batch = Vec::new();
+        let header = BatchHeader {
+            magic: BATCH_MAGIC,
+            version: BATCH_VERSION,
+            base: base.map(|base| base.generation),
+            generation: state.generation,
+        };
+        serialize_into(&mut batch, &header)?;
+
+        let object_stores = self.object_stores()?;
+        let meta_datasets: HashSet<_> = object_stores.iter().map(|&(_, _, meta)| meta).collect();
+        for (&id, (name, ptr)) in state.datasets.iter() {
+            let old_ptr = base
+                .and_then(|base| base.datasets.get(&id))
+                .map(|(_, old)| old);
+            // Unchanged datasets keep their root node.
+            if old_ptr.map_or(false, |old| {
+                old.offset() == ptr.offset() && old.generation() == ptr.generation()
+            }) {
+                continue;
+            }
+            let kind = if meta_datasets.contains(&id) {
+                DatasetKind::ObjectMeta
+            } else {
+                DatasetKind::Plain
+            };
+            let open = |ptr: &ObjectPointer| match kind {
+                DatasetKind::Plain => entries(self, id, ptr.clone(), DefaultMessageAction),
+                DatasetKind::ObjectMeta => entries(self, id, ptr.clone(), MetaMessageAction),
+            };
+            let old: Entries = match old_ptr {
+                Some(old) => open(old)?,
+                None => Box::new(std::iter::empty()),
+            };
+            let record = Record::Dataset {
+                name: name.clone(),
+                kind,
+                reset: base.is_none(),
+            };
+            serialize_into(&mut batch, &record)?;
+            for_each_change(old, open(ptr)?, |key, value| {
+                let record = match value {
+                    Some(value) => Record::Put { key, value },
+                    None => Record::Delete { key },
+                };
+                serialize_into(&mut batch, &record)?;
+                Ok(())
+            })?;
+        }
+
+        let names: HashMap<_, _> = state
+            .datasets
+            .iter()
+            .map(|(&id, (name, _))| (id, name.clone()))
+            .collect();
+        for (name, data, meta) in object_stores {
+            if let (Some(data), Some(meta)) = (names.get(&data), names.get(&meta)) {
+                let record = Record::ObjectStore {
+                    name,
+                    data: data.clone(),
+                    meta: meta.clone(),
+                };
+                serialize_into(&mut batch, &record)?;
+            }
+        }
+        serialize_into(&mut batch, &Record::End)?;
+        Ok(batch)
+    }
+
+    // Opens the dataset `name` of a follower for a batch, creating it if
+    // necessary.
+    fn replication_target(&self, name: &[u8], kind: DatasetKind) -> Result<Target> {
+        let id = match self.lookup_dataset_id(name) {
+            Ok(id) => id,
+            Err(Error::DoesNotExist) => {
+                let pref = StoragePreference::NONE;
+                match kind {
+                    DatasetKind::Plain => {
+                        self.create_custom_dataset::<DefaultMessageAction>(name, pref)?
+                    }
+                    DatasetKind::ObjectMeta => {
+                        self.create_custom_dataset::<MetaMessageAction>(name, pref)?
+                    }
+                }
+                self.lookup_dataset_id(name)?
+            }
+            Err(e) => return Err(e),
+        };
+        let opened = if self.open_datasets.read().contains_key(&id) {
+            None
+        } else {
+            Some(match kind {
+                DatasetKind::Plain => Opened::Plain(self.open_dataset(name)?),
+                DatasetKind::ObjectMeta => {
+                    Opened::ObjectMeta(self.open_custom_dataset(name, StoragePreference::NONE)?)
+                }
+            })
+        };
+        Ok(Target { id, kind, opened })
+    }
+}
@@ -0,0 +1,180 @@
+//! Space reservations of datasets, see [Database::reserve].
+//!
+//! Reserved blocks stay accounted as free in [Database::free_space_tier], but
+//! allocations of other datasets cannot use them.  Allocations of the
+//! reserving dataset consume its reservations first, see
+//! [Handler::available_blocks](super::Handler::available_blocks).
+use super::{errors::*, Database, Dataset, DatasetId, RootDmu};
+use crate::{data_management::DmlWithStorageHints, vdev::Block, StoragePreference};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Blocks of a storage class which are reserved for the future writes of a
+/// dataset, see [Database::reserve].
+///
+/// The remaining blocks are released when the reservation is dropped.
+pub struct Reservation {
+    dmu: Arc<RootDmu>,
+    id: u64,
+    class: u8,
+}
+
+// A reservation of `blocks` blocks of `class` for the dataset `dataset_id`.
+struct Claim {
+    dataset_id: DatasetId,
+    class: u8,
+    blocks: u64,
+}
+
+/// The open reservations of a database.
+#[derive(Default)]
+pub(crate) struct Reservations {
+    next_id: u64,
+    // The remaining claims, in the order they have been made.
+    claims: BTreeMap<u64, Claim>,
+}
+
+impl Reservations {
+    /// Returns the number of blocks of `class` reserved by all datasets.
+    pub(crate) fn reserved(&self, class: u8) -> u64 {
+        self.claims
+            .values()
+            .filter(|claim| claim.class == class)
+            .map(|claim| claim.blocks)
+            .sum()
+    }
+
+    /// Returns the number of blocks of `class` reserved by the given dataset.
+    pub(crate) fn reserved_for(&self, class: u8, dataset_id: DatasetId) -> u64 {
+        self.claims
+            .values()
+            .filter(|claim| claim.class == class && claim.dataset_id == dataset_id)
+            .map(|claim| claim.blocks)
+            .sum()
+    }
+
+    /// Records a reservation and returns its id.
+    pub(crate) fn insert(&mut self, class: u8, dataset_id: DatasetId, blocks: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.claims.insert(
+            id,
+            Claim {
+                dataset_id,
+                class,
+                blocks,
+            },
+        );
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.claims.remove(&id);
+    }
+
+    fn remaining(&self, id: u64) -> u64 {
+        self.claims.get(&id).map_or(0, |claim| claim.blocks)
+    }
+
+    /// Deducts `blocks` allocated for the given dataset from its
+    /// reservations of `class`, the oldest ones first.
+    pub(crate) fn consume(&mut self, class: u8, dataset_id: DatasetId, mut blocks: u64) {
+        for claim in self.claims.values_mut() {
+            if blocks == 0 {
+                break;
+            }
+            if claim.class == class && claim.dataset_id == dataset_id {
+                let consumed = claim.blocks.min(blocks);
+                claim.blocks -= consumed;
+                blocks -= consumed;
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Reserves `bytes` of the storage class `pref` for future writes of the
+    /// given dataset.
+    ///
+    /// Until the returned reservation is dropped, the reserved blocks cannot
+    /// be allocated for other datasets.  Nodes of the dataset written to the
+    /// storage class consume the reservation, so that the dataset does not
+    /// run out of space in the middle of an operation which has been sized
+    /// beforehand.  Note that the written nodes include the metadata of the
+    /// tree and that blocks are only freed on the next sync after an entry
+    /// has been overwritten.
+    ///
+    /// Fails with [Error::ReservationWouldExceedStorage] if the storage class
+    /// does not have enough free blocks which are neither reserved nor part
+    /// of the headroom of the database, see
+    /// [DatabaseConfiguration::headroom](super::DatabaseConfiguration::headroom).
+    pub fn reserve<M>(
+        &self,
+        ds: &Dataset<M>,
+        bytes: u64,
+        pref: StoragePreference,
+    ) -> Result<Reservation> {
+        let dmu = self.root_tree.dmu();
+        let class = pref
+            .or(dmu.default_storage_class())
+            .preferred_class()
+            .unwrap_or(0);
+        let blocks = Block::round_up_from_bytes(bytes);
+        let id = dmu
+            .handler()
+            .reserve(class, ds.id(), blocks)
+            .ok_or(Error::ReservationWouldExceedStorage(class, blocks))?;
+        Ok(Reservation {
+            dmu: Arc::clone(dmu),
+            id,
+            class,
+        })
+    }
+}
+
+impl Reservation {
+    /// Returns the storage class the blocks are reserved in.
+    pub fn storage_class(&self) -> u8 {
+        self.class
+    }
+
+    /// Returns the number of reserved blocks which have not been consumed by
+    /// writes of the dataset yet.
+    pub fn remaining(&self) -> Block<u64> {
+        Block(self.dmu.handler().reservations.lock().remaining(self.id))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.dmu.handler().reservations.lock().remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatasetId, Reservations};
+
+    #[test]
+    fn consumption_is_per_dataset_and_class() {
+        let mut reservations = Reservations::default();
+        let (first, second) = (
+            DatasetId::default().next(),
+            DatasetId::default().next().next(),
+        );
+        let old = reservations.insert(0, first, 10);
+        let new = reservations.insert(0, first, 10);
+        reservations.insert(1, first, 5);
+        reservations.insert(0, second, 7);
+        assert_eq!(reservations.reserved(0), 27);
+        assert_eq!(reservations.reserved_for(0, first), 20);
+
+        reservations.consume(0, first, 15);
+        assert_eq!(reservations.remaining(old), 0);
+        assert_eq!(reservations.remaining(new), 5);
+        assert_eq!(reservations.reserved_for(1, first), 5);
+        assert_eq!(reservations.reserved_for(0, second), 7);
+
+        reservations.remove(new);
+        assert_eq!(reservations.reserved(0), 7);
+    }
+}
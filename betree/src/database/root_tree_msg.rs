@@ -7,6 +7,11 @@
 // NOTE: Dataset counter and segment is doubly occupied, as only a single
 // counter may ever exist and all segment entries have keys of length 9 this is
 // fine.
+//
+// NOTE: Segment and disk space entries are not stored in the root tree itself
+// but in the allocation tree of the storage class they belong to.  The root
+// pointers of these trees are stored in the root tree under the
+// `ALLOCATION_TREE` prefix.
 pub(super) const DATASET_ID_COUNTER: u8 = 0;
 pub(super) const SEGMENT: u8 = 0;
 
@@ -19,6 +24,15 @@ pub(crate) const OBJECT_STORE_ID_COUNTER_PREFIX: u8 = 6;
 pub(crate) const OBJECT_STORE_NAME_TO_ID_PREFIX: u8 = 7;
 pub(crate) const OBJECT_STORE_DATA_PREFIX: u8 = 8;
 pub(super) const DISK_SPACE: u8 = 9;
+pub(super) const CHECKPOINT: u8 = 10;
+pub(super) const DATASET_USAGE: u8 = 11;
+pub(super) const ALLOCATION_TREE: u8 = 12;
+pub(super) const DATASET_PINS: u8 = 13;
+pub(super) const QUOTA_GROUP: u8 = 14;
+pub(super) const SEALED_DISK: u8 = 15;
+pub(super) const REPLICATION_CURSOR: u8 = 16;
+pub(super) const DELTA_BASE: u8 = 17;
+pub(super) const DATASET_DICTIONARY: u8 = 18;
 
 // DATASETS
 
@@ -77,6 +91,20 @@ pub(super) mod segment {
         BigEndian::write_u64(&mut key[S_ID_OFFSET..], segment_id.0);
         key
     }
+
+    /// This function should always receive a buffer of exactly FULL length.
+    pub fn read_key(buf: &[u8]) -> SegmentId {
+        debug_assert!(buf.len() == FULL);
+        SegmentId(BigEndian::read_u64(&buf[S_ID_OFFSET..]))
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [SEGMENT]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [SEGMENT + 1]
+    }
 }
 
 // SNAPSHOTS
@@ -102,6 +130,23 @@ pub(super) mod snapshot {
         key
     }
 
+    // Partial Key, lower end of all snapshot names of a dataset
+    pub fn min_key(ds_id: DatasetId) -> [u8; SS_ID_OFFSET] {
+        let mut key = [0; SS_ID_OFFSET];
+        key[0] = SNAPSHOT_DS_ID_AND_NAME_TO_ID;
+        key[DS_ID_OFFSET..SS_ID_OFFSET].copy_from_slice(&ds_id.pack());
+        key
+    }
+
+    // Partial Key, above-upper end of all snapshot names of a dataset
+    pub fn max_key(ds_id: DatasetId) -> [u8; SS_ID_OFFSET] {
+        min_key(ds_id.next())
+    }
+
+    pub fn name_from_key(key: &[u8]) -> &[u8] {
+        &key[SS_ID_OFFSET..]
+    }
+
     pub fn data_key(ds_id: DatasetId, ss_id: Generation) -> [u8; FULL] {
         let mut key = [0; FULL];
         key[0] = SNAPSHOT_DATA;
@@ -118,6 +163,20 @@ pub(super) mod snapshot {
         key[DS_ID_OFFSET..SS_ID_OFFSET].copy_from_slice(&ds_id.pack());
         key
     }
+
+    // Above-upper End of the data keys of all snapshots for the use in
+    // non-inclusive range queries.
+    pub fn data_key_max_all() -> [u8; 1] {
+        [SNAPSHOT_DATA + 1]
+    }
+
+    pub fn ds_id_from_data_key(key: &[u8]) -> DatasetId {
+        DatasetId::unpack(&key[DS_ID_OFFSET..SS_ID_OFFSET])
+    }
+
+    pub fn generation_from_data_key(key: &[u8]) -> Generation {
+        Generation::unpack(&key[SS_ID_OFFSET..])
+    }
 }
 
 // DEADLIST - snapshot only objects
@@ -163,6 +222,12 @@ pub(super) mod deadlist {
         key
     }
 
+    // Above-upper End of the deadlist keys of all datasets for the use in
+    // non-inclusive range queries.
+    pub fn max_key_all() -> [u8; 1] {
+        [DEADLIST + 1]
+    }
+
     pub fn key(ds_id: DatasetId, cur_gen: Generation, offset: DiskOffset) -> [u8; FULL] {
         let mut key = [0; FULL];
         key[0] = DEADLIST;
@@ -172,6 +237,14 @@ pub(super) mod deadlist {
         key
     }
 
+    pub fn ds_id_from_key(key: &[u8]) -> DatasetId {
+        DatasetId::unpack(&key[DS_ID_OFFSET..SS_ID_OFFSET])
+    }
+
+    pub fn generation_from_key(key: &[u8]) -> Generation {
+        Generation::unpack(&key[SS_ID_OFFSET..DO_OFFSET])
+    }
+
     pub fn offset_from_key(key: &[u8]) -> DiskOffset {
         DiskOffset::from_u64(BigEndian::read_u64(&key[DO_OFFSET..]))
     }
@@ -215,3 +288,304 @@ pub(super) mod space_accounting {
         [DISK_SPACE + 1]
     }
 }
+
+// CHECKPOINTS
+
+pub(super) mod checkpoint {
+    //! Checkpoints are stored by their name and hold the root pointer and tier
+    //! information of the superblock written when the checkpoint was taken.
+
+    use super::CHECKPOINT;
+
+    const NAME_OFFSET: usize = 1;
+
+    pub fn key(name: &[u8]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(NAME_OFFSET + name.len());
+        key.push(CHECKPOINT);
+        key.extend_from_slice(name);
+        key
+    }
+
+    pub fn name_from_key(key: &[u8]) -> &[u8] {
+        &key[NAME_OFFSET..]
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [CHECKPOINT]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [CHECKPOINT + 1]
+    }
+}
+
+// DATASET USAGE
+
+pub(super) mod dataset_usage {
+    //! Each dataset usage entry is characterized by the prefix followed by the
+    //! dataset id.
+
+    use crate::database::DatasetId;
+
+    use super::DATASET_USAGE;
+
+    const DS_ID_OFFSET: usize = 1;
+    const FULL: usize = 9;
+
+    pub fn key(ds_id: DatasetId) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = DATASET_USAGE;
+        key[DS_ID_OFFSET..].copy_from_slice(&ds_id.pack());
+        key
+    }
+
+    pub fn read_key(buf: &[u8]) -> DatasetId {
+        debug_assert!(buf.len() == FULL);
+        DatasetId::unpack(&buf[DS_ID_OFFSET..])
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [DATASET_USAGE]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [DATASET_USAGE + 1]
+    }
+}
+
+// ALLOCATION TREES
+
+pub(super) mod allocation_tree {
+    //! Each allocation tree entry is characterized by the prefix followed by
+    //! the storage class the tree belongs to.
+
+    use super::ALLOCATION_TREE;
+
+    const FULL: usize = 2;
+    const CLASS_OFFSET: usize = 1;
+
+    pub fn key(class: u8) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = ALLOCATION_TREE;
+        key[CLASS_OFFSET] = class;
+        key
+    }
+}
+
+// DATASET PINS
+
+pub(super) mod dataset_pins {
+    //! The key range pinning rules of a dataset are stored in a single entry
+    //! characterized by the prefix followed by the dataset id.
+
+    use crate::database::DatasetId;
+
+    use super::DATASET_PINS;
+
+    const DS_ID_OFFSET: usize = 1;
+    const FULL: usize = 9;
+
+    pub fn key(ds_id: DatasetId) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = DATASET_PINS;
+        key[DS_ID_OFFSET..].copy_from_slice(&ds_id.pack());
+        key
+    }
+}
+
+// QUOTA GROUPS
+
+pub(super) mod quota_group {
+    //! Quota groups are stored by their name and hold their limit and member
+    //! datasets.
+
+    use super::QUOTA_GROUP;
+
+    const NAME_OFFSET: usize = 1;
+
+    pub fn key(name: &[u8]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(NAME_OFFSET + name.len());
+        key.push(QUOTA_GROUP);
+        key.extend_from_slice(name);
+        key
+    }
+
+    pub fn name_from_key(key: &[u8]) -> &[u8] {
+        &key[NAME_OFFSET..]
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [QUOTA_GROUP]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [QUOTA_GROUP + 1]
+    }
+}
+
+// SEALED DISKS
+
+pub(super) mod sealed_disk {
+    //! Each sealed disk is marked by an entry characterized by the prefix
+    //! followed by the disk id.  The deallocations deferred while the disk is
+    //! sealed follow the marker, their keys are extended by the offset of the
+    //! deallocated blocks and hold their size and the dataset they belonged
+    //! to.
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use crate::{
+        database::DatasetId,
+        storage_pool::{DiskOffset, GlobalDiskId},
+        vdev::Block,
+    };
+
+    use super::SEALED_DISK;
+
+    const D_ID_OFFSET: usize = 1;
+    const DO_OFFSET: usize = 3;
+    const FULL: usize = 11;
+    const SIZE_OFFSET: usize = 4;
+    const VALUE_FULL: usize = 12;
+
+    pub fn key(disk_id: GlobalDiskId) -> [u8; DO_OFFSET] {
+        let mut key = [0; DO_OFFSET];
+        key[0] = SEALED_DISK;
+        BigEndian::write_u16(&mut key[D_ID_OFFSET..], disk_id.as_u16());
+        key
+    }
+
+    pub fn read_key(buf: &[u8]) -> GlobalDiskId {
+        GlobalDiskId(BigEndian::read_u16(&buf[D_ID_OFFSET..DO_OFFSET]))
+    }
+
+    pub fn is_deferred_key(buf: &[u8]) -> bool {
+        buf.len() == FULL
+    }
+
+    pub fn deferred_key(offset: DiskOffset) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[..DO_OFFSET].copy_from_slice(&self::key(offset.class_disk_id()));
+        BigEndian::write_u64(&mut key[DO_OFFSET..], offset.as_u64());
+        key
+    }
+
+    pub fn offset_from_key(key: &[u8]) -> DiskOffset {
+        DiskOffset::from_u64(BigEndian::read_u64(&key[DO_OFFSET..]))
+    }
+
+    pub fn deferred_value(size: Block<u32>, ds_id: DatasetId) -> [u8; VALUE_FULL] {
+        let mut value = [0; VALUE_FULL];
+        BigEndian::write_u32(&mut value[..SIZE_OFFSET], size.as_u32());
+        value[SIZE_OFFSET..].copy_from_slice(&ds_id.pack());
+        value
+    }
+
+    pub fn read_deferred_value(buf: &[u8]) -> (Block<u32>, DatasetId) {
+        (
+            Block(BigEndian::read_u32(&buf[..SIZE_OFFSET])),
+            DatasetId::unpack(&buf[SIZE_OFFSET..VALUE_FULL]),
+        )
+    }
+
+    // Above-upper end of the entries of the given disk.
+    pub fn max_key(disk_id: GlobalDiskId) -> [u8; DO_OFFSET] {
+        key(GlobalDiskId(disk_id.as_u16() + 1))
+    }
+
+    pub fn min_key_all() -> [u8; 1] {
+        [SEALED_DISK]
+    }
+
+    pub fn max_key_all() -> [u8; 1] {
+        [SEALED_DISK + 1]
+    }
+}
+
+// REPLICATION
+
+pub(super) mod replication {
+    //! The cursor of a replication follower is stored under the prefix
+    //! alone, see `Database::apply_replication`.
+
+    use super::REPLICATION_CURSOR;
+
+    pub fn cursor_key() -> [u8; 1] {
+        [REPLICATION_CURSOR]
+    }
+}
+
+// DELTA ENCODING
+
+pub(super) mod delta_base {
+    //! The base of each delta encoded leaf is stored under the prefix
+    //! followed by the offset of the delta, see
+    //! `DatabaseConfiguration::delta_encoding`.
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use crate::storage_pool::DiskOffset;
+
+    use super::DELTA_BASE;
+
+    const FULL: usize = 9;
+
+    pub fn key(offset: DiskOffset) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = DELTA_BASE;
+        BigEndian::write_u64(&mut key[1..], offset.as_u64());
+        key
+    }
+
+    pub fn read_key(buf: &[u8]) -> DiskOffset {
+        DiskOffset::from_u64(BigEndian::read_u64(&buf[1..FULL]))
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [DELTA_BASE]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [DELTA_BASE + 1]
+    }
+}
+
+pub(super) mod dataset_dictionary {
+    //! The compression dictionaries of a dataset are stored under the prefix
+    //! followed by the dataset id and the id of the dictionary, see
+    //! `Dataset::train_dictionary`.
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use crate::database::DatasetId;
+
+    use super::DATASET_DICTIONARY;
+
+    const DS_ID_OFFSET: usize = 1;
+    const DICT_ID_OFFSET: usize = 9;
+    const FULL: usize = 13;
+
+    pub fn key(ds_id: DatasetId, dictionary_id: u32) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = DATASET_DICTIONARY;
+        key[DS_ID_OFFSET..DICT_ID_OFFSET].copy_from_slice(&ds_id.pack());
+        BigEndian::write_u32(&mut key[DICT_ID_OFFSET..], dictionary_id);
+        key
+    }
+
+    pub fn read_key(buf: &[u8]) -> (DatasetId, u32) {
+        debug_assert!(buf.len() == FULL);
+        (
+            DatasetId::unpack(&buf[DS_ID_OFFSET..DICT_ID_OFFSET]),
+            BigEndian::read_u32(&buf[DICT_ID_OFFSET..FULL]),
+        )
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [DATASET_DICTIONARY]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [DATASET_DICTIONARY + 1]
+    }
+}
@@ -0,0 +1,149 @@
+//! Sealing of vdevs and storage classes which become read-only, see
+//! [Database::seal_disk].
+//!
+//! Sealed disks are excluded from allocations like evacuating ones, see
+//! [Database::evacuate_disk].  Blocks freed on them are not returned to the
+//! allocator but recorded in the root tree, so that write-once media are
+//! never overwritten.  The recorded blocks are deallocated when the disk is
+//! unsealed.
+use super::{errors::*, root_tree_msg::sealed_disk, Database};
+use crate::{
+    allocator::Action,
+    data_management::Dml,
+    storage_pool::{DiskOffset, GlobalDiskId, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    tree::{DefaultMessageAction, TreeLayer},
+    StoragePreference,
+};
+use std::collections::HashSet;
+
+/// Loads the sealed disks from the root tree.
+pub(super) fn fetch_sealed_disks<T>(root_tree: &T) -> Result<HashSet<GlobalDiskId>>
+where
+    T: TreeLayer<DefaultMessageAction>,
+{
+    let low = &sealed_disk::min_key_all() as &[_];
+    let high = &sealed_disk::max_key_all() as &[_];
+    let mut sealed = HashSet::new();
+    for entry in root_tree.range(low..high)? {
+        let (key, _) = entry?;
+        if !sealed_disk::is_deferred_key(&key) {
+            sealed.insert(sealed_disk::read_key(&key));
+        }
+    }
+    Ok(sealed)
+}
+
+impl Database {
+    /// Seals the top-level vdev `disk`, which makes it read-only.
+    ///
+    /// No blocks are allocated on a sealed vdev anymore, nodes stored there
+    /// are rewritten to other vdevs when they are modified.  Blocks which are
+    /// no longer used are not freed but only recorded until the vdev is
+    /// unsealed, as write-once media cannot be overwritten.  Migrations may
+    /// still move data off the vdev, e.g. with [Database::evacuate_disk].
+    ///
+    /// The seal is persistent and becomes durable with the next sync.
+    pub fn seal_disk(&self, disk: GlobalDiskId) -> Result<()> {
+        self.check_disk(disk)?;
+        let handler = self.root_tree.dmu().handler();
+        // The lock is released before the root tree is modified, which may
+        // allocate blocks.
+        if handler.sealed_disks.write().insert(disk) {
+            self.root_tree.insert(
+                &sealed_disk::key(disk)[..],
+                DefaultMessageAction::insert_msg(&[]),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Unseals the top-level vdev `disk` and frees the blocks whose
+    /// deallocation has been deferred while it was sealed.  The blocks become
+    /// available with the next sync.
+    pub fn unseal_disk(&self, disk: GlobalDiskId) -> Result<()> {
+        self.check_disk(disk)?;
+        let handler = self.root_tree.dmu().handler();
+        if !handler.sealed_disks.write().remove(&disk) {
+            return Ok(());
+        }
+        // Deferred deallocations are queued until the next sync.
+        self.flush_delayed_messages()?;
+
+        let low = &sealed_disk::key(disk) as &[_];
+        let high = &sealed_disk::max_key(disk) as &[_];
+        let mut deferred = Vec::new();
+        for entry in self.root_tree.range(low..high)? {
+            let (key, value) = entry?;
+            if sealed_disk::is_deferred_key(&key) {
+                deferred.push((key, value));
+            }
+        }
+        for (key, value) in deferred {
+            let (size, dataset_id) = sealed_disk::read_deferred_value(&value);
+            handler.update_allocation_bitmap(
+                sealed_disk::offset_from_key(&key),
+                size,
+                Action::Deallocate,
+                dataset_id,
+                self.root_tree.dmu(),
+            )?;
+            self.root_tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        self.root_tree.insert(
+            &sealed_disk::key(disk)[..],
+            DefaultMessageAction::delete_msg(),
+            StoragePreference::NONE,
+        )?;
+        Ok(())
+    }
+
+    /// Seals all top-level vdevs of the storage class `class`, see
+    /// [Database::seal_disk].  Explicit migrations to the storage class fail
+    /// with [Error::StorageClassSealed], allocations fall back to the other
+    /// storage classes of the allocation strategy.
+    ///
+    /// Vdevs added to the storage class later on are not sealed.
+    pub fn seal_storage_class(&self, class: u8) -> Result<()> {
+        for disk_id in 0..self.disk_count(class)? {
+            self.seal_disk(DiskOffset::construct_disk_id(class, disk_id))?;
+        }
+        Ok(())
+    }
+
+    /// Unseals all top-level vdevs of the storage class `class`, see
+    /// [Database::unseal_disk].
+    pub fn unseal_storage_class(&self, class: u8) -> Result<()> {
+        for disk_id in 0..self.disk_count(class)? {
+            self.unseal_disk(DiskOffset::construct_disk_id(class, disk_id))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the sealed top-level vdevs, ordered by storage class and id.
+    pub fn sealed_disks(&self) -> Vec<GlobalDiskId> {
+        let handler = self.root_tree.dmu().handler();
+        let mut disks: Vec<_> = handler.sealed_disks.read().iter().copied().collect();
+        disks.sort_by_key(GlobalDiskId::as_u16);
+        disks
+    }
+
+    fn disk_count(&self, class: u8) -> Result<u16> {
+        if class as usize >= NUM_STORAGE_CLASSES {
+            return Err(Error::NoSuchStorageClass(class));
+        }
+        Ok(self.root_tree.dmu().spl().disk_count(class))
+    }
+
+    fn check_disk(&self, disk: GlobalDiskId) -> Result<()> {
+        let (class, disk_id) = (disk.storage_class(), disk.disk_id());
+        if disk_id >= self.disk_count(class)? {
+            return Err(Error::NoSuchVdev(class, disk_id));
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,68 @@
 use super::{
-    dataset::Dataset, errors::*, fetch_ds_data, fetch_ss_data, root_tree_msg::dataset,
-    root_tree_msg::deadlist, root_tree_msg::snapshot, Database, DatasetData, DatasetId,
-    DatasetTree, DeadListData, Generation, ObjectPointer, RootDmu
+    backup, dataset::Dataset, errors::*, fetch_ds_data, fetch_ss_data, merkle, merkle::RootHash,
+    root_tree_msg::dataset, root_tree_msg::deadlist, root_tree_msg::snapshot, Database,
+    DatasetData, DatasetId, DatasetTree, DeadListData, Generation, ObjectPointer, RootDmu,
 };
 use crate::{
     allocator::Action,
+    clock,
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::DmlWithHandler,
     tree::{DefaultMessageAction, Tree, TreeLayer},
+    vdev::Block,
     StoragePreference,
 };
 use byteorder::{BigEndian, ByteOrder};
-use std::{borrow::Borrow, ops::RangeBounds, sync::Arc};
+use std::{
+    borrow::Borrow,
+    ops::RangeBounds,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 /// The snapshot type.
 pub struct Snapshot {
     tree: DatasetTree<RootDmu>,
+    id: Generation,
     #[allow(dead_code)]
     name: Box<[u8]>,
 }
 
+/// Information about a snapshot of a data set.
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    /// The name of the snapshot.
+    pub name: CowBytes,
+    /// The generation of the data set captured by the snapshot.
+    pub generation: Generation,
+    /// The time of creation, not known for snapshots created by earlier
+    /// versions.
+    pub created: Option<SystemTime>,
+    /// Blocks referenced by the snapshot which are no longer part of the data
+    /// set itself.
+    pub referenced: Block<u64>,
+    /// Blocks which would be freed by deleting the snapshot.
+    pub unique: Block<u64>,
+}
+
+// The name of a snapshot maps to its id followed by the time of creation in
+// microseconds since the epoch.
+fn pack_snapshot_id(ss_id: Generation, created: SystemTime) -> [u8; 16] {
+    let mut b = [0; 16];
+    b[..8].copy_from_slice(&ss_id.pack());
+    let us_since_epoch = created
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    BigEndian::write_u64(&mut b[8..], us_since_epoch);
+    b
+}
+
+pub(super) fn unpack_snapshot_created(b: &[u8]) -> Option<SystemTime> {
+    b.get(8..16)
+        .map(|us| UNIX_EPOCH + Duration::from_micros(BigEndian::read_u64(us)))
+}
+
 impl Database {
     /// Open a snapshot for the given data set identified by the given name.
     pub fn open_snapshot<M>(&self, ds: &mut Dataset<M>, name: &[u8]) -> Result<Snapshot> {
@@ -36,10 +79,43 @@ impl Database {
                 Arc::clone(self.root_tree.dmu()),
                 StoragePreference::NONE,
             ),
+            id,
             name: Box::from(name),
         })
     }
 
+    /// Closes the given snapshot of the given data set, which may then be
+    /// opened or deleted again.
+    pub fn close_snapshot<M>(&self, ds: &mut Dataset<M>, snapshot: Snapshot) {
+        ds.call_mut_open_snapshots(|set| set.remove(&snapshot.id));
+    }
+
+    /// Rolls the given data set back to the snapshot identified by the given
+    /// name, so that it holds exactly the key-value pairs captured by the
+    /// snapshot.
+    ///
+    /// Only the differing entries are rewritten.  The snapshot itself and
+    /// any later snapshots are kept, and the rollback becomes durable with
+    /// the next sync like any other modification.  The data set must not be
+    /// modified concurrently.
+    pub fn rollback_snapshot(&self, ds: &mut Dataset, name: &[u8]) -> Result<()> {
+        let ss_id = self.lookup_snapshot_id(ds.id(), name)?;
+        let ptr = fetch_ss_data(&self.root_tree, ds.id(), ss_id)?.ptr;
+        let snapshot = backup::entries(self, ds.id(), ptr, DefaultMessageAction)?;
+        let mut changes = Vec::new();
+        backup::for_each_change(ds.range::<_, &[u8]>(..)?, snapshot, |key, value| {
+            changes.push((key, value));
+            Ok(())
+        })?;
+        for (key, value) in changes {
+            match value {
+                Some(value) => ds.insert(key, &value)?,
+                None => ds.delete(key)?,
+            }
+        }
+        Ok(())
+    }
+
     fn lookup_snapshot_id(&self, ds_id: DatasetId, name: &[u8]) -> Result<Generation> {
         let key = snapshot::key(ds_id, name);
         let data = self.root_tree.get(key)?.ok_or(Error::DoesNotExist)?;
@@ -67,12 +143,24 @@ impl Database {
             DefaultMessageAction::insert_msg(&data),
             StoragePreference::NONE,
         )?;
+        self.root_tree.insert(
+            snapshot::key(ds.id(), name),
+            DefaultMessageAction::insert_msg(&pack_snapshot_id(ss_id, clock::system_now())),
+            StoragePreference::NONE,
+        )?;
         let key = &dataset::data_key(ds.id()) as &[_];
         self.root_tree.insert(
             key,
             DatasetData::<ObjectPointer>::update_previous_snapshot(Some(ss_id)),
             StoragePreference::NONE,
         )?;
+        // Blocks referenced by the snapshot have to be retained from now on.
+        self.root_tree
+            .dmu()
+            .handler()
+            .last_snapshot_generation
+            .write()
+            .insert(ds.id(), ss_id);
         self.sync()
     }
 
@@ -81,17 +169,73 @@ impl Database {
         &self,
         ds: &Dataset<M>,
     ) -> Result<impl Iterator<Item = Result<SlicedCowBytes>>> {
-        let mut low = [0; 9];
-        low[0] = 3;
-        BigEndian::write_u64(&mut low[1..], ds.id().0);
-        let high = &[4u8] as &[_];
-        Ok(self.root_tree.range(&low[..]..high)?.map(|result| {
+        let low = &snapshot::min_key(ds.id()) as &[_];
+        let high = &snapshot::max_key(ds.id()) as &[_];
+        Ok(self.root_tree.range(low..high)?.map(|result| {
             let (b, _) = result?;
             let len = b.len() as u32;
             Ok(b.slice(9, len - 9))
         }))
     }
 
+    /// Lists all snapshots of the given data set ordered by their generation.
+    ///
+    /// The space accounting is computed from the dead list of the data set
+    /// and therefore only covers blocks which have been written back.
+    pub fn list_snapshots<M>(&self, ds: &Dataset<M>) -> Result<Vec<SnapshotInfo>> {
+        let low = &snapshot::min_key(ds.id()) as &[_];
+        let high = &snapshot::max_key(ds.id()) as &[_];
+        let mut snapshots = self
+            .root_tree
+            .range(low..high)?
+            .map(|result| {
+                let (key, value) = result?;
+                Ok(SnapshotInfo {
+                    name: CowBytes::from(snapshot::name_from_key(&key)),
+                    generation: Generation::unpack(&value),
+                    created: unpack_snapshot_created(&value),
+                    referenced: Block(0),
+                    unique: Block(0),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        snapshots.sort_by_key(|info| info.generation);
+
+        let generations: Vec<_> = snapshots.iter().map(|info| info.generation).collect();
+        let checkpoint_generations = self
+            .root_tree
+            .dmu()
+            .handler()
+            .checkpoint_generations
+            .read()
+            .clone();
+        let min_key = &deadlist::min_key(ds.id(), Generation(0)) as &[_];
+        let max_key = &deadlist::max_key_ds(ds.id()) as &[_];
+        for result in self.root_tree.range(min_key..max_key)? {
+            let (key, value) = result?;
+            let death = deadlist::generation_from_key(&key);
+            let entry = DeadListData::unpack(&value)?;
+            for (idx, info) in snapshots.iter_mut().enumerate() {
+                if death <= info.generation || entry.birth > info.generation {
+                    continue;
+                }
+                info.referenced.0 += entry.size.as_u64();
+                // Same conditions as for freeing blocks in `delete_snapshot`.
+                let previous = idx.checked_sub(1).map(|prev| generations[prev]);
+                let next = generations.get(idx + 1);
+                if next.map_or(true, |next| death <= *next)
+                    && previous < Some(entry.birth)
+                    && !checkpoint_generations
+                        .iter()
+                        .any(|g| entry.birth <= *g && *g < death)
+                {
+                    info.unique.0 += entry.size.as_u64();
+                }
+            }
+        }
+        Ok(snapshots)
+    }
+
     /// Deletes the snapshot identified by the given name.
     ///
     /// Note that the deletion fails if a snapshot with the given name does not
@@ -133,18 +277,27 @@ impl Database {
             &max_key_dataset as &[_]
         };
         let min_key = &deadlist::min_key(ds.id(), ss_id.next()) as &[_];
+        // Blocks which are part of a checkpoint or read view have to be
+        // retained.
+        let handler = self.root_tree.dmu().handler();
+        let mut retained = handler.read_view_generations(ds.id());
+        retained.extend(handler.checkpoint_generations.read().iter().copied());
 
         for result in self.root_tree.range(min_key..max_key)? {
             let (key, value) = result?;
             let entry = DeadListData::unpack(&value)?;
-            if previous_ss_id < Some(entry.birth) {
+            let death = deadlist::generation_from_key(&key);
+            let retains = |g: &Generation| entry.birth <= *g && *g < death;
+            if previous_ss_id < Some(entry.birth) && !retained.iter().any(retains) {
                 let offset = deadlist::offset_from_key(&key);
                 self.root_tree.dmu().handler().update_allocation_bitmap(
                     offset,
                     entry.size,
                     Action::Deallocate,
+                    ds.id(),
                     self.root_tree.dmu(),
                 )?;
+                self.root_tree.dmu().release_delta(offset);
                 self.root_tree.insert(
                     key,
                     DefaultMessageAction::delete_msg(),
@@ -172,6 +325,9 @@ impl Database {
 
 impl Snapshot {
     /// Returns the value for the given key if existing.
+    ///
+    /// The value shares the buffer of the cached node it is read from, see
+    /// [SlicedCowBytes].
     pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
         Ok(self.tree.get(key)?)
     }
@@ -187,4 +343,10 @@ impl Snapshot {
     {
         Ok(Box::new(self.tree.range(range)?.map(|r| Ok(r?))))
     }
+
+    /// Returns the Merkle hash of the entries of this snapshot, see
+    /// [Dataset::root_hash].
+    pub fn root_hash(&self) -> Result<RootHash> {
+        merkle::root_hash(self.range::<_, &[u8]>(..)?)
+    }
 }
@@ -1,4 +1,9 @@
-use crate::vdev::Block;
+use super::Generation;
+use crate::{
+    allocator::{Action, SEGMENT_SIZE},
+    storage_pool::NUM_STORAGE_CLASSES,
+    vdev::Block,
+};
 use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicU64, Ordering};
 
@@ -42,6 +47,210 @@ impl StorageInfo {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Occupancy report of the whole storage pool.
+pub struct StorageReport {
+    /// Space information for each storage tier, ordered as in
+    /// `storage_preference.as_u8()`.
+    pub tiers: Vec<TierReport>,
+    /// Number of updates to the allocation bitmaps since the database has been
+    /// opened.
+    pub allocations: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Occupancy of a single storage tier.
+pub struct TierReport {
+    /// Name of the tier, if any.
+    pub name: Option<String>,
+    /// Space information of the whole tier.
+    pub info: StorageInfo,
+    /// Space information of each top-level vdev in the tier.
+    pub disks: Vec<StorageInfo>,
+    /// Ids of leaf vdevs which were missing on opening and are marked as
+    /// faulted.
+    pub faulted: Vec<String>,
+}
+
+impl StorageReport {
+    /// Returns whether any vdev of the storage pool is marked as faulted.
+    pub fn is_degraded(&self) -> bool {
+        self.tiers.iter().any(|tier| !tier.faulted.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Distribution of the free space of a segment or top-level vdev.
+pub struct FragmentationInfo {
+    /// Number of free extents by size, the entry `i` counts extents of `2^i`
+    /// up to `2^(i+1) - 1` blocks.
+    pub histogram: Vec<u64>,
+    /// Size of the largest free extent.
+    pub largest_free_extent: Block<u64>,
+    /// Free storage in blocks.
+    pub free: Block<u64>,
+}
+
+impl Default for FragmentationInfo {
+    fn default() -> Self {
+        Self {
+            histogram: Vec::new(),
+            largest_free_extent: Block(0),
+            free: Block(0),
+        }
+    }
+}
+
+impl FragmentationInfo {
+    /// Returns the number of free extents.
+    pub fn free_extents(&self) -> u64 {
+        self.histogram.iter().sum()
+    }
+
+    /// Returns the share of free storage outside of the largest free extent,
+    /// which is 0 for contiguous free storage and approaches 1 the more it
+    /// is scattered.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free.as_u64() == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_extent.as_u64() as f32 / self.free.as_u64() as f32
+    }
+
+    pub(crate) fn add_free_extent(&mut self, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let bucket = (u64::BITS - 1 - len.leading_zeros()) as usize;
+        if self.histogram.len() <= bucket {
+            self.histogram.resize(bucket + 1, 0);
+        }
+        self.histogram[bucket] += 1;
+        self.largest_free_extent = self.largest_free_extent.max(Block(len));
+        self.free += len;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Free space distribution of a single segment.
+pub struct SegmentFragmentation {
+    /// Offset of the segment on its top-level vdev.
+    pub offset: Block<u64>,
+    /// Free space distribution within the segment.
+    pub info: FragmentationInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Free space distribution of a single top-level vdev.
+pub struct DiskFragmentation {
+    /// Free space distribution of the whole vdev, free extents may span
+    /// several segments.
+    pub info: FragmentationInfo,
+    /// Free space distribution of each segment which has been allocated
+    /// from, segments which have never been used are omitted.
+    pub segments: Vec<SegmentFragmentation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Free space distribution of the whole storage pool.
+pub struct FragmentationReport {
+    /// Free space distribution of each top-level vdev, ordered as in
+    /// [StorageReport].
+    pub tiers: Vec<Vec<DiskFragmentation>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Allocation density and age of a single segment.
+pub struct SegmentHeat {
+    /// Offset of the segment on its top-level vdev.
+    pub offset: Block<u64>,
+    /// Blocks allocated in the segment.
+    pub allocated: Block<u32>,
+    /// Allocated blocks holding nodes of the data sets and internal trees.
+    /// The remaining allocated blocks are retained for snapshots or
+    /// checkpoints, or hold nodes which have been modified since the last
+    /// sync.
+    pub referenced: Block<u32>,
+    /// Generation of the oldest referenced node in the segment.
+    pub oldest: Option<Generation>,
+    /// Generation of the newest referenced node in the segment.
+    pub newest: Option<Generation>,
+}
+
+impl SegmentHeat {
+    /// Returns the share of allocated blocks of the segment.
+    pub fn density(&self) -> f32 {
+        self.allocated.as_u32() as f32 / SEGMENT_SIZE as f32
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Allocation density and age of the segments of a single top-level vdev.
+pub struct DiskHeatmap {
+    /// Each segment which has been allocated from in ascending order,
+    /// segments which have never been used are omitted.
+    pub segments: Vec<SegmentHeat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Allocation density and age of all segments of the storage pool, see
+/// [Database::allocation_heatmap](super::Database::allocation_heatmap).
+pub struct AllocationHeatmap {
+    /// The generation which will be written by the next sync, the age of a
+    /// node is the difference of its generation to this one.
+    pub generation: Generation,
+    /// The segments of each top-level vdev, ordered as in [StorageReport].
+    pub tiers: Vec<Vec<DiskHeatmap>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Space usage of a single dataset.
+pub struct SpaceUsage {
+    /// Uncompressed size of the nodes currently referenced by the dataset in
+    /// bytes, excluding padding and redundancy of the storage pool.
+    pub logical_bytes: u64,
+    /// Allocated blocks, including redundancy and blocks which are only
+    /// retained for snapshots or checkpoints.
+    pub physical_blocks: Block<u64>,
+    /// Allocated blocks per storage tier.
+    pub tiers: [Block<u64>; NUM_STORAGE_CLASSES],
+}
+
+impl Default for SpaceUsage {
+    fn default() -> Self {
+        Self {
+            logical_bytes: 0,
+            physical_blocks: Block(0),
+            tiers: [Block(0); NUM_STORAGE_CLASSES],
+        }
+    }
+}
+
+impl SpaceUsage {
+    // Blocks written before the accounting has been introduced are not
+    // tracked, therefore counters saturate at zero.
+    pub(crate) fn update_physical(&mut self, class: u8, size: Block<u64>, action: Action) {
+        let tier = &mut self.tiers[class as usize];
+        match action {
+            Action::Allocate => {
+                tier.0 += size.0;
+                self.physical_blocks.0 += size.0;
+            }
+            Action::Deallocate => {
+                tier.0 = tier.0.saturating_sub(size.0);
+                self.physical_blocks.0 = self.physical_blocks.0.saturating_sub(size.0);
+            }
+        }
+    }
+
+    pub(crate) fn update_logical(&mut self, bytes: u64, action: Action) {
+        match action {
+            Action::Allocate => self.logical_bytes += bytes,
+            Action::Deallocate => self.logical_bytes = self.logical_bytes.saturating_sub(bytes),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 /// Atomic version of [StorageInfo].
 pub(crate) struct AtomicStorageInfo {
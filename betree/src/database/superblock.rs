@@ -1,28 +1,171 @@
-use super::{errors::*, Checksum as DbChecksum, StorageInfo};
+use super::{errors::*, Checksum as DbChecksum, Generation, StorageInfo};
 use crate::{
     buffer::{Buf, BufWrite},
-    checksum::{Builder, Checksum, State},
+    checksum::{Builder, Checksum, GxHash, State},
+    data_management::LegacyObjectPointer,
     size::StaticSize,
     storage_pool::{StoragePoolLayer, NUM_STORAGE_CLASSES},
     vdev::{Block, BLOCK_SIZE},
 };
 use bincode::{deserialize, serialize_into};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::io::{self, Seek};
+use std::{
+    cmp::Reverse,
+    io::{self, Seek},
+};
+
+// The magic of all superblocks which carry a format version, format versions
+// are told apart by that field instead.
+static MAGIC: &[u8] = b"HEAFSv4\0\n";
+// Superblocks of format version 1 have no version field.
+static LEGACY_MAGIC: &[u8] = b"HEAFSv3\0\n";
+// The number of storage classes of format version 1.
+const LEGACY_STORAGE_CLASSES: usize = 4;
 
-static MAGIC: &[u8] = b"HEAFSv3\0\n";
+/// The version of the on-disk format written by this crate.  Superblocks and
+/// nodes carry the version they have been written in, storage pools of a
+/// newer version are refused.
+///
+/// Pools of older versions are opened as is, their nodes are rewritten in
+/// the current format once modified or by [Database::upgrade_format](super::Database::upgrade_format).
+/// The entries of the root tree of pools of format version 1 are converted
+/// when they are opened.
+///
+/// - 1: Superblocks without a version, nodes without a header, two-bit
+///   storage classes, object pointers without an encryption tag and
+///   allocation bitmaps in the root tree.
+/// - 2: Versioned superblocks and node headers, three-bit storage classes,
+///   object pointers with an encryption tag, the uncompressed size of
+///   their object and whether it has been stored without padding,
+///   allocation bitmaps in one allocation tree per storage class and
+///   superblock copies at the end of each vdev.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The number of blocks at the start and at the end of each top-level vdev
+/// which are reserved for superblocks.  Syncs write to these slots in turn,
+/// so that the superblock of the previous generation survives a torn write.
+pub const SUPERBLOCK_BLOCKS: Block<u32> = Block(2);
 
 /// A superblock contains the location of the root tree,
 /// and is read during database initialisation.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Superblock<P> {
     magic: [u8; 9],
+    pub(crate) version: u32,
     pub(crate) root_ptr: P,
     pub(crate) tiers: [StorageInfo; NUM_STORAGE_CLASSES],
+    // The number of top-level vdevs of each storage class.
+    pub(crate) disks: [u16; NUM_STORAGE_CLASSES],
+    // The names of the storage classes.
+    pub(crate) names: [Option<String>; NUM_STORAGE_CLASSES],
+    // The sizes of the top-level vdevs of each storage class, which locate
+    // the superblock blocks reserved at their end.
+    pub(crate) sizes: [Vec<Block<u64>>; NUM_STORAGE_CLASSES],
+    // Nodes born up to this generation may still be in an older format.
+    pub(crate) legacy_generation: Option<Generation>,
+}
+
+// The layout of superblocks of format version 1, whose root pointer `L` is
+// in the legacy layout as well.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub(super) struct LegacySuperblock<L> {
+    _magic: [u8; 9],
+    root_ptr: L,
+    tiers: [StorageInfo; LEGACY_STORAGE_CLASSES],
+}
+
+impl<L, P: From<L>> From<LegacySuperblock<L>> for Superblock<P> {
+    fn from(legacy: LegacySuperblock<L>) -> Self {
+        let mut magic = [0; 9];
+        magic.copy_from_slice(LEGACY_MAGIC);
+        let mut tiers = [StorageInfo {
+            free: Block(0),
+            total: Block(0),
+        }; NUM_STORAGE_CLASSES];
+        tiers[..LEGACY_STORAGE_CLASSES].copy_from_slice(&legacy.tiers);
+        Superblock {
+            magic,
+            version: 1,
+            root_ptr: legacy.root_ptr.into(),
+            tiers,
+            // The vdevs are not recorded, the pool is taken as configured
+            // when opening the database.
+            disks: [0; NUM_STORAGE_CLASSES],
+            names: Default::default(),
+            sizes: Default::default(),
+            // All nodes are in the legacy format, which is taken care of
+            // when opening the database.
+            legacy_generation: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<L> LegacySuperblock<L> {
+    pub(super) fn new(root_ptr: L, tiers: [StorageInfo; LEGACY_STORAGE_CLASSES]) -> Self {
+        let mut magic = [0; 9];
+        magic.copy_from_slice(LEGACY_MAGIC);
+        LegacySuperblock {
+            _magic: magic,
+            root_ptr,
+            tiers,
+        }
+    }
+
+    pub(super) fn pack(&self) -> Result<Buf>
+    where
+        L: Serialize,
+    {
+        seal(self)
+    }
 }
 
-fn checksum(b: &[u8]) -> DbChecksum {
-    let mut state = DbChecksum::builder().build();
+impl<P> Superblock<P> {
+    /// Returns the format version this superblock has been written in, see
+    /// [FORMAT_VERSION].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the generation up to which nodes may be in an older format
+    /// than the one of this superblock, if any.
+    pub fn legacy_generation(&self) -> Option<Generation> {
+        self.legacy_generation
+    }
+
+    /// Returns the pointer to the root node of the root tree.
+    pub fn root_pointer(&self) -> &P {
+        &self.root_ptr
+    }
+
+    /// Returns the space information of all storage classes as of the sync
+    /// which wrote this superblock.
+    pub fn tiers(&self) -> &[StorageInfo; NUM_STORAGE_CLASSES] {
+        &self.tiers
+    }
+
+    /// Returns the number of top-level vdevs of each storage class.
+    pub fn disks(&self) -> &[u16; NUM_STORAGE_CLASSES] {
+        &self.disks
+    }
+
+    /// Returns the name of the storage class `class`, if one has been set.
+    pub fn tier_name(&self, class: u8) -> Option<&str> {
+        self.names.get(class as usize)?.as_deref()
+    }
+}
+
+// Superblocks are checksummed with `GxHash` regardless of the configured
+// checksum, since they are read before the configuration of the database is
+// known, and format version 1 has done so as well.
+type SuperblockChecksum = GxHash;
+
+// The root pointer of superblocks of format version 1.
+type LegacyPointer = LegacyObjectPointer<<DbChecksum as Checksum>::Legacy>;
+
+fn checksum(b: &[u8]) -> SuperblockChecksum {
+    let mut state = SuperblockChecksum::builder().build();
     state.ingest(b);
     state.finish()
 }
@@ -30,80 +173,235 @@ fn checksum(b: &[u8]) -> DbChecksum {
 impl<P: DeserializeOwned> Superblock<P> {
     /// Interpret a byte slice as a database superblock.
     /// Errors if the supposed superblock doesn't begin with
-    /// a specific version byte sequence (currently `b"HEAFSv3\0\n", but
-    /// this sequence is explicitly not part of the stability guarantees),
+    /// a specific byte sequence followed by the format version, if the
+    /// format version is newer than [FORMAT_VERSION],
     /// or the contained checksum doesn't match the actual checksum of the superblock.
-    pub fn unpack(b: &[u8]) -> Result<Superblock<P>> {
-        let checksum_size = DbChecksum::static_size();
+    /// Superblocks of format version 1 are converted, their root pointer is
+    /// read as `L`.
+    pub fn unpack<L>(b: &[u8]) -> Result<Superblock<P>>
+    where
+        L: DeserializeOwned,
+        P: From<L>,
+    {
+        let checksum_size = SuperblockChecksum::static_size();
         let correct_checksum = checksum(&b[..b.len() - checksum_size]);
         let actual_checksum = deserialize(&b[b.len() - checksum_size..])?;
         if correct_checksum != actual_checksum {
             return Err(Error::InvalidSuperblock);
         }
-        let this: Self = deserialize(b)?;
-        if this.magic != MAGIC {
+        let magic = &b[..MAGIC.len()];
+        if magic == LEGACY_MAGIC {
+            return Ok(deserialize::<LegacySuperblock<L>>(b)?.into());
+        }
+        if magic != MAGIC {
             return Err(Error::InvalidSuperblock);
         }
-        Ok(this)
+        // Later versions may change the layout following the version.
+        let version: u32 = deserialize(&b[MAGIC.len()..])?;
+        if version > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(version));
+        }
+        Ok(deserialize(b)?)
     }
 }
 
 impl Superblock<super::ObjectPointer> {
-    /// Try to find a superblock among the first two blocks
-    /// of each top-level vdev, returning the newest one if multiple are found.
-    pub fn fetch_superblocks<S: StoragePoolLayer>(
-        pool: &S,
-    ) -> Result<Option<Superblock<super::ObjectPointer>>> {
-        let v1 = pool.read_raw(Block(1), Block(0))?;
-        let v2 = pool.read_raw(Block(1), Block(1))?;
-        Ok(v1
-            .into_iter()
-            .chain(v2)
-            .filter_map(|sb_data| Self::unpack(&sb_data).ok())
-            .max_by_key(|sb| sb.root_ptr.generation()))
+    /// Try to find a superblock among the reserved blocks at the start and
+    /// at the end of each top-level vdev.
+    ///
+    /// Copies are tried from the newest generation on and the first one
+    /// whose root node can be read is returned.  A superblock which is torn,
+    /// damaged on all vdevs or points to a damaged root node thereby falls
+    /// back to the previous generation, the nodes of which are only freed
+    /// once the following generation has been written.  Fails if superblocks
+    /// are found but none of them is usable, or if any of them has been
+    /// written in a newer format version.
+    pub fn fetch_superblocks<S>(pool: &S) -> Result<Option<Superblock<super::ObjectPointer>>>
+    where
+        S: StoragePoolLayer<Checksum = DbChecksum>,
+    {
+        let mut copies = Vec::new();
+        let mut error = None;
+        let mut read_any = false;
+        for slot in 0..SUPERBLOCK_BLOCKS.as_u64() {
+            let head = pool.read_raw(Block(1), Block(slot));
+            let tail = pool.read_raw_tail(Block(1), tail_slot(slot));
+            for result in [head, tail] {
+                match result {
+                    Ok(data) => {
+                        read_any = true;
+                        for sb_data in data.iter() {
+                            match Self::unpack::<LegacyPointer>(sb_data) {
+                                Ok(sb) => copies.push(sb),
+                                // Torn superblocks fail their checksum, so
+                                // the pool has been written by a newer
+                                // version.
+                                Err(e @ Error::UnsupportedFormat(_)) => return Err(e),
+                                Err(_) => {}
+                            }
+                        }
+                    }
+                    Err(e) => error = Some(e),
+                }
+            }
+        }
+        if let (false, Some(e)) = (read_any, error) {
+            return Err(e.into());
+        }
+        if copies.is_empty() {
+            return Ok(None);
+        }
+
+        copies.sort_by_key(|sb| Reverse(sb.root_ptr.generation()));
+        copies.dedup_by_key(|sb| sb.root_ptr.generation());
+        for sb in copies {
+            if sb.root_node_readable(pool) {
+                return Ok(Some(sb));
+            }
+            warn!(
+                "Root node of generation {:?} is damaged, trying previous superblocks",
+                sb.root_ptr.generation()
+            );
+        }
+        Err(Error::InvalidSuperblock)
     }
 
-    /// Write a superblock to each top-level vdev.
+    // Returns whether the root node this superblock points to matches its
+    // checksum.  Nodes on vdevs missing from the configuration cannot be
+    // checked, opening fails on them later on anyway.
+    fn root_node_readable<S>(&self, pool: &S) -> bool
+    where
+        S: StoragePoolLayer<Checksum = DbChecksum>,
+    {
+        let ptr = &self.root_ptr;
+        let offset = ptr.offset();
+        if offset.disk_id() >= pool.disk_count(offset.storage_class()) {
+            return true;
+        }
+        ptr.read_from(pool).is_ok()
+    }
+
+    /// Write a superblock to each top-level vdev, at its start and at its
+    /// end.  The slot written to rotates with the generation of `ptr`.
+    /// Nodes born up to `legacy_generation` may still be in an older format.
     pub fn write_superblock<S: StoragePoolLayer>(
         pool: &S,
         ptr: &super::ObjectPointer,
         tiers: &[StorageInfo; NUM_STORAGE_CLASSES],
+        legacy_generation: Option<Generation>,
     ) -> Result<()> {
-        let sb_data = Self::pack(ptr, tiers)?;
-        let sb_offset = if ptr.generation().0 & 1 == 0 {
-            Block(0)
-        } else {
-            Block(1)
-        };
-        pool.write_raw(sb_data, sb_offset)?;
+        let mut disks = [0; NUM_STORAGE_CLASSES];
+        for (class, disks) in disks.iter_mut().enumerate() {
+            *disks = pool.disk_count(class as u8);
+        }
+        let names = std::array::from_fn(|class| pool.tier_name(class as u8));
+        let sizes = std::array::from_fn(|class| {
+            (0..disks[class])
+                .map(|disk_id| pool.size_in_blocks(class as u8, disk_id))
+                .collect()
+        });
+        let sb_data = Self::pack(ptr, tiers, &disks, names, sizes, legacy_generation)?;
+        let slot = ptr.generation().0 % SUPERBLOCK_BLOCKS.as_u64();
+        pool.write_raw(sb_data.clone(), Block(slot))?;
+        pool.write_raw_tail(sb_data, tail_slot(slot))?;
         Ok(())
     }
 
     /// Overwrite all superblock locations with zeroes.
     pub fn clear_superblock<S: StoragePoolLayer>(pool: &S) -> Result<()> {
         let empty_data = Buf::zeroed(Block(1));
-        pool.write_raw(empty_data.clone(), Block(0))?;
-        pool.write_raw(empty_data, Block(1))?;
+        for slot in 0..SUPERBLOCK_BLOCKS.as_u64() {
+            pool.write_raw(empty_data.clone(), Block(slot))?;
+            pool.write_raw_tail(empty_data.clone(), tail_slot(slot))?;
+        }
         Ok(())
     }
 }
 
+// The distance of the superblock slot `slot` at the end of a vdev from its
+// end.
+fn tail_slot(slot: u64) -> Block<u64> {
+    Block(SUPERBLOCK_BLOCKS.as_u64() - slot)
+}
+
 impl<P: Serialize> Superblock<P> {
-    fn pack(p: &P, tiers: &[StorageInfo; NUM_STORAGE_CLASSES]) -> Result<Buf> {
-        let mut data = BufWrite::with_capacity(Block(1));
-        {
-            let mut this = Superblock {
-                magic: [0; 9],
-                root_ptr: p,
-                tiers: *tiers,
-            };
-            this.magic.copy_from_slice(MAGIC);
-            serialize_into(&mut data, &this)?;
+    fn pack(
+        p: &P,
+        tiers: &[StorageInfo; NUM_STORAGE_CLASSES],
+        disks: &[u16; NUM_STORAGE_CLASSES],
+        names: [Option<String>; NUM_STORAGE_CLASSES],
+        sizes: [Vec<Block<u64>>; NUM_STORAGE_CLASSES],
+        legacy_generation: Option<Generation>,
+    ) -> Result<Buf> {
+        let mut this = Superblock {
+            magic: [0; 9],
+            version: FORMAT_VERSION,
+            root_ptr: p,
+            tiers: *tiers,
+            disks: *disks,
+            names,
+            sizes,
+            legacy_generation,
+        };
+        this.magic.copy_from_slice(MAGIC);
+        seal(&this)
+    }
+}
+
+// Serializes `this` into a block which ends with its checksum.
+fn seal<T: Serialize>(this: &T) -> Result<Buf> {
+    let mut data = BufWrite::with_capacity(Block(1));
+    serialize_into(&mut data, this)?;
+    let checksum_size = SuperblockChecksum::static_size();
+    data.seek(io::SeekFrom::End(-i64::from(checksum_size as u32)))?;
+    let checksum = checksum(&data.as_ref()[..BLOCK_SIZE - checksum_size]);
+    serialize_into(&mut data, &checksum)?;
+    Ok(data.into_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers() -> [StorageInfo; NUM_STORAGE_CLASSES] {
+        [StorageInfo {
+            free: Block(1),
+            total: Block(2),
+        }; NUM_STORAGE_CLASSES]
+    }
+
+    #[test]
+    fn legacy_superblocks_are_converted() {
+        let mut legacy_tiers = [tiers()[0]; LEGACY_STORAGE_CLASSES];
+        legacy_tiers[1].free = Block(0);
+        let legacy = LegacySuperblock::new(42u32, legacy_tiers);
+        let sb = Superblock::<u64>::unpack::<u32>(&legacy.pack().unwrap()).unwrap();
+        assert_eq!(sb.version(), 1);
+        assert_eq!(*sb.root_pointer(), 42);
+        assert_eq!(sb.disks(), &[0; NUM_STORAGE_CLASSES]);
+        assert_eq!(sb.tiers()[..LEGACY_STORAGE_CLASSES], legacy_tiers);
+        assert_eq!(sb.tiers()[LEGACY_STORAGE_CLASSES].total, Block(0));
+    }
+
+    #[test]
+    fn newer_formats_are_refused() {
+        let data = Superblock::pack(
+            &42u64,
+            &tiers(),
+            &[1; NUM_STORAGE_CLASSES],
+            Default::default(),
+            Default::default(),
+            Some(Generation(3)),
+        )
+        .unwrap();
+        let mut sb = Superblock::<u64>::unpack::<u64>(&data).unwrap();
+        assert_eq!(sb.version(), FORMAT_VERSION);
+        assert_eq!(sb.legacy_generation(), Some(Generation(3)));
+
+        sb.version = FORMAT_VERSION + 1;
+        match Superblock::<u64>::unpack::<u64>(&seal(&sb).unwrap()) {
+            Err(Error::UnsupportedFormat(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+            other => panic!("Unexpected result {other:?}"),
         }
-        let checksum_size = DbChecksum::static_size();
-        data.seek(io::SeekFrom::End(-i64::from(checksum_size as u32)))?;
-        let checksum = checksum(&data.as_ref()[..BLOCK_SIZE - checksum_size]);
-        serialize_into(&mut data, &checksum)?;
-        Ok(data.into_buf())
     }
 }
@@ -1,16 +1,50 @@
 use super::Database;
-use parking_lot::RwLock;
-use std::{sync::Arc, thread, time::Duration};
+use crate::storage_pool::{with_io_priority, IoPriority};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::{sync::Arc, time::Duration};
 
-pub fn sync_timer(timeout_ms: u64, db: Arc<RwLock<Database>>) {
-    let timeout = Duration::from_millis(timeout_ms);
+/// The periodic sync of a database, whose interval can be changed while it is
+/// running, see [Database::update_config].
+pub(super) struct SyncTimer {
+    interval_ms: Mutex<Option<u64>>,
+    changed: Condvar,
+}
+
+impl SyncTimer {
+    pub(super) fn new(interval_ms: Option<u64>) -> Self {
+        SyncTimer {
+            interval_ms: Mutex::new(interval_ms),
+            changed: Condvar::new(),
+        }
+    }
 
-    loop {
-        thread::sleep(timeout);
+    /// Sets the interval, `None` pauses the syncs.  The next sync happens
+    /// one full interval after the change.
+    pub(super) fn set_interval(&self, interval_ms: Option<u64>) {
+        *self.interval_ms.lock() = interval_ms;
+        self.changed.notify_all();
+    }
 
-        log::debug!("syncing db");
-        if let Err(err) = db.write().sync() {
-            log::error!("couldn't sync db: {}", err);
+    pub(super) fn run(&self, db: Arc<RwLock<Database>>) {
+        let mut interval_ms = self.interval_ms.lock();
+        loop {
+            match *interval_ms {
+                None => self.changed.wait(&mut interval_ms),
+                Some(ms) => {
+                    let timeout = Duration::from_millis(ms);
+                    if self.changed.wait_for(&mut interval_ms, timeout).timed_out() {
+                        MutexGuard::unlocked(&mut interval_ms, || sync(&db));
+                    }
+                }
+            }
         }
     }
 }
+
+/// Performs one periodic sync of `db`.
+pub(super) fn sync(db: &RwLock<Database>) {
+    log::debug!("syncing db");
+    if let Err(err) = with_io_priority(IoPriority::Commit, || db.write().sync()) {
+        log::error!("couldn't sync db: {}", err);
+    }
+}
@@ -0,0 +1,377 @@
+//! Optimistic transactions on a dataset, see [Dataset::begin_transaction].
+//!
+//! A transaction buffers its writes and records the keys it has read.  On
+//! commit, the reads are validated against the keys written to the dataset
+//! since the transaction began.  If none of them has been modified, the
+//! buffered writes are applied while the dataset is locked, otherwise the
+//! commit fails with [Error::TransactionConflict] and the transaction can be
+//! retried.
+//!
+//! Modifications are tracked by [KeyVersions], which numbers the writes to a
+//! dataset while transactions are open.  Without open transactions no
+//! versions are kept.
+//!
+//! A [WriteBatch] applies writes to several datasets together, without
+//! validating reads.
+use super::{errors::*, Dataset};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    tree::{self, DefaultMessageAction, MessageAction},
+};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+};
+
+/// The versions of the keys written to a dataset while transactions are
+/// open.
+#[derive(Default)]
+pub(super) struct KeyVersions {
+    // The version of the most recent write.
+    version: u64,
+    // The versions open transactions began at, with their number.
+    open: BTreeMap<u64, usize>,
+    // The version of the last write of each key which has been written after
+    // an open transaction began.
+    keys: BTreeMap<CowBytes, u64>,
+}
+
+impl KeyVersions {
+    /// Registers a transaction and returns the version it begins at.
+    fn begin(&mut self) -> u64 {
+        *self.open.entry(self.version).or_default() += 1;
+        self.version
+    }
+
+    /// Deregisters a transaction which began at `start` and forgets the
+    /// writes no open transaction can conflict with anymore.
+    fn end(&mut self, start: u64) {
+        if let Some(count) = self.open.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 {
+                self.open.remove(&start);
+            }
+        }
+        match self.open.keys().next() {
+            Some(&oldest) => self.keys.retain(|_, version| *version > oldest),
+            None => self.keys.clear(),
+        }
+    }
+
+    /// Records a write of `key`, which has to happen after the write has
+    /// become visible to readers.
+    pub(super) fn record_write(&mut self, key: &CowBytes) {
+        if self.open.is_empty() {
+            return;
+        }
+        self.version += 1;
+        self.keys.insert(key.clone(), self.version);
+    }
+
+    /// Returns whether `key` has been written after version `start`.
+    fn modified_since(&self, key: &[u8], start: u64) -> bool {
+        self.keys.get(key).map_or(false, |version| *version > start)
+    }
+}
+
+/// An optimistic transaction on a dataset, see [Dataset::begin_transaction].
+///
+/// Dropping a transaction without committing it discards its writes.
+pub struct Transaction<Message = DefaultMessageAction> {
+    dataset: Dataset<Message>,
+    // The version of the dataset the transaction began at.
+    start: u64,
+    // The keys read from the dataset.
+    reads: BTreeSet<CowBytes>,
+    // The buffered messages in the order of their insertion.
+    writes: Vec<(CowBytes, SlicedCowBytes)>,
+}
+
+impl<Message> Dataset<Message> {
+    /// Begins an optimistic transaction on this dataset.
+    ///
+    /// Reads of the transaction see the dataset including its own writes,
+    /// which are buffered until [Transaction::commit].  The commit fails if
+    /// any key read by the transaction has been modified in the meantime,
+    /// so that the transaction never acts on outdated values.  Keys which
+    /// have only been written, or ranges which have only been scanned
+    /// outside of the transaction, are not checked.
+    pub fn begin_transaction(&self) -> Transaction<Message> {
+        Transaction {
+            dataset: self.clone(),
+            start: self.call_versions(KeyVersions::begin),
+            reads: BTreeSet::new(),
+            writes: Vec::new(),
+        }
+    }
+}
+
+impl<Message> Transaction<Message> {
+    /// Returns the dataset of this transaction.
+    pub fn dataset(&self) -> &Dataset<Message> {
+        &self.dataset
+    }
+
+    /// Returns the number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns whether no write has been buffered.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl<Message: MessageAction + 'static> Transaction<Message> {
+    /// Returns the value for the given key if existing, including the
+    /// buffered writes of this transaction.
+    ///
+    /// The key is recorded, the commit fails if it is modified by others
+    /// before.
+    pub fn get<K: Borrow<[u8]> + Into<CowBytes>>(
+        &mut self,
+        key: K,
+    ) -> Result<Option<SlicedCowBytes>> {
+        let mut data = self.dataset.get(key.borrow())?;
+        let key: CowBytes = key.into();
+        let writes = &self.writes;
+        self.dataset.call_tree(|tree| {
+            for (_, msg) in writes.iter().filter(|(written, _)| *written == key) {
+                tree.msg_action().apply(&key, msg, &mut data);
+            }
+        });
+        self.reads.insert(key);
+        Ok(data)
+    }
+
+    /// Buffers a message for the given key.
+    pub fn insert_msg<K: Into<CowBytes>>(&mut self, key: K, msg: SlicedCowBytes) {
+        self.writes.push((key.into(), msg));
+    }
+
+    /// Validates the reads of this transaction and applies its writes.
+    ///
+    /// Fails with [Error::TransactionConflict] without applying any write if
+    /// a key read by the transaction has been modified since it began.  All
+    /// other checks of the writes are done before the first one is applied
+    /// as well.  The writes are applied while all other operations on the
+    /// dataset and syncs are blocked, so that they become durable with the
+    /// same sync.
+    pub fn commit(mut self) -> Result<()> {
+        let writes = std::mem::take(&mut self.writes);
+        let inner = self.dataset.lock_exclusive();
+        let conflict = {
+            let versions = inner.versions.lock();
+            self.reads
+                .iter()
+                .any(|key| versions.modified_since(key, self.start))
+        };
+        if conflict {
+            return Err(Error::TransactionConflict);
+        }
+        let handler = inner.tree.dmu().handler();
+        if handler.follower {
+            return Err(Error::ReadOnlyFollower);
+        }
+        if writes.iter().any(|(key, _)| key.is_empty()) {
+            return Err(tree::Error::EmptyKey.into());
+        }
+        // Loads the paths to all written keys up front, so that applying the
+        // writes does not have to read nodes which might fail halfway.
+        for (key, _) in writes.iter() {
+            inner.get(&key[..])?;
+        }
+        let _commit = handler.commit_lock.read();
+        for (key, msg) in writes {
+            inner.insert_msg(key, msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transaction<DefaultMessageAction> {
+    /// Buffers inserting the given key-value pair.
+    pub fn insert<K: Into<CowBytes>>(&mut self, key: K, data: &[u8]) -> Result<()> {
+        if data.len() > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.insert_msg(key, DefaultMessageAction::insert_msg(data));
+        Ok(())
+    }
+
+    /// Buffers upserting the value for the given key at the given offset.
+    pub fn upsert<K: Into<CowBytes>>(&mut self, key: K, data: &[u8], offset: u32) -> Result<()> {
+        if offset as usize + data.len() > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.insert_msg(key, DefaultMessageAction::upsert_msg(offset, data));
+        Ok(())
+    }
+
+    /// Buffers deleting the key-value pair if existing.
+    pub fn delete<K: Into<CowBytes>>(&mut self, key: K) {
+        self.insert_msg(key, DefaultMessageAction::delete_msg());
+    }
+}
+
+impl<Message> Drop for Transaction<Message> {
+    fn drop(&mut self) {
+        let start = self.start;
+        self.dataset.call_versions(|versions| versions.end(start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyVersions;
+    use crate::cow_bytes::CowBytes;
+
+    #[test]
+    fn writes_after_begin_conflict() {
+        let mut versions = KeyVersions::default();
+        let key = CowBytes::from(&b"a"[..]);
+        // Without open transactions, writes are not recorded.
+        versions.record_write(&key);
+        let start = versions.begin();
+        assert!(!versions.modified_since(b"a", start));
+
+        versions.record_write(&key);
+        assert!(versions.modified_since(b"a", start));
+        assert!(!versions.modified_since(b"b", start));
+        let later = versions.begin();
+        assert!(!versions.modified_since(b"a", later));
+    }
+
+    #[test]
+    fn ended_transactions_release_versions() {
+        let mut versions = KeyVersions::default();
+        let first = versions.begin();
+        versions.record_write(&CowBytes::from(&b"a"[..]));
+        let second = versions.begin();
+        versions.record_write(&CowBytes::from(&b"b"[..]));
+
+        versions.end(first);
+        assert_eq!(versions.keys.len(), 1);
+        assert!(versions.modified_since(b"b", second));
+        versions.end(second);
+        assert!(versions.keys.is_empty());
+        assert!(versions.open.is_empty());
+    }
+}
+
+/// Writes to several datasets which are applied together and become durable
+/// with the same sync, e.g. to keep an index consistent with its data in the
+/// case of a crash.
+///
+/// All datasets of a batch have to belong to the same database.  Dropping a
+/// batch without committing it discards its writes.
+pub struct WriteBatch<Message = DefaultMessageAction> {
+    // The buffered messages in the order of their insertion.
+    writes: Vec<(Dataset<Message>, CowBytes, SlicedCowBytes)>,
+}
+
+impl<Message> Default for WriteBatch<Message> {
+    fn default() -> Self {
+        WriteBatch { writes: Vec::new() }
+    }
+}
+
+impl<Message> WriteBatch<Message> {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns whether no write has been buffered.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Buffers a message for the given key of `dataset`.
+    pub fn insert_msg<K: Into<CowBytes>>(
+        &mut self,
+        dataset: &Dataset<Message>,
+        key: K,
+        msg: SlicedCowBytes,
+    ) {
+        self.writes.push((dataset.clone(), key.into(), msg));
+    }
+}
+
+impl<Message: MessageAction + 'static> WriteBatch<Message> {
+    /// Applies the writes of this batch.
+    ///
+    /// All checks of the writes are done before the first one is applied.
+    /// The writes are applied while all other operations on the written
+    /// datasets and syncs are blocked, so that they become durable with the
+    /// same sync.
+    pub fn commit(self) -> Result<()> {
+        let mut datasets: Vec<&Dataset<Message>> = Vec::new();
+        for (dataset, _, _) in self.writes.iter() {
+            if !datasets.iter().any(|other| other.same_dataset(dataset)) {
+                datasets.push(dataset);
+            }
+        }
+        // Datasets are locked in a fixed order, so that concurrent batches
+        // cannot deadlock.
+        datasets.sort_by_key(|dataset| dataset.id());
+        let inners: Vec<_> = datasets
+            .iter()
+            .map(|dataset| dataset.lock_exclusive())
+            .collect();
+        let handler = match inners.first() {
+            Some(inner) => inner.tree.dmu().handler(),
+            None => return Ok(()),
+        };
+        if handler.follower {
+            return Err(Error::ReadOnlyFollower);
+        }
+        if self.writes.iter().any(|(_, key, _)| key.is_empty()) {
+            return Err(tree::Error::EmptyKey.into());
+        }
+        let inner_of = |dataset: &Dataset<Message>| {
+            let idx = datasets
+                .iter()
+                .position(|other| other.same_dataset(dataset))
+                .unwrap();
+            &inners[idx]
+        };
+        // Loads the paths to all written keys up front, so that applying the
+        // writes does not have to read nodes which might fail halfway.
+        for (dataset, key, _) in self.writes.iter() {
+            inner_of(dataset).get(&key[..])?;
+        }
+        let _commit = handler.commit_lock.read();
+        for (dataset, key, msg) in self.writes.iter() {
+            inner_of(dataset).insert_msg(key.clone(), msg.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl WriteBatch<DefaultMessageAction> {
+    /// Buffers inserting the given key-value pair into `dataset`.
+    pub fn insert<K: Into<CowBytes>>(
+        &mut self,
+        dataset: &Dataset,
+        key: K,
+        data: &[u8],
+    ) -> Result<()> {
+        if data.len() > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.insert_msg(dataset, key, DefaultMessageAction::insert_msg(data));
+        Ok(())
+    }
+
+    /// Buffers deleting the key-value pair of `dataset` if existing.
+    pub fn delete<K: Into<CowBytes>>(&mut self, dataset: &Dataset, key: K) {
+        self.insert_msg(dataset, key, DefaultMessageAction::delete_msg());
+    }
+}
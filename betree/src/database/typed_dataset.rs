@@ -0,0 +1,133 @@
+//! Datasets of typed keys and values, see [TypedDataset].
+use super::{errors::*, key_encoding, Dataset};
+use crate::{
+    cow_bytes::CowBytes,
+    tree::{DefaultMessageAction, MessageAction},
+    StoragePreference,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+/// A [Dataset] whose keys and values are of the types `K` and `V`.
+///
+/// Keys are stored in the order-preserving encoding of [key_encoding], so
+/// that the entries of the dataset are sorted like the typed keys, e.g.
+/// numerically for integers or by their fields for tuples and structs.
+/// Values are stored with [bincode].  Both encodings are not self-describing,
+/// the types must not change for an existing dataset.
+pub struct TypedDataset<K, V, Message = DefaultMessageAction> {
+    dataset: Dataset<Message>,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, Message> Clone for TypedDataset<K, V, Message> {
+    fn clone(&self) -> Self {
+        Self {
+            dataset: self.dataset.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, Message> TypedDataset<K, V, Message> {
+    /// Wraps `dataset`, whose entries have to be of the given types.
+    pub fn new(dataset: Dataset<Message>) -> Self {
+        TypedDataset {
+            dataset,
+            types: PhantomData,
+        }
+    }
+
+    /// Returns the untyped handle of the dataset.
+    pub fn dataset(&self) -> &Dataset<Message> {
+        &self.dataset
+    }
+}
+
+fn encode_key<K: Serialize + ?Sized>(key: &K) -> Result<CowBytes> {
+    Ok(key_encoding::to_key(key)?.into())
+}
+
+fn encode_bound<K: Serialize>(bound: Bound<&K>) -> Result<Bound<CowBytes>> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(encode_key(key)?),
+        Bound::Excluded(key) => Bound::Excluded(encode_key(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+fn encode_range<K: Serialize, R: RangeBounds<K>>(
+    range: &R,
+) -> Result<(Bound<CowBytes>, Bound<CowBytes>)> {
+    Ok((
+        encode_bound(range.start_bound())?,
+        encode_bound(range.end_bound())?,
+    ))
+}
+
+impl<K, V, Message> TypedDataset<K, V, Message>
+where
+    K: Serialize + DeserializeOwned + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+    Message: MessageAction + 'static,
+{
+    /// Returns the value for the given key if existing.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.dataset.get(encode_key(key)?)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterates over all entries in the given range of keys, in the order of
+    /// the keys.
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<Box<dyn Iterator<Item = Result<(K, V)>>>> {
+        let iter = self.dataset.range(encode_range(&range)?)?;
+        Ok(Box::new(iter.map(|entry| {
+            let (key, value) = entry?;
+            Ok((key_encoding::from_key(&key)?, bincode::deserialize(&value)?))
+        })))
+    }
+}
+
+impl<K, V> TypedDataset<K, V, DefaultMessageAction>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    /// Sets the value for the given key.
+    pub fn insert(&self, key: &K, value: &V) -> Result<()> {
+        self.dataset
+            .insert(encode_key(key)?, &bincode::serialize(value)?)
+    }
+
+    /// Sets the value for the given key with the given storage preference.
+    pub fn insert_with_pref(
+        &self,
+        key: &K,
+        value: &V,
+        storage_preference: StoragePreference,
+    ) -> Result<()> {
+        self.dataset.insert_with_pref(
+            encode_key(key)?,
+            &bincode::serialize(value)?,
+            storage_preference,
+        )
+    }
+
+    /// Deletes the entry of the given key if existing.
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.dataset.delete(encode_key(key)?)
+    }
+
+    /// Removes all entries in the given range of keys.
+    pub fn range_delete<R: RangeBounds<K>>(&self, range: R) -> Result<()> {
+        self.dataset.range_delete(encode_range(&range)?)
+    }
+}
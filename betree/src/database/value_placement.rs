@@ -0,0 +1,80 @@
+//! Placement of values by their size, see
+//! [DatabaseConfiguration::value_placement](super::DatabaseConfiguration::value_placement).
+//!
+//! Datasets often mix small metadata, which is read frequently, with large
+//! blobs, which are mostly streamed.  Without a storage preference both are
+//! placed identically.  A [ValuePlacement] routes values to storage classes by
+//! their size instead, e.g. small values to the fastest class and large ones
+//! to a slower class.
+use crate::StoragePreference;
+use serde::{Deserialize, Serialize};
+
+/// Places values of up to `max_size` bytes on `storage_class`, see
+/// [ValuePlacement].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueSizeRule {
+    /// The size in bytes up to which values are placed by this rule
+    pub max_size: u32,
+    /// The storage class of the values
+    pub storage_class: u8,
+}
+
+/// Rules which route the values inserted into a dataset to storage classes by
+/// their size.
+///
+/// A value is placed by the first rule whose `max_size` it does not exceed,
+/// values larger than the limits of all rules are placed like without rules.
+/// Explicit storage preferences of insertions and pinned key ranges take
+/// precedence over the rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ValuePlacement {
+    /// The rules in the order they are tried
+    pub rules: Vec<ValueSizeRule>,
+}
+
+impl ValuePlacement {
+    /// Returns the storage preference of a value of `size` bytes.
+    pub(crate) fn preference(&self, size: usize) -> StoragePreference {
+        self.rules
+            .iter()
+            .find(|rule| size <= rule.max_size as usize)
+            .map_or(StoragePreference::NONE, |rule| {
+                StoragePreference::new(rule.storage_class)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ValuePlacement, ValueSizeRule};
+    use crate::StoragePreference;
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let placement = ValuePlacement {
+            rules: vec![
+                ValueSizeRule {
+                    max_size: 4096,
+                    storage_class: 0,
+                },
+                ValueSizeRule {
+                    max_size: 1024 * 1024,
+                    storage_class: 1,
+                },
+            ],
+        };
+        assert_eq!(placement.preference(0), StoragePreference::FASTEST);
+        assert_eq!(placement.preference(4096), StoragePreference::FASTEST);
+        assert_eq!(placement.preference(4097), StoragePreference::FAST);
+        assert_eq!(
+            placement.preference(2 * 1024 * 1024),
+            StoragePreference::NONE
+        );
+        assert_eq!(
+            ValuePlacement::default().preference(0),
+            StoragePreference::NONE
+        );
+    }
+}
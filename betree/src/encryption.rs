@@ -0,0 +1,305 @@
+//! This module provides authenticated encryption of the serialized objects of
+//! the data management layer.
+//!
+//! Objects are encrypted with XChaCha20-Poly1305 after compression. The id of
+//! the key is stored in the [EncryptionTag] of the object pointer, the length
+//! of the plaintext, the random nonce and the authentication tag are stored
+//! alongside the ciphertext. The disk offset of an object is authenticated as
+//! well, so that encrypted objects can not be swapped on disk.
+use crate::{buffer::Buf, database::ErrorCode, size::StaticSize, storage_pool::DiskOffset};
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    Key, Tag, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, fmt};
+use thiserror::Error;
+
+const LEN_SIZE: usize = 4;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+#[allow(missing_docs)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("The encryption key {0} is not configured.")]
+    MissingKey(u16),
+    #[error("The encryption key {0} is configured more than once.")]
+    DuplicateKey(u16),
+    #[error("Decryption failed, the data has been modified or the key is wrong.")]
+    DecryptionFailed,
+    #[error("Encrypted data is stored, but no encryption is configured.")]
+    NotConfigured,
+}
+
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::DecryptionFailed => ErrorCode::Corrupted,
+            Error::MissingKey(_) | Error::DuplicateKey(_) | Error::NotConfigured => {
+                ErrorCode::Configuration
+            }
+        }
+    }
+}
+
+/// Configuration of the encryption at rest.
+///
+/// Keys may be rotated by adding a new key and making it the active one.
+/// Objects are encrypted with the active key whenever they are written, see
+/// [Database::rotate_encryption_key](crate::Database::rotate_encryption_key)
+/// to rewrite all objects. Previous keys have to be kept as long as objects,
+/// e.g. of snapshots, are encrypted with them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfiguration {
+    /// Id of the key new objects are encrypted with.
+    pub active_key: u16,
+    /// All keys objects may be encrypted with.
+    pub keys: Vec<EncryptionKey>,
+}
+
+/// A 256-bit key and its id.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionKey {
+    /// The id under which the key is referenced by encrypted objects.
+    pub id: u16,
+    /// The key, represented as 64 hexadecimal digits.
+    #[serde(serialize_with = "serialize_key", deserialize_with = "deserialize_key")]
+    pub key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+fn serialize_key<S: Serializer>(key: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+    serializer.serialize_str(&hex)
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    use serde::de::Error;
+    let hex = String::deserialize(deserializer)?;
+    let mut key = [0; 32];
+    if hex.len() != 2 * key.len() || !hex.is_ascii() {
+        return Err(D::Error::custom(
+            "a key has to consist of 64 hexadecimal digits",
+        ));
+    }
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).map_err(D::Error::custom)?;
+    }
+    Ok(key)
+}
+
+/// This tag is stored alongside encrypted objects to select the key to
+/// decrypt them with.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum EncryptionTag {
+    /// The object is not encrypted.
+    None,
+    /// The object is encrypted with XChaCha20-Poly1305 and the key of the
+    /// given id.
+    XChaCha20Poly1305(u16),
+}
+
+impl StaticSize for EncryptionTag {
+    fn static_size() -> usize {
+        // The variant index is encoded as u32.
+        4 + 2
+    }
+}
+
+impl EncryptionTag {
+    /// Returns the size of `len` bytes of data once encrypted with this tag,
+    /// excluding padding.
+    pub fn encrypted_len(&self, len: usize) -> usize {
+        match self {
+            EncryptionTag::None => len,
+            EncryptionTag::XChaCha20Poly1305(_) => LEN_SIZE + NONCE_SIZE + len + TAG_SIZE,
+        }
+    }
+}
+
+/// The configured keys of a database.
+pub struct Encryption {
+    active_key: u16,
+    ciphers: HashMap<u16, XChaCha20Poly1305>,
+}
+
+impl Encryption {
+    /// Prepares the keys of `config`. Fails if the active key is not
+    /// configured or ids are not unique.
+    pub fn new(config: &EncryptionConfiguration) -> Result<Self, Error> {
+        let mut ciphers = HashMap::new();
+        for key in config.keys.iter() {
+            let cipher = XChaCha20Poly1305::new(Key::from_slice(&key.key));
+            if ciphers.insert(key.id, cipher).is_some() {
+                return Err(Error::DuplicateKey(key.id));
+            }
+        }
+        if !ciphers.contains_key(&config.active_key) {
+            return Err(Error::MissingKey(config.active_key));
+        }
+        Ok(Encryption {
+            active_key: config.active_key,
+            ciphers,
+        })
+    }
+
+    /// Returns the tag of objects encrypted with the active key.
+    pub fn tag(&self) -> EncryptionTag {
+        EncryptionTag::XChaCha20Poly1305(self.active_key)
+    }
+
+    /// Encrypts `data` with the active key for storing it at `offset`. The
+    /// result is padded to full blocks.
+    pub fn encrypt(&self, data: Buf, offset: DiskOffset) -> Result<Buf, Error> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut buf = Vec::with_capacity(self.tag().encrypted_len(data.len()));
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&data);
+        let tag = self.ciphers[&self.active_key]
+            .encrypt_in_place_detached(
+                XNonce::from_slice(&nonce),
+                &offset.as_u64().to_le_bytes(),
+                &mut buf[LEN_SIZE + NONCE_SIZE..],
+            )
+            .expect("Data exceeds the maximum size of the cipher");
+        buf.extend_from_slice(&tag);
+        Ok(Buf::from_zero_padded(buf))
+    }
+
+    fn decrypt_with(&self, key: u16, data: Buf, offset: DiskOffset) -> Result<Buf, Error> {
+        let cipher = self.ciphers.get(&key).ok_or(Error::MissingKey(key))?;
+        let data = data.as_ref();
+        let len = data
+            .get(..LEN_SIZE)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or(Error::DecryptionFailed)?;
+        if data.len() < LEN_SIZE + NONCE_SIZE + len + TAG_SIZE {
+            return Err(Error::DecryptionFailed);
+        }
+        let (nonce, rest) = data[LEN_SIZE..].split_at(NONCE_SIZE);
+        let (ciphertext, rest) = rest.split_at(len);
+        let mut plaintext = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &offset.as_u64().to_le_bytes(),
+                &mut plaintext,
+                Tag::from_slice(&rest[..TAG_SIZE]),
+            )
+            .map_err(|_| Error::DecryptionFailed)?;
+        Ok(Buf::from_zero_padded(plaintext))
+    }
+}
+
+/// Decrypts `data` which has been read from `offset` according to `tag`.
+pub fn decrypt(
+    encryption: Option<&Encryption>,
+    tag: EncryptionTag,
+    data: Buf,
+    offset: DiskOffset,
+) -> Result<Buf, Error> {
+    match (tag, encryption) {
+        (EncryptionTag::None, _) => Ok(data),
+        (EncryptionTag::XChaCha20Poly1305(_), None) => Err(Error::NotConfigured),
+        (EncryptionTag::XChaCha20Poly1305(key), Some(encryption)) => {
+            encryption.decrypt_with(key, data, offset)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, Encryption, EncryptionConfiguration, EncryptionKey, EncryptionTag};
+    use crate::{buffer::Buf, storage_pool::DiskOffset, vdev::Block};
+
+    fn encryption(active_key: u16) -> Encryption {
+        Encryption::new(&EncryptionConfiguration {
+            active_key,
+            keys: vec![
+                EncryptionKey {
+                    id: 1,
+                    key: [1; 32],
+                },
+                EncryptionKey {
+                    id: 2,
+                    key: [2; 32],
+                },
+            ],
+        })
+        .unwrap()
+    }
+
+    fn data() -> Buf {
+        Buf::from_zero_padded((0..10000u32).map(|x| x as u8).collect())
+    }
+
+    #[test]
+    fn round_trip() {
+        let encryption = encryption(1);
+        let offset = DiskOffset::new(0, 1, Block(2));
+        let tag = encryption.tag();
+        let encrypted = encryption.encrypt(data(), offset).unwrap();
+        assert_eq!(encrypted.len() % crate::vdev::BLOCK_SIZE, 0);
+        assert!(encrypted.len() >= tag.encrypted_len(data().len()));
+        assert_ne!(&encrypted.as_ref()[..data().len()], data().as_ref());
+
+        let decrypted = decrypt(Some(&encryption), tag, encrypted, offset).unwrap();
+        assert_eq!(decrypted.as_ref(), data().as_ref());
+    }
+
+    #[test]
+    fn rotated_keys_decrypt() {
+        let offset = DiskOffset::new(0, 1, Block(2));
+        let old = encryption(1);
+        let encrypted = old.encrypt(data(), offset).unwrap();
+        let new = encryption(2);
+        assert_eq!(new.tag(), EncryptionTag::XChaCha20Poly1305(2));
+        let decrypted = decrypt(Some(&new), old.tag(), encrypted, offset).unwrap();
+        assert_eq!(decrypted.as_ref(), data().as_ref());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let encryption = encryption(1);
+        let offset = DiskOffset::new(0, 1, Block(2));
+        let encrypted = encryption.encrypt(data(), offset).unwrap();
+
+        let moved = DiskOffset::new(0, 1, Block(3));
+        assert!(decrypt(
+            Some(&encryption),
+            encryption.tag(),
+            encrypted.clone(),
+            moved
+        )
+        .is_err());
+
+        let mut modified = encrypted.into_boxed_slice();
+        modified[100] ^= 1;
+        assert!(decrypt(Some(&encryption), encryption.tag(), modified.into(), offset).is_err());
+        assert!(decrypt(None, encryption.tag(), data(), offset).is_err());
+    }
+
+    #[test]
+    fn missing_active_key() {
+        assert!(Encryption::new(&EncryptionConfiguration {
+            active_key: 3,
+            keys: Vec::new(),
+        })
+        .is_err());
+    }
+}
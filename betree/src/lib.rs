@@ -6,8 +6,6 @@
 // a maybe needed in the future basis.
 #![allow(dead_code)]
 
-#[macro_use]
-extern crate error_chain;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -25,6 +23,7 @@ pub mod compression;
 pub mod cow_bytes;
 pub mod data_management;
 pub mod database;
+pub mod encryption;
 pub mod range_validation;
 pub mod size;
 pub mod storage_pool;
@@ -36,6 +35,10 @@ pub mod object;
 
 pub mod migration;
 
+mod clock;
+#[cfg(feature = "sim")]
+pub mod sim;
+
 #[cfg(feature = "init_env_logger")]
 pub mod env_logger;
 
@@ -43,7 +46,9 @@ pub mod env_logger;
 mod arbitrary;
 
 pub use self::{
-    database::{Database, DatabaseConfiguration, Dataset, Error, Snapshot},
+    database::{
+        ingest, inspect, Database, DatabaseConfiguration, Dataset, Error, ErrorCode, Snapshot,
+    },
     storage_pool::{
         AtomicStoragePreference, PreferredAccessType, StoragePoolConfiguration, StoragePreference,
     },
@@ -0,0 +1,60 @@
+//! Everything handed to a [super::MigrationPolicy] on construction.
+use crossbeam_channel::Receiver;
+use parking_lot::{Mutex, RwLock};
+use std::{collections::HashMap, sync::Arc};
+
+use super::{DatabaseMsg, DmlMsg};
+use crate::{data_management::DmlWithStorageHints, tree::PivotKey, Database, StoragePreference};
+
+/// The environment of a migration policy.
+///
+/// The context delivers reports about the usage of nodes and objects and gives
+/// access to the [Database] the policy operates on.  It is passed to the
+/// constructor given to [Database::build_threaded_with_migration_policy].
+pub struct MigrationContext {
+    pub(super) dml_rx: Receiver<DmlMsg>,
+    pub(super) db_rx: Receiver<DatabaseMsg>,
+    pub(super) db: Arc<RwLock<Database>>,
+    pub(super) storage_hints: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+}
+
+impl MigrationContext {
+    pub(crate) fn new(
+        dml_rx: Receiver<DmlMsg>,
+        db_rx: Receiver<DatabaseMsg>,
+        db: Arc<RwLock<Database>>,
+    ) -> Self {
+        let storage_hints = db.read().root_tree.dmu().storage_hints();
+        MigrationContext {
+            dml_rx,
+            db_rx,
+            db,
+            storage_hints,
+        }
+    }
+
+    /// Returns the database the policy operates on.
+    pub fn db(&self) -> &Arc<RwLock<Database>> {
+        &self.db
+    }
+
+    /// Returns the reports about nodes which arrived since the last call.
+    pub fn node_messages(&self) -> impl Iterator<Item = DmlMsg> + '_ {
+        self.dml_rx.try_iter()
+    }
+
+    /// Returns the reports about datasets, object stores and objects which
+    /// arrived since the last call.
+    pub fn object_messages(&self) -> impl Iterator<Item = DatabaseMsg> + '_ {
+        self.db_rx.try_iter()
+    }
+
+    /// Hints that the node identified by `key` should be moved to the storage
+    /// class `pref`.
+    ///
+    /// The hint is applied lazily the next time the node is written, which
+    /// makes it best suited for promotions of frequently used nodes.
+    pub fn hint_node(&self, key: PivotKey, pref: StoragePreference) {
+        self.storage_hints.lock().insert(key, pref);
+    }
+}
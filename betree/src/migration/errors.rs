@@ -1,13 +1,47 @@
 #![allow(missing_docs, unused_doc_comments)]
-error_chain! {
-    foreign_links {
-        DmuError(crate::data_management::Error);
-        DbError(crate::database::errors::Error);
-        Io(std::io::Error);
-        SerializeJson(serde_json::Error);
-    }
-    errors {
-        ConstructionFailed
-        MigrationFailed
+use crate::database::ErrorCode;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Storage operation could not be performed.")]
+    DmuError {
+        #[from]
+        source: crate::data_management::Error,
+    },
+    #[error("Database operation could not be performed.")]
+    DbError {
+        #[from]
+        source: crate::database::Error,
+    },
+    #[error("IO error occurred.")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Could not serialize the policy state.")]
+    SerializeJson {
+        #[from]
+        source: serde_json::Error,
+    },
+    #[error("The migration policy could not be constructed.")]
+    ConstructionFailed,
+    #[error("The migration could not be performed.")]
+    MigrationFailed,
+}
+
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::DmuError { source } => source.code(),
+            Error::DbError { source } => source.code(),
+            Error::Io { .. } => ErrorCode::Io,
+            Error::SerializeJson { .. } => ErrorCode::Internal,
+            Error::ConstructionFailed => ErrorCode::Configuration,
+            Error::MigrationFailed => ErrorCode::Other,
+        }
     }
 }
@@ -0,0 +1,155 @@
+//! Access rates of objects within a sliding time window.
+use super::GlobalObjectId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A number of reads within a time span, e.g. 10 reads per hour.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadRate {
+    /// The number of reads.
+    pub reads: u32,
+    /// The time span in which the reads occur.
+    pub per: Duration,
+}
+
+const BUCKETS: usize = 16;
+
+/// Counts the accesses of objects within a sliding time window.
+///
+/// The window is divided into 16 buckets, accesses expire bucket-wise, so
+/// counts may include accesses up to a sixteenth of the window older than the
+/// window itself.
+pub struct AccessWindow {
+    bucket_len: Duration,
+    objects: HashMap<GlobalObjectId, Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u32; BUCKETS],
+    // The number of the bucket of the latest access.
+    latest: u64,
+}
+
+impl AccessWindow {
+    /// Returns a new window spanning the given duration.
+    pub fn new(window: Duration) -> Self {
+        AccessWindow {
+            bucket_len: (window / BUCKETS as u32).max(Duration::from_nanos(1)),
+            objects: HashMap::new(),
+        }
+    }
+
+    fn bucket(&self, time: SystemTime) -> u64 {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (since_epoch.as_nanos() / self.bucket_len.as_nanos()) as u64
+    }
+
+    /// Records an access of the object `id` at the given time.
+    pub fn record(&mut self, id: GlobalObjectId, time: SystemTime) {
+        let bucket = self.bucket(time);
+        let buckets = self.objects.entry(id).or_default();
+        if bucket > buckets.latest {
+            // Reset the buckets which are reused for the new accesses.
+            for passed in (buckets.latest + 1..=bucket).take(BUCKETS) {
+                buckets.counts[passed as usize % BUCKETS] = 0;
+            }
+            buckets.latest = bucket;
+        }
+        // Accesses reported late may have left the window already.
+        if bucket + BUCKETS as u64 > buckets.latest {
+            let count = &mut buckets.counts[bucket as usize % BUCKETS];
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// Returns the number of accesses of the object `id` within the window
+    /// ending at `now`.
+    pub fn count(&self, id: &GlobalObjectId, now: SystemTime) -> u32 {
+        let now = self.bucket(now);
+        self.objects.get(id).map_or(0, |buckets| {
+            let first = now.max(buckets.latest).saturating_sub(BUCKETS as u64 - 1);
+            (first..=now.min(buckets.latest))
+                .map(|bucket| buckets.counts[bucket as usize % BUCKETS])
+                .sum()
+        })
+    }
+
+    /// Returns all objects accessed at least `min` times within the window
+    /// ending at `now`, the most accessed first.
+    pub fn exceeding(&self, min: u32, now: SystemTime) -> Vec<(GlobalObjectId, u32)> {
+        let mut hot: Vec<_> = self
+            .objects
+            .keys()
+            .map(|id| (id.clone(), self.count(id, now)))
+            .filter(|(_, count)| *count > 0 && *count >= min)
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1));
+        hot
+    }
+
+    /// Forgets all objects which have not been accessed within the window
+    /// ending at `now`.
+    pub fn expire(&mut self, now: SystemTime) {
+        let now = self.bucket(now);
+        self.objects
+            .retain(|_, buckets| buckets.latest + BUCKETS as u64 > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessWindow, GlobalObjectId};
+    use crate::{database::DatasetId, object::ObjectId};
+    use speedy::Readable;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn object(id: u64) -> GlobalObjectId {
+        let os_id = DatasetId::default().next();
+        let id = ObjectId::read_from_buffer(&id.to_le_bytes()).unwrap();
+        GlobalObjectId::build(os_id, id)
+    }
+
+    #[test]
+    fn accesses_expire() {
+        let mut window = AccessWindow::new(minutes(64));
+        let start = UNIX_EPOCH + minutes(64 * 100);
+        for m in 0..10 {
+            window.record(object(1), start + minutes(m));
+        }
+        assert_eq!(window.count(&object(1), start + minutes(10)), 10);
+        assert_eq!(window.count(&object(1), start + minutes(66)), 6);
+        assert_eq!(window.count(&object(1), start + minutes(200)), 0);
+        assert_eq!(window.count(&object(2), start), 0);
+
+        window.record(object(1), start + minutes(200));
+        assert_eq!(window.count(&object(1), start + minutes(200)), 1);
+    }
+
+    #[test]
+    fn most_accessed_first() {
+        let mut window = AccessWindow::new(minutes(60));
+        let now = UNIX_EPOCH + minutes(6000);
+        for _ in 0..3 {
+            window.record(object(1), now);
+        }
+        for _ in 0..5 {
+            window.record(object(2), now);
+        }
+        window.record(object(3), now);
+        assert_eq!(
+            window.exceeding(2, now),
+            vec![(object(2), 5), (object(1), 3)]
+        );
+
+        window.expire(now + minutes(120));
+        assert!(window.exceeding(0, now + minutes(120)).is_empty());
+    }
+}
@@ -6,12 +6,13 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     io::Write,
     sync::Arc,
+    time::Instant,
 };
 
 use crate::{
+    clock,
     cow_bytes::CowBytes,
     data_management::{DmlWithStorageHints, HasStoragePreference},
-    database::RootDmu,
     object::{ObjectStore, ObjectStoreId},
     storage_pool::NUM_STORAGE_CLASSES,
     tree::PivotKey,
@@ -22,7 +23,9 @@ use crate::{
 use super::{
     errors::{Error, Result},
     reinforcment_learning::open_file_buf_write,
-    DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig,
+    AccessWindow, DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig, MigrationFailure,
+    MigrationPlan, MigrationPolicies, MigrationReport, MigrationReports, MigrationSource,
+    MigrationSubject, PlannedMigration, ReadRate,
 };
 
 /// Implementation of Least Frequently Used
@@ -31,7 +34,6 @@ pub struct Lfu {
     dml_rx: Receiver<DmlMsg>,
     db_rx: Receiver<DatabaseMsg>,
     db: Arc<RwLock<Database>>,
-    dmu: Arc<RootDmu>,
     config: MigrationConfig<LfuConfig>,
     // Store open object stores to move inactive objects within.
     object_stores: HashMap<ObjectStoreId, Option<ObjectStore>>,
@@ -40,6 +42,24 @@ pub struct Lfu {
     /// HashMap accessible by the DML, resolution is not guaranteed but always
     /// used when a object is written.
     storage_hint_dml: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+    /// Migrations performed in the current round, or planned in a dry run.
+    /// In a dry run the caches are updated as if the migrations were
+    /// performed and restored at the end of the round.
+    planned: Vec<(PlannedKey, PlannedMigration)>,
+    migration_plan: Arc<Mutex<Option<MigrationPlan>>>,
+    /// Migrations which failed in the current round.
+    failures: Vec<MigrationFailure>,
+    round_start: Instant,
+    migration_reports: Arc<MigrationReports>,
+    /// Recent reads of objects, if promotions are restricted to objects
+    /// exceeding [LfuConfig::min_read_rate].
+    reads: Option<AccessWindow>,
+}
+
+/// Cache key of a planned migration.
+enum PlannedKey {
+    Object(GlobalObjectId),
+    Node(PivotKey),
 }
 
 /// Least frequently used (LFU) specific configuration details.
@@ -66,6 +86,13 @@ pub struct LfuConfig {
     pub path_delta: Option<std::path::PathBuf>,
     /// Migrate Objects, Nodes or Both.
     pub mode: LfuMode,
+    /// Only promote objects which have been read at least this often within
+    /// the given time span, e.g. 10 reads per hour. The most read objects are
+    /// promoted first.  Only used in [LfuMode::Object], if unset all objects
+    /// are considered in order of their access frequency since the start of
+    /// the session.
+    #[serde(default)]
+    pub min_read_rate: Option<ReadRate>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -93,6 +120,7 @@ impl Default for LfuConfig {
             path_state: None,
             path_delta: None,
             mode: LfuMode::Object,
+            min_read_rate: None,
         }
     }
 }
@@ -142,6 +170,9 @@ impl<'lfu> Lfu {
 
     fn update_db(&mut self) -> Result<()> {
         for msg in self.db_rx.try_iter() {
+            if let (DatabaseMsg::ObjectRead(key, time, _), Some(reads)) = (&msg, &mut self.reads) {
+                reads.record(key.clone(), *time);
+            }
             match msg {
                 // FIXME: Noop for now, this should optimally be maybe matching
                 // which datasets are active to prevent migrations from them or
@@ -178,6 +209,17 @@ impl<'lfu> Lfu {
                         self.objects[id].get(&key);
                     }
                 }
+                DatabaseMsg::Reconfigure(MigrationPolicies::Lfu(config)) => {
+                    let per = |config: &MigrationConfig<LfuConfig>| {
+                        config.policy_config.min_read_rate.map(|rate| rate.per)
+                    };
+                    // Keep the recorded reads unless their window changed.
+                    if per(&config) != per(&self.config) {
+                        self.reads = per(&config).map(AccessWindow::new);
+                    }
+                    self.config = config;
+                }
+                DatabaseMsg::Reconfigure(_) => {}
                 DatabaseMsg::ObjectMigrate(key, pref) => {
                     for id in 0..NUM_STORAGE_CLASSES {
                         if let Some((name, freq)) = self.objects[id].remove_with_frequency(&key) {
@@ -202,22 +244,44 @@ impl<'lfu> Lfu {
         config: MigrationConfig<LfuConfig>,
         storage_hint_dml: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
     ) -> Self {
-        let dmu = Arc::clone(db.read().root_tree.dmu());
-        let default_storage_class = dmu.default_storage_class();
+        let default_storage_class = db.read().root_tree.dmu().default_storage_class();
+        let migration_plan = Arc::clone(&db.read().migration_plan);
+        let migration_reports = Arc::clone(&db.read().migration_reports);
+        let reads = config
+            .policy_config
+            .min_read_rate
+            .map(|rate| AccessWindow::new(rate.per));
         Self {
             nodes: [(); NUM_STORAGE_CLASSES].map(|_| LfuCache::unbounded()),
             dml_rx,
             db_rx,
-            dmu,
             db,
             config,
             storage_hint_dml,
             object_stores: Default::default(),
             objects: [(); NUM_STORAGE_CLASSES].map(|_| LfuCache::unbounded()),
             default_storage_class,
+            planned: Vec::new(),
+            migration_plan,
+            failures: Vec::new(),
+            round_start: clock::now(),
+            migration_reports,
+            reads,
         }
     }
 
+    fn plan(&mut self, key: PlannedKey, subject: MigrationSubject, from: u8, to: u8, size: u64) {
+        self.planned.push((
+            key,
+            PlannedMigration {
+                subject,
+                from,
+                to,
+                size,
+            },
+        ));
+    }
+
     fn migrate_object_from_to(
         &self,
         object_id: GlobalObjectId,
@@ -239,10 +303,11 @@ impl<'lfu> Lfu {
                 let os = db.open_object_store_with_id(*object_id.store_key())?;
                 os
             } else {
-                return Err(Error::from_kind(super::errors::ErrorKind::MigrationFailed));
+                return Err(Error::MigrationFailed);
             }
         };
-        if let Some(mut obj) = store.open_object(object_name)? {
+        // Objects are reported by their full key, which may be namespaced.
+        if let Some((mut obj, _)) = store.read_object_handle_with_info(object_name)? {
             let size = obj
                 .info()?
                 .expect("Object does not have any metadata.")
@@ -250,7 +315,82 @@ impl<'lfu> Lfu {
             obj.migrate(to)?;
             return Ok(Block::from_bytes(size));
         }
-        Err(Error::from_kind(super::errors::ErrorKind::MigrationFailed))
+        Err(Error::MigrationFailed)
+    }
+
+    /// Promotes the objects of `storage_tier` exceeding the configured read
+    /// rate, the most read objects first.
+    fn promote_hot_objects(
+        &mut self,
+        storage_tier: u8,
+        target: StoragePreference,
+        desired: Block<u64>,
+        tight_space: bool,
+    ) -> Result<Block<u64>> {
+        let mut moved = Block(0_u64);
+        let (reads, min) = match (&self.reads, self.config.policy_config.min_read_rate) {
+            (Some(reads), Some(rate)) => (reads, rate.reads),
+            _ => return Ok(moved),
+        };
+        let up_freq = self.objects[target.as_u8() as usize]
+            .peek_lfu_frequency()
+            .map_or(0, |(_, freq)| freq);
+        for (object_id, count) in reads.exceeding(min, clock::system_now()) {
+            let (val, freq) =
+                match self.objects[storage_tier as usize].remove_with_frequency(&object_id) {
+                    Some(entry) => entry,
+                    // Stored on another tier.
+                    None => continue,
+                };
+            if tight_space && up_freq >= freq {
+                self.objects[storage_tier as usize].insert_with_frequency(object_id, val, freq);
+                continue;
+            }
+            let size = if self.config.dry_run {
+                val.1
+            } else {
+                match self.migrate_object_from_to(
+                    object_id.clone(),
+                    &val.0,
+                    StoragePreference::from_u8(storage_tier),
+                    target,
+                ) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        warn!("Promoting object {object_id} failed: {e}");
+                        self.failures.push(MigrationFailure {
+                            subject: MigrationSubject::Object {
+                                store: *object_id.store_key(),
+                                name: val.0.clone(),
+                            },
+                            to: target.as_u8(),
+                            error: e.to_string(),
+                        });
+                        self.objects[storage_tier as usize]
+                            .insert_with_frequency(object_id, val, freq);
+                        break;
+                    }
+                }
+            };
+            debug!("Promoting object {object_id} read {count} times");
+            moved += size;
+            self.plan(
+                PlannedKey::Object(object_id.clone()),
+                MigrationSubject::Object {
+                    store: *object_id.store_key(),
+                    name: val.0.clone(),
+                },
+                storage_tier,
+                target.as_u8(),
+                size.to_bytes(),
+            );
+            self.objects[target.as_u8() as usize].insert_with_frequency(object_id, val, freq);
+
+            if moved >= desired {
+                break;
+            }
+        }
+        Ok(moved)
     }
 }
 
@@ -264,8 +404,11 @@ impl super::MigrationPolicy for Lfu {
         let mut moved = Block(0_u64);
         let target = StoragePreference::from_u8(storage_tier - 1);
         match self.config.policy_config.mode {
+            LfuMode::Object if self.config.policy_config.min_read_rate.is_some() => {
+                moved = self.promote_hot_objects(storage_tier, target, desired, tight_space)?;
+            }
             LfuMode::Object => {
-                while let Some((object_id, (name, _), freq)) =
+                while let Some((object_id, (name, size), freq)) =
                     self.objects[storage_tier as usize].peek_mfu_key_value_frequency()
                 {
                     let up_freq = if let Some((_upper_size, other_freq)) =
@@ -277,15 +420,44 @@ impl super::MigrationPolicy for Lfu {
                     };
 
                     if up_freq < freq || !tight_space {
-                        moved += self.migrate_object_from_to(
-                            object_id.clone(),
-                            name,
-                            StoragePreference::from_u8(storage_tier),
-                            target,
-                        )?;
+                        let size = if self.config.dry_run {
+                            *size
+                        } else {
+                            match self.migrate_object_from_to(
+                                object_id.clone(),
+                                name,
+                                StoragePreference::from_u8(storage_tier),
+                                target,
+                            ) {
+                                Ok(size) => size,
+                                Err(e) => {
+                                    warn!("Promoting object {object_id} failed: {e}");
+                                    self.failures.push(MigrationFailure {
+                                        subject: MigrationSubject::Object {
+                                            store: *object_id.store_key(),
+                                            name: name.clone(),
+                                        },
+                                        to: target.as_u8(),
+                                        error: e.to_string(),
+                                    });
+                                    break;
+                                }
+                            }
+                        };
+                        moved += size;
                         let (key, val, freq) = self.objects[storage_tier as usize]
                             .pop_mfu_key_value_frequency()
                             .expect("Invalid Pop");
+                        self.plan(
+                            PlannedKey::Object(key.clone()),
+                            MigrationSubject::Object {
+                                store: *key.store_key(),
+                                name: val.0.clone(),
+                            },
+                            storage_tier,
+                            target.as_u8(),
+                            size.to_bytes(),
+                        );
                         self.objects[target.as_u8() as usize].insert_with_frequency(key, val, freq);
 
                         if moved >= desired {
@@ -307,9 +479,27 @@ impl super::MigrationPolicy for Lfu {
                             0
                         };
                         if up_freq < freq || !tight_space {
-                            // MOVE DATA UPWARDS
-                            self.storage_hint_dml.lock().insert(key.clone(), target);
                             moved += lower_size.as_u64();
+                            // Move the node in the caches right away, so that
+                            // it is considered once.
+                            let (key, size, freq) = self.nodes[storage_tier as usize]
+                                .pop_mfu_key_value_frequency()
+                                .expect("Invalid Pop");
+                            if !self.config.dry_run {
+                                // MOVE DATA UPWARDS
+                                self.storage_hint_dml.lock().insert(key.clone(), target);
+                            }
+                            self.plan(
+                                PlannedKey::Node(key.clone()),
+                                MigrationSubject::Node {
+                                    dataset: key.d_id(),
+                                },
+                                storage_tier,
+                                target.as_u8(),
+                                Block(size.as_u64()).to_bytes(),
+                            );
+                            self.nodes[target.as_u8() as usize]
+                                .insert_with_frequency(key, size, freq);
 
                             // In case enough data has been moved; rate limited.
                             if moved >= desired {
@@ -336,19 +526,48 @@ impl super::MigrationPolicy for Lfu {
         match self.config.policy_config.mode {
             LfuMode::Object => {
                 let target = StoragePreference::from_u8(storage_tier + 1);
-                while let Some((object_id, (name, _), _freq)) =
+                while let Some((object_id, (name, size), _freq)) =
                     self.objects[storage_tier as usize].peek_lfu_key_value_frequency()
                 {
-                    moved += self.migrate_object_from_to(
-                        object_id.clone(),
-                        name,
-                        StoragePreference::from_u8(storage_tier),
-                        target,
-                    )?;
+                    let size = if self.config.dry_run {
+                        *size
+                    } else {
+                        match self.migrate_object_from_to(
+                            object_id.clone(),
+                            name,
+                            StoragePreference::from_u8(storage_tier),
+                            target,
+                        ) {
+                            Ok(size) => size,
+                            Err(e) => {
+                                warn!("Demoting object {object_id} failed: {e}");
+                                self.failures.push(MigrationFailure {
+                                    subject: MigrationSubject::Object {
+                                        store: *object_id.store_key(),
+                                        name: name.clone(),
+                                    },
+                                    to: target.as_u8(),
+                                    error: e.to_string(),
+                                });
+                                break;
+                            }
+                        }
+                    };
+                    moved += size;
 
                     let (key, val, freq) = self.objects[storage_tier as usize]
                         .pop_lfu_key_value_frequency()
                         .expect("Invalid Pop");
+                    self.plan(
+                        PlannedKey::Object(key.clone()),
+                        MigrationSubject::Object {
+                            store: *key.store_key(),
+                            name: val.0.clone(),
+                        },
+                        storage_tier,
+                        target.as_u8(),
+                        size.to_bytes(),
+                    );
                     self.objects[target.as_u8() as usize].insert_with_frequency(key, val, freq);
 
                     if moved >= desired {
@@ -363,13 +582,24 @@ impl super::MigrationPolicy for Lfu {
                     if let Some((key, entry, freq)) =
                         self.nodes[storage_tier as usize].pop_lfu_key_value_frequency()
                     {
-                        let ds = self
-                            .db
-                            .write()
-                            .open_dataset_with_id(key.d_id())
-                            .expect("Dataset Id incorrect");
-                        let mut cache_entry = ds.get_node_pivot_mut(&key).unwrap().unwrap();
-                        cache_entry.set_system_storage_preference(target);
+                        self.plan(
+                            PlannedKey::Node(key.clone()),
+                            MigrationSubject::Node {
+                                dataset: key.d_id(),
+                            },
+                            storage_tier,
+                            target.as_u8(),
+                            Block(entry.as_u64()).to_bytes(),
+                        );
+                        if !self.config.dry_run {
+                            let ds = self
+                                .db
+                                .write()
+                                .open_dataset_with_id(key.d_id())
+                                .expect("Dataset Id incorrect");
+                            let mut cache_entry = ds.get_node_pivot_mut(&key).unwrap().unwrap();
+                            cache_entry.set_system_storage_preference(target);
+                        }
                         // This does not adhere to constant costs, but rather is of O(number of unique frequencies)
                         debug!("Moving {:?}", key);
                         self.nodes[target.as_u8() as usize].insert_with_frequency(key, entry, freq);
@@ -400,12 +630,51 @@ impl super::MigrationPolicy for Lfu {
     }
 
     fn update(&mut self) -> Result<()> {
+        self.round_start = clock::now();
         self.update_dml()?;
-        self.update_db()
+        self.update_db()?;
+        if let Some(reads) = &mut self.reads {
+            reads.expire(clock::system_now());
+        }
+        Ok(())
     }
 
-    fn dmu(&self) -> &Arc<RootDmu> {
-        &self.dmu
+    fn end_round(&mut self) -> Result<()> {
+        if !self.config.dry_run {
+            let migrations: Vec<_> = self.planned.drain(..).map(|(_, m)| m).collect();
+            let failures = std::mem::take(&mut self.failures);
+            if !migrations.is_empty() || !failures.is_empty() {
+                self.migration_reports.publish(MigrationReport {
+                    source: MigrationSource::Policy,
+                    migrations,
+                    failures,
+                    duration: clock::elapsed(self.round_start),
+                });
+            }
+            return Ok(());
+        }
+        // Undo the planned migrations in the caches, the next round has to
+        // start from the actual placement of the data.
+        let mut migrations = Vec::with_capacity(self.planned.len());
+        for (key, migration) in self.planned.drain(..).rev() {
+            let (from, to) = (migration.from as usize, migration.to as usize);
+            match key {
+                PlannedKey::Object(key) => {
+                    if let Some((val, freq)) = self.objects[to].remove_with_frequency(&key) {
+                        self.objects[from].insert_with_frequency(key, val, freq);
+                    }
+                }
+                PlannedKey::Node(key) => {
+                    if let Some((val, freq)) = self.nodes[to].remove_with_frequency(&key) {
+                        self.nodes[from].insert_with_frequency(key, val, freq);
+                    }
+                }
+            }
+            migrations.push(migration);
+        }
+        migrations.reverse();
+        *self.migration_plan.lock() = Some(MigrationPlan { migrations });
+        Ok(())
     }
 
     /// Write metrics about current timestep
@@ -66,24 +66,101 @@
 //! use any method over the other. Policies declare in their documentation which
 //! kinds are used and how they impact the storage use.
 //!
-mod errors;
+//! # Object Hotness
+//!
+//! Each read of an object is reported with [DatabaseMsg::ObjectRead] together
+//! with the time of the read, which allows policies to treat whole objects as
+//! access units.  [AccessWindow] counts these reads within a sliding time span,
+//! the LFU policy uses it to only promote objects read more often than
+//! [LfuConfig::min_read_rate], e.g. more than 10 times per hour.
+//!
+//! # Pinned Ranges
+//!
+//! Key ranges pinned with [crate::database::Dataset::pin_range] are respected
+//! by all policies.  Nodes holding pinned entries may still be promoted to
+//! a faster storage class but are never demoted below the pinned one, and
+//! object migrations skip pinned keys.
+//!
+//! # Displaced Nodes
+//!
+//! With [crate::DatabaseConfiguration::spill_to_slower_tiers] set, nodes are
+//! written to a slower storage class if their preferred one is full.  All
+//! policies hint these nodes back to their preferred class once it has fallen
+//! below its [MigrationConfig::migration_threshold] again, see
+//! [Database::displaced_nodes].  Like other node migrations this happens
+//! lazily the next time a node is fetched or written back.
+//!
+//! # Dry Runs
+//!
+//! Before letting a policy loose on a production system its configuration can
+//! be validated by setting [MigrationConfig::dry_run].  The policy then decides
+//! on migrations as usual but does not perform them, instead the decisions of
+//! the latest round are available as a [MigrationPlan] via
+//! [Database::migration_plan].  The plan lists which objects and nodes would be
+//! moved between which storage tiers and sums up the bytes moved per tier.  Not
+//! all policies support dry runs, see [MigrationPolicies].
+//!
+//! # Reports
+//!
+//! To verify that migrations achieve the desired placement, subscribe to
+//! [Database::subscribe_migrations].  Each round of a policy which migrated
+//! anything, as well as each explicit range migration, results in
+//! a [MigrationReport] listing the moved data, the bytes moved per storage
+//! class, failed migrations, and the time taken.
+//!
+//! # Limiting Migrations
+//!
+//! Migrations compete with user requests for the bandwidth of the storage
+//! devices.  Their IO is issued with
+//! [crate::storage_pool::IoPriority::Background], so the limits of
+//! a tier's [crate::storage_pool::QosConfiguration] are mostly left to user
+//! requests.  Additionally, the bytes migrated by a policy can be limited with
+//! [MigrationConfig::max_bandwidth], and migrations can be restricted to
+//! certain times of the day with [MigrationConfig::active_windows], e.g. to
+//! keep them out of business hours.
+//!
+//! # Custom Policies
+//!
+//! Policies can also be implemented outside of this crate by implementing
+//! [MigrationPolicy] and passing a constructor to
+//! [Database::build_threaded_with_migration_policy].  The constructor receives
+//! a [MigrationContext] from which the policy consumes the reports about
+//! accessed nodes ([DmlMsg]) and objects ([DatabaseMsg]).  Objects are migrated
+//! with [crate::object::ObjectHandle::migrate], nodes by hinting their desired
+//! storage class with [MigrationContext::hint_node].
+//!
+mod context;
+pub mod errors;
+mod hotness;
 mod lfu;
 mod msg;
+mod plan;
 mod reinforcment_learning;
+mod report;
+mod window;
 
-use crossbeam_channel::Receiver;
+pub use context::MigrationContext;
 use errors::*;
+pub use hotness::{AccessWindow, ReadRate};
 use itertools::Itertools;
 pub use lfu::{LfuConfig, LfuMode};
-pub(crate) use msg::*;
-use parking_lot::{Mutex, RwLock};
+pub use msg::{DatabaseMsg, DmlMsg, GlobalObjectId, OpInfo};
+use parking_lot::RwLock;
+pub use plan::{MigrationPlan, MigrationSubject, PlannedMigration};
 pub use reinforcment_learning::RlConfig;
+pub(crate) use report::MigrationReports;
+pub use report::{MigrationFailure, MigrationReport, MigrationSource};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{sync::Arc, time::SystemTime};
+pub use window::MigrationWindow;
 
 use crate::{
-    data_management::DmlWithHandler, database::RootDmu, storage_pool::NUM_STORAGE_CLASSES,
-    tree::PivotKey, vdev::Block, Database, StoragePreference,
+    clock,
+    data_management::DmlWithStorageHints,
+    database::StorageInfo,
+    storage_pool::{QosConfiguration, Throttle, NUM_STORAGE_CLASSES},
+    vdev::Block,
+    Database,
 };
 
 use self::{lfu::Lfu, reinforcment_learning::ZhangHellanderToor};
@@ -122,20 +199,23 @@ pub enum MigrationPolicies {
     /// The configuration is purely informational but may be expanded in the
     /// future to allow for some experimentation with set learning values. They
     /// are closer described in [RlConfig].
+    ///
+    /// Dry runs are not supported, with [MigrationConfig::dry_run] set this
+    /// policy only records accesses.
     ReinforcementLearning(MigrationConfig<Option<RlConfig>>),
 }
 
 impl MigrationPolicies {
-    pub(crate) fn construct(
-        self,
-        dml_rx: Receiver<DmlMsg>,
-        db_rx: Receiver<DatabaseMsg>,
-        db: Arc<RwLock<Database>>,
-        storage_hint_sink: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
-    ) -> Box<dyn MigrationPolicy> {
+    pub(crate) fn construct(self, ctx: MigrationContext) -> Box<dyn MigrationPolicy> {
+        let MigrationContext {
+            dml_rx,
+            db_rx,
+            db,
+            storage_hints,
+        } = ctx;
         match self {
             MigrationPolicies::Lfu(config) => {
-                Box::new(Lfu::build(dml_rx, db_rx, db, config, storage_hint_sink))
+                Box::new(Lfu::build(dml_rx, db_rx, db, config, storage_hints))
             }
             MigrationPolicies::ReinforcementLearning(config) => {
                 Box::new(ZhangHellanderToor::build(dml_rx, db_rx, db, config))
@@ -147,7 +227,7 @@ impl MigrationPolicies {
 use std::time::Duration;
 
 /// Configuration type for [MigrationPolicies]
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MigrationConfig<Config> {
     /// Time at start where operations are _only_ recorded. This may help in avoiding incorrect early migrations by depending on a larger historical data.
     pub grace_period: Duration,
@@ -155,6 +235,15 @@ pub struct MigrationConfig<Config> {
     pub migration_threshold: [f32; NUM_STORAGE_CLASSES],
     /// Duration between consumption of operational messages. Enlarging this leads to greater memory usage, but reduces ongoing computational load.
     pub update_period: Duration,
+    /// Only plan migrations without performing them. The plan of the latest round can be retrieved with [Database::migration_plan].
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Maximum number of bytes migrated per second on average. Each round of migrations is accounted after it has been performed, so single rounds may exceed this limit, see e.g. [LfuConfig::promote_size].
+    #[serde(default)]
+    pub max_bandwidth: Option<u64>,
+    /// Daily windows in which migrations are performed. Outside of them operations are only recorded. Migrations are always performed if no window is given.
+    #[serde(default)]
+    pub active_windows: Vec<MigrationWindow>,
     /// Policy dependent configuration.
     pub policy_config: Config,
 }
@@ -162,14 +251,35 @@ pub struct MigrationConfig<Config> {
 impl<Config> MigrationConfig<Config> {
     /// Create an erased version of this configuration with the specific
     /// migration policy options removed.
-    fn erased(self) -> MigrationConfig<()> {
+    pub fn erased(self) -> MigrationConfig<()> {
         MigrationConfig {
             policy_config: (),
             grace_period: self.grace_period,
             migration_threshold: self.migration_threshold,
             update_period: self.update_period,
+            dry_run: self.dry_run,
+            max_bandwidth: self.max_bandwidth,
+            active_windows: self.active_windows,
         }
     }
+
+    /// Returns whether migrations may be performed at the given time.
+    fn is_active(&self, now: SystemTime) -> bool {
+        let time_of_day = window::time_of_day(now);
+        self.active_windows.is_empty()
+            || self
+                .active_windows
+                .iter()
+                .any(|window| window.contains(time_of_day))
+    }
+
+    /// Returns the throttle enforcing [MigrationConfig::max_bandwidth].
+    fn throttle(&self) -> Option<Throttle> {
+        Throttle::new(&QosConfiguration {
+            iops: None,
+            bandwidth: self.max_bandwidth,
+        })
+    }
 }
 
 impl<Config: Default> Default for MigrationConfig<Config> {
@@ -178,6 +288,9 @@ impl<Config: Default> Default for MigrationConfig<Config> {
             grace_period: Duration::from_secs(300),
             migration_threshold: [0.95; NUM_STORAGE_CLASSES],
             update_period: Duration::from_secs(30),
+            dry_run: false,
+            max_bandwidth: None,
+            active_windows: Vec::new(),
             policy_config: Default::default(),
         }
     }
@@ -187,12 +300,15 @@ impl<Config: Default> Default for MigrationConfig<Config> {
 ///
 /// If you are adding a new policy also include a new variant in
 /// [MigrationPolicies] with a short-hand of your policy name to allow the user
-/// to create your policy from the database definition.
+/// to create your policy from the database definition.  Policies outside of
+/// this crate are passed to [Database::build_threaded_with_migration_policy]
+/// instead.
 ///
 /// When implementing a migration policy you can use two types of messages which
 /// are produced. They are divided by user interface and internal tree
 /// representation. These messages are defined in the two message types [DmlMsg] and [DatabaseMsg]
-pub(crate) trait MigrationPolicy {
+/// and can be received from the [MigrationContext] of the policy.
+pub trait MigrationPolicy {
     /// Consume all present messages and update the migration selection
     /// status for all afflicted objects
     fn update(&mut self) -> Result<()>;
@@ -200,6 +316,15 @@ pub(crate) trait MigrationPolicy {
     /// Run any relevant metric logic such as accumulation and writing out data.
     fn metrics(&self) -> Result<()>;
 
+    /// Finish the current round of promotions and demotions.
+    ///
+    /// Policies publish a [MigrationReport] of the migrations performed in
+    /// this round here, or the planned migrations if they support
+    /// [MigrationConfig::dry_run].
+    fn end_round(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Promote any amount of data from the given tier to the next higher one.
     ///
     /// This functions returns how many blocks have been migrated in total. When
@@ -213,77 +338,122 @@ pub(crate) trait MigrationPolicy {
     /// Return a reference to the active [Database].
     fn db(&self) -> &Arc<RwLock<Database>>;
 
-    /// Return a reference to the underlying DML.
-    fn dmu(&self) -> &Arc<RootDmu>;
-
     /// Return the cleaned configuration.
     fn config(&self) -> MigrationConfig<()>;
 
     /// The main loop of the migration policy.
     ///
-    /// We provide a basic default implementation which may be used or discarded
-    /// if desired.
+    /// We provide a basic default implementation which runs a [Self::round]
+    /// every [MigrationConfig::update_period] after the
+    /// [MigrationConfig::grace_period].
     fn thread_loop(&mut self) -> Result<()> {
         std::thread::sleep(self.config().grace_period);
         loop {
             // PAUSE
             std::thread::sleep(self.config().update_period);
-            // Consuming all messages and updating internal state.
-            self.update()?;
+            self.round()?;
+        }
+    }
 
-            use crate::database::StorageInfo;
+    /// Performs a single round of the migration policy.
+    ///
+    /// We provide a basic default implementation which may be used or discarded
+    /// if desired.
+    fn round(&mut self) -> Result<()> {
+        // A fresh bucket holds the bandwidth of one second, which would have
+        // been refilled between rounds of any sensible update period anyway.
+        let throttle = self.config().throttle();
+        let account = |moved: Block<u64>| {
+            if let Some(throttle) = &throttle {
+                throttle.acquire(moved.to_bytes());
+            }
+        };
+        // Consuming all messages and updating internal state.
+        self.update()?;
+        if !self.config().is_active(clock::system_now()) {
+            self.end_round()?;
+            return self.metrics();
+        }
 
-            let threshold: Vec<f32> = self
-                .config()
-                .migration_threshold
-                .iter()
-                .map(|val| val.clamp(0.0, 1.0))
-                .collect();
-            let infos: Vec<(u8, StorageInfo)> = (0u8..NUM_STORAGE_CLASSES as u8)
-                .filter_map(|class| {
-                    self.dmu()
-                        .handler()
-                        .free_space_tier(class)
-                        .map(|blocks| (class, blocks))
-                })
-                .collect();
+        let threshold: Vec<f32> = self
+            .config()
+            .migration_threshold
+            .iter()
+            .map(|val| val.clamp(0.0, 1.0))
+            .collect();
+        if !self.config().dry_run {
+            account(restore_displaced(self.db(), &threshold));
+        }
+        let infos: Vec<(u8, StorageInfo)> = storage_infos(self.db());
 
-            for ((high_tier, high_info), (low_tier, _low_info)) in infos
-                .iter()
-                .tuple_windows()
-                .filter(|(_, (_, low_info))| low_info.total != Block(0))
-            {
-                self.promote(
-                    *low_tier,
-                    high_info.percent_full() >= threshold[*high_tier as usize],
-                )?;
-            }
+        for ((high_tier, high_info), (low_tier, _low_info)) in infos
+            .iter()
+            .tuple_windows()
+            .filter(|(_, (_, low_info))| low_info.total != Block(0))
+        {
+            account(self.promote(
+                *low_tier,
+                high_info.percent_full() >= threshold[*high_tier as usize],
+            )?);
+        }
 
-            // Update after iteration
-            let infos: Vec<(u8, StorageInfo)> = (0u8..NUM_STORAGE_CLASSES as u8)
-                .filter_map(|class| {
-                    self.dmu()
-                        .handler()
-                        .free_space_tier(class)
-                        .map(|blocks| (class, blocks))
-                })
-                .collect();
+        // Update after iteration
+        let infos: Vec<(u8, StorageInfo)> = storage_infos(self.db());
 
-            for ((high_tier, high_info), (_low_tier, _low_info)) in infos
+        for ((high_tier, high_info), (_low_tier, _low_info)) in
+            infos
                 .iter()
                 .tuple_windows()
                 .filter(|((high_tier, high_info), (low_tier, low_info))| {
                     high_info.percent_full() > threshold[*high_tier as usize]
                         && low_info.percent_full() < threshold[*low_tier as usize]
                 })
-            {
-                let desired: Block<u64> = Block(
-                    (high_info.total.as_u64() as f32 * (1.0 - threshold[*high_tier as usize]))
-                        as u64,
-                ) - high_info.free.as_u64();
-                self.demote(*high_tier, desired)?;
-            }
-            self.metrics()?;
+        {
+            let desired: Block<u64> = Block(
+                (high_info.total.as_u64() as f32 * (1.0 - threshold[*high_tier as usize])) as u64,
+            ) - high_info.free.as_u64();
+            account(self.demote(*high_tier, desired)?);
         }
+        self.end_round()?;
+        self.metrics()
     }
 }
+
+/// Hints the nodes displaced from a full storage class back to it, as long as
+/// the class stays below its migration threshold.  Returns the number of
+/// hinted blocks.
+fn restore_displaced(db: &RwLock<Database>, threshold: &[f32]) -> Block<u64> {
+    let db = db.read();
+    let mut budget: Vec<Block<u64>> = db
+        .free_space_tier()
+        .iter()
+        .zip(threshold)
+        .map(|(info, threshold)| info.blocks_until_filled_to(*threshold))
+        .collect();
+    let storage_hints = db.root_tree.dmu().storage_hints();
+    let mut storage_hints = storage_hints.lock();
+    let mut moved = Block(0);
+    for (key, displacement) in db.displaced_nodes() {
+        let (preferred, size) = (displacement.preferred, Block(displacement.size.as_u64()));
+        let budget = &mut budget[preferred.as_u8() as usize];
+        // Nodes hinted in an earlier round are still waiting to be moved.
+        if *budget < size || storage_hints.contains_key(&key) {
+            continue;
+        }
+        *budget = Block(budget.as_u64() - size.as_u64());
+        debug!("Moving displaced node {key:?} back to {preferred:?}");
+        storage_hints.insert(key, preferred);
+        moved += size;
+    }
+    moved
+}
+
+/// Returns the free space of all storage classes in use.
+fn storage_infos(db: &RwLock<Database>) -> Vec<(u8, StorageInfo)> {
+    db.read()
+        .free_space_tier()
+        .into_iter()
+        .enumerate()
+        .map(|(class, info)| (class as u8, info))
+        .collect()
+}
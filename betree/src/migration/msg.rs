@@ -1,4 +1,6 @@
+use super::MigrationPolicies;
 use crate::{
+    clock,
     cow_bytes::CowBytes,
     database::DatasetId,
     object::{ObjectId, ObjectInfo, ObjectStore, ObjectStoreId},
@@ -37,17 +39,24 @@ pub enum DmlMsg {
 
 use serde::Serialize;
 
+/// Identifier of an object which is unique across all object stores.
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct GlobalObjectId(ObjectStoreId, ObjectId);
 
 impl GlobalObjectId {
-    pub fn build(os_id: ObjectStoreId, id: ObjectId) -> Self {
+    pub(crate) fn build(os_id: ObjectStoreId, id: ObjectId) -> Self {
         Self(os_id, id)
     }
 
-    pub(crate) fn store_key(&self) -> &ObjectStoreId {
+    /// Returns the id of the object store containing the object.
+    pub fn store_key(&self) -> &ObjectStoreId {
         &self.0
     }
+
+    /// Returns the id of the object within its object store.
+    pub fn object_id(&self) -> &ObjectId {
+        &self.1
+    }
 }
 
 impl Serialize for GlobalObjectId {
@@ -76,51 +85,61 @@ impl Display for GlobalObjectId {
 #[derive(Clone)]
 pub enum DatabaseMsg {
     // Relevant for Promotion and/or Demotion
+    /// A dataset has been opened.
     DatasetOpen(DatasetId),
+    /// A dataset has been closed.
     DatasetClose(DatasetId),
 
     /// Announce and deliver an accessible copy of active object stores.
     ObjectstoreOpen(ObjectStoreId, ObjectStore),
+    /// An object store has been closed, copies delivered with
+    /// [Self::ObjectstoreOpen] should be dropped.
     ObjectstoreClose(ObjectStoreId),
 
     /// Informs of openend object, adjoint with extra information for access.
     ObjectOpen(GlobalObjectId, ObjectInfo, CowBytes),
     /// Informs of closed object, adjoint with extra information for access.
     ObjectClose(GlobalObjectId, ObjectInfo),
-    /// Frequency information about read and write operations on an object.
-    ObjectRead(GlobalObjectId, Duration),
+    /// An object has been read at the given time, the read took the given
+    /// duration.
+    ObjectRead(GlobalObjectId, SystemTime, Duration),
     /// Report the written storage class with the new size of the object.
     ObjectWrite(GlobalObjectId, u64, StoragePreference, Duration),
     /// Notification if a manual migration took place.
     ObjectMigrate(GlobalObjectId, StoragePreference),
     /// Notification similar to [Self::ObjectOpen] but with different semantics.
     ObjectDiscover(GlobalObjectId, ObjectInfo, CowBytes),
+
+    /// The configuration of the running policy has been changed with
+    /// [Database::update_config](crate::Database::update_config).  Policies
+    /// adopt the configuration of their own variant and ignore the others.
+    Reconfigure(MigrationPolicies),
 }
 
 impl DmlMsg {
-    pub fn fetch(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
+    pub(crate) fn fetch(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
         Self::Fetch(OpInfo {
             offset,
             size,
-            time: SystemTime::now(),
+            time: clock::system_now(),
             pivot_key,
         })
     }
 
-    pub fn write(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
+    pub(crate) fn write(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
         Self::Write(OpInfo {
             offset,
             size,
-            time: SystemTime::now(),
+            time: clock::system_now(),
             pivot_key,
         })
     }
 
-    pub fn remove(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
+    pub(crate) fn remove(offset: DiskOffset, size: Block<u32>, pivot_key: PivotKey) -> Self {
         Self::Remove(OpInfo {
             offset,
             size,
-            time: SystemTime::now(),
+            time: clock::system_now(),
             pivot_key,
         })
     }
@@ -209,3 +228,25 @@ pub struct OpInfo {
     /// The time at which an operation has occurred.
     pub(crate) time: SystemTime,
 }
+
+impl OpInfo {
+    /// Returns the location of the node on disk.
+    pub fn offset(&self) -> DiskOffset {
+        self.offset
+    }
+
+    /// Returns the key identifying the node across copy on write.
+    pub fn pivot_key(&self) -> &PivotKey {
+        &self.pivot_key
+    }
+
+    /// Returns the size of the node.
+    pub fn size(&self) -> Block<u32> {
+        self.size
+    }
+
+    /// Returns the time at which the operation has occurred.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+}
@@ -0,0 +1,83 @@
+//! Migrations planned by a policy in a dry run.
+//!
+//! With [MigrationConfig::dry_run](super::MigrationConfig) set, policies
+//! decide on migrations as usual but only record them.  The migrations of the
+//! latest round are available as a [MigrationPlan] from
+//! [Database::migration_plan](crate::Database::migration_plan).
+use crate::{cow_bytes::CowBytes, database::DatasetId, storage_pool::NUM_STORAGE_CLASSES};
+use serde::Serialize;
+
+/// The data moved by a [PlannedMigration].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum MigrationSubject {
+    /// The object `name` of the object store `store`.
+    Object {
+        /// The id of the object store.
+        store: DatasetId,
+        /// The name of the object.
+        name: CowBytes,
+    },
+    /// A node of the tree of the dataset `dataset`.
+    Node {
+        /// The id of the dataset.
+        dataset: DatasetId,
+    },
+    /// The entry `key` of the dataset `dataset`.
+    Key {
+        /// The id of the dataset.
+        dataset: DatasetId,
+        /// The key of the entry.
+        key: CowBytes,
+    },
+}
+
+/// A single migration a policy would perform, or has performed if it is part
+/// of a [MigrationReport](super::MigrationReport).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedMigration {
+    /// What would be moved.
+    pub subject: MigrationSubject,
+    /// The storage class the data is on.
+    pub from: u8,
+    /// The storage class the data would be moved to.
+    pub to: u8,
+    /// The size of the data in bytes.
+    pub size: u64,
+}
+
+/// The migrations a policy would perform in one round, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MigrationPlan {
+    /// The planned migrations.
+    pub migrations: Vec<PlannedMigration>,
+}
+
+impl MigrationPlan {
+    /// Returns the number of bytes which would be moved out of each storage
+    /// class.
+    pub fn bytes_out(&self) -> [u64; NUM_STORAGE_CLASSES] {
+        bytes_out(&self.migrations)
+    }
+
+    /// Returns the number of bytes which would be moved into each storage
+    /// class.
+    pub fn bytes_in(&self) -> [u64; NUM_STORAGE_CLASSES] {
+        bytes_in(&self.migrations)
+    }
+}
+
+pub(super) fn bytes_out(migrations: &[PlannedMigration]) -> [u64; NUM_STORAGE_CLASSES] {
+    let mut bytes = [0; NUM_STORAGE_CLASSES];
+    for migration in migrations {
+        bytes[migration.from as usize] += migration.size;
+    }
+    bytes
+}
+
+pub(super) fn bytes_in(migrations: &[PlannedMigration]) -> [u64; NUM_STORAGE_CLASSES] {
+    let mut bytes = [0; NUM_STORAGE_CLASSES];
+    for migration in migrations {
+        bytes[migration.to as usize] += migration.size;
+    }
+    bytes
+}
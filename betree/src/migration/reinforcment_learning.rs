@@ -2,6 +2,7 @@ use crossbeam_channel::Receiver;
 use parking_lot::RwLock;
 
 use crate::{
+    clock,
     cow_bytes::CowBytes,
     data_management::{DmlWithHandler, DmlWithStorageHints},
     database::{RootDmu, StorageInfo},
@@ -10,14 +11,12 @@ use crate::{
     Database, StoragePreference,
 };
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    io::Write,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::HashMap, io::Write, sync::Arc, time::UNIX_EPOCH};
 
-use super::{DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig, MigrationPolicy};
+use super::{
+    DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig, MigrationPolicies, MigrationPolicy,
+    MigrationReport, MigrationSource, MigrationSubject, PlannedMigration,
+};
 // This file contains a migration policy based on reinforcement learning.
 // We based our approach on the description of
 // https://doi.org/10.1109/TKDE.2022.3176753 and aim to use them for object
@@ -120,7 +119,7 @@ mod learning {
                 FileProperties {
                     hotness: Hotness(self.rng.gen_range(0.0..1.0)),
                     size: Size(size),
-                    last_access: Instant::now(),
+                    last_access: crate::clock::now(),
                 },
             );
         }
@@ -170,7 +169,7 @@ mod learning {
         }
 
         pub fn msg(&mut self, key: GlobalObjectId, dur: Duration) {
-            let time = Instant::now();
+            let time = crate::clock::now();
             if let Some(elem) = self.files.get_mut(&key) {
                 elem.last_access = time;
             }
@@ -255,7 +254,7 @@ mod learning {
         // }
 
         pub fn temp_update(&mut self) {
-            let time = Instant::now();
+            let time = crate::clock::now();
             for (_key, tup) in self.files.iter_mut() {
                 tup.hotness = Hotness(
                     self.alpha * tup.hotness.0
@@ -644,7 +643,7 @@ impl DatabaseState {
     ) -> super::errors::Result<()> {
         let os = self.get_or_open_object_store(obj_id.store_key());
         let tier_id = target.as_u8() as usize;
-        let mut obj = os.act.open_object(obj_key)?.unwrap();
+        let (mut obj, _) = os.act.read_object_handle_with_info(obj_key)?.unwrap();
         let start = std::time::Instant::now();
         obj.migrate(target)?;
         debug!("Migrating object took {} ms", start.elapsed().as_millis());
@@ -963,6 +962,37 @@ impl ZhangHellanderToor {
         Ok(())
     }
 
+    /// Publishes the migrations of the latest timestep.
+    fn report(&self, duration: std::time::Duration) {
+        let migrations: Vec<_> = self
+            .delta_moved
+            .iter()
+            .filter_map(|(id, size, from, to)| {
+                Some(PlannedMigration {
+                    subject: MigrationSubject::Object {
+                        store: *id.store_key(),
+                        name: self.objects.get(id)?.key.clone(),
+                    },
+                    from: *from,
+                    to: *to,
+                    size: *size,
+                })
+            })
+            .collect();
+        if !migrations.is_empty() {
+            self.state
+                .db
+                .read()
+                .migration_reports
+                .publish(MigrationReport {
+                    source: MigrationSource::Policy,
+                    migrations,
+                    failures: Vec::new(),
+                    duration,
+                });
+        }
+    }
+
     fn cleanup(&mut self) {
         // Clean-up
         for tier in self.tiers.iter_mut() {
@@ -982,6 +1012,11 @@ impl ZhangHellanderToor {
     ) -> Self {
         // We do not provide single node hints in this policy
         let dmu = Arc::clone(db.read().root_tree.dmu());
+        if config.dry_run {
+            // The evictions of a timestep depend on the space freed by the
+            // previous ones, which cannot be simulated without migrating.
+            warn!("Dry runs are not supported by the reinforcement learning policy, no migrations will be planned or performed.");
+        }
         let active_storage_classes = db
             .read()
             .free_space_tier()
@@ -1082,7 +1117,7 @@ impl MigrationPolicy for ZhangHellanderToor {
                     }
                 }
                 DatabaseMsg::ObjectClose(_, _) => {}
-                DatabaseMsg::ObjectRead(key, dur) => {
+                DatabaseMsg::ObjectRead(key, _, dur) => {
                     let obj_info = self.objects.get_mut(&key).unwrap();
                     obj_info.reqs.push(learning::Request::new(dur));
                     let pref = obj_info.storage_lvl().or(self.default_storage_class);
@@ -1105,6 +1140,10 @@ impl MigrationPolicy for ZhangHellanderToor {
                         .update_size(&key, size);
                     self.tiers[pref.as_u8() as usize].tier.msg(key, dur);
                 }
+                DatabaseMsg::Reconfigure(MigrationPolicies::ReinforcementLearning(config)) => {
+                    self.config = config;
+                }
+                DatabaseMsg::Reconfigure(_) => {}
                 DatabaseMsg::ObjectMigrate(key, pref) => {
                     let prev = self.objects.get_mut(&key).unwrap();
                     if pref != prev.pref {
@@ -1142,31 +1181,34 @@ impl MigrationPolicy for ZhangHellanderToor {
         unimplemented!()
     }
 
-    fn thread_loop(&mut self) -> super::errors::Result<()> {
-        std::thread::sleep(self.config.grace_period);
-        loop {
-            std::thread::sleep(self.config.update_period);
-            let start = std::time::Instant::now();
-            debug!("Update");
-            self.update()?;
+    fn round(&mut self) -> super::errors::Result<()> {
+        let throttle = self.config.throttle();
+        let start = clock::now();
+        debug!("Update");
+        self.update()?;
+        if !self.config.dry_run && self.config.is_active(clock::system_now()) {
+            let restored =
+                super::restore_displaced(&self.state.db, &self.config.migration_threshold);
             debug!("Timestep");
             self.timestep()?;
-            debug!("Metrics");
-            self.metrics()?;
-            debug!("Cleanup");
-            self.cleanup();
-            debug!("Iteration took {} ms", start.elapsed().as_millis());
+            if let Some(throttle) = &throttle {
+                let moved: u64 = self.delta_moved.iter().map(|(_, size, ..)| size).sum();
+                throttle.acquire(restored.to_bytes() + moved);
+            }
+            self.report(clock::elapsed(start));
         }
+        debug!("Metrics");
+        self.metrics()?;
+        debug!("Cleanup");
+        self.cleanup();
+        debug!("Iteration took {} ms", clock::elapsed(start).as_millis());
+        Ok(())
     }
 
     fn db(&self) -> &std::sync::Arc<parking_lot::RwLock<crate::Database>> {
         &self.state.db
     }
 
-    fn dmu(&self) -> &std::sync::Arc<RootDmu> {
-        &self.state.dmu
-    }
-
     fn config(&self) -> super::MigrationConfig<()> {
         self.config.clone().erased()
     }
@@ -1192,7 +1234,7 @@ impl MigrationPolicy for ZhangHellanderToor {
             total_file.write_all(b"\n")?;
             // Write delta
             //
-            let time = SystemTime::now()
+            let time = clock::system_now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
@@ -0,0 +1,100 @@
+//! Reports about the outcome of migrations.
+//!
+//! Receivers returned by
+//! [Database::subscribe_migrations](crate::Database::subscribe_migrations) get
+//! a [MigrationReport] for each call of
+//! [Dataset::migrate_range](crate::database::Dataset::migrate_range) and for
+//! each round of an automatic migration policy which performed any migration.
+use super::plan::{self, MigrationSubject, PlannedMigration};
+use crate::{
+    database::{Event, EventHandlers},
+    storage_pool::NUM_STORAGE_CLASSES,
+};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+/// What triggered the migrations of a [MigrationReport].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MigrationSource {
+    /// An explicit migration of a dataset range, which includes migrations of
+    /// objects with [crate::object::ObjectHandle::migrate].
+    Explicit,
+    /// A round of the automatic migration policy.
+    Policy,
+}
+
+/// A migration which could not be performed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationFailure {
+    /// What should have been moved.
+    pub subject: MigrationSubject,
+    /// The storage class the data should have been moved to.
+    pub to: u8,
+    /// Description of the error.
+    pub error: String,
+}
+
+/// The outcome of a range migration or of a round of a migration policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationReport {
+    /// What triggered the migrations.
+    pub source: MigrationSource,
+    /// The performed migrations, in order.
+    ///
+    /// Migrations of nodes are applied lazily, they are reported once they
+    /// have been hinted to the storage stack.
+    pub migrations: Vec<PlannedMigration>,
+    /// The migrations which failed.
+    pub failures: Vec<MigrationFailure>,
+    /// How long the migrations took.
+    pub duration: Duration,
+}
+
+impl MigrationReport {
+    /// Returns the number of bytes moved out of each storage class.
+    pub fn bytes_out(&self) -> [u64; NUM_STORAGE_CLASSES] {
+        plan::bytes_out(&self.migrations)
+    }
+
+    /// Returns the number of bytes moved into each storage class.
+    pub fn bytes_in(&self) -> [u64; NUM_STORAGE_CLASSES] {
+        plan::bytes_in(&self.migrations)
+    }
+}
+
+/// Delivers reports to all subscribers and event handlers.  Subscribers which
+/// dropped their receiver are removed on the next report.
+pub(crate) struct MigrationReports {
+    subscribers: Mutex<Vec<Sender<MigrationReport>>>,
+    events: Arc<EventHandlers>,
+}
+
+impl MigrationReports {
+    pub(crate) fn new(events: Arc<EventHandlers>) -> Self {
+        MigrationReports {
+            subscribers: Mutex::new(Vec::new()),
+            events,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<MigrationReport> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Returns whether reports are delivered to anyone, so that collecting
+    /// them can be skipped otherwise.
+    pub(crate) fn is_subscribed(&self) -> bool {
+        !self.subscribers.lock().is_empty() || !self.events.is_empty()
+    }
+
+    pub(crate) fn publish(&self, report: MigrationReport) {
+        self.subscribers
+            .lock()
+            .retain(|tx| tx.send(report.clone()).is_ok());
+        self.events.emit(|| Event::Migration(report));
+    }
+}
@@ -0,0 +1,75 @@
+//! Daily time windows in which migrations are performed.
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A daily window in which a migration policy may migrate data.
+///
+/// Both bounds are offsets from midnight UTC.  If `end` is before `start` the
+/// window spans midnight, e.g. a window from 20:00 to 06:00 covers the night.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationWindow {
+    /// Start of the window, inclusive.
+    pub start: Duration,
+    /// End of the window, exclusive.
+    pub end: Duration,
+}
+
+impl MigrationWindow {
+    /// Returns whether the given time of the day lies within this window.
+    pub fn contains(&self, time_of_day: Duration) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            self.start <= time_of_day || time_of_day < self.end
+        }
+    }
+}
+
+/// Returns the time passed since midnight UTC.
+pub(super) fn time_of_day(now: SystemTime) -> Duration {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Duration::from_nanos((since_epoch.as_nanos() % DAY.as_nanos()) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{time_of_day, MigrationWindow, DAY};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn hours(h: u64) -> Duration {
+        Duration::from_secs(h * 60 * 60)
+    }
+
+    #[test]
+    fn window_within_day() {
+        let window = MigrationWindow {
+            start: hours(8),
+            end: hours(18),
+        };
+        assert!(!window.contains(hours(7)));
+        assert!(window.contains(hours(8)));
+        assert!(window.contains(hours(12)));
+        assert!(!window.contains(hours(18)));
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let window = MigrationWindow {
+            start: hours(20),
+            end: hours(6),
+        };
+        assert!(window.contains(hours(22)));
+        assert!(window.contains(hours(0)));
+        assert!(window.contains(hours(5)));
+        assert!(!window.contains(hours(6)));
+        assert!(!window.contains(hours(12)));
+    }
+
+    #[test]
+    fn time_of_day_wraps() {
+        let now = UNIX_EPOCH + DAY * 3 + hours(5);
+        assert_eq!(time_of_day(now), hours(5));
+    }
+}
@@ -1,7 +1,7 @@
 // let Ok(n) | Err((n, _)) = res; is not stable yet, so use if-let for now
 #![allow(irrefutable_let_patterns)]
 
-use super::ObjectHandle;
+use super::{chunk::CHUNK_SIZE, ObjectHandle};
 use crate::{database::Error as DbError, StoragePreference};
 
 use std::io::{self, Read, Seek, SeekFrom, Write};
@@ -39,6 +39,127 @@ impl<'handle, 'r> ObjectCursor<'handle, 'r> {
     pub fn set_storage_preference(&mut self, pref: StoragePreference) {
         self.pref = pref;
     }
+
+    /// Wrap this cursor into a [BufferedObjectCursor] at the same position.
+    pub fn buffered(self) -> BufferedObjectCursor<'handle, 'r> {
+        BufferedObjectCursor {
+            cursor: self,
+            buf_start: 0,
+            buf: Vec::new(),
+            dirty: false,
+        }
+    }
+}
+
+/// A buffered variant of [ObjectCursor], which gathers writes and serves reads in chunk-aligned
+/// pieces, avoiding a tree operation for every small access.
+///
+/// Buffered writes are flushed when the cursor is moved away from them, on [Write::flush], and
+/// when the cursor is dropped, where errors are ignored. Reads are not invalidated by writes
+/// through other handles.
+pub struct BufferedObjectCursor<'handle, 'r> {
+    cursor: ObjectCursor<'handle, 'r>,
+    // Object offset of the first byte in `buf`.
+    buf_start: u64,
+    buf: Vec<u8>,
+    // Whether `buf` holds data which has not been written yet, otherwise it
+    // holds data read from the object.
+    dirty: bool,
+}
+
+fn chunk_end(offset: u64) -> u64 {
+    (offset / CHUNK_SIZE as u64 + 1) * CHUNK_SIZE as u64
+}
+
+impl<'handle, 'r> BufferedObjectCursor<'handle, 'r> {
+    /// Override the storage preference to use for future writes with this cursor.
+    pub fn set_storage_preference(&mut self, pref: StoragePreference) {
+        self.cursor.set_storage_preference(pref);
+    }
+
+    fn buf_end(&self) -> u64 {
+        self.buf_start + self.buf.len() as u64
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.dirty = false;
+            let res =
+                self.cursor
+                    .handle
+                    .write_at_with_pref(&self.buf, self.buf_start, self.cursor.pref);
+            self.buf.clear();
+            convert_res(res)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> Read for BufferedObjectCursor<'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush_buf()?;
+        let pos = self.cursor.pos;
+        if pos < self.buf_start || pos >= self.buf_end() {
+            let chunk_start = pos - pos % CHUNK_SIZE as u64;
+            self.buf.resize(CHUNK_SIZE as usize, 0);
+            self.buf_start = chunk_start;
+            let res = self.cursor.handle.read_at(&mut self.buf, chunk_start);
+            if let Ok(n) | Err((n, _)) = res {
+                self.buf.truncate(n as usize);
+            }
+            convert_res(res)?;
+        }
+
+        // The refilled chunk ends before `pos` if it lies past the end of the
+        // object.
+        let available = self.buf_end().saturating_sub(pos) as usize;
+        if available == 0 {
+            return Ok(0);
+        }
+        let n = available.min(buf.len());
+        let from = (pos - self.buf_start) as usize;
+        buf[..n].copy_from_slice(&self.buf[from..from + n]);
+        self.cursor.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, 'b> Write for BufferedObjectCursor<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.cursor.pos;
+        if !self.dirty || pos != self.buf_end() {
+            self.flush_buf()?;
+            self.buf.clear();
+            self.buf_start = pos;
+            self.dirty = true;
+        }
+
+        let room = (chunk_end(self.buf_start) - self.buf_end()) as usize;
+        let n = room.min(buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        self.cursor.pos += n as u64;
+        if self.buf_end() == chunk_end(self.buf_start) {
+            self.flush_buf()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()
+    }
+}
+
+impl<'a, 'b> Seek for BufferedObjectCursor<'a, 'b> {
+    fn seek(&mut self, target: SeekFrom) -> io::Result<u64> {
+        self.flush_buf()?;
+        self.cursor.seek(target)
+    }
+}
+
+impl<'a, 'b> Drop for BufferedObjectCursor<'a, 'b> {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
 }
 
 fn convert_res(db_res: Result<u64, (u64, DbError)>) -> io::Result<usize> {
@@ -3,6 +3,7 @@ use speedy::{Endianness, Readable, Writable};
 
 use crate::{
     cow_bytes::{CowBytes, SlicedCowBytes},
+    database::Generation,
     object::ObjectId,
     tree::MessageAction,
     PreferredAccessType, StoragePreference,
@@ -27,14 +28,20 @@ pub struct ObjectInfo {
     pub size: u64,
     /// Timestamp of the last modification to the object.
     pub mtime: SystemTime,
-    /// Most recently used storage preference. Can be used for reinitialization.
+    /// Most recently used storage preference. Handles opened without an explicit preference
+    /// continue to use it.
     pub pref: StoragePreference,
     /// The specified access pattern hint.
     pub access_pattern: PreferredAccessType,
+    /// Generation of the last modification to the object.  Objects written
+    /// before it was recorded report generation 0.
+    #[speedy(default_on_eof)]
+    pub generation: Generation,
 }
 
 /// Every message represents an overwrite or merge of a set of [ObjectInfo] properties.
-/// `size` and `mtime` are merged with `max`, whereas `object_id` is just overwritten.
+/// `size`, `mtime` and `generation` are merged with `max`, whereas `object_id` is just
+/// overwritten.
 ///
 /// The `max` merge is required to allow concurrent writes of mutually ignorant clients
 /// without writing over a larger `size` message.
@@ -45,6 +52,7 @@ pub(super) struct MetaMessage {
     pub(super) mtime: Option<SystemTime>,
     pub(super) pref: Option<StoragePreference>,
     pub(super) access_pattern: Option<PreferredAccessType>,
+    pub(super) generation: Option<Generation>,
 }
 
 const CONTENT_FLAG_NONE: u8 = MetaMessage::delete().to_content_flags();
@@ -54,6 +62,7 @@ const CONTENT_FLAG_ALL: u8 = (MetaMessage {
     mtime: Some(UNIX_EPOCH),
     pref: Some(StoragePreference::NONE),
     access_pattern: Some(PreferredAccessType::Unknown),
+    generation: Some(Generation::from_u64(0)),
 })
 .to_content_flags();
 
@@ -66,6 +75,7 @@ impl MetaMessage {
         mtime: Option<SystemTime>,
         pref: Option<StoragePreference>,
         access_pattern: Option<PreferredAccessType>,
+        generation: Option<Generation>,
     ) -> Self {
         MetaMessage {
             object_id,
@@ -73,11 +83,12 @@ impl MetaMessage {
             mtime,
             pref,
             access_pattern,
+            generation,
         }
     }
 
     pub const fn delete() -> MetaMessage {
-        MetaMessage::new(None, None, None, None, None)
+        MetaMessage::new(None, None, None, None, None, None)
     }
 
     pub fn set_info(info: &ObjectInfo) -> MetaMessage {
@@ -87,6 +98,7 @@ impl MetaMessage {
             Some(info.mtime),
             Some(info.pref),
             Some(info.access_pattern),
+            Some(info.generation),
         )
     }
 
@@ -99,6 +111,7 @@ impl MetaMessage {
             | (if self.mtime.is_some() { 4 } else { 0 })
             | (if self.pref.is_some() { 8 } else { 0 })
             | (if self.access_pattern.is_some() { 16 } else { 0 })
+            | (if self.generation.is_some() { 32 } else { 0 })
     }
 
     const fn encoded_length(&self) -> usize {
@@ -107,6 +120,7 @@ impl MetaMessage {
             + (if self.mtime.is_some() { 8 } else { 0 })
             + (if self.pref.is_some() { 1 } else { 0 })
             + (if self.access_pattern.is_some() { 16 } else { 0 })
+            + (if self.generation.is_some() { 8 } else { 0 })
     }
 
     pub(crate) fn pack(&self) -> CowBytes {
@@ -137,6 +151,9 @@ impl MetaMessage {
         if let Some(ap) = self.access_pattern {
             let _ = v.write_u8(ap.as_u8());
         }
+        if let Some(generation) = self.generation {
+            let _ = v.write_u64::<LittleEndian>(generation.as_u64());
+        }
 
         CowBytes::from(v)
     }
@@ -164,6 +181,9 @@ impl MetaMessage {
             message.access_pattern =
                 Some(PreferredAccessType::try_from(cursor.read_u8()?).unwrap_or_default());
         }
+        if content_flags & 32 != 0 {
+            message.generation = Some(Generation::from_u64(cursor.read_u64::<LittleEndian>()?));
+        }
 
         Ok(message)
     }
@@ -183,7 +203,7 @@ impl MetaMessage {
 /// result of write operations without a read-modify-write cycle:
 ///
 /// - the object id is replaced by the upper message if both messages have an id
-/// - size and mtime are merged with max if both messages have the respective field,
+/// - size, mtime and generation are merged with max if both messages have the respective field,
 ///   to avoid "resetting" back to an earlier value with concurrent writes.
 ///
 /// A custom entry is user-specified per-object key-value metadata. As they are stored inline with
@@ -215,6 +235,29 @@ pub(super) fn set_custom(value: &[u8]) -> CowBytes {
     v.into()
 }
 
+impl MetaMessageAction {
+    /// Returns a message which replaces the entry of `key` with `value`,
+    /// which has been read from a meta dataset.
+    pub(crate) fn replace_msg(key: &[u8], value: &[u8]) -> io::Result<SlicedCowBytes> {
+        if is_fixed_key(key) {
+            let info = ObjectInfo::read_from_buffer_with_ctx(ENDIAN, value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(MetaMessage::set_info(&info).pack().into())
+        } else {
+            Ok(set_custom(value).into())
+        }
+    }
+
+    /// Returns a message which deletes the entry of `key`.
+    pub(crate) fn delete_msg(key: &[u8]) -> SlicedCowBytes {
+        if is_fixed_key(key) {
+            MetaMessage::delete().pack().into()
+        } else {
+            delete_custom().into()
+        }
+    }
+}
+
 impl MessageAction for MetaMessageAction {
     fn apply(&self, key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
         if is_fixed_key(key) {
@@ -227,14 +270,17 @@ impl MessageAction for MetaMessageAction {
                     mtime: Some(mtime),
                     pref: Some(pref),
                     access_pattern: Some(access_pattern),
+                    generation,
                 } => {
-                    // message overwrites entirely, don't bother unpacking existing data
+                    // message overwrites entirely, don't bother unpacking existing data,
+                    // messages written before the generation was recorded lack it
                     let info = ObjectInfo {
                         object_id,
                         size,
                         mtime,
                         pref,
                         access_pattern,
+                        generation: generation.unwrap_or_default(),
                     };
                     *data =
                         Some(CowBytes::from(info.write_to_vec_with_ctx(ENDIAN).unwrap()).into());
@@ -245,6 +291,7 @@ impl MessageAction for MetaMessageAction {
                     mtime: None,
                     pref: None,
                     access_pattern: None,
+                    generation: None,
                 } => {
                     // message deletes entirely
                     *data = None;
@@ -255,6 +302,7 @@ impl MessageAction for MetaMessageAction {
                     mtime,
                     pref,
                     access_pattern,
+                    generation,
                 } => {
                     if let Some(d) = data {
                         let mut info = ObjectInfo::read_from_buffer_with_ctx(ENDIAN, d).unwrap();
@@ -274,6 +322,9 @@ impl MessageAction for MetaMessageAction {
                         if let Some(access_pattern) = access_pattern {
                             info.access_pattern = access_pattern;
                         }
+                        if let Some(generation) = generation {
+                            info.generation = generation;
+                        }
 
                         *data = Some(
                             CowBytes::from(info.write_to_vec_with_ctx(ENDIAN).unwrap()).into(),
@@ -323,6 +374,7 @@ impl MessageAction for MetaMessageAction {
                         // Prefer newer if set
                         pref: upper.pref.or(lower.pref),
                         access_pattern: upper.access_pattern.or(lower.access_pattern),
+                        generation: or_max(upper.generation, lower.generation),
                     };
                     new.pack().into()
                 }
@@ -8,8 +8,10 @@
 //! ### Keys
 //!
 //! ```text
-//! [key] -> ObjectInfo, containing object id in data tree, mtime, and current object size in bytes
+//! [key] -> ObjectInfo, containing object id in data tree, mtime, generation of the last write, and
+//! current object size in bytes
 //! [key][0][custom byte key] -> [custom byte value]
+//! [0xFF][namespace][0xFF][key] -> ObjectInfo of a namespaced object, see [ObjectNamespace]
 //! ```
 //!
 //! ## Data tree
@@ -46,6 +48,7 @@
 
 #![allow(missing_docs)]
 use crate::{
+    clock,
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::Dml,
     database::root_tree_msg::{
@@ -68,25 +71,75 @@ use std::{
     convert::TryInto,
     fmt::Display,
     mem,
-    ops::{Range, RangeBounds},
+    ops::{Bound, Range, RangeBounds},
     result,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::{Instant, SystemTime},
 };
 
 mod chunk;
 mod meta;
 use self::{chunk::*, meta::*};
+pub(crate) use meta::MetaMessageAction;
 pub use meta::ObjectInfo;
 
 mod cursor;
-pub use cursor::ObjectCursor;
+mod multipart;
+mod namespace;
+pub use cursor::{BufferedObjectCursor, ObjectCursor};
+pub use multipart::MultipartUpload;
+pub use namespace::{ObjectNamespace, NAMESPACE_MARKER};
 
 const OBJECT_ID_COUNTER_KEY: &[u8] = b"\0oid";
 
+/// The maximum size of a single attribute value in bytes. Attributes are
+/// stored inline with the object metadata and should be kept small.
+pub const MAX_ATTRIBUTE_SIZE: usize = 4096;
+
+// Returns the smallest key greater than all keys starting with `prefix`, or
+// `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Matches `name` against a glob `pattern` supporting `*` and `?`.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern and the name position it
+    // was tried at, to backtrack on mismatch.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
 use serde::Serialize;
 
 #[derive(Debug, Clone, Copy, Readable, Writable, PartialEq, Eq, Hash, Serialize)]
@@ -106,6 +159,43 @@ impl Display for ObjectId {
     }
 }
 
+/// A contiguous byte range of an object, as reported by
+/// [ObjectHandle::read_extents].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extent {
+    /// The range is backed by stored data.
+    Data(Range<u64>),
+    /// The range has never been written and reads as zeroes.
+    Hole(Range<u64>),
+}
+
+impl Extent {
+    /// The byte range covered by this extent.
+    pub fn range(&self) -> &Range<u64> {
+        match self {
+            Extent::Data(range) | Extent::Hole(range) => range,
+        }
+    }
+
+    // Appends `extent` to `extents`, merging it into the last extent if both
+    // are of the same kind and adjacent.
+    fn push(extents: &mut Vec<Extent>, extent: Extent) {
+        if let Some(last) = extents.last_mut() {
+            match (last, &extent) {
+                (Extent::Data(last), Extent::Data(next))
+                | (Extent::Hole(last), Extent::Hole(next))
+                    if last.end == next.start =>
+                {
+                    last.end = next.end;
+                    return;
+                }
+                _ => {}
+            }
+        }
+        extents.push(extent);
+    }
+}
+
 fn object_chunk_key(ObjectId(object_id): ObjectId, chunk_id: u32) -> [u8; 8 + 4] {
     let mut b = [0; 8 + 4];
     let (object, chunk) = b.split_at_mut(8);
@@ -120,6 +210,16 @@ fn decode_object_chunk_key(key: &[u8; 8 + 4]) -> (ObjectId, u32) {
     (ObjectId(id), offset)
 }
 
+fn check_attr_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(Error::InvalidArgument("attribute names may not be empty"));
+    }
+    if name.contains('\0') {
+        return Err(Error::KeyContainsNullByte);
+    }
+    Ok(())
+}
+
 /// An object store which can be shared with multiple threads by cloning.  After
 /// the first instance is closed all others are blocked from access. This is
 /// useful in situations where independent asynchronously running threads need
@@ -224,6 +324,37 @@ impl Database {
             .map(|buf| ObjectStoreData::unpack(&buf)))
     }
 
+    /// Returns the names of all object stores with the ids of their data and
+    /// meta datasets.  The store opened by [Database::open_object_store] is
+    /// named `[0]`.
+    pub(crate) fn object_stores(&self) -> Result<Vec<(CowBytes, DatasetId, DatasetId)>> {
+        let low = &[OBJECT_STORE_NAME_TO_ID_PREFIX] as &[_];
+        let high = &[OBJECT_STORE_NAME_TO_ID_PREFIX + 1] as &[_];
+        let mut stores = Vec::new();
+        for result in self.root_tree.range(low..high)? {
+            let (key, value) = result?;
+            let mut data_key = vec![OBJECT_STORE_DATA_PREFIX];
+            data_key.extend_from_slice(&value);
+            if let Some(data) = self.root_tree.get(data_key)? {
+                let data = ObjectStoreData::unpack(&data);
+                stores.push((CowBytes::from(&key[1..]), data.data, data.meta));
+            }
+        }
+        Ok(stores)
+    }
+
+    /// Registers the object store `name` backed by the datasets `data` and
+    /// `meta`, e.g. when restoring a backup.
+    pub(crate) fn register_object_store(
+        &mut self,
+        name: &[u8],
+        data: DatasetId,
+        meta: DatasetId,
+    ) -> Result<()> {
+        let id = self.get_or_create_os_id(name)?;
+        self.store_os_data(id, ObjectStoreData { data, meta })
+    }
+
     /// For tests only: Exposed version of [object_object_store_with_id].
     #[cfg(feature = "internal-api")]
     pub fn internal_open_object_store_with_id(
@@ -245,10 +376,7 @@ impl Database {
         &mut self,
         os_id: ObjectStoreId,
     ) -> Result<ObjectStore> {
-        let store = self
-            .fetch_os_data(&os_id)?
-            .map(Ok::<ObjectStoreData, crate::database::errors::Error>)
-            .unwrap_or_else(|| bail!(Error::DoesNotExist))?;
+        let store = self.fetch_os_data(&os_id)?.ok_or(Error::DoesNotExist)?;
 
         ObjectStore::with_datasets(
             os_id,
@@ -386,6 +514,11 @@ impl<'os> ObjectStore {
         Ok(store)
     }
 
+    /// Returns the ids of the data and metadata datasets of this store.
+    pub(crate) fn dataset_ids(&self) -> [DatasetId; 2] {
+        [self.data.id(), self.metadata.id()]
+    }
+
     /// Return an iterator overall object names and metadata in this object store.
     pub fn iter_objects(&self) -> Result<impl Iterator<Item = (CowBytes, ObjectInfo)>> {
         // Iterate over the metadata and create tuples of object keys and ids.
@@ -401,6 +534,63 @@ impl<'os> ObjectStore {
             }))
     }
 
+    /// Return an iterator over the names and metadata of all objects whose
+    /// names start with `prefix`, in lexicographic order.
+    ///
+    /// Objects of namespaces are not listed, see [ObjectNamespace::list_objects].
+    pub fn list_objects_with_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = Result<(CowBytes, ObjectInfo)>>> {
+        let end = match prefix_end(prefix) {
+            Some(end) if !namespace::is_namespaced(&end) => end,
+            _ => vec![NAMESPACE_MARKER],
+        };
+        let start = prefix.min(&end[..]).to_vec();
+        self.list_objects_in(start, Bound::Excluded(end))
+    }
+
+    // Lists the objects whose names are within `start..end`, regardless of
+    // namespaces.
+    fn list_objects_in(
+        &self,
+        start: Vec<u8>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<impl Iterator<Item = Result<(CowBytes, ObjectInfo)>>> {
+        Ok(self
+            .metadata
+            .range((Bound::Included(start), end))?
+            .filter(|res| !matches!(res, Ok((key, _)) if !meta::is_fixed_key(key)))
+            .map(|res| {
+                let (k, v) = res?;
+                Ok((
+                    k,
+                    ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, &v).unwrap(),
+                ))
+            }))
+    }
+
+    /// Return an iterator over the names and metadata of all objects whose
+    /// names match the glob `pattern`. `*` matches any sequence of bytes and
+    /// `?` matches a single byte, only the part of the tree covered by the
+    /// literal prefix of `pattern` is scanned.
+    pub fn list_objects_matching(
+        &self,
+        pattern: &[u8],
+    ) -> Result<impl Iterator<Item = Result<(CowBytes, ObjectInfo)>>> {
+        let literal = pattern
+            .iter()
+            .position(|b| *b == b'*' || *b == b'?')
+            .unwrap_or(pattern.len());
+        let pattern = pattern.to_vec();
+        Ok(self
+            .list_objects_with_prefix(&pattern[..literal])?
+            .filter(move |res| match res {
+                Ok((key, _)) => glob_match(&pattern, key),
+                Err(_) => true,
+            }))
+    }
+
     /// Create a new object handle and fit storage location to expected access
     /// pattern.
     pub fn create_object_with_access_type(
@@ -408,6 +598,7 @@ impl<'os> ObjectStore {
         key: &[u8],
         access_type: PreferredAccessType,
     ) -> Result<(ObjectHandle<'os>, ObjectInfo)> {
+        namespace::check_unnamespaced(key)?;
         let pref = self
             .data
             .call_tree(|t| t.dmu().spl().access_type_preference(access_type));
@@ -420,6 +611,7 @@ impl<'os> ObjectStore {
         key: &[u8],
         storage_preference: StoragePreference,
     ) -> Result<(ObjectHandle<'os>, ObjectInfo)> {
+        namespace::check_unnamespaced(key)?;
         self.init_object_with_pref_and_access_type(
             key,
             storage_preference,
@@ -453,9 +645,10 @@ impl<'os> ObjectStore {
         let info = ObjectInfo {
             object_id: oid,
             size: 0,
-            mtime: SystemTime::now(),
+            mtime: clock::system_now(),
             pref: storage_preference,
             access_pattern: access_type,
+            generation: self.data.current_generation(),
         };
 
         self.update_object_info(key, &MetaMessage::set_info(&info))?;
@@ -500,6 +693,16 @@ impl<'os> ObjectStore {
         &'os self,
         key: &[u8],
         storage_preference: StoragePreference,
+    ) -> Result<Option<(ObjectHandle<'os>, ObjectInfo)>> {
+        namespace::check_unnamespaced(key)?;
+        self.read_object_handle(key, storage_preference)
+    }
+
+    // Opens the object `key`, which may be namespaced.
+    fn read_object_handle(
+        &'os self,
+        key: &[u8],
+        storage_preference: StoragePreference,
     ) -> Result<Option<(ObjectHandle<'os>, ObjectInfo)>> {
         if key.contains(&0) {
             return Err(Error::KeyContainsNullByte);
@@ -534,11 +737,41 @@ impl<'os> ObjectStore {
 
     /// Open an existing object by key, return `None` if it doesn't exist.
     /// As the object metadata needs to be queried anyway, it is also returned.
+    ///
+    /// The handle uses the storage preference stored with the object, or the default storage
+    /// preference of this store if the object has none.
     pub fn open_object_with_info(
         &'os self,
         key: &[u8],
     ) -> Result<Option<(ObjectHandle<'os>, ObjectInfo)>> {
-        self.open_object_with_pref(key, self.default_storage_preference)
+        namespace::check_unnamespaced(key)?;
+        self.read_object_handle_with_info(key)
+    }
+
+    // Opens the object `key`, which may be namespaced, with its stored storage
+    // preference.
+    pub(crate) fn read_object_handle_with_info(
+        &'os self,
+        key: &[u8],
+    ) -> Result<Option<(ObjectHandle<'os>, ObjectInfo)>> {
+        Ok(self
+            .read_object_handle(key, self.default_storage_preference)?
+            .map(|(mut handle, info)| {
+                if info.pref != StoragePreference::NONE {
+                    handle.object.storage_preference = info.pref;
+                }
+                (handle, info)
+            }))
+    }
+
+    /// Rename the object `old_key` to `new_key`. If an object named `new_key`
+    /// already exists it is deleted when `overwrite` is set, otherwise
+    /// [Error::AlreadyExists] is returned.
+    ///
+    /// Only the object metadata is moved, no object data is copied.
+    pub fn rename(&'os self, old_key: &[u8], new_key: &[u8], overwrite: bool) -> Result<()> {
+        let mut handle = self.open_object(old_key)?.ok_or(Error::DoesNotExist)?;
+        handle.rename_with(new_key, overwrite)
     }
 
     /// Open an existing object by key, return `None` if it doesn't exist.
@@ -565,7 +798,11 @@ impl<'os> ObjectStore {
         &'os self,
         key: &[u8],
     ) -> Result<(ObjectHandle<'os>, ObjectInfo)> {
-        self.open_or_create_object_with_pref(key, self.default_storage_preference)
+        if let Some(obj) = self.open_object_with_info(key)? {
+            Ok(obj)
+        } else {
+            self.create_object_with_pref(key, self.default_storage_preference)
+        }
     }
 
     /// Try to open an object, but create it if it didn't exist.
@@ -627,7 +864,8 @@ impl<'os> ObjectStore {
                     None
                 }
             })
-            .filter(|(key, _value)| meta::is_fixed_key(key))
+            // Objects of namespaces are only listed through their namespace.
+            .filter(|(key, _value)| meta::is_fixed_key(key) && !namespace::is_namespaced(key))
             .map(move |(key, value)| {
                 let info = ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, &value).unwrap();
                 (
@@ -767,39 +1005,74 @@ impl<'ds> ObjectHandle<'ds> {
         self.store.delete_object(&self)
     }
 
+    /// Rename this object to `new_key`, replacing any object of that name.
+    /// See [ObjectStore::rename] for a variant which does not replace objects
+    /// and [ObjectNamespace::rename] for objects of a namespace.
     pub fn rename(&mut self, new_key: &[u8]) -> Result<()> {
+        self.rename_with(new_key, true)
+    }
+
+    fn rename_with(&mut self, new_key: &[u8], overwrite: bool) -> Result<()> {
+        namespace::check_unnamespaced(new_key)?;
+        self.move_to(new_key, overwrite)
+    }
+
+    // All metadata entries are moved by messages to the metadata tree, the
+    // object data itself is not touched. No sync can start while the messages
+    // are inserted, so the rename, including the deletion of an overwritten
+    // object, is atomic with regard to crashes. `new_key` may be namespaced.
+    fn move_to(&mut self, new_key: &[u8], overwrite: bool) -> Result<()> {
         if new_key.contains(&0) {
             return Err(Error::KeyContainsNullByte);
         }
+        if new_key == &self.object.key[..] {
+            return Ok(());
+        }
+        let store = self.store;
+        store
+            .metadata
+            .call_uninterrupted_by_sync(|| self.move_metadata(new_key, overwrite))
+    }
 
-        let old_key = mem::replace(&mut self.object.key, new_key.to_vec());
-        let custom_delete = SlicedCowBytes::from(meta::delete_custom());
-
-        let mut nk = Vec::with_capacity(new_key.len());
+    fn move_metadata(&mut self, new_key: &[u8], overwrite: bool) -> Result<()> {
+        if let Some((existing, _info)) = self.store.read_object_handle_with_info(new_key)? {
+            if !overwrite {
+                return Err(Error::AlreadyExists);
+            }
+            self.store.delete_object(&existing)?;
+        }
 
-        for (k, v) in self
+        // Gather all entries first, the new entries are inserted before the
+        // old ones are removed so that the object is always reachable.
+        let entries = self
             .store
             .metadata
-            .range(old_key..self.object.metadata_end())?
-            .flatten()
-        {
-            if meta::is_fixed_key(&k) {
+            .range(&self.object.key[..]..&self.object.metadata_end()[..])?
+            .collect::<Result<Vec<_>>>()?;
+        let old_key = mem::replace(&mut self.object.key, new_key.to_vec());
+
+        for (k, v) in entries.iter() {
+            if meta::is_fixed_key(k) {
+                let info = ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, v).unwrap();
                 self.store
-                    .metadata
-                    .insert_msg(k, MetaMessage::delete().pack().into())?;
-                self.store.metadata.insert_msg(new_key, v)?;
+                    .update_object_info(new_key, &MetaMessage::set_info(&info))?;
             } else {
-                nk.clear();
-                nk.extend_from_slice(new_key);
-                nk.push(0);
                 // unwrap-safe, k must contain 0 as is_fixed_key was false
                 let meta_name_start = k.iter().position(|&b| b == 0).unwrap() + 1;
-                nk.extend_from_slice(&k[meta_name_start..]);
+                self.store.metadata.insert_msg(
+                    self.object.metadata_key(&k[meta_name_start..]),
+                    meta::set_custom(v).into(),
+                )?;
+            }
+        }
 
-                self.store.metadata.insert_msg(k, custom_delete.clone())?;
+        let custom_delete = SlicedCowBytes::from(meta::delete_custom());
+        for (k, _) in entries {
+            if meta::is_fixed_key(&k) {
                 self.store
-                    .metadata
-                    .insert_msg(&nk[..], meta::set_custom(&v).into())?;
+                    .update_object_info(&old_key, &MetaMessage::delete())?;
+            } else {
+                self.store.metadata.insert_msg(k, custom_delete.clone())?;
             }
         }
 
@@ -822,7 +1095,7 @@ impl<'ds> ObjectHandle<'ds> {
         let to_be_read = (buf.len() as u64).min(remaining_data);
         let chunk_range = ChunkRange::from_byte_bounds(offset, to_be_read);
 
-        let start = Instant::now();
+        let start = clock::now();
 
         let mut last_offset = offset;
 
@@ -871,7 +1144,8 @@ impl<'ds> ObjectHandle<'ds> {
             let _ = tx
                 .send(DatabaseMsg::ObjectRead(
                     GlobalObjectId::build(self.store.id, self.object.id),
-                    start.elapsed(),
+                    clock::system_now(),
+                    clock::elapsed(start),
                 ))
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
         }
@@ -879,6 +1153,63 @@ impl<'ds> ObjectHandle<'ds> {
         Ok(total_read)
     }
 
+    /// Report which parts of the byte `range` of this object hold stored data
+    /// and which are holes reading as zeroes. The returned extents cover `range`
+    /// up to the object size in ascending order, adjacent extents of the same
+    /// kind are merged.
+    pub fn read_extents(&self, range: Range<u64>) -> Result<Vec<Extent>> {
+        self.read_sparse(range, |_, _| {})
+    }
+
+    /// Read object data into `buf`, starting at offset `offset`, without
+    /// zero-filling holes. Parts of `buf` which correspond to holes are left
+    /// untouched, the returned extents describe which parts of the object have
+    /// been read, see [ObjectHandle::read_extents].
+    pub fn read_at_sparse(&self, buf: &mut [u8], offset: u64) -> Result<Vec<Extent>> {
+        let range = offset..offset.saturating_add(buf.len() as u64);
+        self.read_sparse(range, |range, data| {
+            buf[(range.start - offset) as usize..(range.end - offset) as usize]
+                .copy_from_slice(data)
+        })
+    }
+
+    fn read_sparse<F>(&self, range: Range<u64>, mut f: F) -> Result<Vec<Extent>>
+    where
+        F: FnMut(Range<u64>, &[u8]),
+    {
+        let obj_size = self.info()?.map(|info| info.size).unwrap_or(0);
+        let end = range.end.min(obj_size);
+        let mut extents = Vec::new();
+        if range.start >= end {
+            return Ok(extents);
+        }
+
+        let chunk_range = ChunkRange::from_byte_bounds(range.start, end - range.start);
+        let mut last_offset = range.start;
+        for chunk in self.read_chunk_range(chunk_range.start.chunk_id..chunk_range.end.chunk_id)? {
+            let (chunk_bounds, data) = chunk?;
+            let start = chunk_bounds.start.max(range.start);
+            let stop = chunk_bounds.end.min(end);
+            if start >= stop {
+                continue;
+            }
+            if start > last_offset {
+                Extent::push(&mut extents, Extent::Hole(last_offset..start));
+            }
+            let data_start = (start - chunk_bounds.start) as usize;
+            f(
+                start..stop,
+                &data[data_start..data_start + (stop - start) as usize],
+            );
+            Extent::push(&mut extents, Extent::Data(start..stop));
+            last_offset = stop;
+        }
+        if last_offset < end {
+            Extent::push(&mut extents, Extent::Hole(last_offset..end));
+        }
+        Ok(extents)
+    }
+
     /// Read this object in chunk-aligned blocks. The iterator will contain any existing chunks
     /// within `chunk_range`, and specify the address range of each returned chunk in bytes.
     ///
@@ -889,7 +1220,7 @@ impl<'ds> ObjectHandle<'ds> {
         chunk_range: Range<u32>,
     ) -> Result<impl Iterator<Item = Result<(Range<u64>, SlicedCowBytes)>>> {
         // FIXME: This is incorrect, correctly we shoud measure how long each individual fetch takes
-        let start = Instant::now();
+        let start = clock::now();
         let iter = self.store.data.range(
             &object_chunk_key(self.object.id, chunk_range.start)[..]
                 ..=&object_chunk_key(self.object.id, chunk_range.end),
@@ -913,7 +1244,8 @@ impl<'ds> ObjectHandle<'ds> {
             let _ = tx
                 .send(DatabaseMsg::ObjectRead(
                     GlobalObjectId::build(self.store.id, self.object.id),
-                    start.elapsed(),
+                    clock::system_now(),
+                    clock::elapsed(start),
                 ))
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
         }
@@ -950,7 +1282,7 @@ impl<'ds> ObjectHandle<'ds> {
         let mut total_written = 0;
         log::trace!("Entered object::write_at_with_pref");
 
-        let start = Instant::now();
+        let start = clock::now();
         for chunk in chunk_range.split_at_chunk_bounds() {
             let len = chunk.single_chunk_len() as usize;
             let key = object_chunk_key(self.object.id, chunk.start.chunk_id);
@@ -962,7 +1294,8 @@ impl<'ds> ObjectHandle<'ds> {
                     // best-effort metadata update
                     // this is called only when the original upsert errored,
                     // there's not much we can do to handle an error during error handling
-                    meta_change.mtime = Some(SystemTime::now());
+                    meta_change.mtime = Some(clock::system_now());
+                    meta_change.generation = Some(self.store.data.current_generation());
                     let _ = self
                         .store
                         .update_object_info(&self.object.key, &meta_change);
@@ -983,11 +1316,12 @@ impl<'ds> ObjectHandle<'ds> {
                     GlobalObjectId::build(self.store.id, self.object.id),
                     size,
                     storage_pref,
-                    start.elapsed(),
+                    clock::elapsed(start),
                 ))
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
         }
-        meta_change.mtime = Some(SystemTime::now());
+        meta_change.mtime = Some(clock::system_now());
+        meta_change.generation = Some(self.store.data.current_generation());
         meta_change.pref = Some(storage_pref);
         self.store
             .update_object_info(&self.object.key, &meta_change)
@@ -1002,6 +1336,95 @@ impl<'ds> ObjectHandle<'ds> {
         self.write_at_with_pref(buf, offset, self.object.storage_preference)
     }
 
+    /// Set the size of this object to `len` bytes. Data beyond `len` is removed, extending the
+    /// object leaves a hole reading as zeroes.
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        let mut info = self.info()?.ok_or(Error::DoesNotExist)?;
+        if len < info.size {
+            let end = ChunkOffset {
+                chunk_id: (len / CHUNK_SIZE as u64) as u32,
+                offset: (len % CHUNK_SIZE as u64) as u32,
+            };
+            let first_removed = if end.offset == 0 {
+                end.chunk_id
+            } else {
+                let key = object_chunk_key(self.object.id, end.chunk_id);
+                if let Some(value) = self.store.data.get(&key[..])? {
+                    if value.len() > end.offset as usize {
+                        self.store.data.insert_with_pref(
+                            &key[..],
+                            &value[..end.offset as usize],
+                            info.pref,
+                        )?;
+                    }
+                }
+                end.chunk_id + 1
+            };
+            self.store.data.range_delete(
+                &object_chunk_key(self.object.id, first_removed)[..]
+                    ..&object_chunk_key(self.object.id, u32::MAX)[..],
+            )?;
+        }
+
+        // A complete info is required, as partial size updates are merged with `max`.
+        info.size = len;
+        info.mtime = clock::system_now();
+        info.generation = self.store.data.current_generation();
+        self.store
+            .update_object_info(&self.object.key, &MetaMessage::set_info(&info))
+    }
+
+    /// Deallocate `len` bytes of this object starting at `offset`, the range reads as zeroes
+    /// afterwards. Chunks covered completely are removed, the object size is not changed.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+        let obj_size = self.info()?.map(|info| info.size).unwrap_or(0);
+        let len = len.min(obj_size.saturating_sub(offset));
+        if len == 0 {
+            return Ok(());
+        }
+
+        let chunk_range = ChunkRange::from_byte_bounds(offset, len);
+        let mut full_chunks: Option<Range<u32>> = None;
+        for chunk in chunk_range.split_at_chunk_bounds() {
+            let chunk_len = chunk.single_chunk_len();
+            if chunk_len == CHUNK_SIZE {
+                let id = chunk.start.chunk_id;
+                full_chunks = Some(full_chunks.map_or(id..id + 1, |range| range.start..id + 1));
+                continue;
+            }
+
+            // Zero the part of the stored value which lies within the hole.
+            let key = object_chunk_key(self.object.id, chunk.start.chunk_id);
+            if let Some(value) = self.store.data.get(&key[..])? {
+                let zero_end = (chunk.end.offset as usize).min(value.len());
+                if zero_end > chunk.start.offset as usize {
+                    let zeroes = vec![0; zero_end - chunk.start.offset as usize];
+                    self.store.data.upsert_with_pref(
+                        &key[..],
+                        &zeroes,
+                        chunk.start.offset,
+                        self.object.storage_preference,
+                    )?;
+                }
+            }
+        }
+        if let Some(range) = full_chunks {
+            self.store.data.range_delete(
+                &object_chunk_key(self.object.id, range.start)[..]
+                    ..&object_chunk_key(self.object.id, range.end)[..],
+            )?;
+        }
+
+        self.store.update_object_info(
+            &self.object.key,
+            &MetaMessage {
+                mtime: Some(clock::system_now()),
+                generation: Some(self.store.data.current_generation()),
+                ..MetaMessage::default()
+            },
+        )
+    }
+
     /// Fetches this object's fixed metadata.
     /// Return this objects size in bytes. Size is defined as the largest offset of any byte in
     /// this objects data, and not the total count of bytes, as there could be sparsely allocated
@@ -1059,6 +1482,64 @@ impl<'ds> ObjectHandle<'ds> {
         Ok(Box::new(iter))
     }
 
+    /// Fetches the value of the named attribute `name`.
+    ///
+    /// Attributes are custom metadata entries with UTF-8 names, they are
+    /// visible via [ObjectHandle::iter_metadata] as well.
+    pub fn get_attr(&self, name: &str) -> Result<Option<SlicedCowBytes>> {
+        check_attr_name(name)?;
+        self.get_metadata(name.as_bytes())
+    }
+
+    /// Sets the named attribute `name` to `value`, replacing any previous value.
+    /// Values may not exceed [MAX_ATTRIBUTE_SIZE] bytes.
+    pub fn set_attr(&self, name: &str, value: &[u8]) -> Result<()> {
+        check_attr_name(name)?;
+        if value.len() > MAX_ATTRIBUTE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.set_metadata(name.as_bytes(), value)
+    }
+
+    /// Removes the named attribute `name`.
+    pub fn remove_attr(&self, name: &str) -> Result<()> {
+        check_attr_name(name)?;
+        self.delete_metadata(name.as_bytes())
+    }
+
+    /// Lists all named attributes of this object in lexicographic order.
+    /// Custom metadata entries whose names are not valid UTF-8 are skipped.
+    pub fn list_attrs(&self) -> Result<Vec<(String, SlicedCowBytes)>> {
+        let mut attrs = Vec::new();
+        for res in self.iter_metadata()? {
+            let (name, value) = res?;
+            if let Ok(name) = std::str::from_utf8(&name) {
+                attrs.push((name.to_owned(), value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// The storage preference used for all chunks written through this handle.
+    pub fn storage_preference(&self) -> StoragePreference {
+        self.object.storage_preference
+    }
+
+    /// Set the storage preference for all future chunk writes of this object. The preference is
+    /// stored with the object and used by handles which are opened without an explicit
+    /// preference, already written chunks are not moved, see [ObjectHandle::migrate] for that.
+    pub fn set_storage_preference(&mut self, pref: StoragePreference) -> Result<()> {
+        self.store.update_object_info(
+            &self.object.key,
+            &MetaMessage {
+                pref: Some(pref),
+                ..MetaMessage::default()
+            },
+        )?;
+        self.object.storage_preference = pref;
+        Ok(())
+    }
+
     /// Migrate the whole object to a specified storage preference and write all future accesses to the same storage
     /// tier.
     pub fn migrate(&mut self, pref: StoragePreference) -> Result<()> {
@@ -1111,7 +1592,11 @@ impl<'ds> ObjectHandle<'ds> {
     /// The object will continue to use this tier for future writes.
     /// Returns error if no lower tier is available or no storage tier is specified.
     pub fn migrate_down(&mut self) -> Result<()> {
-        if let Some(pref) = self.object.storage_preference.lower() {
+        if let Some(pref) = self
+            .object
+            .storage_preference
+            .lower(self.store.data.configured_class_count())
+        {
             self.migrate(pref)
         } else {
             Err(Error::MigrationNotPossible)
@@ -1121,7 +1606,11 @@ impl<'ds> ObjectHandle<'ds> {
     /// Migrate the whole object to the next slower storage tier.
     /// Returns error if no lower tier is available or no storage tier is specified.
     pub fn migrate_down_once(&self) -> Result<()> {
-        if let Some(pref) = self.object.storage_preference.lower() {
+        if let Some(pref) = self
+            .object
+            .storage_preference
+            .lower(self.store.data.configured_class_count())
+        {
             self.migrate_once(pref)
         } else {
             Err(Error::MigrationNotPossible)
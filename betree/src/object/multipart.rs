@@ -0,0 +1,82 @@
+use super::{chunk::*, object_chunk_key, MetaMessage, ObjectHandle};
+use crate::{
+    clock,
+    database::{Error, Result},
+    StoragePreference,
+};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// An upload of an object in multiple parts, which may be written concurrently from multiple
+/// threads. Parts have to start at chunk boundaries and must not overlap.
+///
+/// Parts only write object data, the object size is published once by [MultipartUpload::complete].
+/// Until then readers of the object only see the data below its previous size.
+pub struct MultipartUpload<'os> {
+    handle: ObjectHandle<'os>,
+    pref: StoragePreference,
+    // The end of the furthest part written so far.
+    end: AtomicU64,
+}
+
+impl<'os> ObjectHandle<'os> {
+    /// Start a multipart upload to this object, using the storage preference of this handle.
+    pub fn multipart_upload(&self) -> MultipartUpload<'os> {
+        MultipartUpload {
+            handle: self.clone(),
+            pref: self.object.storage_preference,
+            end: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<'os> MultipartUpload<'os> {
+    /// Write the part `buf` at `offset`, which has to be a multiple of the chunk size.
+    pub fn write_part(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        if offset % CHUNK_SIZE as u64 != 0 {
+            return Err(Error::InvalidArgument(
+                "part offsets have to be a multiple of the chunk size",
+            ));
+        }
+
+        let store = self.handle.store;
+        let end = offset + buf.len() as u64;
+        let mut buf = buf;
+        for chunk in ChunkRange::from_byte_bounds(offset, buf.len() as u64).split_at_chunk_bounds()
+        {
+            let len = chunk.single_chunk_len() as usize;
+            let key = object_chunk_key(self.handle.object.id, chunk.start.chunk_id);
+            if len == CHUNK_SIZE as usize {
+                store
+                    .data
+                    .insert_with_pref(&key[..], &buf[..len], self.pref)?;
+            } else {
+                store.data.upsert_with_pref(
+                    &key[..],
+                    &buf[..len],
+                    chunk.start.offset,
+                    self.pref,
+                )?;
+            }
+            buf = &buf[len..];
+        }
+
+        self.end.fetch_max(end, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Finish the upload by publishing the new object size, which is the end of the furthest
+    /// part, in a single metadata update. The size never shrinks below the previous size.
+    pub fn complete(self) -> Result<()> {
+        self.handle.store.update_object_info(
+            &self.handle.object.key,
+            &MetaMessage {
+                size: Some(self.end.load(Ordering::Acquire)),
+                mtime: Some(clock::system_now()),
+                generation: Some(self.handle.store.data.current_generation()),
+                pref: Some(self.pref),
+                ..MetaMessage::default()
+            },
+        )
+    }
+}
@@ -0,0 +1,173 @@
+use super::{meta, prefix_end, ObjectHandle, ObjectInfo, ObjectStore, PreferredAccessType};
+use crate::{
+    cow_bytes::CowBytes,
+    database::{Error, Result},
+};
+
+use std::ops::Bound;
+
+/// Object names starting with this byte are reserved for namespaced objects.
+pub const NAMESPACE_MARKER: u8 = u8::MAX;
+
+// Returns whether `key` names an object of a namespace.
+pub(super) fn is_namespaced(key: &[u8]) -> bool {
+    key.first() == Some(&NAMESPACE_MARKER)
+}
+
+// Names passed to the [ObjectStore] directly must not reach into namespaces.
+pub(super) fn check_unnamespaced(key: &[u8]) -> Result<()> {
+    if is_namespaced(key) {
+        return Err(Error::InvalidArgument(
+            "object names may not start with the namespace marker, use a namespace instead",
+        ));
+    }
+    Ok(())
+}
+
+/// A namespace within an [ObjectStore], isolating a set of objects from the objects of other
+/// namespaces and the objects outside of any namespace.
+///
+/// Namespaced objects are stored with the key `[NAMESPACE_MARKER][namespace][NAMESPACE_MARKER][name]`
+/// in the metadata tree, their data is separated by object id as for any other object. Handles
+/// returned from a namespace report this full key as their [Object::key](super::Object::key).
+pub struct ObjectNamespace<'os> {
+    store: &'os ObjectStore,
+    prefix: Vec<u8>,
+}
+
+impl<'os> ObjectStore {
+    /// Return the namespace `name` of this store. Namespaces exist implicitly as long as they
+    /// contain objects, their names may neither be empty nor contain `0` or [NAMESPACE_MARKER].
+    pub fn namespace(&'os self, name: &[u8]) -> Result<ObjectNamespace<'os>> {
+        if name.is_empty() || name.contains(&NAMESPACE_MARKER) {
+            return Err(Error::InvalidArgument(
+                "namespace names may not be empty or contain the namespace marker",
+            ));
+        }
+        if name.contains(&0) {
+            return Err(Error::KeyContainsNullByte);
+        }
+
+        let mut prefix = Vec::with_capacity(name.len() + 2);
+        prefix.push(NAMESPACE_MARKER);
+        prefix.extend_from_slice(name);
+        prefix.push(NAMESPACE_MARKER);
+        Ok(ObjectNamespace {
+            store: self,
+            prefix,
+        })
+    }
+
+    /// List the names of all namespaces which contain objects, in lexicographic order.
+    pub fn list_namespaces(&'os self) -> Result<Vec<CowBytes>> {
+        let mut namespaces = Vec::new();
+        let mut start = vec![NAMESPACE_MARKER];
+        // Skip over the objects of each found namespace.
+        loop {
+            let next = self
+                .metadata
+                .range((Bound::Included(start), Bound::Unbounded))?
+                .find(|res| !matches!(res, Ok((key, _)) if !meta::is_fixed_key(key)))
+                .transpose()?;
+            let key = match next {
+                Some((key, _)) => key,
+                None => break,
+            };
+            let end = match key[1..].iter().position(|b| *b == NAMESPACE_MARKER) {
+                Some(end) => end + 1,
+                None => break,
+            };
+            namespaces.push(CowBytes::from(&key[1..end]));
+            start = match prefix_end(&key[..=end]) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(namespaces)
+    }
+}
+
+impl<'os> ObjectNamespace<'os> {
+    /// The name of this namespace.
+    pub fn name(&self) -> &[u8] {
+        &self.prefix[1..self.prefix.len() - 1]
+    }
+
+    fn key(&self, name: &[u8]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.prefix.len() + name.len());
+        key.extend_from_slice(&self.prefix);
+        key.extend_from_slice(name);
+        key
+    }
+
+    /// Create a new object in this namespace, see [ObjectStore::create_object].
+    pub fn create_object(&self, name: &[u8]) -> Result<ObjectHandle<'os>> {
+        self.store
+            .init_object_with_pref_and_access_type(
+                &self.key(name),
+                self.store.default_storage_preference,
+                PreferredAccessType::Unknown,
+            )
+            .map(|(handle, _info)| handle)
+    }
+
+    /// Open an existing object in this namespace, see [ObjectStore::open_object].
+    pub fn open_object(&self, name: &[u8]) -> Result<Option<ObjectHandle<'os>>> {
+        self.open_object_with_info(name)
+            .map(|option| option.map(|(handle, _info)| handle))
+    }
+
+    /// Open an existing object in this namespace along with its metadata, see
+    /// [ObjectStore::open_object_with_info].
+    pub fn open_object_with_info(
+        &self,
+        name: &[u8],
+    ) -> Result<Option<(ObjectHandle<'os>, ObjectInfo)>> {
+        self.store.read_object_handle_with_info(&self.key(name))
+    }
+
+    /// Rename the object `old_name` of this namespace to `new_name`, see [ObjectStore::rename].
+    /// Objects can not be moved between namespaces.
+    pub fn rename(&self, old_name: &[u8], new_name: &[u8], overwrite: bool) -> Result<()> {
+        let mut handle = self.open_object(old_name)?.ok_or(Error::DoesNotExist)?;
+        handle.move_to(&self.key(new_name), overwrite)
+    }
+
+    /// Open an object in this namespace or create it if it does not exist, see
+    /// [ObjectStore::open_or_create_object].
+    pub fn open_or_create_object(&self, name: &[u8]) -> Result<ObjectHandle<'os>> {
+        match self.open_object(name)? {
+            Some(handle) => Ok(handle),
+            None => self.create_object(name),
+        }
+    }
+
+    /// Return an iterator over the names, without the namespace prefix, and metadata of all
+    /// objects in this namespace.
+    pub fn list_objects(&self) -> Result<impl Iterator<Item = Result<(CowBytes, ObjectInfo)>>> {
+        let prefix_len = self.prefix.len();
+        Ok(self
+            .objects()?
+            .map(move |res| res.map(|(key, info)| (CowBytes::from(&key[prefix_len..]), info))))
+    }
+
+    /// Delete all objects of this namespace.
+    pub fn delete(self) -> Result<()> {
+        let keys = self
+            .objects()?
+            .map(|res| res.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        for key in keys {
+            if let Some((handle, _info)) = self.store.read_object_handle_with_info(&key)? {
+                handle.delete()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Lists the objects of this namespace by their full key.
+    fn objects(&self) -> Result<impl Iterator<Item = Result<(CowBytes, ObjectInfo)>>> {
+        let end = prefix_end(&self.prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        self.store.list_objects_in(self.prefix.clone(), end)
+    }
+}
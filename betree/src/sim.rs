@@ -0,0 +1,238 @@
+//! Deterministic simulation of a database for tests and bug reproductions.
+//!
+//! A [Simulation] runs a database on a single thread against a virtual clock.
+//! Databases built with [Simulation::build] perform all I/O on the calling
+//! thread and run their periodic syncs, defragmentation and migration policy
+//! as tasks of the simulation instead of background threads.  The tasks only
+//! run when the virtual time is advanced with [Simulation::advance].
+//! Simulated vdevs ([LeafVdev::Simulated](crate::storage_pool::LeafVdev::Simulated))
+//! keep their contents in memory and let each request take a latency drawn
+//! from the seeded random number generator of the simulation.
+//!
+//! Given the same seed, configuration and sequence of calls, a simulation
+//! therefore behaves the same on every run, including snapshot creation
+//! times, modification times of objects, migration decisions and the shape
+//! of the trees.  Only the nonces of encrypted data are still drawn from the
+//! random number generator of the system.
+//!
+//! The virtual clock is only in effect on the thread which created the
+//! simulation, the database must neither be accessed from other threads nor
+//! from multiple simulations at once.
+//!
+//! ```no_run
+//! # use betree_storage_stack::{sim::Simulation, DatabaseConfiguration};
+//! # use std::time::Duration;
+//! let sim = Simulation::new(42);
+//! let db = sim
+//!     .build(DatabaseConfiguration {
+//!         sync_interval_ms: Some(1000),
+//!         ..Default::default()
+//!     })
+//!     .unwrap();
+//! // Runs the periodic sync five times.
+//! sim.advance(Duration::from_secs(5));
+//! ```
+use crate::database::{Database, DatabaseConfiguration, Result};
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Clock>>> = RefCell::new(None);
+}
+
+/// Returns the clock of the simulation running on the current thread.
+pub(crate) fn current_clock() -> Option<Arc<Clock>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// The virtual clock of a [Simulation] along with its random number
+/// generator.
+pub(crate) struct Clock {
+    // Virtual instants are offsets to the start of the simulation.
+    origin: Instant,
+    elapsed: Mutex<Duration>,
+    rng: Mutex<StdRng>,
+}
+
+impl Clock {
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock()
+    }
+
+    pub(crate) fn instant(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    /// The virtual wall-clock time starts at the Unix epoch.
+    pub(crate) fn system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + self.elapsed()
+    }
+
+    /// Advances the virtual time by `duration` without running any tasks.
+    pub(crate) fn elapse(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+
+    fn advance_to(&self, time: Duration) {
+        let mut elapsed = self.elapsed.lock();
+        *elapsed = (*elapsed).max(time);
+    }
+
+    /// Lets a request to a simulated vdev take a random latency between
+    /// `min` and `max`.
+    pub(crate) fn delay(&self, min: Duration, max: Duration) {
+        let latency = if min < max {
+            self.rng.lock().gen_range(min..=max)
+        } else {
+            min
+        };
+        self.elapse(latency);
+    }
+}
+
+struct Task {
+    due: Duration,
+    period: Duration,
+    // Tasks due at the same time run in the order of their registration.
+    seq: u64,
+    run: Box<dyn FnMut()>,
+}
+
+struct Inner {
+    seed: u64,
+    clock: Arc<Clock>,
+    tasks: RefCell<Vec<Task>>,
+    next_seq: Cell<u64>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if matches!(&*current, Some(clock) if Arc::ptr_eq(clock, &self.clock)) {
+                *current = None;
+            }
+        })
+    }
+}
+
+/// A deterministic simulation of databases on the current thread, see the
+/// [module documentation](self).
+///
+/// Clones refer to the same simulation, which ends once all of them have
+/// been dropped.
+#[derive(Clone)]
+pub struct Simulation {
+    inner: Rc<Inner>,
+}
+
+impl Simulation {
+    /// Starts a simulation on the current thread whose random decisions are
+    /// derived from `seed`, replacing any previous simulation on this thread.
+    pub fn new(seed: u64) -> Self {
+        let clock = Arc::new(Clock {
+            origin: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        });
+        CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(&clock)));
+        Simulation {
+            inner: Rc::new(Inner {
+                seed,
+                clock,
+                tasks: RefCell::new(Vec::new()),
+                next_seq: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Returns the seed of this simulation.
+    pub fn seed(&self) -> u64 {
+        self.inner.seed
+    }
+
+    /// Returns the virtual time elapsed since the start of the simulation.
+    pub fn now(&self) -> Duration {
+        self.inner.clock.elapsed()
+    }
+
+    /// Opens or creates a database like [Database::build_threaded], but runs
+    /// the periodic sync, defragmentation and migration policy as tasks of
+    /// this simulation.
+    ///
+    /// The configured numbers of write back and flush threads are ignored,
+    /// the database writes back and flushes on the calling thread.
+    pub fn build(&self, builder: DatabaseConfiguration) -> Result<Arc<RwLock<Database>>> {
+        Database::build_simulated(builder, self)
+    }
+
+    /// Runs `task` every `period` of virtual time, starting one `period`
+    /// from now.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn every<F: FnMut() + 'static>(&self, period: Duration, task: F) {
+        self.every_after(period, period, task)
+    }
+
+    /// Runs `task` `delay` from now and every `period` of virtual time
+    /// thereafter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn every_after<F: FnMut() + 'static>(&self, delay: Duration, period: Duration, task: F) {
+        assert!(!period.is_zero(), "periodic tasks need a period");
+        let seq = self.inner.next_seq.get();
+        self.inner.next_seq.set(seq + 1);
+        self.inner.tasks.borrow_mut().push(Task {
+            due: self.now() + delay,
+            period,
+            seq,
+            run: Box::new(task),
+        });
+    }
+
+    /// Advances the virtual time by `duration` and runs the tasks falling
+    /// due in the meantime on the calling thread, in the order of their due
+    /// times.
+    ///
+    /// Tasks may advance the time further, e.g. by the latencies of simulated
+    /// vdevs, tasks which have fallen due meanwhile run late.
+    pub fn advance(&self, duration: Duration) {
+        let until = self.now() + duration;
+        loop {
+            let mut task = {
+                let mut tasks = self.inner.tasks.borrow_mut();
+                let next = tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, task)| task.due <= until)
+                    .min_by_key(|(_, task)| (task.due, task.seq))
+                    .map(|(idx, _)| idx);
+                match next {
+                    Some(idx) => tasks.swap_remove(idx),
+                    None => break,
+                }
+            };
+            self.inner.clock.advance_to(task.due);
+            (task.run)();
+            task.due += task.period;
+            self.inner.tasks.borrow_mut().push(task);
+        }
+        self.inner.clock.advance_to(until);
+    }
+
+    /// Returns a number drawn from the random number generator of this
+    /// simulation, e.g. to generate workloads.
+    pub fn random(&self) -> u64 {
+        self.inner.clock.rng.lock().gen()
+    }
+}
@@ -2,7 +2,8 @@
 #[cfg(feature = "nvm")]
 use pmdk;
 
-use crate::vdev::{self, Dev, Leaf};
+use super::{QosConfiguration, SchedulerConfiguration};
+use crate::vdev::{self, Dev, Leaf, MirrorReadPolicy};
 use itertools::Itertools;
 use libc;
 use serde::{Deserialize, Serialize};
@@ -38,6 +39,14 @@ impl PreferredAccessType {
     pub fn as_u8(&self) -> u8 {
         *self as u8
     }
+
+    /// Whether the preferred access is sequential, e.g. on a HDD.
+    pub fn is_sequential(&self) -> bool {
+        matches!(
+            self,
+            Self::SequentialWrite | Self::SequentialRead | Self::SequentialReadWrite
+        )
+    }
 }
 
 impl TryFrom<u8> for PreferredAccessType {
@@ -71,8 +80,25 @@ pub struct TierConfiguration {
     /// Which storage access is preferred to be used with this tier. See
     /// [PreferredAccessType] for all variants.
     pub preferred_access_type: PreferredAccessType,
+    /// Limits of the request rate and bandwidth of this tier, unlimited by
+    /// default.
+    #[serde(default)]
+    pub qos: QosConfiguration,
+    /// Limits of the requests in flight to this tier by their
+    /// [IoPriority](crate::storage_pool::IoPriority), unlimited by default.
+    #[serde(default)]
+    pub scheduler: SchedulerConfiguration,
+    /// Name of this tier, which can be resolved to its
+    /// [StoragePreference](crate::StoragePreference) with
+    /// [Database::tier](crate::Database::tier). Names are stored in the
+    /// superblock and at most [MAX_TIER_NAME_LEN] bytes long.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
+/// The maximum length of a tier name in bytes.
+pub const MAX_TIER_NAME_LEN: usize = 32;
+
 /// Configuration for the storage pool unit.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, default)]
@@ -85,6 +111,10 @@ pub struct StoragePoolConfiguration {
     pub thread_pool_size: Option<u32>,
     /// Whether to pin each worker thread to a CPU core
     pub thread_pool_pinned: bool,
+    /// Whether to open mirrors with missing devices in a degraded state. The
+    /// missing devices are marked as faulted, the mirror stays writable as
+    /// long as one device is available.
+    pub allow_degraded: bool,
 }
 
 impl Default for StoragePoolConfiguration {
@@ -94,6 +124,7 @@ impl Default for StoragePoolConfiguration {
             queue_depth_factor: 20,
             thread_pool_size: None,
             thread_pool_pinned: false,
+            allow_degraded: false,
         }
     }
 }
@@ -108,12 +139,19 @@ pub enum Vdev {
     Mirror {
         /// Constituent vdevs of this mirror
         mirror: Vec<LeafVdev>,
+        /// Which replica to read from. Defaults to `striped`.
+        read_policy: Option<MirrorReadPolicy>,
     },
     /// Parity1 aka RAID5.
     Parity1 {
         /// Constituent vdevs of this parity1 aggregation
         parity1: Vec<LeafVdev>,
     },
+    /// Parity2 aka RAID6.
+    Parity2 {
+        /// Constituent vdevs of this parity2 aggregation
+        parity2: Vec<LeafVdev>,
+    },
 }
 
 /// Represents a leaf vdev.
@@ -142,38 +180,80 @@ pub enum LeafVdev {
         /// Size of memory vdev in bytes.
         mem: usize,
     },
+    /// Forwards requests to another leaf vdev, but delays them and lets them
+    /// fail randomly, see [vdev::Impaired].  Intended for testing.
+    Impaired {
+        /// The underlying vdev.
+        impaired: Box<LeafVdev>,
+        /// Latency added to every request in microseconds.
+        latency_us: Option<u64>,
+        /// Maximal random latency added on top of `latency_us` in
+        /// microseconds.
+        jitter_us: Option<u64>,
+        /// The number of bytes per second which may be transferred.
+        bandwidth: Option<u64>,
+        /// The probability with which a request fails.
+        error_rate: Option<f64>,
+    },
+    #[cfg(feature = "internal-api")]
+    /// Backed by the [vdev::Recorder] registered under the given name, which
+    /// records all writes for crash testing.
+    Recorded {
+        /// Name of the recorder.
+        recorder: String,
+    },
+    #[cfg(feature = "sim")]
+    /// Backed by a memory buffer whose requests take a random latency on the
+    /// virtual clock of the [crate::sim::Simulation] building the database.
+    Simulated {
+        /// Size of the vdev in bytes.
+        mem: usize,
+        /// Minimal latency of a request in microseconds.
+        min_latency_us: u64,
+        /// Maximal latency of a request in microseconds.
+        max_latency_us: u64,
+    },
 }
 
-error_chain! {
-    errors {
-        #[allow(missing_docs)]
-        InvalidKeyword
-    }
+/// Errors of parsing a [TierConfiguration].
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The given keyword is neither a vdev type nor a mirror read policy.
+    #[error("Invalid keyword {0:?} in the vdev specification.")]
+    InvalidKeyword(String),
 }
 
+/// The result of parsing a [TierConfiguration].
+pub type Result<T> = std::result::Result<T, Error>;
+
 impl TierConfiguration {
     /// Returns a new `StorageConfiguration` based on the given top-level vdevs.
     pub fn new(top_level_vdevs: Vec<Vdev>) -> Self {
         TierConfiguration {
             top_level_vdevs,
             preferred_access_type: PreferredAccessType::Unknown,
+            qos: QosConfiguration::default(),
+            scheduler: SchedulerConfiguration::default(),
+            name: None,
         }
     }
 
     /// Opens file and devices and constructs a `Vec<Vdev>`.
-    pub(crate) fn build(&self) -> io::Result<Vec<Dev>> {
+    pub(crate) fn build(&self, allow_degraded: bool) -> io::Result<Vec<Dev>> {
         self.top_level_vdevs
             .iter()
             .enumerate()
-            .map(|(n, v)| v.build(n))
+            .map(|(n, v)| v.build(n, allow_degraded))
             .collect()
     }
 
     /// Parses the configuration from a ZFS-like representation.
     ///
     /// This representation is a sequence of top-level vdevs.
-    /// The keywords `mirror` and `parity1` signal
+    /// The keywords `mirror`, `parity1`, and `parity2` signal
     /// that all immediate following devices shall be grouped in such a vdev.
+    /// The read policy of a mirror may be given as `mirror:<policy>`, e.g.
+    /// `mirror:latency`.
     ///
     /// # Example
     /// `/dev/sda mirror /dev/sdb /dev/sdc parity1 /dev/sdd /dev/sde /dev/sdf`
@@ -193,20 +273,35 @@ impl TierConfiguration {
                 v.push(Vdev::Leaf(LeafVdev::from(s)));
                 continue;
             }
-            let f = match s {
-                "mirror" => |leaves| Vdev::Mirror { mirror: leaves },
-                "parity" | "parity1" => |leaves| Vdev::Parity1 { parity1: leaves },
-                _ => bail!(ErrorKind::InvalidKeyword),
+            let invalid = || Error::InvalidKeyword(s.to_string());
+            let (keyword, read_policy) = match s.split_once(':') {
+                Some((keyword, policy)) => (
+                    keyword,
+                    Some(MirrorReadPolicy::from_name(policy).ok_or_else(invalid)?),
+                ),
+                None => (s, None),
             };
-            let leaves = iter
+            let leaves: Vec<_> = iter
                 .peeking_take_while(is_path)
                 .map(|s| LeafVdev::from(s.as_ref()))
                 .collect();
-            v.push(f(leaves));
+            v.push(match keyword {
+                "mirror" => Vdev::Mirror {
+                    mirror: leaves,
+                    read_policy,
+                },
+                _ if read_policy.is_some() => return Err(invalid()),
+                "parity" | "parity1" => Vdev::Parity1 { parity1: leaves },
+                "parity2" => Vdev::Parity2 { parity2: leaves },
+                _ => return Err(invalid()),
+            });
         }
         Ok(TierConfiguration {
             top_level_vdevs: v,
             preferred_access_type: PreferredAccessType::Unknown,
+            qos: QosConfiguration::default(),
+            scheduler: SchedulerConfiguration::default(),
+            name: None,
         })
     }
 
@@ -218,12 +313,23 @@ impl TierConfiguration {
         for vdev in &self.top_level_vdevs {
             let (keyword, leaves) = match *vdev {
                 Vdev::Leaf(ref leaf) => ("", slice::from_ref(leaf)),
-                Vdev::Mirror { mirror: ref leaves } => ("mirror ", &leaves[..]),
+                Vdev::Mirror {
+                    mirror: ref leaves, ..
+                } => ("mirror", &leaves[..]),
                 Vdev::Parity1 {
                     parity1: ref leaves,
                 } => ("parity1 ", &leaves[..]),
+                Vdev::Parity2 {
+                    parity2: ref leaves,
+                } => ("parity2 ", &leaves[..]),
             };
             s.push_str(keyword);
+            if let Vdev::Mirror { read_policy, .. } = vdev {
+                if let Some(policy) = read_policy {
+                    write!(s, ":{}", policy.as_str()).unwrap();
+                }
+                s.push(' ');
+            }
             for leaf in leaves {
                 match leaf {
                     LeafVdev::File(path) => write!(s, "{} ", path.display()).unwrap(),
@@ -231,6 +337,13 @@ impl TierConfiguration {
                         write!(s, "{} (direct: {:?}) ", path.display(), direct).unwrap()
                     }
                     LeafVdev::Memory { mem } => write!(s, "memory({mem}) ").unwrap(),
+                    LeafVdev::Impaired { impaired, .. } => {
+                        write!(s, "impaired({}) ", impaired.id()).unwrap()
+                    }
+                    #[cfg(feature = "internal-api")]
+                    LeafVdev::Recorded { recorder } => write!(s, "recorded({recorder}) ").unwrap(),
+                    #[cfg(feature = "sim")]
+                    LeafVdev::Simulated { mem, .. } => write!(s, "simulated({mem}) ").unwrap(),
                     #[cfg(feature = "nvm")]
                     LeafVdev::PMemFile { path, len } => {
                         write!(s, "{} {}", path.display(), len).unwrap()
@@ -252,21 +365,31 @@ impl FromIterator<Vdev> for TierConfiguration {
         TierConfiguration {
             top_level_vdevs: iter.into_iter().collect(),
             preferred_access_type: PreferredAccessType::Unknown,
+            qos: QosConfiguration::default(),
+            scheduler: SchedulerConfiguration::default(),
+            name: None,
         }
     }
 }
 
 impl Vdev {
     /// Opens file and devices and constructs a `Vdev`.
-    fn build(&self, n: usize) -> io::Result<Dev> {
+    pub(crate) fn build(&self, n: usize, allow_degraded: bool) -> io::Result<Dev> {
         match *self {
-            Vdev::Mirror { mirror: ref vec } => {
+            Vdev::Mirror {
+                mirror: ref vec,
+                read_policy,
+            } => {
                 let leaves: io::Result<Vec<Leaf>> = vec.iter().map(LeafVdev::build).collect();
-                let leaves: Box<[Leaf]> = leaves?.into_boxed_slice();
-                Ok(Dev::Mirror(vdev::Mirror::new(
-                    leaves,
-                    format!("mirror-{n}"),
-                )))
+                let leaves: Box<[Leaf]> = match leaves {
+                    Ok(leaves) => leaves.into_boxed_slice(),
+                    Err(_) if allow_degraded => LeafVdev::build_degraded(vec)?,
+                    Err(e) => return Err(e),
+                };
+                Ok(Dev::Mirror(
+                    vdev::Mirror::new(leaves, format!("mirror-{n}"))
+                        .with_read_policy(read_policy.unwrap_or_default()),
+                ))
             }
             Vdev::Parity1 { parity1: ref vec } => {
                 let leaves: io::Result<Vec<_>> = vec.iter().map(LeafVdev::build).collect();
@@ -276,12 +399,58 @@ impl Vdev {
                     format!("parity-{n}"),
                 )))
             }
+            Vdev::Parity2 { parity2: ref vec } => {
+                let leaves: io::Result<Vec<_>> = vec.iter().map(LeafVdev::build).collect();
+                let leaves = leaves?.into_boxed_slice();
+                Ok(Dev::Parity2(vdev::Parity2::new(
+                    leaves,
+                    format!("parity2-{n}"),
+                )))
+            }
             Vdev::Leaf(ref leaf) => leaf.build().map(Dev::Leaf),
         }
     }
 }
 
 impl LeafVdev {
+    // Builds all available leaves, replacing missing ones by faulted vdevs.
+    // Fails if none of the leaves is available.
+    fn build_degraded(leaves: &[LeafVdev]) -> io::Result<Box<[Leaf]>> {
+        let mut last_err = None;
+        let leaves: Box<[Leaf]> = leaves
+            .iter()
+            .map(|leaf| {
+                leaf.build().unwrap_or_else(|e| {
+                    let id = leaf.id();
+                    warn!("Marking missing device {} as faulted: {}", id, e);
+                    last_err = Some(e);
+                    Leaf::Faulted(vdev::Faulted::new(id))
+                })
+            })
+            .collect();
+        match last_err {
+            Some(e) if leaves.iter().all(vdev::Vdev::is_faulted) => Err(e),
+            _ => Ok(leaves),
+        }
+    }
+
+    // The identifier of the vdev built from this configuration.
+    fn id(&self) -> String {
+        match self {
+            LeafVdev::File(path) | LeafVdev::FileWithOpts { path, .. } => {
+                path.to_string_lossy().into_owned()
+            }
+            LeafVdev::Memory { mem } => format!("memory-{mem}"),
+            LeafVdev::Impaired { impaired, .. } => impaired.id(),
+            #[cfg(feature = "internal-api")]
+            LeafVdev::Recorded { recorder } => format!("recorded-{recorder}"),
+            #[cfg(feature = "sim")]
+            LeafVdev::Simulated { mem, .. } => format!("simulated-{mem}"),
+            #[cfg(feature = "nvm")]
+            LeafVdev::PMemFile { path, .. } => path.to_string_lossy().into_owned(),
+        }
+    }
+
     fn build(&self) -> io::Result<Leaf> {
         use std::os::unix::fs::OpenOptionsExt;
 
@@ -291,8 +460,13 @@ impl LeafVdev {
                     LeafVdev::File(path) => (path, true),
                     LeafVdev::FileWithOpts { path, direct } => (path, direct.unwrap_or(true)),
                     LeafVdev::Memory { .. } => unreachable!(),
+                    LeafVdev::Impaired { .. } => unreachable!(),
                     #[cfg(feature = "nvm")]
                     LeafVdev::PMemFile { .. } => unreachable!(),
+                    #[cfg(feature = "internal-api")]
+                    LeafVdev::Recorded { .. } => unreachable!(),
+                    #[cfg(feature = "sim")]
+                    LeafVdev::Simulated { .. } => unreachable!(),
                 };
 
                 let mut file = OpenOptions::new();
@@ -317,12 +491,58 @@ impl LeafVdev {
                 mem,
                 format!("memory-{mem}"),
             )?)),
+            LeafVdev::Impaired {
+                ref impaired,
+                latency_us,
+                jitter_us,
+                bandwidth,
+                error_rate,
+            } => Ok(Leaf::Impaired(vdev::Impaired::new(
+                impaired.build()?,
+                vdev::Impairments {
+                    latency: std::time::Duration::from_micros(latency_us.unwrap_or(0)),
+                    jitter: std::time::Duration::from_micros(jitter_us.unwrap_or(0)),
+                    bandwidth,
+                    error_rate: error_rate.unwrap_or(0.0),
+                },
+            ))),
+            #[cfg(feature = "internal-api")]
+            LeafVdev::Recorded { ref recorder } => match vdev::Recorder::get(recorder) {
+                Some(recorder) => Ok(Leaf::Recorded(vdev::Recorded::new(recorder))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No recorder named {recorder}"),
+                )),
+            },
+            #[cfg(feature = "sim")]
+            LeafVdev::Simulated {
+                mem,
+                min_latency_us,
+                max_latency_us,
+            } => match crate::sim::current_clock() {
+                Some(clock) => Ok(Leaf::Simulated(vdev::Simulated::new(
+                    mem,
+                    format!("simulated-{mem}"),
+                    clock,
+                    std::time::Duration::from_micros(min_latency_us),
+                    std::time::Duration::from_micros(max_latency_us),
+                ))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Simulated vdevs require a simulation on the current thread",
+                )),
+            },
             #[cfg(feature = "nvm")]
             LeafVdev::PMemFile { .. } => {
                 let (path, len) = match self {
                     LeafVdev::File(path) => unreachable!(),
                     LeafVdev::FileWithOpts { .. } => unreachable!(),
                     LeafVdev::Memory { .. } => unreachable!(),
+                    LeafVdev::Impaired { .. } => unreachable!(),
+                    #[cfg(feature = "internal-api")]
+                    LeafVdev::Recorded { .. } => unreachable!(),
+                    #[cfg(feature = "sim")]
+                    LeafVdev::Simulated { .. } => unreachable!(),
                     LeafVdev::PMemFile { path, len } => (path, len),
                 };
 
@@ -384,8 +604,20 @@ impl Vdev {
     fn display(&self, indent: usize, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Vdev::Leaf(ref leaf) => leaf.display(indent, f),
-            Vdev::Mirror { ref mirror } => {
-                writeln!(f, "{:indent$}mirror", "", indent = indent)?;
+            Vdev::Mirror {
+                ref mirror,
+                read_policy,
+            } => {
+                match read_policy {
+                    Some(policy) => writeln!(
+                        f,
+                        "{:indent$}mirror ({})",
+                        "",
+                        policy.as_str(),
+                        indent = indent
+                    )?,
+                    None => writeln!(f, "{:indent$}mirror", "", indent = indent)?,
+                }
                 for vdev in mirror {
                     vdev.display(indent + 4, f)?;
                 }
@@ -398,6 +630,13 @@ impl Vdev {
                 }
                 Ok(())
             }
+            Vdev::Parity2 { ref parity2 } => {
+                writeln!(f, "{:indent$}parity2", "", indent = indent)?;
+                for vdev in parity2 {
+                    vdev.display(indent + 4, f)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -421,6 +660,18 @@ impl LeafVdev {
             LeafVdev::Memory { mem } => {
                 writeln!(f, "{:indent$}memory({})", "", mem, indent = indent)
             }
+            LeafVdev::Impaired { impaired, .. } => {
+                writeln!(f, "{:indent$}impaired", "", indent = indent)?;
+                impaired.display(indent + 4, f)
+            }
+            #[cfg(feature = "internal-api")]
+            LeafVdev::Recorded { recorder } => {
+                writeln!(f, "{:indent$}recorded({})", "", recorder, indent = indent)
+            }
+            #[cfg(feature = "sim")]
+            LeafVdev::Simulated { mem, .. } => {
+                writeln!(f, "{:indent$}simulated({})", "", mem, indent = indent)
+            }
             #[cfg(feature = "nvm")]
             LeafVdev::PMemFile { path, len: _ } => {
                 writeln!(f, "{:indent$}{}", "", path.display(), indent = indent)
@@ -2,15 +2,15 @@ use crate::{size::StaticSize, vdev::Block};
 use serde::{Deserialize, Serialize};
 use std::{fmt, mem};
 
-/// 2-bit storage class, 10-bit disk ID, 52-bit block offset (see
+/// 3-bit storage class, 10-bit disk ID, 51-bit block offset (see
 /// [`BLOCK_SIZE`](../vdev/constant.BLOCK_SIZE.html))
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DiskOffset(u64);
 
-const MASK_STORAGE_CLASS: u64 = ((1 << 2) - 1) << (10 + 52);
-const MASK_DISK_ID: u64 = ((1 << 10) - 1) << 52;
-const MASK_OFFSET: u64 = (1 << 52) - 1;
-const MASK_CLASS_DISK_ID_COMBINED: u64 = ((1 << 12) - 1) << 52;
+const MASK_STORAGE_CLASS: u64 = ((1 << 3) - 1) << (10 + 51);
+const MASK_DISK_ID: u64 = ((1 << 10) - 1) << 51;
+const MASK_OFFSET: u64 = (1 << 51) - 1;
+const MASK_CLASS_DISK_ID_COMBINED: u64 = ((1 << 13) - 1) << 51;
 
 /// An identifier containing the class id and disk id. Uniquely identifies a
 /// disk over all storage devices.
@@ -22,6 +22,16 @@ impl GlobalDiskId {
     pub fn as_u16(&self) -> u16 {
         self.0
     }
+
+    /// Returns the 3-bit storage class of the disk.
+    pub fn storage_class(&self) -> u8 {
+        (self.0 >> 10) as u8
+    }
+
+    /// Returns the 10-bit disk ID within its storage class.
+    pub fn disk_id(&self) -> u16 {
+        self.0 & ((1 << 10) - 1)
+    }
 }
 
 /// A class specific disk identifier. Only unique within a set class and only
@@ -30,7 +40,7 @@ pub struct LocalDiskId(u16);
 
 impl DiskOffset {
     /// Constructs a new `DiskOffset`.
-    /// The given `block_offset` may not be larger than (1 << 52) - 1.
+    /// The given `block_offset` may not be larger than (1 << 51) - 1.
     pub fn new(storage_class: u8, disk_id: u16, block_offset: Block<u64>) -> Self {
         let block_offset = block_offset.as_u64();
         assert_eq!(
@@ -38,19 +48,19 @@ impl DiskOffset {
             0,
             "the block offset is too large"
         );
-        DiskOffset(((storage_class as u64) << (52 + 10)) | ((disk_id as u64) << 52) | block_offset)
+        DiskOffset(((storage_class as u64) << (51 + 10)) | ((disk_id as u64) << 51) | block_offset)
     }
-    /// Returns the 2-bit storage class.
+    /// Returns the 3-bit storage class.
     pub fn storage_class(&self) -> u8 {
-        ((self.0 & MASK_STORAGE_CLASS) >> (52 + 10)) as u8
+        ((self.0 & MASK_STORAGE_CLASS) >> (51 + 10)) as u8
     }
     /// Returns the 10-bit disk ID.
     pub fn disk_id(&self) -> u16 {
-        ((self.0 & MASK_DISK_ID) >> 52) as u16
+        ((self.0 & MASK_DISK_ID) >> 51) as u16
     }
-    /// Returns the 12-bit storage class with attached disk ID.
+    /// Returns the 13-bit storage class with attached disk ID.
     pub fn class_disk_id(&self) -> GlobalDiskId {
-        GlobalDiskId(((self.0 & MASK_CLASS_DISK_ID_COMBINED) >> 52) as u16)
+        GlobalDiskId(((self.0 & MASK_CLASS_DISK_ID_COMBINED) >> 51) as u16)
     }
     /// Returns the block offset.
     pub fn block_offset(&self) -> Block<u64> {
@@ -64,6 +74,15 @@ impl DiskOffset {
     pub fn from_u64(x: u64) -> Self {
         DiskOffset(x)
     }
+    /// Translates an offset of format version 1, which used two bits for the
+    /// storage class and 52 bits for the block offset.
+    pub fn from_legacy(x: u64) -> Self {
+        DiskOffset::new(
+            (x >> 62) as u8,
+            ((x >> 52) & ((1 << 10) - 1)) as u16,
+            Block(x & ((1 << 52) - 1)),
+        )
+    }
 
     // Glue together a class identifier with a class depdendent disk_id.
     pub fn construct_disk_id(class: u8, disk_id: u16) -> GlobalDiskId {
@@ -105,5 +124,22 @@ mod tests {
         assert_eq!(o.storage_class(), 1);
         assert_eq!(o.disk_id(), 42);
         assert_eq!(o.block_offset().to_bytes(), 4096 * 189631);
+
+        let o = DiskOffset::new(7, 1023, Block(MASK_OFFSET));
+        assert_eq!(o.storage_class(), 7);
+        assert_eq!(o.disk_id(), 1023);
+        assert_eq!(o.block_offset(), Block(MASK_OFFSET));
+        assert_eq!(o.class_disk_id().storage_class(), 7);
+        assert_eq!(o.class_disk_id().disk_id(), 1023);
+    }
+
+    #[test]
+    fn legacy_layout() {
+        let x = (3 << 62) | (513 << 52) | 189631;
+        let o = DiskOffset::from_legacy(x);
+        assert_eq!(o.storage_class(), 3);
+        assert_eq!(o.disk_id(), 513);
+        assert_eq!(o.block_offset(), Block(189631));
+        assert_eq!(DiskOffset::from_legacy(0), DiskOffset::new(0, 0, Block(0)));
     }
 }
@@ -1,6 +1,26 @@
 #![allow(missing_docs, unused_doc_comments)]
-error_chain! {
-    foreign_links {
-        Io(std::io::Error);
+use crate::database::ErrorCode;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Opening the storage devices failed.")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Invalid storage pool configuration: {0}")]
+    InvalidConfiguration(String),
+}
+
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Io { .. } => ErrorCode::Io,
+            Error::InvalidConfiguration(_) => ErrorCode::Configuration,
+        }
     }
 }
@@ -9,7 +9,7 @@ use crate::{
 };
 use futures::{executor::block_on, prelude::*, TryFuture};
 use serde::{de::DeserializeOwned, Serialize};
-use std::fmt;
+use std::{fmt, time::Duration};
 
 pub mod errors;
 pub use self::errors::*;
@@ -58,12 +58,40 @@ pub trait StoragePoolLayer: Clone + Send + Sync + 'static {
     /// Issues a write request that might happen in the background.
     fn begin_write(&self, data: Buf, offset: DiskOffset) -> VdevResult<()>;
 
+    /// Writes `data` at `offset` of a byte-addressable storage class without
+    /// padding it to whole blocks, see
+    /// [is_byte_addressable](StoragePoolLayer::is_byte_addressable).  The
+    /// write is done when this function returns and persistent after the
+    /// next flush.
+    fn write_bytes(&self, data: &[u8], offset: DiskOffset) -> VdevResult<()>;
+
+    /// Reads `len` bytes written by
+    /// [write_bytes](StoragePoolLayer::write_bytes) at `offset` and verifies
+    /// them with the `checksum`.  The returned buffer is padded with zeroes
+    /// to whole blocks.
+    fn read_bytes(&self, len: u32, offset: DiskOffset, checksum: Self::Checksum)
+        -> VdevResult<Buf>;
+
     /// Writes the given `data` at `offset` for every `LeafVdev`.
     fn write_raw(&self, data: Buf, offset: Block<u64>) -> VdevResult<()>;
 
     /// Reads `size` blocks from  the given `offset` for every `LeafVdev`.
+    /// `Vdev`s which cannot be read are skipped, fails only if none can be
+    /// read.
     fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> VdevResult<Vec<Buf>>;
 
+    /// Writes the given `data` `offset` blocks before the end of every
+    /// `LeafVdev`.  The end of the leaf vdevs of a `Vdev` is the end of its
+    /// smallest one, so that all of them are written at the same offset.
+    fn write_raw_tail(&self, data: Buf, offset: Block<u64>) -> VdevResult<()>;
+
+    /// Reads `size` blocks `offset` blocks before the end of every
+    /// `LeafVdev`, the counterpart of
+    /// [write_raw_tail](StoragePoolLayer::write_raw_tail).  Like
+    /// [read_raw](StoragePoolLayer::read_raw), fails only if no `Vdev` can
+    /// be read.
+    fn read_raw_tail(&self, size: Block<u32>, offset: Block<u64>) -> VdevResult<Vec<Buf>>;
+
     /// Returns the actual size of a data block for a specific `Vdev`
     /// which may be larger due to parity data.
     fn actual_size(&self, storage_class: u8, disk_id: u16, size: Block<u32>) -> Block<u32>;
@@ -71,6 +99,10 @@ pub trait StoragePoolLayer: Clone + Send + Sync + 'static {
     /// Returns the size for a specific `Vdev`.
     fn size_in_blocks(&self, storage_class: u8, disk_id: u16) -> Block<u64>;
 
+    /// Probes the size of a specific `Vdev` again and returns it in blocks.
+    /// Fails if the vdev shrank.
+    fn refresh_size(&self, storage_class: u8, disk_id: u16) -> VdevResult<Block<u64>>;
+
     /// Return the number of leaf vdevs for a specific `Vdev`.
     fn num_disks(&self, storage_class: u8, disk_id: u16) -> usize;
 
@@ -91,11 +123,61 @@ pub trait StoragePoolLayer: Clone + Send + Sync + 'static {
     /// Flushes the write-back queue and the underlying storage backend.
     fn flush(&self) -> VdevResult<()>;
 
+    /// Returns the moving average of the duration of writes to the storage
+    /// class `storage_class`, if any write to it has finished.
+    fn write_latency(&self, storage_class: u8) -> Option<Duration>;
+
     /// Gather layer-specific metrics.
     fn metrics(&self) -> Self::Metrics;
 
+    /// Returns the ids of all leaf vdevs of a storage class which are marked
+    /// as faulted.
+    fn faulted_vdevs(&self, storage_class: u8) -> Vec<String>;
+
+    /// Returns the repairs of faulted replicas of a specific `Vdev` made since
+    /// the last call, as the id of the rewritten leaf vdev and the offset of
+    /// the data.
+    fn take_repairs(&self, storage_class: u8, disk_id: u16) -> Vec<(String, DiskOffset)>;
+
+    /// Appends the top-level vdevs of `config` to the storage class
+    /// `storage_class` and returns the disk id of the first new vdev.
+    ///
+    /// The superblock locations at the start and the end of the new vdevs
+    /// are cleared. A previously
+    /// empty storage class adopts the preferred access type, the limits and
+    /// the name of `config`.
+    fn add_vdevs(&self, storage_class: u8, config: &TierConfiguration) -> VdevResult<u16>;
+
+    /// Removes the last top-level vdev of the storage class `storage_class`
+    /// and clears its superblock locations at the start and the end.  The
+    /// vdev must not be referenced
+    /// anymore.
+    fn remove_last_vdev(&self, storage_class: u8) -> VdevResult<()>;
+
+    /// Returns whether all top-level vdevs of the storage class
+    /// `storage_class` are directly mapped persistent memory.
+    fn is_byte_addressable(&self, storage_class: u8) -> bool;
+
+    /// Returns the [PreferredAccessType] of the storage class `storage_class`.
+    fn preferred_access_type(&self, storage_class: u8) -> PreferredAccessType;
+
     /// Return a fitting [StoragePreference] to the given [PreferredAccessType].
     fn access_type_preference(&self, t: PreferredAccessType) -> StoragePreference;
+
+    /// Replaces the limits of the request rate and bandwidth of the storage
+    /// class `storage_class`.  Requests already waiting for the previous
+    /// limits are admitted by them.
+    fn set_qos(&self, storage_class: u8, qos: &QosConfiguration);
+
+    /// Returns the name of the storage class `storage_class`, if any.
+    fn tier_name(&self, storage_class: u8) -> Option<String>;
+
+    /// Names the storage class `storage_class`. Fails if another storage
+    /// class has the same name.
+    fn set_tier_name(&self, storage_class: u8, name: String) -> VdevResult<()>;
+
+    /// Returns the [StoragePreference] of the storage class named `name`.
+    fn tier(&self, name: &str) -> Option<StoragePreference>;
 }
 
 mod disk_offset;
@@ -104,14 +186,25 @@ pub use self::disk_offset::{DiskOffset, GlobalDiskId, LocalDiskId};
 pub mod configuration;
 pub use self::configuration::{
     LeafVdev, PreferredAccessType, StoragePoolConfiguration, TierConfiguration, Vdev,
+    MAX_TIER_NAME_LEN,
 };
+pub use crate::vdev::MirrorReadPolicy;
 
 mod unit;
+pub(crate) use self::unit::check_tier_name;
 pub use self::unit::StoragePoolUnit;
 
+mod throttle;
+pub(crate) use self::throttle::{io_priority, Throttle};
+pub use self::throttle::{with_io_priority, IoPriority, QosConfiguration};
+
+mod scheduler;
+pub(crate) use self::scheduler::Scheduler;
+pub use self::scheduler::SchedulerConfiguration;
+
 mod storage_preference;
 pub(crate) use storage_preference::AtomicSystemStoragePreference;
 pub use storage_preference::{AtomicStoragePreference, StoragePreference};
 
-/// The amount of storage classes.
-pub const NUM_STORAGE_CLASSES: usize = 4;
+/// The maximum amount of storage classes.
+pub const NUM_STORAGE_CLASSES: usize = 8;
@@ -0,0 +1,156 @@
+//! Per storage class dispatch of requests by their priority.
+//!
+//! Each request is tagged with the [IoPriority] of the issuing thread, see
+//! [with_io_priority](super::with_io_priority).  A storage class runs at most
+//! a configured number of requests at once, and of these at most a configured
+//! number of commit and background requests each.  Requests which exceed these
+//! limits wait, and while a request of a higher priority waits which could be
+//! dispatched, no request of a lower priority is.
+use super::throttle::{io_priority, IoPriority};
+use parking_lot::{Condvar, Mutex};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Limits of the requests in flight to a single storage class, see
+/// [crate::storage_pool::TierConfiguration].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SchedulerConfiguration {
+    /// Maximum number of requests in flight.
+    pub max_in_flight: Option<usize>,
+    /// Maximum number of requests with [IoPriority::Commit] in flight.
+    pub max_commit: Option<usize>,
+    /// Maximum number of requests with [IoPriority::Background] in flight.
+    pub max_background: Option<usize>,
+}
+
+const PRIORITIES: [IoPriority; 3] = [
+    IoPriority::Foreground,
+    IoPriority::Commit,
+    IoPriority::Background,
+];
+
+#[derive(Default)]
+struct State {
+    in_flight: [usize; PRIORITIES.len()],
+    waiting: [usize; PRIORITIES.len()],
+}
+
+/// Dispatches the requests to a storage class.
+pub(crate) struct Scheduler {
+    // The limits per priority, the total number of requests in flight is
+    // limited by `max_in_flight`.
+    limits: [Option<usize>; PRIORITIES.len()],
+    max_in_flight: Option<usize>,
+    state: Mutex<State>,
+    dispatched: Condvar,
+}
+
+impl Scheduler {
+    /// Returns `None` if `config` imposes no limits.
+    pub(crate) fn new(config: &SchedulerConfiguration) -> Option<Self> {
+        if *config == SchedulerConfiguration::default() {
+            return None;
+        }
+        // At least one request has to be dispatchable.
+        let at_least_one = |limit: Option<usize>| limit.map(|limit| limit.max(1));
+        Some(Scheduler {
+            limits: [
+                None,
+                at_least_one(config.max_commit),
+                at_least_one(config.max_background),
+            ],
+            max_in_flight: at_least_one(config.max_in_flight),
+            state: Mutex::new(State::default()),
+            dispatched: Condvar::new(),
+        })
+    }
+
+    // Whether a request of `priority` may be dispatched right away.
+    fn fits(&self, state: &State, priority: IoPriority) -> bool {
+        let idx = priority as usize;
+        let total = state.in_flight.iter().sum::<usize>();
+        self.max_in_flight.map_or(true, |max| total < max)
+            && self.limits[idx].map_or(true, |max| state.in_flight[idx] < max)
+    }
+
+    // Whether a request of `priority` may be dispatched, which is not the case
+    // while a dispatchable request of a higher priority waits.
+    fn may_dispatch(&self, state: &State, priority: IoPriority) -> bool {
+        self.fits(state, priority)
+            && !PRIORITIES[..priority as usize]
+                .iter()
+                .any(|&higher| state.waiting[higher as usize] > 0 && self.fits(state, higher))
+    }
+
+    /// Blocks until a request with the priority of the current thread may be
+    /// dispatched.  The request counts as in flight until the returned
+    /// [Dispatched] is dropped.
+    pub(crate) fn dispatch(self: &Arc<Self>) -> Dispatched {
+        let priority = io_priority();
+        let mut state = self.state.lock();
+        if !self.may_dispatch(&state, priority) {
+            state.waiting[priority as usize] += 1;
+            while !self.may_dispatch(&state, priority) {
+                self.dispatched.wait(&mut state);
+            }
+            state.waiting[priority as usize] -= 1;
+        }
+        state.in_flight[priority as usize] += 1;
+        Dispatched {
+            scheduler: Arc::clone(self),
+            priority,
+        }
+    }
+}
+
+/// A request in flight, see [Scheduler::dispatch].
+pub(crate) struct Dispatched {
+    scheduler: Arc<Scheduler>,
+    priority: IoPriority,
+}
+
+impl Drop for Dispatched {
+    fn drop(&mut self) {
+        self.scheduler.state.lock().in_flight[self.priority as usize] -= 1;
+        self.scheduler.dispatched.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoPriority, Scheduler, SchedulerConfiguration, State};
+
+    #[test]
+    fn unlimited_has_no_scheduler() {
+        assert!(Scheduler::new(&SchedulerConfiguration::default()).is_none());
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let scheduler = Scheduler::new(&SchedulerConfiguration {
+            max_in_flight: Some(4),
+            max_commit: Some(1),
+            max_background: Some(1),
+        })
+        .unwrap();
+        let mut state = State::default();
+        state.in_flight[IoPriority::Background as usize] = 1;
+        assert!(!scheduler.may_dispatch(&state, IoPriority::Background));
+        assert!(scheduler.may_dispatch(&state, IoPriority::Commit));
+
+        state.in_flight[IoPriority::Background as usize] = 0;
+        state.waiting[IoPriority::Foreground as usize] = 1;
+        assert!(!scheduler.may_dispatch(&state, IoPriority::Background));
+        assert!(!scheduler.may_dispatch(&state, IoPriority::Commit));
+
+        // Waiting requests which exceed their own limit do not block others.
+        state.waiting[IoPriority::Foreground as usize] = 0;
+        state.in_flight[IoPriority::Commit as usize] = 1;
+        state.waiting[IoPriority::Commit as usize] = 1;
+        assert!(scheduler.may_dispatch(&state, IoPriority::Background));
+
+        state.in_flight[IoPriority::Foreground as usize] = 3;
+        assert!(!scheduler.may_dispatch(&state, IoPriority::Foreground));
+    }
+}
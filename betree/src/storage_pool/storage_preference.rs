@@ -1,3 +1,4 @@
+use super::NUM_STORAGE_CLASSES;
 use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
 use std::{
@@ -10,6 +11,7 @@ const FASTEST: u8 = 0;
 const FAST: u8 = 1;
 const SLOW: u8 = 2;
 const SLOWEST: u8 = 3;
+const LAST: u8 = NUM_STORAGE_CLASSES as u8 - 1;
 
 /// An allocation preference. If a [StoragePreference] other than [StoragePreference::NONE]
 /// is used for an operation, the allocator will try to allocate on that storage class,
@@ -20,7 +22,9 @@ const SLOWEST: u8 = 3;
 /// per-dataset, or the global default.
 ///
 /// The different class constants are vaguely named `FASTEST`, `FAST`, `SLOW`, and `SLOWEST`,
-/// but a [StoragePreference] can also be created with [StoragePreference::new].
+/// and refer to the first four storage classes. Any of the [NUM_STORAGE_CLASSES] classes can be
+/// selected with [StoragePreference::new], or by the name of its tier with
+/// [Database::tier](crate::Database::tier).
 ///
 /// The exact properties of a storage layer depend on the database administrator, who is assumed
 /// to ensure that the vague ordering properties hold for the given deployment.
@@ -43,9 +47,9 @@ impl StoragePreference {
     pub const SLOWEST: Self = Self(SLOWEST);
 
     /// Construct a new [StoragePreference], for a given class.
-    /// Panics if `class > 3`.
+    /// Panics if `class >= NUM_STORAGE_CLASSES`.
     pub const fn new(class: u8) -> Self {
-        assert!(class <= 3);
+        assert!(class <= LAST);
         Self(class)
     }
 
@@ -79,7 +83,7 @@ impl StoragePreference {
         self.0
     }
     pub(crate) const fn from_u8(u: u8) -> Self {
-        debug_assert!(u == u8::MAX - 1 || u <= 3);
+        debug_assert!(u == u8::MAX - 1 || u <= LAST);
         Self(u)
     }
 
@@ -94,21 +98,23 @@ impl StoragePreference {
         }
     }
 
-    pub(crate) fn lower(self) -> Option<StoragePreference> {
+    /// Returns the next slower class, staying at the last of the
+    /// `class_count` configured classes.
+    pub(crate) fn lower(self, class_count: u8) -> Option<StoragePreference> {
         match self {
             Self::NONE => None,
-            Self::SLOWEST => Some(Self::SLOWEST),
-            _ => Some(Self(self.0.saturating_add(1))),
+            _ if self.0 + 1 >= class_count => Some(self),
+            _ => Some(Self(self.0 + 1)),
         }
     }
 }
 
-// Ordered by `strictness`, so 0 < 1 < 2 < 3 < None.
+// Ordered by `strictness`, so 0 < 1 < 2 < ... < None.
 // Implemented separately instead of derived, to comment
 // and error on some changes to struct items.
 impl PartialOrd for StoragePreference {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        // This works as long as NONE.0 is larger than 3
+        // This works as long as NONE.0 is larger than any class
         self.0.partial_cmp(&other.0)
     }
 }
@@ -263,7 +269,7 @@ impl AtomicSystemStoragePreference {
     pub fn weak_bound(&self, prf: &StoragePreference) -> StoragePreference {
         match self.0.load(Ordering::Relaxed) {
             NONE => *prf,
-            lvl @ 0..=3 => {
+            lvl @ 0..=LAST => {
                 if lvl > prf.as_u8() {
                     *prf
                 } else {
@@ -297,6 +303,18 @@ mod tests {
         assert_eq!(S::choose_faster(S::NONE, S::SLOWEST), S::SLOWEST);
     }
 
+    #[test]
+    fn pref_lift_lower() {
+        use super::StoragePreference as S;
+        let last = S::new(super::LAST);
+        assert_eq!(S::FASTEST.lift(), Some(S::FASTEST));
+        assert_eq!(S::SLOW.lower(4), Some(S::SLOWEST));
+        assert_eq!(S::SLOWEST.lower(4), Some(S::SLOWEST));
+        assert_eq!(S::FAST.lower(2), Some(S::FAST));
+        assert_eq!(last.lower(8), Some(last));
+        assert_eq!(S::NONE.lower(8), None);
+    }
+
     #[test]
     fn weak_bound() {
         let bound = AtomicSystemStoragePreference::from(StoragePreference::SLOW);
@@ -0,0 +1,238 @@
+//! Per storage class limits of the request rate and bandwidth.
+//!
+//! Each limit is a token bucket which refills at the configured rate and holds
+//! at most one second worth of tokens. Requests are admitted once enough
+//! tokens are available, larger requests than the bucket can hold wait for a
+//! full bucket and leave a debt.
+//!
+//! Requests issued with [IoPriority::Background] do not take the last quarter
+//! of a bucket and wait while any foreground or commit request is waiting, so
+//! that background work can not starve them.
+use crate::clock;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Limits of a single storage class, see [crate::storage_pool::TierConfiguration].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct QosConfiguration {
+    /// Maximum number of reads and writes per second.
+    pub iops: Option<u32>,
+    /// Maximum number of bytes read and written per second.
+    pub bandwidth: Option<u64>,
+}
+
+/// The priority of the IO requests issued by a thread, from the highest to
+/// the lowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoPriority {
+    /// Latency critical requests like lookups and range queries, the default.
+    Foreground,
+    /// Requests of syncs, which block the writers of the synced datasets.
+    Commit,
+    /// Requests of maintenance work like migrations or defragmentation.
+    Background,
+}
+
+thread_local! {
+    static PRIORITY: Cell<IoPriority> = Cell::new(IoPriority::Foreground);
+}
+
+/// Executes `f` with all IO requests it issues on this thread treated with the
+/// given `priority`.
+pub fn with_io_priority<T, F: FnOnce() -> T>(priority: IoPriority, f: F) -> T {
+    struct Restore(IoPriority);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            PRIORITY.with(|p| p.set(self.0));
+        }
+    }
+
+    let _restore = Restore(PRIORITY.with(|p| p.replace(priority)));
+    f()
+}
+
+/// Returns the priority of the IO requests issued by the current thread.
+pub fn io_priority() -> IoPriority {
+    PRIORITY.with(Cell::get)
+}
+
+// Share of a bucket which is kept for foreground requests.
+const BACKGROUND_RESERVE: f64 = 0.25;
+// Interval in which background requests check for waiting foreground requests.
+const BACKGROUND_BACKOFF: Duration = Duration::from_millis(1);
+
+struct TokenBucket {
+    // Tokens per second, which is also the capacity.
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                refilled: clock::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.refilled = now;
+    }
+
+    // The time until `cost` tokens may be taken while `reserve` tokens are
+    // left.
+    fn wait_time(&self, state: &BucketState, cost: f64, reserve: f64) -> Duration {
+        let required = cost.min(self.rate - reserve) + reserve;
+        Duration::from_secs_f64((required - state.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Throttles the requests to a storage class.
+pub(crate) struct Throttle {
+    iops: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+    // Waiting requests with a higher priority than background.
+    foreground_waiting: AtomicUsize,
+}
+
+impl Throttle {
+    /// Returns `None` if `config` imposes no limits.
+    pub(crate) fn new(config: &QosConfiguration) -> Option<Self> {
+        if config.iops.is_none() && config.bandwidth.is_none() {
+            return None;
+        }
+        Some(Throttle {
+            iops: config.iops.map(|iops| TokenBucket::new(iops.max(1) as f64)),
+            bandwidth: config
+                .bandwidth
+                .map(|bandwidth| TokenBucket::new(bandwidth.max(1) as f64)),
+            foreground_waiting: AtomicUsize::new(0),
+        })
+    }
+
+    /// Blocks until a request of `bytes` bytes may be issued with the
+    /// priority of the current thread.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let priority = io_priority();
+        if priority != IoPriority::Background {
+            self.foreground_waiting.fetch_add(1, Ordering::Relaxed);
+        }
+        loop {
+            if priority == IoPriority::Background
+                && self.foreground_waiting.load(Ordering::Relaxed) > 0
+            {
+                clock::sleep(BACKGROUND_BACKOFF);
+                continue;
+            }
+            match self.try_acquire(bytes, priority) {
+                Ok(()) => break,
+                Err(wait) => clock::sleep(wait),
+            }
+        }
+        if priority != IoPriority::Background {
+            self.foreground_waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn try_acquire(&self, bytes: u64, priority: IoPriority) -> Result<(), Duration> {
+        let now = clock::now();
+        let requests = [
+            (self.iops.as_ref(), 1.0),
+            (self.bandwidth.as_ref(), bytes as f64),
+        ];
+        let mut states: Vec<_> = requests
+            .iter()
+            .filter_map(|&(bucket, cost)| bucket.map(|bucket| (bucket, bucket.state.lock(), cost)))
+            .collect();
+
+        let mut wait = Duration::ZERO;
+        for (bucket, state, cost) in states.iter_mut() {
+            bucket.refill(state, now);
+            let reserve = match priority {
+                IoPriority::Foreground | IoPriority::Commit => 0.0,
+                IoPriority::Background => bucket.rate * BACKGROUND_RESERVE,
+            };
+            wait = wait.max(bucket.wait_time(state, *cost, reserve));
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        for (_, state, cost) in states.iter_mut() {
+            state.tokens -= *cost;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_io_priority, IoPriority, QosConfiguration, Throttle};
+    use std::time::Duration;
+
+    #[test]
+    fn unlimited_has_no_throttle() {
+        assert!(Throttle::new(&QosConfiguration::default()).is_none());
+    }
+
+    #[test]
+    fn requests_are_limited() {
+        let throttle = Throttle::new(&QosConfiguration {
+            iops: Some(10),
+            bandwidth: None,
+        })
+        .unwrap();
+        for _ in 0..10 {
+            assert!(throttle.try_acquire(0, IoPriority::Foreground).is_ok());
+        }
+        let wait = throttle.try_acquire(0, IoPriority::Foreground).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn background_leaves_reserve() {
+        let throttle = Throttle::new(&QosConfiguration {
+            iops: None,
+            bandwidth: Some(400),
+        })
+        .unwrap();
+        assert!(throttle.try_acquire(300, IoPriority::Background).is_ok());
+        assert!(throttle.try_acquire(10, IoPriority::Background).is_err());
+        assert!(throttle.try_acquire(100, IoPriority::Foreground).is_ok());
+    }
+
+    #[test]
+    fn large_requests_leave_debt() {
+        let throttle = Throttle::new(&QosConfiguration {
+            iops: None,
+            bandwidth: Some(400),
+        })
+        .unwrap();
+        assert!(throttle.try_acquire(800, IoPriority::Foreground).is_ok());
+        assert!(throttle.try_acquire(1, IoPriority::Foreground).is_err());
+    }
+
+    #[test]
+    fn priority_is_restored() {
+        with_io_priority(IoPriority::Background, || {
+            assert_eq!(super::io_priority(), IoPriority::Background);
+        });
+        assert_eq!(super::io_priority(), IoPriority::Foreground);
+    }
+}
@@ -1,21 +1,37 @@
 use super::{
-    errors::Result as StoragePoolResult, DiskOffset, StoragePoolConfiguration, StoragePoolLayer,
+    errors::{Error as StoragePoolError, Result as StoragePoolResult},
+    scheduler::{Dispatched, Scheduler},
+    throttle::{QosConfiguration, Throttle},
+    DiskOffset, StoragePoolConfiguration, StoragePoolLayer, TierConfiguration, MAX_TIER_NAME_LEN,
     NUM_STORAGE_CLASSES,
 };
 use crate::{
     bounded_future_queue::BoundedFutureQueue,
     buffer::Buf,
     checksum::Checksum,
+    clock,
     vdev::{self, Block, Dev, Error as VdevError, Vdev, VdevRead, VdevWrite},
     PreferredAccessType, StoragePreference,
 };
 use futures::{
     executor::{block_on, ThreadPool},
+    future,
     prelude::*,
     stream::FuturesUnordered,
     task::SpawnExt,
 };
-use std::{convert::TryInto, marker::PhantomData, ops::Index, pin::Pin, sync::Arc};
+use parking_lot::RwLock;
+use std::{
+    convert::TryInto,
+    marker::PhantomData,
+    ops::Index,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 /// Actual implementation of the `StoragePoolLayer`.
 #[derive(Clone)]
@@ -29,8 +45,11 @@ pub(super) type WriteBackQueue = BoundedFutureQueue<
 >;
 
 struct StorageTier {
-    devs: Box<[Dev]>,
+    devs: Vec<Arc<Dev>>,
     preferred_access_type: PreferredAccessType,
+    throttle: Option<Arc<Throttle>>,
+    scheduler: Option<Arc<Scheduler>>,
+    name: Option<String>,
 }
 
 impl StorageTier {
@@ -39,7 +58,7 @@ impl StorageTier {
     }
 
     fn iter(&self) -> impl Iterator<Item = &Dev> {
-        self.devs.iter()
+        self.devs.iter().map(|dev| &**dev)
     }
 }
 
@@ -54,67 +73,224 @@ impl Index<usize> for StorageTier {
 impl Default for StorageTier {
     fn default() -> Self {
         Self {
-            devs: Box::new([]),
+            devs: Vec::new(),
             preferred_access_type: PreferredAccessType::Unknown,
+            throttle: None,
+            scheduler: None,
+            name: None,
         }
     }
 }
 
-impl From<(Box<[Dev]>, PreferredAccessType)> for StorageTier {
-    fn from(item: (Box<[Dev]>, PreferredAccessType)) -> Self {
+impl From<(Vec<Dev>, &TierConfiguration)> for StorageTier {
+    fn from(item: (Vec<Dev>, &TierConfiguration)) -> Self {
         Self {
-            devs: item.0,
-            preferred_access_type: item.1,
+            devs: item.0.into_iter().map(Arc::new).collect(),
+            preferred_access_type: item.1.preferred_access_type,
+            throttle: Throttle::new(&item.1.qos).map(Arc::new),
+            scheduler: Scheduler::new(&item.1.scheduler).map(Arc::new),
+            name: item.1.name.clone(),
         }
     }
 }
 
 struct Inner<C: Checksum> {
-    tiers: [StorageTier; NUM_STORAGE_CLASSES],
+    // Tiers only ever grow, vdevs keep their disk id once added.
+    tiers: [RwLock<StorageTier>; NUM_STORAGE_CLASSES],
+    allow_degraded: bool,
     _check: PhantomData<Box<C>>,
     write_back_queue: WriteBackQueue,
-    pool: ThreadPool,
+    executor: Executor,
+    // Moving averages of the write durations per storage class in
+    // nanoseconds, zero until the first write has finished.
+    write_latency: [AtomicU64; NUM_STORAGE_CLASSES],
+}
+
+// Runs the requests to the vdevs.
+enum Executor {
+    Pool(ThreadPool),
+    // Within a simulation requests complete on the issuing thread right away.
+    Inline,
 }
 
 impl<C: Checksum> Inner<C> {
-    fn by_offset(&self, offset: DiskOffset) -> &Dev {
-        &self.tiers[offset.storage_class() as usize][offset.disk_id() as usize]
+    fn by_offset(&self, offset: DiskOffset) -> Arc<Dev> {
+        Arc::clone(
+            &self.tiers[offset.storage_class() as usize].read().devs[offset.disk_id() as usize],
+        )
+    }
+
+    // Blocks until the tier of `offset` admits a request of `bytes` bytes.
+    fn throttle(&self, offset: DiskOffset, bytes: u64) {
+        let throttle = self.tiers[offset.storage_class() as usize]
+            .read()
+            .throttle
+            .clone();
+        if let Some(throttle) = throttle {
+            throttle.acquire(bytes);
+        }
+    }
+
+    // Blocks until the tier of `offset` admits another request in flight.
+    fn dispatch(&self, offset: DiskOffset) -> Option<Dispatched> {
+        let scheduler = self.tiers[offset.storage_class() as usize]
+            .read()
+            .scheduler
+            .clone();
+        scheduler.map(|scheduler| scheduler.dispatch())
+    }
+
+    // Weighs in the duration of a write to `storage_class` by an eighth.
+    fn record_write_latency(&self, storage_class: u8, latency: Duration) {
+        let sample = (latency.as_nanos() as u64).max(1);
+        let _ = self.write_latency[storage_class as usize].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |avg| match avg {
+                0 => Some(sample),
+                avg => Some(avg - avg / 8 + sample / 8),
+            },
+        );
+    }
+
+    // Writes `data` to `offset` and records the latency of the write.
+    async fn write(&self, data: Buf, offset: DiskOffset) -> Result<(), VdevError> {
+        let start = clock::now();
+        let res = self
+            .by_offset(offset)
+            .write(data, offset.block_offset())
+            .await;
+        if res.is_ok() {
+            self.record_write_latency(offset.storage_class(), clock::elapsed(start));
+        }
+        res
+    }
+
+    fn class_by_name(&self, name: &str) -> Option<u8> {
+        self.tiers
+            .iter()
+            .position(|tier| tier.read().name.as_deref() == Some(name))
+            .map(|class| class as u8)
+    }
+
+    // Fails if `name` is invalid or used by another storage class.
+    fn check_name(&self, storage_class: u8, name: &str) -> Result<(), String> {
+        check_tier_name(name)?;
+        match self.class_by_name(name) {
+            Some(class) if class != storage_class => Err(format!(
+                "tier name {name} is already used by storage class {class}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn devs(&self) -> Vec<Arc<Dev>> {
+        self.tiers
+            .iter()
+            .flat_map(|tier| tier.read().devs.clone())
+            .collect()
+    }
+
+    // Reads `size` blocks of all top-level vdevs at the offset `offset`
+    // returns for each of them.  Vdevs which fail to read are skipped unless
+    // all of them fail, so that a single damaged vdev does not prevent
+    // finding a superblock.
+    fn read_raw_at<F>(&self, size: Block<u32>, offset: F) -> Result<Vec<Buf>, VdevError>
+    where
+        F: Fn(&Dev) -> Option<Block<u64>>,
+    {
+        let mut vec = Vec::new();
+        let mut error = None;
+        for vdev in self.devs() {
+            let offset = match offset(&*vdev) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            match block_on(vdev.read_raw(size, offset).into_future()) {
+                Ok(v) => vec.extend(v),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if vec.is_empty() => Err(e),
+            _ => Ok(vec),
+        }
     }
 }
 
+// Overwrites the first and the last two blocks of `dev` with `empty`.
+fn clear_superblocks(dev: &Dev, empty: &Buf) -> Result<(), VdevError> {
+    for offset in [Block(0), Block(1)] {
+        block_on(dev.write_raw(empty.clone(), offset))?;
+    }
+    for offset in [Block(1), Block(2)] {
+        if let Some(offset) = tail_offset(dev, offset) {
+            block_on(dev.write_raw(empty.clone(), offset))?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the offset `offset` blocks before the end of the smallest leaf vdev
+// of `dev`, if it is that large.
+fn tail_offset(dev: &Dev, offset: Block<u64>) -> Option<Block<u64>> {
+    let mut size = None;
+    dev.for_each_child(&mut |child| {
+        size = Some(size.map_or(child.size(), |size: Block<u64>| size.min(child.size())));
+    });
+    let size = size.unwrap_or_else(|| dev.size());
+    size.0.checked_sub(offset.0).map(Block)
+}
+
 impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
     type Checksum = C;
     type Configuration = StoragePoolConfiguration;
     type Metrics = StoragePoolMetrics;
 
     fn new(configuration: &Self::Configuration) -> StoragePoolResult<Self> {
-        let tiers: [StorageTier; NUM_STORAGE_CLASSES] = {
-            let mut vec: Vec<StorageTier> = configuration
+        let tiers: [RwLock<StorageTier>; NUM_STORAGE_CLASSES] = {
+            let mut vec: Vec<RwLock<StorageTier>> = configuration
                 .tiers
                 .iter()
                 .map(|tier_cfg| {
                     tier_cfg
-                        .build()
-                        .map(Vec::into_boxed_slice)
-                        .map(|tier| (tier, tier_cfg.preferred_access_type).into())
+                        .build(configuration.allow_degraded)
+                        .map(|tier| RwLock::new((tier, tier_cfg).into()))
                 })
                 .collect::<Result<Vec<_>, _>>()?;
 
             assert!(vec.len() <= NUM_STORAGE_CLASSES, "too many storage classes");
             vec.resize_with(NUM_STORAGE_CLASSES, Default::default);
-            let boxed: Box<[StorageTier; NUM_STORAGE_CLASSES]> =
+            let boxed: Box<[RwLock<StorageTier>; NUM_STORAGE_CLASSES]> =
                 vec.into_boxed_slice().try_into().map_err(|_| ()).unwrap();
             *boxed
         };
 
-        let devices_len = tiers.iter().map(|tier| tier.len()).sum::<usize>();
+        for (class, tier) in tiers.iter().enumerate() {
+            if let Some(name) = &tier.read().name {
+                check_tier_name(name).map_err(StoragePoolError::InvalidConfiguration)?;
+                if tiers[..class]
+                    .iter()
+                    .any(|other| other.read().name.as_ref() == Some(name))
+                {
+                    return Err(StoragePoolError::InvalidConfiguration(format!(
+                        "duplicate tier name {name}"
+                    )));
+                }
+            }
+        }
+
+        let devices_len = tiers.iter().map(|tier| tier.read().len()).sum::<usize>();
         let queue_depth = configuration.queue_depth_factor as usize * devices_len;
         Ok(StoragePoolUnit {
             inner: Arc::new(Inner {
                 tiers,
+                allow_degraded: configuration.allow_degraded,
                 _check: PhantomData::default(),
                 write_back_queue: BoundedFutureQueue::new(queue_depth),
-                pool: {
+                executor: if clock::simulated() {
+                    Executor::Inline
+                } else {
                     let mut pool = ThreadPool::builder();
                     pool.name_prefix("storage_pool");
                     if let Some(size) = configuration.thread_pool_size {
@@ -128,8 +304,9 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
                             core_affinity::set_for_current(core_ids[idx % core_ids.len()]);
                         });
                     }
-                    pool.create()?
+                    Executor::Pool(pool.create()?)
                 },
+                write_latency: Default::default(),
             }),
         })
     }
@@ -142,29 +319,49 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
         offset: DiskOffset,
         checksum: C,
     ) -> Result<Self::ReadAsync, VdevError> {
+        self.inner.throttle(offset, size.to_bytes() as u64);
         // TODO: can move this onto pool without deadlock?
         self.inner.write_back_queue.wait(&offset)?;
+        let dispatched = self.inner.dispatch(offset);
         let inner = self.inner.clone();
-        Ok(Box::pin(self.inner.pool.spawn_with_handle(async move {
+        let read = async move {
             // inner.write_back_queue.wait_async(offset).await;
-            inner
+            let res = inner
                 .by_offset(offset)
                 .read(size, offset.block_offset(), checksum)
-                .await
-        })?))
+                .await;
+            drop(dispatched);
+            res
+        };
+        Ok(match &self.inner.executor {
+            Executor::Pool(pool) => Box::pin(pool.spawn_with_handle(read)?),
+            Executor::Inline => Box::pin(future::ready(block_on(read))),
+        })
     }
 
     fn begin_write(&self, data: Buf, offset: DiskOffset) -> Result<(), VdevError> {
+        self.inner.throttle(offset, data.len() as u64);
+        let dispatched = self.inner.dispatch(offset);
         let inner = self.inner.clone();
 
+        let pool = match &self.inner.executor {
+            Executor::Pool(pool) => pool,
+            Executor::Inline => {
+                // The completed write stays queued until it is drained.
+                let res = block_on(inner.write(data, offset));
+                drop(dispatched);
+                return self
+                    .inner
+                    .write_back_queue
+                    .enqueue(offset, Box::pin(future::ready(res)));
+            }
+        };
         let (enqueue_done, wait_for_enqueue) = futures::channel::oneshot::channel();
-        let write = self.inner.pool.spawn_with_handle(async move {
+        let write = pool.spawn_with_handle(async move {
             wait_for_enqueue.await.unwrap();
 
-            let res = inner
-                .by_offset(offset)
-                .write(data, offset.block_offset())
-                .await;
+            let res = inner.write(data, offset).await;
+            drop(dispatched);
 
             // TODO: what about multiple writes to same offset?
             // NOTE: This is currently covered in the tests and fails as expected
@@ -183,12 +380,39 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
         ret
     }
 
-    fn write_raw(&self, data: Buf, offset: Block<u64>) -> Result<(), VdevError> {
-        let vec = self
+    fn write_bytes(&self, data: &[u8], offset: DiskOffset) -> Result<(), VdevError> {
+        self.inner.throttle(offset, data.len() as u64);
+        // A queued write to the same blocks must not overtake this one.
+        self.inner.write_back_queue.wait(&offset)?;
+        let start = clock::now();
+        let res = self
             .inner
-            .tiers
+            .by_offset(offset)
+            .write_bytes(data, offset.block_offset().to_bytes());
+        if res.is_ok() {
+            self.inner
+                .record_write_latency(offset.storage_class(), clock::elapsed(start));
+        }
+        res
+    }
+
+    fn read_bytes(&self, len: u32, offset: DiskOffset, checksum: C) -> Result<Buf, VdevError> {
+        self.inner.throttle(offset, len as u64);
+        self.inner.write_back_queue.wait(&offset)?;
+        let mut buf = Buf::zeroed(Block::round_up_from_bytes(len)).into_full_mut();
+        self.inner.by_offset(offset).read_bytes(
+            &mut buf.as_mut()[..len as usize],
+            offset.block_offset().to_bytes(),
+        )?;
+        let buf = buf.into_full_buf();
+        checksum.verify(&buf[..len as usize])?;
+        Ok(buf)
+    }
+
+    fn write_raw(&self, data: Buf, offset: Block<u64>) -> Result<(), VdevError> {
+        let devs = self.inner.devs();
+        let vec = devs
             .iter()
-            .flat_map(|tier| tier.iter())
             .map(|vdev| vdev.write_raw(data.clone(), offset))
             .collect::<FuturesUnordered<_>>()
             .try_collect();
@@ -196,26 +420,50 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
     }
 
     fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>, VdevError> {
-        let mut vec = Vec::new();
-        for class in self.inner.tiers.iter() {
-            for vdev in class.iter() {
-                let v = block_on(vdev.read_raw(size, offset).into_future())?;
-                vec.extend(v);
-            }
-        }
-        Ok(vec)
+        self.inner.read_raw_at(size, |_| Some(offset))
+    }
+
+    fn write_raw_tail(&self, data: Buf, offset: Block<u64>) -> Result<(), VdevError> {
+        let devs = self.inner.devs();
+        let vec = devs
+            .iter()
+            .filter_map(|vdev| Some(vdev.write_raw(data.clone(), tail_offset(vdev, offset)?)))
+            .collect::<FuturesUnordered<_>>()
+            .try_collect();
+        block_on(vec).map(|_: Vec<()>| ())
+    }
+
+    fn read_raw_tail(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>, VdevError> {
+        self.inner
+            .read_raw_at(size, |vdev| tail_offset(vdev, offset))
     }
 
     fn actual_size(&self, storage_class: u8, disk_id: u16, size: Block<u32>) -> Block<u32> {
-        self.inner.tiers[storage_class as usize][disk_id as usize].actual_size(size)
+        self.inner.tiers[storage_class as usize].read()[disk_id as usize].actual_size(size)
     }
 
     fn size_in_blocks(&self, storage_class: u8, disk_id: u16) -> Block<u64> {
-        self.inner.tiers[storage_class as usize][disk_id as usize].size()
+        self.inner.tiers[storage_class as usize].read()[disk_id as usize].size()
+    }
+
+    fn refresh_size(&self, storage_class: u8, disk_id: u16) -> Result<Block<u64>, VdevError> {
+        self.inner.tiers[storage_class as usize].read()[disk_id as usize].refresh_size()
     }
 
     fn num_disks(&self, storage_class: u8, disk_id: u16) -> usize {
-        self.inner.tiers[storage_class as usize][disk_id as usize].num_disks()
+        self.inner.tiers[storage_class as usize].read()[disk_id as usize].num_disks()
+    }
+
+    fn take_repairs(&self, storage_class: u8, disk_id: u16) -> Vec<(String, DiskOffset)> {
+        let tier = self.inner.tiers[storage_class as usize].read();
+        match tier.devs.get(disk_id as usize) {
+            Some(vdev) => vdev
+                .take_repairs()
+                .into_iter()
+                .map(|(disk, offset)| (disk, DiskOffset::new(storage_class, disk_id, offset)))
+                .collect(),
+            None => Vec::new(),
+        }
     }
 
     fn effective_free_size(
@@ -224,11 +472,12 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
         disk_id: u16,
         free_size: Block<u64>,
     ) -> Block<u64> {
-        self.inner.tiers[storage_class as usize][disk_id as usize].effective_free_size(free_size)
+        self.inner.tiers[storage_class as usize].read()[disk_id as usize]
+            .effective_free_size(free_size)
     }
 
     fn disk_count(&self, storage_class: u8) -> u16 {
-        self.inner.tiers[storage_class as usize].len() as u16
+        self.inner.tiers[storage_class as usize].read().len() as u16
     }
 
     fn storage_class_count(&self) -> u8 {
@@ -239,35 +488,144 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
         trace!("Entering flush");
         self.inner.write_back_queue.flush()?;
         trace!("Entering flush");
-        for tier in self.inner.tiers.iter() {
-            for vdev in tier.iter() {
-                vdev.flush()?;
-            }
+        for vdev in self.inner.devs() {
+            vdev.flush()?;
         }
         trace!("Leaving flush");
         Ok(())
     }
 
+    fn write_latency(&self, storage_class: u8) -> Option<Duration> {
+        match self.inner.write_latency[storage_class as usize].load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
     fn metrics(&self) -> Self::Metrics {
-        let mut tiers = [None, None, None, None];
+        let mut tiers: [Option<StorageTierMetrics>; NUM_STORAGE_CLASSES] = Default::default();
 
         for (tier, out) in self.inner.tiers.iter().zip(tiers.iter_mut()) {
             *out = Some(StorageTierMetrics {
-                vdevs: tier.iter().map(Vdev::stats).collect(),
+                vdevs: tier.read().iter().map(Vdev::stats).collect(),
             });
         }
 
         StoragePoolMetrics { tiers }
     }
 
+    fn faulted_vdevs(&self, storage_class: u8) -> Vec<String> {
+        let mut faulted = Vec::new();
+        for vdev in self.inner.tiers[storage_class as usize].read().iter() {
+            vdev.for_each_child(&mut |child| {
+                if child.is_faulted() {
+                    faulted.push(child.id().to_string());
+                }
+            });
+        }
+        faulted
+    }
+
+    fn add_vdevs(&self, storage_class: u8, config: &TierConfiguration) -> Result<u16, VdevError> {
+        if let Some(name) = &config.name {
+            self.inner
+                .check_name(storage_class, name)
+                .map_err(VdevError::Write)?;
+        }
+        let mut tier = self.inner.tiers[storage_class as usize].write();
+        let first = tier.len();
+        // Disk ids are limited to 10 bits, see `DiskOffset`.
+        if first + config.top_level_vdevs.len() > 1 << 10 {
+            return Err(VdevError::Write(format!(
+                "too many vdevs in storage class {storage_class}"
+            )));
+        }
+        let devs = config
+            .top_level_vdevs
+            .iter()
+            .enumerate()
+            .map(|(n, vdev)| vdev.build(first + n, self.inner.allow_degraded))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Stale superblocks of a previous pool must not be picked up on the
+        // next open.
+        let empty = Buf::zeroed(Block(1));
+        for dev in devs.iter() {
+            clear_superblocks(dev, &empty)?;
+        }
+
+        if first == 0 {
+            tier.preferred_access_type = config.preferred_access_type;
+            tier.throttle = Throttle::new(&config.qos).map(Arc::new);
+            tier.scheduler = Scheduler::new(&config.scheduler).map(Arc::new);
+            if config.name.is_some() {
+                tier.name = config.name.clone();
+            }
+        }
+        tier.devs.extend(devs.into_iter().map(Arc::new));
+        Ok(first as u16)
+    }
+
+    fn remove_last_vdev(&self, storage_class: u8) -> Result<(), VdevError> {
+        let dev = self.inner.tiers[storage_class as usize]
+            .write()
+            .devs
+            .pop()
+            .ok_or(VdevError::NoVdevs(storage_class))?;
+        clear_superblocks(&dev, &Buf::zeroed(Block(1)))?;
+        dev.flush()?;
+        Ok(())
+    }
+
+    fn is_byte_addressable(&self, storage_class: u8) -> bool {
+        let tier = self.inner.tiers[storage_class as usize].read();
+        tier.len() > 0 && tier.iter().all(Vdev::is_byte_addressable)
+    }
+
+    fn preferred_access_type(&self, storage_class: u8) -> crate::PreferredAccessType {
+        self.inner.tiers[storage_class as usize]
+            .read()
+            .preferred_access_type
+    }
+
     fn access_type_preference(&self, t: crate::PreferredAccessType) -> crate::StoragePreference {
         for (pref, tier) in self.inner.tiers.iter().enumerate() {
-            if tier.preferred_access_type == t {
+            if tier.read().preferred_access_type == t {
                 return StoragePreference::from_u8(pref as u8);
             }
         }
         StoragePreference::NONE
     }
+
+    fn tier_name(&self, storage_class: u8) -> Option<String> {
+        self.inner.tiers[storage_class as usize].read().name.clone()
+    }
+
+    fn set_qos(&self, storage_class: u8, qos: &QosConfiguration) {
+        self.inner.tiers[storage_class as usize].write().throttle =
+            Throttle::new(qos).map(Arc::new);
+    }
+
+    fn set_tier_name(&self, storage_class: u8, name: String) -> Result<(), VdevError> {
+        self.inner
+            .check_name(storage_class, &name)
+            .map_err(VdevError::Write)?;
+        self.inner.tiers[storage_class as usize].write().name = Some(name);
+        Ok(())
+    }
+
+    fn tier(&self, name: &str) -> Option<StoragePreference> {
+        self.inner.class_by_name(name).map(StoragePreference::new)
+    }
+}
+
+pub(crate) fn check_tier_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_TIER_NAME_LEN {
+        return Err(format!(
+            "tier names must have between 1 and {MAX_TIER_NAME_LEN} bytes, got {name:?}"
+        ));
+    }
+    Ok(())
 }
 
 #[derive(serde::Serialize)]
@@ -292,6 +292,20 @@ impl DefaultMessageAction {
             value,
         }])
     }
+
+    /// Return a new message which will set each of the given bit ranges, given
+    /// as `(offset_bits, amount_bits, value)`, in order.
+    pub fn upsert_bit_ranges_msg(ranges: &[(u32, u32, bool)]) -> SlicedCowBytes {
+        let upserts = ranges
+            .iter()
+            .map(|&(offset_bits, amount_bits, value)| Upsert::Bits {
+                offset_bits,
+                amount_bits,
+                value,
+            })
+            .collect::<Vec<_>>();
+        Self::build_upsert_msg(&upserts)
+    }
 }
 
 impl MessageAction for DefaultMessageAction {
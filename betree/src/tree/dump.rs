@@ -0,0 +1,249 @@
+//! A stable, versioned description of the shape of a tree, e.g. for
+//! visualization and debugging tools.
+//!
+//! Unlike the internal node representation, the schema of [TreeDump] only
+//! changes together with [TREE_DUMP_VERSION].  Fields may be added within a
+//! version, consumers should therefore ignore unknown fields.  A dump
+//! serialized to JSON looks like this:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "detail": "full",
+//!   "root": {
+//!     "type": "internal",
+//!     "level": 1,
+//!     "storage": 255,
+//!     "system_storage": 255,
+//!     "children": [
+//!       {
+//!         "to": "6b6579",
+//!         "storage": 255,
+//!         "node": { "type": "leaf", "level": 0, ... }
+//!       },
+//!       ...
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+//! Storage preferences are given by their class, `255` denotes no
+//! preference.  Keys are encoded as lowercase hexadecimal strings.
+use super::imp::{ChildInfo, NodeInfo};
+use crate::StoragePreference;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Write;
+
+/// The version of the [TreeDump] schema produced by this crate.
+pub const TREE_DUMP_VERSION: u32 = 1;
+
+/// How much detail a [TreeDump] contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpDetail {
+    /// All nodes including the keys bounding them.
+    Full,
+    /// All nodes without any keys, which keeps dumps of large trees small.
+    Compact,
+}
+
+/// The shape of a tree, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeDump {
+    /// The schema version, [TREE_DUMP_VERSION] for dumps of this crate.
+    pub version: u32,
+    /// The detail the dump has been created with.
+    pub detail: DumpDetail,
+    /// The root node of the tree.
+    pub root: NodeDump,
+}
+
+/// A single node of a [TreeDump].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NodeDump {
+    /// A node with children.
+    Internal {
+        /// The height of the node, leaves are at level 0.
+        level: u32,
+        /// The storage preference of the node.
+        storage: StoragePreference,
+        /// The storage preference set on the node by the system, e.g. by
+        /// migration policies.
+        system_storage: StoragePreference,
+        /// The children from left to right.
+        children: Vec<ChildDump>,
+    },
+    /// A leaf which has been modified since it was read from disk.
+    Leaf {
+        /// The height of the node, always 0.
+        level: u32,
+        /// The storage preference of the node.
+        storage: StoragePreference,
+        /// The storage preference set on the node by the system.
+        system_storage: StoragePreference,
+        /// The number of entries.
+        entry_count: u64,
+    },
+    /// A leaf in its on-disk representation.
+    Packed {
+        /// The number of entries.
+        entry_count: u64,
+        /// The smallest key, omitted in compact dumps and in empty leaves.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        first_key: Option<HexBytes>,
+        /// The largest key, omitted in compact dumps and in empty leaves.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_key: Option<HexBytes>,
+    },
+}
+
+/// A child of an internal node in a [TreeDump].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildDump {
+    /// The exclusive lower bound of the keys of the child, omitted in compact
+    /// dumps and for the leftmost child.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<HexBytes>,
+    /// The inclusive upper bound of the keys of the child, omitted in compact
+    /// dumps and for the rightmost child.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<HexBytes>,
+    /// The storage preference of the child.
+    pub storage: StoragePreference,
+    /// The child itself.
+    pub node: NodeDump,
+}
+
+/// Bytes serialized as a lowercase hexadecimal string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl Serialize for HexBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = String::with_capacity(self.0.len() * 2);
+        for b in &self.0 {
+            write!(s, "{:02x}", b).unwrap();
+        }
+        serializer.serialize_str(&s)
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s.len() % 2 != 0 {
+            return Err(de::Error::custom("odd number of hexadecimal digits"));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| de::Error::custom("invalid hexadecimal digit"))
+            })
+            .collect::<Result<_, _>>()
+            .map(HexBytes)
+    }
+}
+
+impl TreeDump {
+    pub(crate) fn new(root: NodeInfo, detail: DumpDetail) -> Self {
+        TreeDump {
+            version: TREE_DUMP_VERSION,
+            detail,
+            root: NodeDump::new(root, detail),
+        }
+    }
+}
+
+impl NodeDump {
+    fn new(info: NodeInfo, detail: DumpDetail) -> Self {
+        let keys = |key: Option<Vec<u8>>| match detail {
+            DumpDetail::Full => key.map(HexBytes),
+            DumpDetail::Compact => None,
+        };
+        match info {
+            NodeInfo::Internal {
+                level,
+                storage,
+                system_storage,
+                children,
+            } => NodeDump::Internal {
+                level,
+                storage,
+                system_storage,
+                children: children
+                    .into_iter()
+                    .map(|child: ChildInfo| ChildDump {
+                        from: keys(child.from.map(|key| key.0)),
+                        to: keys(child.to.map(|key| key.0)),
+                        storage: child.storage,
+                        node: NodeDump::new(child.child, detail),
+                    })
+                    .collect(),
+            },
+            NodeInfo::Leaf {
+                level,
+                storage,
+                system_storage,
+                entry_count,
+            } => NodeDump::Leaf {
+                level,
+                storage,
+                system_storage,
+                entry_count: entry_count as u64,
+            },
+            NodeInfo::Packed { entry_count, range } => {
+                let mut range = range.into_iter().map(|key| key.0);
+                NodeDump::Packed {
+                    entry_count: entry_count as u64,
+                    first_key: keys(range.next()),
+                    last_key: keys(range.next()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChildDump, DumpDetail, HexBytes, NodeDump, TreeDump, TREE_DUMP_VERSION};
+    use crate::StoragePreference;
+
+    #[test]
+    fn roundtrip() {
+        let dump = TreeDump {
+            version: TREE_DUMP_VERSION,
+            detail: DumpDetail::Full,
+            root: NodeDump::Internal {
+                level: 1,
+                storage: StoragePreference::NONE,
+                system_storage: StoragePreference::FAST,
+                children: vec![ChildDump {
+                    from: None,
+                    to: Some(HexBytes(vec![0x00, 0xab, 0x7f])),
+                    storage: StoragePreference::FASTEST,
+                    node: NodeDump::Packed {
+                        entry_count: 2,
+                        first_key: Some(HexBytes(vec![])),
+                        last_key: None,
+                    },
+                }],
+            },
+        };
+        let json = serde_json::to_value(&dump).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["root"]["type"], "internal");
+        assert_eq!(json["root"]["children"][0]["to"], "00ab7f");
+        assert!(json["root"]["children"][0].get("from").is_none());
+        assert_eq!(serde_json::from_value::<TreeDump>(json).unwrap(), dump);
+    }
+
+    #[test]
+    fn invalid_hex() {
+        assert!(serde_json::from_str::<HexBytes>("\"abc\"").is_err());
+        assert!(serde_json::from_str::<HexBytes>("\"zz\"").is_err());
+        assert!(serde_json::from_str::<HexBytes>("\"ü0\"").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(missing_docs, unused_doc_comments)]
+use crate::database::ErrorCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -16,3 +17,13 @@ pub enum Error {
     #[error("Invalid range specification")]
     InvalidRange,
 }
+
+impl Error {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::DmuError { source } => source.code(),
+            Error::EmptyKey | Error::InvalidRange => ErrorCode::InvalidArgument,
+        }
+    }
+}
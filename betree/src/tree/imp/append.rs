@@ -0,0 +1,53 @@
+//! Detection of sequential appends to a tree.
+//!
+//! Time series and objects written chunk by chunk insert keys in increasing
+//! order, which all end up in the rightmost leaf.  Once such a run has been
+//! detected, insertions fetch the nodes on the rightmost path instead of
+//! buffering their messages in each internal node on the way, so that the
+//! messages are not rewritten once per level when they are flushed down.
+
+// Number of consecutive insertions with increasing keys from which on
+// insertions are regarded as appends.
+const MIN_APPEND_RUN: u32 = 16;
+
+/// Tracks the order of the keys inserted into a tree.
+#[derive(Debug, Default)]
+pub(super) struct AppendDetector {
+    last_key: Vec<u8>,
+    run: u32,
+}
+
+impl AppendDetector {
+    /// Records the insertion of `key` and returns whether it continues a run
+    /// of insertions with increasing keys long enough to be regarded as
+    /// appends.
+    pub(super) fn record(&mut self, key: &[u8]) -> bool {
+        if key > &self.last_key[..] {
+            self.run = self.run.saturating_add(1);
+        } else {
+            self.run = 0;
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.run >= MIN_APPEND_RUN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppendDetector, MIN_APPEND_RUN};
+
+    #[test]
+    fn detects_increasing_keys() {
+        let mut detector = AppendDetector::default();
+        let appends = (1..=MIN_APPEND_RUN)
+            .map(|idx| detector.record(&idx.to_be_bytes()))
+            .collect::<Vec<_>>();
+        assert!(!appends[..MIN_APPEND_RUN as usize - 1].contains(&true));
+        assert!(appends[MIN_APPEND_RUN as usize - 1]);
+
+        // Overwrites and insertions further left end the run.
+        assert!(!detector.record(&MIN_APPEND_RUN.to_be_bytes()));
+        assert!(!detector.record(&(MIN_APPEND_RUN + 1).to_be_bytes()));
+    }
+}
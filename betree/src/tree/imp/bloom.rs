@@ -0,0 +1,113 @@
+//! Bloom filters over the keys of a [super::child_buffer::ChildBuffer].
+//!
+//! The filters only live in memory and are never serialized, so they do not
+//! count towards the size of a node.
+use std::{fmt, hash::Hasher};
+use twox_hash::XxHash64;
+
+// About 1% false positives with 6 probes.
+const BITS_PER_KEY: usize = 10;
+const PROBES: u64 = 6;
+
+/// A set of keys which may report keys as contained which are not, but never
+/// the other way round.
+#[derive(Clone)]
+pub(super) struct BloomFilter {
+    // Power of two many words.
+    words: Box<[u64]>,
+    entries: usize,
+    capacity: usize,
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("bits", &(self.words.len() * 64))
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+fn hash(key: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(key);
+    hasher.finish()
+}
+
+impl BloomFilter {
+    /// Returns an empty filter for up to `capacity` keys.
+    pub(super) fn with_capacity(capacity: usize) -> Self {
+        let words = (capacity * BITS_PER_KEY / 64).next_power_of_two();
+        BloomFilter {
+            words: vec![0; words].into_boxed_slice(),
+            entries: 0,
+            capacity: words * 64 / BITS_PER_KEY,
+        }
+    }
+
+    /// Returns a filter containing `keys` with room for as many keys again.
+    pub(super) fn from_keys<'a, I>(keys: I) -> Self
+    where
+        I: ExactSizeIterator<Item = &'a [u8]>,
+    {
+        let mut filter = Self::with_capacity(2 * keys.len());
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    // The bits of `key`, by double hashing.
+    fn probes(&self, key: &[u8]) -> impl Iterator<Item = (usize, u64)> {
+        let hash = hash(key);
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        let mask = (self.words.len() * 64 - 1) as u64;
+        (0..PROBES).map(move |probe| {
+            let bit = h1.wrapping_add(probe.wrapping_mul(h2)) & mask;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    /// Adds `key`.  Returns false if the filter is at its capacity, the
+    /// key is added nonetheless.
+    pub(super) fn insert(&mut self, key: &[u8]) -> bool {
+        for (word, bit) in self.probes(key) {
+            self.words[word] |= bit;
+        }
+        self.entries += 1;
+        self.entries <= self.capacity
+    }
+
+    /// Whether `key` may have been added.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(key)
+            .all(|(word, bit)| self.words[word] & bit != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+    use std::collections::BTreeSet;
+
+    #[quickcheck]
+    fn check_no_false_negatives(keys: BTreeSet<Vec<u8>>, added: Vec<Vec<u8>>) {
+        let mut filter = BloomFilter::from_keys(keys.iter().map(|key| &key[..]));
+        for key in &added {
+            filter.insert(key);
+        }
+        assert!(keys.iter().chain(&added).all(|key| filter.may_contain(key)));
+    }
+
+    #[test]
+    fn false_positives() {
+        let mut filter = BloomFilter::with_capacity(1000);
+        for idx in 0..1000u32 {
+            assert!(filter.insert(&idx.to_be_bytes()));
+        }
+        let false_positives = (1000..101_000u32)
+            .filter(|idx| filter.may_contain(&idx.to_be_bytes()))
+            .count();
+        assert!(false_positives < 3000, "{}", false_positives);
+    }
+}
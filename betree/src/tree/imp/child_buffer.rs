@@ -2,7 +2,9 @@
 //!
 //! Encapsulating common nodes like [super::internal::InternalNode] and
 //! [super::leaf::LeafNode].
+use super::{bloom::BloomFilter, key_range::KeyRange};
 use crate::{
+    clock,
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::{HasStoragePreference, ObjectReference},
     size::{Size, StaticSize},
@@ -16,6 +18,7 @@ use std::{
     borrow::Borrow,
     collections::{btree_map::Entry, BTreeMap, Bound},
     mem::replace,
+    time::{Duration, Instant},
 };
 
 /// A buffer for messages that belong to a child of a tree node.
@@ -29,8 +32,19 @@ pub(super) struct ChildBuffer<N: 'static> {
     pub(super) buffer: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
     #[serde(with = "ser_np")]
     pub(super) node_pointer: RwLock<N>,
+    // Bounds of the keys in this buffer and below `node_pointer`.
+    key_range: KeyRange,
+    // Lets point lookups skip the buffer, only kept for larger buffers.
+    #[serde(skip)]
+    filter: Option<BloomFilter>,
+    // The time of the last insertion, unknown for buffers read from disk.
+    #[serde(skip)]
+    last_insert: Option<Instant>,
 }
 
+// Number of messages from which on a buffer keeps a bloom filter.
+const MIN_FILTER_ENTRIES: usize = 64;
+
 impl Size for (KeyInfo, SlicedCowBytes) {
     fn size(&self) -> usize {
         let (_keyinfo, data) = self;
@@ -112,6 +126,37 @@ mod ser_np {
     }
 }
 
+/// The layout of buffers in internal nodes of format version 1, which have
+/// no key range and whose node pointers are in the legacy layout, see
+/// [ObjectReference::deserialize_legacy].
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "N: ObjectReference"))]
+pub(super) struct LegacyChildBuffer<N> {
+    messages_preference: AtomicStoragePreference,
+    buffer_entries_size: usize,
+    buffer: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
+    #[serde(deserialize_with = "N::deserialize_legacy")]
+    node_pointer: N,
+}
+
+impl<N> From<LegacyChildBuffer<N>> for ChildBuffer<N> {
+    fn from(legacy: LegacyChildBuffer<N>) -> Self {
+        let mut buffer = ChildBuffer {
+            messages_preference: legacy.messages_preference,
+            system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
+            buffer_entries_size: legacy.buffer_entries_size,
+            buffer: legacy.buffer,
+            node_pointer: RwLock::new(legacy.node_pointer),
+            // The keys below the buffer are unknown.
+            key_range: KeyRange::all(),
+            filter: None,
+            last_insert: None,
+        };
+        buffer.update_filter();
+        buffer
+    }
+}
+
 impl<N: StaticSize> Size for ChildBuffer<N> {
     fn size(&self) -> usize {
         Self::static_size() + self.buffer_entries_size + N::static_size()
@@ -132,7 +177,7 @@ impl<N: StaticSize> Size for ChildBuffer<N> {
 
 impl<N> ChildBuffer<N> {
     pub fn static_size() -> usize {
-        17
+        51
     }
 
     pub fn buffer_size(&self) -> usize {
@@ -141,13 +186,60 @@ impl<N> ChildBuffer<N> {
 
     /// Returns whether there is no message in this buffer for the given `key`.
     pub fn is_empty(&self, key: &[u8]) -> bool {
-        !self.buffer.contains_key(key)
+        !self.may_contain(key) || !self.buffer.contains_key(key)
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&(KeyInfo, SlicedCowBytes)> {
+        if !self.may_contain(key) {
+            return None;
+        }
         self.buffer.get(key)
     }
 
+    /// Returns whether this buffer holds a message for `key` or any larger
+    /// key.
+    pub fn has_messages_from(&self, key: &[u8]) -> bool {
+        self.buffer.range::<[u8], _>(key..).next().is_some()
+    }
+
+    /// Returns whether no message has been inserted into this buffer for
+    /// `cold_after`.  Buffers read from disk are regarded as cold.
+    pub fn is_cold(&self, cold_after: Duration) -> bool {
+        self.last_insert
+            .map_or(true, |time| clock::elapsed(time) >= cold_after)
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter
+            .as_ref()
+            .map_or(true, |filter| filter.may_contain(key))
+    }
+
+    /// Rebuilds the bloom filter after messages have been removed, or drops
+    /// it if the buffer has become small.  Has to be called after the buffer
+    /// has been changed other than by [ChildBuffer::insert], and after
+    /// deserialization.
+    pub(super) fn update_filter(&mut self) {
+        self.filter = if self.buffer.len() >= MIN_FILTER_ENTRIES {
+            Some(BloomFilter::from_keys(
+                self.buffer.keys().map(|key| &key[..]),
+            ))
+        } else {
+            None
+        };
+    }
+
+    /// Returns the bounds of the keys in this buffer and the child below it.
+    pub fn key_range(&self) -> &KeyRange {
+        &self.key_range
+    }
+
+    /// Extends the key range by `key`, which is about to be inserted into the
+    /// child below this buffer.
+    pub(super) fn extend_key_range(&mut self, key: &[u8]) {
+        self.key_range.extend(key);
+    }
+
     pub fn apply_with_info(&mut self, key: &[u8], pref: StoragePreference) -> Option<()> {
         self.buffer.get_mut(key).map(|(keyinfo, _bytes)| {
             keyinfo.storage_preference = pref;
@@ -167,6 +259,7 @@ impl<N> ChildBuffer<N> {
     /// leaving an empty one in its place.
     pub fn take(&mut self) -> (BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>, usize) {
         self.messages_preference.invalidate();
+        self.filter = None;
         (
             std::mem::take(&mut self.buffer),
             replace(&mut self.buffer_entries_size, 0),
@@ -178,6 +271,10 @@ impl<N> ChildBuffer<N> {
         self.buffer_entries_size += other.buffer_entries_size;
         self.messages_preference
             .upgrade_atomic(&other.messages_preference);
+        self.key_range = self.key_range.union(&other.key_range);
+        self.last_insert = self.last_insert.max(other.last_insert);
+        other.filter = None;
+        self.update_filter();
     }
 
     /// Splits this `ChildBuffer` at `pivot`
@@ -185,13 +282,20 @@ impl<N> ChildBuffer<N> {
     /// and the returned `Self` contains the other entries and `node_pointer`.
     pub fn split_at(&mut self, pivot: &CowBytes, node_pointer: N) -> Self {
         let (buffer, buffer_entries_size) = self.split_off(pivot);
-        ChildBuffer {
+        let (key_range, sibling_key_range) = self.key_range.split_at(pivot);
+        self.key_range = key_range;
+        let mut sibling = ChildBuffer {
             messages_preference: AtomicStoragePreference::unknown(),
             buffer,
             buffer_entries_size,
             node_pointer: RwLock::new(node_pointer),
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
-        }
+            filter: None,
+            key_range: sibling_key_range,
+            last_insert: self.last_insert,
+        };
+        sibling.update_filter();
+        sibling
     }
 
     fn split_off(
@@ -209,14 +313,17 @@ impl<N> ChildBuffer<N> {
             .map(|(key, value)| key.size() + value.size())
             .sum();
         self.buffer_entries_size -= right_entry_size;
+        self.update_filter();
         (right_buffer, right_entry_size)
     }
 
     pub fn rebalance(&mut self, right_sibling: &mut Self, new_pivot_key: &CowBytes) {
         self.append(right_sibling);
         let (buffer, buffer_entries_size) = self.split_off(new_pivot_key);
+        (self.key_range, right_sibling.key_range) = self.key_range.split_at(new_pivot_key);
         right_sibling.buffer = buffer;
         right_sibling.buffer_entries_size = buffer_entries_size;
+        right_sibling.update_filter();
     }
 
     /// Inserts a message to this buffer for the given `key`.
@@ -235,12 +342,21 @@ impl<N> ChildBuffer<N> {
         let key_size = key.size();
 
         self.messages_preference.upgrade(keyinfo.storage_preference);
+        self.key_range.extend(&key);
+        self.last_insert = Some(clock::now());
 
         match self.buffer.entry(key.clone()) {
             Entry::Vacant(e) => {
                 let size_delta = key_size + msg.size() + keyinfo.size();
                 e.insert((keyinfo, msg));
                 self.buffer_entries_size += size_delta;
+                let filter_full = match self.filter {
+                    Some(ref mut filter) => !filter.insert(&key),
+                    None => self.buffer.len() >= MIN_FILTER_ENTRIES,
+                };
+                if filter_full {
+                    self.update_filter();
+                }
                 size_delta as isize
             }
             Entry::Occupied(mut e) => {
@@ -257,14 +373,17 @@ impl<N> ChildBuffer<N> {
         }
     }
 
-    /// Constructs a new, empty buffer.
-    pub fn new(node_pointer: N) -> Self {
+    /// Constructs a new, empty buffer for a child holding keys in `key_range`.
+    pub fn new(node_pointer: N, key_range: KeyRange) -> Self {
         ChildBuffer {
             messages_preference: AtomicStoragePreference::known(StoragePreference::NONE),
             buffer: BTreeMap::new(),
             buffer_entries_size: 0,
             node_pointer: RwLock::new(node_pointer),
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
+            filter: None,
+            key_range,
+            last_insert: None,
         }
     }
 }
@@ -296,6 +415,7 @@ impl<N> ChildBuffer<N> {
         }
         self.buffer_entries_size -= size_delta;
         self.messages_preference.invalidate();
+        self.update_filter();
         size_delta
     }
 }
@@ -303,7 +423,10 @@ impl<N> ChildBuffer<N> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{arbitrary::GenExt, tree::default_message_action::DefaultMessageActionMsg};
+    use crate::{
+        arbitrary::GenExt,
+        tree::{default_message_action::DefaultMessageActionMsg, DefaultMessageAction},
+    };
     use bincode::serialized_size;
     use quickcheck::{Arbitrary, Gen};
     use rand::Rng;
@@ -316,6 +439,9 @@ mod tests {
                 buffer: self.buffer.clone(),
                 node_pointer: RwLock::new(self.node_pointer.read().clone()),
                 system_storage_preference: self.system_storage_preference.clone(),
+                filter: self.filter.clone(),
+                key_range: self.key_range,
+                last_insert: self.last_insert,
             }
         }
     }
@@ -325,13 +451,14 @@ mod tests {
             self.buffer_entries_size == other.buffer_entries_size
                 && self.buffer == other.buffer
                 && *self.node_pointer.read() == *other.node_pointer.read()
+                && self.key_range == other.key_range
         }
     }
 
     impl<N: Arbitrary> Arbitrary for ChildBuffer<N> {
         fn arbitrary(g: &mut Gen) -> Self {
             let mut rng = g.rng();
-            let entries_cnt = rng.gen_range(0..20);
+            let entries_cnt = rng.gen_range(0..2 * MIN_FILTER_ENTRIES);
             let buffer: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)> = (0..entries_cnt)
                 .map(|_| {
                     (
@@ -343,7 +470,7 @@ mod tests {
                     )
                 })
                 .collect();
-            ChildBuffer {
+            let mut child_buffer = ChildBuffer {
                 messages_preference: AtomicStoragePreference::unknown(),
                 buffer_entries_size: buffer
                     .iter()
@@ -354,7 +481,12 @@ mod tests {
                 system_storage_preference: AtomicSystemStoragePreference::from(
                     StoragePreference::NONE,
                 ),
-            }
+                filter: None,
+                key_range: KeyRange::all(),
+                last_insert: None,
+            };
+            child_buffer.update_filter();
+            child_buffer
         }
     }
 
@@ -401,4 +533,25 @@ mod tests {
         buffer.append(&mut sibling.take().0);
         assert_eq!(this.buffer, buffer);
     }
+
+    #[quickcheck]
+    fn check_filter(mut child_buffer: ChildBuffer<()>, pivot_key: CowBytes, keys: Vec<CowBytes>) {
+        for key in keys {
+            child_buffer.insert(
+                key,
+                KeyInfo {
+                    storage_preference: StoragePreference::NONE,
+                },
+                DefaultMessageAction::insert_msg(&[0]),
+                DefaultMessageAction,
+            );
+        }
+        let sibling = child_buffer.split_at(&pivot_key, ());
+        for buffer in [&child_buffer, &sibling] {
+            for key in buffer.buffer.keys() {
+                assert!(!buffer.is_empty(key));
+                assert!(buffer.get(key).is_some());
+            }
+        }
+    }
 }
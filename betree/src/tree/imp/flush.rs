@@ -3,18 +3,70 @@
 //! Calling [Tree::rebalance_tree] is not only possible with the root node but may be
 //! applied to a variety of nodes given that their parent node is correctly
 //! given. Use with caution.
-use std::borrow::Borrow;
+//!
+//! [Tree::compact_buffers] flushes the buffers of rarely written key ranges
+//! ahead of time, e.g. while the database is idle.
+use std::{borrow::Borrow, cmp::Reverse, time::Duration};
 
 use super::{
     child_buffer::ChildBuffer, derivate_ref::DerivateRef, internal::TakeChildBuffer, FillUpResult,
-    Inner, Node, Tree,
+    Inner, Node, Tree, MIN_FLUSH_SIZE,
 };
 use crate::{
     cache::AddSize,
+    cow_bytes::CowBytes,
     data_management::{Dml, HasStoragePreference, ObjectReference},
+    database::Event,
     size::Size,
+    storage_pool::StoragePoolLayer,
     tree::{errors::*, imp::internal::MergeChildResult, MessageAction},
 };
+use serde::{Deserialize, Serialize};
+
+// Write latency up to which adaptive flush sizes stay at their minimum.
+const ADAPTIVE_BASE_LATENCY: Duration = Duration::from_millis(1);
+
+/// The size in bytes from which on the messages buffered for a child are
+/// flushed to it.
+///
+/// Larger flush sizes write nodes less often, at the cost of more messages
+/// being held in the buffers of internal nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlushSize {
+    /// Always flush from the given size on.
+    Fixed(usize),
+    /// Scale the flush size with the observed latency of writes to the
+    /// storage class the tree prefers, or to the slowest storage class if it
+    /// has no preference.  Writes taking up to 1 ms flush from `min` bytes on,
+    /// each additional millisecond adds `min` bytes up to `max` bytes.
+    Adaptive {
+        /// The flush size for fast storage.
+        min: usize,
+        /// The flush size for slow storage.
+        max: usize,
+    },
+}
+
+impl Default for FlushSize {
+    fn default() -> Self {
+        FlushSize::Fixed(MIN_FLUSH_SIZE)
+    }
+}
+
+impl FlushSize {
+    /// Returns the flush size for writes taking `write_latency`, if known.
+    pub(super) fn bytes(&self, write_latency: Option<Duration>) -> usize {
+        match *self {
+            FlushSize::Fixed(bytes) => bytes,
+            FlushSize::Adaptive { min, max } => {
+                let factor = write_latency.map_or(1, |latency| {
+                    (latency.as_micros() / ADAPTIVE_BASE_LATENCY.as_micros()).max(1) as usize
+                });
+                min.saturating_mul(factor).min(max).max(min)
+            }
+        }
+    }
+}
 
 impl<X, R, M, I> Tree<X, M, I>
 where
@@ -23,11 +75,27 @@ where
     M: MessageAction,
     I: Borrow<Inner<X::ObjectRef, M>>,
 {
+    // Returns the current flush size of this tree.
+    fn current_flush_size(&self) -> usize {
+        if let FlushSize::Fixed(bytes) = self.flush_size {
+            return bytes;
+        }
+        let spl = self.dml.spl();
+        let write_latency = match self.storage_preference.preferred_class() {
+            Some(class) => spl.write_latency(class),
+            None => (0..spl.storage_class_count())
+                .filter_map(|class| spl.write_latency(class))
+                .max(),
+        };
+        self.flush_size.bytes(write_latency)
+    }
+
     /// This method performs necessary flushing and rebalancing operations if
     /// too many entries are stored at a node. We use this method immediately
     /// after a new value is inserted in a the specified node to assure that we
     /// will not end up in a state where an overfull node is serialized onto
-    /// disk.
+    /// disk.  The only exception are nodes whose rebalancing has been deferred
+    /// by [Tree::insert_deferred], which stay below twice the allowed size.
     ///
     /// Brief Summary
     /// -------------
@@ -59,6 +127,9 @@ where
             if !node.is_too_large() {
                 return Ok(());
             }
+            // Every step below splits, merges or flushes to children of
+            // `node`, or `node` itself.
+            self.begin_restructuring();
             debug!(
                 "{}, {:?}, lvl: {}, size: {}, actual: {:?}",
                 node.kind(),
@@ -68,24 +139,26 @@ where
                 node.actual_size()
             );
             // 1. Select the largest child buffer which can be flushed.
-            let mut child_buffer =
-                match DerivateRef::try_new(node, |node| node.try_find_flush_candidate()) {
-                    // 1.1. If there is none we have to split the node.
-                    Err(_node) => match parent {
-                        None => {
-                            self.split_root_node(_node);
-                            return Ok(());
-                        }
-                        Some(ref mut parent) => {
-                            let (next_node, size_delta) = self.split_node(_node, parent)?;
-                            parent.add_size(size_delta);
-                            node = next_node;
-                            continue;
-                        }
-                    },
-                    // 1.2. If successful we flush in the following steps to this node.
-                    Ok(selected_child_buffer) => selected_child_buffer,
-                };
+            let flush_size = self.current_flush_size();
+            let mut child_buffer = match DerivateRef::try_new(node, |node| {
+                node.try_find_flush_candidate(flush_size)
+            }) {
+                // 1.1. If there is none we have to split the node.
+                Err(_node) => match parent {
+                    None => {
+                        self.split_root_node(_node);
+                        return Ok(());
+                    }
+                    Some(ref mut parent) => {
+                        let (next_node, size_delta) = self.split_node(_node, parent)?;
+                        parent.add_size(size_delta);
+                        node = next_node;
+                        continue;
+                    }
+                },
+                // 1.2. If successful we flush in the following steps to this node.
+                Ok(selected_child_buffer) => selected_child_buffer,
+            };
             let mut child = self.get_mut_node(child_buffer.node_pointer_mut())?;
             // 2. Iterate down to child if too large
             if !child.is_leaf() && child.is_too_large() {
@@ -105,6 +178,7 @@ where
                         old_np,
                         size_delta,
                     } = m.merge_children();
+                    let level = child.level();
                     if is_right_sibling {
                         let size_delta = child.merge(&mut sibling, pivot_key);
                         child.add_size(size_delta);
@@ -113,62 +187,18 @@ where
                         child.add_size(size_delta);
                     }
                     self.dml.remove(old_np);
+                    self.dml.events().emit(|| Event::NodeMerge {
+                        dataset: self.tree_id(),
+                        level,
+                    });
                     size_delta
                 };
                 child_buffer.add_size(size_delta);
                 node = child_buffer.into_owner();
                 continue;
             }
-            // 4. Remove messages from the child buffer.
-            let (buffer, size_delta) = child_buffer.take_buffer();
-            child_buffer.add_size(size_delta);
-            self.dml.verify_cache();
-            // 5. Insert messages from the child buffer into the child.
-            let size_delta_child = child.insert_msg_buffer(buffer, self.msg_action());
-            child.add_size(size_delta_child);
-
-            // 6. Check if minimal leaf size is fulfilled, otherwise merge again.
-            if child.is_too_small_leaf() {
-                let size_delta = {
-                    let mut m = child_buffer.prepare_merge();
-                    let mut sibling = self.get_mut_node(m.sibling_node_pointer())?;
-                    let left;
-                    let right;
-                    if m.is_right_sibling() {
-                        left = &mut child;
-                        right = &mut sibling;
-                    } else {
-                        left = &mut sibling;
-                        right = &mut child;
-                    };
-                    match left.leaf_rebalance(right) {
-                        FillUpResult::Merged { size_delta } => {
-                            left.add_size(size_delta);
-                            right.add_size(-size_delta);
-                            let MergeChildResult {
-                                old_np, size_delta, ..
-                            } = m.merge_children();
-                            self.dml.remove(old_np);
-                            size_delta
-                        }
-                        FillUpResult::Rebalanced {
-                            pivot_key,
-                            size_delta,
-                        } => {
-                            left.add_size(size_delta);
-                            right.add_size(-size_delta);
-                            m.rebalanced(pivot_key)
-                        }
-                    }
-                };
-                child_buffer.add_size(size_delta);
-            }
-            // 7. If the child is too large, split until it is not.
-            while child.is_too_large_leaf() {
-                let (next_node, size_delta) = self.split_node(child, &mut child_buffer)?;
-                child_buffer.add_size(size_delta);
-                child = next_node;
-            }
+            // 4.-7. Move the messages into the child and rebalance it.
+            let child = self.flush_child_buffer(&mut child_buffer, child)?;
 
             // 8. After finishing all operations once, see if they have to be repeated.
             if child_buffer.size() > super::MAX_INTERNAL_NODE_SIZE {
@@ -185,4 +215,225 @@ where
             node = child;
         }
     }
+
+    // Moves the messages of `child_buffer` into `child`.  A leaf which has
+    // become too small is merged with or refilled from a sibling, one which
+    // has become too large is split.  Returns the node the messages ended up
+    // in.
+    fn flush_child_buffer(
+        &self,
+        child_buffer: &mut DerivateRef<
+            X::CacheValueRefMut,
+            TakeChildBuffer<'static, ChildBuffer<R>>,
+        >,
+        mut child: X::CacheValueRefMut,
+    ) -> Result<X::CacheValueRefMut, Error> {
+        // 4. Remove messages from the child buffer.
+        let (buffer, size_delta) = child_buffer.take_buffer();
+        child_buffer.add_size(size_delta);
+        self.dml.verify_cache();
+        // 5. Insert messages from the child buffer into the child.
+        let size_delta_child = child.insert_msg_buffer(buffer, self.msg_action());
+        child.add_size(size_delta_child);
+
+        // 6. Check if minimal leaf size is fulfilled, otherwise merge again.
+        if child.is_too_small_leaf() {
+            let size_delta = {
+                let mut m = child_buffer.prepare_merge();
+                let mut sibling = self.get_mut_node(m.sibling_node_pointer())?;
+                let left;
+                let right;
+                if m.is_right_sibling() {
+                    left = &mut child;
+                    right = &mut sibling;
+                } else {
+                    left = &mut sibling;
+                    right = &mut child;
+                };
+                match left.leaf_rebalance(right) {
+                    FillUpResult::Merged { size_delta } => {
+                        left.add_size(size_delta);
+                        right.add_size(-size_delta);
+                        let MergeChildResult {
+                            old_np, size_delta, ..
+                        } = m.merge_children();
+                        self.dml.remove(old_np);
+                        self.dml.events().emit(|| Event::NodeMerge {
+                            dataset: self.tree_id(),
+                            level: 0,
+                        });
+                        size_delta
+                    }
+                    FillUpResult::Rebalanced {
+                        pivot_key,
+                        size_delta,
+                    } => {
+                        left.add_size(size_delta);
+                        right.add_size(-size_delta);
+                        m.rebalanced(pivot_key)
+                    }
+                }
+            };
+            child_buffer.add_size(size_delta);
+        }
+        // 7. If the child is too large, split until it is not.
+        while child.is_too_large_leaf() {
+            let (next_node, size_delta) = self.split_node(child, child_buffer)?;
+            child_buffer.add_size(size_delta);
+            child = next_node;
+        }
+        Ok(child)
+    }
+
+    /// Rebalances the topmost overfull node on the path to `key`, e.g. after
+    /// [Tree::insert_deferred].  Only modified nodes are visited, so that
+    /// nothing is fetched or copied if there is no overfull node anymore.
+    ///
+    /// Like an insertion, only the current node and its parent are locked
+    /// while descending, so calls for keys in disjoint subtrees rebalance
+    /// them in parallel.
+    pub(crate) fn rebalance_path(&self, key: &[u8]) -> Result<(), Error> {
+        let mut parent = None;
+        let mut node = match self.dml.try_get_mut(&self.inner.borrow().root_node.read()) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        loop {
+            if node.is_too_large() {
+                return self.rebalance_tree(node, parent);
+            }
+            match DerivateRef::try_new(node, |node| node.walk(key)) {
+                Ok(mut child_buffer) => {
+                    match self.try_get_mut_node(child_buffer.node_pointer_mut()) {
+                        Some(child) => {
+                            node = child;
+                            parent = Some(child_buffer);
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                Err(_leaf) => return Ok(()),
+            }
+        }
+    }
+
+    /// Flushes child buffers holding at least `min_size` bytes of messages
+    /// into which no message has been inserted for `cold_after`, at most
+    /// `max_flushes` of them and those of upper levels first.  Returns the
+    /// number of flushed buffers.
+    ///
+    /// Lookups in rarely written key ranges otherwise apply the messages
+    /// buffered for them on every read, and the first writes to them after
+    /// a quiet period trigger the flushes all at once.
+    pub(crate) fn compact_buffers(
+        &self,
+        min_size: usize,
+        cold_after: Duration,
+        max_flushes: usize,
+    ) -> Result<usize, Error> {
+        let mut candidates = Vec::new();
+        self.collect_cold_buffers(
+            &self.get_root_node()?,
+            min_size,
+            cold_after,
+            &mut candidates,
+        )?;
+        candidates.sort_by_key(|&(level, _)| Reverse(level));
+        let mut flushed = 0;
+        for (level, key) in candidates {
+            if flushed == max_flushes {
+                break;
+            }
+            if self.compact_buffer(level, &key, min_size, cold_after)? {
+                flushed += 1;
+            }
+        }
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(flushed)
+    }
+
+    // Collects the level and the first key of the buffers of `node` and its
+    // descendants which qualify for compaction.  Leaves are not fetched.
+    fn collect_cold_buffers(
+        &self,
+        node: &Node<R>,
+        min_size: usize,
+        cold_after: Duration,
+        candidates: &mut Vec<(u32, CowBytes)>,
+    ) -> Result<(), Error> {
+        let level = node.level();
+        if level == 0 {
+            return Ok(());
+        }
+        for key in node.cold_buffers(min_size, cold_after) {
+            candidates.push((level, key));
+        }
+        if level > 1 {
+            for np in node.child_pointer_iter().into_iter().flatten() {
+                self.collect_cold_buffers(&self.get_node(np)?, min_size, cold_after, candidates)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Flushes the buffer `key` belongs to in the node at `level` on the path
+    // to `key` if it still qualifies for compaction.  Only the current node
+    // and its parent are locked while descending, like for an insertion.
+    fn compact_buffer(
+        &self,
+        level: u32,
+        key: &[u8],
+        min_size: usize,
+        cold_after: Duration,
+    ) -> Result<bool, Error> {
+        let mut parent = None;
+        let mut node = self.get_mut_root_node()?;
+        while node.level() > level {
+            match DerivateRef::try_new(node, |node| node.walk(key)) {
+                Ok(mut child_buffer) => {
+                    node = self.get_mut_node(child_buffer.node_pointer_mut())?;
+                    parent = Some(child_buffer);
+                }
+                Err(_leaf) => return Ok(false),
+            }
+        }
+        // The tree may have shrunk in the meantime.
+        if node.level() != level {
+            return Ok(false);
+        }
+        let mut child_buffer = match DerivateRef::try_new(node, |node| {
+            node.try_find_cold_buffer(key, min_size, cold_after)
+        }) {
+            Ok(child_buffer) => child_buffer,
+            Err(_node) => return Ok(false),
+        };
+        let child = self.get_mut_node(child_buffer.node_pointer_mut())?;
+        self.begin_restructuring();
+        let child = self.flush_child_buffer(&mut child_buffer, child)?;
+        if child.is_too_large() {
+            self.rebalance_tree(child, Some(child_buffer))?;
+        } else {
+            drop(child);
+            self.rebalance_tree(child_buffer.into_owner(), parent)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlushSize;
+    use std::time::Duration;
+
+    #[test]
+    fn adaptive_flush_size() {
+        let size = FlushSize::Adaptive { min: 100, max: 450 };
+        assert_eq!(size.bytes(None), 100);
+        assert_eq!(size.bytes(Some(Duration::from_micros(300))), 100);
+        assert_eq!(size.bytes(Some(Duration::from_millis(3))), 300);
+        assert_eq!(size.bytes(Some(Duration::from_millis(8))), 450);
+        assert_eq!(FlushSize::Fixed(42).bytes(Some(Duration::from_secs(1))), 42);
+    }
 }
@@ -1,6 +1,7 @@
 //! Implementation of the [InternalNode] node type.
 use super::{
-    child_buffer::ChildBuffer,
+    child_buffer::{ChildBuffer, LegacyChildBuffer},
+    key_range::KeyRange,
     node::{PivotGetMutResult, PivotGetResult},
     PivotKey,
 };
@@ -16,7 +17,7 @@ use crate::{
 use bincode::serialized_size;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, collections::BTreeMap, mem::replace};
+use std::{borrow::Borrow, collections::BTreeMap, mem::replace, time::Duration};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
@@ -132,6 +133,26 @@ impl<T: HasStoragePreference> HasStoragePreference for InternalNode<T> {
     }
 }
 
+impl<N: StaticSize> InternalNode<ChildBuffer<N>> {
+    /// Converts an internal node of format version 1.  Its buffers gain a
+    /// key range and larger node pointers, so the size of its entries is
+    /// determined anew.
+    pub fn from_legacy(legacy: InternalNode<LegacyChildBuffer<N>>) -> Self {
+        let children: Vec<ChildBuffer<N>> =
+            legacy.children.into_iter().map(ChildBuffer::from).collect();
+        let entries_size = legacy.pivot.iter().map(Size::size).sum::<usize>()
+            + children.iter().map(Size::size).sum::<usize>();
+        InternalNode {
+            level: legacy.level,
+            entries_size,
+            system_storage_preference: legacy.system_storage_preference,
+            pref: AtomicStoragePreference::unknown(),
+            pivot: legacy.pivot,
+            children,
+        }
+    }
+}
+
 impl<T> InternalNode<T> {
     pub fn new(left_child: T, right_child: T, pivot_key: CowBytes, level: u32) -> Self
     where
@@ -266,34 +287,78 @@ impl<N> InternalNode<ChildBuffer<N>> {
         child.node_pointer.get_mut()
     }
 
+    /// Returns the first child, starting from the one `key` belongs to, which
+    /// may hold keys up to the inclusive `max_key` and collects its messages.
+    /// Children whose key range rules this out are skipped without fetching
+    /// them, `None` is returned if all of them up to `max_key` are skipped.
+    ///
+    /// The left pivot key stays the one of the child `key` belongs to, so that
+    /// messages of ancestors for the skipped children are not disregarded.
     pub fn get_range(
         &self,
         key: &[u8],
+        max_key: Option<&[u8]>,
         left_pivot_key: &mut Option<CowBytes>,
         right_pivot_key: &mut Option<CowBytes>,
         all_msgs: &mut BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
-    ) -> &RwLock<N> {
-        let idx = self.idx(key);
+    ) -> Option<&RwLock<N>> {
+        let mut idx = self.idx(key);
         if idx > 0 {
             *left_pivot_key = Some(self.pivot[idx - 1].clone());
         }
-        if idx < self.pivot.len() {
-            *right_pivot_key = Some(self.pivot[idx].clone());
-        }
-        let child = &self.children[idx];
-        for (key, msg) in child.get_all_messages() {
-            all_msgs
-                .entry(key.clone())
-                .or_insert_with(Vec::new)
-                .push(msg.clone());
+        loop {
+            if idx < self.pivot.len() {
+                *right_pivot_key = Some(self.pivot[idx].clone());
+            }
+            let child = &self.children[idx];
+            if child.key_range().may_intersect(key, max_key) {
+                for (key, msg) in child.get_all_messages() {
+                    all_msgs
+                        .entry(key.clone())
+                        .or_insert_with(Vec::new)
+                        .push(msg.clone());
+                }
+                return Some(&child.node_pointer);
+            }
+            if idx == self.pivot.len()
+                || max_key.map_or(false, |max_key| &self.pivot[idx][..] >= max_key)
+            {
+                return None;
+            }
+            idx += 1;
         }
+    }
 
-        &child.node_pointer
+    /// Returns the children following the one `key` belongs to in key order,
+    /// as long as they may hold keys up to the inclusive `max_key`.
+    pub fn get_next_nodes<'a>(
+        &'a self,
+        key: &'a [u8],
+        max_key: Option<&'a [u8]>,
+    ) -> impl Iterator<Item = &'a RwLock<N>> + 'a {
+        let idx = self.idx(key);
+        // The child at `idx + 1` only holds keys larger than `pivot[idx]`.
+        self.children[idx + 1..]
+            .iter()
+            .zip(&self.pivot[idx..])
+            .take_while(move |(_, pivot)| max_key.map_or(true, |max_key| &pivot[..] < max_key))
+            .filter(move |(child, _)| child.key_range().may_intersect(key, max_key))
+            .map(|(child, _)| &child.node_pointer)
     }
 
-    pub fn get_next_node(&self, key: &[u8]) -> Option<&RwLock<N>> {
-        let idx = self.idx(key) + 1;
-        self.children.get(idx).map(|child| &child.node_pointer)
+    /// Returns the children which may hold keys from `min_key` up to the
+    /// inclusive `max_key` in key order.
+    pub fn children_in_range<'a>(
+        &'a self,
+        min_key: &'a [u8],
+        max_key: Option<&'a [u8]>,
+    ) -> impl Iterator<Item = &'a RwLock<N>> + 'a {
+        let first = self.idx(min_key);
+        let last = max_key.map_or(self.pivot.len(), |max_key| self.idx(max_key).max(first));
+        self.children[first..=last]
+            .iter()
+            .filter(move |child| child.key_range().may_intersect(min_key, max_key))
+            .map(|child| &child.node_pointer)
     }
 
     pub fn insert<Q, M>(
@@ -342,6 +407,15 @@ impl<N> InternalNode<ChildBuffer<N>> {
         added_size
     }
 
+    /// Returns bounds of the keys below this node.
+    pub fn key_range(&self) -> KeyRange {
+        self.children
+            .iter()
+            .map(|child| *child.key_range())
+            .reduce(|range, child| range.union(&child))
+            .unwrap_or_else(KeyRange::all)
+    }
+
     pub fn drain_children(&mut self) -> impl Iterator<Item = N> + '_ {
         self.pref.invalidate();
         self.entries_size = 0;
@@ -349,6 +423,15 @@ impl<N> InternalNode<ChildBuffer<N>> {
             .drain(..)
             .map(|child| child.node_pointer.into_inner())
     }
+
+    /// Returns the children along with the messages buffered for them.
+    pub fn into_buffers(
+        self,
+    ) -> impl Iterator<Item = (N, BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>)> {
+        self.children
+            .into_iter()
+            .map(|child| (child.node_pointer.into_inner(), child.buffer))
+    }
 }
 
 impl<N: StaticSize + HasStoragePreference> InternalNode<ChildBuffer<N>> {
@@ -454,7 +537,8 @@ impl<N: ObjectReference> InternalNode<ChildBuffer<N>> {
         size_delta as isize
     }
 
-    /// Translate any object ref in a `ChildBuffer` from `Incomplete` to `Unmodified` state.
+    /// Translate any object ref in a `ChildBuffer` from `Incomplete` to `Unmodified` state
+    /// and build the bloom filters of the buffers, which are not serialized.
     pub fn complete_object_refs(mut self, d_id: DatasetId) -> Self {
         // TODO:
         let first_pk = match self.pivot.first() {
@@ -470,7 +554,8 @@ impl<N: ObjectReference> InternalNode<ChildBuffer<N>> {
         {
             // SAFETY: There must always be pivots + 1 many children, otherwise
             // the state of the Internal Node is broken.
-            self.children[id].complete_object_ref(pk)
+            self.children[id].complete_object_ref(pk);
+            self.children[id].update_filter();
         }
         self
     }
@@ -480,9 +565,13 @@ impl<N: HasStoragePreference> InternalNode<ChildBuffer<N>>
 where
     ChildBuffer<N>: Size,
 {
+    /// Returns the child `key` belongs to if its buffer holds no message for
+    /// `key`.  The key range of the child is extended by `key`, as the caller
+    /// inserts it into the child directly.
     pub fn try_walk(&mut self, key: &[u8]) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
         let child_idx = self.idx(key);
         if self.children[child_idx].is_empty(key) {
+            self.children[child_idx].extend_key_range(key);
             Some(TakeChildBuffer {
                 node: self,
                 child_idx,
@@ -492,6 +581,14 @@ where
         }
     }
 
+    pub fn walk(&mut self, key: &[u8]) -> TakeChildBuffer<ChildBuffer<N>> {
+        let child_idx = self.idx(key);
+        TakeChildBuffer {
+            node: self,
+            child_idx,
+        }
+    }
+
     pub fn try_find_flush_candidate(
         &mut self,
         min_flush_size: usize,
@@ -523,6 +620,39 @@ where
             child_idx,
         })
     }
+
+    /// Returns the first key of each child buffer holding at least
+    /// `min_size` bytes of messages which is cold, see [ChildBuffer::is_cold].
+    pub fn cold_buffers(
+        &self,
+        min_size: usize,
+        cold_after: Duration,
+    ) -> impl Iterator<Item = &CowBytes> + '_ {
+        self.children
+            .iter()
+            .filter(move |child| child.buffer_size() >= min_size && child.is_cold(cold_after))
+            .filter_map(|child| child.buffer.keys().next())
+    }
+
+    /// Returns the child buffer `key` belongs to if it qualifies like in
+    /// [InternalNode::cold_buffers].
+    pub fn try_find_cold_buffer(
+        &mut self,
+        key: &[u8],
+        min_size: usize,
+        cold_after: Duration,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+        let child_idx = self.idx(key);
+        let child = &self.children[child_idx];
+        if child.buffer_size() >= min_size && child.is_cold(cold_after) {
+            Some(TakeChildBuffer {
+                node: self,
+                child_idx,
+            })
+        } else {
+            None
+        }
+    }
 }
 
 pub(super) struct TakeChildBuffer<'a, T: 'a> {
@@ -646,6 +776,15 @@ impl<'a, N: Size + HasStoragePreference> TakeChildBuffer<'a, ChildBuffer<N>> {
     pub fn node_pointer_mut(&mut self) -> &mut RwLock<N> {
         &mut self.node.children[self.child_idx].node_pointer
     }
+
+    /// Returns whether an append of `key` may bypass this buffer, which is
+    /// the case if it is the buffer of the last child of the node and holds
+    /// no message for `key` or any larger key.  Otherwise older messages of
+    /// this buffer would be applied on top of the appended one.
+    pub(super) fn may_bypass(&self, key: &[u8]) -> bool {
+        self.child_idx + 1 == self.node.children.len()
+            && !self.node.children[self.child_idx].has_messages_from(key)
+    }
     pub fn take_buffer(&mut self) -> (BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>, isize) {
         let (buffer, size_delta) = self.node.children[self.child_idx].take();
         self.node.entries_size -= size_delta;
@@ -846,6 +985,12 @@ mod tests {
                 PK.as_ref().unwrap()
             }
         }
+
+        fn deserialize_legacy<'de, D: serde::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Self, D::Error> {
+            <()>::deserialize(deserializer)
+        }
     }
 
     #[quickcheck]
@@ -895,6 +1040,76 @@ mod tests {
         TestResult::passed()
     }
 
+    #[test]
+    fn get_range_skips_disjoint_children() {
+        let child = |np: u32, key: &[u8]| ChildBuffer::new(np, KeyRange::new(key, key));
+        let node = InternalNode {
+            level: 1,
+            entries_size: 0,
+            pivot: vec![CowBytes::from(&b"b"[..]), CowBytes::from(&b"d"[..])],
+            children: vec![child(0, b"a"), child(1, b"c"), child(2, b"e")],
+            system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
+            pref: AtomicStoragePreference::unknown(),
+        };
+        let get_range = |key: &[u8], max_key: Option<&[u8]>| {
+            let (mut left_pivot_key, mut right_pivot_key) = (None, None);
+            let np = node
+                .get_range(
+                    key,
+                    max_key,
+                    &mut left_pivot_key,
+                    &mut right_pivot_key,
+                    &mut BTreeMap::new(),
+                )
+                .map(|np| *np.read());
+            (np, left_pivot_key, right_pivot_key)
+        };
+
+        assert_eq!(
+            get_range(b"a0", None),
+            (Some(1), None, Some(CowBytes::from(&b"d"[..])))
+        );
+        assert_eq!(
+            get_range(b"a0", Some(b"b5")),
+            (None, None, Some(CowBytes::from(&b"d"[..])))
+        );
+        assert_eq!(
+            get_range(b"d0", Some(b"d5")),
+            (None, Some(CowBytes::from(&b"d"[..])), None)
+        );
+        assert_eq!(get_range(b"a", Some(b"a")).0, Some(0));
+        assert_eq!(
+            node.get_next_nodes(b"a0", None)
+                .map(|np| *np.read())
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn children_in_range_skips_disjoint_children() {
+        let child = |np: u32, key: &[u8]| ChildBuffer::new(np, KeyRange::new(key, key));
+        let node = InternalNode {
+            level: 1,
+            entries_size: 0,
+            pivot: vec![CowBytes::from(&b"b"[..]), CowBytes::from(&b"d"[..])],
+            children: vec![child(0, b"a"), child(1, b"c"), child(2, b"e")],
+            system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
+            pref: AtomicStoragePreference::unknown(),
+        };
+        let children_in_range = |min_key: &[u8], max_key: Option<&[u8]>| {
+            node.children_in_range(min_key, max_key)
+                .map(|np| *np.read())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(children_in_range(b"", None), vec![0, 1, 2]);
+        assert_eq!(children_in_range(b"a", Some(b"c")), vec![0, 1]);
+        assert_eq!(children_in_range(b"a0", Some(b"b5")), Vec::<u32>::new());
+        assert_eq!(children_in_range(b"c", Some(b"c")), vec![1]);
+        assert_eq!(children_in_range(b"d0", None), vec![2]);
+    }
+
     // #[test]
     // fn check_constant() {
     //     let node: InternalNode<ChildBuffer<()>> = InternalNode {
@@ -0,0 +1,170 @@
+//! Summaries of the keys below a [super::child_buffer::ChildBuffer].
+//!
+//! Internal nodes store a [KeyRange] per child so that range queries can skip
+//! children which cannot hold any key of the queried range without fetching
+//! them.  The bounds are stored as fixed size prefixes of the keys, so keeping
+//! them up to date never changes the size of a node.
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// Number of leading key bytes kept for a bound.
+const PREFIX_LEN: usize = 16;
+
+/// The first [PREFIX_LEN] bytes of a key.
+///
+/// Truncation preserves the order of keys, i.e. `a <= b` implies
+/// `Prefix::of(a) <= Prefix::of(b)`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Prefix {
+    len: u8,
+    bytes: [u8; PREFIX_LEN],
+}
+
+impl Prefix {
+    fn of(key: &[u8]) -> Self {
+        let len = key.len().min(PREFIX_LEN);
+        let mut bytes = [0; PREFIX_LEN];
+        bytes[..len].copy_from_slice(&key[..len]);
+        Prefix {
+            len: len as u8,
+            bytes,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl PartialEq for Prefix {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Prefix {}
+
+impl PartialOrd for Prefix {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Prefix {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+/// Bounds of all keys in a subtree, including the keys of buffered messages.
+///
+/// The bounds are conservative: every key of the subtree lies within them,
+/// but they may be wider than the keys actually present, e.g. after messages
+/// have been deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct KeyRange {
+    min: Prefix,
+    max: Prefix,
+}
+
+impl KeyRange {
+    /// The range containing all keys.
+    pub(super) fn all() -> Self {
+        KeyRange {
+            min: Prefix::of(&[]),
+            max: Prefix::of(&[u8::MAX; PREFIX_LEN]),
+        }
+    }
+
+    /// The range of the keys from `first` to `last`.
+    pub(super) fn new(first: &[u8], last: &[u8]) -> Self {
+        debug_assert!(first <= last);
+        KeyRange {
+            min: Prefix::of(first),
+            max: Prefix::of(last),
+        }
+    }
+
+    /// Extends the range to contain `key`.
+    pub(super) fn extend(&mut self, key: &[u8]) {
+        let key = Prefix::of(key);
+        self.min = self.min.min(key);
+        self.max = self.max.max(key);
+    }
+
+    /// Returns the smallest range containing both ranges.
+    pub(super) fn union(&self, other: &Self) -> Self {
+        KeyRange {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Splits the range into the parts up to (and including) `pivot` and above
+    /// `pivot`.
+    pub(super) fn split_at(&self, pivot: &[u8]) -> (Self, Self) {
+        let pivot = Prefix::of(pivot);
+        (
+            KeyRange {
+                min: self.min,
+                max: self.max.min(pivot),
+            },
+            KeyRange {
+                min: self.min.max(pivot),
+                max: self.max,
+            },
+        )
+    }
+
+    /// Returns whether the range may contain keys from `min_key` to the
+    /// inclusive `max_key`.
+    pub(super) fn may_intersect(&self, min_key: &[u8], max_key: Option<&[u8]>) -> bool {
+        self.max >= Prefix::of(min_key)
+            && max_key.map_or(true, |max_key| self.min <= Prefix::of(max_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyRange;
+    use std::collections::BTreeSet;
+
+    #[quickcheck]
+    fn check_no_false_negatives(keys: BTreeSet<Vec<u8>>, min_key: Vec<u8>, max_key: Vec<u8>) {
+        let (first, last) = match (keys.iter().next(), keys.iter().next_back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        let range = KeyRange::new(first, last);
+        if keys.iter().any(|key| min_key <= *key && *key <= max_key) {
+            assert!(range.may_intersect(&min_key, Some(&max_key)));
+        }
+        if keys.iter().any(|key| min_key <= *key) {
+            assert!(range.may_intersect(&min_key, None));
+        }
+        assert!(KeyRange::all().may_intersect(&min_key, Some(&max_key)));
+    }
+
+    #[quickcheck]
+    fn check_split_at(keys: BTreeSet<Vec<u8>>, pivot: Vec<u8>) {
+        let mut range = KeyRange::new(&pivot, &pivot);
+        for key in &keys {
+            range.extend(key);
+        }
+        let (left, right) = range.split_at(&pivot);
+        for key in &keys {
+            let part = if *key <= pivot { left } else { right };
+            assert!(part.may_intersect(key, Some(key)));
+            assert!(range.union(&part) == range);
+        }
+    }
+
+    #[test]
+    fn prunes_disjoint_ranges() {
+        let range = KeyRange::new(b"bar", b"foo");
+        assert!(!range.may_intersect(b"fop", None));
+        assert!(!range.may_intersect(b"a", Some(b"bap")));
+        assert!(range.may_intersect(b"a", Some(b"bar")));
+        assert!(range.may_intersect(b"foo", Some(b"zzz")));
+    }
+}
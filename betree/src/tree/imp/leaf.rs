@@ -7,7 +7,12 @@ use crate::{
     tree::{imp::packed, pivot_key::LocalPivotKey, KeyInfo, MessageAction},
     AtomicStoragePreference, StoragePreference,
 };
-use std::{borrow::Borrow, collections::BTreeMap, iter::FromIterator};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+    iter::FromIterator,
+    mem::replace,
+};
 
 /// A leaf node of the tree holds pairs of keys values which are plain data.
 #[derive(Debug, Clone)]
@@ -18,6 +23,9 @@ pub(super) struct LeafNode {
     system_storage_preference: AtomicSystemStoragePreference,
     entries_size: usize,
     entries: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
+    /// The keys changed since the leaf has been read from disk, `None` if
+    /// they are not known, e.g. after a split.
+    changed: Option<BTreeSet<CowBytes>>,
 }
 
 /// Case-dependent outcome of a rebalance operation.
@@ -32,6 +40,8 @@ pub(super) enum FillUpResult {
     },
 }
 
+// The size of the packed leaf without prefix compression, which bounds the
+// size of the packed leaf.
 impl Size for LeafNode {
     fn size(&self) -> usize {
         packed::HEADER_FIXED_LEN + self.entries_size
@@ -76,10 +86,10 @@ impl HasStoragePreference for LeafNode {
     }
 }
 
-impl<'a> FromIterator<(&'a [u8], (KeyInfo, SlicedCowBytes))> for LeafNode {
+impl<K: Into<CowBytes>> FromIterator<(K, (KeyInfo, SlicedCowBytes))> for LeafNode {
     fn from_iter<T>(iter: T) -> Self
     where
-        T: IntoIterator<Item = (&'a [u8], (KeyInfo, SlicedCowBytes))>,
+        T: IntoIterator<Item = (K, (KeyInfo, SlicedCowBytes))>,
     {
         let mut storage_pref = StoragePreference::NONE;
         let mut entries_size = 0;
@@ -92,15 +102,16 @@ impl<'a> FromIterator<(&'a [u8], (KeyInfo, SlicedCowBytes))> for LeafNode {
             // We're already looking at every entry here, so finding the overall pref here
             // avoids a full scan later.
             storage_pref.upgrade(keyinfo.storage_preference);
-            entries_size += packed::ENTRY_LEN + key.len() + value.len();
+            let key: CowBytes = key.into();
+            let key_len = key.len();
+            entries_size += packed::ENTRY_LEN + key_len + value.len();
 
             let curr_storage_pref = keyinfo.storage_preference;
-            if let Some((ckeyinfo, cvalue)) = entries.insert(CowBytes::from(key), (keyinfo, value))
-            {
+            if let Some((ckeyinfo, cvalue)) = entries.insert(key, (keyinfo, value)) {
                 // iterator has collisions, try to compensate
                 //
                 // this entry will no longer be part of the final map, subtract its size
-                entries_size -= packed::ENTRY_LEN + key.len() + cvalue.len();
+                entries_size -= packed::ENTRY_LEN + key_len + cvalue.len();
 
                 // In case the old value increased the overall storage priority (faster), and the new
                 // value wouldn't have increased it as much, we might need to recalculate the
@@ -123,6 +134,7 @@ impl<'a> FromIterator<(&'a [u8], (KeyInfo, SlicedCowBytes))> for LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size,
             entries,
+            changed: None,
         }
     }
 }
@@ -135,6 +147,7 @@ impl LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size: 0,
             entries: BTreeMap::new(),
+            changed: None,
         }
     }
 
@@ -152,9 +165,53 @@ impl LeafNode {
     }
 
     pub(in crate::tree) fn entry_info(&mut self, key: &[u8]) -> Option<&mut KeyInfo> {
+        self.record_change(key);
         self.entries.get_mut(key).map(|e| &mut e.0)
     }
 
+    /// Starts recording the keys which are changed from now on, or stops
+    /// recording them if `track` is false.
+    pub(super) fn track_changes(&mut self, track: bool) {
+        self.changed = if track { Some(BTreeSet::new()) } else { None };
+    }
+
+    // Remembers that the entry `key` has been changed.
+    fn record_change(&mut self, key: &[u8]) {
+        if let Some(changed) = &mut self.changed {
+            if !changed.contains(key) {
+                changed.insert(CowBytes::from(key));
+            }
+        }
+    }
+
+    /// Returns the changed keys along with their current entries, `None`
+    /// for removed ones, if they have been tracked.
+    pub(super) fn changes(
+        &self,
+    ) -> Option<impl Iterator<Item = (&CowBytes, Option<&(KeyInfo, SlicedCowBytes)>)> + '_> {
+        self.changed
+            .as_ref()
+            .map(|changed| changed.iter().map(move |key| (key, self.entries.get(key))))
+    }
+
+    /// Sets the entry `key` to `entry` or removes it, as done when applying
+    /// a delta.  The entry is recorded as changed.
+    pub(super) fn set_entry(&mut self, key: CowBytes, entry: Option<(KeyInfo, SlicedCowBytes)>) {
+        self.record_change(&key);
+        let key_size = key.len();
+        self.storage_preference.invalidate();
+        let old = match entry {
+            Some(entry) => {
+                self.entries_size += packed::ENTRY_LEN + key_size + entry.1.len();
+                self.entries.insert(key, entry)
+            }
+            None => self.entries.remove(&key),
+        };
+        if let Some((_, old_data)) = old {
+            self.entries_size -= packed::ENTRY_LEN + key_size + old_data.len();
+        }
+    }
+
     /// Split the node and transfer entries to a given other node `right_sibling`.
     /// Use entries which are, when summed up in-order, above the `min_size` limit.
     /// Returns new pivot key and size delta to the left sibling.
@@ -182,6 +239,7 @@ impl LeafNode {
         let split_key = split_key.unwrap();
 
         right_sibling.entries = self.entries.split_off(&split_key);
+        self.changed = None;
         self.entries_size -= sibling_size;
         right_sibling.entries_size = sibling_size;
         right_sibling.storage_preference.set(sibling_pref);
@@ -195,15 +253,20 @@ impl LeafNode {
         (pivot_key, size_delta)
     }
 
+    /// Sets the storage preference of the entry `key` and returns its
+    /// previous info.
     pub fn apply<K>(&mut self, key: K, pref: StoragePreference) -> Option<KeyInfo>
     where
         K: Borrow<[u8]>,
     {
         self.storage_preference.invalidate();
-        self.entries.get_mut(key.borrow()).map(|entry| {
-            entry.0.storage_preference = pref;
-            entry.0.clone()
-        })
+        if self.entries.contains_key(key.borrow()) {
+            self.record_change(key.borrow());
+        }
+        self.entries
+            .get_mut(key.borrow())
+            .map(|entry| replace(&mut entry.0.storage_preference, pref))
+            .map(|storage_preference| KeyInfo { storage_preference })
     }
 
     /// Inserts a new message as leaf entry.
@@ -222,6 +285,7 @@ impl LeafNode {
         let key_size = key.borrow().len();
         let mut data = self.get(key.borrow());
         msg_action.apply_to_leaf(key.borrow(), msg, &mut data);
+        self.record_change(key.borrow());
 
         if let Some(data) = data {
             // Value was added or preserved by msg
@@ -295,6 +359,7 @@ impl LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size: 0,
             entries: BTreeMap::new(),
+            changed: None,
         };
 
         // This adjusts sibling's size and pref according to its new entries
@@ -313,6 +378,8 @@ impl LeafNode {
     /// node.
     pub fn merge(&mut self, right_sibling: &mut Self) -> isize {
         self.entries.append(&mut right_sibling.entries);
+        self.changed = None;
+        right_sibling.changed = None;
         let size_delta = right_sibling.entries_size;
         self.entries_size += right_sibling.entries_size;
 
@@ -434,7 +501,7 @@ mod tests {
 
     fn serialized_size(leaf_node: &LeafNode) -> usize {
         let mut data = Vec::new();
-        PackedMap::pack(leaf_node, &mut data).unwrap();
+        PackedMap::pack_uncompressed(leaf_node, &mut data).unwrap();
         data.len()
     }
 
@@ -463,7 +530,7 @@ mod tests {
     fn check_serialization(leaf_node: LeafNode) {
         let mut data = Vec::new();
         PackedMap::pack(&leaf_node, &mut data).unwrap();
-        let twin = PackedMap::new(data).unpack_leaf();
+        let twin = PackedMap::new(data.into()).unpack_leaf();
 
         assert_eq!(leaf_node, twin);
     }
@@ -482,6 +549,28 @@ mod tests {
         assert_eq!({ serialized_size(&leaf_node) }, size_after);
     }
 
+    #[quickcheck]
+    fn check_changes_reproduce_leaf(
+        mut leaf_node: LeafNode,
+        key: CowBytes,
+        key_info: KeyInfo,
+        msg: DefaultMessageActionMsg,
+    ) {
+        let mut base = leaf_node.clone();
+        leaf_node.track_changes(true);
+        leaf_node.insert(key, key_info, msg.0, DefaultMessageAction);
+        let changes: Vec<_> = leaf_node
+            .changes()
+            .unwrap()
+            .map(|(key, entry)| (key.clone(), entry.cloned()))
+            .collect();
+        for (key, entry) in changes {
+            base.set_entry(key, entry);
+        }
+        assert_eq!(base.entries(), leaf_node.entries());
+        assert_eq!(base.size(), leaf_node.size());
+    }
+
     const MIN_LEAF_SIZE: usize = 512;
     const MAX_LEAF_SIZE: usize = 2048;
 
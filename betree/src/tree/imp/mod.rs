@@ -1,12 +1,14 @@
 //! Implementation of tree structures.
 use self::{
+    append::AppendDetector,
     derivate_ref::DerivateRef,
     node::{ApplyResult, GetResult, PivotGetMutResult, PivotGetResult},
 };
 use super::{
+    dump::{DumpDetail, TreeDump},
     errors::*,
     layer::{ErasedTreeSync, TreeLayer},
-    PivotKey,
+    AtomicOperationStatistics, OperationStatistics, PivotKey,
 };
 use crate::{
     cache::AddSize,
@@ -20,8 +22,15 @@ use crate::{
 };
 use leaf::FillUpResult;
 use owning_ref::OwningRef;
-use parking_lot::{RwLock, RwLockWriteGuard};
-use std::{borrow::Borrow, marker::PhantomData, mem, ops::RangeBounds};
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+use std::{
+    borrow::Borrow,
+    marker::PhantomData,
+    mem,
+    ops::RangeBounds,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 /// Additional information for a single entry. Concerns meta information like
 /// the desired storage level of a key.
@@ -65,6 +74,7 @@ pub struct Tree<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>> {
     evict: bool,
     marker: PhantomData<M>,
     storage_preference: StoragePreference,
+    flush_size: FlushSize,
 }
 
 impl<X: Clone + Dml, M, I: Clone + Borrow<Inner<X::ObjectRef, M>>> Clone for Tree<X, M, I> {
@@ -75,6 +85,7 @@ impl<X: Clone + Dml, M, I: Clone + Borrow<Inner<X::ObjectRef, M>>> Clone for Tre
             evict: self.evict,
             marker: PhantomData,
             storage_preference: self.storage_preference,
+            flush_size: self.flush_size,
         }
     }
 }
@@ -84,6 +95,11 @@ pub struct Inner<R, M> {
     root_node: RwLock<R>,
     tree_id: Option<DatasetId>,
     msg_action: M,
+    // Incremented whenever nodes are split, merged or flushed, which
+    // invalidates concurrent lookups without lock coupling.
+    structure_version: AtomicU64,
+    stats: AtomicOperationStatistics,
+    appends: Mutex<AppendDetector>,
 }
 
 impl<R, M> Inner<R, M> {
@@ -92,6 +108,9 @@ impl<R, M> Inner<R, M> {
             tree_id: Some(tree_id),
             root_node: RwLock::new(root_node),
             msg_action,
+            structure_version: AtomicU64::new(0),
+            stats: AtomicOperationStatistics::default(),
+            appends: Mutex::default(),
         }
     }
 
@@ -101,6 +120,9 @@ impl<R, M> Inner<R, M> {
             tree_id: None,
             root_node: RwLock::new(root_node),
             msg_action,
+            structure_version: AtomicU64::new(0),
+            stats: AtomicOperationStatistics::default(),
+            appends: Mutex::default(),
         }
     }
 
@@ -158,6 +180,7 @@ where
             evict: true,
             marker: PhantomData,
             storage_preference,
+            flush_size: FlushSize::default(),
         }
     }
 }
@@ -187,6 +210,7 @@ where
             evict,
             marker: PhantomData,
             storage_preference,
+            flush_size: FlushSize::default(),
         }
     }
 
@@ -195,6 +219,16 @@ where
         &self.dml
     }
 
+    /// Sets the size from which on buffered messages are flushed.
+    pub fn set_flush_size(&mut self, flush_size: FlushSize) {
+        self.flush_size = flush_size;
+    }
+
+    /// Sets the storage preference of messages inserted without one.
+    pub fn set_storage_preference(&mut self, storage_preference: StoragePreference) {
+        self.storage_preference = storage_preference;
+    }
+
     /// Locks the root node.
     /// Returns `None` if the root node is modified.
     pub fn try_lock_root(&self) -> Option<OwningRef<RwLockWriteGuard<R>, X::ObjectPointer>> {
@@ -219,10 +253,15 @@ where
             .expect("Mutating operations called on read only tree")
     }
 
-    fn msg_action(&self) -> &M {
+    pub(crate) fn msg_action(&self) -> &M {
         &self.inner.borrow().msg_action
     }
 
+    /// Returns the operation counters of this tree.
+    pub(crate) fn stats(&self) -> &AtomicOperationStatistics {
+        &self.inner.borrow().stats
+    }
+
     fn get_mut_root_node(&self) -> Result<X::CacheValueRefMut, Error> {
         if let Some(node) = self.dml.try_get_mut(&self.inner.borrow().root_node.read()) {
             return Ok(node);
@@ -297,6 +336,185 @@ where
         self.get_mut_node_mut(np_ref.get_mut())
     }
 
+    /// Marks all nodes which are stored at an object pointer matching
+    /// `relocate` as modified, so that they are written to a new location on
+    /// the next sync.  Returns the number of relocated nodes.
+    ///
+    /// Only the paths to matching nodes are modified, leaves are not fetched
+    /// unless they have to be relocated themselves.
+    pub(crate) fn relocate_nodes(
+        &self,
+        relocate: &dyn Fn(&X::ObjectPointer) -> bool,
+    ) -> Result<usize, Error> {
+        let root_matches = self
+            .inner
+            .borrow()
+            .root_node
+            .read()
+            .get_unmodified()
+            .map_or(false, relocate);
+        if !root_matches && self.count_relocations(&self.get_root_node()?, relocate)? == 0 {
+            return Ok(0);
+        }
+        let relocated = {
+            let mut root = self.get_mut_root_node()?;
+            root_matches as usize + self.relocate_children(&mut root, relocate)?
+        };
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(relocated)
+    }
+
+    // Counts the descendants of `node` which match `relocate`.
+    fn count_relocations(
+        &self,
+        node: &Node<R>,
+        relocate: &dyn Fn(&X::ObjectPointer) -> bool,
+    ) -> Result<usize, Error> {
+        let level = node.level();
+        let mut count = 0;
+        for np in node.child_pointer_iter().into_iter().flatten() {
+            if np.read().get_unmodified().map_or(false, relocate) {
+                count += 1;
+            }
+            if level > 1 {
+                count += self.count_relocations(&self.get_node(np)?, relocate)?;
+            }
+        }
+        Ok(count)
+    }
+
+    // Modifies all descendants of `node` which match `relocate` and the
+    // nodes on the paths to them.
+    fn relocate_children(
+        &self,
+        node: &mut Node<R>,
+        relocate: &dyn Fn(&X::ObjectPointer) -> bool,
+    ) -> Result<usize, Error> {
+        let level = node.level();
+        let mut relocated = 0;
+        for np in node.child_pointer_iter_mut().into_iter().flatten() {
+            let matches = np.get_unmodified().map_or(false, relocate);
+            if level == 1 {
+                if matches {
+                    self.get_mut_node_mut(np)?;
+                    relocated += 1;
+                }
+                continue;
+            }
+            let pending = matches || {
+                let child = match self.dml.try_get(np) {
+                    Some(child) => child,
+                    None => self.dml.get(np)?,
+                };
+                self.count_relocations(&child, relocate)? > 0
+            };
+            if pending {
+                let mut child = self.get_mut_node_mut(np)?;
+                relocated += matches as usize + self.relocate_children(&mut child, relocate)?;
+            }
+        }
+        Ok(relocated)
+    }
+
+    /// Fetches all nodes at `min_level` and above into the cache, leaves are
+    /// at level 0.  Returns the number of fetched nodes.
+    pub(crate) fn prewarm(&self, min_level: u32) -> Result<usize, Error> {
+        Ok(1 + self.prewarm_children(&self.get_root_node()?, min_level)?)
+    }
+
+    // Fetches the descendants of `node` at `min_level` and above.
+    fn prewarm_children(&self, node: &Node<R>, min_level: u32) -> Result<usize, Error> {
+        if node.level() <= min_level {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for np in node.child_pointer_iter().into_iter().flatten() {
+            count += 1 + self.prewarm_children(&self.get_node(np)?, min_level)?;
+        }
+        Ok(count)
+    }
+
+    /// Calls `f` with the object pointers of all nodes which have not been
+    /// modified since they were last written.  Leaves are not fetched.
+    pub(crate) fn for_each_pointer(
+        &self,
+        f: &mut dyn FnMut(&X::ObjectPointer),
+    ) -> Result<(), Error> {
+        if let Some(ptr) = self.inner.borrow().root_node.read().get_unmodified() {
+            f(ptr);
+        }
+        self.for_each_child_pointer(&self.get_root_node()?, f)?;
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(())
+    }
+
+    // Calls `f` with the unmodified object pointers below `node`.
+    fn for_each_child_pointer(
+        &self,
+        node: &Node<R>,
+        f: &mut dyn FnMut(&X::ObjectPointer),
+    ) -> Result<(), Error> {
+        let level = node.level();
+        for np in node.child_pointer_iter().into_iter().flatten() {
+            if let Some(ptr) = np.read().get_unmodified() {
+                f(ptr);
+            }
+            if level > 1 {
+                self.for_each_child_pointer(&self.get_node(np)?, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the object pointers of all nodes which may hold keys from
+    /// `min_key` up to the inclusive `max_key` and have not been modified
+    /// since they were last written, parents before their children.  Leaves
+    /// are not fetched.
+    pub(crate) fn pointers_in_range(
+        &self,
+        min_key: &[u8],
+        max_key: Option<&[u8]>,
+    ) -> Result<Vec<X::ObjectPointer>, Error> {
+        let mut pointers = Vec::new();
+        if let Some(ptr) = self.inner.borrow().root_node.read().get_unmodified() {
+            pointers.push(ptr.clone());
+        }
+        self.child_pointers_in_range(&self.get_root_node()?, min_key, max_key, &mut pointers)?;
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(pointers)
+    }
+
+    // Collects the unmodified object pointers below `node` which may hold
+    // keys of the range.
+    fn child_pointers_in_range(
+        &self,
+        node: &Node<R>,
+        min_key: &[u8],
+        max_key: Option<&[u8]>,
+        pointers: &mut Vec<X::ObjectPointer>,
+    ) -> Result<(), Error> {
+        let level = node.level();
+        for np in node
+            .child_pointers_in_range(min_key, max_key)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(ptr) = np.read().get_unmodified() {
+                pointers.push(ptr.clone());
+            }
+            if level > 1 {
+                self.child_pointers_in_range(&self.get_node(np)?, min_key, max_key, pointers)?;
+            }
+        }
+        Ok(())
+    }
+
     /*fn walk_tree(
         &self,
         mut node: X::CacheValueRefMut,
@@ -366,6 +584,16 @@ where
         Ok(root.node_info(&self.dml))
     }
 
+    /// Returns the shape of the tree in the stable [TreeDump] format.
+    pub fn dump(&self, detail: DumpDetail) -> Result<TreeDump, Error>
+    where
+        X::ObjectRef: HasStoragePreference,
+    {
+        let root = self.get_root_node()?;
+
+        Ok(TreeDump::new(root.node_info(&self.dml), detail))
+    }
+
     //    pub fn is_modified(&mut self) -> bool {
     //        self.inner.borrow_mut().root_node.is_modified()
     //    }
@@ -375,6 +603,12 @@ where
         key: K,
     ) -> Result<Option<(KeyInfo, SlicedCowBytes)>, Error> {
         let key = key.borrow();
+        if let Some(result) = self.get_optimistic(key) {
+            if self.evict {
+                self.dml.evict()?;
+            }
+            return Ok(result);
+        }
         let mut msgs = Vec::new();
         let mut node = self.get_root_node()?;
         let data = loop {
@@ -386,12 +620,18 @@ where
         };
 
         match data {
-            None => Ok(None),
+            None => {
+                self.stats().record_get(0, msgs.len());
+                Ok(None)
+            }
             Some((info, data)) => {
+                let buffered = msgs.len();
                 let mut tmp = Some(data);
                 for (_keyinfo, msg) in msgs.into_iter().rev() {
                     self.msg_action().apply(key, &msg, &mut tmp);
                 }
+                self.stats()
+                    .record_get(tmp.as_ref().map_or(0, |data| data.len()), buffered);
 
                 drop(node);
                 if self.evict {
@@ -402,6 +642,110 @@ where
         }
     }
 
+    // Looks up `key` while locking only one node at a time instead of
+    // coupling the locks of parent and child, so that readers do not hold
+    // the upper nodes while descending.  Returns `None` if a node on the path
+    // is not cached or the tree has been restructured in the meantime, then
+    // the lookup has to be repeated with lock coupling.
+    fn get_optimistic(&self, key: &[u8]) -> Option<Option<(KeyInfo, SlicedCowBytes)>> {
+        let inner = self.inner.borrow();
+        let version = inner.structure_version.load(Ordering::Acquire);
+        let mut msgs = Vec::new();
+        let mut np = inner.root_node.read().clone();
+        let data = loop {
+            let node = self.dml.try_get(&np)?;
+            np = match node.get(key, &mut msgs) {
+                GetResult::NextNode(child_np) => child_np.read().clone(),
+                GetResult::Data(data) => break data,
+            };
+        };
+        // A restructuring started after the version has been read may have
+        // moved entries between the visited nodes.
+        if inner.structure_version.load(Ordering::Acquire) != version {
+            return None;
+        }
+        let buffered = msgs.len();
+        let result = data.and_then(|(info, data)| {
+            let mut tmp = Some(data);
+            for (_keyinfo, msg) in msgs.into_iter().rev() {
+                self.msg_action().apply(key, &msg, &mut tmp);
+            }
+            tmp.map(|data| (info, data))
+        });
+        inner.stats.record_get(
+            result.as_ref().map_or(0, |(_info, data)| data.len()),
+            buffered,
+        );
+        Some(result)
+    }
+
+    // Invalidates concurrent lookups without lock coupling.  Has to be called
+    // while the topmost node to be restructured is locked, before any of the
+    // involved nodes is modified.
+    pub(super) fn begin_restructuring(&self) {
+        self.inner
+            .borrow()
+            .structure_version
+            .fetch_add(1, Ordering::Release);
+    }
+
+    /// Looks up `key` like [TreeLayer::get], but waits for the reads of nodes
+    /// which are not cached without blocking the calling thread.
+    pub(crate) async fn get_async(&self, key: &[u8]) -> Result<Option<SlicedCowBytes>, Error> {
+        loop {
+            let prefetch = match self.get_or_prefetch(key)? {
+                Ok(data) => return Ok(data),
+                Err(prefetch) => prefetch,
+            };
+            self.dml.finish_prefetch_async(prefetch).await?;
+        }
+    }
+
+    // Looks up `key` in the cached nodes. Returns the prefetch of the first
+    // node on the path which is not cached, the lookup has to be repeated
+    // once it is finished.
+    fn get_or_prefetch(
+        &self,
+        key: &[u8],
+    ) -> Result<Result<Option<SlicedCowBytes>, X::Prefetch>, Error> {
+        let mut msgs = Vec::new();
+        let mut node = match self.dml.try_get(&self.inner.borrow().root_node.read()) {
+            Some(node) => node,
+            None => match self.dml.prefetch(&self.inner.borrow().root_node.read())? {
+                Some(prefetch) => return Ok(Err(prefetch)),
+                None => self.get_root_node()?,
+            },
+        };
+        let data = loop {
+            let next_node = match node.get(key, &mut msgs) {
+                GetResult::NextNode(np) => match self.dml.try_get(&np.read()) {
+                    Some(node) => node,
+                    None => match self.dml.prefetch(&np.read())? {
+                        Some(prefetch) => return Ok(Err(prefetch)),
+                        None => self.get_node(np)?,
+                    },
+                },
+                GetResult::Data(data) => break data,
+            };
+            node = next_node;
+        };
+
+        let buffered = msgs.len();
+        let mut tmp = data.map(|(_info, data)| data);
+        if tmp.is_some() {
+            for (_keyinfo, msg) in msgs.into_iter().rev() {
+                self.msg_action().apply(key, &msg, &mut tmp);
+            }
+        }
+        self.stats()
+            .record_get(tmp.as_ref().map_or(0, |data| data.len()), buffered);
+        drop(node);
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(Ok(tmp))
+    }
+
     /// "Piercing" update, with insertion logic of a B-Tree.
     /// To keep data sanity only modification of the key information is allowed
     /// and all key infos on the paths will be updated to reflect this change.
@@ -422,44 +766,47 @@ where
         });
         res
     }
-}
-
-// impl<X, R, M> Tree<X, M, Arc<Inner<X::ObjectRef, X::Info, M>>>
-// where
-//     X: Dml<Object = Node<Message<M>, R>, ObjectRef = R>,
-//     R: ObjectRef,
-//     M: MessageAction,
-// {
-//     pub fn is_modified(&mut self) -> Option<bool> {
-// Arc::get_mut(&mut self.inner).map(|inner|
-// inner.root_node.is_modified())
-//     }
-// }
 
-impl<X, R, M, I> TreeLayer<M> for Tree<X, M, I>
-where
-    X: Dml<Object = Node<R>, ObjectRef = R>,
-    R: ObjectReference<ObjectPointer = X::ObjectPointer> + HasStoragePreference,
-    M: MessageAction,
-    I: Borrow<Inner<X::ObjectRef, M>>,
-{
-    fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>, Error> {
-        self.get_with_info(key)
-            .map(|res| res.map(|(_info, data)| data))
+    /// Inserts a message like [TreeLayer::insert], but leaves the rebalancing
+    /// of the node the message ended up in to a later call of
+    /// [Tree::rebalance_path] with the same key.  Returns whether such a call
+    /// is necessary.
+    ///
+    /// Only nodes which are less than twice as large as allowed are left
+    /// overfull, larger nodes are rebalanced right away.
+    pub(crate) fn insert_deferred<K>(
+        &self,
+        key: K,
+        msg: SlicedCowBytes,
+        storage_preference: StoragePreference,
+    ) -> Result<bool, Error>
+    where
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        self.insert_node(key, msg, storage_preference, true)
     }
 
-    fn insert<K>(
+    // Inserts a message into the topmost node on the path to `key` which has
+    // to take it, or into the rightmost leaf if it is an append, and
+    // rebalances that node, unless `defer` is set and the node is not far too
+    // large.  Returns whether the rebalancing has been
+    // deferred.
+    fn insert_node<K>(
         &self,
         key: K,
         msg: SlicedCowBytes,
         storage_preference: StoragePreference,
-    ) -> Result<(), Error>
+        defer: bool,
+    ) -> Result<bool, Error>
     where
         K: Borrow<[u8]> + Into<CowBytes>,
     {
         if key.borrow().is_empty() {
             return Err(Error::EmptyKey);
         }
+        // Appends go straight to the rightmost leaf, even if it is not cached,
+        // as long as no buffer on the way holds messages they would overtake.
+        let append = self.inner.borrow().appends.lock().record(key.borrow());
         let mut parent = None;
         let mut node = {
             let mut node = self.get_mut_root_node()?;
@@ -470,6 +817,9 @@ where
                         {
                             node = child;
                             parent = Some(child_buffer);
+                        } else if append && child_buffer.may_bypass(key.borrow()) {
+                            node = self.get_mut_node(child_buffer.node_pointer_mut())?;
+                            parent = Some(child_buffer);
                         } else {
                             break child_buffer.into_owner();
                         }
@@ -479,6 +829,7 @@ where
             }
         };
 
+        self.stats().record_insert(key.borrow().len() + msg.len());
         let op_preference = storage_preference.or(self.storage_preference);
         let added_size = node.insert(key, msg, self.msg_action(), op_preference);
         node.add_size(added_size);
@@ -490,7 +841,13 @@ where
             unimplemented!();
         }
 
-        self.rebalance_tree(node, parent)?;
+        let deferred = defer && node.is_too_large() && !node.is_far_too_large();
+        if deferred {
+            drop(node);
+            drop(parent);
+        } else {
+            self.rebalance_tree(node, parent)?;
+        }
 
         // All non-root trees will start the eviction process.
         // TODO: Is the eviction on root trees harmful? Evictions started by
@@ -498,7 +855,45 @@ where
         if self.evict {
             self.dml.evict()?;
         }
-        Ok(())
+        Ok(deferred)
+    }
+}
+
+// impl<X, R, M> Tree<X, M, Arc<Inner<X::ObjectRef, X::Info, M>>>
+// where
+//     X: Dml<Object = Node<Message<M>, R>, ObjectRef = R>,
+//     R: ObjectRef,
+//     M: MessageAction,
+// {
+//     pub fn is_modified(&mut self) -> Option<bool> {
+// Arc::get_mut(&mut self.inner).map(|inner|
+// inner.root_node.is_modified())
+//     }
+// }
+
+impl<X, R, M, I> TreeLayer<M> for Tree<X, M, I>
+where
+    X: Dml<Object = Node<R>, ObjectRef = R>,
+    R: ObjectReference<ObjectPointer = X::ObjectPointer> + HasStoragePreference,
+    M: MessageAction,
+    I: Borrow<Inner<X::ObjectRef, M>>,
+{
+    fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>, Error> {
+        self.get_with_info(key)
+            .map(|res| res.map(|(_info, data)| data))
+    }
+
+    fn insert<K>(
+        &self,
+        key: K,
+        msg: SlicedCowBytes,
+        storage_preference: StoragePreference,
+    ) -> Result<(), Error>
+    where
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        self.insert_node(key, msg, storage_preference, false)
+            .map(|_| ())
     }
 
     fn depth(&self) -> Result<u32, Error> {
@@ -518,6 +913,7 @@ where
         if !is_inclusive_non_empty(&range) {
             return Err(Error::InvalidRange);
         }
+        self.stats().record_range();
         Ok(RangeIterator::new(range, self.clone()))
     }
 
@@ -533,10 +929,10 @@ where
 
 impl<X, R, M, I> ErasedTreeSync for Tree<X, M, I>
 where
-    X: Dml<Object = Node<R>, ObjectRef = R>,
+    X: Dml<Object = Node<R>, ObjectRef = R> + Clone,
     R: ObjectReference<ObjectPointer = X::ObjectPointer> + HasStoragePreference,
     M: MessageAction,
-    I: Borrow<Inner<R, M>>,
+    I: Borrow<Inner<R, M>> + Clone,
 {
     type Pointer = X::ObjectPointer;
     type ObjectRef = R;
@@ -548,12 +944,44 @@ where
     ) -> Option<OwningRef<RwLockWriteGuard<Self::ObjectRef>, Self::Pointer>> {
         self.try_lock_root()
     }
+    fn erased_relocate_nodes(
+        &self,
+        relocate: &dyn Fn(&Self::Pointer) -> bool,
+    ) -> Result<usize, Error> {
+        self.relocate_nodes(relocate)
+    }
+    fn erased_for_each_pointer(&self, f: &mut dyn FnMut(&Self::Pointer)) -> Result<(), Error> {
+        self.for_each_pointer(f)
+    }
+    fn erased_insert(&self, key: CowBytes, msg: SlicedCowBytes) -> Result<(), Error> {
+        TreeLayer::insert(self, key, msg, StoragePreference::NONE)
+    }
+    fn erased_compact_buffers(
+        &self,
+        min_size: usize,
+        cold_after: Duration,
+        max_flushes: usize,
+    ) -> Result<usize, Error> {
+        self.compact_buffers(min_size, cold_after, max_flushes)
+    }
+    fn erased_stats(&self) -> OperationStatistics {
+        self.stats().as_stats()
+    }
+    fn erased_range(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes), Error>> + '_>, Error>
+    {
+        Ok(Box::new(TreeLayer::range::<&[u8], _>(self, ..)?))
+    }
 }
 
+mod append;
+mod bloom;
 mod child_buffer;
 mod derivate_ref;
 mod flush;
 mod internal;
+mod key_range;
 mod leaf;
 mod node;
 mod packed;
@@ -561,6 +989,12 @@ mod range;
 mod split;
 
 pub use self::{
-    node::{Node, NodeInfo},
+    flush::FlushSize,
+    node::{ChildInfo, Node, NodeInfo},
     range::RangeIterator,
 };
+
+pub(crate) use self::node::NodeContents;
+
+#[cfg(test)]
+pub(crate) use self::node::legacy;
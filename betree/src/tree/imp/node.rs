@@ -1,35 +1,61 @@
 //! Implementation of the generic node wrapper.
 use self::Inner::*;
 use super::{
-    child_buffer::ChildBuffer,
+    child_buffer::{ChildBuffer, LegacyChildBuffer},
     internal::{InternalNode, TakeChildBuffer},
+    key_range::KeyRange,
     leaf::LeafNode,
-    packed::PackedMap,
+    packed::{self, PackedMap},
     FillUpResult, KeyInfo, PivotKey, MAX_INTERNAL_NODE_SIZE, MAX_LEAF_NODE_SIZE, MIN_FANOUT,
-    MIN_FLUSH_SIZE, MIN_LEAF_NODE_SIZE,
+    MIN_LEAF_NODE_SIZE,
 };
 use crate::{
+    buffer::Buf,
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::{Dml, HasStoragePreference, Object, ObjectReference},
-    database::DatasetId,
+    database::{DatasetId, FORMAT_VERSION},
     size::{Size, SizeMut, StaticSize},
     storage_pool::DiskOffset,
     tree::{pivot_key::LocalPivotKey, MessageAction},
     StoragePreference,
 };
 use bincode::{deserialize, serialize_into};
+use byteorder::{ByteOrder, LittleEndian};
 use parking_lot::RwLock;
 use std::{
     borrow::Borrow,
     collections::BTreeMap,
     io::{self, Write},
+    iter,
     mem::replace,
+    time::Duration,
 };
 
 /// The tree node type.
 #[derive(Debug)]
 pub struct Node<N: 'static>(Inner<N>);
 
+// Nodes start with a header of their kind, the format version they have been
+// written in (u16) and a marker.  Nodes of format version 1 have no header,
+// they start with the entry count of a leaf, which is below 2^24 apart from
+// the prefix compression flag, or with the marker of an internal node.  The
+// last byte tells them apart.
+const NODE_HEADER_LEN: usize = 4;
+const NODE_HEADER_MARKER: u8 = 0xFE;
+const LEAF_KIND: u8 = 0;
+const INTERNAL_KIND: u8 = 1;
+// Deltas of leaves, which are only readable along with the node they refer
+// to, see `Node::pack_delta`.
+const DELTA_KIND: u8 = 2;
+// Marks internal nodes of format version 1.
+const LEGACY_INTERNAL_MARKER: [u8; 4] = [0xFF; 4];
+
+fn node_header(kind: u8) -> [u8; NODE_HEADER_LEN] {
+    let mut header = [kind, 0, 0, NODE_HEADER_MARKER];
+    LittleEndian::write_u16(&mut header[1..3], FORMAT_VERSION as u16);
+    header
+}
+
 #[derive(Debug)]
 pub(super) enum Inner<N: 'static> {
     PackedLeaf(PackedMap),
@@ -80,20 +106,55 @@ impl<R: HasStoragePreference + StaticSize> HasStoragePreference for Node<R> {
 
 impl<R: ObjectReference + HasStoragePreference> Object<R> for Node<R> {
     fn pack<W: Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        // Nodes read in an older format are written in the current one.
         match self.0 {
-            PackedLeaf(ref map) => writer.write_all(map.inner()),
-            Leaf(ref leaf) => PackedMap::pack(leaf, writer),
+            PackedLeaf(ref map) => {
+                writer.write_all(&node_header(LEAF_KIND))?;
+                writer.write_all(map.inner())
+            }
+            Leaf(ref leaf) => {
+                writer.write_all(&node_header(LEAF_KIND))?;
+                PackedMap::pack(leaf, writer)
+            }
             Internal(ref internal) => {
-                writer.write_all(&[0xFFu8, 0xFF, 0xFF, 0xFF] as &[u8])?;
+                writer.write_all(&node_header(INTERNAL_KIND))?;
                 serialize_into(writer, internal)
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
             }
         }
     }
 
-    fn unpack_at(_offset: DiskOffset, d_id: DatasetId, data: Box<[u8]>) -> Result<Self, io::Error> {
-        if data[..4] == [0xFFu8, 0xFF, 0xFF, 0xFF] {
-            match deserialize::<InternalNode<_>>(&data[4..]) {
+    fn unpack_at(_offset: DiskOffset, d_id: DatasetId, data: Buf) -> Result<Self, io::Error> {
+        let (kind, legacy, start) = if data[NODE_HEADER_LEN - 1] == NODE_HEADER_MARKER {
+            let version = LittleEndian::read_u16(&data[1..3]);
+            if u32::from(version) > FORMAT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Node of unsupported format version {version}"),
+                ));
+            }
+            let kind = data[0];
+            if kind == DELTA_KIND {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Delta of a leaf cannot be read without its base",
+                ));
+            }
+            (kind, false, NODE_HEADER_LEN)
+        } else if data[..NODE_HEADER_LEN] == LEGACY_INTERNAL_MARKER {
+            (INTERNAL_KIND, true, NODE_HEADER_LEN)
+        } else {
+            // Leaves of format version 1 are laid out as the current ones.
+            (LEAF_KIND, true, 0)
+        };
+        if kind == INTERNAL_KIND {
+            let internal = if legacy {
+                deserialize::<InternalNode<LegacyChildBuffer<_>>>(&data[start..])
+                    .map(InternalNode::from_legacy)
+            } else {
+                deserialize::<InternalNode<_>>(&data[start..])
+            };
+            match internal {
                 Ok(internal) => Ok(Node(Internal(internal.complete_object_refs(d_id)))),
                 Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
             }
@@ -103,7 +164,12 @@ impl<R: ObjectReference + HasStoragePreference> Object<R> for Node<R> {
             // and every modification requires them to be unpacked.
             // The leaf contents are scanned cheaply during unpacking, which
             // recalculates the correct storage_preference for the contained keys.
-            Ok(Node(PackedLeaf(PackedMap::new(data.into_vec()))))
+            // The leaf stays on the buffer it has been read into.
+            let end = data.len();
+            Ok(Node(PackedLeaf(PackedMap::new(CowBytes::from_buf(
+                data,
+                start..end,
+            )))))
         }
     }
 
@@ -133,17 +199,17 @@ impl<R: ObjectReference + HasStoragePreference> Object<R> for Node<R> {
 impl<N: StaticSize> Size for Node<N> {
     fn size(&self) -> usize {
         match self.0 {
-            PackedLeaf(ref map) => map.size(),
-            Leaf(ref leaf) => leaf.size(),
-            Internal(ref internal) => 4 + internal.size(),
+            PackedLeaf(ref map) => NODE_HEADER_LEN + map.size(),
+            Leaf(ref leaf) => NODE_HEADER_LEN + leaf.size(),
+            Internal(ref internal) => NODE_HEADER_LEN + internal.size(),
         }
     }
 
     fn actual_size(&self) -> Option<usize> {
         match self.0 {
-            PackedLeaf(ref map) => map.actual_size(),
-            Leaf(ref leaf) => leaf.actual_size(),
-            Internal(ref internal) => internal.actual_size().map(|size| 4 + size),
+            PackedLeaf(ref map) => map.actual_size().map(|size| NODE_HEADER_LEN + size),
+            Leaf(ref leaf) => leaf.actual_size().map(|size| NODE_HEADER_LEN + size),
+            Internal(ref internal) => internal.actual_size().map(|size| NODE_HEADER_LEN + size),
         }
     }
 }
@@ -156,17 +222,49 @@ impl<N: StaticSize + HasStoragePreference> Node<N> {
         }
     }
 
-    pub(super) fn try_find_flush_candidate(&mut self) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+    pub(super) fn walk(&mut self, key: &[u8]) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => None,
+            Internal(ref mut internal) => Some(internal.walk(key)),
+        }
+    }
+
+    pub(super) fn try_find_flush_candidate(
+        &mut self,
+        min_flush_size: usize,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => None,
             Internal(ref mut internal) => internal.try_find_flush_candidate(
-                MIN_FLUSH_SIZE,
+                min_flush_size,
                 MAX_INTERNAL_NODE_SIZE,
                 MIN_FANOUT,
             ),
         }
     }
 
+    pub(super) fn cold_buffers(&self, min_size: usize, cold_after: Duration) -> Vec<CowBytes> {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => Vec::new(),
+            Internal(ref internal) => internal
+                .cold_buffers(min_size, cold_after)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub(super) fn try_find_cold_buffer(
+        &mut self,
+        key: &[u8],
+        min_size: usize,
+        cold_after: Duration,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => None,
+            Internal(ref mut internal) => internal.try_find_cold_buffer(key, min_size, cold_after),
+        }
+    }
+
     pub(super) fn is_too_large(&self) -> bool {
         match self.0 {
             PackedLeaf(ref map) => map.size() > MAX_LEAF_NODE_SIZE,
@@ -174,6 +272,15 @@ impl<N: StaticSize + HasStoragePreference> Node<N> {
             Internal(ref internal) => internal.size() > MAX_INTERNAL_NODE_SIZE,
         }
     }
+
+    /// Whether the node is too large to defer its rebalancing any further.
+    pub(super) fn is_far_too_large(&self) -> bool {
+        match self.0 {
+            PackedLeaf(ref map) => map.size() > 2 * MAX_LEAF_NODE_SIZE,
+            Leaf(ref leaf) => leaf.size() > 2 * MAX_LEAF_NODE_SIZE,
+            Internal(ref internal) => internal.size() > 2 * MAX_INTERNAL_NODE_SIZE,
+        }
+    }
 }
 
 impl<N: HasStoragePreference + StaticSize> Node<N> {
@@ -191,6 +298,28 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         }
     }
 
+    /// Returns bounds of the keys in this node and below it.
+    pub(super) fn key_range(&self) -> KeyRange {
+        match self.0 {
+            PackedLeaf(ref map) => match map.entry_count() {
+                0 => KeyRange::all(),
+                count => {
+                    let (first, _) = map.get_full_by_index(0).unwrap();
+                    let (last, _) = map.get_full_by_index(count - 1).unwrap();
+                    KeyRange::new(&first, &last)
+                }
+            },
+            Leaf(ref leaf) => {
+                let entries = leaf.entries();
+                match (entries.keys().next(), entries.keys().next_back()) {
+                    (Some(first), Some(last)) => KeyRange::new(first, last),
+                    _ => KeyRange::all(),
+                }
+            }
+            Internal(ref internal) => internal.key_range(),
+        }
+    }
+
     fn ensure_unpacked(&mut self) -> isize {
         let before = self.size();
 
@@ -201,10 +330,74 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         };
 
         self.0 = Leaf(leaf);
+        self.reset_changes(true);
         let after = self.size();
         after as isize - before as isize
     }
 
+    /// Packs the entries of a leaf which have changed since it has been read
+    /// from disk, unless they make up more than `max_share` of its size or
+    /// are not known.  Returns whether the delta has been written.
+    pub(crate) fn pack_delta<W: Write>(&self, max_share: f32, mut writer: W) -> io::Result<bool> {
+        let (leaf, changes) = match self.0 {
+            Leaf(ref leaf) => match leaf.changes() {
+                Some(changes) => (leaf, changes.collect::<Vec<_>>()),
+                None => return Ok(false),
+            },
+            PackedLeaf(_) | Internal(_) => return Ok(false),
+        };
+        let changed_size: usize = changes
+            .iter()
+            .map(|(key, entry)| {
+                packed::ENTRY_LEN + key.len() + entry.map_or(0, |(_, value)| value.len())
+            })
+            .sum();
+        if changed_size as f32 > max_share * leaf.size() as f32 {
+            return Ok(false);
+        }
+        writer.write_all(&node_header(DELTA_KIND))?;
+        let system_preference = leaf.system_storage_preference().as_u8();
+        serialize_into(writer, &(system_preference, changes))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(true)
+    }
+
+    /// Applies a delta written by [Node::pack_delta] to the leaf it refers
+    /// to.  The entries of the delta count as changed afterwards.
+    pub(crate) fn apply_delta(&mut self, data: &[u8]) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if data.len() < NODE_HEADER_LEN
+            || data[0] != DELTA_KIND
+            || data[NODE_HEADER_LEN - 1] != NODE_HEADER_MARKER
+        {
+            return Err(invalid("Not a delta of a leaf"));
+        }
+        if u32::from(LittleEndian::read_u16(&data[1..3])) > FORMAT_VERSION {
+            return Err(invalid("Delta of unsupported format version"));
+        }
+        type Entries = Vec<(CowBytes, Option<(KeyInfo, SlicedCowBytes)>)>;
+        let (system_preference, entries): (u8, Entries) = deserialize(&data[NODE_HEADER_LEN..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.ensure_unpacked();
+        let leaf = match self.0 {
+            Leaf(ref mut leaf) => leaf,
+            PackedLeaf(_) | Internal(_) => return Err(invalid("Base of a delta is no leaf")),
+        };
+        leaf.set_system_storage_preference(StoragePreference::from_u8(system_preference));
+        for (key, entry) in entries {
+            leaf.set_entry(key, entry);
+        }
+        Ok(())
+    }
+
+    /// Restarts recording the changes of a leaf once it has been written in
+    /// full, or stops recording them if `track` is false.
+    pub(crate) fn reset_changes(&mut self, track: bool) {
+        if let Leaf(ref mut leaf) = self.0 {
+            leaf.track_changes(track);
+        }
+    }
+
     fn take(&mut self) -> Self {
         replace(self, Self::empty_leaf())
     }
@@ -243,7 +436,7 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         Node(Leaf(LeafNode::new()))
     }
 
-    pub(super) fn level(&self) -> u32 {
+    pub(crate) fn level(&self) -> u32 {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => 0,
             Internal(ref internal) => internal.level(),
@@ -280,15 +473,17 @@ impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
             }
         };
         debug!("Root split pivot key: {:?}", pivot_key);
+        let (left_key_range, right_key_range) =
+            (left_sibling.key_range(), right_sibling.key_range());
         *self = Node(Internal(InternalNode::new(
-            ChildBuffer::new(allocate_obj(
-                left_sibling,
-                LocalPivotKey::LeftOuter(pivot_key.clone()),
-            )),
-            ChildBuffer::new(allocate_obj(
-                right_sibling,
-                LocalPivotKey::Right(pivot_key.clone()),
-            )),
+            ChildBuffer::new(
+                allocate_obj(left_sibling, LocalPivotKey::LeftOuter(pivot_key.clone())),
+                left_key_range,
+            ),
+            ChildBuffer::new(
+                allocate_obj(right_sibling, LocalPivotKey::Right(pivot_key.clone())),
+                right_key_range,
+            ),
             pivot_key,
             cur_level + 1,
         )));
@@ -321,11 +516,13 @@ pub(super) enum GetRangeResult<'a, T, N: 'a> {
     Data(T),
     NextNode {
         np: &'a RwLock<N>,
-        prefetch_option: Option<&'a RwLock<N>>,
+        /// The following siblings of `np` if they are leaves.
+        next_leaves: Option<Box<dyn Iterator<Item = &'a RwLock<N>> + 'a>>,
     },
 }
 
 impl<N: HasStoragePreference> Node<N> {
+    // Packed leaves are searched in place, so lookups never unpack a leaf.
     pub(super) fn get(
         &self,
         key: &[u8],
@@ -346,27 +543,30 @@ impl<N: HasStoragePreference> Node<N> {
 
     pub(super) fn get_range<'a>(
         &'a self,
-        key: &[u8],
+        key: &'a [u8],
+        max_key: Option<&'a [u8]>,
         left_pivot_key: &mut Option<CowBytes>,
         right_pivot_key: &mut Option<CowBytes>,
         all_msgs: &mut BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
-    ) -> GetRangeResult<Box<dyn Iterator<Item = (&'a [u8], (KeyInfo, SlicedCowBytes))> + 'a>, N>
+    ) -> GetRangeResult<Box<dyn Iterator<Item = (CowBytes, (KeyInfo, SlicedCowBytes))> + 'a>, N>
     {
         match self.0 {
             PackedLeaf(ref map) => GetRangeResult::Data(Box::new(map.get_all())),
             Leaf(ref leaf) => GetRangeResult::Data(Box::new(
-                leaf.entries().iter().map(|(k, v)| (&k[..], v.clone())),
+                leaf.entries().iter().map(|(k, v)| (k.clone(), v.clone())),
             )),
             Internal(ref internal) => {
-                let prefetch_option = if internal.level() == 1 {
-                    internal.get_next_node(key)
+                let next_leaves = if internal.level() == 1 {
+                    let next_leaves: Box<dyn Iterator<Item = _>> =
+                        Box::new(internal.get_next_nodes(key, max_key));
+                    Some(next_leaves)
                 } else {
                     None
                 };
-                let np = internal.get_range(key, left_pivot_key, right_pivot_key, all_msgs);
-                GetRangeResult::NextNode {
-                    prefetch_option,
-                    np,
+                match internal.get_range(key, max_key, left_pivot_key, right_pivot_key, all_msgs) {
+                    Some(np) => GetRangeResult::NextNode { next_leaves, np },
+                    // No child may hold keys of the range.
+                    None => GetRangeResult::Data(Box::new(iter::empty())),
                 }
             }
         }
@@ -470,6 +670,17 @@ impl<N: HasStoragePreference> Node<N> {
         }
     }
 
+    pub(super) fn child_pointers_in_range<'a>(
+        &'a self,
+        min_key: &'a [u8],
+        max_key: Option<&'a [u8]>,
+    ) -> Option<impl Iterator<Item = &'a RwLock<N>> + 'a> {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => None,
+            Internal(ref internal) => Some(internal.children_in_range(min_key, max_key)),
+        }
+    }
+
     pub(super) fn drain_children(&mut self) -> Option<impl Iterator<Item = N> + '_> {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => None,
@@ -546,11 +757,55 @@ impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
     }*/
 }
 
+/// The contents of a node without any cached state, see
+/// [Node::into_contents].
+pub(crate) enum NodeContents<N> {
+    /// The children of an internal node with the messages buffered for each.
+    Internal {
+        level: u32,
+        children: Vec<(N, BTreeMap<CowBytes, SlicedCowBytes>)>,
+    },
+    /// The entries of a leaf in ascending order.
+    Leaf(Vec<(CowBytes, SlicedCowBytes)>),
+}
+
+impl<N: 'static> Node<N> {
+    pub(crate) fn into_contents(self) -> NodeContents<N> {
+        match self.0 {
+            PackedLeaf(packed) => NodeContents::Leaf(
+                packed
+                    .get_all()
+                    .map(|(key, (_, value))| (key, value))
+                    .collect(),
+            ),
+            Leaf(leaf) => NodeContents::Leaf(
+                leaf.entries()
+                    .iter()
+                    .map(|(key, (_, value))| (key.clone(), value.clone()))
+                    .collect(),
+            ),
+            Internal(internal) => NodeContents::Internal {
+                level: internal.level(),
+                children: internal
+                    .into_buffers()
+                    .map(|(child, buffer)| {
+                        let messages = buffer
+                            .into_iter()
+                            .map(|(key, (_, msg))| (key, msg))
+                            .collect();
+                        (child, messages)
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 pub struct ChildInfo {
-    from: Option<ByteString>,
-    to: Option<ByteString>,
-    storage: StoragePreference,
+    pub(crate) from: Option<ByteString>,
+    pub(crate) to: Option<ByteString>,
+    pub(crate) storage: StoragePreference,
     pub pivot_key: PivotKey,
     pub child: NodeInfo,
 }
@@ -576,7 +831,7 @@ pub enum NodeInfo {
     },
 }
 
-pub struct ByteString(Vec<u8>);
+pub struct ByteString(pub(crate) Vec<u8>);
 
 impl serde::Serialize for ByteString {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -668,3 +923,66 @@ impl<N: HasStoragePreference + ObjectReference> Node<N> {
         }
     }
 }
+
+/// Packs nodes in the layout of format version 1, to build storage pools of
+/// that version in tests.
+#[cfg(test)]
+pub(crate) mod legacy {
+    use super::{KeyInfo, LeafNode, PackedMap, LEGACY_INTERNAL_MARKER};
+    use crate::{
+        cow_bytes::{CowBytes, SlicedCowBytes},
+        size::{Size, StaticSize},
+        AtomicStoragePreference, StoragePreference,
+    };
+    use bincode::serialize_into;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    const KEY_INFO: KeyInfo = KeyInfo {
+        storage_preference: StoragePreference::NONE,
+    };
+
+    /// Packs a leaf holding `entries`.
+    pub(crate) fn pack_leaf(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let leaf: LeafNode = entries
+            .iter()
+            .map(|&(key, value)| (key, (KEY_INFO, SlicedCowBytes::from(CowBytes::from(value)))))
+            .collect();
+        let mut data = Vec::new();
+        PackedMap::pack_uncompressed(&leaf, &mut data).unwrap();
+        data
+    }
+
+    /// Packs an internal node on level `level`, whose children are given by
+    /// the messages buffered for them and their legacy object pointer.
+    pub(crate) fn pack_internal<P: Serialize>(
+        level: u32,
+        pivots: &[&[u8]],
+        children: Vec<(Vec<(&[u8], SlicedCowBytes)>, P)>,
+    ) -> Vec<u8> {
+        let pivots: Vec<CowBytes> = pivots.iter().map(|&pivot| pivot.into()).collect();
+        let children: Vec<_> = children
+            .into_iter()
+            .map(|(messages, ptr)| {
+                let buffer: BTreeMap<CowBytes, _> = messages
+                    .into_iter()
+                    .map(|(key, msg)| (key.into(), (KEY_INFO, msg)))
+                    .collect();
+                let buffer_entries_size = buffer
+                    .iter()
+                    .map(|(key, (_, msg))| key.size() + msg.size() + KeyInfo::static_size())
+                    .sum::<usize>();
+                (
+                    AtomicStoragePreference::known(StoragePreference::NONE),
+                    buffer_entries_size,
+                    buffer,
+                    ptr,
+                )
+            })
+            .collect();
+        // The size of the entries is determined anew when reading the node.
+        let mut data = LEGACY_INTERNAL_MARKER.to_vec();
+        serialize_into(&mut data, &(level, 0usize, pivots, children)).unwrap();
+        data
+    }
+}
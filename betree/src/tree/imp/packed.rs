@@ -29,11 +29,20 @@ pub(crate) const ENTRY_KEY_OFFSET: usize = 0;
 pub(crate) const ENTRY_KEY_INFO_OFFSET: usize = ENTRY_KEY_OFFSET + OFFSET_LEN;
 pub(crate) const ENTRY_DATA_OFFSET: usize = ENTRY_KEY_INFO_OFFSET + 1;
 
+// Set in the entry count of leaves with prefix compressed keys.
+const PREFIX_COMPRESSED: u32 = 1 << 31;
+// Additionally the length of the prefix shared with the previous key (u16)
+const COMPRESSED_ENTRY_LEN: usize = ENTRY_LEN + size_of::<u16>();
+const ENTRY_SHARED_OFFSET: usize = ENTRY_DATA_OFFSET + OFFSET_LEN;
+// Every this many entries a key is stored in full, lookups start from these.
+const RESTART_INTERVAL: u32 = 16;
+
 /// On-disk serialized leaf node. Simplified to a map contains 40 bytes of
 /// headers followed by data.
 ///
 /// ```text
 /// Layout:
+///     # the highest bit is set if the keys are prefix compressed
 ///     entry_count: u32,
 ///     system_pref: u8,
 ///     entries: [Entry; entry_count],
@@ -48,7 +57,10 @@ pub(crate) const ENTRY_DATA_OFFSET: usize = ENTRY_KEY_INFO_OFFSET + 1;
 /// Entry:
 ///     key_pos: Offset,
 ///     key_info: KeyInfo,
-///     data_pos: Offset
+///     data_pos: Offset,
+///     # only if prefix compressed, the key is stored without its first
+///     # shared_len bytes, which are the same as those of the previous key
+///     shared_len: u16
 ///
 /// # 2^24 byte ~= 16.7MB, plenty at a max node size of 4MiB
 /// Offset:
@@ -58,10 +70,16 @@ pub(crate) const ENTRY_DATA_OFFSET: usize = ENTRY_KEY_INFO_OFFSET + 1;
 ///     storage_preference: u8
 ///
 /// ```
+///
+/// Keys are only prefix compressed if that saves space.  Every
+/// `RESTART_INTERVAL` entries a key is stored in full, so that lookups can
+/// binary search these keys and only have to decompress the keys following
+/// them.
 #[derive(Debug)]
 pub(crate) struct PackedMap {
     entry_count: u32,
     system_preference: u8,
+    compressed: bool,
     data: CowBytes,
 }
 
@@ -69,20 +87,61 @@ pub(crate) struct PackedMap {
 #[derive(Debug, Copy, Clone)]
 struct Offset(u32);
 
-fn prefix_size(entry_count: u32) -> usize {
-    HEADER_FIXED_LEN + ENTRY_LEN * entry_count as usize
+fn prefix_size(entry_count: u32, entry_len: usize) -> usize {
+    HEADER_FIXED_LEN + entry_len * entry_count as usize
+}
+
+// The lengths of the prefixes the keys share with their predecessors.
+fn shared_lens<'a, I>(keys: I) -> impl Iterator<Item = usize> + 'a
+where
+    I: Iterator<Item = &'a [u8]> + 'a,
+{
+    let mut previous: &[u8] = &[];
+    keys.enumerate().map(move |(idx, key)| {
+        let shared = if idx as u32 % RESTART_INTERVAL == 0 {
+            0
+        } else {
+            previous
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+                .min(u16::MAX as usize)
+        };
+        previous = key;
+        shared
+    })
 }
 
 impl PackedMap {
-    pub fn new(data: Vec<u8>) -> Self {
+    pub fn new(data: CowBytes) -> Self {
         debug_assert!(data.len() >= 4);
         let entry_count = LittleEndian::read_u32(&data[..4]);
         let system_preference = data[4];
 
         PackedMap {
-            data: data.into(),
-            entry_count,
+            data,
+            entry_count: entry_count & !PREFIX_COMPRESSED,
             system_preference,
+            compressed: entry_count & PREFIX_COMPRESSED != 0,
+        }
+    }
+
+    fn entry_pos(&self, idx: u32) -> usize {
+        let entry_len = if self.compressed {
+            COMPRESSED_ENTRY_LEN
+        } else {
+            ENTRY_LEN
+        };
+        HEADER_LEN + idx as usize * entry_len
+    }
+
+    // Entries whose keys are stored in full are at multiples of this.
+    fn restart_interval(&self) -> u32 {
+        if self.compressed {
+            RESTART_INTERVAL
+        } else {
+            1
         }
     }
 
@@ -93,11 +152,12 @@ impl PackedMap {
     }
 
     // In the data segment, the value is always written directly after the key,
-    // so the key length can be calculated by subtraction.
+    // so the key length can be calculated by subtraction.  For compressed keys
+    // this is the position of the part not shared with the previous key.
     fn key_pos(&self, idx: u32) -> (Offset, u32) {
         debug_assert!(idx < self.entry_count);
 
-        let entry_pos = HEADER_LEN + idx as usize * ENTRY_LEN;
+        let entry_pos = self.entry_pos(idx);
 
         let key_offset = self.read_offset(entry_pos + ENTRY_KEY_OFFSET);
         let data_offset = self.read_offset(entry_pos + ENTRY_DATA_OFFSET);
@@ -111,21 +171,48 @@ impl PackedMap {
     fn val_pos(&self, idx: u32) -> (Offset, u32) {
         debug_assert!(idx < self.entry_count);
 
-        let entry_pos = HEADER_LEN + idx as usize * ENTRY_LEN;
+        let entry_pos = self.entry_pos(idx);
         let data_offset = self.read_offset(entry_pos + ENTRY_DATA_OFFSET);
 
         // this works even for the last entry, as a single offset is appended to the last full
         // entry, and the key offset comes first, so the rest of that fake entry is not missed.
-        let next_entry_pos = entry_pos + ENTRY_LEN;
+        let next_entry_pos = self.entry_pos(idx + 1);
         let next_key_offset = self.read_offset(next_entry_pos + ENTRY_KEY_OFFSET);
 
         let data_len = next_key_offset.0 - data_offset.0;
         (data_offset, data_len)
     }
 
+    fn shared_len(&self, idx: u32) -> usize {
+        debug_assert!(idx < self.entry_count);
+        if !self.compressed {
+            return 0;
+        }
+        let entry_pos = self.entry_pos(idx) + ENTRY_SHARED_OFFSET;
+        LittleEndian::read_u16(&self.data[entry_pos..]) as usize
+    }
+
+    // Replaces `key`, which has to hold the previous key, with the one of
+    // entry `idx`.
+    fn next_key(&self, idx: u32, key: &mut Vec<u8>) {
+        key.truncate(self.shared_len(idx));
+        key.extend_from_slice(self.get_slice(self.key_pos(idx)));
+    }
+
+    fn key(&self, idx: u32) -> SlicedCowBytes {
+        if !self.compressed {
+            return self.get_slice_cow(self.key_pos(idx));
+        }
+        let mut key = Vec::new();
+        for idx in idx - idx % RESTART_INTERVAL..=idx {
+            self.next_key(idx, &mut key);
+        }
+        CowBytes::from(key).into()
+    }
+
     fn key_info(&self, idx: u32) -> KeyInfo {
         debug_assert!(idx < self.entry_count);
-        let entry_pos = HEADER_LEN + idx as usize * ENTRY_LEN;
+        let entry_pos = self.entry_pos(idx);
 
         KeyInfo {
             storage_preference: StoragePreference::from_u8(
@@ -142,27 +229,41 @@ impl PackedMap {
         self.data.clone().slice(pos, len)
     }
 
-    // Adapted from std::slice::binary_search_by
+    // Adapted from std::slice::binary_search_by, searches the keys stored in
+    // full first and then the keys following the last one not greater than
+    // `key`.
     fn binary_search(&self, key: &[u8]) -> Result<u32, u32> {
         use cmp::Ordering::*;
-        let mut size = self.entry_count;
-        if size == 0 {
+        if self.entry_count == 0 {
             return Err(0);
         }
+        let interval = self.restart_interval();
+        let mut size = (self.entry_count + interval - 1) / interval;
         let mut base = 0;
         while size > 1 {
             let half = size / 2;
             let mid = base + half;
-            let cmp = self.get_slice(self.key_pos(mid)).cmp(key);
+            let cmp = self.get_slice(self.key_pos(mid * interval)).cmp(key);
             base = if cmp == Greater { base } else { mid };
             size -= half;
         }
-        let cmp = self.get_slice(self.key_pos(base)).cmp(key);
-        if cmp == Equal {
-            Ok(base)
-        } else {
-            Err(base + (cmp == Less) as u32)
+        let start = base * interval;
+        let end = cmp::min(start + interval, self.entry_count);
+        let mut current = Vec::new();
+        for idx in start..end {
+            let cmp = if self.compressed {
+                self.next_key(idx, &mut current);
+                current[..].cmp(key)
+            } else {
+                self.get_slice(self.key_pos(idx)).cmp(key)
+            };
+            match cmp {
+                Less => {}
+                Equal => return Ok(idx),
+                Greater => return Err(idx),
+            }
         }
+        Err(end)
     }
 
     pub fn get_by_index(&self, idx: u32) -> Option<(KeyInfo, SlicedCowBytes)> {
@@ -174,11 +275,13 @@ impl PackedMap {
         idx: u32,
     ) -> Option<(SlicedCowBytes, (KeyInfo, SlicedCowBytes))> {
         Some((
-            self.get_slice_cow(self.key_pos(idx)),
+            self.key(idx),
             (self.key_info(idx), self.get_slice_cow(self.val_pos(idx))),
         ))
     }
 
+    /// Looks up `key` by binary searching the packed entries.  Nothing is
+    /// deserialized, the returned value references the packed data.
     pub fn get(&self, key: &[u8]) -> Option<(KeyInfo, SlicedCowBytes)> {
         let result = self.binary_search(key);
         let idx = match result {
@@ -189,18 +292,20 @@ impl PackedMap {
         self.get_by_index(idx)
     }
 
-    pub fn get_all(&self) -> impl Iterator<Item = (&[u8], (KeyInfo, SlicedCowBytes))> + '_ {
+    pub fn get_all(&self) -> impl Iterator<Item = (CowBytes, (KeyInfo, SlicedCowBytes))> + '_ {
         struct Iter<'a> {
             packed: &'a PackedMap,
             idx: u32,
+            key: Vec<u8>,
         }
         impl<'a> Iterator for Iter<'a> {
-            type Item = (&'a [u8], (KeyInfo, SlicedCowBytes));
+            type Item = (CowBytes, (KeyInfo, SlicedCowBytes));
 
             fn next(&mut self) -> Option<Self::Item> {
                 if self.idx < self.packed.entry_count {
+                    self.packed.next_key(self.idx, &mut self.key);
                     let ret = Some((
-                        self.packed.get_slice(self.packed.key_pos(self.idx)),
+                        CowBytes::from(&self.key[..]),
                         (
                             self.packed.key_info(self.idx),
                             self.packed.get_slice_cow(self.packed.val_pos(self.idx)),
@@ -217,6 +322,7 @@ impl PackedMap {
         Iter {
             packed: self,
             idx: 0,
+            key: Vec::new(),
         }
     }
 
@@ -227,27 +333,59 @@ impl PackedMap {
         leaf
     }
 
-    pub(super) fn pack<W: Write>(leaf: &LeafNode, mut writer: W) -> io::Result<()> {
+    /// Writes `leaf`, with prefix compressed keys if that saves space.
+    pub(super) fn pack<W: Write>(leaf: &LeafNode, writer: W) -> io::Result<()> {
+        let entries = leaf.entries();
+        let compressed = shared_lens(entries.keys().map(|key| &key[..])).sum::<usize>()
+            > (COMPRESSED_ENTRY_LEN - ENTRY_LEN) * entries.len();
+        Self::write(leaf, writer, compressed)
+    }
+
+    /// Writes `leaf` without compressing the keys, which results in the size
+    /// predicted by [LeafNode].
+    #[cfg(test)]
+    pub(super) fn pack_uncompressed<W: Write>(leaf: &LeafNode, writer: W) -> io::Result<()> {
+        Self::write(leaf, writer, false)
+    }
+
+    fn write<W: Write>(leaf: &LeafNode, mut writer: W, compressed: bool) -> io::Result<()> {
         let entries = leaf.entries();
         let entries_cnt = entries.len() as u32;
-        writer.write_u32::<LittleEndian>(entries_cnt)?;
+        let keys = || entries.keys().map(|key| &key[..]);
+        let (flags, entry_len) = if compressed {
+            (PREFIX_COMPRESSED, COMPRESSED_ENTRY_LEN)
+        } else {
+            (0, ENTRY_LEN)
+        };
+        writer.write_u32::<LittleEndian>(entries_cnt | flags)?;
         writer.write_u8(leaf.system_storage_preference().as_u8())?;
 
-        let mut pos = prefix_size(entries_cnt) as u32;
-        for (key, (keyinfo, value)) in entries {
+        let mut pos = prefix_size(entries_cnt, entry_len) as u32;
+        for ((key, (keyinfo, value)), shared) in entries.iter().zip(shared_lens(keys())) {
             writer.write_u24::<LittleEndian>(pos)?;
             pos += key.len() as u32;
+            if compressed {
+                pos -= shared as u32;
+            }
 
             writer.write_u8(keyinfo.storage_preference.as_u8())?;
 
             writer.write_u24::<LittleEndian>(pos)?;
             pos += value.len() as u32;
+
+            if compressed {
+                writer.write_u16::<LittleEndian>(shared as u16)?;
+            }
         }
 
         writer.write_u24::<LittleEndian>(pos)?;
 
-        for (key, (_keyinfo, value)) in entries {
-            writer.write_all(key)?;
+        for ((key, (_keyinfo, value)), shared) in entries.iter().zip(shared_lens(keys())) {
+            if compressed {
+                writer.write_all(&key[shared..])?;
+            } else {
+                writer.write_all(key)?;
+            }
             writer.write_all(value)?;
         }
         Ok(())
@@ -275,14 +413,13 @@ impl Size for PackedMap {
 #[cfg(test)]
 mod tests {
     use super::{LeafNode, PackedMap};
+    use crate::{
+        size::Size,
+        tree::{DefaultMessageAction, KeyInfo},
+        StoragePreference,
+    };
 
-    #[quickcheck]
-    fn check_packed_contents(leaf: LeafNode) {
-        let mut v = Vec::new();
-        PackedMap::pack(&leaf, &mut v).unwrap();
-
-        let packed = PackedMap::new(v);
-
+    fn check_contents(leaf: &LeafNode, packed: &PackedMap) {
         for (k, (ki, v)) in leaf.entries() {
             let (pki, pv) = packed.get(k).unwrap();
             assert_eq!(ki, &pki, "keyinfo mismatch");
@@ -292,9 +429,72 @@ mod tests {
         assert_eq!(
             leaf.entries()
                 .iter()
-                .map(|(k, v)| (&k[..], v.clone()))
+                .map(|(k, v)| (k.clone(), v.clone()))
                 .collect::<Vec<_>>(),
             packed.get_all().collect::<Vec<_>>()
         );
     }
+
+    #[quickcheck]
+    fn check_packed_contents(leaf: LeafNode) {
+        let mut v = Vec::new();
+        PackedMap::pack(&leaf, &mut v).unwrap();
+        check_contents(&leaf, &PackedMap::new(v.into()));
+
+        let mut v = Vec::new();
+        PackedMap::pack_uncompressed(&leaf, &mut v).unwrap();
+        check_contents(&leaf, &PackedMap::new(v.into()));
+    }
+
+    #[test]
+    fn prefix_compression() {
+        let key = |object: u32, chunk: u32| {
+            let mut key = b"some/rather/long/object/store/path/".to_vec();
+            key.extend_from_slice(&object.to_be_bytes());
+            key.extend_from_slice(&chunk.to_be_bytes());
+            key
+        };
+        let leaf: LeafNode = (0..4)
+            .flat_map(|object| (0..100).map(move |chunk| key(object, 2 * chunk)))
+            .map(|key| {
+                let keyinfo = KeyInfo {
+                    storage_preference: StoragePreference::NONE,
+                };
+                (key, (keyinfo, DefaultMessageAction::insert_msg(b"value")))
+            })
+            .collect();
+        let mut v = Vec::new();
+        PackedMap::pack(&leaf, &mut v).unwrap();
+        let packed = PackedMap::new(v.into());
+        assert!(packed.size() < leaf.size() / 2);
+        check_contents(&leaf, &packed);
+
+        // Keys between and around the stored ones.
+        for object in 0..5 {
+            for chunk in 0..201 {
+                assert_eq!(
+                    packed.get(&key(object, chunk)).is_some(),
+                    object < 4 && chunk % 2 == 0 && chunk < 200
+                );
+            }
+        }
+        assert!(packed.get(b"a").is_none());
+        assert!(packed.get(b"z").is_none());
+        assert_eq!(
+            &packed.get_full_by_index(123).unwrap().0[..],
+            &key(1, 46)[..]
+        );
+    }
+
+    #[quickcheck]
+    fn check_get_references_packed_data(leaf: LeafNode) {
+        let mut v = Vec::new();
+        PackedMap::pack(&leaf, &mut v).unwrap();
+        let packed = PackedMap::new(v.into());
+        let data = packed.inner().as_ptr_range();
+        for key in leaf.entries().keys() {
+            let (_, value) = packed.get(key).unwrap();
+            assert!(data.contains(&value.as_ptr()) || value.is_empty());
+        }
+    }
 }
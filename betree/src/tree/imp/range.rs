@@ -11,10 +11,13 @@ use crate::{
 use std::{
     borrow::Borrow,
     collections::{BTreeMap, Bound, VecDeque},
-    mem::replace,
     ops::RangeBounds,
 };
 
+// The number of leaves following the current one which are fetched ahead
+// during a range query.
+const READAHEAD_LEAVES: usize = 4;
+
 fn increment_pivot_key(v: &mut Vec<u8>) {
     v.push(0);
 }
@@ -27,9 +30,9 @@ enum Bounded<T> {
 
 /// The range iterator over (key,value)-tuples of a tree.
 ///
-/// The iterator performs asynchronous prefetching to allow for a better
-/// utilization of underlying resources. It is advised to use [RangeIterator]
-/// and methods utilizing it in almost all cases.
+/// The iterator performs asynchronous prefetching of the next leaves to allow
+/// for a better utilization of underlying resources. It is advised to use
+/// [RangeIterator] and methods utilizing it in almost all cases.
 pub struct RangeIterator<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>> {
     buffer: VecDeque<(Key, (KeyInfo, Value))>,
     min_key: Bounded<Vec<u8>>,
@@ -37,7 +40,9 @@ pub struct RangeIterator<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>> {
     max_key: Option<Vec<u8>>,
     tree: Tree<X, M, I>,
     finished: bool,
-    prefetch: Option<X::Prefetch>,
+    /// Prefetches of the leaves following the current one in key order,
+    /// `None` for leaves which were cached already.
+    prefetches: VecDeque<Option<X::Prefetch>>,
 }
 
 impl<X, R, M, I> Iterator for RangeIterator<X, M, I>
@@ -52,6 +57,7 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             if let Some((key, (_keyinfo, data))) = self.buffer.pop_front() {
+                self.tree.stats().record_range_entry(data.len());
                 return Some(Ok((key, data)));
             } else if self.finished {
                 return None;
@@ -96,7 +102,7 @@ where
             tree,
             finished: false,
             buffer: VecDeque::new(),
-            prefetch: None,
+            prefetches: VecDeque::new(),
         }
     }
 
@@ -105,8 +111,12 @@ where
             let min_key = match self.min_key {
                 Bounded::Included(ref x) | Bounded::Excluded(ref x) => x,
             };
-            self.tree
-                .leaf_range_query(min_key, &mut self.buffer, &mut self.prefetch)?
+            self.tree.leaf_range_query(
+                min_key,
+                self.max_key.as_deref(),
+                &mut self.buffer,
+                &mut self.prefetches,
+            )?
         };
 
         // Strip entries which are out of bounds from the buffer.
@@ -167,8 +177,9 @@ where
     fn leaf_range_query(
         &self,
         key: &[u8],
+        max_key: Option<&[u8]>,
         data: &mut VecDeque<(CowBytes, (KeyInfo, SlicedCowBytes))>,
-        prefetch: &mut Option<X::Prefetch>,
+        prefetches: &mut VecDeque<Option<X::Prefetch>>,
     ) -> Result<Option<CowBytes>, Error> {
         let result = {
             let mut left_pivot_key = None;
@@ -181,22 +192,26 @@ where
             loop {
                 let next_node = match node.get_range(
                     key,
+                    max_key,
                     &mut left_pivot_key,
                     &mut right_pivot_key,
                     &mut messages,
                 ) {
-                    GetRangeResult::NextNode {
-                        prefetch_option,
-                        np,
-                    } => {
-                        let previous_prefetch = if let Some(prefetch_np) = prefetch_option {
-                            let f = self.dml.prefetch(&prefetch_np.read())?;
-                            replace(prefetch, f)
-                        } else {
-                            prefetch.take()
-                        };
-                        if let Some(previous_prefetch) = previous_prefetch {
-                            self.dml.finish_prefetch(previous_prefetch)?;
+                    GetRangeResult::NextNode { next_leaves, np } => {
+                        if let Some(next_leaves) = next_leaves {
+                            // Leaves are visited in key order, so the oldest
+                            // prefetch is the one of the leaf `np`.
+                            let current = prefetches.pop_front().flatten();
+                            // Top up the prefetches before waiting for the
+                            // current one, so that their IO overlaps with
+                            // applying the messages.
+                            let missing = READAHEAD_LEAVES - prefetches.len();
+                            for leaf in next_leaves.skip(prefetches.len()).take(missing) {
+                                prefetches.push_back(self.dml.prefetch(&leaf.read())?);
+                            }
+                            if let Some(current) = current {
+                                self.dml.finish_prefetch(current)?;
+                            }
                         }
                         self.get_node(np)?
                     }
@@ -221,7 +236,7 @@ where
         result
     }
 
-    fn apply_messages<'a, J>(
+    fn apply_messages<J>(
         &self,
         left_pivot_key: &Option<CowBytes>,
         right_pivot_key: &Option<CowBytes>,
@@ -229,7 +244,7 @@ where
         leaf_entries: J,
         data: &mut VecDeque<(CowBytes, (KeyInfo, SlicedCowBytes))>,
     ) where
-        J: Iterator<Item = (&'a [u8], (KeyInfo, SlicedCowBytes))>,
+        J: Iterator<Item = (CowBytes, (KeyInfo, SlicedCowBytes))>,
     {
         // disregard any messages with keys outside of
         // left_pivot_key..right_pivot_key.
@@ -243,8 +258,6 @@ where
                 None => true,
                 Some(ref max_key) => key <= max_key,
             });
-        let leaf_entries = leaf_entries.map(|(k, v)| (CowBytes::from(k), v));
-
         for (key, msgs, value) in MergeByKeyIterator::new(msgs_iter, leaf_entries) {
             let (mut keyinfo, mut value) = match value {
                 Some((keyinfo, value)) => (Some(keyinfo), Some(value)),
@@ -3,6 +3,7 @@ use super::{child_buffer::ChildBuffer, internal::TakeChildBuffer, Inner, Node, T
 use crate::{
     cache::AddSize,
     data_management::{Dml, HasStoragePreference, ObjectReference},
+    database::Event,
     size::Size,
     tree::{errors::*, MessageAction},
 };
@@ -18,6 +19,7 @@ where
     pub(super) fn split_root_node(&self, mut root_node: X::CacheValueRefMut) {
         self.dml.verify_cache();
         let before = root_node.size();
+        let level = root_node.level();
         debug!(
             "Splitting root. {}, {:?}, {}, {:?}",
             root_node.kind(),
@@ -40,6 +42,10 @@ where
         debug_assert!(before as isize + size_delta == root_node.size() as isize);
         root_node.finish(size_delta);
         self.dml.verify_cache();
+        self.dml.events().emit(|| Event::NodeSplit {
+            dataset: self.tree_id(),
+            level,
+        });
     }
 
     pub(super) fn split_node(
@@ -71,6 +77,10 @@ where
         };
 
         let size_delta = parent.split_child(sibling_np, pivot_key, select_right);
+        self.dml.events().emit(|| Event::NodeSplit {
+            dataset: self.tree_id(),
+            level: node.level(),
+        });
 
         Ok((node, size_delta))
     }
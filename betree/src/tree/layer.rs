@@ -1,5 +1,5 @@
 //! Interface traits for the tree layer of *Haura*.
-use super::{Key, MessageAction, Value};
+use super::{Key, MessageAction, OperationStatistics, Value};
 use crate::{
     cow_bytes::{CowBytes, SlicedCowBytes},
     StoragePreference,
@@ -7,7 +7,7 @@ use crate::{
 use owning_ref::OwningRef;
 use parking_lot::RwLockWriteGuard;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{borrow::Borrow, ops::RangeBounds};
+use std::{borrow::Borrow, ops::RangeBounds, time::Duration};
 
 use super::errors::*;
 
@@ -64,4 +64,21 @@ pub(crate) trait ErasedTreeSync {
     fn erased_try_lock_root(
         &self,
     ) -> Option<OwningRef<RwLockWriteGuard<Self::ObjectRef>, Self::Pointer>>;
+    fn erased_relocate_nodes(
+        &self,
+        relocate: &dyn Fn(&Self::Pointer) -> bool,
+    ) -> Result<usize, Error>;
+    fn erased_for_each_pointer(&self, f: &mut dyn FnMut(&Self::Pointer)) -> Result<(), Error>;
+    fn erased_insert(&self, key: CowBytes, msg: SlicedCowBytes) -> Result<(), Error>;
+    fn erased_compact_buffers(
+        &self,
+        min_size: usize,
+        cold_after: Duration,
+        max_flushes: usize,
+    ) -> Result<usize, Error>;
+    fn erased_stats(&self) -> OperationStatistics;
+    #[allow(clippy::type_complexity)]
+    fn erased_range(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes), Error>> + '_>, Error>;
 }
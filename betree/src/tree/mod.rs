@@ -2,29 +2,43 @@
 //! Layer.
 
 mod default_message_action;
+mod dump;
 mod errors;
 mod imp;
 mod layer;
 mod message_action;
 mod pivot_key;
+mod stats;
 
 use crate::cow_bytes::{CowBytes, SlicedCowBytes};
 
 pub use self::{
     default_message_action::DefaultMessageAction,
-    imp::{Inner, Node, Tree},
+    dump::{ChildDump, DumpDetail, HexBytes, NodeDump, TreeDump, TREE_DUMP_VERSION},
+    imp::{FlushSize, Inner, Node, Tree},
     layer::TreeLayer,
     message_action::MessageAction,
+    stats::OperationStatistics,
 };
 
+pub use self::pivot_key::PivotKey;
+
 #[cfg(not(feature = "internal-api"))]
-pub(crate) use self::{imp::NodeInfo, pivot_key::PivotKey};
+pub(crate) use self::imp::NodeInfo;
 
 #[cfg(feature = "internal-api")]
-pub use self::{imp::NodeInfo, pivot_key::PivotKey};
+pub use self::imp::NodeInfo;
 
 type Key = CowBytes;
 type Value = SlicedCowBytes;
 
 use self::imp::KeyInfo;
-pub(crate) use self::{errors::Error, imp::MAX_MESSAGE_SIZE, layer::ErasedTreeSync};
+pub(crate) use self::{
+    errors::Error,
+    imp::{NodeContents, MAX_MESSAGE_SIZE},
+    layer::ErasedTreeSync,
+    stats::AtomicOperationStatistics,
+};
+
+#[cfg(test)]
+pub(crate) use self::imp::legacy;
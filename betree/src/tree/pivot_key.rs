@@ -30,8 +30,11 @@ use crate::{cow_bytes::CowBytes, database::DatasetId};
 /// ```
 #[derive(Hash, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum PivotKey {
+    /// The leftmost child of a node, identified by the first pivot of the node.
     LeftOuter(CowBytes, DatasetId),
+    /// The child right of the given pivot.
     Right(CowBytes, DatasetId),
+    /// The root node of a tree.
     Root(DatasetId),
 }
 
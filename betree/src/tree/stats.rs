@@ -0,0 +1,78 @@
+//! Operation counters of a tree, see
+//! [Dataset::stats](crate::database::Dataset::stats).
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts the operations performed on a tree since it has been opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct OperationStatistics {
+    /// The number of point lookups
+    pub gets: u64,
+    /// The number of messages inserted, including upserts and deletions
+    pub inserts: u64,
+    /// The number of deletions of single keys, also counted as inserts
+    pub deletes: u64,
+    /// The number of range queries
+    pub range_ops: u64,
+    /// The total size of the values returned by lookups and range queries
+    pub bytes_read: u64,
+    /// The total size of the keys and messages inserted
+    pub bytes_written: u64,
+    /// The number of lookups which had to apply messages still buffered in
+    /// internal nodes
+    pub buffer_hits: u64,
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct AtomicOperationStatistics {
+    gets: AtomicU64,
+    inserts: AtomicU64,
+    deletes: AtomicU64,
+    range_ops: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    buffer_hits: AtomicU64,
+}
+
+impl AtomicOperationStatistics {
+    /// Records a lookup which has returned `bytes` and has applied `msgs`
+    /// buffered messages.
+    pub(crate) fn record_get(&self, bytes: usize, msgs: usize) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        if msgs > 0 {
+            self.buffer_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the insertion of a message of `bytes` including its key.
+    pub(crate) fn record_insert(&self, bytes: usize) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_range(&self) {
+        self.range_ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an entry of `bytes` returned by a range query.
+    pub(crate) fn record_range_entry(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn as_stats(&self) -> OperationStatistics {
+        OperationStatistics {
+            gets: self.gets.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            range_ops: self.range_ops.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            buffer_hits: self.buffer_hits.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(missing_docs, unused_doc_comments)]
 
+use crate::database::ErrorCode;
 use std::sync::Arc;
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -14,6 +15,20 @@ pub enum VdevError {
     Write(String),
     #[error("Spawn: {0}")]
     Spawn(Arc<futures::task::SpawnError>),
+    #[error("Storage class {0} has no vdevs.")]
+    NoVdevs(u8),
+}
+
+impl VdevError {
+    /// Returns the classification of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            VdevError::Checksum(_) => ErrorCode::Checksum,
+            VdevError::Io(_) | VdevError::Read(_) | VdevError::Write(_) => ErrorCode::Io,
+            VdevError::Spawn(_) => ErrorCode::Internal,
+            VdevError::NoVdevs(_) => ErrorCode::InvalidArgument,
+        }
+    }
 }
 
 impl From<std::io::Error> for VdevError {
@@ -0,0 +1,118 @@
+use super::{
+    errors::*, AtomicStatistics, Block, Result, ScrubResult, Statistics, Vdev, VdevLeafRead,
+    VdevLeafWrite, VdevRead,
+};
+use crate::{buffer::Buf, checksum::Checksum};
+use async_trait::async_trait;
+use std::sync::atomic::Ordering;
+
+/// `LeafVdev` standing in for a device which was missing when the storage
+/// pool was opened.  All requests to this vdev fail.
+pub struct Faulted {
+    id: String,
+    stats: AtomicStatistics,
+}
+
+impl Faulted {
+    /// Creates a new `Faulted` vdev.
+    pub fn new(id: String) -> Self {
+        Faulted {
+            id,
+            stats: Default::default(),
+        }
+    }
+
+    fn failed_read(&self, size: Block<u32>) -> VdevError {
+        self.stats
+            .failed_reads
+            .fetch_add(size.as_u64(), Ordering::Relaxed);
+        VdevError::Read(self.id.clone())
+    }
+}
+
+#[async_trait]
+impl VdevRead for Faulted {
+    async fn read<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        _offset: Block<u64>,
+        _checksum: C,
+    ) -> Result<Buf> {
+        Err(self.failed_read(size))
+    }
+
+    async fn scrub<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        _offset: Block<u64>,
+        _checksum: C,
+    ) -> Result<ScrubResult> {
+        Err(self.failed_read(size))
+    }
+
+    async fn read_raw(&self, size: Block<u32>, _offset: Block<u64>) -> Result<Vec<Buf>> {
+        Err(self.failed_read(size))
+    }
+}
+
+impl Vdev for Faulted {
+    fn actual_size(&self, size: Block<u32>) -> Block<u32> {
+        size
+    }
+
+    fn num_disks(&self) -> usize {
+        1
+    }
+
+    fn size(&self) -> Block<u64> {
+        // Do not restrict the size of the parent vdev.
+        Block(u64::MAX)
+    }
+
+    fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
+        free_size
+    }
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn stats(&self) -> Statistics {
+        self.stats.as_stats()
+    }
+
+    fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+
+    fn is_faulted(&self) -> bool {
+        true
+    }
+}
+
+#[async_trait]
+impl VdevLeafRead for Faulted {
+    async fn read_raw<T: AsMut<[u8]> + Send>(&self, mut buf: T, _offset: Block<u64>) -> Result<T> {
+        Err(self.failed_read(Block::from_bytes(buf.as_mut().len() as u32)))
+    }
+
+    fn checksum_error_occurred(&self, _size: Block<u32>) {}
+}
+
+#[async_trait]
+impl VdevLeafWrite for Faulted {
+    async fn write_raw<W: AsRef<[u8]> + Send>(
+        &self,
+        data: W,
+        _offset: Block<u64>,
+        _is_repair: bool,
+    ) -> Result<()> {
+        self.stats.failed_writes.fetch_add(
+            Block::from_bytes(data.as_ref().len() as u64).as_u64(),
+            Ordering::Relaxed,
+        );
+        Err(VdevError::Write(self.id.clone()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -11,40 +11,45 @@ use std::{
         fs::{FileExt, FileTypeExt},
         io::AsRawFd,
     },
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 /// `LeafVdev` that is backed by a file.
 pub struct File {
     file: fs::File,
     id: String,
-    size: Block<u64>,
+    // Size in blocks, which may grow with the underlying device.
+    size: AtomicU64,
     stats: AtomicStatistics,
 }
 
 impl File {
     /// Creates a new `File`.
     pub fn new(file: fs::File, id: String) -> io::Result<Self> {
-        let file_type = file.metadata()?.file_type();
-        let size = if file_type.is_file() {
-            Block::from_bytes(file.metadata()?.len())
-        } else if file_type.is_block_device() {
-            get_block_device_size(&file)?
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported file type: {file_type:?}"),
-            ));
-        };
+        let size = get_size(&file)?;
         Ok(File {
             file,
             id,
-            size,
+            size: AtomicU64::new(size.as_u64()),
             stats: Default::default(),
         })
     }
 }
 
+fn get_size(file: &fs::File) -> io::Result<Block<u64>> {
+    let file_type = file.metadata()?.file_type();
+    if file_type.is_file() {
+        Ok(Block::from_bytes(file.metadata()?.len()))
+    } else if file_type.is_block_device() {
+        get_block_device_size(file)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported file type: {file_type:?}"),
+        ))
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn get_block_device_size(file: &fs::File) -> io::Result<Block<u64>> {
     const BLKGETSIZE64: c_ulong = 2148012658;
@@ -84,7 +89,7 @@ impl VdevRead for File {
                 self.stats
                     .failed_reads
                     .fetch_add(size.as_u64(), Ordering::Relaxed);
-                bail!(e)
+                return Err(e.into());
             }
             #[cfg(feature = "latency_metrics")]
             self.stats.read_op_latency.fetch_add(
@@ -154,7 +159,7 @@ impl VdevRead for File {
                 self.stats
                     .failed_reads
                     .fetch_add(size.as_u64(), Ordering::Relaxed);
-                bail!(e)
+                return Err(e.into());
             }
         }
     }
@@ -170,7 +175,7 @@ impl Vdev for File {
     }
 
     fn size(&self) -> Block<u64> {
-        self.size
+        Block(self.size.load(Ordering::Relaxed))
     }
 
     fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
@@ -186,6 +191,20 @@ impl Vdev for File {
     }
 
     fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+
+    fn refresh_size(&self) -> Result<Block<u64>> {
+        let size = get_size(&self.file)?;
+        // Blocks beyond the new end may still be in use.
+        if size < self.size() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} shrank to {:?}", self.id, size),
+            )
+            .into());
+        }
+        self.size.store(size.as_u64(), Ordering::Relaxed);
+        Ok(size)
+    }
 }
 
 #[async_trait]
@@ -221,7 +240,7 @@ impl VdevLeafRead for File {
                 self.stats
                     .failed_reads
                     .fetch_add(size.as_u64(), Ordering::Relaxed);
-                bail!(e)
+                return Err(e.into());
             }
         }
     }
@@ -0,0 +1,237 @@
+use super::{
+    errors::*, Block, Leaf, Result, ScrubResult, Statistics, Vdev, VdevLeafRead, VdevLeafWrite,
+    VdevRead,
+};
+use crate::{buffer::Buf, checksum::Checksum};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The impairments of an [Impaired] vdev.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Impairments {
+    /// Latency added to every request.
+    pub latency: Duration,
+    /// Upper bound of a uniformly distributed latency added on top of
+    /// `latency`.
+    pub jitter: Duration,
+    /// The number of bytes per second which may be transferred, requests
+    /// are delayed until their data fits into the bandwidth.
+    pub bandwidth: Option<u64>,
+    /// The probability with which a request fails without reaching the
+    /// underlying vdev.
+    pub error_rate: f64,
+}
+
+/// `LeafVdev` that forwards requests to another leaf vdev, but delays them
+/// and lets them fail randomly, so that slow or unreliable devices can be
+/// tested without special hardware.
+/// It is used by [LeafVdev::Impaired](crate::storage_pool::LeafVdev::Impaired).
+pub struct Impaired {
+    inner: Box<Leaf>,
+    impairments: Impairments,
+    // The point in time at which the bandwidth is available again.
+    busy_until: Mutex<Instant>,
+    failed_reads: AtomicU64,
+    failed_writes: AtomicU64,
+}
+
+impl Impaired {
+    /// Creates a new `Impaired` vdev on top of `inner`.
+    pub(crate) fn new(inner: Leaf, impairments: Impairments) -> Self {
+        Impaired {
+            inner: Box::new(inner),
+            impairments,
+            busy_until: Mutex::new(Instant::now()),
+            failed_reads: AtomicU64::new(0),
+            failed_writes: AtomicU64::new(0),
+        }
+    }
+
+    // Blocks for the latency of a request transferring `bytes`.
+    fn delay(&self, bytes: u64) {
+        let mut until = Instant::now() + self.impairments.latency;
+        if !self.impairments.jitter.is_zero() {
+            until += rand::thread_rng().gen_range(Duration::ZERO..=self.impairments.jitter);
+        }
+        if let Some(bandwidth) = self.impairments.bandwidth {
+            let transfer = Duration::from_secs_f64(bytes as f64 / bandwidth.max(1) as f64);
+            let mut busy_until = self.busy_until.lock();
+            *busy_until = (*busy_until).max(Instant::now()) + transfer;
+            until = until.max(*busy_until);
+        }
+        let now = Instant::now();
+        if until > now {
+            thread::sleep(until - now);
+        }
+    }
+
+    fn fails(&self) -> bool {
+        self.impairments.error_rate > 0.0
+            && rand::thread_rng().gen_bool(self.impairments.error_rate.min(1.0))
+    }
+
+    // Delays a read of `size` blocks and decides whether it fails.
+    fn impair_read(&self, size: Block<u32>) -> Result<()> {
+        self.delay(size.to_bytes() as u64);
+        if self.fails() {
+            self.failed_reads
+                .fetch_add(size.as_u64(), Ordering::Relaxed);
+            return Err(VdevError::Read(self.id().to_string()));
+        }
+        Ok(())
+    }
+
+    // Delays a write of `bytes` and decides whether it fails.
+    fn impair_write(&self, bytes: usize) -> Result<()> {
+        self.delay(bytes as u64);
+        if self.fails() {
+            self.failed_writes.fetch_add(
+                Block::round_up_from_bytes(bytes as u64).as_u64(),
+                Ordering::Relaxed,
+            );
+            return Err(VdevError::Write(self.id().to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VdevRead for Impaired {
+    async fn read<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<Buf> {
+        self.impair_read(size)?;
+        VdevRead::read(&*self.inner, size, offset, checksum).await
+    }
+
+    async fn scrub<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<ScrubResult> {
+        self.impair_read(size)?;
+        VdevRead::scrub(&*self.inner, size, offset, checksum).await
+    }
+
+    async fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>> {
+        self.impair_read(size)?;
+        VdevRead::read_raw(&*self.inner, size, offset).await
+    }
+}
+
+impl Vdev for Impaired {
+    fn actual_size(&self, size: Block<u32>) -> Block<u32> {
+        size
+    }
+
+    fn num_disks(&self) -> usize {
+        1
+    }
+
+    fn size(&self) -> Block<u64> {
+        self.inner.size()
+    }
+
+    fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
+        free_size
+    }
+
+    fn id(&self) -> &str {
+        self.inner.id()
+    }
+
+    fn stats(&self) -> Statistics {
+        let mut stats = self.inner.stats();
+        stats.failed_reads += self.failed_reads.load(Ordering::Relaxed);
+        stats.failed_writes += self.failed_writes.load(Ordering::Relaxed);
+        stats
+    }
+
+    fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+
+    fn is_faulted(&self) -> bool {
+        self.inner.is_faulted()
+    }
+
+    fn refresh_size(&self) -> Result<Block<u64>> {
+        self.inner.refresh_size()
+    }
+}
+
+#[async_trait]
+impl VdevLeafRead for Impaired {
+    async fn read_raw<T: AsMut<[u8]> + Send>(&self, mut buf: T, offset: Block<u64>) -> Result<T> {
+        self.impair_read(Block::round_up_from_bytes(buf.as_mut().len() as u32))?;
+        VdevLeafRead::read_raw(&*self.inner, buf, offset).await
+    }
+
+    fn checksum_error_occurred(&self, size: Block<u32>) {
+        self.inner.checksum_error_occurred(size)
+    }
+}
+
+#[async_trait]
+impl VdevLeafWrite for Impaired {
+    async fn write_raw<W: AsRef<[u8]> + Send + 'static>(
+        &self,
+        data: W,
+        offset: Block<u64>,
+        is_repair: bool,
+    ) -> Result<()> {
+        self.impair_write(data.as_ref().len())?;
+        VdevLeafWrite::write_raw(&*self.inner, data, offset, is_repair).await
+    }
+
+    fn flush(&self) -> Result<()> {
+        VdevLeafWrite::flush(&*self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Impaired, Impairments};
+    use crate::vdev::{Block, Leaf, Memory, VdevLeafRead, VdevLeafWrite};
+    use futures::executor::block_on;
+    use std::time::{Duration, Instant};
+
+    fn impaired(impairments: Impairments) -> Impaired {
+        let memory = Memory::new(1024 * 1024, "memory".to_string()).unwrap();
+        Impaired::new(Leaf::Memory(memory), impairments)
+    }
+
+    #[test]
+    fn bandwidth_delays_requests() {
+        let vdev = impaired(Impairments {
+            bandwidth: Some(1024 * 1024),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for i in 0..4 {
+            block_on(vdev.write_raw(vec![1u8; 64 * 1024], Block(i * 16), false)).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn failures_are_counted() {
+        let vdev = impaired(Impairments {
+            error_rate: 1.0,
+            ..Default::default()
+        });
+        assert!(block_on(vdev.write_raw(vec![1u8; 4096], Block(0), false)).is_err());
+        assert!(block_on(VdevLeafRead::read_raw(&vdev, vec![0u8; 8192], Block(0))).is_err());
+        let stats = crate::vdev::Vdev::stats(&vdev);
+        assert_eq!(stats.failed_writes, Block(1));
+        assert_eq!(stats.failed_reads, Block(2));
+    }
+}
@@ -25,12 +25,22 @@ pub struct Memory {
 impl Memory {
     /// Creates a new `File`.
     pub fn new(size: usize, id: String) -> io::Result<Self> {
-        Ok(Memory {
-            mem: RwLock::new(vec![0; size].into_boxed_slice()),
+        Ok(Self::with_contents(vec![0; size].into_boxed_slice(), id))
+    }
+
+    /// Creates a new `Memory` holding `mem`.
+    pub(crate) fn with_contents(mem: Box<[u8]>, id: String) -> Self {
+        Memory {
+            size: Block::from_bytes(mem.len() as u64),
+            mem: RwLock::new(mem),
             id,
-            size: Block::from_bytes(size as u64),
             stats: Default::default(),
-        })
+        }
+    }
+
+    /// Returns a copy of the whole contents.
+    pub(crate) fn contents(&self) -> Box<[u8]> {
+        self.mem.read().clone()
     }
 
     fn slice(&self, size: usize, offset: usize) -> Result<impl Deref<Target = [u8]> + '_> {
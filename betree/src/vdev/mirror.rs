@@ -8,24 +8,141 @@ use futures::{
     prelude::*,
     stream::{FuturesOrdered, FuturesUnordered},
 };
-use std::sync::atomic::Ordering;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Selects the replica a [Mirror] reads from first. The other replicas are
+/// only read if this read fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MirrorReadPolicy {
+    /// Switch the replica every 32 MiB of the address space.
+    Striped,
+    /// Cycle through the replicas with each read.
+    RoundRobin,
+    /// Read from the replica with the fewest reads in flight.
+    QueueDepth,
+    /// Read from the replica with the lowest average read latency.  Replicas
+    /// without measurements are preferred, so that each is measured once.
+    Latency,
+    /// Always read from the first replica.
+    First,
+}
+
+impl Default for MirrorReadPolicy {
+    fn default() -> Self {
+        MirrorReadPolicy::Striped
+    }
+}
+
+impl MirrorReadPolicy {
+    /// The name of this policy in configurations.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MirrorReadPolicy::Striped => "striped",
+            MirrorReadPolicy::RoundRobin => "roundrobin",
+            MirrorReadPolicy::QueueDepth => "queuedepth",
+            MirrorReadPolicy::Latency => "latency",
+            MirrorReadPolicy::First => "first",
+        }
+    }
+
+    /// Parses the name of a policy as returned by `as_str`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            MirrorReadPolicy::Striped,
+            MirrorReadPolicy::RoundRobin,
+            MirrorReadPolicy::QueueDepth,
+            MirrorReadPolicy::Latency,
+            MirrorReadPolicy::First,
+        ]
+        .into_iter()
+        .find(|policy| policy.as_str() == name)
+    }
+}
 
 /// This `vdev` will mirror all data to its child vdevs.
 pub struct Mirror<V> {
     vdevs: Box<[V]>,
     id: String,
     stats: AtomicStatistics,
+    read_policy: MirrorReadPolicy,
+    next_read: AtomicUsize,
+    // Reads in flight per replica.
+    pending_reads: Box<[AtomicUsize]>,
+    // Moving average of the read latency per replica in microseconds, zero if
+    // not measured yet.
+    read_latency: Box<[AtomicU64]>,
+    // Rewritten replicas which have not been taken by `take_repairs` yet.
+    repairs: Mutex<Vec<(String, Block<u64>)>>,
 }
 
 impl<V> Mirror<V> {
     /// Creates a new `Mirror`.
     pub fn new(vdevs: Box<[V]>, id: String) -> Self {
+        let pending_reads = vdevs.iter().map(|_| AtomicUsize::new(0)).collect();
+        let read_latency = vdevs.iter().map(|_| AtomicU64::new(0)).collect();
         Mirror {
             vdevs,
             id,
             stats: Default::default(),
+            read_policy: MirrorReadPolicy::default(),
+            next_read: AtomicUsize::new(0),
+            pending_reads,
+            read_latency,
+            repairs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the policy which selects the replica to read from.
+    pub fn with_read_policy(mut self, read_policy: MirrorReadPolicy) -> Self {
+        self.read_policy = read_policy;
+        self
+    }
+
+    /// Returns the index of the replica to read from first.
+    fn first_replica(&self, offset: Block<u64>) -> usize {
+        let disk_cnt = self.vdevs.len();
+        let least = |load: &dyn Fn(usize) -> u64| {
+            // Ties are broken in turn to spread the load.
+            let start = self.next_read.fetch_add(1, Ordering::Relaxed);
+            (0..disk_cnt)
+                .map(|idx| (start + idx) % disk_cnt)
+                .min_by_key(|&idx| load(idx))
+                .unwrap()
+        };
+        match self.read_policy {
+            // Switch disk every 32 MiB. (which is 2^25 bytes)
+            // TODO 32 MiB too large?
+            MirrorReadPolicy::Striped => (offset.to_bytes() >> 25) as usize % disk_cnt,
+            MirrorReadPolicy::RoundRobin => {
+                self.next_read.fetch_add(1, Ordering::Relaxed) % disk_cnt
+            }
+            MirrorReadPolicy::QueueDepth => {
+                least(&|idx| self.pending_reads[idx].load(Ordering::Relaxed) as u64)
+            }
+            MirrorReadPolicy::Latency => {
+                least(&|idx| self.read_latency[idx].load(Ordering::Relaxed))
+            }
+            MirrorReadPolicy::First => 0,
         }
     }
+
+    fn record_latency(&self, idx: usize, start: Instant) {
+        let sample = (start.elapsed().as_micros() as u64).max(1);
+        let average = &self.read_latency[idx];
+        let old = average.load(Ordering::Relaxed);
+        let new = if old == 0 {
+            sample
+        } else {
+            old - old / 8 + sample / 8
+        };
+        average.store(new.max(1), Ordering::Relaxed);
+    }
 }
 
 struct ReadResult {
@@ -33,7 +150,7 @@ struct ReadResult {
     failed_disks: Vec<usize>,
 }
 
-impl<V: VdevLeafWrite> Mirror<V> {
+impl<V: Vdev + VdevLeafWrite> Mirror<V> {
     async fn handle_repair<R>(
         &self,
         size: Block<u32>,
@@ -62,16 +179,40 @@ impl<V: VdevLeafWrite> Mirror<V> {
             .map(|idx| {
                 self.vdevs[idx]
                     .write_raw(data.clone(), offset, true)
-                    .into_future()
+                    .map(move |result| (idx, result))
             })
             .collect();
-        while let Some(write_result) = s.next().await {
-            if write_result.is_err() {
-                // TODO
-            } else {
-                total_repaired += 1;
+        while let Some((idx, write_result)) = s.next().await {
+            match write_result {
+                Ok(()) => {
+                    total_repaired += 1;
+                    self.repairs
+                        .lock()
+                        .push((self.vdevs[idx].id().to_string(), offset));
+                }
+                Err(e) => {
+                    self.stats
+                        .failed_writes
+                        .fetch_add(size.as_u64(), Ordering::Relaxed);
+                    warn!(
+                        "Repairing {:?} at {:?} on {} failed: {}",
+                        size,
+                        offset,
+                        self.vdevs[idx].id(),
+                        e
+                    );
+                }
             }
         }
+        if faulted > 0 {
+            // A faulted replica is healed from a good copy, the read itself
+            // succeeds and is only reported here, in the statistics and by
+            // `take_repairs`.
+            warn!(
+                "Mirror {} read faulted data from {} replicas at {:?}, repaired {}",
+                self.id, faulted, offset, total_repaired
+            );
+        }
         self.stats
             .repaired
             .fetch_add(u64::from(total_repaired) * size.as_u64(), Ordering::Relaxed);
@@ -92,17 +233,22 @@ impl<V: Vdev + VdevRead + VdevLeafRead + VdevLeafWrite + 'static> VdevRead for M
         offset: Block<u64>,
         checksum: C,
     ) -> Result<Buf> {
-        // Switch disk every 32 MiB. (which is 2^25 bytes)
-        // TODO 32 MiB too large?
-        let start_idx = (offset.to_bytes() >> 25) as usize % self.vdevs.len();
+        let start_idx = self.first_replica(offset);
         let mut failed_disks = Vec::new();
         let mut data = None;
         let disk_cnt = self.vdevs.len();
         for idx in 0..disk_cnt {
             let idx = (idx + start_idx) % self.vdevs.len();
-            let f = self.vdevs[idx].read(size, offset, checksum.clone());
-            match f.into_future().await {
+            self.pending_reads[idx].fetch_add(1, Ordering::Relaxed);
+            let start = Instant::now();
+            let result = self.vdevs[idx]
+                .read(size, offset, checksum.clone())
+                .into_future()
+                .await;
+            self.pending_reads[idx].fetch_sub(1, Ordering::Relaxed);
+            match result {
                 Ok(x) => {
+                    self.record_latency(idx, start);
                     data = Some(x);
                     break;
                 }
@@ -160,7 +306,7 @@ impl<V: Vdev + VdevRead + VdevLeafRead + VdevLeafWrite + 'static> VdevRead for M
 }
 
 #[async_trait]
-impl<V: VdevLeafWrite + 'static> VdevWrite for Mirror<V> {
+impl<V: Vdev + VdevLeafWrite + 'static> VdevWrite for Mirror<V> {
     async fn write(&self, data: Buf, offset: Block<u64>) -> Result<()> {
         let size = Block::from_bytes(data.len() as u32);
         self.stats
@@ -195,9 +341,11 @@ impl<V: VdevLeafWrite + 'static> VdevWrite for Mirror<V> {
     }
 
     async fn write_raw(&self, data: Buf, offset: Block<u64>) -> Result<()> {
+        // Missing devices would fail every raw write and are left out.
         let futures: FuturesUnordered<_> = self
             .vdevs
             .iter()
+            .filter(|disk| !disk.is_faulted())
             .map(|disk| disk.write_raw(data.clone(), offset, false).into_future())
             .collect();
 
@@ -246,11 +394,15 @@ impl<V: Vdev> Vdev for Mirror<V> {
             f(vdev);
         }
     }
+
+    fn take_repairs(&self) -> Vec<(String, Block<u64>)> {
+        std::mem::take(&mut *self.repairs.lock())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Mirror;
+    use super::{Mirror, MirrorReadPolicy};
     use crate::{
         buffer::Buf,
         checksum::{Builder, Checksum, State, XxHashBuilder},
@@ -261,6 +413,7 @@ mod tests {
     };
     use futures::executor::block_on;
     use quickcheck::TestResult;
+    use std::sync::atomic::Ordering;
 
     fn build_mirror_vdev(
         disk_size: Block<u32>,
@@ -394,6 +547,88 @@ mod tests {
         assert!(block_on(vdev.write(Buf::from(data), Block(0))).is_err());
     }
 
+    #[test]
+    fn read_policies_select_replica() {
+        let build = |policy| {
+            let disks: Vec<_> = (0..3)
+                .map(|id| FailingLeafVdev::new(Block(16), format!("{id}")))
+                .collect();
+            Mirror::new(disks.into_boxed_slice(), String::from("mirror")).with_read_policy(policy)
+        };
+
+        let vdev = build(MirrorReadPolicy::First);
+        assert!((0..4).all(|_| vdev.first_replica(Block(0)) == 0));
+
+        let vdev = build(MirrorReadPolicy::RoundRobin);
+        let order: Vec<_> = (0..4).map(|_| vdev.first_replica(Block(0))).collect();
+        assert_eq!(order, [0, 1, 2, 0]);
+
+        let vdev = build(MirrorReadPolicy::QueueDepth);
+        vdev.pending_reads[0].store(2, Ordering::Relaxed);
+        vdev.pending_reads[2].store(1, Ordering::Relaxed);
+        assert!((0..4).all(|_| vdev.first_replica(Block(0)) == 1));
+
+        let vdev = build(MirrorReadPolicy::Latency);
+        for (idx, latency) in [300, 100, 200].into_iter().enumerate() {
+            vdev.read_latency[idx].store(latency, Ordering::Relaxed);
+        }
+        assert!((0..4).all(|_| vdev.first_replica(Block(0)) == 1));
+    }
+
+    #[test]
+    fn first_policy_reads_first_replica() {
+        let disks: Vec<_> = (0..2)
+            .map(|id| FailingLeafVdev::new(Block(16), format!("{id}")))
+            .collect();
+        let vdev = Mirror::new(disks.into_boxed_slice(), String::from("mirror"))
+            .with_read_policy(MirrorReadPolicy::First);
+        let size = Block(4);
+        let data = generate_data(0, Block(0), size);
+        let checksum = {
+            let mut state = XxHashBuilder.build();
+            state.ingest(&data);
+            state.finish()
+        };
+        block_on(vdev.write(data, Block(0))).unwrap();
+
+        vdev.vdevs[1].fail_reads(FailureMode::Panic);
+        for _ in 0..4 {
+            let read = block_on(vdev.read(size, Block(0), checksum)).unwrap();
+            assert!(checksum.verify(&read).is_ok());
+        }
+    }
+
+    #[test]
+    fn read_repairs_faulted_replica() {
+        let disks: Vec<_> = (0..2)
+            .map(|id| FailingLeafVdev::new(Block(16), format!("{id}")))
+            .collect();
+        let vdev = Mirror::new(disks.into_boxed_slice(), String::from("mirror"));
+        let size = Block(4);
+        let data = generate_data(0, Block(0), size);
+        let checksum = {
+            let mut state = XxHashBuilder.build();
+            state.ingest(&data);
+            state.finish()
+        };
+
+        // Reads at offset 0 start with the first replica, which is corrupted.
+        vdev.vdevs[0].fail_writes(FailureMode::BadData);
+        block_on(vdev.write(data, Block(0))).unwrap();
+        vdev.vdevs[0].fail_writes(FailureMode::NoFail);
+
+        let read = block_on(vdev.read(size, Block(0), checksum)).unwrap();
+        assert!(checksum.verify(&read).is_ok());
+        assert_eq!(vdev.stats().repaired, Block(4));
+        assert_eq!(vdev.vdevs[0].stats().repaired, Block(4));
+        assert_eq!(vdev.take_repairs(), vec![(String::from("0"), Block(0))]);
+        assert!(vdev.take_repairs().is_empty());
+
+        vdev.vdevs[1].fail_reads(FailureMode::FailOperation);
+        let read = block_on(vdev.read(size, Block(0), checksum)).unwrap();
+        assert!(checksum.verify(&read).is_ok());
+    }
+
     #[quickcheck]
     fn scrub_detects_bad_data_and_repairs_data(
         writes: Vec<(u8, u8)>,
@@ -24,6 +24,9 @@ pub struct Statistics {
     pub checksum_errors: Block<u64>,
     /// The total number of blocks of failed write requests
     pub failed_writes: Block<u64>,
+    /// The total number of blocks which have been rewritten to repair faulted
+    /// data
+    pub repaired: Block<u64>,
     #[cfg(feature = "latency_metrics")]
     /// The average latency over all read operations
     pub read_latency: u64,
@@ -56,6 +59,7 @@ impl AtomicStatistics {
             failed_reads: Block(self.failed_reads.load(Ordering::Relaxed)),
             checksum_errors: Block(self.checksum_errors.load(Ordering::Relaxed)),
             failed_writes: Block(self.failed_writes.load(Ordering::Relaxed)),
+            repaired: Block(self.repaired.load(Ordering::Relaxed)),
             #[cfg(feature = "latency_metrics")]
             read_latency: self
                 .read_op_latency
@@ -168,6 +172,49 @@ pub trait Vdev: Send + Sync {
 
     /// Executes `f` for each child vdev.
     fn for_each_child(&self, f: &mut dyn FnMut(&dyn Vdev));
+
+    /// Returns whether this vdev is a placeholder for a missing device.
+    fn is_faulted(&self) -> bool {
+        false
+    }
+
+    /// Returns whether this vdev is directly mapped persistent memory.
+    fn is_byte_addressable(&self) -> bool {
+        false
+    }
+
+    /// Writes `data` at the byte offset `offset` of a directly mapped vdev,
+    /// without padding it to whole blocks.  The data is persistent after the
+    /// next [flush](VdevWrite::flush).
+    fn write_bytes(&self, _data: &[u8], _offset: u64) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Reads `buf.len()` bytes at the byte offset `offset` of a directly
+    /// mapped vdev, the counterpart of [Vdev::write_bytes].  Does not verify
+    /// the data.
+    fn read_bytes(&self, _buf: &mut [u8], _offset: u64) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Returns the repairs of faulted replicas made since the last call, as
+    /// the id of the rewritten leaf vdev and the offset of the data.
+    fn take_repairs(&self) -> Vec<(String, Block<u64>)> {
+        Vec::new()
+    }
+
+    /// Probes the sizes of the underlying devices again, e.g. after a block
+    /// device has been extended, and returns the new size of this vdev.
+    fn refresh_size(&self) -> Result<Block<u64>> {
+        let mut result = Ok(());
+        self.for_each_child(&mut |child| {
+            if result.is_ok() {
+                result = child.refresh_size().map(drop);
+            }
+        });
+        result?;
+        Ok(self.size())
+    }
 }
 
 /// Trait for reading from a leaf vdev.
@@ -237,12 +284,31 @@ pub use self::file::File;
 mod parity1;
 pub use self::parity1::Parity1;
 
+mod parity2;
+pub use self::parity2::Parity2;
+
 mod mirror;
-pub use self::mirror::Mirror;
+pub use self::mirror::{Mirror, MirrorReadPolicy};
 
 mod mem;
 pub use self::mem::Memory;
 
+mod faulted;
+pub use self::faulted::Faulted;
+
+mod impaired;
+pub use self::impaired::{Impaired, Impairments};
+
+#[cfg(feature = "internal-api")]
+mod recorder;
+#[cfg(feature = "internal-api")]
+pub use self::recorder::{Recorded, Recorder};
+
+#[cfg(feature = "sim")]
+mod simulated;
+#[cfg(feature = "sim")]
+pub use self::simulated::Simulated;
+
 #[cfg(feature = "nvm")]
 mod pmemfile;
 #[cfg(feature = "nvm")]
@@ -252,8 +318,14 @@ pub use self::pmemfile::PMemFile;
 pub(crate) enum Leaf {
     File,
     Memory,
+    Faulted,
+    Impaired,
     #[cfg(feature = "nvm")]
     PMemFile,
+    #[cfg(feature = "internal-api")]
+    Recorded,
+    #[cfg(feature = "sim")]
+    Simulated,
 }
 
 #[enum_dispatch(Vdev, VdevWrite, VdevRead)]
@@ -261,4 +333,5 @@ pub(crate) enum Dev {
     Leaf(Leaf),
     Mirror(Mirror<Leaf>),
     Parity1(Parity1<Leaf>),
+    Parity2(Parity2<Leaf>),
 }
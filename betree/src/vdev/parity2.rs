@@ -0,0 +1,707 @@
+use super::{
+    errors::*, AtomicStatistics, Block, Result, ScrubResult, Statistics, Vdev, VdevLeafRead,
+    VdevLeafWrite, VdevRead, VdevWrite,
+};
+use crate::{buffer::Buf, checksum::Checksum};
+use async_trait::async_trait;
+use futures::{
+    prelude::*,
+    stream::{FuturesOrdered, FuturesUnordered},
+};
+use std::sync::atomic::Ordering;
+
+/// This `vdev` will generate two independent parity columns and stripe all
+/// data to its child vdevs, so that any two of them may fail.
+///
+/// The first parity column `P` is the XOR of all data columns, the second
+/// parity column `Q` is a Reed-Solomon syndrome over GF(2^8).  Both parity
+/// columns rotate over the child vdevs like the parity column of
+/// [Parity1](super::Parity1).
+pub struct Parity2<V> {
+    vdevs: Box<[V]>,
+    id: String,
+    stats: AtomicStatistics,
+}
+
+// Position of the parity columns within a stripe, data columns follow them.
+const P_COL: usize = 0;
+const Q_COL: usize = 1;
+const PARITY_COLS: usize = 2;
+
+impl<V> Parity2<V> {
+    /// Constructs a new `Parity2` vdev with the given child `vdevs` and `id`.
+    /// Note: `vdevs.len()` must be at least 4 and at most 257.
+    pub fn new(vdevs: Box<[V]>, id: String) -> Self {
+        assert!(vdevs.len() >= 4);
+        // The Q syndrome needs a distinct coefficient for each data column.
+        assert!(vdevs.len() - PARITY_COLS <= 255);
+        Parity2 {
+            vdevs,
+            id,
+            stats: Default::default(),
+        }
+    }
+
+    fn data_col_cnt(&self) -> usize {
+        self.vdevs.len() - PARITY_COLS
+    }
+
+    /// The length in blocks of the long columns for a given request.
+    fn long_col_len(&self, size: Block<u32>) -> Block<u32> {
+        if size == Block(0) {
+            Block(0)
+        } else {
+            (size - 1) / (self.data_col_cnt() as u32) + 1
+        }
+    }
+
+    /// The number of the long data columns for a given request.
+    fn long_col_cnt(&self, size: Block<u32>) -> usize {
+        let cnt = size.as_u64() as usize % self.data_col_cnt();
+        if cnt == 0 {
+            self.data_col_cnt()
+        } else {
+            cnt
+        }
+    }
+
+    /// The child vdev and its offset holding the column at `position` of the
+    /// stripe at `offset`, where the parity columns come first.
+    fn column_disk(&self, offset: Block<u64>, position: usize) -> (&V, Block<u64>) {
+        let disk_cnt = self.vdevs.len();
+        let first_disk_idx = (offset.as_u64() % disk_cnt as u64) as usize;
+        let disk_offset = offset / (disk_cnt as u64);
+        if first_disk_idx + position < disk_cnt {
+            (&self.vdevs[first_disk_idx + position], disk_offset)
+        } else {
+            (
+                &self.vdevs[first_disk_idx + position - disk_cnt],
+                disk_offset + 1,
+            )
+        }
+    }
+}
+
+impl<V: Vdev + VdevLeafRead + VdevLeafWrite> Vdev for Parity2<V> {
+    fn actual_size(&self, size: Block<u32>) -> Block<u32> {
+        size + self.long_col_len(size) * PARITY_COLS as u32
+    }
+
+    fn num_disks(&self) -> usize {
+        self.vdevs.len()
+    }
+
+    fn size(&self) -> Block<u64> {
+        let min_size = self.vdevs.iter().map(Vdev::size).min().unwrap();
+        min_size * (self.vdevs.len() as u64)
+    }
+
+    fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
+        let cnt = self.vdevs.len() as u64;
+        free_size * (cnt - PARITY_COLS as u64) / cnt
+    }
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn stats(&self) -> Statistics {
+        self.stats.as_stats()
+    }
+
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn Vdev)) {
+        for vdev in self.vdevs.iter() {
+            f(vdev);
+        }
+    }
+}
+
+#[async_trait]
+impl<V: VdevLeafRead + VdevLeafWrite + 'static> VdevRead for Parity2<V> {
+    async fn read<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<Buf> {
+        self.read_(size, offset, checksum, false).await
+    }
+
+    async fn scrub<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<ScrubResult> {
+        self.read_(size, offset, checksum, true).await
+    }
+
+    async fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>> {
+        let futures: FuturesUnordered<_> = self
+            .vdevs
+            .iter()
+            .map(|disk| {
+                let data = Buf::zeroed(size).into_full_mut();
+                disk.read_raw(data, offset).into_future()
+            })
+            .collect();
+        let result = futures.collect::<Vec<_>>().await;
+        let mut v = Vec::new();
+        for x in result.into_iter().flatten() {
+            v.push(x.into_full_buf());
+        }
+        if v.is_empty() {
+            Err(VdevError::Read(self.id.clone()))
+        } else {
+            Ok(v)
+        }
+    }
+}
+
+impl<V: VdevLeafRead + VdevLeafWrite> Parity2<V> {
+    async fn read_<R, C>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+        scrub: bool,
+    ) -> Result<R>
+    where
+        R: From<ScrubResult>,
+        C: Checksum,
+    {
+        self.stats.read.fetch_add(size.as_u64(), Ordering::Relaxed);
+
+        let long_col_len = self.long_col_len(size);
+        let long_col_cnt = self.long_col_cnt(size);
+        let col_lens = col_lengths(long_col_len, long_col_cnt, self.data_col_cnt());
+
+        let mut buf = Buf::zeroed(size).into_full_mut();
+        let mut reads = FuturesOrdered::new();
+        for (idx, &col_len) in col_lens.iter().enumerate() {
+            if col_len == Block(0) {
+                break;
+            }
+            let (left, right) = buf.split_at(col_len);
+            buf = right;
+            let (disk, disk_offset) = self.column_disk(offset, PARITY_COLS + idx);
+            reads.push(disk.read_raw(left, disk_offset).into_future());
+        }
+        let mut failed_cols = Vec::new();
+        for (idx, result) in reads.collect::<Vec<_>>().await.into_iter().enumerate() {
+            if result.is_err() {
+                failed_cols.push(idx);
+            }
+        }
+        if failed_cols.len() > PARITY_COLS {
+            return Err(self.failed_read(size));
+        }
+
+        let data = buf.into_full_buf();
+        if failed_cols.is_empty() && !scrub && checksum.verify(&data).is_ok() {
+            return Ok(ScrubResult {
+                data,
+                faulted: Block(0),
+                repaired: Block(0),
+            }
+            .into());
+        }
+
+        let parity_len = long_col_len.to_bytes() as usize;
+        let read_parity = |position| {
+            let (disk, disk_offset) = self.column_disk(offset, position);
+            disk.read_raw(vec![0; parity_len].into_boxed_slice(), disk_offset)
+                .into_future()
+                .map(Result::ok)
+        };
+        let (p, q) = future::join(read_parity(P_COL), read_parity(Q_COL)).await;
+        if failed_cols.len() + p.is_none() as usize + q.is_none() as usize > PARITY_COLS {
+            return Err(self.failed_read(size));
+        }
+
+        let mut cols = split_columns(&data, &col_lens, parity_len);
+        let mut bad_cols = failed_cols.clone();
+        if failed_cols.is_empty() {
+            if checksum.verify(&data).is_err() {
+                // Every disk returned some data, but the checksum does not
+                // match. Try to rebuild each single column with either parity
+                // and each pair of columns until the checksum matches.
+                let (p, q) = (p.as_deref(), q.as_deref());
+                let candidates =
+                    (0..cols.len())
+                        .map(|x| (vec![x], p, None))
+                        .chain((0..cols.len()).map(|x| (vec![x], None, q)))
+                        .chain((0..cols.len()).flat_map(move |x| {
+                            (x + 1..cols.len()).map(move |y| (vec![x, y], p, q))
+                        }));
+                let mut rebuilt = None;
+                for (missing, p, q) in candidates {
+                    let mut candidate = cols.clone();
+                    if rebuild(&mut candidate, &missing, p, q)
+                        && checksum
+                            .verify_buffer(columns_data(&candidate, &col_lens))
+                            .is_ok()
+                    {
+                        rebuilt = Some((candidate, missing));
+                        break;
+                    }
+                }
+                match rebuilt {
+                    Some((candidate, missing)) => {
+                        for &idx in missing.iter() {
+                            let (disk, _) = self.column_disk(offset, PARITY_COLS + idx);
+                            VdevLeafRead::checksum_error_occurred(disk, col_lens[idx]);
+                        }
+                        cols = candidate;
+                        bad_cols = missing;
+                    }
+                    None => {
+                        self.stats
+                            .checksum_errors
+                            .fetch_add(size.as_u64(), Ordering::Relaxed);
+                        return Err(self.failed_read(size));
+                    }
+                }
+            }
+        } else {
+            // Up to two devices failed. Rebuild their data with the parity.
+            rebuild(&mut cols, &failed_cols, p.as_deref(), q.as_deref());
+            if checksum
+                .verify_buffer(columns_data(&cols, &col_lens))
+                .is_err()
+            {
+                // We give up if the checksum does not match after rebuild.
+                self.stats
+                    .checksum_errors
+                    .fetch_add(size.as_u64(), Ordering::Relaxed);
+                return Err(self.failed_read(size));
+            }
+        }
+
+        // Rewrite the defective data columns and all parity columns which
+        // could not be read or do not match the data.
+        let mut rewrites = Vec::new();
+        for &idx in bad_cols.iter() {
+            let col_len = col_lens[idx].to_bytes() as usize;
+            rewrites.push((PARITY_COLS + idx, cols[idx][..col_len].to_vec()));
+        }
+        let (new_p, new_q) = build_parity(&cols, parity_len);
+        if p.as_deref() != Some(&new_p[..]) {
+            rewrites.push((P_COL, new_p));
+        }
+        if q.as_deref() != Some(&new_q[..]) {
+            rewrites.push((Q_COL, new_q));
+        }
+        let mut faulted = Block(0);
+        let mut repaired = Block(0);
+        for (position, block) in rewrites {
+            let len = Block::from_bytes(block.len() as u32);
+            faulted += len;
+            let (disk, disk_offset) = self.column_disk(offset, position);
+            if disk
+                .write_raw(block.into_boxed_slice(), disk_offset, true)
+                .into_future()
+                .await
+                .is_ok()
+            {
+                repaired += len;
+            }
+        }
+        self.stats
+            .repaired
+            .fetch_add(repaired.as_u64(), Ordering::Relaxed);
+
+        let mut data = data.into_full_mut();
+        let mut start = 0;
+        for (col, col_len) in cols.iter().zip(col_lens.iter()) {
+            let end = start + col_len.to_bytes() as usize;
+            data.as_mut()[start..end].copy_from_slice(&col[..end - start]);
+            start = end;
+        }
+        Ok(ScrubResult {
+            data: data.into_full_buf(),
+            faulted,
+            repaired,
+        }
+        .into())
+    }
+
+    fn failed_read(&self, size: Block<u32>) -> VdevError {
+        self.stats
+            .failed_reads
+            .fetch_add(size.as_u64(), Ordering::Relaxed);
+        VdevError::Read(self.id.clone())
+    }
+}
+
+#[async_trait]
+impl<V: VdevLeafWrite> VdevWrite for Parity2<V> {
+    async fn write(&self, data: Buf, offset: Block<u64>) -> Result<()> {
+        let size = data.size();
+        let long_col_len = self.long_col_len(size);
+        let long_col_cnt = self.long_col_cnt(size);
+        let col_lens = col_lengths(long_col_len, long_col_cnt, self.data_col_cnt());
+        let parity_len = long_col_len.to_bytes() as usize;
+        let (p, q) = build_parity(&split_columns(&data, &col_lens, parity_len), parity_len);
+
+        let writes = FuturesUnordered::new();
+        for (position, parity) in [(P_COL, p), (Q_COL, q)] {
+            let (disk, disk_offset) = self.column_disk(offset, position);
+            writes.push(
+                disk.write_raw(Buf::from(parity.into_boxed_slice()), disk_offset, false)
+                    .into_future(),
+            );
+        }
+        let mut data = data;
+        for (idx, &col_len) in col_lens.iter().enumerate() {
+            if col_len == Block(0) {
+                break;
+            }
+            let (left, right) = data.split_at(col_len);
+            data = right;
+            let (disk, disk_offset) = self.column_disk(offset, PARITY_COLS + idx);
+            writes.push(disk.write_raw(left, disk_offset, false).into_future());
+        }
+        let results: Vec<_> = writes.collect().await;
+
+        let mut errors_occurred = 0;
+        for result in results {
+            if let Err(e) = result {
+                errors_occurred += 1;
+                if errors_occurred > PARITY_COLS {
+                    self.stats
+                        .failed_writes
+                        .fetch_add(size.as_u64(), Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for vdev in self.vdevs.iter() {
+            vdev.flush()?;
+        }
+        Ok(())
+    }
+
+    async fn write_raw(&self, data: Buf, offset: Block<u64>) -> Result<()> {
+        let size = Block::from_bytes(data.len() as u32);
+        let futures: FuturesUnordered<_> = self
+            .vdevs
+            .iter()
+            .map(|v| v.write(data.clone(), offset).into_future())
+            .collect();
+        let results: Vec<_> = futures.collect().await;
+        for result in results {
+            if let Err(e) = result {
+                self.stats
+                    .failed_writes
+                    .fetch_add(size.as_u64(), Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The lengths of all data columns for a request.
+fn col_lengths(
+    long_col_len: Block<u32>,
+    long_col_cnt: usize,
+    data_col_cnt: usize,
+) -> Vec<Block<u32>> {
+    (0..data_col_cnt)
+        .map(|idx| {
+            if idx < long_col_cnt || long_col_len == Block(0) {
+                long_col_len
+            } else {
+                long_col_len - 1
+            }
+        })
+        .collect()
+}
+
+/// Splits `data` into its columns, each padded with zeroes to `parity_len`
+/// bytes.
+fn split_columns(mut data: &[u8], col_lens: &[Block<u32>], parity_len: usize) -> Vec<Vec<u8>> {
+    col_lens
+        .iter()
+        .map(|col_len| {
+            let (col, rest) = data.split_at(col_len.to_bytes() as usize);
+            data = rest;
+            let mut col = col.to_vec();
+            col.resize(parity_len, 0);
+            col
+        })
+        .collect()
+}
+
+/// The data of padded columns without the padding.
+fn columns_data<'a>(
+    cols: &'a [Vec<u8>],
+    col_lens: &'a [Block<u32>],
+) -> impl Iterator<Item = &'a [u8]> + 'a {
+    cols.iter()
+        .zip(col_lens)
+        .map(|(col, col_len)| &col[..col_len.to_bytes() as usize])
+}
+
+/// Computes the parity columns `P` and `Q` of the padded columns `cols`.
+fn build_parity(cols: &[Vec<u8>], parity_len: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut p = vec![0; parity_len];
+    let mut q = vec![0; parity_len];
+    for (idx, col) in cols.iter().enumerate() {
+        xor(&mut p, col);
+        mul_xor(&mut q, col, gf::exp(idx));
+    }
+    (p, q)
+}
+
+/// Rebuilds the padded data columns `missing` from all other columns and the
+/// parity columns. Returns `false` if there is not enough parity left to do
+/// so.
+fn rebuild(cols: &mut [Vec<u8>], missing: &[usize], p: Option<&[u8]>, q: Option<&[u8]>) -> bool {
+    // Parity of the columns which are not missing.
+    let remaining_parity = |cols: &[Vec<u8>], parity: &[u8], coefficients: bool| {
+        let mut parity = parity.to_vec();
+        for (idx, col) in cols.iter().enumerate() {
+            if !missing.contains(&idx) {
+                if coefficients {
+                    mul_xor(&mut parity, col, gf::exp(idx));
+                } else {
+                    xor(&mut parity, col);
+                }
+            }
+        }
+        parity
+    };
+    match (missing, p, q) {
+        (&[], _, _) => {}
+        (&[x], Some(p), _) => {
+            cols[x] = remaining_parity(cols, p, false);
+        }
+        (&[x], None, Some(q)) => {
+            let mut col = remaining_parity(cols, q, true);
+            let factor = gf::inv(gf::exp(x));
+            for byte in col.iter_mut() {
+                *byte = gf::mul(*byte, factor);
+            }
+            cols[x] = col;
+        }
+        (&[x, y], Some(p), Some(q)) => {
+            // pxy = D_x + D_y and qxy = g^x * D_x + g^y * D_y, so that
+            // D_x = (qxy + g^y * pxy) / (g^x + g^y).
+            let pxy = remaining_parity(cols, p, false);
+            let qxy = remaining_parity(cols, q, true);
+            let (gx, gy) = (gf::exp(x), gf::exp(y));
+            let factor = gf::inv(gx ^ gy);
+            let col_x: Vec<u8> = pxy
+                .iter()
+                .zip(qxy.iter())
+                .map(|(&pb, &qb)| gf::mul(qb ^ gf::mul(gy, pb), factor))
+                .collect();
+            let mut col_y = pxy;
+            xor(&mut col_y, &col_x);
+            cols[x] = col_x;
+            cols[y] = col_y;
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn xor(d: &mut [u8], s: &[u8]) {
+    for (d_b, &s_b) in d.iter_mut().zip(s) {
+        *d_b ^= s_b;
+    }
+}
+
+fn mul_xor(d: &mut [u8], s: &[u8], factor: u8) {
+    for (d_b, &s_b) in d.iter_mut().zip(s) {
+        *d_b ^= gf::mul(s_b, factor);
+    }
+}
+
+/// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1 and
+/// the generator 2.
+mod gf {
+    const TABLES: ([u8; 512], [u8; 256]) = tables();
+
+    const fn tables() -> ([u8; 512], [u8; 256]) {
+        let mut exp = [0; 512];
+        let mut log = [0; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+            i += 1;
+        }
+        (exp, log)
+    }
+
+    /// Returns `2^n`.
+    pub fn exp(n: usize) -> u8 {
+        TABLES.0[n % 255]
+    }
+
+    pub fn mul(a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            TABLES.0[TABLES.1[a as usize] as usize + TABLES.1[b as usize] as usize]
+        }
+    }
+
+    /// Returns the multiplicative inverse of `a`, which must not be zero.
+    pub fn inv(a: u8) -> u8 {
+        debug_assert_ne!(a, 0);
+        TABLES.0[255 - TABLES.1[a as usize] as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gf, Parity2};
+    use crate::{
+        buffer::Buf,
+        checksum::{Builder, Checksum, State, XxHashBuilder},
+        vdev::{
+            test::{generate_data, test_writes_are_persistent, FailingLeafVdev, FailureMode},
+            Block, Vdev, VdevRead, VdevWrite,
+        },
+    };
+    use futures::{executor::block_on, TryFutureExt};
+    use quickcheck::TestResult;
+
+    fn build_parity_vdev(
+        disk_size: Block<u32>,
+        num_disks: u8,
+    ) -> Result<Parity2<FailingLeafVdev>, TestResult> {
+        if num_disks < 4 || disk_size == Block(0) {
+            return Err(TestResult::discard());
+        }
+        let disks: Vec<_> = (0..num_disks)
+            .map(|id| FailingLeafVdev::new(disk_size, format!("{id}")))
+            .collect();
+        let vdev = Parity2::new(disks.into_boxed_slice(), String::from("parity2"));
+        Ok(vdev)
+    }
+
+    #[test]
+    fn field_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(gf::mul(a, gf::inv(a)), 1);
+        }
+    }
+
+    #[quickcheck]
+    fn effective_free_size(disk_size: u8, num_disks: u8) -> TestResult {
+        let disk_size = Block(disk_size as u32);
+        let vdev = try_ret!(build_parity_vdev(disk_size, num_disks));
+        TestResult::from_bool(
+            vdev.effective_free_size(vdev.size())
+                == Block::<u64>::from(disk_size) * (num_disks as u64 - 2),
+        )
+    }
+
+    #[quickcheck]
+    fn writes_without_failure(writes: Vec<(u8, u8)>, num_disks: u8) -> TestResult {
+        let vdev = try_ret!(build_parity_vdev(Block(512), num_disks));
+        test_writes_are_persistent(&writes, &vdev);
+        TestResult::passed()
+    }
+
+    #[quickcheck]
+    fn writes_with_two_failing_disks(
+        writes: Vec<(u8, u8)>,
+        num_disks: u8,
+        failing_disk_idx: (u8, u8),
+        failure_mode: (FailureMode, FailureMode),
+    ) -> TestResult {
+        if num_disks < 4 {
+            return TestResult::discard();
+        }
+        let disks: Vec<_> = (0..num_disks)
+            .map(|id| FailingLeafVdev::new(Block(512), format!("{id}")))
+            .collect();
+        disks[(failing_disk_idx.0 % num_disks) as usize].fail_reads(failure_mode.0);
+        disks[(failing_disk_idx.0 % num_disks) as usize].fail_writes(failure_mode.0);
+        disks[(failing_disk_idx.1 % num_disks) as usize].fail_reads(failure_mode.1);
+        disks[(failing_disk_idx.1 % num_disks) as usize].fail_writes(failure_mode.1);
+        let vdev = Parity2::new(disks.into_boxed_slice(), String::from("parity2"));
+        test_writes_are_persistent(&writes, &vdev);
+        TestResult::passed()
+    }
+
+    #[test]
+    fn writes_fail_with_three_failing_disks() {
+        let disks: Vec<_> = (0..10)
+            .map(|id| FailingLeafVdev::new(Block(512), format!("{id}")))
+            .collect();
+        let data = vec![1; Block(8u32).to_bytes() as usize].into_boxed_slice();
+
+        for disk in &disks[..3] {
+            disk.fail_writes(FailureMode::FailOperation);
+        }
+        let vdev = Parity2::new(disks.into_boxed_slice(), String::from("parity2"));
+        assert!(block_on(vdev.write(Buf::from(data), Block(0)).into_future()).is_err());
+    }
+
+    #[quickcheck]
+    fn scrub_repairs_two_corrupted_disks(
+        writes: Vec<(u8, u8)>,
+        num_disks: u8,
+        failing_disk_idx: (u8, u8),
+    ) -> TestResult {
+        if num_disks < 4 {
+            return TestResult::discard();
+        }
+        let failing_disk_idx = [
+            (failing_disk_idx.0 % num_disks) as usize,
+            (failing_disk_idx.1 % num_disks) as usize,
+        ];
+        let vdev = try_ret!(build_parity_vdev(Block(512), num_disks));
+
+        for (idx, &(offset, size)) in writes.iter().enumerate() {
+            let offset = Block(offset as u64);
+            let size = Block(size as u32);
+
+            for &disk_idx in failing_disk_idx.iter() {
+                vdev.vdevs[disk_idx].fail_writes(FailureMode::BadData);
+            }
+            let data = generate_data(idx, offset, size);
+            let checksum = {
+                let mut state = XxHashBuilder.build();
+                state.ingest(&data);
+                state.finish()
+            };
+            assert!(block_on(vdev.write(data, offset).into_future()).is_ok());
+            for &disk_idx in failing_disk_idx.iter() {
+                vdev.vdevs[disk_idx].fail_writes(FailureMode::NoFail);
+            }
+
+            let scrub_result = block_on(vdev.scrub(size, offset, checksum)).unwrap();
+            assert!(checksum.verify(&scrub_result.data).is_ok());
+            assert_eq!(scrub_result.repaired, scrub_result.faulted);
+
+            // After the repair, any two disks may fail again.
+            for &disk_idx in failing_disk_idx.iter() {
+                vdev.vdevs[disk_idx].fail_reads(FailureMode::FailOperation);
+            }
+            let data = block_on(vdev.read(size, offset, checksum)).unwrap();
+            assert!(checksum.verify(&data).is_ok());
+            for &disk_idx in failing_disk_idx.iter() {
+                vdev.vdevs[disk_idx].fail_reads(FailureMode::NoFail);
+            }
+        }
+        TestResult::passed()
+    }
+}
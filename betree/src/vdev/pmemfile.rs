@@ -16,6 +16,11 @@ use std::{
 };
 
 /// `LeafVdev` which is backed by NVM and uses `pmdk`.
+///
+/// Storage classes consisting of actual persistent memory store their nodes
+/// uncompressed.  Nodes are written to and read from the mapping at their
+/// serialized length, see [Vdev::write_bytes], the rest of their last block
+/// is neither written nor read.
 #[derive(Debug)]
 pub struct PMemFile {
     file: pmdk::PMem,
@@ -25,6 +30,18 @@ pub struct PMemFile {
 }
 
 impl PMemFile {
+    // Fails if `len` bytes at `offset` exceed the mapping.
+    fn check_range(&self, offset: u64, len: usize) -> Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.file.len() as u64 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{len} bytes at {offset} exceed the mapping of {}", self.id),
+            )
+            .into()),
+        }
+    }
+
     /// Creates a new `PMEMFile`.
     pub fn new(file: pmdk::PMem, id: String) -> io::Result<Self> {
         let size = Block::from_bytes(file.len() as u64);
@@ -125,6 +142,26 @@ impl Vdev for PMemFile {
     }
 
     fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+
+    fn is_byte_addressable(&self) -> bool {
+        self.file.is_pmem()
+    }
+
+    fn write_bytes(&self, data: &[u8], offset: u64) -> Result<()> {
+        self.check_range(offset, data.len())?;
+        let blocks = Block::round_up_from_bytes(data.len() as u64).as_u64();
+        self.stats.written.fetch_add(blocks, Ordering::Relaxed);
+        unsafe { self.file.write(offset as usize, data) };
+        Ok(())
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        let blocks = Block::round_up_from_bytes(buf.len() as u64).as_u64();
+        self.stats.read.fetch_add(blocks, Ordering::Relaxed);
+        self.file.read(offset as usize, buf);
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -160,6 +197,29 @@ impl VdevLeafWrite for PMemFile {
     }
 
     fn flush(&self) -> Result<()> {
-        Ok(())
+        Ok(self.file.persist()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpadded_bytes() {
+        let path = std::env::temp_dir().join(format!("betree-pmem-{}", std::process::id()));
+        let file = pmdk::PMem::create(path.clone(), 1024 * 1024).unwrap();
+        let vdev = PMemFile::new(file, path.to_string_lossy().into_owned()).unwrap();
+        vdev.write_bytes(&[1; 4096], 0).unwrap();
+        vdev.write_bytes(&[2; 100], 0).unwrap();
+        let mut buf = [0; 4096];
+        vdev.read_bytes(&mut buf, 0).unwrap();
+        assert!(buf[..100].iter().all(|b| *b == 2));
+        // The rest of the block is left as it was.
+        assert!(buf[100..].iter().all(|b| *b == 1));
+        assert!(vdev.read_bytes(&mut buf, 1024 * 1024 - 100).is_err());
+        assert!(vdev.write_bytes(&buf, u64::MAX).is_err());
+        drop(vdev);
+        std::fs::remove_file(path).unwrap();
     }
 }
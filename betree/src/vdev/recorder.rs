@@ -0,0 +1,196 @@
+use super::{
+    Block, Memory, Result, ScrubResult, Statistics, Vdev, VdevLeafRead, VdevLeafWrite, VdevRead,
+};
+use crate::{buffer::Buf, checksum::Checksum};
+use async_trait::async_trait;
+use parking_lot::{const_mutex, Mutex};
+use std::{collections::BTreeMap, sync::Arc};
+
+// The recorders by name, so that a storage pool configuration can refer to
+// them and they outlive the databases built on them.
+static RECORDERS: Mutex<BTreeMap<String, Arc<Recorder>>> = const_mutex(BTreeMap::new());
+
+struct RecordedWrite {
+    offset: Block<u64>,
+    data: Box<[u8]>,
+}
+
+/// A memory-backed disk which records all writes issued to it, so that the
+/// contents after a crash at any point of the recording can be reproduced.
+/// It is used by [LeafVdev::Recorded](crate::storage_pool::LeafVdev::Recorded) and
+/// only intended for testing.
+pub struct Recorder {
+    memory: Memory,
+    // The contents when the recording has been started and the writes
+    // issued since then.
+    base: Mutex<Box<[u8]>>,
+    writes: Mutex<Vec<RecordedWrite>>,
+}
+
+impl Recorder {
+    /// Registers a new recorder of `size` zeroed bytes under `name`,
+    /// replacing any previous one of this name.
+    pub fn create(name: &str, size: usize) -> Arc<Self> {
+        Self::with_image(name, vec![0; size].into_boxed_slice())
+    }
+
+    /// Registers a new recorder holding `image` under `name`, replacing any
+    /// previous one of this name.
+    pub fn with_image(name: &str, image: Box<[u8]>) -> Arc<Self> {
+        let recorder = Arc::new(Recorder {
+            memory: Memory::with_contents(image.clone(), format!("recorded-{name}")),
+            base: Mutex::new(image),
+            writes: Mutex::new(Vec::new()),
+        });
+        RECORDERS
+            .lock()
+            .insert(name.to_string(), Arc::clone(&recorder));
+        recorder
+    }
+
+    /// Returns the recorder registered under `name`.
+    pub fn get(name: &str) -> Option<Arc<Self>> {
+        RECORDERS.lock().get(name).cloned()
+    }
+
+    /// Unregisters the recorder `name`.
+    pub fn remove(name: &str) {
+        RECORDERS.lock().remove(name);
+    }
+
+    /// Discards the writes recorded so far and starts recording from the
+    /// current contents.
+    pub fn start_recording(&self) {
+        let mut writes = self.writes.lock();
+        *self.base.lock() = self.memory.contents();
+        writes.clear();
+    }
+
+    /// Returns the sizes of the recorded writes in the order in which they
+    /// have reached the disk.
+    pub fn writes(&self) -> Vec<Block<u32>> {
+        self.writes
+            .lock()
+            .iter()
+            .map(|write| Block::from_bytes(write.data.len() as u32))
+            .collect()
+    }
+
+    /// Returns the contents after a crash which happened once the first
+    /// `count` recorded writes and the first `torn` blocks of the following
+    /// one have reached the disk.
+    pub fn crash_image(&self, count: usize, torn: Block<u32>) -> Box<[u8]> {
+        let writes = self.writes.lock();
+        let mut image = self.base.lock().clone();
+        for write in writes.iter().take(count) {
+            let offset = write.offset.to_bytes() as usize;
+            image[offset..offset + write.data.len()].copy_from_slice(&write.data);
+        }
+        if let Some(write) = writes.get(count) {
+            let offset = write.offset.to_bytes() as usize;
+            let len = (torn.to_bytes() as usize).min(write.data.len());
+            image[offset..offset + len].copy_from_slice(&write.data[..len]);
+        }
+        image
+    }
+}
+
+/// `LeafVdev` on a [Recorder].
+pub struct Recorded {
+    recorder: Arc<Recorder>,
+}
+
+impl Recorded {
+    /// Creates a new `Recorded` vdev.
+    pub fn new(recorder: Arc<Recorder>) -> Self {
+        Recorded { recorder }
+    }
+}
+
+#[async_trait]
+impl VdevRead for Recorded {
+    async fn read<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<Buf> {
+        VdevRead::read(&self.recorder.memory, size, offset, checksum).await
+    }
+
+    async fn scrub<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<ScrubResult> {
+        VdevRead::scrub(&self.recorder.memory, size, offset, checksum).await
+    }
+
+    async fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>> {
+        VdevRead::read_raw(&self.recorder.memory, size, offset).await
+    }
+}
+
+impl Vdev for Recorded {
+    fn actual_size(&self, size: Block<u32>) -> Block<u32> {
+        size
+    }
+
+    fn num_disks(&self) -> usize {
+        1
+    }
+
+    fn size(&self) -> Block<u64> {
+        self.recorder.memory.size()
+    }
+
+    fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
+        free_size
+    }
+
+    fn id(&self) -> &str {
+        self.recorder.memory.id()
+    }
+
+    fn stats(&self) -> Statistics {
+        self.recorder.memory.stats()
+    }
+
+    fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+}
+
+#[async_trait]
+impl VdevLeafRead for Recorded {
+    async fn read_raw<T: AsMut<[u8]> + Send>(&self, buf: T, offset: Block<u64>) -> Result<T> {
+        VdevLeafRead::read_raw(&self.recorder.memory, buf, offset).await
+    }
+
+    fn checksum_error_occurred(&self, size: Block<u32>) {
+        self.recorder.memory.checksum_error_occurred(size)
+    }
+}
+
+#[async_trait]
+impl VdevLeafWrite for Recorded {
+    async fn write_raw<W: AsRef<[u8]> + Send + 'static>(
+        &self,
+        data: W,
+        offset: Block<u64>,
+        is_repair: bool,
+    ) -> Result<()> {
+        let copy = Box::from(data.as_ref());
+        VdevLeafWrite::write_raw(&self.recorder.memory, data, offset, is_repair).await?;
+        // Writes are recorded in the order in which they have reached the
+        // disk.
+        self.recorder
+            .writes
+            .lock()
+            .push(RecordedWrite { offset, data: copy });
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        VdevLeafWrite::flush(&self.recorder.memory)
+    }
+}
@@ -0,0 +1,124 @@
+use super::{
+    Block, Memory, Result, ScrubResult, Statistics, Vdev, VdevLeafRead, VdevLeafWrite, VdevRead,
+};
+use crate::{buffer::Buf, checksum::Checksum, sim::Clock};
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+
+/// `LeafVdev` that is backed by memory and lets each request take a random
+/// latency on the virtual clock of a [Simulation](crate::sim::Simulation).
+/// It is used by [LeafVdev::Simulated](crate::storage_pool::LeafVdev::Simulated).
+pub struct Simulated {
+    memory: Memory,
+    clock: Arc<Clock>,
+    min_latency: Duration,
+    max_latency: Duration,
+}
+
+impl Simulated {
+    /// Creates a new `Simulated` vdev of `size` bytes whose requests take
+    /// between `min_latency` and `max_latency` on `clock`.
+    pub(crate) fn new(
+        size: usize,
+        id: String,
+        clock: Arc<Clock>,
+        min_latency: Duration,
+        max_latency: Duration,
+    ) -> Self {
+        Simulated {
+            memory: Memory::with_contents(vec![0; size].into_boxed_slice(), id),
+            clock,
+            min_latency,
+            max_latency,
+        }
+    }
+
+    fn delay(&self) {
+        self.clock.delay(self.min_latency, self.max_latency)
+    }
+}
+
+#[async_trait]
+impl VdevRead for Simulated {
+    async fn read<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<Buf> {
+        self.delay();
+        VdevRead::read(&self.memory, size, offset, checksum).await
+    }
+
+    async fn scrub<C: Checksum>(
+        &self,
+        size: Block<u32>,
+        offset: Block<u64>,
+        checksum: C,
+    ) -> Result<ScrubResult> {
+        self.delay();
+        VdevRead::scrub(&self.memory, size, offset, checksum).await
+    }
+
+    async fn read_raw(&self, size: Block<u32>, offset: Block<u64>) -> Result<Vec<Buf>> {
+        self.delay();
+        VdevRead::read_raw(&self.memory, size, offset).await
+    }
+}
+
+impl Vdev for Simulated {
+    fn actual_size(&self, size: Block<u32>) -> Block<u32> {
+        size
+    }
+
+    fn num_disks(&self) -> usize {
+        1
+    }
+
+    fn size(&self) -> Block<u64> {
+        self.memory.size()
+    }
+
+    fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
+        free_size
+    }
+
+    fn id(&self) -> &str {
+        self.memory.id()
+    }
+
+    fn stats(&self) -> Statistics {
+        self.memory.stats()
+    }
+
+    fn for_each_child(&self, _f: &mut dyn FnMut(&dyn Vdev)) {}
+}
+
+#[async_trait]
+impl VdevLeafRead for Simulated {
+    async fn read_raw<T: AsMut<[u8]> + Send>(&self, buf: T, offset: Block<u64>) -> Result<T> {
+        self.delay();
+        VdevLeafRead::read_raw(&self.memory, buf, offset).await
+    }
+
+    fn checksum_error_occurred(&self, size: Block<u32>) {
+        self.memory.checksum_error_occurred(size)
+    }
+}
+
+#[async_trait]
+impl VdevLeafWrite for Simulated {
+    async fn write_raw<W: AsRef<[u8]> + Send + 'static>(
+        &self,
+        data: W,
+        offset: Block<u64>,
+        is_repair: bool,
+    ) -> Result<()> {
+        self.delay();
+        VdevLeafWrite::write_raw(&self.memory, data, offset, is_repair).await
+    }
+
+    fn flush(&self) -> Result<()> {
+        VdevLeafWrite::flush(&self.memory)
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::{Once, RwLock, RwLockWriteGuard};
 use betree_storage_stack::{
     database::AccessMode,
     migration::{LfuConfig, LfuMode, MigrationConfig, MigrationPolicies},
-    storage_pool::{configuration::Vdev, LeafVdev, TierConfiguration},
+    storage_pool::{configuration::Vdev, LeafVdev, TierConfiguration, NUM_STORAGE_CLASSES},
     DatabaseConfiguration, StoragePoolConfiguration,
 };
 
@@ -19,6 +19,7 @@ pub fn access_specific_config() -> DatabaseConfiguration {
                     })],
                     preferred_access_type:
                         betree_storage_stack::PreferredAccessType::RandomReadWrite,
+                    ..Default::default()
                 },
                 TierConfiguration {
                     top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
@@ -26,6 +27,7 @@ pub fn access_specific_config() -> DatabaseConfiguration {
                     })],
                     preferred_access_type:
                         betree_storage_stack::PreferredAccessType::SequentialReadWrite,
+                    ..Default::default()
                 },
             ],
             ..Default::default()
@@ -69,8 +71,11 @@ fn migration_config_lfu(mode: LfuMode) -> DatabaseConfiguration {
         access_mode: AccessMode::OpenOrCreate,
         migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig {
             grace_period: std::time::Duration::from_millis(0),
-            migration_threshold: [0.7; 4],
+            migration_threshold: [0.7; NUM_STORAGE_CLASSES],
             update_period: std::time::Duration::from_secs(1),
+            dry_run: false,
+            max_bandwidth: None,
+            active_windows: Vec::new(),
             policy_config: LfuConfig {
                 mode,
                 ..LfuConfig::default()
@@ -103,8 +108,11 @@ pub(crate) fn migration_config_rl() -> DatabaseConfiguration {
         access_mode: AccessMode::OpenOrCreate,
         migration_policy: Some(MigrationPolicies::ReinforcementLearning(MigrationConfig {
             grace_period: std::time::Duration::from_millis(0),
-            migration_threshold: [0.7; 4],
+            migration_threshold: [0.7; NUM_STORAGE_CLASSES],
             update_period: std::time::Duration::from_millis(100),
+            dry_run: false,
+            max_bandwidth: None,
+            active_windows: Vec::new(),
             policy_config: None,
         })),
         default_storage_class: 1,
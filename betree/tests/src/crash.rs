@@ -0,0 +1,164 @@
+//! Crash-consistency tests, which reopen a database from the disk contents
+//! after a crash at every point of a recorded workload.
+use super::TO_MEBIBYTE;
+use betree_storage_stack::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    database::{AccessMode, FsckOptions},
+    storage_pool::{LeafVdev, TierConfiguration, Vdev},
+    vdev::{Block, Recorder},
+    Database, DatabaseConfiguration, StoragePoolConfiguration,
+};
+
+const KEYS: u32 = 32;
+
+fn config(recorder: &str, access_mode: AccessMode) -> DatabaseConfiguration {
+    DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration::new(vec![Vdev::Leaf(
+                LeafVdev::Recorded {
+                    recorder: recorder.to_string(),
+                },
+            )])],
+            ..Default::default()
+        },
+        access_mode,
+        ..Default::default()
+    }
+}
+
+/// Runs `workload` on a database prepared by `setup` and records all writes
+/// of it.  Afterwards, the database is reopened from the contents after a
+/// crash following every prefix of the recorded writes, also with the next
+/// write torn in half if it spans multiple blocks.  Every reopened database
+/// is checked by `verify` and has to pass `fsck` without unallocated or
+/// doubly referenced blocks.
+pub fn check_crash_consistency<S, W, V>(name: &str, setup: S, workload: W, verify: V)
+where
+    S: FnOnce(&mut Database),
+    W: FnOnce(&mut Database),
+    V: Fn(&mut Database),
+{
+    let recorder = Recorder::create(name, 32 * TO_MEBIBYTE);
+    {
+        let mut db = Database::build(config(name, AccessMode::AlwaysCreateNew)).unwrap();
+        setup(&mut db);
+        db.sync().unwrap();
+        recorder.start_recording();
+        workload(&mut db);
+    }
+
+    let writes = recorder.writes();
+    let mut crashes = (0..=writes.len())
+        .map(|count| (count, Block(0)))
+        .collect::<Vec<_>>();
+    crashes.extend(
+        writes
+            .iter()
+            .enumerate()
+            .filter(|(_, size)| size.as_u32() > 1)
+            .map(|(count, size)| (count, Block(size.as_u32() / 2))),
+    );
+
+    let crashed = format!("{name}-crashed");
+    for (count, torn) in crashes {
+        Recorder::with_image(&crashed, recorder.crash_image(count, torn));
+        let mut db = Database::build(config(&crashed, AccessMode::OpenIfExists))
+            .unwrap_or_else(|e| panic!("Reopening after {count} writes ({torn:?} torn): {e:?}"));
+        verify(&mut db);
+        let report = db.fsck(FsckOptions::default()).unwrap();
+        assert!(
+            report.unallocated.is_empty() && report.doubly_referenced.is_empty(),
+            "Inconsistent after {count} writes ({torn:?} torn): {report:?}"
+        );
+    }
+    Recorder::remove(name);
+    Recorder::remove(&crashed);
+}
+
+fn value(round: u8) -> Vec<u8> {
+    vec![round; 4096]
+}
+
+fn fill(db: &mut Database, round: u8, keys: std::ops::Range<u32>) {
+    let ds = db.open_or_create_dataset(b"data").unwrap();
+    for key in keys {
+        ds.insert(&key.to_be_bytes()[..], &value(round)).unwrap();
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+// Returns the round of which all values of the dataset are.
+fn check_values(entries: Vec<(CowBytes, SlicedCowBytes)>, keys: usize) -> u8 {
+    assert_eq!(entries.len(), keys);
+    let round = entries[0].1[0];
+    for (_, data) in entries.iter() {
+        assert_eq!(&data[..], &value(round)[..]);
+    }
+    round
+}
+
+#[test]
+fn syncs_are_atomic() {
+    check_crash_consistency(
+        "syncs_are_atomic",
+        |db| fill(db, 1, 0..KEYS),
+        |db| {
+            for round in 2..=3 {
+                fill(db, round, 0..KEYS);
+                db.sync().unwrap();
+            }
+            // Never synced, must not be visible after any crash.
+            fill(db, 4, KEYS..2 * KEYS);
+        },
+        |db| {
+            let ds = db.open_dataset(b"data").unwrap();
+            let entries = ds
+                .range::<_, &[u8]>(..)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let round = check_values(entries, KEYS as usize);
+            assert!((1..=3).contains(&round));
+        },
+    );
+}
+
+#[test]
+fn snapshots_survive_crashes() {
+    check_crash_consistency(
+        "snapshots_survive_crashes",
+        |db| fill(db, 1, 0..KEYS),
+        |db| {
+            let mut ds = db.open_dataset(b"data").unwrap();
+            db.create_snapshot(&mut ds, b"snap").unwrap();
+            ds.range_delete::<_, &[u8]>(..).unwrap();
+            db.close_dataset(ds).unwrap();
+            fill(db, 2, 0..KEYS / 2);
+            db.sync().unwrap();
+        },
+        |db| {
+            let mut ds = db.open_dataset(b"data").unwrap();
+            let entries = ds
+                .range::<_, &[u8]>(..)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let snapshot = db.open_snapshot(&mut ds, b"snap");
+            if entries.len() == KEYS as usize {
+                assert_eq!(check_values(entries, KEYS as usize), 1);
+            } else {
+                assert_eq!(check_values(entries, KEYS as usize / 2), 2);
+                // The dataset has been modified after the snapshot.
+                assert!(snapshot.is_ok());
+            }
+            if let Ok(snapshot) = snapshot {
+                let entries = snapshot
+                    .range::<_, &[u8]>(..)
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                assert_eq!(check_values(entries, KEYS as usize), 1);
+            }
+        },
+    );
+}
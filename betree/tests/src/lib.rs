@@ -1,24 +1,57 @@
 #![allow(dead_code)]
 
 mod configs;
+mod crash;
 mod object_store;
 mod pivot_key;
+mod sim;
+mod snapshot;
 mod util;
 
 use betree_storage_stack::{
-    compression::CompressionConfiguration,
-    database::AccessMode,
+    allocator::{Action, Extent, SegmentId, SEGMENT_SIZE},
+    buffer::BufferAllocation,
+    cache::CachePolicyType,
+    checksum::ChecksumConfiguration,
+    compression::{CompressionConfiguration, DecompressionTag, Lz4, Zstd},
+    data_management::{DeltaConfiguration, L2CacheConfiguration},
+    database::{
+        value_checksum, AccessMode, AsyncDataset, CompactionConfiguration, DatasetId,
+        DefragmentationConfiguration, Error, ErrorCode, Event, FsckOptions, ReplicationCursor,
+        ReplicationLeader, ReplicationTransport, StorageInfo, ValuePlacement, ValueSizeRule,
+        WriteBatch, FORMAT_VERSION, SUPERBLOCK_BLOCKS,
+    },
+    encryption::{EncryptionConfiguration, EncryptionKey},
     env_logger,
+    ingest::{write_binary_entry, BinaryDumpReader, HexDumpReader},
+    inspect::Inspector,
+    migration::{
+        errors::Result as MigrationResult, DatabaseMsg, MigrationConfig, MigrationContext,
+        MigrationPolicies, MigrationPolicy, MigrationSource, MigrationSubject, MigrationWindow,
+        ReadRate,
+    },
     object::{ObjectHandle, ObjectStore},
-    storage_pool::{LeafVdev, TierConfiguration, Vdev},
+    storage_pool::{DiskOffset, LeafVdev, MirrorReadPolicy, TierConfiguration, Vdev},
+    tree::{DefaultMessageAction, DumpDetail, FlushSize, NodeDump, TreeDump, TREE_DUMP_VERSION},
+    vdev::Block,
     Database, DatabaseConfiguration, StoragePoolConfiguration, StoragePreference,
 };
 use std::{
+    convert::TryInto,
     env,
     io::{BufReader, Read},
-    sync::RwLockWriteGuard,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLockWriteGuard,
+    },
 };
 
+use futures::{
+    executor::{block_on, ThreadPool},
+    StreamExt,
+};
+
+use parking_lot::{Mutex, RwLock};
 use rand::{prelude::ThreadRng, Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256PlusPlus;
 
@@ -62,7 +95,7 @@ fn test_db_uneven(tiers: usize, mb_per_tier: &[u32]) -> Database {
         },
         compression: CompressionConfiguration::None,
         access_mode: AccessMode::AlwaysCreateNew,
-        alloc_strategy: [vec![0], vec![1], vec![2], vec![3]],
+        alloc_strategy: vec![vec![0], vec![1], vec![2], vec![3]],
         ..Default::default()
     };
 
@@ -406,6 +439,39 @@ fn write_overfull(#[case] tier_size_mb: u32, #[case] par_space: f32) {
     // NOTE: If the sync errors are not corrected this will deadlock here on the final drop. Test with timeout.
 }
 
+#[test]
+fn error_codes() {
+    let db = test_db(1, 512);
+    let missing = db.open_dataset(b"missing").err().unwrap();
+    assert!(matches!(missing, Error::DoesNotExist));
+    assert_eq!(missing.code(), ErrorCode::DoesNotExist);
+    assert_eq!(missing.code() as u32, 6);
+
+    db.create_dataset(b"present").unwrap();
+    let existing = db.create_dataset(b"present").unwrap_err();
+    assert_eq!(existing.code(), ErrorCode::AlreadyExists);
+    assert!(existing.dataset().is_none());
+    assert!(existing.disk_offset().is_none());
+}
+
+#[test]
+fn tree_dump_stable() {
+    let (_db, ds) = util::random_db(2, 128);
+
+    let full = ds.dump(DumpDetail::Full).unwrap();
+    assert_eq!(full.version, TREE_DUMP_VERSION);
+    let children = match &full.root {
+        NodeDump::Internal { children, .. } => children,
+        _ => panic!("Root is not an internal node"),
+    };
+    assert!(children.iter().skip(1).all(|child| child.from.is_some()));
+
+    let compact = ds.dump(DumpDetail::Compact).unwrap();
+    let json = serde_json::to_string(&compact).unwrap();
+    assert!(!json.contains("\"from\"") && !json.contains("\"to\""));
+    assert_eq!(serde_json::from_str::<TreeDump>(&json).unwrap(), compact);
+}
+
 #[fixture]
 fn rng() -> ThreadRng {
     rand::thread_rng()
@@ -665,6 +731,28 @@ fn object_migrate_down(#[case] tier_size_mb: u32) {
     dbg!(db.free_space_tier());
 }
 
+#[test]
+fn object_migrate_down_at_slowest_tier() {
+    let mut db = test_db(2, 32);
+    let os = db
+        .open_named_object_store(b"test", StoragePreference::FASTEST)
+        .unwrap();
+    let mut obj = os.open_or_create_object(b"foobar").unwrap();
+    obj.write_at(&[42; 4 * TO_MEBIBYTE], 0).unwrap();
+    obj.migrate_down().unwrap();
+    db.sync().unwrap();
+    assert_eq!(obj.storage_preference(), StoragePreference::FAST);
+
+    // The second tier is the slowest one configured, there is nothing below.
+    obj.migrate_down().unwrap();
+    obj.migrate_down_once().unwrap();
+    db.sync().unwrap();
+    assert_eq!(obj.storage_preference(), StoragePreference::FAST);
+    let mut buf = vec![0; 4 * TO_MEBIBYTE];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert!(buf.iter().all(|b| *b == 42));
+}
+
 #[rstest]
 #[case::a(32)]
 fn dataset_migrate_up(#[case] tier_size_mb: u32) {
@@ -683,6 +771,172 @@ fn dataset_migrate_up(#[case] tier_size_mb: u32) {
     assert!(space[0].free < space[1].free);
 }
 
+#[rstest]
+fn dataset_pinned_range() {
+    let mut db = test_db(2, 32);
+    let ds = db.open_or_create_dataset(b"miniprod").unwrap();
+    let buf = vec![42u8; 512 * 1024];
+    let (hot, cold) = (b"hot".to_vec(), b"cold".to_vec());
+    ds.insert_with_pref(hot.clone(), &buf, StoragePreference::FAST)
+        .unwrap();
+    ds.insert_with_pref(cold.clone(), &buf, StoragePreference::FAST)
+        .unwrap();
+    db.sync().unwrap();
+    let space = db.free_space_tier();
+    assert!(space[0].free > space[1].free);
+
+    ds.pin_range(hot.clone()..=hot.clone(), StoragePreference::FASTEST)
+        .unwrap();
+    db.sync().unwrap();
+    let space = db.free_space_tier();
+    assert!(space[0].free < space[1].free);
+
+    // Pinned keys are skipped by range migrations and cannot be moved.
+    ds.migrate_range::<_, &[u8]>(.., StoragePreference::FAST)
+        .unwrap();
+    db.sync().unwrap();
+    let space = db.free_space_tier();
+    assert!(space[0].free < space[1].free);
+    assert!(matches!(
+        ds.migrate(hot.clone(), StoragePreference::FAST),
+        Err(Error::KeyPinned(0))
+    ));
+    ds.migrate(cold, StoragePreference::FAST).unwrap();
+
+    // The rule survives reopening the dataset.
+    db.close_dataset(ds).unwrap();
+    let ds = db.open_dataset(b"miniprod").unwrap();
+    assert!(matches!(
+        ds.migrate(hot.clone(), StoragePreference::FAST),
+        Err(Error::KeyPinned(0))
+    ));
+
+    ds.unpin_range(hot.clone()..=hot.clone()).unwrap();
+    ds.migrate(hot, StoragePreference::FAST).unwrap();
+    db.sync().unwrap();
+    let space = db.free_space_tier();
+    assert!(space[0].free > space[1].free);
+}
+
+#[rstest]
+fn dataset_spill_to_slower_tier() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: [4, 64]
+                .iter()
+                .map(|mb| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: mb * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        compression: CompressionConfiguration::None,
+        access_mode: AccessMode::AlwaysCreateNew,
+        spill_to_slower_tiers: true,
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"miniprod").unwrap();
+    // Four times the size of the fastest tier.
+    for idx in 0..64u32 {
+        ds.insert_with_pref(
+            idx.to_be_bytes().to_vec(),
+            &[idx as u8; 256 * 1024],
+            StoragePreference::FASTEST,
+        )
+        .unwrap();
+    }
+    db.sync().unwrap();
+
+    let displaced = db.displaced_nodes();
+    assert!(!displaced.is_empty());
+    for (_, displacement) in displaced {
+        assert_eq!(displacement.preferred, StoragePreference::FASTEST);
+        assert_eq!(displacement.actual, StoragePreference::FAST);
+    }
+    for idx in 0..64u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8; 256 * 1024][..]);
+    }
+}
+
+#[rstest]
+fn dataset_migration_report() {
+    let db = test_db(2, 32);
+    let reports = db.subscribe_migrations();
+    let ds = db.open_or_create_dataset(b"miniprod").unwrap();
+    let buf = vec![42u8; 128 * 1024];
+    for key in [&b"a"[..], b"b", b"c"] {
+        ds.insert_with_pref(key.to_vec(), &buf, StoragePreference::FASTEST)
+            .unwrap();
+    }
+    ds.pin_range(b"c".to_vec()..=b"c".to_vec(), StoragePreference::FASTEST)
+        .unwrap();
+    ds.migrate_range::<_, &[u8]>(.., StoragePreference::FAST)
+        .unwrap();
+
+    let report = reports.try_recv().unwrap();
+    assert_eq!(report.source, MigrationSource::Explicit);
+    assert_eq!(report.migrations.len(), 2);
+    let size = 2 * (1 + buf.len() as u64);
+    assert_eq!(report.bytes_out()[..2], [size, 0]);
+    assert_eq!(report.bytes_in()[..2], [0, size]);
+    // The pinned key could not be moved.
+    assert_eq!(report.failures.len(), 1);
+    assert!(matches!(
+        &report.failures[0].subject,
+        MigrationSubject::Key { key, .. } if &key[..] == b"c"
+    ));
+    assert!(reports.try_recv().is_err());
+
+    // Dropped receivers are unsubscribed.
+    drop(reports);
+    ds.migrate_range::<_, &[u8]>(.., StoragePreference::FASTEST)
+        .unwrap();
+}
+
+#[rstest]
+fn database_event_handler() {
+    let mut db = test_db(2, 64);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&events);
+    let id = db.register_event_handler(move |event| recorded.lock().push(event.clone()));
+    let ds = db.open_or_create_dataset(b"miniprod").unwrap();
+    let buf = vec![42u8; 64 * 1024];
+    for idx in 0..256u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &buf).unwrap();
+    }
+    db.sync().unwrap();
+    ds.migrate_range::<_, &[u8]>(.., StoragePreference::FAST)
+        .unwrap();
+
+    {
+        let events = events.lock();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::NodeSplit { level: 0, .. })));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::SyncCompleted { .. })));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::Migration(report) if report.source == MigrationSource::Explicit
+        )));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::ReadFailed { .. })));
+    }
+
+    // Removed handlers are not called anymore.
+    assert!(db.unregister_event_handler(id));
+    let seen = events.lock().len();
+    db.sync().unwrap();
+    assert_eq!(events.lock().len(), seen);
+}
+
 #[rstest]
 #[case::a(32)]
 #[case::b(128)]
@@ -820,105 +1074,3727 @@ fn space_accounting_persistence(
     assert!(previous[0].free > after[0].free);
 }
 
-#[fixture]
-fn file_backed_config() -> RwLockWriteGuard<'static, DatabaseConfiguration> {
-    configs::file_backed()
-}
+#[rstest]
+fn storage_report() {
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    ds.insert(b"key".to_vec(), &[42; 4096]).unwrap();
+    db.sync().unwrap();
 
-fn migration_policy_smoke(cfg: DatabaseConfiguration) {
-    let shared_db = Database::build_threaded(cfg).unwrap();
-    let ds;
-    {
-        let mut db = shared_db.write();
-        ds = db
-            .open_named_object_store(b"test", StoragePreference::NONE)
-            .unwrap();
-        db.sync().unwrap();
-    }
-    let obj = ds.open_or_create_object(b"foobar").unwrap();
-    let buf = vec![42u8; 1024 * TO_MEBIBYTE];
-    dbg!(obj.write_at(&buf, 0).unwrap());
-    {
-        let mut db = shared_db.write();
-        db.close_object_store(ds);
-        db.sync().unwrap();
+    let report = db.storage_report();
+    assert!(report.allocations > 0);
+    assert_eq!(report.tiers[0].disks.len(), 1);
+    assert_eq!(report.tiers[2].disks.len(), 0);
+    for tier in report.tiers.iter() {
+        assert_eq!(
+            tier.info.free.as_u64(),
+            tier.disks.iter().map(|d| d.free.as_u64()).sum::<u64>()
+        );
     }
-    // Rest to let all messages be updated and synchronization proceed
-    std::thread::sleep(std::time::Duration::from_secs(5));
+    assert!(report.tiers[0].info.free < report.tiers[1].info.free);
 }
 
 #[rstest]
-fn migration_policy_smoke_rl() {
-    // env_logger::init();
-    migration_policy_smoke(configs::migration_config_rl());
+fn fragmentation_report() {
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[42; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+
+    let report = db.fragmentation_report().unwrap();
+    let storage = db.storage_report();
+    assert_eq!(report.tiers.len(), storage.tiers.len());
+    let disk = &report.tiers[0][0];
+    assert!(!disk.segments.is_empty());
+    assert!(disk.info.free <= storage.tiers[0].disks[0].total);
+    assert!(disk.info.largest_free_extent <= disk.info.free);
+    assert!(disk.info.free_extents() > 0);
+    // Nothing has been allocated on the second tier.
+    let untouched = &report.tiers[1][0];
+    assert!(untouched.segments.is_empty());
+    assert_eq!(untouched.info.free_extents(), 1);
+    assert_eq!(untouched.info.fragmentation(), 0.0);
 }
 
 #[rstest]
-fn migration_policy_smoke_lfu() {
-    // env_logger::init();
-    migration_policy_smoke(configs::migration_config_lfu_node());
+fn allocation_heatmap() {
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[42; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    ds.insert(b"newer".to_vec(), &[43; 4096]).unwrap();
+    db.sync().unwrap();
+
+    let heatmap = db.allocation_heatmap().unwrap();
+    assert_eq!(heatmap.tiers.len(), db.storage_report().tiers.len());
+    let segments = &heatmap.tiers[0][0].segments;
+    assert!(!segments.is_empty());
+    for segment in segments {
+        assert!(segment.referenced <= segment.allocated);
+        assert!(segment.density() <= 1.0);
+        if let (Some(oldest), Some(newest)) = (segment.oldest, segment.newest) {
+            assert!(oldest <= newest && newest < heatmap.generation);
+        }
+    }
+    assert!(segments
+        .iter()
+        .any(|segment| segment.referenced.as_u32() > 0));
+    assert!(heatmap.tiers[1][0].segments.is_empty());
+    assert!(serde_json::to_string(&heatmap).is_ok());
 }
 
-#[rstest]
-fn migration_policy_single_node() {
-    // env_logger::init();
-    let shared_db = Database::build_threaded(configs::migration_config_lfu_node()).unwrap();
-    let ds;
-    {
-        let mut db = shared_db.write();
-        ds = db.open_or_create_dataset(b"test").unwrap();
-        db.sync().unwrap();
+#[test]
+fn format_upgrade() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"data").unwrap();
+    ds.insert(b"key".to_vec(), &[42; 64]).unwrap();
+    db.sync().unwrap();
+    // Nodes are written in the current format from the start.
+    assert!(!db.has_legacy_format());
+    assert!(db.upgrade_format().unwrap());
+    assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], &[42; 64]);
+    db.close_dataset(ds).unwrap();
+}
+
+#[test]
+fn backup_restore() {
+    let mut db = test_db(2, 64);
+    let mut ds = db.open_or_create_dataset(b"data").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 1024]).unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"first").unwrap();
+    for idx in 0..32u32 {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
     }
+    ds.insert(b"new".to_vec(), &[2; 1024]).unwrap();
+    db.create_snapshot(&mut ds, b"second").unwrap();
+    ds.insert(63u32.to_be_bytes().to_vec(), &[3; 1024]).unwrap();
+    ds.pin_range(b"new".to_vec()..=b"new".to_vec(), StoragePreference::FAST)
+        .unwrap();
+    let hash = ds.root_hash().unwrap();
+    assert_eq!(hash.entries, 33);
+    let first = db.open_snapshot(&mut ds, b"first").unwrap();
+    let first_hash = first.root_hash().unwrap();
+    assert_ne!(hash, first_hash);
+    db.close_snapshot(&mut ds, first);
+    db.close_dataset(ds).unwrap();
 
-    let sync = move || {
-        let mut db = shared_db.write();
-        db.sync().unwrap();
+    let os = db
+        .open_named_object_store(b"objects", StoragePreference::NONE)
+        .unwrap();
+    let obj = os.open_or_create_object(b"obj").unwrap();
+    obj.write_at(b"hello", 128 * 1024).unwrap();
+    obj.set_metadata(b"owner", b"me").unwrap();
+    obj.close().unwrap();
+    db.close_object_store(os);
+
+    let mut archive = Vec::new();
+    db.backup(&mut archive).unwrap();
+    drop(db);
+
+    // Restore onto a pool with a different number of tiers.
+    let config = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: (0..3)
+                .map(|_| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: 64 * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        ..Default::default()
     };
+    let mut db = Database::restore(&archive[..], config).unwrap();
+    let mut ds = db.open_dataset(b"data").unwrap();
+    let entries = ds
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 33);
+    // The trees are built anew, but their contents are the same.
+    assert_eq!(ds.root_hash().unwrap(), hash);
+    assert_eq!(&ds.get(b"new".to_vec()).unwrap().unwrap()[..], &[2; 1024]);
+    assert_eq!(
+        &ds.get(63u32.to_be_bytes().to_vec()).unwrap().unwrap()[..],
+        &[3; 1024]
+    );
+    assert!(matches!(
+        ds.migrate(b"new".to_vec(), StoragePreference::FASTEST),
+        Err(Error::KeyPinned(1))
+    ));
 
-    ds.insert(b"foobar".to_vec(), &[42; 4096]).unwrap();
-    sync();
-    assert_json_snapshot!(
-        "migration_policy_single_node__before_migration",
-        json!(ds.tree_dump().unwrap())
+    let first = db.open_snapshot(&mut ds, b"first").unwrap();
+    let entries = first
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 64);
+    assert!(entries.iter().all(|(_, value)| &value[..] == &[1; 1024]));
+    assert_eq!(first.root_hash().unwrap(), first_hash);
+    let second = db.open_snapshot(&mut ds, b"second").unwrap();
+    assert_eq!(
+        &second.get(63u32.to_be_bytes().to_vec()).unwrap().unwrap()[..],
+        &[1; 1024]
     );
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
-    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
-    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
-    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
-    sync();
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    assert_json_snapshot!(
-        "migration_policy_single_node__after_migration",
-        json!(ds.tree_dump().unwrap())
+    assert!(second.get(0u32.to_be_bytes().to_vec()).unwrap().is_none());
+    db.close_dataset(ds).unwrap();
+
+    let os = db
+        .open_named_object_store(b"objects", StoragePreference::NONE)
+        .unwrap();
+    let obj = os.open_object(b"obj").unwrap().unwrap();
+    let mut buf = [0; 5];
+    obj.read_at(&mut buf, 128 * 1024).unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(&obj.get_metadata(b"owner").unwrap().unwrap()[..], b"me");
+    assert_eq!(obj.info().unwrap().unwrap().size, 128 * 1024 + 5);
+}
+
+// Applies the shipped batches to the follower right away.
+struct DirectTransport<'a> {
+    follower: &'a mut Database,
+}
+
+impl ReplicationTransport for DirectTransport<'_> {
+    fn cursor(&mut self) -> Result<Option<ReplicationCursor>, Error> {
+        self.follower.replication_cursor()
+    }
+
+    fn ship(&mut self, batch: Vec<u8>) -> Result<(), Error> {
+        self.follower.apply_replication(&batch).map(|_| ())
+    }
+}
+
+#[test]
+fn replication() {
+    let mut leader = test_db(1, 64);
+    let mut follower = Database::build(DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        replication_follower: true,
+        ..Default::default()
+    })
+    .unwrap();
+    let ds = leader.open_or_create_dataset(b"data").unwrap();
+    for idx in 0..256u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 1024])
+            .unwrap();
+    }
+    let mut state = ReplicationLeader::new();
+    let cursor = leader
+        .ship_replication(
+            &mut state,
+            &mut DirectTransport {
+                follower: &mut follower,
+            },
+        )
+        .unwrap();
+    assert_eq!(follower.replication_cursor().unwrap(), Some(cursor));
+    assert_eq!(state.cursor(), Some(cursor));
+
+    let replica = follower.open_dataset(b"data").unwrap();
+    assert_eq!(replica.root_hash().unwrap(), ds.root_hash().unwrap());
+    assert!(matches!(
+        replica.insert(b"local".to_vec(), &[1]),
+        Err(Error::ReadOnlyFollower)
+    ));
+
+    // Only the changes are shipped, to the dataset opened on the follower.
+    for idx in (0..256u32).filter(|idx| idx % 3 == 0) {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
+    }
+    ds.insert(b"new".to_vec(), &[2; 1024]).unwrap();
+    let next = leader
+        .ship_replication(
+            &mut state,
+            &mut DirectTransport {
+                follower: &mut follower,
+            },
+        )
+        .unwrap();
+    assert!(next.generation > cursor.generation);
+    assert_eq!(replica.root_hash().unwrap(), ds.root_hash().unwrap());
+    assert_eq!(
+        &replica.get(b"new".to_vec()).unwrap().unwrap()[..],
+        &[2; 1024]
     );
-    sync();
+    assert!(replica.get(0u32.to_be_bytes().to_vec()).unwrap().is_none());
+
+    // A leader without the state of the follower ships all entries.
+    leader.close_replication(state).unwrap();
+    ds.insert(b"newer".to_vec(), &[3; 1024]).unwrap();
+    let mut state = ReplicationLeader::new();
+    leader
+        .ship_replication(
+            &mut state,
+            &mut DirectTransport {
+                follower: &mut follower,
+            },
+        )
+        .unwrap();
+    assert_eq!(replica.root_hash().unwrap(), ds.root_hash().unwrap());
+    leader.close_replication(state).unwrap();
+    leader.close_dataset(ds).unwrap();
+    follower.close_dataset(replica).unwrap();
 }
 
-#[rstest]
-fn migration_policy_single_object() {
-    // Migrate a single object upwards after it has been used
-    let shared_db = Database::build_threaded(configs::migration_config_lfu_object()).unwrap();
-    let os;
-    {
-        let mut db = shared_db.write();
-        os = db.open_object_store().unwrap();
-        db.sync().unwrap();
+#[test]
+fn value_checksums() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"data").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
     }
-    let obj = os
-        .open_or_create_object_with_pref(b"foo", StoragePreference::FAST)
+    let key = 7u32.to_be_bytes().to_vec();
+    let (value, checksum) = ds.get_with_checksum(key.clone()).unwrap().unwrap();
+    assert_eq!(checksum, value_checksum(&value));
+    assert_eq!(
+        &ds.get_verified(key.clone(), &checksum).unwrap().unwrap()[..],
+        &[7; 4096]
+    );
+    let err = ds
+        .get_verified(key.clone(), &value_checksum(b"other"))
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Checksum);
+    assert!(ds
+        .get_verified(b"missing".to_vec(), &checksum)
         .unwrap()
-        .0;
-    let mut buf = vec![42; 200 * TO_MEBIBYTE];
-    obj.write_at_with_pref(&buf, 0, StoragePreference::FAST)
+        .is_none());
+
+    db.sync().unwrap();
+    let all = ds.verify_range::<_, &[u8]>(..).unwrap();
+    let single = ds.verify_range(key.clone()..=key).unwrap();
+    assert!(single >= 1 && single < all, "{single} of {all}");
+}
+
+#[test]
+fn fsck() {
+    let mut db = test_db(1, 64);
+    let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[42; 4096]).unwrap();
+    }
+    db.create_snapshot(&mut ds, b"snap").unwrap();
+    for idx in (0..64u32).filter(|idx| idx % 2 == 0) {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
+    }
+    db.checkpoint(b"checkpoint").unwrap();
+    ds.insert(b"newer".to_vec(), &[43; 4096]).unwrap();
+
+    let report = db.fsck(FsckOptions::default()).unwrap();
+    assert!(report.is_consistent(), "{:?}", report);
+    assert!(report.nodes > 0);
+
+    // Leak blocks and free the superblock reservation at the end of the vdev
+    // behind the back of the database.
+    let dmu = db.root_tree().dmu();
+    let leak = DiskOffset::new(0, 0, Block(16000));
+    dmu.allocate_raw_at(leak, Block(4), DatasetId::default())
         .unwrap();
-    std::thread::sleep(std::time::Duration::from_secs(2));
-    shared_db.write().sync().unwrap();
-    obj.read_at(&mut buf, 0).unwrap();
-    obj.read_at(&mut buf, 0).unwrap();
-    obj.read_at(&mut buf, 0).unwrap();
-    std::thread::sleep(std::time::Duration::from_secs(10));
-    shared_db.write().close_object_store(os);
+    let end = Block::<u64>::from_bytes(64 * TO_MEBIBYTE as u64);
+    let superblock = DiskOffset::new(0, 0, Block(end.as_u64() - SUPERBLOCK_BLOCKS.as_u64()));
+    dmu.handler()
+        .update_allocation_bitmap(
+            superblock,
+            SUPERBLOCK_BLOCKS,
+            Action::Deallocate,
+            DatasetId::default(),
+            &**dmu,
+        )
+        .unwrap();
+
+    let report = db.fsck(FsckOptions::default()).unwrap();
+    assert_eq!(report.leaked, vec![Extent::new(leak, Block(4))]);
+    assert_eq!(
+        report.unallocated,
+        vec![Extent::new(superblock, SUPERBLOCK_BLOCKS)]
+    );
+    assert!(report.doubly_referenced.is_empty());
+    assert!(!report.repaired);
+
+    let report = db.fsck(FsckOptions { repair: true }).unwrap();
+    assert!(report.repaired);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    assert_eq!(ds.get(b"newer".to_vec()).unwrap().unwrap().len(), 4096);
+}
+
+#[test]
+fn extent_spanning_segments() {
+    // The last of five segments is not the first segment of any allocation
+    // shard, its start stays free.
+    let segment = SEGMENT_SIZE as u64;
+    let disk_end = 4 * segment + 16384;
+    let path = env::temp_dir().join(format!("extents_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(Block(disk_end).to_bytes())
+        .unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        sync_interval_ms: None,
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg.clone()).unwrap();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    db.sync().unwrap();
+    // A leaf of 3MiB, which is written first on the next sync.
+    for idx in 0..6u8 {
+        ds.insert(vec![idx], &[idx; 512 * 1024]).unwrap();
+    }
+
+    // Apart from room for small nodes, only 2MiB at the end of the fourth
+    // segment and 2MiB at the start of the fifth segment are left.
+    let holes = [
+        (segment / 2, segment / 2 + 256),
+        (4 * segment - 512, 4 * segment + 512),
+    ];
+    let dmu = db.root_tree().dmu();
+    for index in 0..5 {
+        let id = SegmentId::get(DiskOffset::new(0, 0, Block(index * segment)));
+        let free: Vec<_> = dmu
+            .handler()
+            .get_allocation_bitmap(id, &**dmu)
+            .unwrap()
+            .access()
+            .free_ranges()
+            .collect();
+        for (start, len) in free {
+            let start = index * segment + u64::from(start);
+            let mut ranges = vec![(start, (start + u64::from(len)).min(disk_end))];
+            for &(hole_start, hole_end) in holes.iter() {
+                ranges = ranges
+                    .into_iter()
+                    .flat_map(|(from, to)| [(from, to.min(hole_start)), (from.max(hole_end), to)])
+                    .filter(|(from, to)| from < to)
+                    .collect();
+            }
+            for (from, to) in ranges {
+                dmu.allocate_raw_at(
+                    DiskOffset::new(0, 0, Block(from)),
+                    Block((to - from) as u32),
+                    DatasetId::default(),
+                )
+                .unwrap();
+            }
+        }
+    }
+    db.sync().unwrap();
+    db.close_dataset(ds).unwrap();
+    drop(db);
+
+    let inspector = Inspector::open(&cfg).unwrap();
+    let datasets = inspector.datasets().unwrap();
+    let foo = datasets.iter().find(|ds| &ds.name[..] == b"foo").unwrap();
+    let mut spanning = 0;
+    inspector.walk(&foo.root, |ptr, node| {
+        node.unwrap();
+        if ptr.extent().is_multi_segment() {
+            spanning += 1;
+        }
+    });
+    assert_eq!(spanning, 1);
+    drop(inspector);
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"foo").unwrap();
+    for idx in 0..6u8 {
+        let value = ds.get(vec![idx]).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx; 512 * 1024][..]);
+    }
+    db.close_dataset(ds).unwrap();
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn audit_dead_lists() {
+    let mut db = test_db(1, 64);
+    let mut ds = db.open_or_create_dataset(b"data").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 4096]).unwrap();
+    }
+    db.create_snapshot(&mut ds, b"snap").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[2; 4096]).unwrap();
+    }
+    let report = db.audit_dead_lists(false).unwrap();
+    assert!(report.entries > 0);
+    assert_eq!(report.orphaned, 0);
+
+    // A later checkpoint does not contain the blocks kept for the snapshot,
+    // so they are freed together with it.
+    db.checkpoint(b"checkpoint").unwrap();
+    db.delete_snapshot(&mut ds, b"snap").unwrap();
+    let report = db.audit_dead_lists(false).unwrap();
+    assert_eq!(report.orphaned, 0);
+    assert_eq!(report.orphaned_size.as_u64(), 0);
+    assert!(!db.audit_dead_lists(true).unwrap().reclaimed);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    assert_eq!(
+        &ds.get(0u32.to_be_bytes().to_vec()).unwrap().unwrap()[..],
+        &[2; 4096]
+    );
+}
+
+#[rstest]
+fn read_views() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"data").unwrap();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    let generation = db.synced_generation();
+    let view = db.open_read_view(&ds, generation).unwrap();
+    assert_eq!(view.generation(), generation);
+
+    // Later modifications are not visible in the view, even after a sync.
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[2; 4096]).unwrap();
+    }
+    ds.delete(0u32.to_be_bytes().to_vec()).unwrap();
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+    let mut entries = 0;
+    for entry in view.range::<_, &[u8]>(..).unwrap() {
+        assert_eq!(&entry.unwrap().1[..], &[1; 4096]);
+        entries += 1;
+    }
+    assert_eq!(entries, 64);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    assert_eq!(db.audit_dead_lists(false).unwrap().orphaned, 0);
+    assert!(matches!(
+        db.open_read_view(&ds, generation),
+        Err(Error::GenerationUnavailable(_))
+    ));
+
+    db.close_read_view(view).unwrap();
+    assert_eq!(db.audit_dead_lists(false).unwrap().entries, 0);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+
+    // Blocks of dropped views are left to the audit.
+    let view = db.sync_read_view(&ds).unwrap();
+    ds.insert(1u32.to_be_bytes().to_vec(), &[3; 4096]).unwrap();
+    assert_eq!(
+        &view.get(1u32.to_be_bytes()).unwrap().unwrap()[..],
+        &[2; 4096]
+    );
+    drop(view);
+    assert!(db.audit_dead_lists(true).unwrap().reclaimed);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+}
+
+#[rstest]
+fn transactions() {
+    let db = test_db(1, 32);
+    let ds = db.open_or_create_dataset(b"accounts").unwrap();
+    ds.insert(&b"alice"[..], &100u32.to_be_bytes()).unwrap();
+    ds.insert(&b"bob"[..], &0u32.to_be_bytes()).unwrap();
+    let balance = |value: Option<_>| u32::from_be_bytes(value.unwrap()[..].try_into().unwrap());
+
+    // Writes are buffered until the commit, but visible to the transaction.
+    let mut txn = ds.begin_transaction();
+    let alice = balance(txn.get(&b"alice"[..]).unwrap());
+    txn.insert(&b"alice"[..], &(alice - 30).to_be_bytes())
+        .unwrap();
+    txn.upsert(&b"bob"[..], &30u32.to_be_bytes(), 0).unwrap();
+    assert_eq!(balance(txn.get(&b"alice"[..]).unwrap()), 70);
+    assert_eq!(balance(txn.get(&b"bob"[..]).unwrap()), 30);
+    assert_eq!(balance(ds.get(&b"alice"[..]).unwrap()), 100);
+    assert_eq!(txn.len(), 2);
+    txn.commit().unwrap();
+    assert_eq!(balance(ds.get(&b"alice"[..]).unwrap()), 70);
+    assert_eq!(balance(ds.get(&b"bob"[..]).unwrap()), 30);
+
+    // A modification of a read key aborts the commit.
+    let mut first = ds.begin_transaction();
+    let mut second = ds.begin_transaction();
+    first.get(&b"bob"[..]).unwrap();
+    second.get(&b"bob"[..]).unwrap();
+    first.delete(&b"bob"[..]);
+    second.insert(&b"bob"[..], &50u32.to_be_bytes()).unwrap();
+    first.commit().unwrap();
+    let err = second.commit().unwrap_err();
+    assert!(matches!(err, Error::TransactionConflict));
+    assert_eq!(err.code(), ErrorCode::Conflict);
+    assert!(ds.get(&b"bob"[..]).unwrap().is_none());
+
+    // Writes outside of transactions are detected as well, unrelated keys
+    // are not.
+    let mut txn = ds.begin_transaction();
+    txn.get(&b"alice"[..]).unwrap();
+    txn.insert(&b"carol"[..], &1u32.to_be_bytes()).unwrap();
+    ds.insert(&b"dave"[..], &1u32.to_be_bytes()).unwrap();
+    txn.commit().unwrap();
+    let mut txn = ds.begin_transaction();
+    txn.get(&b"alice"[..]).unwrap();
+    ds.delete(&b"alice"[..]).unwrap();
+    assert!(matches!(txn.commit(), Err(Error::TransactionConflict)));
+
+    // Dropped transactions are discarded.
+    let mut txn = ds.begin_transaction();
+    txn.insert(&b"eve"[..], &1u32.to_be_bytes()).unwrap();
+    drop(txn);
+    assert!(ds.get(&b"eve"[..]).unwrap().is_none());
+
+    // Invalid writes fail the commit before any write is applied.
+    let mut txn = ds.begin_transaction();
+    txn.insert(&b"frank"[..], &1u32.to_be_bytes()).unwrap();
+    txn.insert(&b""[..], &1u32.to_be_bytes()).unwrap();
+    assert_eq!(txn.commit().unwrap_err().code(), ErrorCode::InvalidArgument);
+    assert!(ds.get(&b"frank"[..]).unwrap().is_none());
+}
+
+#[rstest]
+fn write_batch() {
+    let db = test_db(1, 32);
+    let index = db.open_or_create_dataset(b"index").unwrap();
+    let data = db.open_or_create_dataset(b"data").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.insert(&data, &b"object"[..], &[42; 512]).unwrap();
+    batch.insert(&index, &b"object"[..], b"data").unwrap();
+    batch.delete(&index.clone(), &b"stale"[..]);
+    assert_eq!(batch.len(), 3);
+    batch.commit().unwrap();
+    assert_eq!(&data.get(&b"object"[..]).unwrap().unwrap()[..], &[42; 512]);
+    assert_eq!(&index.get(&b"object"[..]).unwrap().unwrap()[..], b"data");
+
+    // Invalid writes fail the commit before any write is applied.
+    let mut batch = WriteBatch::new();
+    batch.insert(&data, &b"other"[..], &[1; 512]).unwrap();
+    batch.insert(&index, &b""[..], b"data").unwrap();
+    assert_eq!(
+        batch.commit().unwrap_err().code(),
+        ErrorCode::InvalidArgument
+    );
+    assert!(data.get(&b"other"[..]).unwrap().is_none());
+}
+
+#[rstest]
+fn space_reservations() {
+    let mut db = Database::build(DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        headroom: 0.25,
+        ..Default::default()
+    })
+    .unwrap();
+    let data = db.open_or_create_dataset(b"data").unwrap();
+    let other = db.open_or_create_dataset(b"other").unwrap();
+    db.sync().unwrap();
+    let info = db.free_space_tier()[0];
+    let unreserved = info.free.as_u64() - info.total.as_u64() / 4;
+
+    // The headroom cannot be reserved.
+    let too_large = (unreserved + 1) * 4096;
+    assert!(matches!(
+        db.reserve(&data, too_large, StoragePreference::FASTEST),
+        Err(Error::ReservationWouldExceedStorage(0, _))
+    ));
+    let reservation = db
+        .reserve(&data, 8 * TO_MEBIBYTE as u64, StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(reservation.storage_class(), 0);
+    assert_eq!(reservation.remaining(), Block(2048));
+
+    // Reserved blocks are not available to other datasets.
+    let rest = (unreserved - 2048 + 1) * 4096;
+    assert!(db.reserve(&other, rest, StoragePreference::NONE).is_err());
+
+    // Writes of the dataset consume its reservation.
+    for idx in 0..256u32 {
+        data.insert(idx.to_be_bytes().to_vec(), &[1; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    assert!(reservation.remaining() <= Block(2048 - 256));
+    let consumed = 2048 - reservation.remaining().as_u64();
+    drop(reservation);
+    let rest = (unreserved - consumed) * 4096;
+    db.reserve(&other, rest / 2, StoragePreference::NONE)
+        .unwrap();
+}
+
+#[rstest]
+fn quota_groups(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    let limit = 2 * TO_MEBIBYTE as u64;
+    {
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        let first = db.open_or_create_dataset(b"first").unwrap();
+        let second = db.open_or_create_dataset(b"second").unwrap();
+        let other = db.open_or_create_dataset(b"other").unwrap();
+        db.create_quota_group(b"tenant", limit).unwrap();
+        assert!(matches!(
+            db.create_quota_group(b"tenant", limit),
+            Err(Error::AlreadyExists)
+        ));
+        db.assign_quota_group(&first, Some(b"tenant")).unwrap();
+        db.assign_quota_group(&second, Some(b"tenant")).unwrap();
+        assert!(matches!(
+            db.assign_quota_group(&other, Some(b"missing")),
+            Err(Error::DoesNotExist)
+        ));
+
+        // The datasets of a group share its limit.
+        for idx in 0..256u32 {
+            first
+                .insert(idx.to_be_bytes().to_vec(), &[1; 4096])
+                .unwrap();
+            second
+                .insert(idx.to_be_bytes().to_vec(), &[2; 4096])
+                .unwrap();
+        }
+        db.sync().unwrap();
+        let info = db.quota_group(b"tenant").unwrap();
+        assert_eq!(info.limit, limit);
+        assert!(info.used >= limit);
+        assert_eq!(info.datasets.len(), 2);
+        let err = first.insert(&b"more"[..], &[1; 4096]).unwrap_err();
+        assert!(matches!(err, Error::QuotaExceeded(_)));
+        assert_eq!(err.code(), ErrorCode::OutOfSpace);
+        second.delete(0u32.to_be_bytes().to_vec()).unwrap();
+        other.insert(&b"more"[..], &[1; 4096]).unwrap();
+
+        // Leaving the group lifts the limit.
+        db.assign_quota_group(&first, None).unwrap();
+        first.insert(&b"more"[..], &[1; 4096]).unwrap();
+        db.sync().unwrap();
+    }
+
+    // Groups are persistent.
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let groups = db.quota_groups();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(&groups[0].name[..], b"tenant");
+    assert_eq!(groups[0].datasets.len(), 1);
+    let second = db.open_dataset(b"second").unwrap();
+    db.set_quota_limit(b"tenant", limit / 4).unwrap();
+    assert!(second.insert(&b"more"[..], &[1; 4096]).is_err());
+    db.set_quota_limit(b"tenant", 64 * limit).unwrap();
+    second.insert(&b"more"[..], &[1; 4096]).unwrap();
+    db.delete_quota_group(b"tenant").unwrap();
+    assert!(db.quota_groups().is_empty());
+}
+
+#[rstest]
+fn checksum_configuration(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    // Large enough for leaves which `Crc32c` checksums in parallel chunks.
+    let data: Vec<u8> = (0..8 * TO_MEBIBYTE)
+        .map(|idx| (idx * 7 + idx / 251) as u8)
+        .collect();
+    fn read_back(obj: &ObjectHandle, data: &[u8]) {
+        let mut buf = vec![0; data.len()];
+        assert_eq!(obj.read_at(&mut buf, 0).unwrap(), data.len() as u64);
+        assert!(buf == data);
+    }
+    {
+        let mut cfg = file_backed_config.clone();
+        cfg.checksum = ChecksumConfiguration::Crc32c;
+        let mut db = Database::build(cfg).unwrap();
+        let os = db
+            .open_named_object_store(b"store", StoragePreference::NONE)
+            .unwrap();
+        let obj = os.open_or_create_object(b"crc32c").unwrap();
+        obj.write_at(&data, 0).unwrap();
+        db.sync().unwrap();
+        db.drop_cache().unwrap();
+        read_back(&obj, &data);
+        db.close_object_store(os);
+        db.sync().unwrap();
+    }
+
+    // Nodes stay verifiable after the configuration has been changed.
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    cfg.checksum = ChecksumConfiguration::GxHash;
+    let mut db = Database::build(cfg).unwrap();
+    let os = db
+        .open_named_object_store(b"store", StoragePreference::NONE)
+        .unwrap();
+    read_back(&os.open_object(b"crc32c").unwrap().unwrap(), &data);
+    let obj = os.open_or_create_object(b"gxhash").unwrap();
+    obj.write_at(&data, 0).unwrap();
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+    read_back(&obj, &data);
+    read_back(&os.open_object(b"crc32c").unwrap().unwrap(), &data);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+}
+
+#[rstest]
+fn conflicting_buffer_allocation() {
+    // Other tests may open databases concurrently, all of which use the
+    // default allocation.
+    let _db = test_db(1, 32);
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 32 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        buffer_allocation: BufferAllocation::HugePages,
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    let err = Database::build(cfg).err().unwrap();
+    assert!(matches!(
+        err,
+        Error::InvalidConfiguration { ref field, .. } if field == "buffer_allocation"
+    ));
+}
+
+#[rstest]
+fn dataset_stats() {
+    let db = test_db(1, 32);
+    let hot = db.open_or_create_dataset(b"hot").unwrap();
+    let cold = db.open_or_create_dataset(b"cold").unwrap();
+    assert_eq!(hot.stats(), Default::default());
+
+    hot.insert(&b"a"[..], &[1; 100]).unwrap();
+    hot.upsert(&b"a"[..], &[2; 10], 100).unwrap();
+    hot.insert(&b"b"[..], &[3; 50]).unwrap();
+    hot.delete(&b"b"[..]).unwrap();
+    assert_eq!(hot.get(&b"a"[..]).unwrap().unwrap().len(), 110);
+    assert!(hot.get(&b"b"[..]).unwrap().is_none());
+    assert_eq!(hot.range::<_, &[u8]>(..).unwrap().count(), 1);
+    cold.insert(&b"c"[..], &[4; 10]).unwrap();
+
+    let stats = hot.stats();
+    assert_eq!(stats.inserts, 4);
+    assert_eq!(stats.deletes, 1);
+    assert_eq!(stats.gets, 2);
+    assert_eq!(stats.range_ops, 1);
+    assert_eq!(stats.bytes_read, 2 * 110);
+    assert!(stats.bytes_written > 160);
+    assert!(stats.buffer_hits <= stats.gets);
+    assert_eq!(cold.stats().inserts, 1);
+    assert_eq!(cold.stats().gets, 0);
+}
+
+#[rstest]
+fn sequential_appends() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 128 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        cache_size: 2 * TO_MEBIBYTE,
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"series").unwrap();
+    // Far more than fits into the cache, so that most leaves are evicted.
+    for idx in 0..8192u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 2048])
+            .unwrap();
+        if idx % 1024 == 0 {
+            db.sync().unwrap();
+        }
+    }
+    db.sync().unwrap();
+
+    // Appends are never staged in the buffers of internal nodes.
+    for idx in 0..8192u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8; 2048][..]);
+    }
+    assert_eq!(ds.stats().buffer_hits, 0);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    db.close_dataset(ds).unwrap();
+}
+
+#[test]
+fn appends_over_buffered_messages() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 128 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        cache_size: 2 * TO_MEBIBYTE,
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"series").unwrap();
+    let key = |idx: u32| idx.to_be_bytes().to_vec();
+    for idx in 0..4096u32 {
+        ds.insert(key(idx), &[1; 2048]).unwrap();
+    }
+    db.sync().unwrap();
+
+    // Buffered while the rightmost leaves are evicted, then overwritten by
+    // an increasing run of insertions.
+    ds.delete(key(4000)).unwrap();
+    ds.upsert(key(4010), &[2; 16], 0).unwrap();
+    ds.delete(key(4020)).unwrap();
+    for idx in 3980..=4010u32 {
+        ds.insert(key(idx), &[3; 2048]).unwrap();
+    }
+    db.sync().unwrap();
+
+    for idx in 3980..=4010u32 {
+        assert_eq!(&ds.get(key(idx)).unwrap().unwrap()[..], &[3; 2048][..]);
+    }
+    assert!(ds.get(key(4020)).unwrap().is_none());
+    assert_eq!(&ds.get(key(4030)).unwrap().unwrap()[..], &[1; 2048][..]);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    db.close_dataset(ds).unwrap();
+}
+
+#[test]
+fn io_scheduling() {
+    use betree_storage_stack::storage_pool::{
+        with_io_priority, IoPriority, SchedulerConfiguration,
+    };
+
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                scheduler: SchedulerConfiguration {
+                    max_in_flight: Some(2),
+                    max_commit: Some(1),
+                    max_background: Some(1),
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        cache_size: TO_MEBIBYTE,
+        write_back_threads: 4,
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"scheduled").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 1024])
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    // Requests of all priorities are dispatched eventually.
+    with_io_priority(IoPriority::Background, || {
+        for idx in (0..2048u32).step_by(7) {
+            ds.insert(idx.to_be_bytes().to_vec(), &[!idx as u8; 1024])
+                .unwrap();
+        }
+        db.sync().unwrap();
+    });
+    for idx in 0..2048u32 {
+        let expected = if idx % 7 == 0 { !idx as u8 } else { idx as u8 };
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[expected; 1024][..]);
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn dataset_space_usage(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    let written;
+    {
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        let full = db.open_or_create_dataset(b"full").unwrap();
+        let empty = db.open_or_create_dataset(b"empty").unwrap();
+        for idx in 0u32..1024 {
+            full.insert(idx.to_be_bytes().to_vec(), &[42; 4096])
+                .unwrap();
+        }
+        db.sync().unwrap();
+
+        written = db.space_usage(&full);
+        let untouched = db.space_usage(&empty);
+        assert!(written.logical_bytes >= 1024 * 4096);
+        assert!(written.logical_bytes > untouched.logical_bytes);
+        assert!(written.physical_blocks > untouched.physical_blocks);
+        assert_eq!(
+            written.physical_blocks.as_u64(),
+            written.tiers.iter().map(|b| b.as_u64()).sum::<u64>()
+        );
+    }
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let full = db.open_dataset(b"full").unwrap();
+    assert_eq!(db.space_usage(&full), written);
+}
+
+#[rstest]
+fn degraded_mirror_open() {
+    let dir = env::temp_dir();
+    let paths: Vec<_> = ["degraded_mirror_a", "degraded_mirror_b"]
+        .iter()
+        .map(|name| dir.join(format!("{}_{}", name, std::process::id())))
+        .collect();
+    for path in paths.iter() {
+        std::fs::File::create(path)
+            .unwrap()
+            .set_len(128 * TO_MEBIBYTE as u64)
+            .unwrap();
+    }
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Mirror {
+                    mirror: paths
+                        .iter()
+                        .map(|path| LeafVdev::FileWithOpts {
+                            path: path.clone(),
+                            direct: Some(false),
+                        })
+                        .collect(),
+                    read_policy: None,
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"before".to_vec(), b"value").unwrap();
+        db.sync().unwrap();
+        assert!(!db.storage_report().is_degraded());
+    }
+
+    std::fs::remove_file(&paths[1]).unwrap();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    assert!(Database::build(cfg.clone()).is_err());
+
+    cfg.storage.allow_degraded = true;
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let report = db.storage_report();
+        assert!(report.is_degraded());
+        assert_eq!(
+            report.tiers[0].faulted,
+            vec![paths[1].to_string_lossy().into_owned()]
+        );
+
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"before".to_vec()).unwrap().unwrap()[..], b"value");
+        ds.insert(b"after".to_vec(), b"value").unwrap();
+        db.sync().unwrap();
+    }
+
+    let db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"foo").unwrap();
+    assert_eq!(&ds.get(b"after".to_vec()).unwrap().unwrap()[..], b"value");
+    drop(ds);
+    drop(db);
+    std::fs::remove_file(&paths[0]).unwrap();
+}
+
+#[rstest]
+fn impaired_mirror_leaf() {
+    let dir = env::temp_dir();
+    let paths: Vec<_> = ["impaired_mirror_a", "impaired_mirror_b"]
+        .iter()
+        .map(|name| dir.join(format!("{}_{}", name, std::process::id())))
+        .collect();
+    for path in paths.iter() {
+        std::fs::File::create(path)
+            .unwrap()
+            .set_len(128 * TO_MEBIBYTE as u64)
+            .unwrap();
+    }
+    let leaf = |path: &std::path::PathBuf| LeafVdev::FileWithOpts {
+        path: path.clone(),
+        direct: Some(false),
+    };
+    let config = |impaired: bool| {
+        let mut mirror: Vec<_> = paths.iter().map(leaf).collect();
+        if impaired {
+            // Every request to the second leaf fails after a delay.
+            mirror[1] = LeafVdev::Impaired {
+                impaired: Box::new(leaf(&paths[1])),
+                latency_us: Some(100),
+                jitter_us: Some(100),
+                bandwidth: None,
+                error_rate: Some(1.0),
+            };
+        }
+        DatabaseConfiguration {
+            storage: StoragePoolConfiguration {
+                tiers: vec![TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Mirror {
+                        mirror,
+                        read_policy: None,
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            access_mode: AccessMode::OpenIfExists,
+            ..Default::default()
+        }
+    };
+    {
+        let mut cfg = config(false);
+        cfg.access_mode = AccessMode::AlwaysCreateNew;
+        let mut db = Database::build(cfg).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"before".to_vec(), b"value").unwrap();
+        db.sync().unwrap();
+    }
+    {
+        // The mirror hides the failures of the impaired leaf.
+        let mut db = Database::build(config(true)).unwrap();
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"before".to_vec()).unwrap().unwrap()[..], b"value");
+        ds.insert(b"after".to_vec(), b"value").unwrap();
+        db.sync().unwrap();
+    }
+    let db = Database::build(config(false)).unwrap();
+    let ds = db.open_dataset(b"foo").unwrap();
+    assert_eq!(&ds.get(b"after".to_vec()).unwrap().unwrap()[..], b"value");
+    drop(ds);
+    drop(db);
+    for path in paths.iter() {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[rstest]
+fn mirror_read_repair_event() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = env::temp_dir();
+    let paths: Vec<_> = ["repaired_mirror_a", "repaired_mirror_b"]
+        .iter()
+        .map(|name| dir.join(format!("{}_{}", name, std::process::id())))
+        .collect();
+    for path in paths.iter() {
+        std::fs::File::create(path)
+            .unwrap()
+            .set_len(128 * TO_MEBIBYTE as u64)
+            .unwrap();
+    }
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Mirror {
+                    mirror: paths
+                        .iter()
+                        .map(|path| LeafVdev::FileWithOpts {
+                            path: path.clone(),
+                            direct: Some(false),
+                        })
+                        .collect(),
+                    // Reads start with the corrupted leg.
+                    read_policy: Some(MirrorReadPolicy::First),
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        for idx in 0..64u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 16 * 1024])
+                .unwrap();
+        }
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+
+    // Everything but the superblocks of the first leg is overwritten.
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&paths[0])
+        .unwrap();
+    let start = SUPERBLOCK_BLOCKS.to_bytes();
+    let garbage = vec![0x55; 128 * TO_MEBIBYTE - 2 * start as usize];
+    file.write_all_at(&garbage, start).unwrap();
+    drop(file);
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&events);
+    db.register_event_handler(move |event| recorded.lock().push(event.clone()));
+    let ds = db.open_dataset(b"foo").unwrap();
+    for idx in 0..64u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8; 16 * 1024][..]);
+    }
+
+    let repaired = paths[0].to_string_lossy().into_owned();
+    {
+        let events = events.lock();
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::MirrorRepaired { disk, .. } if *disk == repaired
+        )));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::ReadFailed { .. })));
+    }
+    drop(ds);
+    drop(db);
+    for path in paths.iter() {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[rstest]
+fn online_vdev_addition() {
+    let dir = env::temp_dir();
+    let leaves: Vec<_> = ["online_vdev_a", "online_vdev_b", "online_vdev_c"]
+        .iter()
+        .map(|name| {
+            let path = dir.join(format!("{}_{}", name, std::process::id()));
+            std::fs::File::create(&path)
+                .unwrap()
+                .set_len(64 * TO_MEBIBYTE as u64)
+                .unwrap();
+            Vdev::Leaf(LeafVdev::FileWithOpts {
+                path,
+                direct: Some(false),
+            })
+        })
+        .collect();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![leaves[0].clone()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"before".to_vec(), b"value").unwrap();
+        db.sync().unwrap();
+        let before = db.free_space_tier();
+
+        db.add_vdev(0, leaves[1].clone()).unwrap();
+        db.add_vdev(1, leaves[2].clone()).unwrap();
+        let after = db.free_space_tier();
+        assert!(after[0].total > before[0].total);
+        assert!(after[0].free > before[0].free);
+        assert_eq!(before[1].total.as_u64(), 0);
+        assert!(after[1].free.as_u64() > 0);
+
+        let os = db
+            .open_named_object_store(b"fast", StoragePreference::FAST)
+            .unwrap();
+        let obj = os.open_or_create_object(b"obj").unwrap();
+        obj.write_at(&[42; 4096], 0).unwrap();
+        drop(obj);
+        db.close_object_store(os);
+        db.sync().unwrap();
+        let report = db.storage_report();
+        assert_eq!(report.tiers[0].disks.len(), 2);
+        assert_eq!(report.tiers[1].disks.len(), 1);
+        assert!(report.tiers[1].info.free < after[1].free);
+    }
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    assert!(matches!(
+        Database::build(cfg.clone()),
+        Err(Error::MissingVdevs(0, 1))
+    ));
+
+    cfg.storage.tiers[0].top_level_vdevs.push(leaves[1].clone());
+    cfg.storage.tiers.push(TierConfiguration {
+        top_level_vdevs: vec![leaves[2].clone()],
+        ..Default::default()
+    });
+    {
+        let mut db = Database::build(cfg).unwrap();
+        assert_eq!(db.storage_report().tiers[0].disks.len(), 2);
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"before".to_vec()).unwrap().unwrap()[..], b"value");
+        let os = db
+            .open_named_object_store(b"fast", StoragePreference::FAST)
+            .unwrap();
+        let obj = os.open_object(b"obj").unwrap().unwrap();
+        let mut buf = [0; 4096];
+        obj.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..], &[42; 4096][..]);
+    }
+    for leaf in leaves.iter() {
+        if let Vdev::Leaf(LeafVdev::FileWithOpts { path, .. }) = leaf {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[rstest]
+fn vdev_evacuation() {
+    let dir = env::temp_dir();
+    let leaves: Vec<_> = ["evacuate_vdev_a", "evacuate_vdev_b"]
+        .iter()
+        .map(|name| {
+            let path = dir.join(format!("{}_{}", name, std::process::id()));
+            std::fs::File::create(&path)
+                .unwrap()
+                .set_len(64 * TO_MEBIBYTE as u64)
+                .unwrap();
+            Vdev::Leaf(LeafVdev::FileWithOpts {
+                path,
+                direct: Some(false),
+            })
+        })
+        .collect();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: leaves.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        for idx in 0..256u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 8192])
+                .unwrap();
+        }
+        db.sync().unwrap();
+        let used = |info: &StorageInfo| info.total.as_u64() - info.free.as_u64();
+        let report = db.storage_report();
+        assert!(used(&report.tiers[0].disks[1]) > 2 * SUPERBLOCK_BLOCKS.as_u64());
+
+        assert!(db
+            .evacuate_disk(DiskOffset::construct_disk_id(0, 1))
+            .unwrap());
+        let report = db.storage_report();
+        assert_eq!(report.tiers[0].disks.len(), 1);
+        assert_eq!(report.tiers[0].info.total, report.tiers[0].disks[0].total);
+
+        for idx in 0..256u32 {
+            let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(&value[..], &[idx as u8; 8192][..]);
+        }
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    cfg.storage.tiers[0].top_level_vdevs.pop();
+    {
+        let mut db = Database::build(cfg).unwrap();
+        assert_eq!(db.storage_report().tiers[0].disks.len(), 1);
+        let ds = db.open_dataset(b"foo").unwrap();
+        for idx in 0..256u32 {
+            let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(&value[..], &[idx as u8; 8192][..]);
+        }
+        assert!(db
+            .evacuate_disk(DiskOffset::construct_disk_id(0, 1))
+            .is_err());
+    }
+    for leaf in leaves.iter() {
+        if let Vdev::Leaf(LeafVdev::FileWithOpts { path, .. }) = leaf {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[rstest]
+fn sealed_storage_class() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: (0..2)
+                .map(|_| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: 64 * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        compression: CompressionConfiguration::None,
+        access_mode: AccessMode::AlwaysCreateNew,
+        alloc_strategy: vec![vec![0, 1], vec![1], vec![2], vec![3]],
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..64u32 {
+        ds.insert_with_pref(
+            idx.to_be_bytes().to_vec(),
+            &[idx as u8; 8192],
+            StoragePreference::FASTEST,
+        )
+        .unwrap();
+    }
+    db.sync().unwrap();
+    db.sync().unwrap();
+    let sealed_free = db.free_space_tier()[0].free;
+
+    db.seal_storage_class(0).unwrap();
+    assert_eq!(db.sealed_disks(), vec![DiskOffset::construct_disk_id(0, 0)]);
+    assert!(matches!(
+        ds.migrate(0u32.to_be_bytes().to_vec(), StoragePreference::FASTEST),
+        Err(Error::StorageClassSealed(0))
+    ));
+
+    // Overwritten entries move to the next storage class, the space they
+    // occupied on the sealed one is not freed.
+    for idx in 0..64u32 {
+        ds.insert_with_pref(
+            idx.to_be_bytes().to_vec(),
+            &[idx as u8 + 1; 8192],
+            StoragePreference::FASTEST,
+        )
+        .unwrap();
+    }
+    db.sync().unwrap();
+    db.sync().unwrap();
+    assert_eq!(db.free_space_tier()[0].free, sealed_free);
+    let report = db.fsck(FsckOptions::default()).unwrap();
+    assert!(report.is_consistent(), "{:?}", report);
+
+    db.unseal_storage_class(0).unwrap();
+    db.sync().unwrap();
+    assert!(db.sealed_disks().is_empty());
+    assert!(db.free_space_tier()[0].free > sealed_free);
+    let report = db.fsck(FsckOptions::default()).unwrap();
+    assert!(report.is_consistent(), "{:?}", report);
+    for idx in 0..64u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8 + 1; 8192][..]);
+    }
+}
+
+#[test]
+fn value_placement() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: (0..2)
+                .map(|_| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: 64 * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        compression: CompressionConfiguration::None,
+        access_mode: AccessMode::AlwaysCreateNew,
+        value_placement: ValuePlacement {
+            rules: vec![
+                ValueSizeRule {
+                    max_size: 1024,
+                    storage_class: 0,
+                },
+                ValueSizeRule {
+                    max_size: 1024 * 1024,
+                    storage_class: 1,
+                },
+            ],
+        },
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    db.sync().unwrap();
+    let before = db.free_space_tier();
+
+    // Large values are placed on the slow storage class.
+    for idx in 0..64u32 {
+        let key = [&[1u8][..], &idx.to_be_bytes()].concat();
+        ds.insert(key, &[idx as u8; 64 * 1024]).unwrap();
+    }
+    db.sync().unwrap();
+    let large = db.free_space_tier();
+    let used = |from: &[StorageInfo], to: &[StorageInfo], class: usize| {
+        (from[class].free.to_bytes() - to[class].free.to_bytes()) as usize
+    };
+    assert!(used(&before, &large, 1) >= 4 * TO_MEBIBYTE);
+    assert!(used(&before, &large, 0) < TO_MEBIBYTE);
+
+    // An explicit storage preference overrides the rules.
+    for idx in 0..32u32 {
+        let key = [&[2u8][..], &idx.to_be_bytes()].concat();
+        ds.insert_with_pref(key, &[idx as u8; 64 * 1024], StoragePreference::FASTEST)
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let pinned = db.free_space_tier();
+    assert!(used(&large, &pinned, 0) >= 2 * TO_MEBIBYTE);
+
+    for idx in 0..64u32 {
+        let key = [&[1u8][..], &idx.to_be_bytes()].concat();
+        assert_eq!(&*ds.get(key).unwrap().unwrap(), &[idx as u8; 64 * 1024][..]);
+    }
+}
+
+#[test]
+fn dataset_default_storage_preference() {
+    let mut db = test_db(2, 64);
+    db.create_custom_dataset::<DefaultMessageAction>(b"slow", StoragePreference::FAST)
+        .unwrap();
+    let ds = db.open_dataset(b"slow").unwrap();
+    assert_eq!(ds.default_storage_preference(), StoragePreference::FAST);
+    db.sync().unwrap();
+    let before = db.free_space_tier();
+    for idx in 0..64u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 64 * 1024])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let after = db.free_space_tier();
+    assert!(before[1].free.to_bytes() - after[1].free.to_bytes() >= 4 * TO_MEBIBYTE as u64);
+
+    // Changes are restored when the data set is opened again.
+    ds.set_default_storage_preference(StoragePreference::FASTEST)
+        .unwrap();
+    db.close_dataset(ds).unwrap();
+    let ds = db.open_dataset(b"slow").unwrap();
+    assert_eq!(ds.default_storage_preference(), StoragePreference::FASTEST);
+    assert!(matches!(
+        ds.set_default_storage_preference(StoragePreference::SLOW),
+        Err(Error::EmptyStorageClass(2))
+    ));
+    db.close_dataset(ds).unwrap();
+}
+
+#[test]
+fn root_tree_storage_preference() {
+    let tier_one_free = |root_tree_storage_preference| {
+        let cfg = DatabaseConfiguration {
+            storage: StoragePoolConfiguration {
+                tiers: (0..2)
+                    .map(|_| TierConfiguration {
+                        top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                            mem: 64 * TO_MEBIBYTE,
+                        })],
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+            access_mode: AccessMode::AlwaysCreateNew,
+            root_tree_storage_preference,
+            ..Default::default()
+        };
+        let mut db = Database::build(cfg).unwrap();
+        for idx in 0..64u32 {
+            db.create_dataset(&idx.to_be_bytes()).unwrap();
+        }
+        db.sync().unwrap();
+        assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+        db.free_space_tier()[1].free
+    };
+    assert!(tier_one_free(StoragePreference::FAST) < tier_one_free(StoragePreference::FASTEST));
+
+    let cfg = DatabaseConfiguration {
+        root_tree_storage_preference: StoragePreference::SLOW,
+        ..Default::default()
+    };
+    assert!(matches!(
+        cfg.validate(),
+        Err(Error::InvalidConfiguration { field, .. }) if field == "root_tree_storage_preference"
+    ));
+}
+
+#[rstest]
+fn delta_encoding() {
+    let path = env::temp_dir().join(format!("delta_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(64 * TO_MEBIBYTE as u64)
+        .unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        compression: CompressionConfiguration::None,
+        delta_encoding: Some(DeltaConfiguration {
+            max_changed_share: 0.25,
+            max_deltas: 3,
+        }),
+        ..Default::default()
+    };
+    let key = |idx: u32| idx.to_be_bytes().to_vec();
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+        for idx in 0..64u32 {
+            ds.insert(key(idx), &[0; 1024]).unwrap();
+        }
+        db.sync().unwrap();
+        // The last round exceeds the number of deltas of the same base.
+        for round in 1..=4u8 {
+            ds.insert(key(round as u32), &[round; 1024]).unwrap();
+            if round == 2 {
+                db.create_snapshot(&mut ds, b"snap").unwrap();
+            }
+            db.sync().unwrap();
+        }
+        ds.delete(key(63)).unwrap();
+        db.sync().unwrap();
+        db.drop_cache().unwrap();
+        assert_eq!(&ds.get(key(2)).unwrap().unwrap()[..], &[2; 1024]);
+        let snapshot = db.open_snapshot(&mut ds, b"snap").unwrap();
+        assert_eq!(&snapshot.get(key(2)).unwrap().unwrap()[..], &[2; 1024]);
+        assert_eq!(&snapshot.get(key(3)).unwrap().unwrap()[..], &[0; 1024]);
+        drop(snapshot);
+        let report = db.fsck(FsckOptions::default()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+
+        // Blocks which die after the deletion of the snapshot, like the base
+        // of its leaf, end up orphaned.
+        db.delete_snapshot(&mut ds, b"snap").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.audit_dead_lists(true).unwrap();
+        let report = db.fsck(FsckOptions::default()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+    }
+
+    // Only the removed entry has been written by the last sync.
+    let inspector = Inspector::open(&cfg).unwrap();
+    let datasets = inspector.datasets().unwrap();
+    let foo = datasets.iter().find(|ds| &ds.name[..] == b"foo").unwrap();
+    assert!(foo.root.size().to_bytes() < 16 * 1024);
+    assert_eq!(inspector.read_node(&foo.root).unwrap().entries, 63);
+    drop(inspector);
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"foo").unwrap();
+    for idx in 0..63u32 {
+        let value = if (1..=4).contains(&idx) { idx as u8 } else { 0 };
+        assert_eq!(&ds.get(key(idx)).unwrap().unwrap()[..], &[value; 1024]);
+    }
+    assert!(ds.get(key(63)).unwrap().is_none());
+    ds.insert(key(0), &[5; 1024]).unwrap();
+    db.close_dataset(ds).unwrap();
+    let report = db.fsck(FsckOptions::default()).unwrap();
+    assert!(report.is_consistent(), "{:?}", report);
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[rstest]
+fn defragmentation() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: (0..2)
+                    .map(|_| {
+                        Vdev::Leaf(LeafVdev::Memory {
+                            mem: 64 * TO_MEBIBYTE,
+                        })
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        defragmentation: DefragmentationConfiguration {
+            max_occupancy: 1.0,
+            max_segments: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..512u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 8192])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    // Leave holes behind on both vdevs.
+    for idx in (0..512u32).filter(|idx| idx % 4 != 0) {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
+    }
+    db.sync().unwrap();
+    db.sync().unwrap();
+
+    db.defragment().unwrap();
+    let used = |info: &StorageInfo| info.total.as_u64() - info.free.as_u64();
+    let report = db.storage_report();
+    assert!(report.tiers[0]
+        .disks
+        .iter()
+        .any(|disk| used(disk) == 2 * SUPERBLOCK_BLOCKS.as_u64()));
+
+    for idx in 0..512u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap();
+        if idx % 4 == 0 {
+            assert_eq!(&value.unwrap()[..], &[idx as u8; 8192][..]);
+        } else {
+            assert!(value.is_none());
+        }
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn defragmentation_skips_snapshots() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        defragmentation: DefragmentationConfiguration {
+            max_occupancy: 1.0,
+            max_segments: usize::MAX,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..512u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 8192])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"snap").unwrap();
+    // The holes are retained by the snapshot.
+    for idx in (0..512u32).filter(|idx| idx % 4 != 0) {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
+    }
+    db.sync().unwrap();
+    db.sync().unwrap();
+
+    // Rewriting the nodes retained by the snapshot would only add copies.
+    let used = |db: &Database| {
+        let info = db.storage_report().tiers[0].info;
+        info.total.as_u64() - info.free.as_u64()
+    };
+    let before = used(&db);
+    db.defragment().unwrap();
+    assert!(used(&db) <= before);
+
+    let snapshot = db.open_snapshot(&mut ds, b"snap").unwrap();
+    for idx in 0..512u32 {
+        let value = snapshot.get(idx.to_be_bytes().to_vec()).unwrap();
+        assert_eq!(&value.unwrap()[..], &[idx as u8; 8192][..]);
+    }
+    drop(snapshot);
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn compaction() {
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 256 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        compaction: CompactionConfiguration {
+            min_buffer_size: 1,
+            cold_after_ms: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0..4096u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    // Buffer a few overwrites in the internal nodes.
+    for idx in (0..4096u32).step_by(64) {
+        ds.insert(idx.to_be_bytes().to_vec(), &[2; 4096]).unwrap();
+    }
+
+    assert!(db.compact().unwrap() > 0);
+    for idx in 0..4096u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        let expected = if idx % 64 == 0 { 2 } else { 1 };
+        assert_eq!(&value[..], &[expected; 4096][..]);
+    }
+    db.sync().unwrap();
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn vdev_growth() {
+    let path = env::temp_dir().join(format!("grow_vdev_{}", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    file.set_len(64 * TO_MEBIBYTE as u64).unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    let grown = Block::<u64>::from_bytes(64 * TO_MEBIBYTE as u64);
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let before = db.storage_report().tiers[0].info;
+        assert_eq!(db.expand().unwrap(), Block(0));
+
+        file.set_len(128 * TO_MEBIBYTE as u64).unwrap();
+        assert_eq!(db.expand().unwrap(), grown);
+        let after = db.storage_report().tiers[0].info;
+        assert_eq!(after.total, before.total + grown);
+        assert_eq!(after.free, before.free + grown);
+        assert_eq!(db.storage_report().tiers[0].disks[0].total, after.total);
+        db.sync().unwrap();
+    }
+
+    cfg.access_mode = AccessMode::OpenIfExists;
+    {
+        let db = Database::build(cfg).unwrap();
+        let info = db.storage_report().tiers[0].info;
+        assert_eq!(info.total, Block::from_bytes(128 * TO_MEBIBYTE as u64));
+        assert_eq!(db.expand().unwrap(), Block(0));
+
+        file.set_len(32 * TO_MEBIBYTE as u64).unwrap();
+        assert!(db.expand().is_err());
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[rstest]
+fn named_tiers() {
+    let paths: Vec<_> = ["fast", "archive"]
+        .iter()
+        .map(|name| env::temp_dir().join(format!("tier_{name}_{}", std::process::id())))
+        .collect();
+    let tier = |path: &std::path::PathBuf, name: &str| {
+        std::fs::File::create(path)
+            .unwrap()
+            .set_len(64 * TO_MEBIBYTE as u64)
+            .unwrap();
+        TierConfiguration {
+            top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                path: path.clone(),
+                direct: Some(false),
+            })],
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    };
+    let mut tiers = vec![TierConfiguration::default(); 6];
+    tiers[0] = tier(&paths[0], "fast");
+    tiers[5] = tier(&paths[1], "archive");
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers,
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let archive = db.tier("archive").unwrap();
+        assert_eq!(archive, StoragePreference::new(5));
+        assert_eq!(db.tier("fast"), Some(StoragePreference::FASTEST));
+        assert_eq!(db.tier("slow"), None);
+        assert!(db.set_tier_name(0, "archive").is_err());
+
+        let ds = db
+            .open_or_create_custom_dataset::<DefaultMessageAction>(b"foo", archive)
+            .unwrap();
+        ds.insert(b"key".to_vec(), &[42; 8192]).unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+        let report = db.storage_report();
+        assert_eq!(report.tiers[5].name.as_deref(), Some("archive"));
+        let info = report.tiers[5].info;
+        assert!(info.total.as_u64() - info.free.as_u64() > 2 * SUPERBLOCK_BLOCKS.as_u64());
+    }
+
+    // The names are kept in the superblock.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    for tier in cfg.storage.tiers.iter_mut() {
+        tier.name = None;
+    }
+    {
+        let db = Database::build(cfg).unwrap();
+        assert_eq!(db.tier("archive"), Some(StoragePreference::new(5)));
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], &[42; 8192]);
+    }
+    for path in paths {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn inspect_offline() {
+    let path = env::temp_dir().join(format!("inspect_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(64 * TO_MEBIBYTE as u64)
+        .unwrap();
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+        for idx in 0..1000u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[42; 512]).unwrap();
+        }
+        db.create_snapshot(&mut ds, b"first").unwrap();
+        ds.insert(b"later".to_vec(), &[43; 8]).unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+
+    let inspector = Inspector::open(&cfg).unwrap();
+    assert_eq!(inspector.superblock().version(), FORMAT_VERSION);
+    assert_eq!(inspector.superblock().legacy_generation(), None);
+    let datasets = inspector.datasets().unwrap();
+    let foo = datasets.iter().find(|ds| &ds.name[..] == b"foo").unwrap();
+    let entries = inspector.entries(&foo.root, &DefaultMessageAction).unwrap();
+    assert_eq!(entries.len(), 1001);
+    assert_eq!(&entries[&b"later"[..]][..], &[43; 8]);
+
+    let snapshots = inspector.snapshots(foo.id).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(&snapshots[0].name[..], b"first");
+    assert_eq!(Some(snapshots[0].generation), foo.previous_snapshot);
+    let snapshot_entries = inspector
+        .entries(&snapshots[0].root, &DefaultMessageAction)
+        .unwrap();
+    assert_eq!(snapshot_entries.len(), 1000);
+
+    let mut nodes = 0;
+    inspector.walk(inspector.root_pointer(), |_, node| {
+        node.unwrap();
+        nodes += 1;
+    });
+    assert!(nodes > 0);
+
+    let segments = inspector.segments(0).unwrap();
+    assert!(segments
+        .iter()
+        .any(|(_, allocator)| allocator.allocated() > 0));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn superblock_fallback() {
+    use std::os::unix::fs::FileExt;
+
+    let path = env::temp_dir().join(format!("superblocks_{}", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    file.set_len(64 * TO_MEBIBYTE as u64).unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"key".to_vec(), &[42; 8]).unwrap();
+        db.close_dataset(ds).unwrap();
+        // Both superblock slots refer to the key from now on.
+        db.sync().unwrap();
+        db.sync().unwrap();
+    }
+
+    let end = Block::<u64>::from_bytes(64 * TO_MEBIBYTE as u64).as_u64();
+    let damage = |blocks: &[u64]| {
+        for &block in blocks {
+            file.write_at(&[0xff; 4096], Block(block).to_bytes())
+                .unwrap();
+        }
+        file.sync_all().unwrap();
+    };
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let check = |cfg: &DatabaseConfiguration| {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], &[42; 8]);
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    };
+
+    // The copies at the end stand in for the ones at the start.
+    damage(&[0, 1]);
+    check(&cfg);
+    // A slot lost on the whole vdev falls back to the other one, whichever
+    // generation it holds.
+    damage(&[0, end - 2]);
+    check(&cfg);
+    damage(&[1, end - 1]);
+    check(&cfg);
+
+    damage(&[0, 1, end - 2, end - 1]);
+    assert!(matches!(
+        Database::build(cfg),
+        Err(Error::InvalidSuperblock)
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn encryption_at_rest() {
+    let path = env::temp_dir().join(format!("encrypted_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(64 * TO_MEBIBYTE as u64)
+        .unwrap();
+    let key = |id: u16| EncryptionKey {
+        id,
+        key: [id as u8; 32],
+    };
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        encryption: Some(EncryptionConfiguration {
+            active_key: 1,
+            keys: vec![key(1)],
+        }),
+        ..Default::default()
+    };
+    let value = b"plaintext which must not be found on disk".repeat(100);
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"key".to_vec(), &value).unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    let raw = std::fs::read(&path).unwrap();
+    assert!(!raw.windows(value.len()).any(|window| window == value));
+
+    // Objects encrypted with the old key are readable until rewritten.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    cfg.encryption = Some(EncryptionConfiguration {
+        active_key: 2,
+        keys: vec![key(1), key(2)],
+    });
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], &value[..]);
+        db.close_dataset(ds).unwrap();
+        db.rotate_encryption_key().unwrap();
+    }
+
+    cfg.encryption = Some(EncryptionConfiguration {
+        active_key: 2,
+        keys: vec![key(2)],
+    });
+    {
+        let db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_dataset(b"foo").unwrap();
+        assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], &value[..]);
+    }
+
+    cfg.encryption = None;
+    assert!(Database::build(cfg).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn mixed_compression() {
+    let path = env::temp_dir().join(format!("compression_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(64 * TO_MEBIBYTE as u64)
+        .unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        compression: CompressionConfiguration::Zstd(Zstd { level: 1 }),
+        dataset_compression: std::collections::HashMap::from([(
+            "fast".to_string(),
+            CompressionConfiguration::Lz4(Lz4 { level: 0 }),
+        )]),
+        ..Default::default()
+    };
+    let value = b"compressible ".repeat(1000);
+    let write = |db: &mut Database, name: &[u8], key: &[u8]| {
+        let ds = db.open_or_create_dataset(name).unwrap();
+        ds.insert(key.to_vec(), &value).unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        write(&mut db, b"fast", b"first");
+        write(&mut db, b"small", b"first");
+    }
+
+    // Objects keep the codec they have been written with.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    cfg.compression = CompressionConfiguration::Lz4(Lz4 { level: 9 });
+    cfg.dataset_compression.clear();
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        write(&mut db, b"fast", b"second");
+        write(&mut db, b"small", b"second");
+    }
+
+    cfg.compression = CompressionConfiguration::None;
+    let db = Database::build(cfg).unwrap();
+    for name in [&b"fast"[..], b"small"] {
+        let ds = db.open_dataset(name).unwrap();
+        for key in [&b"first"[..], b"second"] {
+            assert_eq!(&ds.get(key).unwrap().unwrap()[..], &value[..]);
+        }
+    }
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn compression_dictionary() {
+    let path = env::temp_dir().join(format!("dictionary_{}", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(64 * TO_MEBIBYTE as u64)
+        .unwrap();
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        compression: CompressionConfiguration::Zstd(Zstd { level: 3 }),
+        ..Default::default()
+    };
+    let key = |idx: u32| idx.to_be_bytes().to_vec();
+    let record = |idx: u32, round: u32| {
+        let message = "request served";
+        format!(r#"{{"id": {idx}, "level": "info", "message": "{message}", "round": {round}}}"#)
+            .into_bytes()
+    };
+    {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"logs").unwrap();
+        assert!(matches!(
+            ds.train_dictionary(16 * 1024),
+            Err(Error::DictionaryTrainingFailed(_))
+        ));
+        for idx in 0..2000 {
+            ds.insert(key(idx), &record(idx, 0)).unwrap();
+        }
+        db.sync().unwrap();
+        assert_eq!(ds.train_dictionary(16 * 1024).unwrap(), 0);
+        for idx in (0..2000).step_by(2) {
+            ds.insert(key(idx), &record(idx, 1)).unwrap();
+        }
+        db.sync().unwrap();
+        db.drop_cache().unwrap();
+        for idx in 0..2000 {
+            let round = if idx % 2 == 0 { 1 } else { 0 };
+            assert_eq!(
+                &ds.get(key(idx)).unwrap().unwrap()[..],
+                &record(idx, round)[..]
+            );
+        }
+        db.close_dataset(ds).unwrap();
+        let report = db.fsck(FsckOptions::default()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+    }
+
+    let inspector = Inspector::open(&cfg).unwrap();
+    let datasets = inspector.datasets().unwrap();
+    let logs = datasets.iter().find(|ds| &ds.name[..] == b"logs").unwrap();
+    assert_eq!(
+        logs.root.decompression_tag(),
+        DecompressionTag::ZstdDictionary(0)
+    );
+    inspector.read_node(&logs.root).unwrap();
+    drop(inspector);
+
+    // Nodes compressed with older dictionaries stay readable after
+    // retraining.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"logs").unwrap();
+    assert_eq!(ds.train_dictionary(16 * 1024).unwrap(), 1);
+    for idx in (1..2000).step_by(2) {
+        ds.insert(key(idx), &record(idx, 2)).unwrap();
+    }
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+    for idx in 0..2000 {
+        let round = if idx % 2 == 0 { 1 } else { 2 };
+        assert_eq!(
+            &ds.get(key(idx)).unwrap().unwrap()[..],
+            &record(idx, round)[..]
+        );
+    }
+    db.close_dataset(ds).unwrap();
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[fixture]
+fn file_backed_config() -> RwLockWriteGuard<'static, DatabaseConfiguration> {
+    configs::file_backed()
+}
+
+fn migration_policy_smoke(cfg: DatabaseConfiguration) {
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let ds;
+    {
+        let mut db = shared_db.write();
+        ds = db
+            .open_named_object_store(b"test", StoragePreference::NONE)
+            .unwrap();
+        db.sync().unwrap();
+    }
+    let obj = ds.open_or_create_object(b"foobar").unwrap();
+    let buf = vec![42u8; 1024 * TO_MEBIBYTE];
+    dbg!(obj.write_at(&buf, 0).unwrap());
+    {
+        let mut db = shared_db.write();
+        db.close_object_store(ds);
+        db.sync().unwrap();
+    }
+    // Rest to let all messages be updated and synchronization proceed
+    std::thread::sleep(std::time::Duration::from_secs(5));
+}
+
+#[rstest]
+fn migration_policy_smoke_rl() {
+    // env_logger::init();
+    migration_policy_smoke(configs::migration_config_rl());
+}
+
+#[rstest]
+fn migration_policy_smoke_lfu() {
+    // env_logger::init();
+    migration_policy_smoke(configs::migration_config_lfu_node());
+}
+
+#[rstest]
+fn migration_policy_single_node() {
+    // env_logger::init();
+    let shared_db = Database::build_threaded(configs::migration_config_lfu_node()).unwrap();
+    let ds;
+    {
+        let mut db = shared_db.write();
+        ds = db.open_or_create_dataset(b"test").unwrap();
+        db.sync().unwrap();
+    }
+
+    let sync = move || {
+        let mut db = shared_db.write();
+        db.sync().unwrap();
+    };
+
+    ds.insert(b"foobar".to_vec(), &[42; 4096]).unwrap();
+    sync();
+    assert_json_snapshot!(
+        "migration_policy_single_node__before_migration",
+        json!(ds.tree_dump().unwrap())
+    );
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
+    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
+    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
+    ds.upsert(b"foobar".to_vec(), &[43; 1024], 1).unwrap();
+    sync();
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    assert_json_snapshot!(
+        "migration_policy_single_node__after_migration",
+        json!(ds.tree_dump().unwrap())
+    );
+    sync();
+}
+
+#[rstest]
+fn migration_policy_single_object() {
+    // Migrate a single object upwards after it has been used
+    let shared_db = Database::build_threaded(configs::migration_config_lfu_object()).unwrap();
+    let os;
+    {
+        let mut db = shared_db.write();
+        os = db.open_object_store().unwrap();
+        db.sync().unwrap();
+    }
+    let obj = os
+        .open_or_create_object_with_pref(b"foo", StoragePreference::FAST)
+        .unwrap()
+        .0;
+    let mut buf = vec![42; 200 * TO_MEBIBYTE];
+    obj.write_at_with_pref(&buf, 0, StoragePreference::FAST)
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    shared_db.write().sync().unwrap();
+    obj.read_at(&mut buf, 0).unwrap();
+    obj.read_at(&mut buf, 0).unwrap();
+    obj.read_at(&mut buf, 0).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(10));
+    shared_db.write().close_object_store(os);
     let free = shared_db.read().free_space_tier();
     assert!(free[1].free > free[0].free);
 }
+
+#[rstest]
+fn migration_policy_dry_run() {
+    // Plan the promotion of a used object without moving it
+    let mut cfg = configs::migration_config_lfu_object();
+    if let Some(MigrationPolicies::Lfu(config)) = &mut cfg.migration_policy {
+        config.dry_run = true;
+    }
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let os;
+    {
+        let mut db = shared_db.write();
+        os = db.open_object_store().unwrap();
+        db.sync().unwrap();
+    }
+    let obj = os.open_or_create_object(b"foo").unwrap().0;
+    let mut buf = vec![42; 16 * TO_MEBIBYTE];
+    obj.write_at(&buf, 0).unwrap();
+    shared_db.write().sync().unwrap();
+    obj.read_at(&mut buf, 0).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(3));
+
+    let find_foo = || {
+        let plan = shared_db.read().migration_plan().unwrap();
+        let planned = plan
+            .migrations
+            .iter()
+            .find(|migration| match &migration.subject {
+                MigrationSubject::Object { name, .. } => &name[..] == b"foo",
+                MigrationSubject::Node { .. } => false,
+            })
+            .cloned()
+            .unwrap();
+        assert!(plan.bytes_in()[0] >= planned.size);
+        assert!(plan.bytes_out()[1] >= planned.size);
+        planned
+    };
+    let planned = find_foo();
+    assert_eq!((planned.from, planned.to), (1, 0));
+    assert!(planned.size >= buf.len() as u64);
+
+    // Nothing has been moved, so the next round plans the same promotion
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    assert_eq!(find_foo(), planned);
+    shared_db.write().close_object_store(os);
+}
+
+#[rstest]
+fn migration_policy_read_rate() {
+    // Only plan the promotion of objects read at least three times an hour
+    let mut cfg = configs::migration_config_lfu_object();
+    if let Some(MigrationPolicies::Lfu(config)) = &mut cfg.migration_policy {
+        config.dry_run = true;
+        config.policy_config.min_read_rate = Some(ReadRate {
+            reads: 3,
+            per: std::time::Duration::from_secs(60 * 60),
+        });
+    }
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let os;
+    {
+        let mut db = shared_db.write();
+        os = db.open_object_store().unwrap();
+        db.sync().unwrap();
+    }
+    let hot = os.open_or_create_object(b"hot").unwrap().0;
+    let cold = os.open_or_create_object(b"cold").unwrap().0;
+    let mut buf = vec![42; 4 * TO_MEBIBYTE];
+    hot.write_at(&buf, 0).unwrap();
+    cold.write_at(&buf, 0).unwrap();
+    shared_db.write().sync().unwrap();
+    for _ in 0..3 {
+        hot.read_at(&mut buf, 0).unwrap();
+    }
+    cold.read_at(&mut buf, 0).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(3));
+
+    let plan = shared_db.read().migration_plan().unwrap();
+    let promoted: Vec<_> = plan
+        .migrations
+        .iter()
+        .filter(|migration| migration.to < migration.from)
+        .filter_map(|migration| match &migration.subject {
+            MigrationSubject::Object { name, .. } => Some(name.to_vec()),
+            MigrationSubject::Node { .. } => None,
+        })
+        .collect();
+    assert_eq!(promoted, vec![b"hot".to_vec()]);
+    shared_db.write().close_object_store(os);
+}
+
+#[rstest]
+fn migration_policy_outside_of_window() {
+    // Only plan in a window which starts an hour from now
+    let day = 24 * 60 * 60;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut cfg = configs::migration_config_lfu_object();
+    if let Some(MigrationPolicies::Lfu(config)) = &mut cfg.migration_policy {
+        config.dry_run = true;
+        config.active_windows = vec![MigrationWindow {
+            start: std::time::Duration::from_secs((now + 60 * 60) % day),
+            end: std::time::Duration::from_secs((now + 2 * 60 * 60) % day),
+        }];
+    }
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let os;
+    {
+        let mut db = shared_db.write();
+        os = db.open_object_store().unwrap();
+        db.sync().unwrap();
+    }
+    let obj = os.open_or_create_object(b"foo").unwrap().0;
+    let mut buf = vec![42; 16 * TO_MEBIBYTE];
+    obj.write_at(&buf, 0).unwrap();
+    shared_db.write().sync().unwrap();
+    obj.read_at(&mut buf, 0).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(3));
+
+    assert!(shared_db
+        .read()
+        .migration_plan()
+        .unwrap()
+        .migrations
+        .is_empty());
+    shared_db.write().close_object_store(os);
+}
+
+// Records the names of all opened objects and the number of closed datasets.
+struct RecordingPolicy {
+    ctx: MigrationContext,
+    opened: Arc<Mutex<Vec<Vec<u8>>>>,
+    closed_datasets: Arc<Mutex<usize>>,
+}
+
+impl MigrationPolicy for RecordingPolicy {
+    fn update(&mut self) -> MigrationResult<()> {
+        for msg in self.ctx.object_messages() {
+            match msg {
+                DatabaseMsg::ObjectOpen(_, _, name) => self.opened.lock().push(name.to_vec()),
+                DatabaseMsg::DatasetClose(_) => *self.closed_datasets.lock() += 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> MigrationResult<()> {
+        Ok(())
+    }
+
+    fn promote(&mut self, _storage_tier: u8, _tight_space: bool) -> MigrationResult<Block<u64>> {
+        Ok(Block(0))
+    }
+
+    fn demote(&mut self, _storage_tier: u8, _desired: Block<u64>) -> MigrationResult<Block<u64>> {
+        Ok(Block(0))
+    }
+
+    fn db(&self) -> &Arc<RwLock<Database>> {
+        self.ctx.db()
+    }
+
+    fn config(&self) -> MigrationConfig<()> {
+        MigrationConfig {
+            grace_period: std::time::Duration::from_millis(0),
+            update_period: std::time::Duration::from_millis(100),
+            ..MigrationConfig::default()
+        }
+    }
+}
+
+#[rstest]
+fn migration_policy_custom() {
+    let opened = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&opened);
+    let policy = move |ctx: MigrationContext| {
+        Box::new(RecordingPolicy {
+            ctx,
+            opened: recorded,
+            closed_datasets: Default::default(),
+        }) as Box<dyn MigrationPolicy>
+    };
+    // The configured policy is replaced by the custom one.
+    let shared_db = Database::build_threaded_with_migration_policy(
+        configs::migration_config_lfu_object(),
+        policy,
+    )
+    .unwrap();
+    let os = shared_db.write().open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"foo").unwrap().0;
+    obj.write_at(&[42; 4096], 0).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    assert!(opened.lock().iter().any(|name| &name[..] == b"foo"));
+    shared_db.write().close_object_store(os);
+}
+
+#[rstest]
+fn dataset_close_reported_on_drop() {
+    let closed_datasets = Arc::new(Mutex::new(0));
+    let recorded = Arc::clone(&closed_datasets);
+    let policy = move |ctx: MigrationContext| {
+        Box::new(RecordingPolicy {
+            ctx,
+            opened: Default::default(),
+            closed_datasets: recorded,
+        }) as Box<dyn MigrationPolicy>
+    };
+    let shared_db = Database::build_threaded_with_migration_policy(
+        configs::migration_config_lfu_object(),
+        policy,
+    )
+    .unwrap();
+    let ds = shared_db.read().open_or_create_dataset(b"foo").unwrap();
+    ds.insert(b"key".to_vec(), b"value").unwrap();
+    // Dropping the last handle closes the dataset like closing it does.
+    drop(ds);
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    assert_eq!(*closed_datasets.lock(), 1);
+    let ds = shared_db.read().open_dataset(b"foo").unwrap();
+    assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], b"value");
+    shared_db.read().close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn dataset_lifecycle_shared_database() {
+    let db = test_db(1, 64);
+    std::thread::scope(|s| {
+        for id in 0..4u8 {
+            let db = &db;
+            s.spawn(move || {
+                let ds = db.open_or_create_dataset(&[b'd', id]).unwrap();
+                ds.insert(b"foo".to_vec(), &[id; 32]).unwrap();
+                db.close_dataset(ds).unwrap();
+            });
+        }
+    });
+    for id in 0..4u8 {
+        let ds = db.open_dataset(&[b'd', id]).unwrap();
+        assert_eq!(&ds.get(b"foo".to_vec()).unwrap().unwrap()[..], &[id; 32]);
+        db.close_dataset(ds).unwrap();
+    }
+}
+
+#[rstest]
+fn concurrent_allocations() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 128 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    // Evictions make the writers allocate concurrently.
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    std::thread::scope(|s| {
+        for id in 0..8u8 {
+            let db = &db;
+            s.spawn(move || {
+                let ds = db.open_or_create_dataset(&[b'd', id]).unwrap();
+                for idx in 0..1024u32 {
+                    ds.insert(idx.to_be_bytes().to_vec(), &[id; 4096]).unwrap();
+                }
+                db.close_dataset(ds).unwrap();
+            });
+        }
+    });
+    db.sync().unwrap();
+    for id in 0..8u8 {
+        let ds = db.open_dataset(&[b'd', id]).unwrap();
+        for idx in 0..1024u32 {
+            let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(&value[..], &[id; 4096][..]);
+        }
+        db.close_dataset(ds).unwrap();
+    }
+}
+
+#[rstest]
+fn reads_during_restructuring() {
+    let db = test_db(1, 128);
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    let value = |idx: u32, round: u8| {
+        let mut value = vec![round; 1024];
+        value[..4].copy_from_slice(&idx.to_be_bytes());
+        value
+    };
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &value(idx, 0))
+            .unwrap();
+    }
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    for idx in (0..2048u32).step_by(7) {
+                        let found = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+                        assert_eq!(&found[..4], &idx.to_be_bytes());
+                    }
+                }
+            });
+        }
+        // Flushes and splits move the entries read concurrently.
+        for round in 1..4u8 {
+            for idx in 0..16384u32 {
+                ds.insert(idx.to_be_bytes().to_vec(), &value(idx, round))
+                    .unwrap();
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+    for idx in 0..16384u32 {
+        let found = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&found[..], &value(idx, 3)[..]);
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+#[case::clock(CachePolicyType::Clock)]
+#[case::lru(CachePolicyType::Lru)]
+#[case::clock_pro(CachePolicyType::ClockPro)]
+#[case::w_tiny_lfu(CachePolicyType::WTinyLfu)]
+fn cache_policy(#[case] policy: CachePolicyType) {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        cache_policy: policy,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for idx in 0..4096u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    // Scan everything while reusing a few keys in between.
+    for idx in 0..4096u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8; 4096][..]);
+        let value = ds.get((idx % 16).to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[(idx % 16) as u8; 4096][..]);
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn pinned_levels() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for idx in 0..4096u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    ds.pin_in_cache(1).unwrap();
+    assert_eq!(ds.prewarm().unwrap(), 1);
+    ds.pin_in_cache(2).unwrap();
+    assert!(ds.prewarm().unwrap() > 1);
+    // Pinned nodes stay usable while everything else is evicted.
+    for idx in (0..4096u32).rev() {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[idx as u8; 4096][..]);
+    }
+    ds.insert(b"foo".to_vec(), b"bar").unwrap();
+    db.sync().unwrap();
+
+    ds.unpin_from_cache();
+    assert_eq!(ds.prewarm().unwrap(), 1);
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn l2_cache() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: (0..2)
+                .map(|_| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: 64 * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        default_storage_class: 1,
+        l2_cache: Some(L2CacheConfiguration {
+            storage_class: 0,
+            size: 16 * TO_MEBIBYTE,
+        }),
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    // The first pass stages the evicted leaves, the second one reads them
+    // from the fast tier.
+    for _ in 0..2 {
+        for idx in 0..2048u32 {
+            let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(&value[..], &[idx as u8; 4096][..]);
+        }
+    }
+    let stats = db.l2_cache_stats().unwrap();
+    assert!(stats.entries > 0);
+    assert!(stats.hits > 0);
+    assert!(stats.used <= stats.capacity);
+
+    // Staged copies of modified nodes are not read anymore.
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[!idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    for idx in 0..2048u32 {
+        let value = ds.get(idx.to_be_bytes().to_vec()).unwrap().unwrap();
+        assert_eq!(&value[..], &[!idx as u8; 4096][..]);
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn range_readahead() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for idx in 0..4096u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    // Pending messages are applied to the prefetched leaves.
+    for idx in (0..4096u32).step_by(3) {
+        ds.delete(idx.to_be_bytes().to_vec()).unwrap();
+    }
+
+    let keys = ds
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .map(|entry| {
+            let (key, value) = entry.unwrap();
+            let idx = u32::from_be_bytes(key[..].try_into().unwrap());
+            assert_eq!(&value[..], &[idx as u8; 4096][..]);
+            idx
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        (0..4096).filter(|idx| idx % 3 != 0).collect::<Vec<_>>()
+    );
+
+    // Leaves beyond the end of the range are not needed.
+    let start = 1000u32.to_be_bytes();
+    let end = 1100u32.to_be_bytes();
+    let keys = ds
+        .range(&start[..]..&end[..])
+        .unwrap()
+        .map(|entry| u32::from_be_bytes(entry.unwrap().0[..].try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        (1000..1100).filter(|idx| idx % 3 != 0).collect::<Vec<_>>()
+    );
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn async_dataset() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    let pool = ThreadPool::builder().pool_size(2).create().unwrap();
+    let async_ds = AsyncDataset::new(ds.clone(), Arc::new(pool));
+
+    block_on(async {
+        for idx in 0..2048u32 {
+            async_ds
+                .insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+                .await
+                .unwrap();
+        }
+    });
+    // Most nodes have to be read again.
+    db.sync().unwrap();
+    block_on(async {
+        for idx in (0..2048u32).step_by(7) {
+            let value = async_ds.get(&idx.to_be_bytes()[..]).await.unwrap();
+            assert_eq!(&value.unwrap()[..], &[idx as u8; 4096][..]);
+        }
+        assert!(async_ds
+            .get(&4096u32.to_be_bytes()[..])
+            .await
+            .unwrap()
+            .is_none());
+
+        async_ds.delete(5u32.to_be_bytes().to_vec()).await.unwrap();
+        assert!(async_ds
+            .get(&5u32.to_be_bytes()[..])
+            .await
+            .unwrap()
+            .is_none());
+
+        let keys = async_ds
+            .range::<_, &[u8]>(..)
+            .unwrap()
+            .map(|entry| u32::from_be_bytes(entry.unwrap().0[..].try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(keys, (0..2048).filter(|&idx| idx != 5).collect::<Vec<_>>());
+    });
+    assert_eq!(
+        &ds.get(&7u32.to_be_bytes()[..]).unwrap().unwrap()[..],
+        &[7; 4096][..]
+    );
+    drop(async_ds);
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn parallel_write_back() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        compression: CompressionConfiguration::Lz4(Lz4 { level: 0 }),
+        write_back_threads: 4,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for round in 0..3u8 {
+        for idx in 0..2048u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8 ^ round; 4096])
+                .unwrap();
+        }
+        db.sync().unwrap();
+        for idx in (0..2048u32).step_by(5) {
+            assert_eq!(
+                &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+                &[idx as u8 ^ round; 4096][..]
+            );
+        }
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn background_flush() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 128 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        flush_threads: 4,
+        ..Default::default()
+    };
+    cfg.cache_size = 16 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    // Writers on disjoint key ranges fill disjoint subtrees.
+    std::thread::scope(|s| {
+        for writer in 0..4u32 {
+            let ds = &ds;
+            s.spawn(move || {
+                for idx in writer * 4096..(writer + 1) * 4096 {
+                    ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+                        .unwrap();
+                }
+            });
+        }
+    });
+    db.sync().unwrap();
+    for idx in (0..16384u32).step_by(3) {
+        assert_eq!(
+            &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &[idx as u8; 4096][..]
+        );
+    }
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn dataset_flush_size() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 128 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        flush_size: FlushSize::Fixed(64 * 1024),
+        dataset_flush_size: std::collections::HashMap::from([(
+            "adaptive".to_string(),
+            FlushSize::Adaptive {
+                min: 128 * 1024,
+                max: 2 * TO_MEBIBYTE,
+            },
+        )]),
+        ..Default::default()
+    };
+    cfg.cache_size = 16 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    for name in [&b"fixed"[..], b"adaptive"] {
+        let ds = db.open_or_create_dataset(name).unwrap();
+        for idx in 0..8192u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+                .unwrap();
+        }
+        db.sync().unwrap();
+        for idx in (0..8192u32).step_by(3) {
+            assert_eq!(
+                &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+                &[idx as u8; 4096][..]
+            );
+        }
+        db.close_dataset(ds).unwrap();
+    }
+}
+
+#[rstest]
+fn zero_copy_get() {
+    let mut cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: 64 * TO_MEBIBYTE,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        ..Default::default()
+    };
+    cfg.cache_size = 4 * TO_MEBIBYTE;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[idx as u8; 4096])
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    let key = 1000u32.to_be_bytes();
+    let first = ds.get(&key[..]).unwrap().unwrap();
+    let second = ds.get(&key[..]).unwrap().unwrap();
+    assert_eq!(first.as_ptr(), second.as_ptr());
+
+    // The node buffer outlives the eviction of the node.
+    for idx in (0..2048u32).step_by(2) {
+        ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap();
+    }
+    assert_eq!(&first[..], &[1000u32 as u8; 4096][..]);
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn checkpoint_rollback(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    {
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"test").unwrap();
+        ds.insert(b"foo".to_vec(), b"before").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.checkpoint(b"good").unwrap();
+        assert!(db.checkpoint(b"good").is_err());
+
+        let ds = db.open_dataset(b"test").unwrap();
+        ds.insert(b"foo".to_vec(), b"after").unwrap();
+        ds.insert(b"bar".to_vec(), b"after").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.checkpoint(b"later").unwrap();
+        let names = db
+            .list_checkpoints()
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![b"good".to_vec(), b"later".to_vec()]);
+    }
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    {
+        let db = Database::open_at_checkpoint(cfg.clone(), b"good").unwrap();
+        let ds = db.open_dataset(b"test").unwrap();
+        assert_eq!(&ds.get(b"foo".to_vec()).unwrap().unwrap()[..], b"before");
+        assert!(ds.get(b"bar".to_vec()).unwrap().is_none());
+        let checkpoints = db.list_checkpoints().unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(&checkpoints[0].name[..], b"good");
+    }
+
+    // The rollback has been persisted.
+    let db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"test").unwrap();
+    assert_eq!(&ds.get(b"foo".to_vec()).unwrap().unwrap()[..], b"before");
+}
+
+#[test]
+fn checkpoint_deletion() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"data").unwrap();
+    for value in 1..=3u8 {
+        for idx in 0..64u32 {
+            ds.insert(idx.to_be_bytes().to_vec(), &[value; 4096])
+                .unwrap();
+        }
+        if value < 3 {
+            db.checkpoint(format!("{value}").as_bytes()).unwrap();
+        }
+    }
+    let retained = db.audit_dead_lists(false).unwrap();
+    assert!(retained.entries > 0);
+    assert_eq!(retained.orphaned, 0);
+
+    // Only the blocks of the first checkpoint which are not part of the
+    // second one are freed.
+    db.delete_checkpoint(b"1").unwrap();
+    assert!(matches!(
+        db.delete_checkpoint(b"1"),
+        Err(Error::DoesNotExist)
+    ));
+    let names = db
+        .list_checkpoints()
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name.to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![b"2".to_vec()]);
+    let report = db.audit_dead_lists(false).unwrap();
+    assert!(report.size < retained.size);
+    assert!(report.entries > 0);
+    assert_eq!(report.orphaned, 0);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+
+    db.delete_checkpoint(b"2").unwrap();
+    assert!(db.list_checkpoints().unwrap().is_empty());
+    assert_eq!(db.audit_dead_lists(false).unwrap().entries, 0);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+    assert_eq!(
+        &ds.get(0u32.to_be_bytes().to_vec()).unwrap().unwrap()[..],
+        &[3; 4096]
+    );
+}
+
+#[rstest]
+fn sync_datasets_together(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    {
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        let index = db.open_or_create_dataset(b"index").unwrap();
+        let data = db.open_or_create_dataset(b"data").unwrap();
+        data.insert(b"object".to_vec(), &[42; 512]).unwrap();
+        index.insert(b"object".to_vec(), b"data").unwrap();
+        db.sync_datasets(&[&index, &data, &index.clone()]).unwrap();
+    }
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let index = db.open_dataset(b"index").unwrap();
+    let data = db.open_dataset(b"data").unwrap();
+    assert!(index.get(b"object".to_vec()).unwrap().is_some());
+    assert_eq!(
+        &data.get(b"object".to_vec()).unwrap().unwrap()[..],
+        &[42; 512]
+    );
+}
+
+#[rstest]
+fn dataset_closed_on_drop() {
+    let db = test_db(1, 512);
+    {
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        let handle = ds.clone();
+        ds.insert(b"key".to_vec(), b"value").unwrap();
+        drop(ds);
+        // A remaining handle keeps the dataset open.
+        assert!(matches!(db.open_dataset(b"foo"), Err(Error::InUse)));
+        drop(handle);
+    }
+    let ds = db.open_dataset(b"foo").unwrap();
+    assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], b"value");
+    db.close_dataset(ds).unwrap();
+}
+
+#[rstest]
+fn database_sync_on_drop(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    {
+        let mut cfg = file_backed_config.clone();
+        cfg.sync_on_drop = true;
+        let db = Database::build(cfg).unwrap();
+        let ds = db.open_or_create_dataset(b"foo").unwrap();
+        ds.insert(b"key".to_vec(), b"value").unwrap();
+    }
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::OpenIfExists;
+    let db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"foo").unwrap();
+    assert_eq!(&ds.get(b"key".to_vec()).unwrap().unwrap()[..], b"value");
+}
+
+#[rstest]
+fn snapshot_listing() {
+    let mut db = test_db(1, 512);
+    let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0u32..256 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"first").unwrap();
+
+    for idx in 0u32..256 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[2; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"second").unwrap();
+
+    let snapshots = db.list_snapshots(&ds).unwrap();
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(&snapshots[0].name[..], b"first");
+    assert_eq!(&snapshots[1].name[..], b"second");
+    assert!(snapshots[0].generation < snapshots[1].generation);
+    assert!(snapshots.iter().all(|info| info.created.is_some()));
+    assert!(snapshots[0].unique.as_u64() > 0);
+    assert!(snapshots[0].referenced >= snapshots[0].unique);
+    assert_eq!(snapshots[1].referenced.as_u64(), 0);
+
+    let first = db.open_snapshot(&mut ds, b"first").unwrap();
+    assert_eq!(
+        &first.get(0u32.to_be_bytes()).unwrap().unwrap()[..],
+        &[1; 4096]
+    );
+}
+
+#[rstest]
+fn snapshot_rollback() {
+    let mut db = test_db(1, 512);
+    let mut ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0u32..128 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 1024]).unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"first").unwrap();
+
+    for idx in 64u32..192 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[2; 1024]).unwrap();
+    }
+    ds.delete(0u32.to_be_bytes().to_vec()).unwrap();
+    db.sync().unwrap();
+    db.create_snapshot(&mut ds, b"second").unwrap();
+
+    db.rollback_snapshot(&mut ds, b"first").unwrap();
+    let entries: Vec<_> = ds
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(entries.len(), 128);
+    for (idx, (key, value)) in entries.iter().enumerate() {
+        assert_eq!(&key[..], &(idx as u32).to_be_bytes());
+        assert_eq!(&value[..], &[1; 1024]);
+    }
+
+    // Later snapshots are kept.
+    let second = db.open_snapshot(&mut ds, b"second").unwrap();
+    assert!(second.get(0u32.to_be_bytes()).unwrap().is_none());
+    assert_eq!(
+        &second.get(191u32.to_be_bytes()).unwrap().unwrap()[..],
+        &[2; 1024]
+    );
+    db.close_snapshot(&mut ds, second);
+    db.delete_snapshot(&mut ds, b"second").unwrap();
+
+    assert!(matches!(
+        db.rollback_snapshot(&mut ds, b"second"),
+        Err(Error::DoesNotExist)
+    ));
+}
+
+#[rstest]
+fn ingest_sorted_dumps() {
+    let db = test_db(1, 512);
+    let ds = db.open_or_create_dataset(b"binary").unwrap();
+    let mut dump = Vec::new();
+    for idx in 0u32..256 {
+        write_binary_entry(&mut dump, &idx.to_be_bytes(), &[idx as u8; 512]).unwrap();
+    }
+    let report = ds
+        .ingest(BinaryDumpReader::new(&dump[..]), StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(report.entries, 256);
+    assert_eq!(report.bytes, 256 * (4 + 512));
+    for (idx, entry) in ds.range::<_, &[u8]>(..).unwrap().enumerate() {
+        let (key, value) = entry.unwrap();
+        assert_eq!(&key[..], &(idx as u32).to_be_bytes());
+        assert_eq!(&value[..], &[idx as u8; 512]);
+    }
+
+    // Entries before an unsorted or truncated record remain inserted.
+    let ds = db.open_or_create_dataset(b"unsorted").unwrap();
+    let mut dump = Vec::new();
+    for key in [&b"a"[..], b"c", b"b"] {
+        write_binary_entry(&mut dump, key, b"value").unwrap();
+    }
+    assert!(matches!(
+        ds.ingest(BinaryDumpReader::new(&dump[..]), StoragePreference::NONE),
+        Err(Error::UnsortedKeys)
+    ));
+    assert!(ds.get(&b"c"[..]).unwrap().is_some());
+    assert!(ds.get(&b"b"[..]).unwrap().is_none());
+    assert!(matches!(
+        ds.ingest(
+            BinaryDumpReader::new(&dump[..dump.len() - 1]),
+            StoragePreference::NONE
+        ),
+        Err(Error::InvalidDump(_))
+    ));
+
+    // Output of `ldb dump --hex`.
+    let ds = db.open_or_create_dataset(b"ldb").unwrap();
+    let dump = "0x6161 ==> 0x01\n0x6162 ==> 0x\n\nKeys in range: 2\n";
+    ds.ingest(HexDumpReader::new(dump.as_bytes()), StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(&ds.get(&b"aa"[..]).unwrap().unwrap()[..], &[1]);
+    assert!(ds.get(&b"ab"[..]).unwrap().unwrap().is_empty());
+
+    // Output of `sst_dump --command=scan --output_hex`, of which only the
+    // newest version of each key is ingested.
+    let ds = db.open_or_create_dataset(b"sst").unwrap();
+    let dump = "Process /tmp/000012.sst\n\
+                Sst file format: block-based\n\
+                '61' seq:9, type:1 => 02\n\
+                '61' seq:3, type:1 => 01\n\
+                '62' seq:8, type:0 => \n\
+                '62' seq:2, type:1 => 01\n\
+                '63' seq:4, type:1 => 0A0b\n";
+    let report = ds
+        .ingest(HexDumpReader::new(dump.as_bytes()), StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(report.entries, 2);
+    assert_eq!(&ds.get(&b"a"[..]).unwrap().unwrap()[..], &[2]);
+    assert!(ds.get(&b"b"[..]).unwrap().is_none());
+    assert_eq!(&ds.get(&b"c"[..]).unwrap().unwrap()[..], &[10, 11]);
+
+    assert!(matches!(
+        ds.ingest(
+            HexDumpReader::new(&b"'64' seq:1, type:2 => 01\n"[..]),
+            StoragePreference::NONE
+        ),
+        Err(Error::InvalidDump(_))
+    ));
+}
+
+#[rstest]
+fn configuration_files() {
+    let dir = env::temp_dir();
+    let toml = dir.join(format!("config_{}.toml", std::process::id()));
+    let yaml = dir.join(format!("config_{}.yaml", std::process::id()));
+    std::fs::write(
+        &toml,
+        "cache_size = 1048576\n\
+         [[storage.tiers]]\n\
+         top_level_vdevs = [{ mem = 67108864 }]\n\
+         preferred_access_type = \"Unknown\"\n\
+         name = \"fast\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &yaml,
+        "cache_size: 2097152\n\
+         storage: { tiers: [{ top_level_vdevs: [{ mem: 67108864 }], \
+                              preferred_access_type: Unknown, name: slow }] }\n",
+    )
+    .unwrap();
+
+    let first = DatabaseConfiguration::load(&toml).unwrap();
+    assert_eq!(first.cache_size, 1 << 20);
+    assert_eq!(first.storage.tiers[0].name.as_deref(), Some("fast"));
+    let second = DatabaseConfiguration::load(&yaml).unwrap();
+    let changes: Vec<_> = first
+        .diff(&second)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            "cache_size: 1048576 -> 2097152",
+            "storage.tiers[0].name: \"fast\" -> \"slow\"",
+        ]
+    );
+    assert!(first.diff(&first).is_empty());
+
+    for (contents, expected) in [
+        ("alloc_strategy = [[0], [9]]\n", "alloc_strategy[1][0]"),
+        ("cache_size = \"large\"\n", "cache_size"),
+        ("sync_interval_ms = 0\n", "sync_interval_ms"),
+    ] {
+        std::fs::write(&toml, contents).unwrap();
+        match DatabaseConfiguration::load(&toml) {
+            Err(Error::InvalidConfiguration { field, .. }) => assert_eq!(field, expected),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+    std::fs::write(&toml, "cache_sizes = 1\n").unwrap();
+    let err = DatabaseConfiguration::load(&toml).err().unwrap();
+    assert_eq!(err.code(), ErrorCode::Configuration);
+    assert!(err.to_string().contains("cache_sizes"));
+
+    std::fs::remove_file(&toml).unwrap();
+    std::fs::remove_file(&yaml).unwrap();
+    assert!(matches!(
+        DatabaseConfiguration::load(&toml),
+        Err(Error::IoError { .. })
+    ));
+}
+
+#[rstest]
+fn runtime_configuration_update() {
+    use betree_storage_stack::{
+        cache::Cache, database::RuntimeConfiguration, storage_pool::QosConfiguration,
+    };
+
+    fn assert_invalid(result: Result<(), Error>, expected: &str) {
+        match result {
+            Err(Error::InvalidConfiguration { field, .. }) => assert_eq!(field, expected),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    let cfg = DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: (0..2)
+                .map(|_| TierConfiguration {
+                    top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
+                        mem: 64 * TO_MEBIBYTE,
+                    })],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig::default())),
+        ..Default::default()
+    };
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let mut db = shared_db.write();
+    let ds = db.open_or_create_dataset(b"foo").unwrap();
+    for idx in 0u32..4096 {
+        ds.insert(idx.to_be_bytes().to_vec(), &[1; 1024]).unwrap();
+    }
+    db.sync().unwrap();
+    let before = db.root_tree().dmu().cache().read().size();
+    assert!(before > TO_MEBIBYTE);
+
+    db.update_config(RuntimeConfiguration {
+        cache_size: Some(TO_MEBIBYTE),
+        sync_interval_ms: Some(None),
+        migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig {
+            dry_run: true,
+            ..Default::default()
+        })),
+        qos: [(
+            1,
+            QosConfiguration {
+                iops: Some(1000),
+                bandwidth: None,
+            },
+        )]
+        .into_iter()
+        .collect(),
+    })
+    .unwrap();
+    assert!(db.root_tree().dmu().cache().read().size() < before);
+    for idx in (0u32..4096).step_by(256) {
+        assert_eq!(&ds.get(idx.to_be_bytes()).unwrap().unwrap()[..], &[1; 1024]);
+    }
+
+    assert_invalid(
+        db.update_config(RuntimeConfiguration {
+            cache_size: Some(0),
+            ..Default::default()
+        }),
+        "cache_size",
+    );
+    assert_invalid(
+        db.update_config(RuntimeConfiguration {
+            qos: [(5, QosConfiguration::default())].into_iter().collect(),
+            ..Default::default()
+        }),
+        "storage.tiers[5].qos",
+    );
+    assert_invalid(
+        db.update_config(RuntimeConfiguration {
+            migration_policy: Some(MigrationPolicies::ReinforcementLearning(
+                MigrationConfig::default(),
+            )),
+            ..Default::default()
+        }),
+        "migration_policy",
+    );
+
+    // Neither a periodic sync nor a migration policy is running.
+    let mut db = test_db(1, 64);
+    assert_invalid(
+        db.update_config(RuntimeConfiguration {
+            sync_interval_ms: Some(Some(100)),
+            ..Default::default()
+        }),
+        "sync_interval_ms",
+    );
+    assert_invalid(
+        db.update_config(RuntimeConfiguration {
+            migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig::default())),
+            ..Default::default()
+        }),
+        "migration_policy",
+    );
+}
+
+#[rstest]
+fn typed_dataset() {
+    use betree_storage_stack::database::TypedDataset;
+
+    let db = test_db(1, 64);
+    let ds: TypedDataset<(String, i64), Vec<u32>> =
+        TypedDataset::new(db.open_or_create_dataset(b"typed").unwrap());
+    for name in ["b", "a", "ab"] {
+        for idx in [300, -2, 0, 7] {
+            ds.insert(&(name.to_string(), idx), &vec![idx as u32; 3])
+                .unwrap();
+        }
+    }
+    assert_eq!(
+        ds.get(&("ab".to_string(), -2)).unwrap(),
+        Some(vec![-2i64 as u32; 3])
+    );
+    assert_eq!(ds.get(&("c".to_string(), 0)).unwrap(), None);
+
+    // Entries are sorted by name and then numerically.
+    let keys: Vec<_> = ds
+        .range(("a".to_string(), 0)..("b".to_string(), 0))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<_> = [
+        ("a", 0),
+        ("a", 7),
+        ("a", 300),
+        ("ab", -2),
+        ("ab", 0),
+        ("ab", 7),
+        ("ab", 300),
+        ("b", -2),
+    ]
+    .iter()
+    .map(|(name, idx)| (name.to_string(), *idx))
+    .collect();
+    assert_eq!(keys, expected);
+
+    ds.range_delete(("a".to_string(), i64::MIN)..=("a".to_string(), i64::MAX))
+        .unwrap();
+    ds.delete(&("b".to_string(), 7)).unwrap();
+    assert_eq!(ds.range(..).unwrap().count(), 7);
+
+    // Untyped entries cannot be decoded.
+    ds.dataset().insert(&b"x"[..], b"value").unwrap();
+    let entry = ds.range(..).unwrap().last().unwrap();
+    assert_eq!(entry.unwrap_err().code(), ErrorCode::InvalidArgument);
+}
+
+#[rstest]
+fn dataset_with_stateful_message_action() {
+    use betree_storage_stack::{
+        cow_bytes::{CowBytes, SlicedCowBytes},
+        tree::MessageAction,
+    };
+
+    // Adds up increments, but never exceeds the configured limit.
+    #[derive(Debug, Clone)]
+    struct BoundedCounter {
+        limit: u64,
+    }
+
+    fn decode(b: &[u8]) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(b);
+        u64::from_be_bytes(buf)
+    }
+
+    fn encode(v: u64) -> SlicedCowBytes {
+        CowBytes::from(&v.to_be_bytes()[..]).into()
+    }
+
+    impl MessageAction for BoundedCounter {
+        fn apply(&self, _key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
+            let current = data.as_ref().map_or(0, |d| decode(d));
+            *data = Some(encode((current + decode(msg)).min(self.limit)));
+        }
+
+        fn merge(
+            &self,
+            _key: &[u8],
+            upper_msg: SlicedCowBytes,
+            lower_msg: SlicedCowBytes,
+        ) -> SlicedCowBytes {
+            encode((decode(&upper_msg) + decode(&lower_msg)).min(self.limit))
+        }
+    }
+
+    let db = test_db(1, 512);
+    let ds = db
+        .open_or_create_custom_dataset_with(
+            b"counters",
+            BoundedCounter { limit: 10 },
+            StoragePreference::NONE,
+        )
+        .unwrap();
+    for _ in 0..5 {
+        ds.insert_msg(b"counter".to_vec(), encode(4)).unwrap();
+    }
+    assert_eq!(decode(&ds.get(b"counter".to_vec()).unwrap().unwrap()), 10);
+    db.close_dataset(ds).unwrap();
+
+    let ds = db
+        .open_custom_dataset_with(
+            b"counters",
+            BoundedCounter { limit: 20 },
+            StoragePreference::NONE,
+        )
+        .unwrap();
+    ds.insert_msg(b"counter".to_vec(), encode(4)).unwrap();
+    assert_eq!(decode(&ds.get(b"counter".to_vec()).unwrap().unwrap()), 14);
+}
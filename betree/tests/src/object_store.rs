@@ -1,4 +1,10 @@
-use betree_storage_stack::{Database, StoragePreference};
+use betree_storage_stack::{
+    cow_bytes::CowBytes,
+    database::Error,
+    object::{self, Extent},
+    Database, StoragePreference,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use super::{configs, test_db, TO_MEBIBYTE};
 
@@ -136,3 +142,354 @@ fn object_store_reinit_from_id() {
         .internal_open_object_store_with_id(osl.next().unwrap().unwrap())
         .unwrap();
 }
+
+#[test]
+fn object_store_attributes() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"hewo").unwrap();
+    obj.set_attr("content-type", b"text/plain").unwrap();
+    obj.set_attr("tag", b"uwu").unwrap();
+    obj.set_metadata(&[0xff], b"raw").unwrap();
+    assert_eq!(&obj.get_attr("tag").unwrap().unwrap()[..], b"uwu");
+    assert!(obj
+        .set_attr("big", &vec![0; object::MAX_ATTRIBUTE_SIZE + 1])
+        .is_err());
+    obj.remove_attr("tag").unwrap();
+    db.close_object_store(os);
+
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_object(b"hewo").unwrap().unwrap();
+    let attrs = obj.list_attrs().unwrap();
+    assert_eq!(attrs.len(), 1);
+    assert_eq!(attrs[0].0, "content-type");
+    assert_eq!(&attrs[0].1[..], b"text/plain");
+    assert!(obj.get_attr("tag").unwrap().is_none());
+}
+
+fn names(
+    iter: impl Iterator<Item = Result<(CowBytes, object::ObjectInfo), Error>>,
+) -> Vec<Vec<u8>> {
+    iter.map(|res| {
+        let (name, info) = res.unwrap();
+        assert_eq!(info.size, name.len() as u64);
+        name.to_vec()
+    })
+    .collect()
+}
+
+#[test]
+fn object_store_prefix_listing() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    for name in [&b"a/1"[..], b"a/2", b"a/sub/3", b"ab", b"b/1"] {
+        let obj = os.open_or_create_object(name).unwrap();
+        obj.write_at(name, 0).unwrap();
+        obj.set_attr("tag", b"uwu").unwrap();
+    }
+    assert_eq!(
+        names(os.list_objects_with_prefix(b"a/").unwrap()),
+        [&b"a/1"[..], b"a/2", b"a/sub/3"]
+    );
+    assert_eq!(
+        names(os.list_objects_matching(b"a/?").unwrap()),
+        [&b"a/1"[..], b"a/2"]
+    );
+    assert_eq!(
+        names(os.list_objects_matching(b"*/1").unwrap()),
+        [&b"a/1"[..], b"b/1"]
+    );
+    assert!(os.list_objects_with_prefix(b"c").unwrap().next().is_none());
+
+    // Listings report the generation of the last write.
+    db.sync().unwrap();
+    let obj = os.open_or_create_object(b"b/1").unwrap();
+    obj.write_at(b"b/1", 0).unwrap();
+    let generations: Vec<_> = os
+        .list_objects_matching(b"*/1")
+        .unwrap()
+        .map(|res| res.unwrap().1.generation)
+        .collect();
+    assert!(generations[0] < generations[1]);
+}
+
+#[test]
+fn object_store_sparse_read() {
+    const CHUNK: u64 = 128 * 1024;
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"sparse").unwrap();
+    obj.write_at(b"hewo", 0).unwrap();
+    obj.write_at(b"uwu", 3 * CHUNK).unwrap();
+    assert_eq!(
+        obj.read_extents(0..u64::MAX).unwrap(),
+        [
+            Extent::Data(0..4),
+            Extent::Hole(4..3 * CHUNK),
+            Extent::Data(3 * CHUNK..3 * CHUNK + 3),
+        ]
+    );
+    assert_eq!(
+        obj.read_extents(2..CHUNK).unwrap(),
+        [Extent::Data(2..4), Extent::Hole(4..CHUNK)]
+    );
+
+    let mut buf = vec![42; 8];
+    let extents = obj.read_at_sparse(&mut buf, 3 * CHUNK - 4).unwrap();
+    assert_eq!(
+        extents,
+        [
+            Extent::Hole(3 * CHUNK - 4..3 * CHUNK),
+            Extent::Data(3 * CHUNK..3 * CHUNK + 3),
+        ]
+    );
+    assert_eq!(buf, [42, 42, 42, 42, b'u', b'w', b'u', 42]);
+}
+
+#[test]
+fn object_store_rename() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"old").unwrap();
+    obj.write_at(b"hewo", 0).unwrap();
+    obj.set_attr("tag", b"uwu").unwrap();
+    let other = os.open_or_create_object(b"other").unwrap();
+    other.write_at(b"snek", 0).unwrap();
+    other.set_attr("other-tag", b"owo").unwrap();
+
+    assert!(matches!(
+        os.rename(b"old", b"other", false),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        os.rename(b"missing", b"new", false),
+        Err(Error::DoesNotExist)
+    ));
+
+    os.rename(b"old", b"new", false).unwrap();
+    assert!(os.open_object(b"old").unwrap().is_none());
+    os.rename(b"new", b"other", true).unwrap();
+    db.close_object_store(os);
+
+    let os = db.open_object_store().unwrap();
+    assert!(os.open_object(b"new").unwrap().is_none());
+    let obj = os.open_object(b"other").unwrap().unwrap();
+    let mut buf = [0; 4];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"hewo");
+    let attrs = obj.list_attrs().unwrap();
+    assert_eq!(attrs.len(), 1);
+    assert_eq!(attrs[0].0, "tag");
+}
+
+#[test]
+fn object_store_truncate_and_punch_hole() {
+    const CHUNK: u64 = 128 * 1024;
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"log").unwrap();
+    obj.write_at(&vec![1; 4 * CHUNK as usize], 0).unwrap();
+
+    obj.punch_hole(CHUNK / 2, 2 * CHUNK).unwrap();
+    assert_eq!(obj.info().unwrap().unwrap().size, 4 * CHUNK);
+    assert_eq!(
+        obj.read_extents(0..4 * CHUNK).unwrap(),
+        [
+            Extent::Data(0..CHUNK),
+            Extent::Hole(CHUNK..2 * CHUNK),
+            Extent::Data(2 * CHUNK..4 * CHUNK),
+        ]
+    );
+    let mut buf = vec![0; 4 * CHUNK as usize];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert!(buf[..CHUNK as usize / 2].iter().all(|b| *b == 1));
+    assert!(buf[CHUNK as usize / 2..5 * CHUNK as usize / 2]
+        .iter()
+        .all(|b| *b == 0));
+    assert!(buf[5 * CHUNK as usize / 2..].iter().all(|b| *b == 1));
+
+    obj.truncate(3 * CHUNK + 10).unwrap();
+    assert_eq!(obj.info().unwrap().unwrap().size, 3 * CHUNK + 10);
+    obj.truncate(4 * CHUNK).unwrap();
+    let mut buf = vec![42; 20];
+    obj.read_at(&mut buf, 3 * CHUNK).unwrap();
+    assert_eq!(&buf[..10], &[1; 10]);
+    assert_eq!(&buf[10..], &[0; 10]);
+
+    obj.truncate(0).unwrap();
+    assert!(obj.read_extents(0..u64::MAX).unwrap().is_empty());
+    assert!(obj.read_all_chunks().unwrap().next().is_none());
+}
+
+#[test]
+fn object_store_buffered_cursor() {
+    const CHUNK: usize = 128 * 1024;
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"stream").unwrap();
+    let data = (0..2 * CHUNK + 100)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+
+    let mut cursor = obj.cursor().buffered();
+    for piece in data.chunks(1000) {
+        cursor.write_all(piece).unwrap();
+    }
+    cursor.seek(SeekFrom::Start(10)).unwrap();
+    cursor.write_all(&[42; 5]).unwrap();
+    drop(cursor);
+    assert_eq!(obj.info().unwrap().unwrap().size, data.len() as u64);
+
+    let mut cursor = obj.cursor().buffered();
+    let mut read = Vec::new();
+    cursor.read_to_end(&mut read).unwrap();
+    assert_eq!(&read[..10], &data[..10]);
+    assert_eq!(&read[10..15], &[42; 5]);
+    assert_eq!(&read[15..], &data[15..]);
+
+    assert_eq!(cursor.seek(SeekFrom::End(-100)).unwrap(), 2 * CHUNK as u64);
+    let mut tail = [0; 100];
+    cursor.read_exact(&mut tail).unwrap();
+    assert_eq!(&tail[..], &data[2 * CHUNK..]);
+
+    // Reads past the end of the object return nothing.
+    for target in [SeekFrom::End(50), SeekFrom::Start(10 * CHUNK as u64)] {
+        cursor.seek(target).unwrap();
+        assert_eq!(cursor.read(&mut tail).unwrap(), 0);
+    }
+}
+
+#[test]
+fn object_store_multipart_upload() {
+    const CHUNK: usize = 128 * 1024;
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"upload").unwrap();
+    let data = (0..4 * CHUNK + 10)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+
+    let upload = obj.multipart_upload();
+    assert!(upload.write_part(1, &data[..10]).is_err());
+    std::thread::scope(|s| {
+        for (idx, part) in data.chunks(CHUNK).enumerate() {
+            let upload = &upload;
+            s.spawn(move || upload.write_part((idx * CHUNK) as u64, part).unwrap());
+        }
+    });
+    assert_eq!(obj.info().unwrap().unwrap().size, 0);
+    upload.complete().unwrap();
+    assert_eq!(obj.info().unwrap().unwrap().size, data.len() as u64);
+
+    let mut buf = vec![0; data.len()];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn object_store_namespaces() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    assert!(os.namespace(b"").is_err());
+    assert!(os.namespace(b"a\xffb").is_err());
+
+    let alpha = os.namespace(b"alpha").unwrap();
+    let beta = os.namespace(b"beta").unwrap();
+    for (ns, name) in [
+        (&alpha, &b"x"[..]),
+        (&alpha, &b"yy"[..]),
+        (&beta, &b"x"[..]),
+    ] {
+        let obj = ns.create_object(name).unwrap();
+        obj.write_at(name, 0).unwrap();
+    }
+    let plain = os.create_object(b"x").unwrap();
+    plain.write_at(b"plain", 0).unwrap();
+
+    assert_eq!(
+        names(alpha.list_objects().unwrap()),
+        vec![b"x".to_vec(), b"yy".to_vec()]
+    );
+    assert_eq!(names(beta.list_objects().unwrap()), vec![b"x".to_vec()]);
+    assert_eq!(
+        os.list_namespaces().unwrap(),
+        vec![CowBytes::from(&b"alpha"[..]), CowBytes::from(&b"beta"[..])]
+    );
+    assert_eq!(
+        beta.open_object(b"x")
+            .unwrap()
+            .unwrap()
+            .info()
+            .unwrap()
+            .unwrap()
+            .size,
+        1
+    );
+    assert_eq!(
+        os.open_object(b"x")
+            .unwrap()
+            .unwrap()
+            .info()
+            .unwrap()
+            .unwrap()
+            .size,
+        5
+    );
+    assert!(beta.open_object(b"yy").unwrap().is_none());
+
+    // Namespaced objects are only reachable through their namespace.
+    assert!(os.open_object(b"\xffalpha\xffx").is_err());
+    assert!(os.create_object(b"\xffalpha\xffz").is_err());
+    assert!(os.rename(b"x", b"\xffbeta\xffy", false).is_err());
+    let plain_names: Vec<_> = os
+        .list_objects_with_prefix(&[])
+        .unwrap()
+        .map(|res| res.unwrap().0.to_vec())
+        .collect();
+    assert_eq!(plain_names, vec![b"x".to_vec()]);
+    alpha.rename(b"x", b"z", false).unwrap();
+    assert_eq!(
+        names(alpha.list_objects().unwrap()),
+        vec![b"yy".to_vec(), b"z".to_vec()]
+    );
+
+    alpha.delete().unwrap();
+    assert_eq!(
+        os.list_namespaces().unwrap(),
+        vec![CowBytes::from(&b"beta"[..])]
+    );
+    assert!(os.open_object(b"x").unwrap().is_some());
+}
+
+#[test]
+fn object_store_storage_preference() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let mut obj = os.open_or_create_object(b"tiered").unwrap();
+    obj.set_storage_preference(StoragePreference::FAST).unwrap();
+    obj.write_at(&[1; 1000], 0).unwrap();
+
+    let mut obj = os.open_object(b"tiered").unwrap().unwrap();
+    assert_eq!(obj.storage_preference(), StoragePreference::FAST);
+    assert_eq!(
+        os.open_or_create_object(b"tiered")
+            .unwrap()
+            .storage_preference(),
+        StoragePreference::FAST
+    );
+
+    obj.migrate(StoragePreference::FASTEST).unwrap();
+    db.sync().unwrap();
+    let obj = os.open_object(b"tiered").unwrap().unwrap();
+    assert_eq!(obj.storage_preference(), StoragePreference::FASTEST);
+    assert_eq!(
+        obj.info().unwrap().unwrap().pref,
+        StoragePreference::FASTEST
+    );
+
+    let (obj, _) = os
+        .open_object_with_pref(b"tiered", StoragePreference::FAST)
+        .unwrap()
+        .unwrap();
+    assert_eq!(obj.storage_preference(), StoragePreference::FAST);
+}
@@ -0,0 +1,92 @@
+//! Tests of the deterministic simulation mode.
+use super::TO_MEBIBYTE;
+use betree_storage_stack::{
+    database::{AccessMode, Event},
+    sim::Simulation,
+    storage_pool::{LeafVdev, TierConfiguration, Vdev},
+    DatabaseConfiguration, StoragePoolConfiguration,
+};
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+fn config() -> DatabaseConfiguration {
+    DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration::new(vec![Vdev::Leaf(
+                LeafVdev::Simulated {
+                    mem: 128 * TO_MEBIBYTE,
+                    min_latency_us: 10,
+                    max_latency_us: 500,
+                },
+            )])],
+            ..Default::default()
+        },
+        access_mode: AccessMode::AlwaysCreateNew,
+        sync_interval_ms: Some(1000),
+        ..Default::default()
+    }
+}
+
+struct Outcome {
+    sync_durations: Vec<Duration>,
+    snapshot_created: Option<SystemTime>,
+    elapsed: Duration,
+}
+
+// Inserts random values for five seconds of virtual time and takes a
+// snapshot at the end.
+fn run(seed: u64) -> Outcome {
+    let sim = Simulation::new(seed);
+    let db = sim.build(config()).unwrap();
+    let sync_durations = Arc::new(Mutex::new(Vec::new()));
+    db.read().register_event_handler({
+        let sync_durations = Arc::clone(&sync_durations);
+        move |event| {
+            if let Event::SyncCompleted { duration, .. } = event {
+                sync_durations.lock().push(*duration);
+            }
+        }
+    });
+
+    let mut ds = db.write().open_or_create_dataset(b"sim").unwrap();
+    for round in 0..10u8 {
+        for _ in 0..200 {
+            let key = (sim.random() % 1000).to_be_bytes();
+            let len = (sim.random() % 4096) as usize + 1;
+            ds.insert(key.to_vec(), &vec![round; len]).unwrap();
+        }
+        sim.advance(Duration::from_millis(500));
+    }
+    db.write().create_snapshot(&mut ds, b"snap").unwrap();
+    let snapshot_created = db.read().list_snapshots(&ds).unwrap()[0].created;
+
+    let sync_durations = sync_durations.lock().clone();
+    Outcome {
+        sync_durations,
+        snapshot_created,
+        elapsed: sim.now(),
+    }
+}
+
+#[test]
+fn simulation_is_repeatable() {
+    let first = run(1);
+    let second = run(1);
+    assert_eq!(first.sync_durations, second.sync_durations);
+    assert_eq!(first.snapshot_created, second.snapshot_created);
+    assert_eq!(first.elapsed, second.elapsed);
+
+    // The periodic sync runs on the virtual clock, the latencies of the vdevs
+    // add to it.
+    assert!(first.sync_durations.len() >= 5);
+    assert!(first.sync_durations.iter().all(|d| !d.is_zero()));
+    assert!(first.elapsed > Duration::from_secs(5));
+    let created = first.snapshot_created.unwrap();
+    assert!(created > SystemTime::UNIX_EPOCH + Duration::from_secs(4));
+    assert!(created <= SystemTime::UNIX_EPOCH + first.elapsed);
+
+    assert_ne!(first.elapsed, run(2).elapsed);
+}
@@ -0,0 +1,56 @@
+use betree_storage_stack::database::{Error, FsckOptions};
+
+use super::test_db;
+
+#[test]
+fn snapshot_lookup_and_retention() {
+    let mut db = test_db(1, 512);
+    let mut first = db.open_or_create_dataset(b"first").unwrap();
+    let mut second = db.open_or_create_dataset(b"second").unwrap();
+    for idx in 0u32..128 {
+        first
+            .insert(idx.to_be_bytes().to_vec(), &[1; 1024])
+            .unwrap();
+        second
+            .insert(idx.to_be_bytes().to_vec(), &[1; 1024])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    db.create_snapshot(&mut first, b"a").unwrap();
+    db.create_snapshot(&mut second, b"b").unwrap();
+
+    // Snapshots are found by their name within their own dataset only.
+    assert!(matches!(
+        db.create_snapshot(&mut first, b"a"),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        db.open_snapshot(&mut first, b"b"),
+        Err(Error::DoesNotExist)
+    ));
+    for (ds, name) in [(&first, b"a"), (&second, b"b")] {
+        let names: Vec<_> = db
+            .iter_snapshots(ds)
+            .unwrap()
+            .map(|name| name.unwrap().to_vec())
+            .collect();
+        assert_eq!(names, vec![name.to_vec()]);
+    }
+
+    // The blocks of a snapshot are retained from its creation on.
+    for idx in 0u32..128 {
+        first
+            .insert(idx.to_be_bytes().to_vec(), &[2; 1024])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let snapshot = db.open_snapshot(&mut first, b"a").unwrap();
+    for idx in 0u32..128 {
+        assert_eq!(
+            &snapshot.get(idx.to_be_bytes()).unwrap().unwrap()[..],
+            &[1; 1024]
+        );
+    }
+    db.close_snapshot(&mut first, snapshot);
+    assert!(db.fsck(FsckOptions::default()).unwrap().is_consistent());
+}